@@ -0,0 +1,91 @@
+use alloc::string::{String, ToString};
+use core::fmt;
+
+bitflags! {
+    /// x86_64缺页异常（#PF）的错误码
+    ///
+    /// 参见Intel SDM Vol. 3A, 4.7 "Page-Fault Exceptions"
+    pub struct X86PageFaultErrorCode: u64 {
+        /// 为1时，缺页异常由保护违例（页面存在）引起；为0时，由页面不存在引起
+        const PRESENT = 1 << 0;
+        /// 为1时，由写入引起；为0时，由读取引起
+        const WRITE = 1 << 1;
+        /// 为1时，由用户态（CPL=3）的访问引起
+        const USER = 1 << 2;
+        /// 某一级页表项中设置了保留位。这通常意味着页表已经被破坏
+        const RESERVED_BIT = 1 << 3;
+        /// 由取指引起（需要开启NX或者SMEP）
+        const INSTRUCTION_FETCH = 1 << 4;
+        /// 由保护键（PKU/PKS）引起
+        const PROTECTION_KEY = 1 << 5;
+        /// 由影子栈的访问引起（CET）
+        const SHADOW_STACK = 1 << 6;
+        /// 由HLAT分页引起
+        const HLAT = 1 << 7;
+        /// 由SGX的访问控制引起
+        const SGX = 1 << 15;
+    }
+}
+
+impl X86PageFaultErrorCode {
+    /// 从缺页异常的错误码中解码（未定义的位被忽略）
+    pub fn decode(error_code: u64) -> Self {
+        return Self::from_bits_truncate(error_code);
+    }
+
+    /// 页表是否已经被破坏（页表项中设置了保留位）
+    pub fn is_corrupted_table(&self) -> bool {
+        return self.contains(Self::RESERVED_BIT);
+    }
+
+    /// 用文字描述缺页异常，比如“write to non-present user page”（参见`Display`的实现）
+    pub fn describe(&self) -> String {
+        return self.to_string();
+    }
+}
+
+/// 输出缺页异常的文字描述。不会分配内存，因此可以在缺页异常的处理过程中直接写入日志
+impl fmt::Display for X86PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.contains(Self::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if self.contains(Self::SHADOW_STACK) {
+            if self.contains(Self::WRITE) {
+                "shadow stack write to"
+            } else {
+                "shadow stack read from"
+            }
+        } else if self.contains(Self::WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+        let present = if self.contains(Self::PRESENT) {
+            "present"
+        } else {
+            "non-present"
+        };
+        let mode = if self.contains(Self::USER) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} {} {} page", access, present, mode)?;
+
+        let causes = [
+            (
+                Self::RESERVED_BIT,
+                "reserved bit set (corrupted page table)",
+            ),
+            (Self::PROTECTION_KEY, "protection key violation"),
+            (Self::HLAT, "HLAT paging"),
+            (Self::SGX, "SGX access control"),
+        ];
+        for (flag, cause) in causes.iter() {
+            if self.contains(*flag) {
+                write!(f, ", {}", cause)?;
+            }
+        }
+        return Ok(());
+    }
+}
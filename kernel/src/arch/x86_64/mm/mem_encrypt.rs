@@ -0,0 +1,148 @@
+//! AMD SME/SEV的内存加密（C位）
+//!
+//! 开启SME（宿主机）或者SEV（虚拟机）时，页表项中的C位决定了页面是否被加密：设置C位的页面是私有的（加密），
+//! 清除C位的页面可以与设备、虚拟机监视器共享（比如virtio的缓冲区、bounce buffer）。
+//! C位在页表项中的位置由CPUID.8000001FH:EBX[5:0]给出。
+//!
+//! `mark_shared`/`mark_private`修改物理页帧在直接映射区域中的映射的C位。
+//! 在没有开启内存加密的系统上，它们不做任何事情。
+
+use core::sync::atomic::{compiler_fence, AtomicUsize, Ordering};
+
+use x86::cpuid::cpuid;
+use x86::msr::rdmsr;
+
+use crate::{
+    arch::{interrupt::ipi::send_ipi, MMArch},
+    exception::ipi::{IpiKind, IpiTarget},
+    include::bindings::bindings::smp_get_total_cpu,
+    kinfo,
+    mm::{
+        allocator::page_frame::PageFrameCount, kernel_mapper::KernelMapper, page::Flusher,
+        page::PageFlushAll, MemoryManagementArch, PhysAddr,
+    },
+    syscall::SystemError,
+};
+
+/// SYSCFG寄存器
+const MSR_AMD64_SYSCFG: u32 = 0xc001_0010;
+/// SYSCFG.MemEncryptionModeEn（SME已开启）
+const SYSCFG_MEM_ENCRYPT: u64 = 1 << 23;
+/// SEV_STATUS寄存器
+const MSR_AMD64_SEV_STATUS: u32 = 0xc001_0131;
+/// SEV_STATUS.SEV_Enabled（当前运行在SEV虚拟机中）
+const SEV_STATUS_ENABLED: u64 = 1 << 0;
+
+/// 页表项中C位的掩码（为0表示没有开启内存加密）
+static MEM_ENCRYPT_MASK: AtomicUsize = AtomicUsize::new(0);
+
+/// 检测SME/SEV是否已经开启，并记录C位的位置（在内存管理初始化时调用一次）
+pub fn init_mem_encrypt() {
+    // 最大的扩展CPUID叶小于8000001FH时，处理器不支持SME/SEV
+    if cpuid!(0x8000_0000).eax < 0x8000_001f {
+        return;
+    }
+    let leaf = cpuid!(0x8000_001f);
+    let sme_supported = leaf.eax & (1 << 0) != 0;
+    let sev_supported = leaf.eax & (1 << 1) != 0;
+
+    // 只有在CPUID报告支持时才读取对应的MSR，否则会触发#GP
+    let sev_active =
+        sev_supported && unsafe { rdmsr(MSR_AMD64_SEV_STATUS) } & SEV_STATUS_ENABLED != 0;
+    let sme_active = sme_supported && unsafe { rdmsr(MSR_AMD64_SYSCFG) } & SYSCFG_MEM_ENCRYPT != 0;
+    if !sev_active && !sme_active {
+        return;
+    }
+
+    let c_bit = (leaf.ebx & 0x3f) as usize;
+    MEM_ENCRYPT_MASK.store(1 << c_bit, Ordering::Relaxed);
+    kinfo!(
+        "Memory encryption active ({}), C-bit: {}",
+        if sev_active { "SEV" } else { "SME" },
+        c_bit
+    );
+}
+
+/// 是否开启了内存加密（SME或者SEV）
+pub fn mem_encrypt_active() -> bool {
+    return mem_encrypt_mask() != 0;
+}
+
+/// 页表项中C位的掩码。没有开启内存加密时，返回0
+pub fn mem_encrypt_mask() -> usize {
+    return MEM_ENCRYPT_MASK.load(Ordering::Relaxed);
+}
+
+/// 把一段物理页帧标记为共享（清除直接映射区域中的C位），用于virtio、bounce buffer等需要与设备或者虚拟机监视器
+/// 共享的内存。在没有开启内存加密的系统上，不做任何事情
+///
+/// 页帧原有的内容不会被保留（在修改之前，以加密方式写入的数据在修改之后无法被正确读出），调用者需要在之后重新初始化
+///
+/// ## 参数
+///
+/// - `paddr`：起始物理地址（必须按页对齐）
+/// - `count`：页帧的数量
+///
+/// ## 返回值
+///
+/// - `EINVAL`：paddr没有按页对齐，或者页帧不在直接映射区域中
+/// - `EFAULT`：页帧在直接映射区域中没有被映射
+/// - `EAGAIN_OR_EWOULDBLOCK`：当前CPU已经持有内核映射器的锁
+pub fn mark_shared(paddr: PhysAddr, count: PageFrameCount) -> Result<(), SystemError> {
+    return set_encrypted(paddr, count, false);
+}
+
+/// 把一段物理页帧标记为私有（设置直接映射区域中的C位），撤销`mark_shared`。在没有开启内存加密的系统上，不做任何事情
+///
+/// 参数和返回值与`mark_shared`相同
+pub fn mark_private(paddr: PhysAddr, count: PageFrameCount) -> Result<(), SystemError> {
+    return set_encrypted(paddr, count, true);
+}
+
+fn set_encrypted(
+    paddr: PhysAddr,
+    count: PageFrameCount,
+    encrypted: bool,
+) -> Result<(), SystemError> {
+    if !paddr.check_aligned(MMArch::PAGE_SIZE) {
+        return Err(SystemError::EINVAL);
+    }
+    let mask = mem_encrypt_mask();
+    if mask == 0 {
+        return Ok(());
+    }
+    let (clear, set) = if encrypted { (0, mask) } else { (mask, 0) };
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper
+        .as_mut()
+        .ok_or(SystemError::EAGAIN_OR_EWOULDBLOCK)?;
+    let mut flusher = PageFlushAll::<MMArch>::new();
+    let mut r = Ok(());
+    for i in 0..count.data() {
+        let virt = match unsafe { MMArch::phys_2_virt(paddr + i * MMArch::PAGE_SIZE) } {
+            Some(virt) => virt,
+            None => {
+                r = Err(SystemError::EINVAL);
+                break;
+            }
+        };
+        // 同一个物理地址的加密和非加密的缓存行不保证一致，因此在修改C位之前写回缓存
+        unsafe { MMArch::clflush_range(virt, MMArch::PAGE_SIZE) };
+        match unsafe { mapper.update_entry_bits(virt, clear, set) } {
+            Ok(f) => flusher.consume(f),
+            Err(e) => {
+                r = Err(e.into());
+                break;
+            }
+        }
+    }
+
+    // 即使中途失败，已经修改的页表项也需要刷新
+    flusher.flush();
+    if unsafe { smp_get_total_cpu() } > 1 {
+        send_ipi(IpiKind::FlushTLB, IpiTarget::Other);
+    }
+    compiler_fence(Ordering::SeqCst);
+    return r;
+}
@@ -1,56 +1,647 @@
 pub mod barrier;
+pub mod fault;
+pub mod mem_encrypt;
+pub mod pcid;
+pub mod tests;
+pub mod verify;
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 use hashbrown::HashSet;
+use x86::controlregs::{cr0, cr0_write, cr4, Cr0};
+use x86::cpuid::{cpuid, CpuId};
+use x86::msr::{rdmsr, wrmsr, IA32_GS_BASE, IA32_PAT};
 use x86::time::rdtsc;
 use x86_64::registers::model_specific::EferFlags;
 
-use crate::driver::uart::uart::c_uart_send_str;
+use crate::arch::interrupt::ipi::send_ipi;
+use crate::arch::mm::fault::X86PageFaultErrorCode;
+use crate::arch::mm::mem_encrypt::{init_mem_encrypt, mem_encrypt_mask};
+use crate::arch::mm::pcid::{
+    alloc_pcid, current_pcid, init_pcid, invpcid_supported, pcid_enabled, pcid_flush_current,
+    pcid_mark_stale, pcid_note_invalidate, pcid_switch_cr3, CR3_NOFLUSH, CR3_PCID_MASK,
+};
+use crate::arch::rand::random_u64;
+use crate::driver::uart::uart::{c_uart_send, c_uart_send_str, UartPort};
+use crate::exception::ipi::{IpiKind, IpiTarget};
 use crate::include::bindings::bindings::{
-    disable_textui, enable_textui, multiboot2_get_memory, multiboot2_iter, multiboot_mmap_entry_t,
-    video_reinitialize,
+    disable_textui, enable_textui, iter_data_t, multiboot2_get_cmdline, multiboot2_get_memory,
+    multiboot2_get_modules, multiboot2_iter, multiboot2_module_info_t, multiboot2_module_list_t,
+    multiboot_mmap_entry_t, pt_regs, smp_get_total_cpu, video_reinitialize,
+    MULTIBOOT2_MODULE_CMDLINE_MAX,
 };
 use crate::libs::align::page_align_up;
+use crate::libs::lazy_init::Lazy;
 use crate::libs::printk::PrintkWriter;
-use crate::libs::spinlock::SpinLock;
-
-use crate::mm::allocator::page_frame::{FrameAllocator, PageFrameCount};
+use crate::libs::spinlock::{SpinLock, SpinLockGuard};
+
+use crate::mm::allocator::cma::{cma_init, cma_reserve};
+use crate::mm::allocator::early_heap::early_heap_handoff;
+use crate::mm::allocator::emergency::{emergency_alloc, emergency_refill, emergency_refill_cpu};
+use crate::mm::allocator::frame_cache::{
+    frame_cache_pop, frame_cache_push, frame_cache_push_local, frame_cache_room, frame_cache_take,
+    frame_cache_total, FRAME_CACHE_BATCH, FRAME_CACHE_SIZE, FRAME_CACHE_WARMUP,
+};
+use crate::mm::allocator::frame_tag::{
+    frame_tag_clear, frame_tag_init, frame_tag_set, FrameTag, FRAME_TAG_UNTAGGED,
+};
+use crate::mm::allocator::free_check::free_check_init;
+use crate::mm::allocator::huge_pool::{huge_pool_refill, huge_pool_size, set_huge_pool_size};
+use crate::mm::allocator::loworder_pool::{
+    loworder_pool_drain, loworder_pool_pop, loworder_pool_push, loworder_pool_size,
+};
+use crate::mm::allocator::page_frame::{
+    page_map_range, pinned_frames, AllocFlags, FrameAllocator, PageFrameCount, PageFrameUsage,
+    PageRange,
+};
+use crate::mm::allocator::pressure::low_memory_check;
+use crate::mm::allocator::scrub::{
+    scrub_enabled, scrub_pop_any, scrub_pop_clean, scrub_push_dirty, scrub_set_enabled, scrub_some,
+};
 use crate::mm::mmio_buddy::mmio_init;
 use crate::{
-    arch::MMArch,
+    arch::{CurrentIrqArch, MMArch},
+    exception::InterruptArch,
     mm::allocator::{buddy::BuddyAllocator, bump::BumpAllocator},
 };
 
+use crate::arch::asm::current::current_pcb;
+use crate::mm::cache_type::CacheType;
+use crate::mm::error::MmError;
+use crate::mm::fixmap::fixmap_init;
+use crate::mm::initrd::{initrd_frames, initrd_reserve};
 use crate::mm::kernel_mapper::KernelMapper;
-use crate::mm::page::{PageEntry, PageFlags};
-use crate::mm::{MemoryManagementArch, PageTableKind, PhysAddr, PhysMemoryArea, VirtAddr};
+use crate::mm::kmem_stat::{kmem_stat_add, kmem_stat_get, kmem_stat_sub, KernelMemPurpose};
+use crate::mm::page::{
+    flush_mark_boot_complete, force_4k_pages, FlushBatch, Flusher, PageEntry, PageFlags,
+    PageFlushAll, PageSize, PageTable, PageTableAllocStats,
+};
+use crate::mm::percpu::{percpu_area_init, percpu_unit_base, PerCpu};
+use crate::mm::quarantine::{quarantine_intercept, quarantine_pending_in};
+use crate::mm::reserved::{reserved_kind_of_mmap_type, reserved_region_add, ReservedKind};
+use crate::mm::stack_guard::{alloc_kernel_stack, classify_stack_fault, classify_user_stack_fault};
+use crate::mm::trampoline::trampoline_area_init;
+use crate::mm::ucontext::{zero_frame_init, UserMapper, WriteFaultOutcome};
+use crate::mm::{
+    cap_memory_areas, merge_memory_areas, push_memory_area, subtract_memory_areas,
+    MemoryManagementArch, PageTableKind, PhysAddr, PhysMemoryArea, VirtAddr,
+};
+use crate::process::preempt::{preempt_disable, preempt_enable};
+use crate::smp::core::smp_get_processor_id;
 use crate::syscall::SystemError;
-use crate::{kdebug, kinfo};
+use crate::{kdebug, kerror, kinfo, kwarn};
 
 use core::arch::asm;
 use core::ffi::c_void;
 use core::fmt::{Debug, Write};
+use core::intrinsics::{likely, unlikely};
 use core::mem::{self};
+use core::ops::Range;
+
+use core::sync::atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering};
+
+/// 在启动早期，直接向串口输出告警信息的Writer（不需要动态内存分配）
+struct BootUartWriter;
+
+impl Write for BootUartWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for b in s.bytes() {
+            c_uart_send(UartPort::COM1.to_u16(), b);
+        }
+        return Ok(());
+    }
+}
+
+/// 在启动早期，通过串口输出一条醒目的告警信息，并同时打印到屏幕上
+fn boot_uart_warn(args: core::fmt::Arguments) {
+    BootUartWriter.write_str("\n[ WARN ] ").ok();
+    BootUartWriter.write_fmt(args).ok();
+    BootUartWriter.write_str("\n").ok();
+    kwarn!("{}", args);
+}
+
+/// 启动阶段内存初始化失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootMmFailure {
+    /// 物理内存不足，无法为所有的物理内存建立映射
+    OutOfMemory,
+    /// 内部的不变量被破坏（内核的bug）
+    InvariantBroken,
+}
+
+impl BootMmFailure {
+    fn from_error(err: &MmError) -> Self {
+        match err {
+            MmError::OutOfMemory { .. } => BootMmFailure::OutOfMemory,
+            _ => BootMmFailure::InvariantBroken,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            BootMmFailure::OutOfMemory => "not enough RAM to map all RAM",
+            BootMmFailure::InvariantBroken => "internal invariant broken",
+        }
+    }
+}
+
+/// 格式化启动阶段内存初始化失败的信息
+///
+/// ## 参数
+///
+/// - `w`：输出的目标
+/// - `stage`：失败时所处的阶段
+/// - `err`：导致失败的错误
+/// - `bump_offset`：bump分配器的高水位（下一个将要分配的物理地址）
+/// - `areas`：物理内存区域
+fn format_boot_mm_failure(
+    w: &mut impl Write,
+    stage: &str,
+    err: &MmError,
+    bump_offset: usize,
+    areas: &[PhysMemoryArea],
+) -> core::fmt::Result {
+    let kind = BootMmFailure::from_error(err);
+    writeln!(
+        w,
+        "mm init failed at [{}]: {} ({})",
+        stage,
+        kind.description(),
+        err
+    )?;
+    writeln!(w, "bump allocator high-water mark: {:#x}", bump_offset)?;
+    writeln!(w, "memory map ({} areas):", areas.len())?;
+    for (i, area) in areas.iter().enumerate() {
+        writeln!(
+            w,
+            "  [{}] {:#018x} - {:#018x} ({} KB)",
+            i,
+            area.base.data(),
+            area.base.data() + area.size,
+            area.size / 1024
+        )?;
+    }
+    return Ok(());
+}
+
+/// 输出建立直接映射区域时，各级页表所占用的内存
+fn log_direct_map_overhead(stats: &PageTableAllocStats) {
+    let bytes = stats.total().bytes();
+    kinfo!(
+        "direct map page-table overhead: {}.{:02} MB (PML4: {}, PDPT: {}, PD: {}, PT: {})",
+        bytes / (1024 * 1024),
+        bytes % (1024 * 1024) * 100 / (1024 * 1024),
+        stats.frames_at(3),
+        stats.frames_at(2),
+        stats.frames_at(1),
+        stats.frames_at(0)
+    );
+}
+
+/// 直接映射区域中，可以使用大页映射的内存（用于评估大页映射能够节省的页表开销）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HugeMapOpportunities {
+    /// 完整地位于RAM中、按2M对齐的2M块的数量（包括位于1G块中的部分）
+    pub runs_2m: usize,
+    /// 完整地位于RAM中、按1G对齐的1G块的数量
+    pub runs_1g: usize,
+    /// RAM的总大小（字节）
+    pub ram_bytes: usize,
+}
+
+impl HugeMapOpportunities {
+    /// 2M块所覆盖的RAM的比例（千分比）
+    pub fn coverage_2m_permille(&self) -> usize {
+        return Self::permille(self.runs_2m * (1 << 21), self.ram_bytes);
+    }
+
+    /// 1G块所覆盖的RAM的比例（千分比）
+    pub fn coverage_1g_permille(&self) -> usize {
+        return Self::permille(self.runs_1g * (1 << 30), self.ram_bytes);
+    }
+
+    fn permille(covered: usize, total: usize) -> usize {
+        if total == 0 {
+            return 0;
+        }
+        return (covered as u128 * 1000 / total as u128) as usize;
+    }
+}
+
+/// 选择直接映射区域中，从vaddr开始映射时使用的页的大小
+///
+/// 只有在虚拟地址、物理地址都按2M对齐，剩余的大小不少于2M，并且这2M中的每一页的flags都与第一页相同时，
+/// 才使用2M的大页（内核镜像的各个段的边界只按4K对齐，段的边界所在的2M范围只能使用4K页）。
+/// 设置了`FORCE_4K_PAGES`时，总是使用4K页
+///
+/// ## 参数
+///
+/// - `vaddr`：虚拟地址
+/// - `paddr`：物理地址
+/// - `remaining`：区域中剩余要映射的字节数
+/// - `flags`：vaddr处的页的flags（即`kernel_page_flags(vaddr)`）
+fn direct_map_page_size(
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    remaining: usize,
+    flags: PageFlags<MMArch>,
+) -> PageSize {
+    let huge = PageSize::Size2M.bytes::<MMArch>();
+    if force_4k_pages()
+        || remaining < huge
+        || !vaddr.check_aligned(huge)
+        || !paddr.check_aligned(huge)
+    {
+        return PageSize::Size4K;
+    }
+    let uniform = (1..huge / MMArch::PAGE_SIZE).all(|i| {
+        unsafe { kernel_page_flags::<MMArch>(vaddr + i * MMArch::PAGE_SIZE) }.data() == flags.data()
+    });
+    if uniform {
+        return PageSize::Size2M;
+    }
+    return PageSize::Size4K;
+}
+
+/// 统计物理内存区域中，按2M、1G对齐的完整块的数量
+///
+/// 区域应当按照基地址从小到大排列，首尾相接的区域会被合并之后再统计（bootloader有时会把一段连续的RAM拆分成多个区域）。
+/// 没有排序的区域不会被合并，统计的结果只会偏少
+pub fn direct_map_huge_opportunities(areas: &[PhysMemoryArea]) -> HugeMapOpportunities {
+    let mut result = HugeMapOpportunities::default();
+    let mut count_run = |start: usize, end: usize| {
+        for (shift, runs) in [(21, &mut result.runs_2m), (30, &mut result.runs_1g)] {
+            let size = 1usize << shift;
+            let first = (start + size - 1) & !(size - 1);
+            let last = end & !(size - 1);
+            if last > first {
+                *runs += (last - first) >> shift;
+            }
+        }
+    };
+
+    // 当前正在合并的连续区域
+    let mut run: Option<(usize, usize)> = None;
+    for area in areas.iter().filter(|area| area.size != 0) {
+        let (start, end) = (area.base.data(), area.base.data() + area.size);
+        run = match run {
+            Some((run_start, run_end)) if run_end == start => Some((run_start, end)),
+            Some((run_start, run_end)) => {
+                count_run(run_start, run_end);
+                Some((start, end))
+            }
+            None => Some((start, end)),
+        };
+    }
+    if let Some((run_start, run_end)) = run {
+        count_run(run_start, run_end);
+    }
+
+    result.ram_bytes = areas.iter().map(|area| area.size).sum();
+    return result;
+}
+
+/// 输出直接映射区域中，可以使用大页映射的内存
+fn log_direct_map_huge_opportunities(op: &HugeMapOpportunities) {
+    let permille_2m = op.coverage_2m_permille();
+    let permille_1g = op.coverage_1g_permille();
+    kinfo!(
+        "direct map huge-page opportunities: {} x 2M ({}.{}% of RAM), {} x 1G ({}.{}% of RAM)",
+        op.runs_2m,
+        permille_2m / 10,
+        permille_2m % 10,
+        op.runs_1g,
+        permille_1g / 10,
+        permille_1g % 10
+    );
+}
+
+/// 启动阶段内存初始化失败时，通过串口输出内存布局等诊断信息，然后停机
+fn boot_mm_fail(stage: &str, err: MmError, bump_offset: usize) -> ! {
+    let areas = X86_64MMArch::phys_memory_areas();
+    BootUartWriter.write_str("\n[ FATAL ] ").ok();
+    format_boot_mm_failure(&mut BootUartWriter, stage, &err, bump_offset, areas).ok();
+    panic!(
+        "mm init failed at [{}]: {} ({})",
+        stage,
+        BootMmFailure::from_error(&err).description(),
+        err
+    );
+}
+
+/// bootloader没有报告任何可用内存时的错误信息
+const NO_USABLE_MEMORY_MSG: &str = "no usable memory reported by bootloader";
+
+/// 启用`mem_fallback`特性时，bootloader没有报告可用内存的情况下，假设存在的内存区域：[1MB, 1MB + 64MB)
+///
+/// 这是一个保守的假设，能够运行本内核的x86_64机器都满足
+const MEM_FALLBACK_AREA: PhysMemoryArea = PhysMemoryArea {
+    base: PhysAddr::new(0x10_0000),
+    size: 64 * 1024 * 1024,
+};
+
+/// 计算可用内存的总大小
+///
+/// ## 返回值
+///
+/// 如果没有可用的内存区域（或者所有区域的大小都为0），返回None
+fn usable_memory_total(areas: &[PhysMemoryArea]) -> Option<usize> {
+    let total: usize = areas.iter().map(|area| area.size).sum();
+    if total == 0 {
+        return None;
+    }
+    return Some(total);
+}
 
-use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+/// 格式化bootloader没有报告可用内存时的错误信息
+fn format_no_usable_memory(w: &mut impl Write, areas: &[PhysMemoryArea]) -> core::fmt::Result {
+    writeln!(
+        w,
+        "{} ({} usable areas from multiboot2, total 0 bytes)",
+        NO_USABLE_MEMORY_MSG,
+        areas.len()
+    )?;
+    if cfg!(feature = "mem_fallback") {
+        writeln!(
+            w,
+            "assuming {:#x} - {:#x} is RAM (mem_fallback)",
+            MEM_FALLBACK_AREA.base.data(),
+            MEM_FALLBACK_AREA.base.data() + MEM_FALLBACK_AREA.size
+        )?;
+    } else {
+        writeln!(
+            w,
+            "check the bootloader configuration, or build with the mem_fallback feature"
+        )?;
+    }
+    return Ok(());
+}
 
 pub type PageMapper =
     crate::mm::page::PageMapper<crate::arch::x86_64::mm::X86_64MMArch, LockedFrameAllocator>;
 
+/// 从multiboot2获取内存区域信息时，所使用的缓冲区的容量（条目数）
+const MB2_MMAP_BUFFER_ENTRIES: usize = 512;
+
+/// 物理内存区域数组的容量
+///
+/// 只有可用的内存区域会被放入这个数组，因此没有必要比multiboot2的缓冲区更大
+const MAX_PHYS_MEMORY_AREAS: usize = MB2_MMAP_BUFFER_ENTRIES;
+
+/// 有效的物理内存区域的数量
+static PHYS_MEMORY_AREAS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// 保留的内存区域数组的容量
+const MAX_RESERVED_MEMORY_AREAS: usize = MB2_MMAP_BUFFER_ENTRIES;
+
+/// 保留的内存区域的数量
+static RESERVED_MEMORY_AREAS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// `mem=`启动参数所设置的可用内存上限（字节），为0表示没有限制
+static MEM_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// `mem=`所设置的上限至少比内核镜像（以及initrd）的结束地址多出的字节数
+const MEM_LIMIT_MIN_FREE: usize = 32 * 1024 * 1024;
+/// 内核命令行参数的最大长度（包括结尾的'\0'）
+const BOOT_CMDLINE_MAX: usize = 256;
+
+/// 把multiboot2提供的内核命令行参数读取到buf中
+///
+/// ## 返回值
+///
+/// 命令行参数。如果bootloader没有提供命令行参数（或者不是合法的UTF-8），返回空字符串
+unsafe fn read_boot_cmdline(buf: &mut [u8; BOOT_CMDLINE_MAX]) -> &str {
+    let mut len: u32 = BOOT_CMDLINE_MAX as u32;
+    multiboot2_iter(
+        Some(multiboot2_get_cmdline),
+        buf.as_mut_ptr() as usize as *mut c_void,
+        &mut len,
+    );
+    // 没有找到命令行参数时，len保持不变
+    if len as usize >= BOOT_CMDLINE_MAX {
+        return "";
+    }
+    return core::str::from_utf8(&buf[0..len as usize]).unwrap_or("");
+}
+
+/// 最多记录的multiboot2模块的数量，超出的模块会被忽略
+const MB2_MAX_MODULES: usize = 8;
+/// 模块命令行的最大长度（包括结尾的'\0'）
+const MB2_MODULE_CMDLINE_MAX: usize = MULTIBOOT2_MODULE_CMDLINE_MAX as usize;
+
+/// bootloader加载的一个multiboot2模块
+#[derive(Clone, Copy)]
+pub struct Mb2Module {
+    start: PhysAddr,
+    end: PhysAddr,
+    cmdline: [u8; MB2_MODULE_CMDLINE_MAX],
+    cmdline_len: usize,
+}
+
+impl Mb2Module {
+    fn from_info(info: &multiboot2_module_info_t) -> Self {
+        let mut cmdline = [0u8; MB2_MODULE_CMDLINE_MAX];
+        let cmdline_len = core::cmp::min(info.cmdline_len as usize, MB2_MODULE_CMDLINE_MAX - 1);
+        for (dst, src) in cmdline.iter_mut().zip(info.cmdline[..cmdline_len].iter()) {
+            *dst = *src as u8;
+        }
+        return Self {
+            start: PhysAddr::new(info.mod_start as usize),
+            end: PhysAddr::new(info.mod_end as usize),
+            cmdline,
+            cmdline_len,
+        };
+    }
+
+    /// 模块的起始物理地址（不一定按页对齐）
+    pub fn start(&self) -> PhysAddr {
+        return self.start;
+    }
+
+    /// 模块的结束物理地址（不包含）
+    pub fn end(&self) -> PhysAddr {
+        return self.end;
+    }
+
+    /// 模块的大小（字节）。如果bootloader提供的结束地址小于起始地址，返回0
+    pub fn size(&self) -> usize {
+        return self.end.data().saturating_sub(self.start.data());
+    }
+
+    /// 模块的命令行（超过`MB2_MODULE_CMDLINE_MAX - 1`字节的部分会被截断）
+    ///
+    /// ## 返回值
+    ///
+    /// 如果命令行为空，或者不是合法的UTF-8，返回None
+    pub fn cmdline(&self) -> Option<&str> {
+        if self.cmdline_len == 0 {
+            return None;
+        }
+        return core::str::from_utf8(&self.cmdline[..self.cmdline_len]).ok();
+    }
+}
+
+impl core::fmt::Debug for Mb2Module {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Mb2Module")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .field("cmdline", &self.cmdline())
+            .finish()
+    }
+}
+
+/// 收集multiboot2模块信息的固定大小的缓冲区（启动阶段不能进行动态内存分配）
+struct Mb2ModuleBuffer {
+    infos: [multiboot2_module_info_t; MB2_MAX_MODULES],
+    /// 模块的总数（可能大于缓冲区的容量）
+    count: u32,
+}
+
+impl Mb2ModuleBuffer {
+    fn new() -> Self {
+        return Self {
+            infos: unsafe { mem::zeroed() },
+            count: 0,
+        };
+    }
+
+    /// 把一个multiboot2标签交给`multiboot2_get_modules`处理（不是模块标签时被忽略）
+    ///
+    /// ## Safety
+    ///
+    /// tag必须指向一个完整的multiboot2标签
+    unsafe fn visit(&mut self, tag: *const iter_data_t) {
+        let mut list = self.list();
+        multiboot2_get_modules(tag, &mut list as *mut _ as *mut c_void, &mut self.count);
+    }
+
+    fn list(&mut self) -> multiboot2_module_list_t {
+        return multiboot2_module_list_t {
+            capacity: MB2_MAX_MODULES as u32,
+            modules: self.infos.as_mut_ptr(),
+        };
+    }
+
+    /// 按照bootloader提供的顺序，返回缓冲区中的模块
+    fn modules(self) -> impl Iterator<Item = Mb2Module> {
+        let count = core::cmp::min(self.count as usize, MB2_MAX_MODULES);
+        return self
+            .infos
+            .into_iter()
+            .take(count)
+            .map(|info| Mb2Module::from_info(&info));
+    }
+}
+
+/// 获取bootloader加载的所有multiboot2模块（按照bootloader提供的顺序）
+///
+/// 模块的信息被复制到一个固定大小的缓冲区中，不会进行动态内存分配，因此可以在启动阶段使用。
+/// 超过`MB2_MAX_MODULES`个的模块会被忽略（并打印警告）
+pub fn multiboot2_modules() -> impl Iterator<Item = Mb2Module> {
+    let mut buffer = Mb2ModuleBuffer::new();
+    let mut list = buffer.list();
+    unsafe {
+        multiboot2_iter(
+            Some(multiboot2_get_modules),
+            &mut list as *mut multiboot2_module_list_t as usize as *mut c_void,
+            &mut buffer.count,
+        )
+    };
+    if unlikely(buffer.count as usize > MB2_MAX_MODULES) {
+        boot_uart_warn(format_args!(
+            "multiboot2: {} modules provided, only the first {} are used",
+            buffer.count, MB2_MAX_MODULES
+        ));
+    }
+    return buffer.modules();
+}
+
+/// 在命令行参数中查找`key=value`形式的参数，返回第一个匹配的value
+fn boot_cmdline_param<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    return cmdline.split_ascii_whitespace().find_map(|param| {
+        let (k, v) = param.split_once('=')?;
+        if k == key {
+            Some(v)
+        } else {
+            None
+        }
+    });
+}
+
+/// 解析内存的大小，支持十进制以及`0x`开头的十六进制，可以带有K、M、G后缀（不区分大小写）
+///
+/// ## 返回值
+///
+/// 大小（字节）。如果格式不合法或者溢出，返回None
+fn parse_mem_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        b'g' | b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<usize>().ok()?,
+    };
+    return value.checked_mul(1 << shift);
+}
+
 /// @brief 用于存储物理内存区域的数组
-static mut PHYS_MEMORY_AREAS: [PhysMemoryArea; 512] = [PhysMemoryArea {
+static mut PHYS_MEMORY_AREAS: [PhysMemoryArea; MAX_PHYS_MEMORY_AREAS] = [PhysMemoryArea {
+    base: PhysAddr::new(0),
+    size: 0,
+}; MAX_PHYS_MEMORY_AREAS];
+
+/// 用于存储multiboot2报告的保留（类型2）、ACPI可回收（类型3）以及ACPI NVS（类型4）的内存区域的数组
+///
+/// 按基地址排序，互不重叠（相邻的区域会被合并，因此不区分类型）
+static mut RESERVED_MEMORY_AREAS: [PhysMemoryArea; MAX_RESERVED_MEMORY_AREAS] = [PhysMemoryArea {
     base: PhysAddr::new(0),
     size: 0,
-}; 512];
+};
+    MAX_RESERVED_MEMORY_AREAS];
+
+/// 直接映射区域上方的保护页的数量
+///
+/// 这些页不会被映射，用于捕获“计算出的直接映射地址超出了RAM的末尾”的错误
+const DIRECT_MAP_GUARD_PAGES: usize = 16;
+
+/// 是否把`PHYS_OFFSET`下方的一页也视为保护页
+///
+/// 在x86_64上，这一页位于非规范地址空间中，访问它会触发#GP而不是#PF，
+/// 这里只是把它记录下来，以便在报告错误时能够识别出直接映射区域的下溢
+const DIRECT_MAP_LOW_GUARD: bool = true;
+
+/// 直接映射区域中，最高的RAM地址所对应的虚拟地址（按页对齐）。为0表示还未建立直接映射
+static DIRECT_MAP_TOP: AtomicUsize = AtomicUsize::new(0);
 
 /// 初始的CR3寄存器的值，用于内存管理初始化时，创建的第一个内核页表的位置
-static mut INITIAL_CR3_VALUE: PhysAddr = PhysAddr::new(0);
+///
+/// 在`allocator_init`中被设置一次，之后只读
+static INITIAL_CR3_VALUE: Lazy<PhysAddr> = Lazy::new();
+
+const ACTIVE_TABLE_INIT: AtomicUsize = AtomicUsize::new(0);
+/// 每个CPU上，最近一次通过`set_table`加载到CR3的顶级页表的物理地址。为0表示尚未记录
+///
+/// 只有通过`set_table`（以及`PageMapper::make_current`）切换页表时才会更新，
+/// 因此可以用来检查是否有代码绕过了它直接写入CR3，或者刷新/切换了错误的页表
+static ACTIVE_TABLE: [AtomicUsize; PerCpu::MAX_CPU_NUM] = [ACTIVE_TABLE_INIT; PerCpu::MAX_CPU_NUM];
 
 /// 内核的第一个页表在pml4中的索引
 /// 顶级页表的[256, 512)项是内核的页表
 static KERNEL_PML4E_NO: usize = (X86_64MMArch::PHYS_OFFSET & ((1 << 48) - 1)) >> 39;
 
 static INNER_ALLOCATOR: SpinLock<Option<BuddyAllocator<MMArch>>> = SpinLock::new(None);
+/// 全局页帧分配器的锁被获取的次数（用于检查每个CPU的页帧缓存是否生效）
+static INNER_ALLOCATOR_LOCKS: AtomicUsize = AtomicUsize::new(0);
+/// 全局页帧分配器是否已经被设置（由`set_inner_allocator`设置，之后不会被清除）
+static INNER_ALLOCATOR_READY: AtomicBool = AtomicBool::new(false);
+
+/// 获取全局页帧分配器的锁（关中断），并增加计数
+fn lock_inner_allocator() -> SpinLockGuard<'static, Option<BuddyAllocator<MMArch>>> {
+    INNER_ALLOCATOR_LOCKS.fetch_add(1, Ordering::Relaxed);
+    return INNER_ALLOCATOR.lock_irqsave();
+}
 
 #[derive(Clone, Copy)]
 pub struct X86_64MMBootstrapInfo {
@@ -58,6 +649,9 @@ pub struct X86_64MMBootstrapInfo {
     kernel_code_end: usize,
     kernel_data_end: usize,
     kernel_rodata_end: usize,
+    /// `.data.ro_after_init`段的起始地址（初始化完成之后只读）
+    ro_after_init_start: usize,
+    ro_after_init_end: usize,
     start_brk: usize,
 }
 
@@ -65,8 +659,88 @@ impl Debug for X86_64MMBootstrapInfo {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
-            "kernel_code_start: {:x}, kernel_code_end: {:x}, kernel_data_end: {:x}, kernel_rodata_end: {:x}, start_brk: {:x}",
-            self.kernel_code_start, self.kernel_code_end, self.kernel_data_end, self.kernel_rodata_end, self.start_brk)
+            "kernel_code_start: {:x}, kernel_code_end: {:x}, kernel_data_end: {:x}, kernel_rodata_end: {:x}, ro_after_init: [{:x}, {:x}), start_brk: {:x}",
+            self.kernel_code_start, self.kernel_code_end, self.kernel_data_end, self.kernel_rodata_end, self.ro_after_init_start, self.ro_after_init_end, self.start_brk)
+    }
+}
+
+impl X86_64MMBootstrapInfo {
+    /// 检查内核各个段的边界是否按照预期的顺序排列：
+    ///
+    /// `kernel_code_start < kernel_code_end <= kernel_data_end <= kernel_rodata_end
+    ///  <= ro_after_init_start <= ro_after_init_end <= start_brk`
+    ///
+    /// 如果链接脚本被修改，导致这些符号的顺序错乱，那么`kernel_page_flags`将无法正确地识别只读数据段，
+    /// 从而把所有的页面都映射为可写、可执行。
+    ///
+    /// ## 返回值
+    ///
+    /// 如果顺序正确，返回Ok(())，否则返回描述错误的字符串
+    pub fn check_layout(&self) -> Result<(), &'static str> {
+        if self.kernel_code_start >= self.kernel_code_end {
+            return Err("kernel_code_start >= kernel_code_end");
+        }
+        if self.kernel_code_end > self.kernel_data_end {
+            return Err("kernel_code_end > kernel_data_end");
+        }
+        if self.kernel_data_end > self.kernel_rodata_end {
+            return Err("kernel_data_end > kernel_rodata_end");
+        }
+        if self.kernel_rodata_end > self.ro_after_init_start {
+            return Err("kernel_rodata_end > ro_after_init_start");
+        }
+        if self.ro_after_init_start > self.ro_after_init_end {
+            return Err("ro_after_init_start > ro_after_init_end");
+        }
+        if self.ro_after_init_end > self.start_brk {
+            return Err("ro_after_init_end > start_brk");
+        }
+        return Ok(());
+    }
+
+    /// 获取内核镜像结束位置（start_brk）所对应的物理地址
+    ///
+    /// 内核镜像必须位于直接映射区域内，如果start_brk不在直接映射区域内（比如链接脚本中的内核基址被修改），返回None
+    pub fn kernel_phys_end(&self) -> Option<PhysAddr> {
+        return Self::kernel_virt_2_phys(self.start_brk);
+    }
+
+    /// 获取内核代码段起始位置所对应的物理地址。如果不在直接映射区域内，返回None
+    pub fn kernel_phys_start(&self) -> Option<PhysAddr> {
+        return Self::kernel_virt_2_phys(self.kernel_code_start);
+    }
+
+    /// 检查内核镜像所占用的物理内存`[kernel_phys_start, kernel_phys_end)`是否完全位于固件报告的可用内存区域中
+    ///
+    /// 某些bootloader提供的内存布局中，没有把内核的加载区域报告为可用内存（type 1）。
+    /// 这种情况下，直接映射以及bump分配器关于内核镜像位置的假设都不再成立。
+    ///
+    /// ## 参数
+    ///
+    /// - `areas`：可用的物理内存区域（可以是无序的，相邻的区域会被视为连续的）
+    ///
+    /// ## 返回值
+    ///
+    /// 如果内核镜像完全被覆盖，返回Ok(())，否则返回第一个没有被覆盖的物理地址
+    pub fn check_ram_coverage(&self, areas: &[PhysMemoryArea]) -> Result<(), PhysAddr> {
+        let start = self.kernel_phys_start().ok_or(PhysAddr::new(0))?;
+        let end = self.kernel_phys_end().ok_or(start)?;
+        let mut cursor = start.data();
+        while cursor < end.data() {
+            let area = areas
+                .iter()
+                .find(|a| a.base.data() <= cursor && cursor < a.base.data() + a.size)
+                .ok_or(PhysAddr::new(cursor))?;
+            cursor = area.base.data() + area.size;
+        }
+        return Ok(());
+    }
+
+    fn kernel_virt_2_phys(vaddr: usize) -> Option<PhysAddr> {
+        if vaddr < X86_64MMArch::PHYS_OFFSET {
+            return None;
+        }
+        return unsafe { X86_64MMArch::virt_2_phys(VirtAddr::new(vaddr)) };
     }
 }
 
@@ -79,6 +753,190 @@ pub struct X86_64MMArch;
 /// XD标志位是否被保留
 static XD_RESERVED: AtomicBool = AtomicBool::new(false);
 
+/// 处理器是否支持1G的大页（CPUID.80000001H:EDX[bit 26]）
+static HUGE_PAGE_1G_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// 处理器支持的大页的大小
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugePageSupport {
+    /// 是否支持2M的大页
+    pub supports_2m: bool,
+    /// 是否支持1G的大页
+    pub supports_1g: bool,
+}
+
+/// 处理器是否支持CET影子栈（CPUID.(EAX=07H,ECX=0):ECX[bit 7]）
+static CET_SS_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// CR4.CET（控制流强制技术的总开关）
+const CR4_CET: usize = 1 << 23;
+
+/// 处理器是否支持SSE2（CPUID.01H:EDX[bit 26]，x86_64上总是支持），用于非临时存储
+static SSE2_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// 抑制TLB刷新的CPU的id加1（为0表示没有抑制）
+#[cfg(feature = "tlb_fault_inject")]
+static TLB_SUPPRESS_CPU: AtomicUsize = AtomicUsize::new(0);
+/// 被抑制的TLB刷新的次数
+#[cfg(feature = "tlb_fault_inject")]
+static TLB_SUPPRESSED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// 当前CPU上的TLB刷新是否被抑制。如果是，记录一次被抑制的刷新
+///
+/// 没有启用`tlb_fault_inject`特性时总是返回false，TLB刷新的路径上没有额外的开销
+#[cfg(not(feature = "tlb_fault_inject"))]
+#[inline(always)]
+fn tlb_invalidate_suppressed() -> bool {
+    return false;
+}
+
+/// 当前CPU上的TLB刷新是否被抑制。如果是，记录一次被抑制的刷新
+#[cfg(feature = "tlb_fault_inject")]
+fn tlb_invalidate_suppressed() -> bool {
+    let cpu = TLB_SUPPRESS_CPU.load(Ordering::Relaxed);
+    if core::intrinsics::likely(cpu == 0) || cpu != smp_get_processor_id() as usize + 1 {
+        return false;
+    }
+    TLB_SUPPRESSED_COUNT.fetch_add(1, Ordering::Relaxed);
+    return true;
+}
+
+/// 故障注入（仅用于测试）：在当前CPU上执行f，期间`invalidate_page`、`invalidate_all`不做任何事情，
+/// 从而可以观察到TLB中过时的翻译，证明某处的TLB刷新确实是必需的
+///
+/// f在关中断的情况下执行，其他CPU上的TLB刷新不受影响。f返回之后，调用者需要自行刷新被修改的页面
+///
+/// ## 返回值
+///
+/// (f的返回值, 被抑制的TLB刷新的次数)
+#[cfg(feature = "tlb_fault_inject")]
+pub fn with_tlb_invalidate_suppressed<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let cpu = smp_get_processor_id() as usize + 1;
+    TLB_SUPPRESS_CPU
+        .compare_exchange(0, cpu, Ordering::SeqCst, Ordering::SeqCst)
+        .expect("TLB invalidation is already suppressed");
+    let before = TLB_SUPPRESSED_COUNT.load(Ordering::SeqCst);
+    let r = f();
+    let suppressed = TLB_SUPPRESSED_COUNT.load(Ordering::SeqCst) - before;
+    TLB_SUPPRESS_CPU.store(0, Ordering::SeqCst);
+    drop(guard);
+    return (r, suppressed);
+}
+
+/// 软件TLB的容量
+#[cfg(feature = "tlb_fault_inject")]
+const SHADOW_TLB_ENTRIES: usize = 8;
+/// 软件TLB（仅用于测试）：记录测试认为已经进入TLB的翻译，只有没有被抑制的TLB刷新会清除其中的条目，
+/// 从而可以确定地检查某次修改页表之后，被缓存的翻译是否被刷新，而不依赖处理器是否恰好缓存了它
+#[cfg(feature = "tlb_fault_inject")]
+static SHADOW_TLB: SpinLock<[Option<(VirtAddr, PhysAddr)>; SHADOW_TLB_ENTRIES]> =
+    SpinLock::new([None; SHADOW_TLB_ENTRIES]);
+
+/// 在软件TLB中记录vaddr所在的页面被翻译为paddr（仅用于测试）
+///
+/// 软件TLB已满时，替换第一个条目
+#[cfg(feature = "tlb_fault_inject")]
+pub fn shadow_tlb_fill(vaddr: VirtAddr, paddr: PhysAddr) {
+    let vaddr = VirtAddr::new(vaddr.data() & !X86_64MMArch::PAGE_OFFSET_MASK);
+    let mut tlb = SHADOW_TLB.lock_irqsave();
+    let slot = tlb
+        .iter()
+        .position(|e| e.map_or(true, |(v, _)| v == vaddr))
+        .unwrap_or(0);
+    tlb[slot] = Some((vaddr, paddr));
+}
+
+/// 在软件TLB中查找vaddr所在的页面的翻译（仅用于测试）
+///
+/// ## 返回值
+///
+/// 还没有被TLB刷新清除的翻译；没有记录或者已经被清除时返回None
+#[cfg(feature = "tlb_fault_inject")]
+pub fn shadow_tlb_lookup(vaddr: VirtAddr) -> Option<PhysAddr> {
+    let vaddr = VirtAddr::new(vaddr.data() & !X86_64MMArch::PAGE_OFFSET_MASK);
+    return SHADOW_TLB
+        .lock_irqsave()
+        .iter()
+        .flatten()
+        .find(|(v, _)| *v == vaddr)
+        .map(|(_, p)| *p);
+}
+
+/// 清除软件TLB中，从start开始的count个页面的条目。range为None时清除所有条目
+#[cfg(feature = "tlb_fault_inject")]
+fn shadow_tlb_invalidate(range: Option<(VirtAddr, usize)>) {
+    let mut tlb = SHADOW_TLB.lock_irqsave();
+    for entry in tlb.iter_mut() {
+        let hit = match (*entry, range) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some((v, _)), Some((start, count))) => {
+                let start = start.data() & !X86_64MMArch::PAGE_OFFSET_MASK;
+                v.data() >= start && (v.data() - start) / X86_64MMArch::PAGE_SIZE < count
+            }
+        };
+        if hit {
+            *entry = None;
+        }
+    }
+}
+
+/// 没有启用`tlb_fault_inject`特性时，没有软件TLB
+#[cfg(not(feature = "tlb_fault_inject"))]
+#[inline(always)]
+fn shadow_tlb_invalidate(_range: Option<(VirtAddr, usize)>) {}
+
+/// `invalidate_range`刷新的页面数量超过这个值时，重新加载CR3来刷新整个TLB，而不是逐页执行`invlpg`
+static TLB_FLUSH_RANGE_MAX_PAGES: AtomicUsize = AtomicUsize::new(64);
+/// `invalidate_range`逐页刷新的次数
+static TLB_RANGE_FLUSH_PAGED: AtomicUsize = AtomicUsize::new(0);
+/// `invalidate_range`退化为刷新整个TLB的次数
+static TLB_RANGE_FLUSH_FULL: AtomicUsize = AtomicUsize::new(0);
+
+/// `invalidate_range`的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbRangeFlushStats {
+    /// 逐页刷新的次数
+    pub paged: usize,
+    /// 退化为刷新整个TLB的次数
+    pub full: usize,
+}
+
+/// 设置`invalidate_range`逐页刷新的页面数量上限
+///
+/// ## 返回值
+///
+/// 之前的上限
+pub fn set_tlb_flush_range_threshold(pages: usize) -> usize {
+    return TLB_FLUSH_RANGE_MAX_PAGES.swap(pages, Ordering::Relaxed);
+}
+
+/// 获取`invalidate_range`逐页刷新的页面数量上限
+pub fn tlb_flush_range_threshold() -> usize {
+    return TLB_FLUSH_RANGE_MAX_PAGES.load(Ordering::Relaxed);
+}
+
+/// 获取`invalidate_range`的统计信息
+pub fn tlb_range_flush_stats() -> TlbRangeFlushStats {
+    return TlbRangeFlushStats {
+        paged: TLB_RANGE_FLUSH_PAGED.load(Ordering::Relaxed),
+        full: TLB_RANGE_FLUSH_FULL.load(Ordering::Relaxed),
+    };
+}
+
+/// 清零的页帧数量达到这个值时，使用非临时存储（不经过缓存）
+pub const ZERO_NT_THRESHOLD: usize = 16;
+
+/// 清零页帧时使用的方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroMethod {
+    /// 普通的存储指令（数据会被放入缓存）
+    Temporal,
+    /// 非临时存储指令（movnti），不会污染缓存
+    NonTemporal,
+}
+
 impl MemoryManagementArch for X86_64MMArch {
     /// 4K页
     const PAGE_SHIFT: usize = 12;
@@ -114,6 +972,22 @@ impl MemoryManagementArch for X86_64MMArch {
     /// x86_64不存在EXEC标志位，只有NO_EXEC（XD）标志位
     const ENTRY_FLAG_EXEC: usize = 0;
 
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
+
+    /// PS位（只在PDPT、PD的页表项中有效）
+    const ENTRY_FLAG_HUGE_PAGE: usize = 1 << 7;
+    /// 4K页表项的PAT位与大页的PS位是同一位
+    const ENTRY_FLAG_PAT: usize = 1 << 7;
+    /// 大页的PAT位位于第12位（地址字段的最低位，大页的物理地址至少按2M对齐）
+    const ENTRY_FLAG_PAT_HUGE: usize = 1 << 12;
+
+    const PAGE_TABLE_NAMES: &'static [&'static str] = &["PT", "PD", "PDPT", "PML4"];
+
+    const ENTRY_FLAG_GLOBAL: usize = 1 << 8;
+    /// 第9-11位由硬件忽略，可以由软件使用
+    const ENTRY_FLAG_COW: usize = 1 << 9;
+    const ENTRY_FLAG_SWAP: usize = 1 << 10;
+
     /// 物理地址与虚拟地址的偏移量
     /// 0xffff_8000_0000_0000
     const PHYS_OFFSET: usize = Self::PAGE_NEGATIVE_MASK + (Self::PAGE_ADDRESS_SIZE >> 1);
@@ -129,43 +1003,130 @@ impl MemoryManagementArch for X86_64MMArch {
             fn _etext();
             fn _edata();
             fn _erodata();
+            fn _ro_after_init();
+            fn _ero_after_init();
             fn _end();
         }
 
         Self::init_xd_rsvd();
+        Self::init_cet_ss();
+        Self::init_pcid();
+        Self::init_huge_page_support();
+        Self::init_sse2();
+        init_mem_encrypt();
 
         let bootstrap_info = X86_64MMBootstrapInfo {
             kernel_code_start: _text as usize,
             kernel_code_end: _etext as usize,
             kernel_data_end: _edata as usize,
             kernel_rodata_end: _erodata as usize,
+            ro_after_init_start: _ro_after_init as usize,
+            ro_after_init_end: _ero_after_init as usize,
             start_brk: _end as usize,
         };
+        if let Err(e) = bootstrap_info.check_layout() {
+            panic!(
+                "Invalid kernel section layout: {}, bootstrap info: {:?}",
+                e, bootstrap_info
+            );
+        }
         unsafe {
             BOOTSTRAP_MM_INFO = Some(bootstrap_info);
         }
 
         // 初始化物理内存区域(从multiboot2中获取)
-        let areas_count =
+        let mut areas_count =
             Self::init_memory_area_from_multiboot2().expect("init memory area failed");
+        // 在触碰任何分配器之前，检查bootloader是否报告了可用的内存
+        if usable_memory_total(&PHYS_MEMORY_AREAS[0..areas_count]).is_none() {
+            BootUartWriter
+                .write_str(if cfg!(feature = "mem_fallback") {
+                    "\n[ WARN ] "
+                } else {
+                    "\n[ FATAL ] "
+                })
+                .ok();
+            format_no_usable_memory(&mut BootUartWriter, &PHYS_MEMORY_AREAS[0..areas_count]).ok();
+            if !cfg!(feature = "mem_fallback") {
+                panic!("{}", NO_USABLE_MEMORY_MSG);
+            }
+            PHYS_MEMORY_AREAS[0] = MEM_FALLBACK_AREA;
+            areas_count = 1;
+        }
         c_uart_send_str(0x3f8, "x86 64 init end\n\0".as_ptr());
-
+        let areas_count =
+            Self::init_mem_limit(&mut PHYS_MEMORY_AREAS[0..areas_count], &bootstrap_info);
+
+        PHYS_MEMORY_AREAS_COUNT.store(areas_count, Ordering::SeqCst);
+        if let Err(paddr) = bootstrap_info.check_ram_coverage(&PHYS_MEMORY_AREAS[0..areas_count]) {
+            panic!(
+                "Kernel image is loaded into memory that the firmware didn't report as RAM: {:?} is not covered by any usable memory area, bootstrap info: {:?}",
+                paddr, bootstrap_info
+            );
+        }
+        Self::init_initrd_from_multiboot2(&PHYS_MEMORY_AREAS[0..areas_count]);
+        Self::init_ap_trampoline(&PHYS_MEMORY_AREAS[0..areas_count]);
         return &PHYS_MEMORY_AREAS[0..areas_count];
     }
 
     /// @brief 刷新TLB中，关于指定虚拟地址的条目
     unsafe fn invalidate_page(address: VirtAddr) {
+        if tlb_invalidate_suppressed() {
+            return;
+        }
         compiler_fence(Ordering::SeqCst);
         asm!("invlpg [{0}]", in(reg) address.data(), options(nostack, preserves_flags));
         compiler_fence(Ordering::SeqCst);
+        pcid_note_invalidate(current_pcid(), address);
+        shadow_tlb_invalidate(Some((address, 1)));
     }
 
-    /// @brief 刷新TLB中，所有的条目
-    unsafe fn invalidate_all() {
+    /// 刷新TLB中，从start开始的count个页面的条目
+    ///
+    /// 只在整个循环的前后各放置一次编译器屏障。页面数量超过`tlb_flush_range_threshold`时，
+    /// 重新加载CR3刷新整个TLB，这比逐页执行`invlpg`的开销更小
+    unsafe fn invalidate_range(start: VirtAddr, count: PageFrameCount) {
+        if count.data() == 0 || tlb_invalidate_suppressed() {
+            return;
+        }
+        shadow_tlb_invalidate(Some((start, count.data())));
+        if count.data() > tlb_flush_range_threshold() {
+            TLB_RANGE_FLUSH_FULL.fetch_add(1, Ordering::Relaxed);
+            Self::invalidate_all();
+            return;
+        }
+        TLB_RANGE_FLUSH_PAGED.fetch_add(1, Ordering::Relaxed);
+        let start = VirtAddr::new(start.data() & !Self::PAGE_OFFSET_MASK);
+        let last = start + (count.data() - 1) * Self::PAGE_SIZE;
         compiler_fence(Ordering::SeqCst);
-        // 通过设置cr3寄存器，来刷新整个TLB
-        Self::set_table(PageTableKind::User, Self::table(PageTableKind::User));
+        for i in 0..count.data() {
+            let vaddr = start.data() + i * Self::PAGE_SIZE;
+            asm!("invlpg [{0}]", in(reg) vaddr, options(nostack, preserves_flags));
+        }
         compiler_fence(Ordering::SeqCst);
+        let pcid = current_pcid();
+        pcid_note_invalidate(pcid, start);
+        // 范围可能跨越用户空间与内核空间的边界
+        if last.check_user() != start.check_user() {
+            pcid_note_invalidate(pcid, last);
+        }
+    }
+
+    /// @brief 刷新TLB中，所有的条目
+    ///
+    /// 开启PCID时，立即刷新的只有当前PCID的条目，其他PCID的条目在切换到它们时刷新（参见`pcid`模块）
+    unsafe fn invalidate_all() {
+        if tlb_invalidate_suppressed() {
+            return;
+        }
+        let current = Self::table(PageTableKind::User);
+        debug_assert!(
+            Self::current_table_phys().map_or(true, |active| active == current),
+            "invalidate_all: CR3 {:?} differs from the recorded active table {:?}",
+            current,
+            Self::current_table_phys()
+        );
+        Self::flush_tlb_local();
     }
 
     /// @brief 获取顶级页表的物理地址
@@ -174,14 +1135,24 @@ impl MemoryManagementArch for X86_64MMArch {
         compiler_fence(Ordering::SeqCst);
         asm!("mov {}, cr3", out(reg) paddr, options(nomem, nostack, preserves_flags));
         compiler_fence(Ordering::SeqCst);
-        return PhysAddr::new(paddr);
+        // 开启PCID时，CR3的低12位是PCID
+        return PhysAddr::new(paddr & !(CR3_PCID_MASK | CR3_NOFLUSH));
     }
 
     /// @brief 设置顶级页表的物理地址到处理器中
+    ///
+    /// 不指定PCID（使用PCID 0），总是刷新TLB。切换到用户地址空间请使用`UserMapper::make_current`
     unsafe fn set_table(_table_kind: PageTableKind, table: PhysAddr) {
+        Self::set_table_pcid(table, 0);
+    }
+
+    /// 获取最近一次缺页异常的线性地址（CR2）
+    unsafe fn fault_address() -> VirtAddr {
+        let vaddr: usize;
         compiler_fence(Ordering::SeqCst);
-        asm!("mov cr3, {}", in(reg) table.data(), options(nostack, preserves_flags));
+        asm!("mov {}, cr2", out(reg) vaddr, options(nomem, nostack, preserves_flags));
         compiler_fence(Ordering::SeqCst);
+        return VirtAddr::new(vaddr);
     }
 
     /// @brief 判断虚拟地址是否合法
@@ -190,10 +1161,12 @@ impl MemoryManagementArch for X86_64MMArch {
     }
 
     /// 获取内存管理初始化时，创建的第一个内核页表的地址
+    ///
+    /// ## Panic
+    ///
+    /// 如果内存管理尚未初始化（初始页表还没有被创建），会panic
     fn initial_page_table() -> PhysAddr {
-        unsafe {
-            return INITIAL_CR3_VALUE;
-        }
+        return *INITIAL_CR3_VALUE.get();
     }
 
     /// @brief 创建新的顶层页表
@@ -201,18 +1174,29 @@ impl MemoryManagementArch for X86_64MMArch {
     /// 该函数会创建页表并复制内核的映射到新的页表中
     ///
     /// @return 新的页表
-    fn setup_new_usermapper() -> Result<crate::mm::ucontext::UserMapper, SystemError> {
+    fn setup_new_usermapper() -> Result<crate::mm::ucontext::UserMapper, MmError> {
         let new_umapper: crate::mm::page::PageMapper<X86_64MMArch, LockedFrameAllocator> = unsafe {
-            PageMapper::create(PageTableKind::User, LockedFrameAllocator)
-                .ok_or(SystemError::ENOMEM)?
+            PageMapper::create(PageTableKind::User, LockedFrameAllocator).ok_or(
+                MmError::OutOfMemory {
+                    needed: PageFrameCount::new(1),
+                },
+            )?
         };
 
         let current_ktable: KernelMapper = KernelMapper::lock();
-        let copy_mapping = |pml4_entry_no| unsafe {
+        let copy_mapping = |pml4_entry_no: usize| unsafe {
             let entry: PageEntry<X86_64MMArch> = current_ktable
                 .table()
                 .entry(pml4_entry_no)
-                .unwrap_or_else(|| panic!("entry {} not found", pml4_entry_no));
+                .unwrap_or_else(|| {
+                    // 打印这个PML4页表项所覆盖的范围中的映射，便于排查
+                    let span =
+                        MMArch::PAGE_SIZE << ((MMArch::PAGE_LEVELS - 1) * MMArch::PAGE_ENTRY_SHIFT);
+                    let base = VirtAddr::new(MMArch::PAGE_NEGATIVE_MASK | (pml4_entry_no * span));
+                    // 最后一个页表项覆盖到地址空间的末尾，结束地址不能用VirtAddr表示，因此范围不包括最后一个字节（不影响输出）
+                    dump_page_table(current_ktable.as_ref(), base..base + (span - 1));
+                    panic!("entry {} not found", pml4_entry_no)
+                });
             new_umapper.table().set_entry(pml4_entry_no, entry)
         };
 
@@ -221,61 +1205,679 @@ impl MemoryManagementArch for X86_64MMArch {
             copy_mapping(pml4_entry_no);
         }
 
-        return Ok(crate::mm::ucontext::UserMapper::new(new_umapper));
+        return Ok(crate::mm::ucontext::UserMapper::new(new_umapper).with_pcid(alloc_pcid()));
+    }
+
+    /// XD位被保留时，不可执行标志会被丢弃，所有的页面都是可执行的
+    fn can_enforce_wx() -> bool {
+        return !Self::is_xd_reserved();
+    }
+
+    fn encrypt_mask() -> usize {
+        return mem_encrypt_mask();
     }
 }
 
 impl X86_64MMArch {
     unsafe fn init_memory_area_from_multiboot2() -> Result<usize, SystemError> {
         // 这个数组用来存放内存区域的信息（从C获取）
-        let mut mb2_mem_info: [multiboot_mmap_entry_t; 512] = mem::zeroed();
+        let mut mb2_mem_info: [multiboot_mmap_entry_t; MB2_MMAP_BUFFER_ENTRIES] = mem::zeroed();
         c_uart_send_str(0x3f8, "init_memory_area_from_multiboot2 begin\n\0".as_ptr());
 
-        let mut mb2_count: u32 = 0;
+        // 传入缓冲区的容量，返回multiboot2提供的内存区域的总数
+        let mut mb2_count: u32 = MB2_MMAP_BUFFER_ENTRIES as u32;
         multiboot2_iter(
             Some(multiboot2_get_memory),
-            &mut mb2_mem_info as *mut [multiboot_mmap_entry_t; 512] as usize as *mut c_void,
+            &mut mb2_mem_info as *mut [multiboot_mmap_entry_t; MB2_MMAP_BUFFER_ENTRIES] as usize
+                as *mut c_void,
             &mut mb2_count,
         );
         c_uart_send_str(0x3f8, "init_memory_area_from_multiboot2 2\n\0".as_ptr());
 
-        let mb2_count = mb2_count as usize;
+        let mb2_total = mb2_count as usize;
+        if unlikely(mb2_total > MB2_MMAP_BUFFER_ENTRIES) {
+            boot_uart_warn(format_args!(
+                "multiboot2 memory map truncated: {} entries provided, only {} fit in the buffer, {} entries dropped",
+                mb2_total,
+                MB2_MMAP_BUFFER_ENTRIES,
+                mb2_total - MB2_MMAP_BUFFER_ENTRIES
+            ));
+        }
+        let mb2_count = core::cmp::min(mb2_total, MB2_MMAP_BUFFER_ENTRIES);
+
         let mut areas_count = 0usize;
-        let mut total_mem_size = 0usize;
+        let mut reserved_count = 0usize;
+        // 由于RESERVED_MEMORY_AREAS容量不足而被丢弃的保留区域
+        let mut lost_reserved = 0usize;
+        // 由于PHYS_MEMORY_AREAS容量不足而被丢弃的内存区域
+        let mut lost_areas = 0usize;
+        let mut lost_bytes = 0usize;
+        // 由于基地址或者大小没有按页对齐而被裁剪掉的内存
+        let mut trimmed_bytes = 0usize;
         for i in 0..mb2_count {
+            // 记录固件保留的区域，便于通过reserved_regions审计
+            if let Some(kind) = reserved_kind_of_mmap_type(mb2_mem_info[i].type_) {
+                reserved_region_add(
+                    PhysMemoryArea {
+                        base: PhysAddr::new(mb2_mem_info[i].addr as usize),
+                        size: mb2_mem_info[i].len as usize,
+                    },
+                    kind,
+                );
+            }
+            // 保留的、ACPI可回收的以及ACPI NVS区域单独记录（供之后解析ACPI表、避免映射保留的MMIO使用）
+            if matches!(mb2_mem_info[i].type_, 2..=4) && mb2_mem_info[i].len != 0 {
+                let area = PhysMemoryArea {
+                    base: PhysAddr::new(mb2_mem_info[i].addr as usize),
+                    size: mb2_mem_info[i].len as usize,
+                };
+                match push_memory_area(&mut RESERVED_MEMORY_AREAS, reserved_count, area) {
+                    Ok(count) => reserved_count = count,
+                    Err(count) => {
+                        reserved_count = count;
+                        lost_reserved += 1;
+                    }
+                }
+                continue;
+            }
             // Only use the memory area if its type is 1 (RAM)
             if mb2_mem_info[i].type_ == 1 {
                 // Skip the memory area if its len is 0
                 if mb2_mem_info[i].len == 0 {
                     continue;
                 }
-                total_mem_size += mb2_mem_info[i].len as usize;
-                PHYS_MEMORY_AREAS[areas_count].base = PhysAddr::new(mb2_mem_info[i].addr as usize);
-                PHYS_MEMORY_AREAS[areas_count].size = mb2_mem_info[i].len as usize;
+                let raw = PhysMemoryArea {
+                    base: PhysAddr::new(mb2_mem_info[i].addr as usize),
+                    size: mb2_mem_info[i].len as usize,
+                };
+                if unlikely(raw.base.data().checked_add(raw.size).is_none()) {
+                    kwarn!(
+                        "memory area {:?} (+{:#x}) overflows the address space, truncated",
+                        raw.base,
+                        raw.size
+                    );
+                }
+                // 区域可能是乱序、相邻或者重叠的，先原样记录，全部收集之后再排序、合并。
+                // 数组已满时，先合并已经收集的区域，腾出空间
+                match push_memory_area(&mut PHYS_MEMORY_AREAS, areas_count, raw) {
+                    Ok(count) => areas_count = count,
+                    Err(count) => {
+                        areas_count = count;
+                        lost_areas += 1;
+                        lost_bytes += raw.end() - raw.base.data();
+                    }
+                }
+            }
+        }
+
+        // 合并之后，再把区域裁剪为完整的页（分配器以页为粒度管理内存）。
+        // 先合并再裁剪，相邻区域之间不按页对齐的边界不会损失内存
+        let merged = merge_memory_areas(&mut PHYS_MEMORY_AREAS[..areas_count]);
+
+        // 固件报告的RAM可能与保留的区域重叠，重叠的部分不能交给分配器。
+        // 挖去之后再裁剪为完整的页，与保留区域共享的页也不会被使用
+        let reserved_count = merge_memory_areas(&mut RESERVED_MEMORY_AREAS[..reserved_count]);
+        RESERVED_MEMORY_AREAS_COUNT.store(reserved_count, Ordering::SeqCst);
+        let ram_bytes = |areas: &[PhysMemoryArea]| areas.iter().map(|a| a.size).sum::<usize>();
+        let before = ram_bytes(&PHYS_MEMORY_AREAS[..merged]);
+        let merged = subtract_memory_areas(
+            &mut PHYS_MEMORY_AREAS,
+            merged,
+            &RESERVED_MEMORY_AREAS[..reserved_count],
+        );
+        let carved = before - ram_bytes(&PHYS_MEMORY_AREAS[..merged]);
+        if unlikely(carved != 0) {
+            kwarn!(
+                "{} bytes of RAM overlap reserved memory areas and will not be used",
+                carved
+            );
+        }
+        if unlikely(lost_reserved != 0) {
+            boot_uart_warn(format_args!(
+                "RESERVED_MEMORY_AREAS is full (capacity {}): {} reserved areas dropped",
+                MAX_RESERVED_MEMORY_AREAS, lost_reserved
+            ));
+        }
+
+        areas_count = 0;
+        let mut total_mem_size = 0usize;
+        for i in 0..merged {
+            let raw = PHYS_MEMORY_AREAS[i];
+            let area = raw.page_trimmed();
+            trimmed_bytes += raw.size - area.map_or(0, |a| a.size);
+            if let Some(area) = area {
+                total_mem_size += area.size;
+                PHYS_MEMORY_AREAS[areas_count] = area;
                 areas_count += 1;
             }
         }
+        if trimmed_bytes != 0 {
+            kwarn!(
+                "{} bytes of memory lost to trimming unaligned memory areas to page granularity",
+                trimmed_bytes
+            );
+        }
+        if unlikely(lost_areas != 0) {
+            boot_uart_warn(format_args!(
+                "PHYS_MEMORY_AREAS is full (capacity {}): {} usable areas dropped, {} bytes ({} MB) of memory lost",
+                MAX_PHYS_MEMORY_AREAS,
+                lost_areas,
+                lost_bytes,
+                lost_bytes / 1024 / 1024
+            ));
+        }
         c_uart_send_str(0x3f8, "init_memory_area_from_multiboot2 end\n\0".as_ptr());
-        kinfo!("Total memory size: {} MB, total areas from multiboot2: {mb2_count}, valid areas: {areas_count}", total_mem_size / 1024 / 1024);
+        kinfo!("Total memory size: {} MB, total areas from multiboot2: {mb2_total}, valid areas: {areas_count}", total_mem_size / 1024 / 1024);
 
         return Ok(areas_count);
     }
 
+    /// 处理`mem=`启动参数：把可用内存的总大小限制在指定的大小以内，优先裁剪地址最高的区域
+    ///
+    /// 内核镜像以及initrd所在的内存不会被裁剪。为了让内核能够完成初始化，
+    /// 上限至少比这些内存的结束地址多`MEM_LIMIT_MIN_FREE`字节
+    ///
+    /// ## 返回值
+    ///
+    /// 裁剪之后的区域数量
+    unsafe fn init_mem_limit(areas: &mut [PhysMemoryArea], info: &X86_64MMBootstrapInfo) -> usize {
+        let mut cmdline = [0u8; BOOT_CMDLINE_MAX];
+        let requested = match boot_cmdline_param(read_boot_cmdline(&mut cmdline), "mem") {
+            Some(value) => match parse_mem_size(value) {
+                Some(limit) => limit,
+                None => {
+                    kwarn!("Invalid boot parameter mem={}, ignored", value);
+                    return areas.len();
+                }
+            },
+            None => return areas.len(),
+        };
+
+        let mut keep_below = info.kernel_phys_end().map_or(0, |end| end.data());
+        if let Some((start, size)) = Self::multiboot2_initrd() {
+            keep_below = core::cmp::max(keep_below, start.data() + size);
+        }
+        let limit = core::cmp::max(requested, keep_below + MEM_LIMIT_MIN_FREE);
+        if limit != requested {
+            kwarn!(
+                "mem={:#x} is too small, raised to {:#x} to keep the kernel bootable",
+                requested,
+                limit
+            );
+        }
+
+        let count = cap_memory_areas(areas, limit, PhysAddr::new(keep_below));
+        let total: usize = areas[0..count].iter().map(|area| area.size).sum();
+        MEM_LIMIT.store(limit, Ordering::SeqCst);
+        kinfo!(
+            "Memory limited by mem= to {} MB: {} MB usable in {} areas",
+            limit / 1024 / 1024,
+            total / 1024 / 1024,
+            count
+        );
+        return count;
+    }
+
+    /// 从multiboot2的模块标签中获取initrd的物理地址范围（起始地址，大小）
+    ///
+    /// 第一个模块被当作initrd
+    unsafe fn multiboot2_initrd() -> Option<(PhysAddr, usize)> {
+        let module = multiboot2_modules().next()?;
+        if module.size() == 0 {
+            return None;
+        }
+        return Some((module.start(), module.size()));
+    }
+
+    /// 从multiboot2的模块标签中获取initrd的物理地址范围，并记录下来
+    ///
+    /// initrd所在的页帧会在`allocator_init`中被保留（不会被bump分配器或者伙伴分配器分配出去）
+    unsafe fn init_initrd_from_multiboot2(areas: &[PhysMemoryArea]) {
+        let (start, size) = match Self::multiboot2_initrd() {
+            Some(initrd) => initrd,
+            None => return,
+        };
+        // initrd必须完整地位于某一个RAM区域中，否则无法通过直接映射区域访问，也不能归还给页帧分配器
+        let in_ram = areas
+            .iter()
+            .any(|area| start >= area.base && start.data() + size <= area.base.data() + area.size);
+        if !in_ram {
+            boot_uart_warn(format_args!(
+                "initrd {:?}+{:#x} is not in RAM, ignored",
+                start, size
+            ));
+            return;
+        }
+        initrd_reserve(start, size).ok();
+    }
+
+    /// 在1MB以下的RAM中，为AP处理器的启动代码（smp/apu_boot.S）保留`AP_TRAMPOLINE_PAGES`个页帧
+    ///
+    /// 这些页帧位于内核镜像之前，因此不会被bump分配器或者伙伴分配器分配出去（见`allocator_init`中的检查）。
+    /// 为了远离BIOS数据区，优先选择1MB以下最高的可用地址
+    unsafe fn init_ap_trampoline(areas: &[PhysMemoryArea]) {
+        let size = AP_TRAMPOLINE_PAGES * MMArch::PAGE_SIZE;
+        let initrd = initrd_frames();
+        let base = areas
+            .iter()
+            .filter_map(|area| {
+                let end = core::cmp::min(area.base.data() + area.size, AP_TRAMPOLINE_LIMIT);
+                let base = end.checked_sub(size)? & !(MMArch::PAGE_SIZE - 1);
+                if base < area.base.data() || base < AP_TRAMPOLINE_MIN {
+                    return None;
+                }
+                // 不能与initrd重叠
+                if let Some((initrd_base, initrd_count)) = initrd {
+                    if base < initrd_base.data() + initrd_count.bytes()
+                        && initrd_base.data() < base + size
+                    {
+                        return None;
+                    }
+                }
+                return Some(base);
+            })
+            .max();
+
+        match base {
+            Some(base) => {
+                AP_TRAMPOLINE_FRAME.init(PhysAddr::new(base));
+                reserved_region_add(
+                    PhysMemoryArea {
+                        base: PhysAddr::new(base),
+                        size,
+                    },
+                    ReservedKind::ApTrampoline,
+                );
+                kmem_stat_add(
+                    KernelMemPurpose::Reserved,
+                    PageFrameCount::new(AP_TRAMPOLINE_PAGES),
+                );
+            }
+            None if cfg!(feature = "smp_ap_bringup") => {
+                panic!(
+                    "No usable RAM below {:#x} for the AP boot trampoline ({} pages)",
+                    AP_TRAMPOLINE_LIMIT, AP_TRAMPOLINE_PAGES
+                );
+            }
+            None => {
+                boot_uart_warn(format_args!(
+                    "No usable RAM below {:#x} for the AP boot trampoline",
+                    AP_TRAMPOLINE_LIMIT
+                ));
+            }
+        }
+    }
+
     fn init_xd_rsvd() {
         // 读取ia32-EFER寄存器的值
         let efer: EferFlags = x86_64::registers::model_specific::Efer::read();
         if !efer.contains(EferFlags::NO_EXECUTE_ENABLE) {
             // NO_EXECUTE_ENABLE是false，那么就设置xd_reserved为true
-            kdebug!("NO_EXECUTE_ENABLE is false, set XD_RESERVED to true");
+            // 之后生成页表项时，不可执行标志会被丢弃（参见`PageFlags::to_entry_bits_at`）
+            kwarn!("NO_EXECUTE_ENABLE is false, XD bit is reserved: all pages are executable, W^X cannot be enforced");
             XD_RESERVED.store(true, Ordering::Relaxed);
         }
         compiler_fence(Ordering::SeqCst);
     }
 
+    fn init_cet_ss() {
+        // 最大的基本CPUID叶小于7时，处理器不支持CET
+        let supported = cpuid!(0).eax >= 7 && cpuid!(7, 0).ecx & (1 << 7) != 0;
+        CET_SS_SUPPORTED.store(supported, Ordering::Relaxed);
+    }
+
+    fn init_huge_page_support() {
+        // 最大的扩展CPUID叶小于80000001H时，处理器不支持1G的大页
+        let supported =
+            cpuid!(0x8000_0000).eax >= 0x8000_0001 && cpuid!(0x8000_0001).edx & (1 << 26) != 0;
+        HUGE_PAGE_1G_SUPPORTED.store(supported, Ordering::Relaxed);
+    }
+
+    fn init_sse2() {
+        let supported = cpuid!(1).edx & (1 << 26) != 0;
+        SSE2_SUPPORTED.store(supported, Ordering::Relaxed);
+    }
+
+    /// 获取处理器支持的大页的大小（在内存管理初始化时，通过CPUID检测一次）
+    ///
+    /// 所有使用大页的代码（直接映射区域、透明大页、DMA分配器等）都应当使用这个函数，而不是自己执行CPUID
+    pub fn huge_page_support() -> HugePageSupport {
+        return HugePageSupport {
+            // 长模式要求开启PAE，因此总是支持2M的大页
+            supports_2m: true,
+            supports_1g: HUGE_PAGE_1G_SUPPORTED.load(Ordering::Relaxed),
+        };
+    }
+
+    /// 读取IA32_PAT寄存器，获取8个PAT表项的缓存类型
+    pub fn pat_entries() -> [CacheType; 8] {
+        return Self::pat_decode(unsafe { rdmsr(IA32_PAT) });
+    }
+
+    /// 解析IA32_PAT寄存器的值（每个表项占8位，只有低3位有效）
+    ///
+    /// UC-在没有MTRR的情况下等同于UC，因此也被解析为`CacheType::Uncacheable`
+    pub fn pat_decode(pat: u64) -> [CacheType; 8] {
+        let mut entries = [CacheType::Uncacheable; 8];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            *entry = match (pat >> (i * 8)) & 0x7 {
+                1 => CacheType::WriteCombining,
+                4 => CacheType::WriteThrough,
+                5 => CacheType::WriteProtected,
+                6 => CacheType::WriteBack,
+                // 0：UC；7：UC-；2、3为保留的编码
+                _ => CacheType::Uncacheable,
+            };
+        }
+        return entries;
+    }
+
+    /// 判断是否可以使用CET影子栈的页表编码
+    ///
+    /// 需要处理器支持CET影子栈，并且CR4.CET已经被开启。否则，“只读且脏”的页表项只是普通的只读页面
+    pub fn shadow_stack_enabled() -> bool {
+        if !CET_SS_SUPPORTED.load(Ordering::Relaxed) {
+            return false;
+        }
+        return unsafe { cr4() }.bits() & CR4_CET != 0;
+    }
+
     /// 判断XD标志位是否被保留
     pub fn is_xd_reserved() -> bool {
         return XD_RESERVED.load(Ordering::Relaxed);
     }
+
+    /// 从缺页异常的陷阱帧中获取错误码（与`fault_address`获取的CR2一起描述这次缺页异常）
+    pub fn fault_error_code(regs: &pt_regs) -> X86PageFaultErrorCode {
+        return X86PageFaultErrorCode::decode(regs.errcode);
+    }
+
+    /// 获取当前CPU上，最近一次通过`set_table`加载的顶级页表的物理地址
+    ///
+    /// 与`table()`不同，这个函数不会读取CR3寄存器
+    ///
+    /// ## 返回值
+    ///
+    /// 如果当前CPU上还没有通过`set_table`切换过页表，返回None
+    pub fn current_table_phys() -> Option<PhysAddr> {
+        let cpu_id = smp_get_processor_id() as usize;
+        let paddr = ACTIVE_TABLE.get(cpu_id)?.load(Ordering::Relaxed);
+        if paddr == 0 {
+            return None;
+        }
+        return Some(PhysAddr::new(paddr));
+    }
+
+    /// 检测处理器是否支持PCID（CPUID.01H:ECX[17]），支持时在当前CPU上开启CR4.PCIDE
+    ///
+    /// 不支持时不做任何事情，切换页表总是刷新TLB
+    pub fn init_pcid() -> bool {
+        return init_pcid();
+    }
+
+    /// 设置顶级页表的物理地址到处理器中，并使用pcid标记它在TLB中的条目
+    ///
+    /// 如果当前CPU上pcid的条目仍然有效，设置CR3的第63位，切换时不刷新TLB。
+    /// pcid为0、或者当前CPU上没有开启PCID时，与`set_table`相同
+    pub unsafe fn set_table_pcid(table: PhysAddr, pcid: usize) {
+        let cpu_id = smp_get_processor_id() as usize;
+        // 如果记录的值与CR3不一致，说明有代码绕过了set_table直接写入了CR3
+        debug_assert!(
+            Self::current_table_phys()
+                .map_or(true, |active| active == Self::table(PageTableKind::User)),
+            "set_table: CR3 {:?} was changed behind the back of the active table tracker ({:?})",
+            Self::table(PageTableKind::User),
+            Self::current_table_phys()
+        );
+        // 在计算CR3的值和写入CR3之间，不能被刷新TLB的IPI打断
+        let irq_guard = CurrentIrqArch::save_and_disable_irq();
+        let cr3 = pcid_switch_cr3(table, pcid);
+        compiler_fence(Ordering::SeqCst);
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+        compiler_fence(Ordering::SeqCst);
+        drop(irq_guard);
+        if let Some(active) = ACTIVE_TABLE.get(cpu_id) {
+            active.store(table.data(), Ordering::Relaxed);
+        }
+    }
+
+    /// 重新写入CR3，刷新当前CPU上的TLB（不受`with_tlb_invalidate_suppressed`的影响）
+    unsafe fn flush_tlb_local() {
+        let irq_guard = CurrentIrqArch::save_and_disable_irq();
+        compiler_fence(Ordering::SeqCst);
+        let cr3 = pcid_flush_current();
+        asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+        compiler_fence(Ordering::SeqCst);
+        shadow_tlb_invalidate(None);
+        drop(irq_guard);
+    }
+
+    /// 刷新TLB中，PCID为pcid的地址空间中关于vaddr的条目（这个地址空间不需要是当前的地址空间）
+    ///
+    /// 处理器支持INVPCID时使用INVPCID，否则：pcid是当前的PCID时使用`invlpg`，
+    /// 不是当前的PCID时，使当前CPU上这个PCID的所有条目在切换到它时刷新。
+    /// 当前CPU上没有开启PCID时，TLB中只有当前地址空间的条目，等同于`invalidate_page`
+    pub unsafe fn invalidate_page_pcid(pcid: usize, vaddr: VirtAddr) {
+        if tlb_invalidate_suppressed() {
+            return;
+        }
+        if !pcid_enabled() {
+            Self::invalidate_page(vaddr);
+            return;
+        }
+        compiler_fence(Ordering::SeqCst);
+        if invpcid_supported() {
+            // INVPCID的描述符：[PCID, 线性地址]；类型0表示刷新单个地址
+            let descriptor: [u64; 2] = [pcid as u64, vaddr.data() as u64];
+            asm!("invpcid {0}, [{1}]", in(reg) 0usize, in(reg) &descriptor, options(nostack, preserves_flags));
+        } else if pcid == current_pcid() {
+            asm!("invlpg [{0}]", in(reg) vaddr.data(), options(nostack, preserves_flags));
+        } else {
+            pcid_mark_stale(pcid);
+        }
+        compiler_fence(Ordering::SeqCst);
+        pcid_note_invalidate(pcid, vaddr);
+        shadow_tlb_invalidate(Some((vaddr, 1)));
+    }
+
+    /// 获取可用的物理内存（RAM）区域
+    pub fn phys_memory_areas() -> &'static [PhysMemoryArea] {
+        let count = PHYS_MEMORY_AREAS_COUNT.load(Ordering::SeqCst);
+        return unsafe { &PHYS_MEMORY_AREAS[0..count] };
+    }
+
+    /// 获取multiboot2报告的保留（类型2）、ACPI可回收（类型3）以及ACPI NVS（类型4）的内存区域
+    ///
+    /// 区域按基地址排序并且互不重叠，不会与`phys_memory_areas`返回的区域重叠
+    pub fn reserved_areas() -> &'static [PhysMemoryArea] {
+        let count = RESERVED_MEMORY_AREAS_COUNT.load(Ordering::SeqCst);
+        return unsafe { &RESERVED_MEMORY_AREAS[0..count] };
+    }
+
+    /// 获取可用的物理内存（RAM）的总大小（字节），受`mem=`启动参数的限制
+    pub fn total_ram_bytes() -> usize {
+        return Self::phys_memory_areas().iter().map(|area| area.size).sum();
+    }
+
+    /// 获取`mem=`启动参数所设置的（生效的）可用内存上限。如果没有设置，返回None
+    pub fn mem_limit() -> Option<usize> {
+        let limit = MEM_LIMIT.load(Ordering::SeqCst);
+        if limit == 0 {
+            return None;
+        }
+        return Some(limit);
+    }
+
+    /// 获取直接映射区域周围的保护区域
+    ///
+    /// ## 返回值
+    ///
+    /// 返回(上方的保护区域, 下方的保护区域)。如果直接映射区域尚未建立，或者没有启用下方的保护页，对应的项为None
+    pub fn direct_map_guard_ranges() -> (Option<Range<VirtAddr>>, Option<Range<VirtAddr>>) {
+        let top = DIRECT_MAP_TOP.load(Ordering::SeqCst);
+        let high = if top != 0 {
+            Some(VirtAddr::new(top)..VirtAddr::new(top + DIRECT_MAP_GUARD_PAGES * Self::PAGE_SIZE))
+        } else {
+            None
+        };
+        let low = if DIRECT_MAP_LOW_GUARD {
+            Some(
+                VirtAddr::new(Self::PHYS_OFFSET - Self::PAGE_SIZE)
+                    ..VirtAddr::new(Self::PHYS_OFFSET),
+            )
+        } else {
+            None
+        };
+        return (high, low);
+    }
+
+    /// 判断虚拟地址是否位于直接映射区域周围的保护区域内
+    pub fn is_direct_map_guard(vaddr: VirtAddr) -> bool {
+        let (high, low) = Self::direct_map_guard_ranges();
+        return high.map_or(false, |r| r.contains(&vaddr))
+            || low.map_or(false, |r| r.contains(&vaddr));
+    }
+
+    /// 获取处理器支持的物理地址位数（MAXPHYADDR）
+    pub fn phys_address_bits() -> usize {
+        return CpuId::new()
+            .get_processor_capacity_feature_info()
+            .map(|info| info.physical_address_bits() as usize)
+            // 不支持该CPUID叶的处理器，物理地址位数为36
+            .unwrap_or(36);
+    }
+
+    /// 选择清零count个页帧时使用的方法：较大的范围使用非临时存储，避免把即将被覆盖的数据填满缓存；
+    /// 较小的范围使用普通的存储，因为它们很可能马上就会被访问
+    pub fn zero_method(count: PageFrameCount) -> ZeroMethod {
+        if count.data() >= ZERO_NT_THRESHOLD && SSE2_SUPPORTED.load(Ordering::Relaxed) {
+            return ZeroMethod::NonTemporal;
+        }
+        return ZeroMethod::Temporal;
+    }
+
+    /// 清零从paddr开始的count个页帧，根据`zero_method`选择清零的方法
+    ///
+    /// ## 返回值
+    ///
+    /// 实际使用的方法
+    pub unsafe fn zero_frames(paddr: PhysAddr, count: PageFrameCount) -> ZeroMethod {
+        let method = Self::zero_method(count);
+        match method {
+            ZeroMethod::NonTemporal => Self::zero_frames_nt(paddr, count),
+            ZeroMethod::Temporal => {
+                Self::write_bytes(Self::phys_2_virt(paddr).unwrap(), 0, count.bytes())
+            }
+        }
+        return method;
+    }
+
+    /// 使用非临时存储（movnti）清零从paddr开始的count个页帧，不会污染缓存。
+    /// 如果处理器不支持SSE2，则退化为普通的存储
+    pub unsafe fn zero_frames_nt(paddr: PhysAddr, count: PageFrameCount) {
+        let vaddr = Self::phys_2_virt(paddr).unwrap();
+        if !SSE2_SUPPORTED.load(Ordering::Relaxed) {
+            Self::write_bytes(vaddr, 0, count.bytes());
+            return;
+        }
+
+        let mut addr = vaddr.data();
+        let end = addr + count.bytes();
+        // 每次写入一个缓存行（64字节）
+        while addr < end {
+            asm!(
+                "movnti [{0}], {1}",
+                "movnti [{0} + 8], {1}",
+                "movnti [{0} + 16], {1}",
+                "movnti [{0} + 24], {1}",
+                "movnti [{0} + 32], {1}",
+                "movnti [{0} + 40], {1}",
+                "movnti [{0} + 48], {1}",
+                "movnti [{0} + 56], {1}",
+                in(reg) addr,
+                in(reg) 0usize,
+                options(nostack, preserves_flags)
+            );
+            addr += 64;
+        }
+        // 非临时存储是弱序的，需要sfence保证之后的存储不会越过它们
+        asm!("sfence", options(nostack, preserves_flags));
+    }
+
+    /// 把一段虚拟地址所在的缓存行写回内存并使其失效（clflush）
+    ///
+    /// 修改页面的缓存类型（比如从WB改为UC）之后，需要调用这个函数，避免缓存中残留旧的数据
+    pub unsafe fn clflush_range(vaddr: VirtAddr, size: usize) {
+        let line = CpuId::new()
+            .get_feature_info()
+            .map(|info| info.cflush_cache_line_size() as usize * 8)
+            .filter(|line| *line != 0)
+            .unwrap_or(64);
+        let start = vaddr.data() & !(line - 1);
+        for addr in (start..vaddr.data() + size).step_by(line) {
+            asm!("clflush [{0}]", in(reg) addr, options(nostack, preserves_flags));
+        }
+        asm!("mfence", options(nostack, preserves_flags));
+    }
+
+    /// 获取内存管理初始化时，创建的第一个内核页表的地址
+    ///
+    /// ## 返回值
+    ///
+    /// 如果初始页表还没有被创建，返回None（而不是一个为0的物理地址）
+    pub fn try_initial_page_table() -> Option<PhysAddr> {
+        return INITIAL_CR3_VALUE.try_get().copied();
+    }
+
+    /// 获取当前CPU的GS基址（IA32_GS_BASE）
+    pub fn percpu_base() -> VirtAddr {
+        return VirtAddr::new(unsafe { rdmsr(IA32_GS_BASE) } as usize);
+    }
+
+    /// 设置当前CPU的GS基址（IA32_GS_BASE），使得`gs:[offset]`指向这个CPU的每CPU数据
+    ///
+    /// ## Safety
+    ///
+    /// 调用者需要保证base是`percpu_unit_base(当前CPU的id)`，并且当前没有代码依赖于原来的GS基址
+    pub unsafe fn set_percpu_base(base: VirtAddr) {
+        wrmsr(IA32_GS_BASE, base.data() as u64);
+    }
+
+    /// 判断CR0.WP（内核态写保护）是否处于开启状态
+    pub fn wp_enabled() -> bool {
+        return unsafe { cr0() }.contains(Cr0::CR0_WRITE_PROTECT);
+    }
+
+    /// 在关闭CR0.WP（内核态写保护）、并且关中断的情况下执行闭包，执行完成后恢复原来的WP状态。
+    ///
+    /// 关闭WP之后，内核可以写入所有只读的页面，这是非常危险的操作。
+    /// 如果只是要修改某个只读页面，应当优先使用`KernelMapper::with_writable_alias`，
+    /// 只有在无法使用别名映射的情况下（比如启动早期），才使用这个方法。
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回闭包的返回值
+    /// - `EFAULT`：闭包自己修改了CR0.WP，或者执行完成后WP没有被恢复为原来的状态
+    pub unsafe fn with_wp_disabled<R>(f: impl FnOnce() -> R) -> Result<R, SystemError> {
+        let irq_guard = CurrentIrqArch::save_and_disable_irq();
+        let old = cr0();
+        cr0_write(old - Cr0::CR0_WRITE_PROTECT);
+        compiler_fence(Ordering::SeqCst);
+
+        let r = f();
+
+        compiler_fence(Ordering::SeqCst);
+        // 闭包不应该修改CR0.WP
+        let tampered = Self::wp_enabled();
+        cr0_write(old);
+        let restored = Self::wp_enabled() == old.contains(Cr0::CR0_WRITE_PROTECT);
+        drop(irq_guard);
+        if tampered || !restored {
+            kerror!("with_wp_disabled: WP state is tampered or not restored");
+            return Err(SystemError::EFAULT);
+        }
+        return Ok(r);
+    }
 }
 
 impl VirtAddr {
@@ -313,20 +1915,111 @@ pub fn mm_init() {
 
     // 初始化内存管理器
     unsafe { allocator_init() };
-    // enable mmio
+    // 新的内核页表已经被激活，全局分配器可以从伙伴分配器中分配内存了
+    early_heap_handoff();
+    frame_tag_init();
+    // 检查表需要根据伙伴分配器的空闲链表初始化，因此在持有伙伴分配器的锁时进行
+    if let Some(allocator) = lock_inner_allocator().as_mut() {
+        free_check_init(allocator);
+    }
+    // 填充BSP的紧急页帧池
+    emergency_refill();
+    scrub_init_from_cmdline();
+    huge_pool_init_from_cmdline();
+    // enable mmio（必须在allocator_init之后调用）
     mmio_init();
+    cma_init();
+    percpu_area_init();
+    trampoline_area_init();
+    fixmap_init();
+    zero_frame_init();
+    // 新的内核页表已经被激活，之后被忽略的刷新器需要被检查
+    flush_mark_boot_complete();
     // 启用printk的alloc选项
     PrintkWriter.enable_alloc();
 }
 
 unsafe fn allocator_init() {
-    let virt_offset = BOOTSTRAP_MM_INFO.unwrap().start_brk;
+    let info = BOOTSTRAP_MM_INFO.unwrap();
+    let kernel_phys_end = info.kernel_phys_end().unwrap_or_else(|| {
+        panic!(
+            "allocator_init: kernel end (start_brk={:#x}) is not in the direct map (PHYS_OFFSET={:#x}), please check the linker script",
+            info.start_brk,
+            MMArch::PHYS_OFFSET
+        )
+    });
+    let virt_offset = info.start_brk;
     let phy_offset =
         unsafe { MMArch::virt_2_phys(VirtAddr::new(page_align_up(virt_offset))) }.unwrap();
+    // bump分配器的起始位置必须在内核镜像之后，否则会把内核镜像所在的物理页分配出去
+    if phy_offset < kernel_phys_end
+        || info
+            .kernel_phys_start()
+            .map_or(true, |start| phy_offset <= start)
+    {
+        panic!(
+            "allocator_init: bump allocator start {:?} overlaps the kernel image (kernel phys: {:?} - {:?})",
+            phy_offset,
+            info.kernel_phys_start(),
+            kernel_phys_end
+        );
+    }
+
+    // AP处理器的启动代码所在的页帧位于bump分配器的起始位置之前，因此不会被分配出去。
+    // 启动代码通过低地址的映射被复制过去，并在AP处理器切换到长模式时使用
+    if let Some(trampoline) = AP_TRAMPOLINE_FRAME.try_get() {
+        let trampoline_end = trampoline.data() + AP_TRAMPOLINE_PAGES * MMArch::PAGE_SIZE;
+        assert!(
+            trampoline_end <= phy_offset.data(),
+            "allocator_init: AP trampoline {:?} overlaps the bump allocator (start: {:?})",
+            trampoline,
+            phy_offset
+        );
+        assert!(
+            !LowAddressRemapping::enabled() || trampoline_end <= LowAddressRemapping::REMAP_SIZE,
+            "allocator_init: AP trampoline {:?} is not covered by the low-address remap",
+            trampoline
+        );
+    }
+
+    // 内核镜像所占用的内存
+    let kernel_phys_start = info.kernel_phys_start().unwrap();
+    let kernel_size = page_align_up(kernel_phys_end.data() - kernel_phys_start.data());
+    kmem_stat_add(
+        KernelMemPurpose::Reserved,
+        PageFrameCount::new(kernel_size / MMArch::PAGE_SIZE),
+    );
+    reserved_region_add(
+        PhysMemoryArea {
+            base: kernel_phys_start,
+            size: kernel_size,
+        },
+        ReservedKind::KernelImage,
+    );
+
+    // bootloader通常把initrd放在内核镜像之后，也就是bump分配器的起始位置。
+    // 这种情况下，bump分配器需要从initrd之后开始分配，initrd之前的空隙在伙伴分配器初始化之后再归还
+    let initrd = initrd_frames();
+    let mut bump_start = phy_offset;
+    let mut initrd_gap: Option<(PhysAddr, PageFrameCount)> = None;
+    if let Some((initrd_base, initrd_count)) = initrd {
+        let initrd_end = initrd_base + initrd_count.bytes();
+        if initrd_end > phy_offset {
+            if initrd_base > phy_offset {
+                initrd_gap = Some((
+                    phy_offset,
+                    PageFrameCount::new(
+                        (initrd_base.data() - phy_offset.data()) / MMArch::PAGE_SIZE,
+                    ),
+                ));
+            }
+            bump_start = initrd_end;
+        }
+    }
 
     kdebug!("PhysArea[0..10] = {:?}", &PHYS_MEMORY_AREAS[0..10]);
     let mut bump_allocator =
-        BumpAllocator::<X86_64MMArch>::new(&PHYS_MEMORY_AREAS, phy_offset.data());
+        BumpAllocator::<X86_64MMArch>::new(&PHYS_MEMORY_AREAS, bump_start.data());
     kdebug!(
         "BumpAllocator created, offset={:?}",
         bump_allocator.offset()
@@ -341,12 +2034,22 @@ unsafe fn allocator_init() {
     // 使用bump分配器，把所有的内存页都映射到页表
     {
         // 用bump allocator创建新的页表
+        let bump_offset = bump_allocator.offset();
+        let table_stats_before = PageTableAllocStats::snapshot();
         let mut mapper: crate::mm::page::PageMapper<MMArch, &mut BumpAllocator<MMArch>> =
             crate::mm::page::PageMapper::<MMArch, _>::create(
                 PageTableKind::Kernel,
                 &mut bump_allocator,
             )
-            .expect("Failed to create page mapper");
+            .unwrap_or_else(|| {
+                boot_mm_fail(
+                    "create kernel page table",
+                    MmError::OutOfMemory {
+                        needed: PageFrameCount::new(1),
+                    },
+                    bump_offset,
+                )
+            });
         new_page_table = mapper.table().phys();
         kdebug!("PageMapper created");
 
@@ -355,42 +2058,125 @@ unsafe fn allocator_init() {
             let table = mapper.table();
             let empty_entry = PageEntry::<MMArch>::new(0);
             for i in 0..MMArch::PAGE_ENTRY_NUM {
-                table
-                    .set_entry(i, empty_entry)
-                    .expect("Failed to empty page table entry");
+                if table.set_entry(i, empty_entry).is_none() {
+                    boot_mm_fail(
+                        "empty kernel page table",
+                        MmError::KernelRangeViolation(table.base()),
+                        mapper.allocator_ref().offset(),
+                    );
+                }
             }
         }
         kdebug!("Successfully emptied page table");
 
+        // 新的页表尚未被激活，所有的修改都记录在这里，最后统一忽略
+        let mut flush_batch = FlushBatch::<MMArch>::new();
+        let page_table_frames_before = kmem_stat_get(KernelMemPurpose::PageTable);
         for area in PHYS_MEMORY_AREAS.iter() {
             // kdebug!("area: base={:?}, size={:#x}, end={:?}", area.base, area.size, area.base + area.size);
-            for i in 0..((area.size + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE) {
-                let paddr = area.base.add(i * MMArch::PAGE_SIZE);
-                let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+            let count = PageFrameCount::new(page_align_up(area.size) / MMArch::PAGE_SIZE);
+            let vbase = unsafe { MMArch::phys_2_virt(area.base) }.unwrap();
+            // 区域中按2M对齐的部分使用2M的大页映射，首尾不对齐的部分使用4K页
+            let mut offset = 0;
+            while offset < count.bytes() {
+                let (vaddr, paddr) = (vbase + offset, area.base + offset);
                 let flags = kernel_page_flags::<MMArch>(vaddr);
+                let size = direct_map_page_size(vaddr, paddr, count.bytes() - offset, flags);
+
+                match mapper.map_phys_huge(vaddr, paddr, flags, size) {
+                    Ok(flusher) => flush_batch.consume(flusher),
+                    // 固件提供的内存区域可能互相重叠，重叠的部分已经被前面的区域映射过了
+                    Err(MmError::AlreadyMapped(_)) => {}
+                    Err(e) => {
+                        boot_mm_fail("map physical memory", e, mapper.allocator_ref().offset())
+                    }
+                }
+                offset += size.bytes::<MMArch>();
+            }
+        }
 
-                let flusher = mapper
-                    .map_phys(vaddr, paddr, flags)
-                    .expect("Failed to map frame");
-                // 暂时不刷新TLB
-                flusher.ignore();
+        // 确保最高的RAM地址上方的若干页没有被映射，作为直接映射区域的保护页
+        let ram_top = PHYS_MEMORY_AREAS
+            .iter()
+            .filter(|area| area.size != 0)
+            .map(|area| page_align_up(area.base.data() + area.size))
+            .max()
+            .unwrap_or(0);
+        let guard_base = unsafe { MMArch::phys_2_virt(PhysAddr::new(ram_top)) }.unwrap();
+        for i in 0..DIRECT_MAP_GUARD_PAGES {
+            let vaddr = guard_base + i * MMArch::PAGE_SIZE;
+            if mapper.translate(vaddr).is_some() {
+                if let Ok((_, _, flusher)) = unsafe { mapper.unmap_phys(vaddr, false) } {
+                    flush_batch.consume(flusher);
+                }
             }
         }
+        DIRECT_MAP_TOP.store(guard_base.data(), Ordering::SeqCst);
+
+        // 检查伙伴分配器将会接管的第一个页帧已经被新的页表映射。这个页帧只是临时从bump分配器中取出的，
+        // 检查之后回退，它仍然会交给伙伴分配器
+        let scratch = mapper.allocator_ref().checkpoint();
+        if let Some(frame) = mapper.allocator_mut().allocate_one() {
+            let vaddr = MMArch::phys_2_virt(frame).unwrap();
+            if mapper.translate(vaddr).map(|(paddr, _)| paddr) != Some(frame) {
+                boot_mm_fail(
+                    "verify direct map",
+                    MmError::NotMapped(vaddr),
+                    mapper.allocator_ref().offset(),
+                );
+            }
+        }
+        mapper.allocator_mut().rewind(scratch);
+
+        // 把建立直接映射区域时所分配的页表，统计到直接映射区域的开销中
+        let direct_map_frames =
+            kmem_stat_get(KernelMemPurpose::PageTable) - page_table_frames_before;
+        kmem_stat_sub(KernelMemPurpose::PageTable, direct_map_frames);
+        kmem_stat_add(KernelMemPurpose::DirectMap, direct_map_frames);
+        log_direct_map_overhead(&PageTableAllocStats::snapshot().since(&table_stats_before));
+        log_direct_map_huge_opportunities(&direct_map_huge_opportunities(
+            X86_64MMArch::phys_memory_areas(),
+        ));
 
         // 添加低地址的映射（在smp完成初始化之前，需要使用低地址的映射.初始化之后需要取消这一段映射）
-        LowAddressRemapping::remap_at_low_address(&mut mapper);
-    }
+        #[cfg(not(feature = "no_low_remap"))]
+        LowAddressRemapping::remap_at_low_address(&mut mapper, &mut flush_batch);
 
-    unsafe {
-        INITIAL_CR3_VALUE = new_page_table;
+        // 暂时不刷新TLB（新的页表会在稍后通过写入CR3的方式被激活）
+        flush_batch.ignore();
     }
+
+    INITIAL_CR3_VALUE.init(new_page_table);
     kdebug!(
         "After mapping all physical memory, DragonOS used: {} KB",
         bump_allocator.offset() / 1024
     );
 
+    // CMA区域需要在伙伴分配器接管剩余的内存之前，从bump分配器中取出
+    cma_reserve_from_cmdline(&mut bump_allocator);
+
     // 初始化buddy_allocator
-    let buddy_allocator = unsafe { BuddyAllocator::<X86_64MMArch>::new(bump_allocator).unwrap() };
+    let mut buddy_allocator =
+        unsafe { BuddyAllocator::<X86_64MMArch>::new(bump_allocator).unwrap() };
+
+    // initrd所在的页帧以“已分配”的状态交给伙伴分配器，在release_initrd时释放
+    if let Some((_, initrd_count)) = initrd {
+        buddy_allocator.adopt_reserved(initrd_count);
+    }
+    // initrd之前的空隙直接归还给伙伴分配器（跳过不属于RAM的空洞）
+    if let Some((gap_base, gap_count)) = initrd_gap {
+        for i in 0..gap_count.data() {
+            let paddr = gap_base + i * MMArch::PAGE_SIZE;
+            let in_ram = PHYS_MEMORY_AREAS.iter().any(|area| {
+                paddr >= area.base
+                    && paddr.data() + MMArch::PAGE_SIZE <= area.base.data() + area.size
+            });
+            if in_ram {
+                buddy_allocator.adopt_reserved(PageFrameCount::new(1));
+                unsafe { buddy_allocator.free(paddr, PageFrameCount::new(1)) };
+            }
+        }
+    }
 
     // 设置全局的页帧分配器
     unsafe { set_inner_allocator(buddy_allocator) };
@@ -428,42 +2214,281 @@ unsafe fn allocator_init() {
     kdebug!("Text UI enabled");
 }
 
+/// @brief 判断发生缺页异常的地址是否位于直接映射区域的保护区域内
+///
+/// 供C语言的缺页异常处理函数使用，以便报告“direct-map overrun”
 #[no_mangle]
-pub extern "C" fn rs_test_buddy() {
-    test_buddy();
+pub extern "C" fn rs_mm_is_direct_map_guard(vaddr: u64) -> bool {
+    return X86_64MMArch::is_direct_map_guard(VirtAddr::new(vaddr as usize));
 }
-pub fn test_buddy() {
-    // 申请内存然后写入数据然后free掉
-    // 总共申请200MB内存
-    const TOTAL_SIZE: usize = 200 * 1024 * 1024;
 
-    for i in 0..10 {
-        kdebug!("Test buddy, round: {i}");
-        // 存放申请的内存块
-        let mut v: Vec<(PhysAddr, PageFrameCount)> = Vec::with_capacity(60 * 1024);
-        // 存放已经申请的内存块的地址（用于检查重复）
-        let mut addr_set: HashSet<PhysAddr> = HashSet::new();
+/// @brief 记录帧缓冲区所占用的物理内存（供C语言的帧缓冲区初始化函数使用）
+#[no_mangle]
+pub extern "C" fn rs_mm_reserve_framebuffer(paddr: u64, size: u64) {
+    reserved_region_add(
+        PhysMemoryArea {
+            base: PhysAddr::new(paddr as usize),
+            size: page_align_up(size as usize),
+        },
+        ReservedKind::Framebuffer,
+    );
+}
 
-        let mut allocated = 0usize;
+/// @brief 检查发生缺页异常的地址是否位于某个内核栈的保护页内，如果是，则以“kernel stack overflow”panic
+///
+/// 供C语言的缺页异常处理函数使用（只对内核态的缺页异常调用）
+#[no_mangle]
+pub extern "C" fn rs_mm_check_stack_overflow(vaddr: u64, rsp: u64) {
+    if let Some(g) = classify_stack_fault(VirtAddr::new(vaddr as usize)) {
+        panic!(
+            "kernel stack overflow: pid {} overflowed the stack at {:?} (fault address: {:#x}, rsp: {:#x}, guard: {:?}..{:?})",
+            g.pid, g.stack, vaddr, rsp, g.guard.start, g.guard.end
+        );
+    }
+}
 
-        let mut free_count = 0usize;
+/// @brief 检查发生缺页异常的地址是否位于当前地址空间的用户栈的保护页内，如果是，则报告用户栈溢出
+///
+/// 供C语言的缺页异常处理函数使用（只对用户态的缺页异常调用）。之后的处理与其他缺页异常相同
+#[no_mangle]
+pub extern "C" fn rs_mm_check_user_stack_overflow(vaddr: u64, rsp: u64) {
+    let table = unsafe { MMArch::table(PageTableKind::User) };
+    if let Some(g) = classify_user_stack_fault(table, VirtAddr::new(vaddr as usize)) {
+        kerror!(
+            "user stack overflow: the stack at {:?} overflowed (fault address: {:#x}, rsp: {:#x}, guard: {:?}..{:?})",
+            g.stack, vaddr, rsp, g.guard.start, g.guard.end
+        );
+    }
+}
 
-        while allocated < TOTAL_SIZE {
-            let mut random_size = 0u64;
-            unsafe { x86::random::rdrand64(&mut random_size) };
-            // 一次最多申请4M
-            random_size = random_size % (1024 * 4096);
-            if random_size == 0 {
-                continue;
-            }
-            let random_size =
-                core::cmp::min(page_align_up(random_size as usize), TOTAL_SIZE - allocated);
-            let random_size = PageFrameCount::from_bytes(random_size.next_power_of_two()).unwrap();
+/// @brief 分配一个带保护页的内核栈（参见`alloc_kernel_stack`）
+///
+/// ## 返回值
+///
+/// 栈顶的虚拟地址。如果分配失败，返回0，调用者可以退化为使用kmalloc分配的栈
+#[no_mangle]
+pub extern "C" fn rs_alloc_kernel_stack(pid: i64) -> u64 {
+    return match alloc_kernel_stack(pid) {
+        Ok(top) => top.data() as u64,
+        Err(e) => {
+            kwarn!("Failed to allocate a guarded kernel stack: {:?}", e);
+            0
+        }
+    };
+}
+
+/// @brief 用文字打印缺页异常的错误码，比如“write to non-present user page”
+///
+/// 供C语言的缺页异常处理函数使用。描述直接被格式化到日志中，不会分配内存
+#[no_mangle]
+pub extern "C" fn rs_mm_report_fault_error_code(error_code: u64) {
+    let code = X86PageFaultErrorCode::decode(error_code);
+    kerror!("Page fault: {} (error code: {:#x})", code, error_code);
+    if code.is_corrupted_table() {
+        kerror!(
+            "Page fault: a page table entry has reserved bits set, the page table may be corrupted"
+        );
+    }
+}
+
+/// @brief 打印与发生缺页异常的地址最近的已映射区域，便于发现越界访问
+///
+/// 供C语言的缺页异常处理函数使用。对于用户空间的地址，如果当前进程的地址空间正在被修改（锁被占用），则不打印
+#[no_mangle]
+pub extern "C" fn rs_mm_report_nearest_mappings(vaddr: u64) {
+    let vaddr = VirtAddr::new(vaddr as usize);
+    let (below, above) = if vaddr < MMArch::USER_END_VADDR {
+        let space = match current_pcb().address_space() {
+            Some(space) => space,
+            None => return,
+        };
+        let guard = match space.try_read() {
+            Some(guard) => guard,
+            None => return,
+        };
+        guard.user_mapper.nearest_mappings(vaddr)
+    } else if vaddr.data() >= MMArch::PHYS_OFFSET {
+        KernelMapper::lock().nearest_mappings(vaddr)
+    } else {
+        return;
+    };
+    kerror!(
+        "Nearest mappings of {:?}: below ends at {:?}, above starts at {:?}",
+        vaddr,
+        below,
+        above
+    );
+}
+
+/// @brief 处理用户态对已映射的只读页面的写入（写时复制、共享零页）
+///
+/// 供C语言的缺页异常处理函数使用。内核态写入用户缓冲区（copy_to_user等）触发的缺页异常也会进入这里，
+/// 而这里需要获取当前地址空间的写锁，因此内核不能在持有当前地址空间的锁时写入可能只读映射的用户页面，
+/// 否则会在这里死锁。目前持有写锁写入用户内存的路径只有ELF的加载（`load_elf_segment`、清零BSS）
+/// 和execve中把参数压入用户栈，它们写入的都是刚刚以可写权限映射、并且已经分配了私有页帧的页面，不会触发这个缺页异常
+///
+/// @return 0表示缺页异常已经被处理，可以返回重新执行；-1表示应当向进程发送SIGSEGV（或者地址不是用户地址）
+#[no_mangle]
+pub extern "C" fn rs_handle_user_write_fault(vaddr: u64) -> i32 {
+    if VirtAddr::new(vaddr as usize) >= MMArch::USER_END_VADDR {
+        return -1;
+    }
+    let space = match current_pcb().address_space() {
+        Some(space) => space,
+        None => return -1,
+    };
+    let r = space
+        .write()
+        .user_mapper
+        .handle_write_fault(VirtAddr::new(vaddr as usize));
+    match r {
+        Ok(WriteFaultOutcome::CowResolved)
+        | Ok(WriteFaultOutcome::ZeroPageReplaced)
+        | Ok(WriteFaultOutcome::Spurious) => return 0,
+        Ok(outcome) => {
+            kerror!("write fault at {:#x}: {:?}, SIGSEGV", vaddr, outcome);
+            return -1;
+        }
+        Err(e) => {
+            kerror!("write fault at {:#x}: {:?}", vaddr, e);
+            return -1;
+        }
+    }
+}
+
+/// @brief 补充当前CPU的紧急页帧池
+///
+/// 供C语言的缺页异常处理函数在处理完用户态的缺页异常之后使用：此时被中断的是用户态，没有持有内核中的锁
+#[no_mangle]
+pub extern "C" fn rs_emergency_refill() {
+    emergency_refill();
+}
+
+/// idle循环每次最多清零的页帧数量，避免长时间推迟对中断的响应
+const SCRUB_IDLE_BATCH: usize = 16;
+
+/// @brief idle循环在每次hlt之前调用，完成内存管理的后台工作（后台清零、补充大页池）
+#[no_mangle]
+pub extern "C" fn rs_mm_idle_work() {
+    if scrub_enabled() {
+        scrub_some(SCRUB_IDLE_BATCH);
+    }
+    // 清零一个大页的耗时较长，每次只补充一个块
+    if huge_pool_size() > 0 {
+        huge_pool_refill(1);
+    }
+}
+
+/// @brief 低地址的重映射是否被建立（AP处理器的启动依赖于它）
+#[no_mangle]
+pub extern "C" fn rs_low_remap_enabled() -> bool {
+    return LowAddressRemapping::enabled();
+}
+
+/// @brief 在AP处理器上开启PCID（CR4是每个CPU独立的）
+#[no_mangle]
+pub extern "C" fn rs_init_pcid_ap() {
+    X86_64MMArch::init_pcid();
+}
+
+/// TLB shootdown的序号，每次同步的刷新（`tlb_shootdown_sync`）都会使它加1
+static TLB_SHOOTDOWN_GEN: AtomicUsize = AtomicUsize::new(0);
+const TLB_SHOOTDOWN_ACK_INIT: AtomicUsize = AtomicUsize::new(0);
+/// 每个CPU已经完成的TLB shootdown的序号
+static TLB_SHOOTDOWN_ACKS: [AtomicUsize; PerCpu::MAX_CPU_NUM] =
+    [TLB_SHOOTDOWN_ACK_INIT; PerCpu::MAX_CPU_NUM];
+
+/// 刷新当前CPU上的TLB，并确认在此之前发出的TLB shootdown
+///
+/// 序号需要在刷新之前读取：被确认的序号对应的页表修改一定发生在这次刷新之前
+fn tlb_shootdown_ack() {
+    let gen = TLB_SHOOTDOWN_GEN.load(Ordering::SeqCst);
+    unsafe { X86_64MMArch::flush_tlb_local() };
+    let cpu_id = smp_get_processor_id() as usize;
+    if cpu_id < PerCpu::MAX_CPU_NUM {
+        TLB_SHOOTDOWN_ACKS[cpu_id].fetch_max(gen, Ordering::SeqCst);
+    }
+}
+
+/// 向其他CPU发送刷新TLB的IPI，并等待它们全部完成刷新
+///
+/// 与直接发送IPI不同，返回之后其他CPU上一定不再有修改之前的TLB条目，因此调用者可以立即重用被替换的页帧，
+/// 或者依赖被收紧的权限。只有一个CPU时什么也不做。
+///
+/// 等待的过程中，当前CPU也会确认其他CPU发出的shootdown，因此两个CPU在关中断的情况下互相等待不会死锁
+pub fn tlb_shootdown_sync() {
+    let cpus = (unsafe { smp_get_total_cpu() } as usize).min(PerCpu::MAX_CPU_NUM);
+    if cpus <= 1 {
+        return;
+    }
+    let gen = TLB_SHOOTDOWN_GEN.fetch_add(1, Ordering::SeqCst) + 1;
+    send_ipi(IpiKind::FlushTLB, IpiTarget::Other);
+
+    let cpu_id = smp_get_processor_id() as usize;
+    for cpu in (0..cpus).filter(|cpu| *cpu != cpu_id) {
+        while TLB_SHOOTDOWN_ACKS[cpu].load(Ordering::SeqCst) < gen {
+            if TLB_SHOOTDOWN_ACKS[cpu_id].load(Ordering::Relaxed)
+                < TLB_SHOOTDOWN_GEN.load(Ordering::SeqCst)
+            {
+                tlb_shootdown_ack();
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// @brief 处理刷新TLB的IPI
+///
+/// 发送IPI的CPU修改的可能是任意一个地址空间，因此开启PCID时，这个CPU上其他PCID的条目在切换到它们时也会被刷新。
+/// 刷新之后确认shootdown（参见`tlb_shootdown_sync`）
+#[no_mangle]
+pub extern "C" fn rs_flush_tlb_ipi() {
+    tlb_shootdown_ack();
+}
+
+/// @brief 获取初始的内核页表的物理地址（供AP处理器启动时使用）
+///
+/// 如果初始页表还没有被创建，返回0
+#[no_mangle]
+pub extern "C" fn rs_initial_page_table() -> u64 {
+    return X86_64MMArch::try_initial_page_table().map_or(0, |paddr| paddr.data() as u64);
+}
+
+#[no_mangle]
+pub extern "C" fn rs_test_buddy() {
+    test_buddy();
+}
+pub fn test_buddy() {
+    // 申请内存然后写入数据然后free掉
+    // 总共申请200MB内存
+    const TOTAL_SIZE: usize = 200 * 1024 * 1024;
+
+    for i in 0..10 {
+        kdebug!("Test buddy, round: {i}");
+        // 存放申请的内存块
+        let mut v: Vec<(PhysAddr, PageFrameCount)> = Vec::with_capacity(60 * 1024);
+        // 存放已经申请的内存块的地址（用于检查重复）
+        let mut addr_set: HashSet<PhysAddr> = HashSet::new();
+
+        let mut allocated = 0usize;
+
+        let mut free_count = 0usize;
+
+        while allocated < TOTAL_SIZE {
+            let mut random_size = random_u64();
+            // 一次最多申请4M
+            random_size = random_size % (1024 * 4096);
+            if random_size == 0 {
+                continue;
+            }
+            let random_size =
+                core::cmp::min(page_align_up(random_size as usize), TOTAL_SIZE - allocated);
+            let random_size = PageFrameCount::from_bytes(random_size.next_power_of_two()).unwrap();
             // 获取帧
             let (paddr, allocated_frame_count) =
                 unsafe { LockedFrameAllocator.allocate(random_size).unwrap() };
             assert!(allocated_frame_count.data().is_power_of_two());
-            assert!(paddr.data() % MMArch::PAGE_SIZE == 0);
+            assert!(paddr.data() % LockedFrameAllocator::alignment_of(random_size) == 0);
             unsafe {
                 assert!(MMArch::phys_2_virt(paddr)
                     .as_ref()
@@ -488,8 +2513,7 @@ pub fn test_buddy() {
 
             // 随机释放一个内存块
             if v.len() > 0 {
-                let mut random_index = 0u64;
-                unsafe { x86::random::rdrand64(&mut random_index) };
+                let mut random_index = random_u64();
                 // 70%概率释放
                 if random_index % 10 > 7 {
                     continue;
@@ -500,6 +2524,9 @@ pub fn test_buddy() {
                 assert!(addr_set.remove(&paddr));
                 unsafe { LockedFrameAllocator.free(paddr, allocated_frame_count) };
                 free_count += allocated_frame_count.data() * MMArch::PAGE_SIZE;
+                if cfg!(feature = "buddy_verify") {
+                    LockedFrameAllocator.verify_invariants();
+                }
             }
         }
 
@@ -516,6 +2543,9 @@ pub fn test_buddy() {
             unsafe { LockedFrameAllocator.free(paddr, allocated_frame_count) };
             assert!(addr_set.remove(&paddr));
             free_count += allocated_frame_count.data() * MMArch::PAGE_SIZE;
+            if cfg!(feature = "buddy_verify") {
+                LockedFrameAllocator.verify_invariants();
+            }
         }
 
         kdebug!("release done!, allocated: {allocated}, free_count: {free_count}");
@@ -525,16 +2555,544 @@ pub fn test_buddy() {
 #[derive(Debug, Clone, Copy, Hash)]
 pub struct LockedFrameAllocator;
 
+impl LockedFrameAllocator {
+    /// 全局页帧分配器是否已经初始化（`allocator_init`是否已经设置了伙伴分配器）
+    ///
+    /// 依赖页帧分配器的子系统（比如mmio）在初始化时用它检查初始化的顺序
+    pub fn is_initialized() -> bool {
+        return INNER_ALLOCATOR_READY.load(Ordering::SeqCst);
+    }
+
+    /// 低水位线：总页帧数的1/128。空闲页帧数低于这个值时，只有带有CRITICAL标志的分配请求才能成功
+    const LOW_WATERMARK_SHIFT: usize = 7;
+
+    /// 计算低水位线（保留给关键分配的页帧数量）
+    fn low_watermark(total: PageFrameCount) -> PageFrameCount {
+        return PageFrameCount::new(total.data() >> Self::LOW_WATERMARK_SHIFT);
+    }
+
+    /// 打印分配失败的警告信息
+    ///
+    /// 由于打印信息本身也需要分配内存，因此需要防止在打印过程中，再次因为分配失败而递归地打印
+    fn warn_alloc_failure(args: core::fmt::Arguments) {
+        static WARNING: AtomicBool = AtomicBool::new(false);
+        if WARNING.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        kwarn!("{}", args);
+        WARNING.store(false, Ordering::SeqCst);
+    }
+
+    /// 根据分配标志，分配count个页帧
+    ///
+    /// ## 参数
+    ///
+    /// - `count`：要分配的页帧数量
+    /// - `flags`：分配标志
+    /// - `tag`：分配标签（启用`frame_tag`特性时，用于统计泄漏的页帧属于哪个子系统）
+    ///
+    /// ## 返回值
+    ///
+    /// 分配成功时，返回页帧的起始物理地址以及实际分配的页帧数量，否则返回None
+    pub unsafe fn allocate_flags(
+        &mut self,
+        count: PageFrameCount,
+        flags: AllocFlags,
+        tag: FrameTag,
+    ) -> Option<(PhysAddr, PageFrameCount)> {
+        // 优先使用已经被后台清零的页帧，从而跳过清零的步骤。
+        // 链表中的页帧在伙伴分配器看来是已使用的，因此同样需要遵守低水位线
+        if count.data() == 1
+            && flags.contains(AllocFlags::ZERO)
+            && !flags.contains(AllocFlags::DMA32)
+            && (flags.contains(AllocFlags::CRITICAL) || Self::above_low_watermark(count))
+        {
+            // 缓存中的页帧可能在等待隔离（参见`mm::quarantine`），此时跳过它
+            while let Some(paddr) = scrub_pop_clean() {
+                if quarantine_intercept(paddr) {
+                    continue;
+                }
+                frame_tag_set(paddr, count, tag);
+                return Some((paddr, count));
+            }
+        }
+
+        // 单页的请求优先从当前CPU的页帧缓存中分配，不需要获取全局分配器的锁。
+        // 启用了低阶页帧池时，缓存从池中补充（参见`warmup_cpu`）
+        if count.data() == 1 && !flags.contains(AllocFlags::DMA32) {
+            if let Some(paddr) = self.frame_cache_alloc() {
+                if flags.contains(AllocFlags::ZERO) {
+                    MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0, MMArch::PAGE_SIZE);
+                }
+                frame_tag_set(paddr, count, tag);
+                return Some((paddr, count));
+            }
+        }
+
+        let mut guard = lock_inner_allocator();
+        let allocator = guard.as_mut()?;
+
+        if !flags.contains(AllocFlags::CRITICAL) {
+            let usage = allocator.usage();
+            let reserve = Self::low_watermark(usage.total());
+            if usage.free().data() < count.data() + reserve.data() {
+                drop(guard);
+                // 每个CPU的缓存中的页帧在伙伴分配器看来是已使用的，把它们归还之后重试
+                if self.drain_frame_caches() > 0 {
+                    return self.allocate_flags(count, flags, tag);
+                }
+                if !flags.contains(AllocFlags::NOWARN) {
+                    Self::warn_alloc_failure(format_args!(
+                        "allocate_flags: free frames below low watermark, count={:?}, free={:?}, reserve={:?}",
+                        count,
+                        usage.free(),
+                        reserve
+                    ));
+                }
+                return None;
+            }
+        }
+
+        let free_before = allocator.usage().free();
+        let r = allocator.allocate(count);
+        let free_after = allocator.usage().free();
+        drop(guard);
+        // 释放锁之后再调用内存压力的回调函数，回调函数中可以再次分配页帧
+        low_memory_check(free_before, free_after);
+
+        // 伙伴分配器中没有空闲页帧的时候，回收等待清零的页帧
+        let r = r.or_else(|| {
+            if count.data() == 1 {
+                core::iter::from_fn(scrub_pop_any)
+                    .find(|paddr| !quarantine_intercept(*paddr))
+                    .map(|paddr| (paddr, count))
+            } else {
+                None
+            }
+        });
+
+        let (paddr, allocated) = match r {
+            Some(r) => r,
+            None => {
+                if self.drain_frame_caches() > 0 {
+                    return self.allocate_flags(count, flags, tag);
+                }
+                // 关键的单页分配最后使用当前CPU的紧急页帧池
+                if count.data() == 1
+                    && !flags.contains(AllocFlags::DMA32)
+                    && flags.contains(AllocFlags::CRITICAL)
+                {
+                    if let Some(paddr) = emergency_alloc() {
+                        if flags.contains(AllocFlags::ZERO) {
+                            MMArch::zero_frames(paddr, count);
+                        }
+                        frame_tag_set(paddr, count, tag);
+                        return Some((paddr, count));
+                    }
+                }
+                if !flags.contains(AllocFlags::NOWARN) {
+                    Self::warn_alloc_failure(format_args!(
+                        "allocate_flags: out of memory, count={:?}",
+                        count
+                    ));
+                }
+                return None;
+            }
+        };
+
+        // 伙伴分配器不区分内存区域，因此如果分配到的页帧不满足DMA32的要求，只能归还并返回失败
+        if flags.contains(AllocFlags::DMA32) && paddr.data() + allocated.bytes() > (1usize << 32) {
+            self.free(paddr, allocated);
+            if !flags.contains(AllocFlags::NOWARN) {
+                Self::warn_alloc_failure(format_args!(
+                    "allocate_flags: no frames below 4GB, count={:?}",
+                    count
+                ));
+            }
+            return None;
+        }
+
+        if flags.contains(AllocFlags::ZERO) {
+            MMArch::zero_frames(paddr, allocated);
+        }
+
+        frame_tag_set(paddr, allocated, tag);
+        return Some((paddr, allocated));
+    }
+
+    /// 判断分配count个页帧之后，伙伴分配器中的空闲页帧是否仍然不低于低水位线
+    fn above_low_watermark(count: PageFrameCount) -> bool {
+        return match lock_inner_allocator().as_ref() {
+            Some(allocator) => {
+                let usage = unsafe { allocator.usage() };
+                usage.free().data() >= count.data() + Self::low_watermark(usage.total()).data()
+            }
+            None => false,
+        };
+    }
+
+    /// 分配count个已经清零的页帧
+    ///
+    /// 如果启用了后台清零，单页的分配会优先使用已经清零的页帧
+    pub unsafe fn allocate_zeroed(
+        &mut self,
+        count: PageFrameCount,
+    ) -> Option<(PhysAddr, PageFrameCount)> {
+        return self.allocate_flags(count, AllocFlags::ZERO, FRAME_TAG_UNTAGGED);
+    }
+
+    /// 把页帧直接归还给伙伴分配器（不经过后台清零的链表）
+    pub unsafe fn free_to_buddy(&mut self, address: PhysAddr, count: PageFrameCount) {
+        let count = Self::checked_free_count(address, count);
+        frame_tag_clear(address, count);
+        // 块中有等待隔离的页帧时，逐页释放其余的页帧（伙伴分配器会重新合并它们）
+        if quarantine_pending_in(address, count) {
+            for i in 0..count.data() {
+                let paddr = address + i * MMArch::PAGE_SIZE;
+                if !quarantine_intercept(paddr) {
+                    if let Some(ref mut allocator) = *lock_inner_allocator() {
+                        allocator.free(paddr, PageFrameCount::new(1));
+                    }
+                }
+            }
+            return;
+        }
+        if let Some(ref mut allocator) = *lock_inner_allocator() {
+            allocator.free(address, count);
+        }
+    }
+
+    /// 把一个空闲的页帧从伙伴分配器中取出，用于隔离出现内存错误的页帧（参见`BuddyAllocator::take_frame`）
+    ///
+    /// ## 返回值
+    ///
+    /// 如果页帧是空闲的，并且已经被取出，返回true
+    pub fn take_free_frame(&mut self, paddr: PhysAddr) -> bool {
+        return lock_inner_allocator()
+            .as_mut()
+            .map_or(false, |allocator| allocator.take_frame(paddr));
+    }
+
+    /// 释放count个页帧
+    ///
+    /// 与`free`不同，如果count不是2的幂，则不会释放任何页帧，而是返回错误
+    ///
+    /// ## 返回值
+    ///
+    /// - `Ok(())`：释放成功
+    /// - `Err(SystemError::EINVAL)`：count不是2的幂
+    pub unsafe fn try_free(
+        &mut self,
+        address: PhysAddr,
+        count: PageFrameCount,
+    ) -> Result<(), SystemError> {
+        if unlikely(!count.data().is_power_of_two()) {
+            return Err(SystemError::EINVAL);
+        }
+        self.free(address, count);
+        return Ok(());
+    }
+
+    /// 检查要释放的页帧数量是否为2的幂
+    ///
+    /// 在debug模式下，如果不是2的幂，则直接panic，以便尽早发现错误的调用者；
+    /// 在release模式下，则向上取整到2的幂（与分配时伙伴分配器的取整方式一致），并打印警告，
+    /// 避免单个有问题的调用者导致整个内核崩溃。
+    fn checked_free_count(address: PhysAddr, count: PageFrameCount) -> PageFrameCount {
+        if likely(count.data().is_power_of_two()) {
+            return count;
+        }
+
+        debug_assert!(
+            false,
+            "free: count is not a power of two: address={:?}, count={}",
+            address,
+            count.data()
+        );
+
+        let rounded = Self::round_free_count(count);
+        kwarn!(
+            "free: count is not a power of two, rounded up: address={:?}, count={}, rounded={}",
+            address,
+            count.data(),
+            rounded.data()
+        );
+        return rounded;
+    }
+
+    /// 把要释放的页帧数量向上取整到2的幂（与分配时伙伴分配器的取整方式一致）
+    ///
+    /// 与`checked_free_count`不同，这里不检查count是否已经是2的幂，在debug模式下也不会panic
+    fn round_free_count(count: PageFrameCount) -> PageFrameCount {
+        return PageFrameCount::new(count.data().next_power_of_two());
+    }
+}
+
+impl LockedFrameAllocator {
+    /// 分配count个页帧，并返回它们的物理地址以及在直接映射区域中的虚拟地址
+    ///
+    /// 请注意，返回的虚拟地址只有在页帧位于直接映射区域所覆盖的物理内存中时才有效。
+    /// （目前所有由伙伴分配器管理的物理内存都位于直接映射区域中）
+    ///
+    /// ## 参数
+    ///
+    /// - `count`：页帧数量
+    /// - `tag`：分配标签（参见`allocate_flags`）
+    ///
+    /// ## 返回值
+    ///
+    /// 分配成功时，返回(物理地址, 虚拟地址)，否则返回None
+    pub unsafe fn allocate_kernel(
+        &mut self,
+        count: PageFrameCount,
+        tag: FrameTag,
+    ) -> Option<(PhysAddr, VirtAddr)> {
+        let (paddr, _) = self.allocate_flags(count, AllocFlags::empty(), tag)?;
+        match MMArch::phys_2_virt(paddr) {
+            Some(vaddr) => return Some((paddr, vaddr)),
+            None => {
+                self.free(paddr, count);
+                return None;
+            }
+        }
+    }
+
+    /// 分配count个页帧，并尽量使得第一个页帧的颜色为color（页着色，用于减少缓存冲突）
+    ///
+    /// 如果找不到颜色匹配的页帧，则退化为普通的分配。详见`BuddyAllocator::allocate_colored`
+    ///
+    /// ## 返回值
+    ///
+    /// 分配成功时，返回页帧的起始物理地址以及实际分配的页帧数量，否则返回None
+    pub fn allocate_colored(
+        &mut self,
+        count: PageFrameCount,
+        color: usize,
+    ) -> Option<(PhysAddr, PageFrameCount)> {
+        let count = PageFrameCount::new(count.data().next_power_of_two());
+        let mut guard = lock_inner_allocator();
+        let (paddr, allocated) = guard.as_mut()?.allocate_colored(count, color)?;
+        drop(guard);
+        frame_tag_set(paddr, allocated, FRAME_TAG_UNTAGGED);
+        return Some((paddr, allocated));
+    }
+
+    /// 释放由`allocate_kernel`分配的页帧
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：页帧在直接映射区域中的虚拟地址
+    /// - `count`：页帧数量（与分配时的数量相同）
+    pub unsafe fn free_kernel(&mut self, vaddr: VirtAddr, count: PageFrameCount) {
+        let paddr =
+            MMArch::virt_2_phys(vaddr).expect("free_kernel: vaddr is not in the direct map");
+        self.free(paddr, count);
+    }
+}
+
+impl LockedFrameAllocator {
+    /// 预热指定CPU的页帧缓存：只获取一次全局分配器的锁，取出count个单页帧放入缓存中
+    ///
+    /// 可以在目标CPU上线之前（由其他CPU）调用。缓存中的页帧在`usage`中被统计为已使用。
+    /// 空闲页帧低于低水位线时，停止预热
+    ///
+    /// 启用了低阶页帧池时，页帧从池中取出（池为空时由池从伙伴分配器中成批补充），
+    /// 使得单页的分配集中在池所分配的少数几个块中
+    ///
+    /// ## 参数
+    ///
+    /// - `cpu_id`：目标CPU的id
+    /// - `count`：要放入缓存的页帧数量（受缓存的剩余容量限制）
+    ///
+    /// ## 返回值
+    ///
+    /// 实际放入缓存的页帧数量
+    pub fn warmup_cpu(&mut self, cpu_id: usize, count: usize) -> usize {
+        let count = core::cmp::min(count, frame_cache_room(cpu_id));
+        if count == 0 {
+            return 0;
+        }
+        if loworder_pool_size() > 0 {
+            return Self::warmup_cpu_from_pool(cpu_id, count);
+        }
+
+        let mut frames = [PhysAddr::new(0); FRAME_CACHE_SIZE];
+        let mut taken = 0;
+        let (free_before, free_after) = {
+            let mut guard = lock_inner_allocator();
+            let allocator = match guard.as_mut() {
+                Some(allocator) => allocator,
+                None => return 0,
+            };
+            let free_before = unsafe { allocator.usage() }.free();
+            let reserve = Self::low_watermark(unsafe { allocator.usage() }.total());
+            while taken < count {
+                if unsafe { allocator.usage() }.free().data() <= reserve.data() {
+                    break;
+                }
+                match unsafe { allocator.allocate(PageFrameCount::new(1)) } {
+                    Some((paddr, _)) => frames[taken] = paddr,
+                    None => break,
+                }
+                taken += 1;
+            }
+            (free_before, unsafe { allocator.usage() }.free())
+        };
+        low_memory_check(free_before, free_after);
+
+        let mut cached = 0;
+        for paddr in frames[..taken].iter() {
+            if frame_cache_push(cpu_id, *paddr) {
+                cached += 1;
+            } else {
+                // 在预热的过程中，缓存被其他CPU填满了
+                unsafe { self.free_to_buddy(*paddr, PageFrameCount::new(1)) };
+            }
+        }
+        return cached;
+    }
+
+    /// 从低阶页帧池中取出count个页帧放入指定CPU的页帧缓存
+    ///
+    /// ## 返回值
+    ///
+    /// 实际放入缓存的页帧数量
+    fn warmup_cpu_from_pool(cpu_id: usize, count: usize) -> usize {
+        let mut cached = 0;
+        while cached < count {
+            let paddr = match loworder_pool_pop() {
+                Some(paddr) => paddr,
+                None => break,
+            };
+            // 池中的页帧可能在等待隔离（参见`mm::quarantine`），此时跳过它
+            if quarantine_intercept(paddr) {
+                continue;
+            }
+            if !frame_cache_push(cpu_id, paddr) {
+                // 在预热的过程中，缓存被其他CPU填满了
+                if !loworder_pool_push(paddr) {
+                    unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+                }
+                break;
+            }
+            cached += 1;
+        }
+        return cached;
+    }
+
+    /// 从当前CPU的页帧缓存中取出一个页帧，缓存为空时先补充`FRAME_CACHE_BATCH`个页帧（参见`warmup_cpu`）
+    ///
+    /// ## 返回值
+    ///
+    /// 缓存为空并且无法补充（空闲页帧低于低水位线）时，返回None
+    fn frame_cache_alloc(&mut self) -> Option<PhysAddr> {
+        let mut refilled = false;
+        loop {
+            match frame_cache_pop() {
+                // 缓存中的页帧可能在等待隔离（参见`mm::quarantine`），此时跳过它
+                Some(paddr) if quarantine_intercept(paddr) => continue,
+                Some(paddr) => return Some(paddr),
+                None if !refilled => {
+                    refilled = true;
+                    // 补充的过程中可能被迁移到其他CPU上，此时页帧只是被放入了原来的CPU的缓存
+                    if self.warmup_cpu(smp_get_processor_id() as usize, FRAME_CACHE_BATCH) == 0 {
+                        return None;
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+
+    /// 把一个被释放的页帧放入当前CPU的页帧缓存。缓存已满时，先把`FRAME_CACHE_BATCH`个页帧归还给全局分配器
+    ///
+    /// ## 返回值
+    ///
+    /// 如果页帧被放入了缓存，返回true
+    fn frame_cache_free(&mut self, paddr: PhysAddr) -> bool {
+        if frame_cache_push_local(paddr) {
+            return true;
+        }
+        preempt_disable();
+        let cpu_id = smp_get_processor_id() as usize;
+        self.flush_frame_cache(cpu_id, FRAME_CACHE_BATCH);
+        let r = frame_cache_push(cpu_id, paddr);
+        preempt_enable();
+        return r;
+    }
+
+    /// 把指定CPU的页帧缓存中最早放入的count个页帧归还给全局分配器（只获取一次全局分配器的锁）
+    ///
+    /// 启用了低阶页帧池时，页帧先放回池中，池已满时才归还给伙伴分配器
+    ///
+    /// ## 参数
+    ///
+    /// - `cpu_id`：CPU的id
+    /// - `count`：要归还的页帧数量
+    ///
+    /// ## 返回值
+    ///
+    /// 从缓存中取出的页帧数量（包括在缓存中等待隔离，因此被隔离的页帧）
+    pub fn flush_frame_cache(&mut self, cpu_id: usize, count: usize) -> usize {
+        let mut frames = [PhysAddr::new(0); FRAME_CACHE_SIZE];
+        let count = core::cmp::min(count, FRAME_CACHE_SIZE);
+        let taken = frame_cache_take(cpu_id, &mut frames[..count]);
+
+        // 在获取全局分配器的锁之前处理等待隔离的页帧
+        let mut to_free = [PhysAddr::new(0); FRAME_CACHE_SIZE];
+        let mut kept = 0;
+        for paddr in frames[..taken].iter() {
+            if !quarantine_intercept(*paddr) && !loworder_pool_push(*paddr) {
+                to_free[kept] = *paddr;
+                kept += 1;
+            }
+        }
+        if kept > 0 {
+            if let Some(ref mut allocator) = *lock_inner_allocator() {
+                for paddr in to_free[..kept].iter() {
+                    unsafe { allocator.free(*paddr, PageFrameCount::new(1)) };
+                }
+            }
+        }
+        return taken;
+    }
+
+    /// 把所有CPU的页帧缓存，以及低阶页帧池中的页帧归还给全局分配器，在内存不足时使用
+    ///
+    /// ## 返回值
+    ///
+    /// 从缓存和池中取出的页帧总数
+    pub fn drain_frame_caches(&mut self) -> usize {
+        let mut drained = 0;
+        if frame_cache_total() > 0 {
+            drained = (0..PerCpu::MAX_CPU_NUM)
+                .map(|cpu_id| self.flush_frame_cache(cpu_id, FRAME_CACHE_SIZE))
+                .sum();
+        }
+        return drained + loworder_pool_drain();
+    }
+
+    /// 获取全局页帧分配器的锁被获取的次数
+    pub fn global_lock_count() -> usize {
+        return INNER_ALLOCATOR_LOCKS.load(Ordering::Relaxed);
+    }
+
+    /// 检查伙伴分配器的不变量（开销很大，只应当在调试时使用）
+    ///
+    /// 详见`BuddyAllocator::verify_invariants`
+    pub fn verify_invariants(&self) {
+        if let Some(ref allocator) = *INNER_ALLOCATOR.lock_irqsave() {
+            allocator.verify_invariants();
+        }
+    }
+}
+
 impl FrameAllocator for LockedFrameAllocator {
     unsafe fn allocate(
         &mut self,
         count: crate::mm::allocator::page_frame::PageFrameCount,
     ) -> Option<(PhysAddr, PageFrameCount)> {
-        if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock_irqsave() {
-            return allocator.allocate(count);
-        } else {
-            return None;
-        }
+        return self.allocate_flags(count, AllocFlags::empty(), FRAME_TAG_UNTAGGED);
     }
 
     unsafe fn free(
@@ -542,29 +3100,399 @@ impl FrameAllocator for LockedFrameAllocator {
         address: crate::mm::PhysAddr,
         count: crate::mm::allocator::page_frame::PageFrameCount,
     ) {
-        assert!(count.data().is_power_of_two());
-        if let Some(ref mut allocator) = *INNER_ALLOCATOR.lock_irqsave() {
-            return allocator.free(address, count);
+        // 如果启用了后台清零，单个页帧先放入待清零的链表
+        if count.data() == 1 {
+            frame_tag_clear(address, count);
+            if quarantine_intercept(address)
+                || scrub_push_dirty(address)
+                || self.frame_cache_free(address)
+            {
+                return;
+            }
         }
+        self.free_to_buddy(address, count);
     }
 
     unsafe fn usage(&self) -> crate::mm::allocator::page_frame::PageFrameUsage {
-        todo!()
+        // 每个CPU的缓存由各自的锁保护，在获取全局分配器的锁之前统计
+        let percpu_cached = PageFrameCount::new(frame_cache_total());
+        if let Some(ref allocator) = *lock_inner_allocator() {
+            let usage = allocator.usage();
+            return usage.with_breakdown(
+                Self::low_watermark(usage.total()),
+                percpu_cached,
+                pinned_frames(),
+            );
+        } else {
+            return PageFrameUsage::new(PageFrameCount::new(0), PageFrameCount::new(0));
+        }
+    }
+
+    fn alignment_of(count: PageFrameCount) -> usize {
+        return BuddyAllocator::<MMArch>::alignment_of(count);
+    }
+}
+
+/// 内核地址空间中，按照用途划分的区域（决定了区域中的页面默认的权限）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelRegion {
+    /// 低地址的恒等映射（AP处理器的启动代码在这里执行，smp初始化完成之后被取消）
+    LowRemap,
+    /// 内核镜像的代码段
+    Text,
+    /// 内核镜像的只读数据段
+    Rodata,
+    /// 内核镜像的`.data.ro_after_init`段（初始化完成之前可写，之后只读）
+    RoAfterInit,
+    /// 直接映射区域中的其他地址（包括内核镜像的数据段以及其他所有的RAM）
+    DirectMap,
+}
+
+impl KernelRegion {
+    /// 获取虚拟地址所在的区域
+    ///
+    /// 内核镜像位于直接映射区域中（KERNEL_VMA == PHYS_OFFSET），因此内核的代码段、只读数据段
+    /// 与它们在直接映射区域中的别名是同一段虚拟地址，只能根据链接脚本中的符号来区分。
+    pub fn of(virt: VirtAddr) -> Self {
+        let info: X86_64MMBootstrapInfo = BOOTSTRAP_MM_INFO.clone().unwrap();
+
+        if virt.data() < LowAddressRemapping::REMAP_SIZE {
+            return Self::LowRemap;
+        } else if virt.data() >= info.kernel_code_start && virt.data() < info.kernel_code_end {
+            return Self::Text;
+        } else if virt.data() >= info.kernel_data_end && virt.data() < info.kernel_rodata_end {
+            return Self::Rodata;
+        } else if virt.data() >= info.ro_after_init_start && virt.data() < info.ro_after_init_end {
+            return Self::RoAfterInit;
+        } else {
+            return Self::DirectMap;
+        }
+    }
+
+    /// 区域中的页面默认的页面标志
+    pub fn flags<A: MemoryManagementArch>(&self) -> PageFlags<A> {
+        match self {
+            // 低地址的映射只在启动阶段存在，AP处理器的启动代码需要在这里执行，并且写入其中的数据
+            Self::LowRemap => PageFlags::new().set_write(true).set_execute(true),
+            Self::Text => PageFlags::new().set_execute(true),
+            Self::Rodata => PageFlags::new(),
+            Self::RoAfterInit => PageFlags::new().set_write(!ro_after_init_sealed()),
+            Self::DirectMap => PageFlags::new().set_write(true),
+        }
     }
 }
 
 /// 获取内核地址默认的页面标志
+///
+/// 只有内核的代码段是可执行的（并且是只读的），只读数据段不可写、不可执行，
+/// 直接映射区域中的其他地址可写、不可执行。参见`KernelRegion`
 pub unsafe fn kernel_page_flags<A: MemoryManagementArch>(virt: VirtAddr) -> PageFlags<A> {
+    return KernelRegion::of(virt).flags();
+}
+
+/// 打印页表中，与虚拟地址范围相交的所有存在的页表项（用于调试错误的映射）
+///
+/// 从PML4开始，逐级（PDPT、PD、PT）向下遍历，通过`kdebug!`输出每一个存在的页表项的名称、下标、所覆盖的虚拟地址、
+/// 物理地址、级别，以及解码之后的标志位（PRESENT、RW、USER、NX、PS）。映射大页的页表项（设置了PS位）不会继续向下遍历。
+/// 范围较大时输出的内容会非常多
+///
+/// ## 参数
+///
+/// - `mapper`：页表映射器
+/// - `range`：虚拟地址范围（规范地址）
+///
+/// ## 返回值
+///
+/// 打印的页表项的数量
+pub fn dump_page_table<F: FrameAllocator>(
+    mapper: &PageMapper<MMArch, F>,
+    range: Range<VirtAddr>,
+) -> usize {
+    kdebug!(
+        "page table {:?}, range {:?}..{:?}:",
+        mapper.table().phys(),
+        range.start,
+        range.end
+    );
+    if range.start >= range.end {
+        return 0;
+    }
+    // 页表中的地址不包含符号扩展的部分
+    let lo = range.start.data() & !MMArch::PAGE_NEGATIVE_MASK;
+    let hi = ((range.end.data() - 1) & !MMArch::PAGE_NEGATIVE_MASK) + 1;
+    let mut printed = 0;
+    if lo < hi {
+        unsafe { dump_table_inner(&mapper.table(), lo, hi, &mut printed) };
+    }
+    return printed;
+}
+
+unsafe fn dump_table_inner(table: &PageTable<MMArch>, lo: usize, hi: usize, printed: &mut usize) {
+    let level = table.level();
+    let size = 1usize << (level * MMArch::PAGE_ENTRY_SHIFT + MMArch::PAGE_SHIFT);
+    for i in 0..MMArch::PAGE_ENTRY_NUM {
+        let base = table.entry_base(i).unwrap().data();
+        if base + size <= lo || base >= hi {
+            continue;
+        }
+        let entry = match table.entry(i) {
+            Some(entry) if entry.present() => entry,
+            _ => continue,
+        };
+        let huge = table.entry_is_huge(i);
+        let mut paddr = entry.data() & MMArch::PAGE_ADDRESS_MASK;
+        if huge {
+            // 大页的PAT位位于地址字段的最低位
+            paddr &= !(size - 1);
+        }
+
+        let names = [
+            (MMArch::ENTRY_FLAG_PRESENT, "PRESENT"),
+            (MMArch::ENTRY_FLAG_READWRITE, "RW"),
+            (MMArch::ENTRY_FLAG_USER, "USER"),
+            (MMArch::ENTRY_FLAG_NO_EXEC, "NX"),
+        ];
+        let mut flags = String::new();
+        for (flag, name) in names.iter() {
+            if entry.data() & flag != 0 {
+                if !flags.is_empty() {
+                    flags.push('|');
+                }
+                flags.push_str(name);
+            }
+        }
+        if huge {
+            flags.push_str("|PS");
+        }
+
+        let vaddr = if base & (MMArch::PAGE_ADDRESS_SIZE >> 1) != 0 {
+            VirtAddr::new(base | MMArch::PAGE_NEGATIVE_MASK)
+        } else {
+            VirtAddr::new(base)
+        };
+        // 上一级的页表项缩进更少
+        kdebug!(
+            "{:indent$}{}[{}] {:?}: phys {:#x}, level {}, flags {}",
+            "",
+            MMArch::PAGE_TABLE_NAMES[level],
+            i,
+            vaddr,
+            paddr,
+            level,
+            flags,
+            indent = (MMArch::PAGE_LEVELS - 1 - level) * 2
+        );
+        *printed += 1;
+
+        if !huge {
+            if let Some(next) = table.next_level_table(i) {
+                dump_table_inner(&next, lo.max(base), hi.min(base + size), printed);
+            }
+        }
+    }
+}
+
+/// 检查内核地址空间中是否存在同时可写、可执行的映射（W^X）
+///
+/// 低地址的恒等映射位于用户地址空间的范围内，不会被检查
+///
+/// ## 返回值
+///
+/// 违反W^X的映射的（起始虚拟地址, 大小）。每一个都会被打印为警告
+pub fn check_kernel_wx() -> Vec<(VirtAddr, usize)> {
+    let wx =
+        KernelMapper::lock().find_wx(VirtAddr::new(MMArch::PHYS_OFFSET)..VirtAddr::new(usize::MAX));
+    for (vaddr, size) in wx.iter() {
+        kwarn!(
+            "W^X violation: {:?} (+{:#x}) is writable and executable",
+            vaddr,
+            size
+        );
+    }
+    return wx;
+}
+
+/// `.data.ro_after_init`段是否已经被重新映射为只读
+static RO_AFTER_INIT_SEALED: AtomicBool = AtomicBool::new(false);
+
+/// `.data.ro_after_init`段是否已经被重新映射为只读（参见`mark_ro_after_init`）
+pub fn ro_after_init_sealed() -> bool {
+    return RO_AFTER_INIT_SEALED.load(Ordering::SeqCst);
+}
+
+/// 把一段内核地址重新映射为只读（保留页面的其他标志）
+///
+/// 先检查范围内所有的页面都已经被映射，再逐页修改。只支持4K页面的映射（内核镜像以4K页面映射）
+///
+/// ## 参数
+///
+/// - `mapper`：页面映射器
+/// - `range`：虚拟地址范围（必须按页对齐）
+///
+/// ## 返回值
+///
+/// - 成功：返回刷新器（调用者负责刷新，必要时还需要通知其他CPU）
+/// - `EINVAL`：范围没有按页对齐
+/// - `EFAULT`：范围内有页面没有被映射
+pub unsafe fn remap_readonly(
+    mapper: &mut PageMapper,
+    range: Range<VirtAddr>,
+) -> Result<PageFlushAll<MMArch>, SystemError> {
+    if !range.start.check_aligned(MMArch::PAGE_SIZE)
+        || !range.end.check_aligned(MMArch::PAGE_SIZE)
+        || range.start > range.end
+    {
+        return Err(SystemError::EINVAL);
+    }
+    let pages = (range.end.data() - range.start.data()) / MMArch::PAGE_SIZE;
+    for i in 0..pages {
+        if mapper
+            .translate(range.start + i * MMArch::PAGE_SIZE)
+            .is_none()
+        {
+            return Err(SystemError::EFAULT);
+        }
+    }
+
+    let mut flusher = PageFlushAll::<MMArch>::new();
+    for i in 0..pages {
+        let vaddr = range.start + i * MMArch::PAGE_SIZE;
+        let (_, flags) = mapper.translate(vaddr).unwrap();
+        let f = mapper
+            .remap(vaddr, flags.set_write(false))
+            .ok_or(SystemError::EFAULT)?;
+        flusher.consume(f);
+    }
+    return Ok(flusher);
+}
+
+/// 检查一段内核地址中是否存在可写的映射（没有被映射的页面被忽略）
+///
+/// ## 返回值
+///
+/// 可写的页面的起始虚拟地址。每一个都会被打印为警告
+pub fn check_kernel_readonly(range: Range<VirtAddr>) -> Vec<VirtAddr> {
+    let mapper = KernelMapper::lock();
+    let mut writable = Vec::new();
+    let mut vaddr = range.start;
+    while vaddr < range.end {
+        if let Some((_, flags)) = mapper.translate(vaddr) {
+            if flags.has_write() {
+                kwarn!("Read-only violation: {:?} is writable", vaddr);
+                writable.push(vaddr);
+            }
+        }
+        vaddr += MMArch::PAGE_SIZE;
+    }
+    return writable;
+}
+
+/// 内核初始化完成之后，把`.data.ro_after_init`段重新映射为只读，然后检查该段不可写，并且内核地址空间中没有W^X的违例
+///
+/// 只在初始化阶段被写入的静态变量（比如函数指针表、配置）可以通过`#[link_section = ".data.ro_after_init"]`
+/// 放入该段。之后对它们的写入会触发缺页异常
+pub fn mark_ro_after_init() {
+    if RO_AFTER_INIT_SEALED.swap(true, Ordering::SeqCst) {
+        kwarn!("mark_ro_after_init: already sealed");
+        return;
+    }
     let info: X86_64MMBootstrapInfo = BOOTSTRAP_MM_INFO.clone().unwrap();
+    let range = VirtAddr::new(info.ro_after_init_start)..VirtAddr::new(info.ro_after_init_end);
+    if range.start < range.end {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper
+            .as_mut()
+            .expect("mark_ro_after_init: kernel mapper is busy");
+        match unsafe { remap_readonly(mapper, range.clone()) } {
+            Ok(flusher) => flusher.flush(),
+            Err(e) => panic!(
+                "mark_ro_after_init: failed to remap {:?}..{:?}: {:?}",
+                range.start, range.end, e
+            ),
+        }
+        if unsafe { smp_get_total_cpu() } > 1 {
+            send_ipi(IpiKind::FlushTLB, IpiTarget::Other);
+        }
+    }
 
-    if virt.data() >= info.kernel_code_start && virt.data() < info.kernel_code_end {
-        // Remap kernel code  execute
-        return PageFlags::new().set_execute(true).set_write(true);
-    } else if virt.data() >= info.kernel_data_end && virt.data() < info.kernel_rodata_end {
-        // Remap kernel rodata read only
-        return PageFlags::new().set_execute(true);
-    } else {
-        return PageFlags::new().set_write(true).set_execute(true);
+    let writable = check_kernel_readonly(range.clone());
+    if !writable.is_empty() {
+        panic!(
+            "mark_ro_after_init: {} page(s) are still writable",
+            writable.len()
+        );
+    }
+    check_kernel_wx();
+    kinfo!(
+        "Marked {} KB of ro_after_init data read-only",
+        (range.end.data() - range.start.data()) / 1024
+    );
+}
+
+/// 处理`scrub=`启动参数：`scrub=on`时启用空闲页帧的后台清零（参见`mm::allocator::scrub`）
+fn scrub_init_from_cmdline() {
+    let mut cmdline = [0u8; BOOT_CMDLINE_MAX];
+    match boot_cmdline_param(unsafe { read_boot_cmdline(&mut cmdline) }, "scrub") {
+        Some("on") => {
+            scrub_set_enabled(true);
+            kinfo!("Background scrubbing of free frames enabled");
+        }
+        Some("off") | None => {}
+        Some(value) => kwarn!("Invalid boot parameter scrub={}, ignored", value),
+    }
+}
+
+/// 处理`hugepool=`启动参数：设置预先清零的大页池的大小（块的数量，参见`mm::allocator::huge_pool`）
+///
+/// 池由idle循环补充，因此这里不会立即分配
+fn huge_pool_init_from_cmdline() {
+    let mut cmdline = [0u8; BOOT_CMDLINE_MAX];
+    let value = match boot_cmdline_param(unsafe { read_boot_cmdline(&mut cmdline) }, "hugepool") {
+        Some(value) => value,
+        None => return,
+    };
+    match value.parse::<usize>() {
+        Ok(blocks) => set_huge_pool_size(blocks),
+        Err(_) => kwarn!("Invalid boot parameter hugepool={}, ignored", value),
+    }
+}
+
+/// 处理`cma=`启动参数：从bump分配器中取出一段连续的页帧，预留给连续内存分配器（CMA）
+///
+/// 这段内存已经被映射到直接映射区域中，并且不会被交给伙伴分配器
+unsafe fn cma_reserve_from_cmdline(bump_allocator: &mut BumpAllocator<MMArch>) {
+    let mut cmdline = [0u8; BOOT_CMDLINE_MAX];
+    let value = match boot_cmdline_param(read_boot_cmdline(&mut cmdline), "cma") {
+        Some(value) => value,
+        None => return,
+    };
+    let size = match parse_mem_size(value) {
+        Some(size) if size != 0 => page_align_up(size),
+        _ => {
+            kwarn!("Invalid boot parameter cma={}, ignored", value);
+            return;
+        }
+    };
+
+    let count = PageFrameCount::new(size / MMArch::PAGE_SIZE);
+    match bump_allocator.allocate(count) {
+        Some((base, count)) => {
+            kmem_stat_add(KernelMemPurpose::Reserved, count);
+            reserved_region_add(
+                PhysMemoryArea {
+                    base,
+                    size: count.bytes(),
+                },
+                ReservedKind::Cma,
+            );
+            cma_reserve(base, count);
+            bump_allocator.hand_off();
+        }
+        None => kwarn!(
+            "cma={}: no contiguous free memory of {:#x} bytes, CMA disabled",
+            value,
+            size
+        ),
     }
 }
 
@@ -577,47 +3505,128 @@ unsafe fn set_inner_allocator(allocator: BuddyAllocator<MMArch>) {
         panic!("Cannot set inner allocator twice!");
     }
     *INNER_ALLOCATOR.lock() = Some(allocator);
+    INNER_ALLOCATOR_READY.store(true, Ordering::SeqCst);
+}
+
+// AP处理器的启动代码运行在低地址，依赖于低地址的重映射
+#[cfg(all(feature = "no_low_remap", feature = "smp_ap_bringup"))]
+compile_error!(
+    "feature `no_low_remap` conflicts with `smp_ap_bringup`: AP bring-up needs the low-address remap, build with --no-default-features"
+);
+
+/// AP处理器的启动代码（smp/apu_boot.S）所占用的页数（启动代码的各部分按4K对齐）
+const AP_TRAMPOLINE_PAGES: usize = 8;
+/// AP处理器以实模式开始执行，SIPI的向量号是启动代码所在的物理页号（8位），因此启动代码必须位于1MB以下
+const AP_TRAMPOLINE_LIMIT: usize = 0x10_0000;
+/// 不使用第一个页面（实模式的中断向量表以及BIOS数据区）
+const AP_TRAMPOLINE_MIN: usize = 0x1000;
+/// 为AP处理器的启动代码保留的页帧的起始地址
+static AP_TRAMPOLINE_FRAME: Lazy<PhysAddr> = Lazy::new();
+
+/// 获取为AP处理器的启动代码保留的页帧的起始物理地址（按页对齐，位于1MB以下）
+pub fn ap_trampoline_frame() -> PhysAddr {
+    return *AP_TRAMPOLINE_FRAME
+        .try_get()
+        .expect("AP trampoline frame is not reserved");
+}
+
+/// @brief 在AP处理器启动之前，预热它的页帧缓存，并填充它的紧急页帧池
+#[no_mangle]
+pub extern "C" fn rs_frame_allocator_warmup_cpu(cpu_id: u32) {
+    emergency_refill_cpu(cpu_id as usize);
+    let cached = LockedFrameAllocator.warmup_cpu(cpu_id as usize, FRAME_CACHE_WARMUP);
+    if cached < FRAME_CACHE_WARMUP {
+        kwarn!(
+            "frame cache warmup for cpu {}: only {} of {} frames cached",
+            cpu_id,
+            cached,
+            FRAME_CACHE_WARMUP
+        );
+    }
+}
+
+/// @brief 获取AP处理器启动代码的物理地址，以及保留的大小（字节）
+#[no_mangle]
+pub extern "C" fn rs_ap_trampoline_frame(size: *mut u64) -> u64 {
+    if !size.is_null() {
+        unsafe { *size = (AP_TRAMPOLINE_PAGES * MMArch::PAGE_SIZE) as u64 };
+    }
+    return ap_trampoline_frame().data() as u64;
 }
 
 /// 低地址重映射的管理器
 ///
 /// 低地址重映射的管理器，在smp初始化完成之前，需要使用低地址的映射，因此需要在smp初始化完成之后，取消这一段映射
+///
+/// 启用`no_low_remap` feature时，不会建立低地址的映射，`unmap_at_low_address`也不做任何事情。
+/// 此时AP处理器无法被启动（`smp_ap_bringup` feature不能同时启用），内核以单核的方式运行
 pub struct LowAddressRemapping;
 
 impl LowAddressRemapping {
     // 映射32M
-    const REMAP_SIZE: usize = 32 * 1024 * 1024;
+    pub const REMAP_SIZE: usize = 32 * 1024 * 1024;
 
     pub unsafe fn remap_at_low_address(
         mapper: &mut crate::mm::page::PageMapper<MMArch, &mut BumpAllocator<MMArch>>,
+        flush_batch: &mut FlushBatch<MMArch>,
     ) {
-        for i in 0..(Self::REMAP_SIZE / MMArch::PAGE_SIZE) {
-            let paddr = PhysAddr::new(i * MMArch::PAGE_SIZE);
-            let vaddr = VirtAddr::new(i * MMArch::PAGE_SIZE);
+        for (vaddr, paddr) in page_map_range(
+            VirtAddr::new(0),
+            PhysAddr::new(0),
+            PageFrameCount::new(Self::REMAP_SIZE / MMArch::PAGE_SIZE),
+        ) {
             let flags = kernel_page_flags::<MMArch>(vaddr);
 
-            let flusher = mapper
-                .map_phys(vaddr, paddr, flags)
-                .expect("Failed to map frame");
-            // 暂时不刷新TLB
-            flusher.ignore();
+            match mapper.map_phys(vaddr, paddr, flags) {
+                Ok(flusher) => flush_batch.consume(flusher),
+                Err(e) => boot_mm_fail("remap low address", e, mapper.allocator_ref().offset()),
+            }
         }
     }
 
+    /// 低地址的映射是否被建立
+    pub const fn enabled() -> bool {
+        return cfg!(not(feature = "no_low_remap"));
+    }
+
     /// 取消低地址的映射
     pub unsafe fn unmap_at_low_address(flush: bool) {
+        if !Self::enabled() {
+            Self::assert_null_unmapped();
+            return;
+        }
         let mut mapper = KernelMapper::lock();
         assert!(mapper.as_mut().is_some());
-        for i in 0..(Self::REMAP_SIZE / MMArch::PAGE_SIZE) {
-            let vaddr = VirtAddr::new(i * MMArch::PAGE_SIZE);
+        let count = PageFrameCount::new(Self::REMAP_SIZE / MMArch::PAGE_SIZE);
+        for vaddr in PageRange::virt(VirtAddr::new(0), count) {
             let (_, _, flusher) = mapper
                 .as_mut()
                 .unwrap()
                 .unmap_phys(vaddr, true)
                 .expect("Failed to unmap frame");
-            if flush == false {
-                flusher.ignore();
-            }
+            // 整个范围在最后一次性刷新
+            flusher.ignore_safe();
+        }
+        drop(mapper);
+        if flush {
+            MMArch::invalidate_range(VirtAddr::new(0), count);
+        }
+
+        Self::assert_null_unmapped();
+    }
+
+    /// 检查当前页表中，虚拟地址0所在的页面没有被映射
+    ///
+    /// 这样能确保内核中的空指针解引用一定会触发缺页异常，而不是静默地读写低地址的物理内存。
+    ///
+    /// 用户进程的页表只会复制内核空间（高地址）的页表项，因此只需要检查当前页表即可。
+    pub fn assert_null_unmapped() {
+        let mapper = KernelMapper::lock();
+        if let Some((paddr, flags)) = mapper.translate(VirtAddr::new(0)) {
+            panic!(
+                "Null page is still mapped after low address unmapping: paddr={:?}, flags={:?}",
+                paddr, flags
+            );
         }
     }
 }
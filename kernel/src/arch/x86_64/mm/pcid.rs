@@ -0,0 +1,271 @@
+//! PCID（Process-Context Identifier）
+//!
+//! 开启CR4.PCIDE之后，TLB中的条目会被标记上加载它时CR3中的PCID（CR3的低12位）。
+//! 切换页表时如果设置了CR3的第63位（`CR3_NOFLUSH`），处理器不会刷新新的PCID在TLB中的条目，
+//! 切换回之前的地址空间时，它在TLB中的条目仍然可以被使用。
+//!
+//! 每个用户地址空间（`UserMapper`）在创建时从池中分配一个PCID，销毁时归还。PCID 0保留给内核的页表、
+//! 以及池耗尽之后创建的地址空间，切换到PCID 0时总是刷新TLB（与不支持PCID时相同）。
+//!
+//! `invlpg`以及重新写入CR3只能刷新当前PCID的条目，其他PCID的条目是否仍然有效通过刷新代数来跟踪：
+//!
+//! - 每个PCID有一个全局的代数。任意CPU刷新了一个PCID中用户地址的条目（或者PCID被归还）时增加它的代数，
+//!   其他CPU下一次切换到这个PCID时发现代数变化，就刷新它的条目
+//! - 刷新内核地址、刷新整个TLB（包括刷新TLB的IPI）时，被修改的可能是任意一个地址空间（比如`InactiveFlusher`），
+//!   因此这个CPU上其他所有PCID的条目都在下一次切换到它们时刷新
+//!
+//! 处理器不支持PCID时（CPUID.01H:ECX[17]为0），`init_pcid`不会开启CR4.PCIDE，不会分配PCID，切换页表的行为与原来相同。
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use x86::controlregs::{cr4, cr4_write, Cr4};
+use x86::cpuid::cpuid;
+
+use crate::{
+    arch::MMArch,
+    kinfo, kwarn,
+    mm::{percpu::PerCpu, MemoryManagementArch, PhysAddr, VirtAddr},
+    smp::core::smp_get_processor_id,
+};
+
+/// PCID池的大小（PCID 0保留，不会被分配）
+pub const PCID_COUNT: usize = 64;
+
+/// CR3中PCID所在的位（开启CR4.PCIDE时）
+pub const CR3_PCID_MASK: usize = 0xfff;
+/// 写入CR3时设置这一位，处理器不会刷新新的PCID在TLB中的条目
+pub const CR3_NOFLUSH: usize = 1 << 63;
+
+/// CPU上的条目已经过期的PCID的代数。PCID的代数总是偶数，因此不会与它相等
+const GEN_STALE: u32 = 1;
+
+/// 处理器是否支持PCID（BSP开启PCID之后才会分配PCID）
+static PCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+/// 处理器是否支持INVPCID指令（CPUID.(EAX=07H,ECX=0):EBX[10]）
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// PCID的分配位图（每一位对应一个PCID，为1表示已被分配）。PCID 0总是被占用
+static PCID_BITMAP: AtomicU64 = AtomicU64::new(1);
+
+const PCID_GEN_INIT: AtomicU32 = AtomicU32::new(0);
+/// 每个PCID的刷新代数
+static PCID_GEN: [AtomicU32; PCID_COUNT] = [PCID_GEN_INIT; PCID_COUNT];
+
+/// 每个CPU上的PCID状态（只会被这个CPU自己访问）
+struct PcidCpuState {
+    /// 这个CPU上是否已经开启了CR4.PCIDE
+    enabled: AtomicBool,
+    /// 下一次切换页表时，是否需要使这个CPU上所有PCID的条目失效
+    flush_all_pending: AtomicBool,
+    /// 这个CPU上，每个PCID在TLB中的条目对应的代数。与`PCID_GEN`不同时，切换到这个PCID需要刷新
+    seen: [AtomicU32; PCID_COUNT],
+}
+
+const PCID_SEEN_INIT: AtomicU32 = AtomicU32::new(GEN_STALE);
+const PCID_CPU_INIT: PcidCpuState = PcidCpuState {
+    enabled: AtomicBool::new(false),
+    flush_all_pending: AtomicBool::new(false),
+    seen: [PCID_SEEN_INIT; PCID_COUNT],
+};
+static PCID_CPU: [PcidCpuState; PerCpu::MAX_CPU_NUM] = [PCID_CPU_INIT; PerCpu::MAX_CPU_NUM];
+
+fn cpu_state() -> Option<&'static PcidCpuState> {
+    return PCID_CPU
+        .get(smp_get_processor_id() as usize)
+        .filter(|state| state.enabled.load(Ordering::Relaxed));
+}
+
+fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags))
+    };
+    return cr3;
+}
+
+/// 检测处理器是否支持PCID，支持时在当前CPU上开启CR4.PCIDE
+///
+/// 每个CPU都需要调用一次（CR4是每个CPU独立的）：BSP在内存管理初始化时调用，AP在启动时通过`rs_init_pcid_ap`调用
+///
+/// ## 返回值
+///
+/// 当前CPU上是否开启了PCID
+pub fn init_pcid() -> bool {
+    if cpuid!(1).ecx & (1 << 17) == 0 {
+        return false;
+    }
+    let cpu_id = smp_get_processor_id() as usize;
+    let state = match PCID_CPU.get(cpu_id) {
+        Some(state) => state,
+        None => return false,
+    };
+    if state.enabled.load(Ordering::Relaxed) {
+        return true;
+    }
+    // CR3的低12位不为0时开启CR4.PCIDE会触发#GP
+    let cr3 = read_cr3();
+    if cr3 & CR3_PCID_MASK != 0 {
+        kwarn!(
+            "init_pcid: CR3 {:#x} has low bits set, PCID is not enabled on CPU {}",
+            cr3,
+            cpu_id
+        );
+        return false;
+    }
+
+    let invpcid = cpuid!(0).eax >= 7 && cpuid!(7, 0).ebx & (1 << 10) != 0;
+    unsafe { cr4_write(cr4() | Cr4::CR4_ENABLE_PCID) };
+    // 开启之前TLB中的条目都属于PCID 0，其他PCID的`seen`初始为过期，因此第一次切换到它们时总是刷新
+    state.enabled.store(true, Ordering::Relaxed);
+    INVPCID_SUPPORTED.store(invpcid, Ordering::Relaxed);
+    if !PCID_SUPPORTED.swap(true, Ordering::SeqCst) {
+        kinfo!("PCID enabled, INVPCID supported: {}", invpcid);
+    }
+    return true;
+}
+
+/// 判断当前CPU上是否开启了PCID
+pub fn pcid_enabled() -> bool {
+    return cpu_state().is_some();
+}
+
+/// 判断处理器是否支持INVPCID指令
+pub fn invpcid_supported() -> bool {
+    return INVPCID_SUPPORTED.load(Ordering::Relaxed);
+}
+
+/// 为新的地址空间分配一个PCID
+///
+/// ## 返回值
+///
+/// 分配的PCID。处理器不支持PCID或者池已经耗尽时，返回0（切换到这个地址空间时总是刷新TLB）
+pub fn alloc_pcid() -> usize {
+    if !PCID_SUPPORTED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut bitmap = PCID_BITMAP.load(Ordering::Relaxed);
+    while bitmap != u64::MAX {
+        let pcid = bitmap.trailing_ones() as usize;
+        match PCID_BITMAP.compare_exchange_weak(
+            bitmap,
+            bitmap | (1 << pcid),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return pcid,
+            Err(current) => bitmap = current,
+        }
+    }
+    return 0;
+}
+
+/// 归还`alloc_pcid`分配的PCID
+///
+/// 其他CPU上可能还缓存着这个PCID的条目，因此先增加它的代数，之后使用这个PCID的地址空间第一次被加载时会刷新TLB
+pub fn free_pcid(pcid: usize) {
+    if pcid == 0 || pcid >= PCID_COUNT {
+        return;
+    }
+    PCID_GEN[pcid].fetch_add(2, Ordering::SeqCst);
+    PCID_BITMAP.fetch_and(!(1 << pcid), Ordering::AcqRel);
+}
+
+/// 已经分配的PCID的数量（不包括保留的PCID 0）
+pub fn pcid_allocated() -> usize {
+    return PCID_BITMAP.load(Ordering::Relaxed).count_ones() as usize - 1;
+}
+
+/// 计算切换到顶级页表table（使用pcid）时写入CR3的值，并更新当前CPU上pcid的代数
+///
+/// 调用者需要关闭中断，直到CR3被写入
+pub(super) fn pcid_switch_cr3(table: PhysAddr, pcid: usize) -> usize {
+    let state = match cpu_state() {
+        Some(state) => state,
+        None => return table.data(),
+    };
+    if state.flush_all_pending.swap(false, Ordering::Relaxed) {
+        for seen in state.seen.iter() {
+            seen.store(GEN_STALE, Ordering::Relaxed);
+        }
+    }
+    if pcid == 0 || pcid >= PCID_COUNT {
+        return table.data();
+    }
+    let gen = PCID_GEN[pcid].load(Ordering::Acquire);
+    if state.seen[pcid].swap(gen, Ordering::Relaxed) == gen {
+        return table.data() | pcid | CR3_NOFLUSH;
+    }
+    return table.data() | pcid;
+}
+
+/// 记录当前CPU刷新了pcid中关于vaddr的条目
+pub(super) fn pcid_note_invalidate(pcid: usize, vaddr: VirtAddr) {
+    let state = match cpu_state() {
+        Some(state) => state,
+        None => return,
+    };
+    if vaddr >= MMArch::USER_END_VADDR {
+        // 内核的映射在所有的地址空间中共享
+        state.flush_all_pending.store(true, Ordering::Relaxed);
+        return;
+    }
+    if pcid == 0 || pcid >= PCID_COUNT {
+        return;
+    }
+    let prev = PCID_GEN[pcid].fetch_add(2, Ordering::SeqCst);
+    // 如果这个CPU之前已经错过了其他CPU的刷新，就保持过期的状态
+    let _ = state.seen[pcid].compare_exchange(
+        prev,
+        prev.wrapping_add(2),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
+
+/// 重新写入CR3来刷新当前PCID的条目之前调用：增加当前PCID的代数，并使这个CPU上其他PCID的条目在下一次切换时刷新
+///
+/// ## 返回值
+///
+/// 应当写入CR3的值
+pub(super) fn pcid_flush_current() -> usize {
+    let cr3 = read_cr3();
+    let state = match cpu_state() {
+        Some(state) => state,
+        None => return cr3,
+    };
+    state.flush_all_pending.store(true, Ordering::Relaxed);
+    let pcid = cr3 & CR3_PCID_MASK;
+    if pcid != 0 && pcid < PCID_COUNT {
+        let gen = PCID_GEN[pcid]
+            .fetch_add(2, Ordering::SeqCst)
+            .wrapping_add(2);
+        state.seen[pcid].store(gen, Ordering::Relaxed);
+    }
+    return cr3 & !CR3_NOFLUSH;
+}
+
+/// 使当前CPU上所有PCID的条目在下一次切换到它们时刷新
+///
+/// 修改了没有在当前CPU上激活的页表之后调用（当前CPU上仍然可能缓存着它的条目）
+pub fn pcid_invalidate_all_contexts() {
+    if let Some(state) = cpu_state() {
+        state.flush_all_pending.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 当前CR3中的PCID（没有开启PCID时为0）
+pub fn current_pcid() -> usize {
+    if !pcid_enabled() {
+        return 0;
+    }
+    return read_cr3() & CR3_PCID_MASK;
+}
+
+/// 使当前CPU上pcid的所有条目在下一次切换到它时刷新（不支持INVPCID时，刷新其他PCID的地址使用）
+pub(super) fn pcid_mark_stale(pcid: usize) {
+    if let Some(state) = cpu_state() {
+        if let Some(seen) = state.seen.get(pcid) {
+            seen.store(GEN_STALE, Ordering::Relaxed);
+        }
+    }
+}
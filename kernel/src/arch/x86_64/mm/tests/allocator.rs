@@ -0,0 +1,1061 @@
+//! 页帧分配器（伙伴分配器、页帧缓存、低阶页帧池、大页池等）的测试
+
+use super::*;
+
+/// 检查伙伴分配器在不同的阶数下，分配的块都按照块的大小自然对齐
+pub fn test_buddy_alignment() {
+    for order in 0..=10 {
+        let count = PageFrameCount::new(1 << order);
+        let align = LockedFrameAllocator::alignment_of(count);
+        assert_eq!(align, count.bytes());
+
+        // 每一轮结束时，由守卫释放所有的页帧块
+        let mut guard = FrameGuard::new();
+        for _ in 0..8 {
+            let (paddr, allocated) = match unsafe { LockedFrameAllocator.allocate(count) } {
+                Some(r) => r,
+                None => break,
+            };
+            assert_eq!(allocated, count);
+            assert!(
+                paddr.data() % align == 0,
+                "order {}: {:?} is not aligned to {:#x}",
+                order,
+                paddr,
+                align
+            );
+            guard.push(paddr, allocated);
+        }
+    }
+}
+
+/// 在不同阶数的分配与释放之后检查伙伴分配器的不变量（参见`BuddyAllocator::verify_invariants`），
+/// 以及已分配的块不在空闲链表中、释放之后与伙伴合并
+pub fn test_buddy_invariants() {
+    LockedFrameAllocator.verify_invariants();
+
+    let mut blocks = Vec::new();
+    for order in [0, 3, 1, 5, 0, 2] {
+        let count = PageFrameCount::new(1 << order);
+        if let Some((paddr, allocated)) = unsafe { LockedFrameAllocator.allocate(count) } {
+            blocks.push((paddr, allocated));
+        }
+    }
+    LockedFrameAllocator.verify_invariants();
+    if let Some(ref allocator) = *INNER_ALLOCATOR.lock_irqsave() {
+        for (paddr, _) in blocks.iter() {
+            assert!(!allocator.is_free(*paddr));
+        }
+    }
+
+    // 逆序释放，使得相邻的块有机会合并
+    while let Some((paddr, allocated)) = blocks.pop() {
+        unsafe { LockedFrameAllocator.free(paddr, allocated) };
+        LockedFrameAllocator.verify_invariants();
+    }
+}
+
+/// 检查RDRAND不可用时，`random_u64`（test_buddy使用它生成随机的分配大小）回退到软件伪随机数生成器，
+/// 并且软件生成器的输出与xorshift64*一致
+pub fn test_random_fallback() {
+    use crate::arch::rand::{rdrand_set_disabled, soft_random_seed, soft_random_u64};
+
+    rdrand_set_disabled(true);
+    soft_random_seed(1);
+    let fallback = [random_u64(), random_u64(), random_u64()];
+    soft_random_seed(1);
+    let soft = [soft_random_u64(), soft_random_u64(), soft_random_u64()];
+    assert_eq!(fallback, soft);
+
+    // 种子为1时，xorshift64*的第一个输出
+    let mut x: u64 = 1;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    assert_eq!(fallback[0], x.wrapping_mul(0x2545_f491_4f6c_dd1d));
+    assert!(fallback[0] != fallback[1] && fallback[1] != fallback[2]);
+
+    // 恢复：重新检测RDRAND，并在下一次使用软件生成器时重新获取种子
+    soft_random_seed(0);
+    rdrand_set_disabled(false);
+}
+
+/// 检查页表分配的统计：在新的用户页表中映射一段按2M对齐的2M区域，
+/// 需要且只需要各分配一个PDPT、PD和PT（使用大页映射时不需要PT，参见`test_map_phys_huge`）
+pub fn test_page_table_alloc_stats() {
+    let before = PageTableAllocStats::snapshot();
+    with_user_mapper(|umapper| {
+        let created = PageTableAllocStats::snapshot().since(&before);
+        assert_eq!(created.frames_at(3), 1);
+        assert_eq!(created.total().data(), 1);
+
+        let (paddr, count) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(512)) }
+            .expect("Failed to allocate 2M");
+        let flags = PageFlags::new().set_user(true).set_write(true);
+        let before = PageTableAllocStats::snapshot();
+        for (vaddr, paddr) in page_map_range(VirtAddr::new(0x4000_0000), paddr, count) {
+            unsafe { umapper.utable.map_phys(vaddr, paddr, flags) }
+                .expect("Failed to map user page")
+                .flush();
+        }
+        let mapped = PageTableAllocStats::snapshot().since(&before);
+        assert_eq!(mapped.frames_at(2), 1);
+        assert_eq!(mapped.frames_at(1), 1);
+        assert_eq!(mapped.frames_at(0), 1);
+        assert_eq!(mapped.total().data(), 3);
+
+        // clear_user_space会释放这些物理页
+    });
+}
+
+/// 检查分配之后没有释放的页帧会以分配时的标签出现在leak_report中
+pub fn test_frame_tag() {
+    if !cfg!(feature = "frame_tag") {
+        kdebug!("test_frame_tag skipped: frame_tag feature is disabled");
+        return;
+    }
+    let outstanding = |tag: FrameTag| {
+        leak_report()
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, count)| count.data())
+            .unwrap_or(0)
+    };
+
+    let before = outstanding(FRAME_TAG_DMA);
+    let (paddr, count) = unsafe {
+        LockedFrameAllocator.allocate_flags(
+            PageFrameCount::new(2),
+            AllocFlags::empty(),
+            FRAME_TAG_DMA,
+        )
+    }
+    .unwrap();
+    assert_eq!(frame_tag_of(paddr), Some(FRAME_TAG_DMA));
+    assert_eq!(outstanding(FRAME_TAG_DMA), before + count.data());
+
+    unsafe { LockedFrameAllocator.free(paddr, count) };
+    assert_eq!(frame_tag_of(paddr), None);
+    assert_eq!(outstanding(FRAME_TAG_DMA), before);
+}
+
+/// 检查页表、SLAB（伙伴分配器中的大对象）以及用户页面的分配点使用了各自的标签
+pub fn test_frame_tag_sites() {
+    use crate::mm::allocator::page_frame::VirtPageFrame;
+    use crate::mm::page::InactiveFlusher;
+    use crate::mm::ucontext::VMA;
+
+    if !cfg!(feature = "frame_tag") {
+        kdebug!("test_frame_tag_sites skipped: frame_tag feature is disabled");
+        return;
+    }
+
+    with_user_mapper(|umapper| {
+        let root = umapper.utable.table().phys();
+        assert_eq!(frame_tag_of(root), Some(FRAME_TAG_PAGE_TABLE));
+
+        let start = VirtAddr::new(0x4000_0000);
+        let flags = PageFlags::new().set_user(true).set_write(true);
+        let vma = VMA::zeroed(
+            VirtPageFrame::new(start),
+            PageFrameCount::new(1),
+            flags,
+            &mut umapper.utable,
+            InactiveFlusher::new(),
+        )
+        .expect("test_frame_tag_sites: map failed");
+        let (paddr, _) = umapper.utable.translate(start).unwrap();
+        assert_eq!(frame_tag_of(paddr), Some(FRAME_TAG_USER_ANON));
+        // 新建立的各级页表
+        let mut table = umapper.utable.table();
+        while table.level() > 0 {
+            let index = unsafe { table.index_of(start) }.unwrap();
+            table = unsafe { table.next_level_table(index) }.unwrap();
+            assert_eq!(frame_tag_of(table.phys()), Some(FRAME_TAG_PAGE_TABLE));
+        }
+        vma.unmap(&mut umapper.utable, InactiveFlusher::new());
+    });
+
+    // 堆上的对象由KernelAllocator从伙伴分配器中分配
+    let buf: Vec<u8> = vec![0; 2 * MMArch::PAGE_SIZE];
+    let vaddr = VirtAddr::new(buf.as_ptr() as usize);
+    let paddr = unsafe { MMArch::virt_2_phys(vaddr) }.unwrap();
+    assert_eq!(frame_tag_of(paddr), Some(FRAME_TAG_SLAB));
+    drop(buf);
+}
+
+/// 检查启用低阶页帧池之后，单页的反复分配与释放不会分裂伙伴分配器中更大的块，
+/// 与大块分配交替进行的单页分配集中在同一个块中，而大块分配仍然成功
+///
+/// 关闭低阶页帧池时，每一次单页分配都会从当前最小的空闲块中分裂一页，
+/// 大块分配之后，下一次单页分配可能会分裂另一个大块
+pub fn test_loworder_pool() {
+    const POOL_FRAMES: usize = 16;
+    const LARGE_FRAMES: usize = 64;
+    // 伙伴分配器中，不小于LARGE_FRAMES的空闲块所包含的页帧数
+    let large_free = || {
+        let mut pages = 0;
+        if let Some(ref allocator) = *INNER_ALLOCATOR.lock_irqsave() {
+            allocator.for_each_free_block(|_, count| {
+                if count.data() >= LARGE_FRAMES {
+                    pages += count.data();
+                }
+            });
+        }
+        pages
+    };
+
+    // 清空页帧缓存，使得之后的单页分配都经过池
+    LockedFrameAllocator.drain_frame_caches();
+    set_loworder_pool_size(POOL_FRAMES);
+
+    // 第一次分配使池从伙伴分配器中补充一个块，页帧缓存从池中补充
+    let first = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    unsafe { LockedFrameAllocator.free_one(first) };
+    assert!(frame_cache_count(smp_get_processor_id() as usize) > 0);
+
+    // 之后的单页分配与释放都在页帧缓存与池之间进行，不再分裂大块
+    let before = large_free();
+    for _ in 0..8 {
+        let frames: Vec<PhysAddr> = (0..POOL_FRAMES - 1)
+            .map(|_| unsafe { LockedFrameAllocator.allocate_one() }.unwrap())
+            .collect();
+        for paddr in frames.iter() {
+            assert_eq!(
+                paddr.data() & !(POOL_FRAMES * MMArch::PAGE_SIZE - 1),
+                first.data() & !(POOL_FRAMES * MMArch::PAGE_SIZE - 1),
+                "{:?}",
+                paddr
+            );
+        }
+        for paddr in frames {
+            unsafe { LockedFrameAllocator.free_one(paddr) };
+        }
+    }
+    assert!(large_free() >= before);
+
+    let mut small = Vec::new();
+    let mut large = Vec::new();
+    for _ in 0..POOL_FRAMES {
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        small.push(paddr);
+        let (paddr, count) =
+            unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(LARGE_FRAMES)) }
+                .expect("large allocation failed");
+        large.push((paddr, count));
+    }
+
+    // 所有的单页都来自同一个按POOL_FRAMES对齐的块
+    let block = POOL_FRAMES * MMArch::PAGE_SIZE;
+    let first_block = small[0].data() & !(block - 1);
+    for paddr in small.iter() {
+        assert_eq!(paddr.data() & !(block - 1), first_block, "{:?}", paddr);
+    }
+    // 大块都没有被单页分配所分裂
+    for (paddr, count) in large.iter() {
+        assert!(paddr.data() + count.bytes() <= first_block || paddr.data() >= first_block + block);
+    }
+
+    for paddr in small {
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+    }
+    for (paddr, count) in large {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+    set_loworder_pool_size(0);
+    LockedFrameAllocator.drain_frame_caches();
+}
+
+/// 检查`allocate_kernel`返回的虚拟地址是页帧在直接映射区域中的地址，以及`free_kernel`通过虚拟地址归还页帧
+pub fn test_allocate_kernel() {
+    // 低阶页帧池以及待清零队列会吸收被释放的单页，因此使用多页的块进行测试
+    let count = PageFrameCount::new(4);
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+
+    let (paddr, vaddr) = unsafe { LockedFrameAllocator.allocate_kernel(count, FRAME_TAG_SLAB) }
+        .expect("allocate_kernel failed");
+    assert_eq!(Some(vaddr), unsafe { MMArch::phys_2_virt(paddr) });
+    assert_eq!(frame_tag_of(paddr), Some(FRAME_TAG_SLAB));
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data() + count.data(),
+        free_before.data()
+    );
+    // 整个范围都可以通过返回的虚拟地址访问
+    let last = vaddr + count.bytes() - core::mem::size_of::<u64>();
+    unsafe {
+        MMArch::write::<u64>(last, 0x1234_5678);
+        assert_eq!(MMArch::read::<u64>(last), 0x1234_5678);
+    }
+
+    unsafe { LockedFrameAllocator.free_kernel(vaddr, count) };
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+}
+
+/// 检查释放的页帧数量不是2的幂时的处理：`try_free`返回错误并且不释放任何页帧；
+/// `free`在release模式下向上取整到2的幂（debug模式下会panic，因此直接检查取整的函数）
+pub fn test_free_non_power_of_two() {
+    // 低阶页帧池以及待清零队列会吸收被释放的单页，因此使用多页的块进行测试
+    let count = PageFrameCount::new(4);
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate(count) }.unwrap();
+    assert_eq!(allocated, count);
+
+    assert_eq!(
+        unsafe { LockedFrameAllocator.try_free(paddr, PageFrameCount::new(3)) },
+        Err(SystemError::EINVAL)
+    );
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data() + count.data(),
+        free_before.data()
+    );
+
+    assert_eq!(
+        LockedFrameAllocator::checked_free_count(paddr, count),
+        count
+    );
+    for (n, rounded) in [(1, 1), (3, 4), (4, 4), (5, 8), (9, 16)] {
+        assert_eq!(
+            LockedFrameAllocator::round_free_count(PageFrameCount::new(n)),
+            PageFrameCount::new(rounded)
+        );
+    }
+
+    assert_eq!(
+        unsafe { LockedFrameAllocator.try_free(paddr, count) },
+        Ok(())
+    );
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+}
+
+/// 检查页着色：空闲内存充足时，每一种颜色的请求都能被满足；无法满足的颜色退化为普通的分配
+pub fn test_allocate_colored() {
+    let color_of = |paddr: PhysAddr| (paddr.data() >> MMArch::PAGE_SHIFT) & (PAGE_COLORS - 1);
+    let single = PageFrameCount::new(1);
+    let mut held: Vec<(PhysAddr, PageFrameCount)> = Vec::with_capacity(PAGE_COLORS + 2);
+
+    for color in 0..PAGE_COLORS {
+        let (paddr, count) = LockedFrameAllocator
+            .allocate_colored(single, color)
+            .expect("allocate_colored failed");
+        assert_eq!(count, single);
+        assert_eq!(color_of(paddr), color);
+        held.push((paddr, count));
+    }
+    // 多页的块：与块的大小对齐的颜色可以被满足
+    let four = PageFrameCount::new(4);
+    let (paddr, count) = LockedFrameAllocator.allocate_colored(four, 8).unwrap();
+    assert_eq!(count, four);
+    assert_eq!(color_of(paddr), 8);
+    held.push((paddr, count));
+    // 块的起始地址按块的大小对齐，颜色1无法被满足，此时退化为普通的分配
+    let (paddr, count) = LockedFrameAllocator.allocate_colored(four, 1).unwrap();
+    assert_eq!(count, four);
+    assert!(paddr.check_aligned(four.bytes()));
+    held.push((paddr, count));
+
+    for (paddr, count) in held {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+}
+
+/// 检查FrameGuard被drop时，会把所有的页帧归还给页帧分配器；调用into_inner之后则不会释放
+pub fn test_frame_guard() {
+    // 低阶页帧池以及待清零队列会吸收被释放的单页，因此使用多页的块进行测试
+    let count = PageFrameCount::new(4);
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+
+    let guard = alloc_frames(count, 8).expect("Failed to allocate frames");
+    assert_eq!(guard.len(), 8);
+    let mut seen = HashSet::new();
+    for (paddr, allocated) in guard.frames() {
+        assert_eq!(*allocated, count);
+        assert!(seen.insert(*paddr));
+    }
+    let free_allocated = unsafe { LockedFrameAllocator.usage() }.free();
+    assert_eq!(free_allocated.data() + 8 * count.data(), free_before.data());
+
+    drop(guard);
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+
+    // 在中途提前返回时，已经分配的页帧同样会被释放
+    let early_return = || -> Result<(), SystemError> {
+        let _guard = alloc_frames(count, 4)?;
+        return Err(SystemError::EINVAL);
+    };
+    assert_eq!(early_return(), Err(SystemError::EINVAL));
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+
+    // into_inner之后由调用者负责释放
+    let frames = alloc_frames(count, 2).unwrap().into_inner();
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data() + 2 * count.data(),
+        free_before.data()
+    );
+    for (paddr, allocated) in frames {
+        unsafe { LockedFrameAllocator.free(paddr, allocated) };
+    }
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+}
+
+/// 检查不可缓存的临时页面：映射以及直接映射区域中的别名都设置了PCD，写入的值能被读回，释放之后恢复为WB
+pub fn test_uncached_page() {
+    let vmap_before = kmem_stat_get(KernelMemPurpose::Vmap).data();
+    let (paddr, vaddr) = uncached_page().expect("uncached_page failed");
+    assert_eq!(
+        kmem_stat_get(KernelMemPurpose::Vmap).data(),
+        vmap_before + 1
+    );
+    assert!(uncached_pages().contains(&(paddr, vaddr)));
+    assert_eq!(cache_type_of(paddr), Some(CacheType::Uncacheable));
+
+    let direct = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    {
+        let mapper = KernelMapper::lock();
+        let (mapped, flags) = mapper.translate(vaddr).unwrap();
+        assert_eq!(mapped, paddr);
+        assert!(flags.has_page_cache_disable());
+        let (_, direct_flags) = mapper.translate(direct).unwrap();
+        assert!(direct_flags.has_page_cache_disable());
+    }
+
+    let value: u64 = 0x5ca7_c4ed_dead_beef;
+    unsafe {
+        assert_eq!(core::ptr::read_volatile(vaddr.as_ptr::<u64>()), 0);
+        core::ptr::write_volatile(vaddr.as_ptr::<u64>(), value);
+        assert_eq!(core::ptr::read_volatile(vaddr.as_ptr::<u64>()), value);
+        assert_eq!(core::ptr::read_volatile(direct.as_ptr::<u64>()), value);
+    }
+
+    // 缓存类型的冲突只在内核页表中检查（debug模式下返回错误），用户页表中的映射不检查
+    let wb = PageFlags::<MMArch>::new().set_write(true);
+    if cfg!(debug_assertions) {
+        let mut kernel_mapper = KernelMapper::lock();
+        let mapper = kernel_mapper.as_mut().expect("kernel mapper is busy");
+        assert!(matches!(
+            unsafe { mapper.map_phys(VirtAddr::new(0x7000_0000), paddr, wb) },
+            Err(MmError::CacheTypeConflict { .. })
+        ));
+    }
+    with_user_mapper(|umapper| {
+        let flush = unsafe {
+            umapper
+                .utable
+                .map_phys(VirtAddr::new(0x4000_0000), paddr, wb.set_user(true))
+        }
+        .expect("user mappings are not checked for cache type conflicts");
+        unsafe { flush.ignore_safe() };
+        // 页帧由free_uncached_page释放，不能留给clear_user_space
+        let (_, _, flush) =
+            unsafe { umapper.utable.unmap_phys(VirtAddr::new(0x4000_0000), true) }.unwrap();
+        unsafe { flush.ignore_safe() };
+    });
+
+    free_uncached_page(vaddr).expect("free_uncached_page failed");
+    assert_eq!(kmem_stat_get(KernelMemPurpose::Vmap).data(), vmap_before);
+    assert!(!uncached_pages().contains(&(paddr, vaddr)));
+    assert_eq!(cache_type_of(paddr), None);
+    let (_, direct_flags) = KernelMapper::lock().translate(direct).unwrap();
+    assert!(!direct_flags.has_page_cache_disable());
+    assert!(KernelMapper::lock().translate(vaddr).is_none());
+    assert_eq!(free_uncached_page(vaddr), Err(SystemError::EINVAL));
+}
+
+/// 检查页帧缓存的预热：缓存中的页帧被统计为已使用，预热之后的前count次单页分配不获取全局分配器的锁
+pub fn test_frame_cache_warmup() {
+    const COUNT: usize = 4;
+    let cpu_id = smp_get_processor_id() as usize;
+    // 预先分配好内存：之后堆的分配也可能从页帧缓存中取得页帧
+    let mut frames = Vec::with_capacity(FRAME_CACHE_SIZE + 1);
+    // 被释放的页帧会放入缓存，先清空缓存，保证有足够的容量用于预热
+    LockedFrameAllocator.drain_frame_caches();
+    let cached_before = frame_cache_count(cpu_id);
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+
+    assert_eq!(LockedFrameAllocator.warmup_cpu(cpu_id, COUNT), COUNT);
+    assert_eq!(frame_cache_count(cpu_id), cached_before + COUNT);
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data(),
+        free_before.data() - COUNT
+    );
+    // 超出范围的CPU不会被预热
+    assert_eq!(
+        LockedFrameAllocator.warmup_cpu(PerCpu::MAX_CPU_NUM, COUNT),
+        0
+    );
+
+    let locks_before = LockedFrameAllocator::global_lock_count();
+    for _ in 0..cached_before + COUNT {
+        let (paddr, _) = unsafe {
+            LockedFrameAllocator.allocate_flags(
+                PageFrameCount::new(1),
+                AllocFlags::empty(),
+                FRAME_TAG_UNTAGGED,
+            )
+        }
+        .expect("allocate_flags failed");
+        frames.push(paddr);
+    }
+    assert_eq!(LockedFrameAllocator::global_lock_count(), locks_before);
+    assert_eq!(frame_cache_count(cpu_id), 0);
+
+    // 缓存为空之后，分配需要获取全局分配器的锁
+    let (paddr, _) = unsafe {
+        LockedFrameAllocator.allocate_flags(
+            PageFrameCount::new(1),
+            AllocFlags::empty(),
+            FRAME_TAG_UNTAGGED,
+        )
+    }
+    .expect("allocate_flags failed");
+    assert!(LockedFrameAllocator::global_lock_count() > locks_before);
+    frames.push(paddr);
+
+    for paddr in frames {
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+    }
+}
+
+/// 检查清零方法的选择，以及两种方法清零之后的内容
+pub fn test_zero_frames_nt() {
+    let is_zero = |paddr: PhysAddr, count: PageFrameCount| -> bool {
+        let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+        let words = count.bytes() / mem::size_of::<u64>();
+        let words = unsafe { core::slice::from_raw_parts(vaddr.data() as *const u64, words) };
+        words.iter().all(|w| *w == 0)
+    };
+    let dirty = |paddr: PhysAddr, count: PageFrameCount| unsafe {
+        MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0xa5, count.bytes());
+    };
+
+    // x86_64总是支持SSE2
+    assert_eq!(
+        MMArch::zero_method(PageFrameCount::new(ZERO_NT_THRESHOLD - 1)),
+        ZeroMethod::Temporal
+    );
+    assert_eq!(
+        MMArch::zero_method(PageFrameCount::new(ZERO_NT_THRESHOLD)),
+        ZeroMethod::NonTemporal
+    );
+
+    for (count, expected) in [
+        (1, ZeroMethod::Temporal),
+        (ZERO_NT_THRESHOLD, ZeroMethod::NonTemporal),
+        (ZERO_NT_THRESHOLD * 4, ZeroMethod::NonTemporal),
+    ] {
+        let count = PageFrameCount::new(count);
+        let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate(count) }.unwrap();
+        dirty(paddr, allocated);
+        assert_eq!(unsafe { MMArch::zero_frames(paddr, allocated) }, expected);
+        assert!(is_zero(paddr, allocated), "{:?} pages", allocated);
+
+        // 直接调用非临时存储的版本（后台清零使用）
+        dirty(paddr, allocated);
+        unsafe { MMArch::zero_frames_nt(paddr, allocated) };
+        assert!(is_zero(paddr, allocated), "{:?} pages (nt)", allocated);
+        unsafe { LockedFrameAllocator.free(paddr, allocated) };
+    }
+
+    // 较大的清零分配
+    let count = PageFrameCount::new(ZERO_NT_THRESHOLD * 2);
+    let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate(count) }.unwrap();
+    dirty(paddr, allocated);
+    unsafe { LockedFrameAllocator.free(paddr, allocated) };
+    let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate_zeroed(count) }.unwrap();
+    assert!(is_zero(paddr, allocated));
+    unsafe { LockedFrameAllocator.free(paddr, allocated) };
+}
+
+/// 在一张独立的检查表上，检查重复释放以及释放从未分配的页帧能够被发现
+pub fn test_free_check() {
+    use crate::mm::allocator::free_check::{FreeCheckError, FreeCheckTable};
+    let page = |pfn: usize| PhysAddr::new(pfn * MMArch::PAGE_SIZE);
+    let mut free = vec![0u64; 2];
+    let mut seen_allocated = vec![0u64; 2];
+    let mut table = FreeCheckTable::new(&mut free, &mut seen_allocated);
+    assert_eq!(table.frames(), 128);
+    // [16, 48)位于伙伴分配器中，其余的页帧在启用检查之前就已经被分配
+    table.mark_free(page(16), PageFrameCount::new(32));
+
+    // 释放从未分配的页帧（其中的第一个空闲页帧被报告）
+    assert_eq!(
+        table.on_free(page(12), PageFrameCount::new(8)),
+        Err(FreeCheckError::NotAllocated(page(16)))
+    );
+    // 出错时表没有被修改：[12, 16)仍然可以被释放
+    table.on_free(page(12), PageFrameCount::new(4)).unwrap();
+    // 在启用检查之前被分配的页帧，释放之后再次释放，同样是重复释放
+    assert_eq!(
+        table.on_free(page(14), PageFrameCount::new(1)),
+        Err(FreeCheckError::DoubleFree(page(14)))
+    );
+
+    table.on_alloc(page(32), PageFrameCount::new(8));
+    table.on_free(page(32), PageFrameCount::new(8)).unwrap();
+    assert_eq!(
+        table.on_free(page(32), PageFrameCount::new(8)),
+        Err(FreeCheckError::DoubleFree(page(32)))
+    );
+    // 只释放了一部分的块
+    table.on_alloc(page(32), PageFrameCount::new(8));
+    table.on_free(page(36), PageFrameCount::new(4)).unwrap();
+    assert_eq!(
+        table.on_free(page(32), PageFrameCount::new(8)),
+        Err(FreeCheckError::DoubleFree(page(36)))
+    );
+
+    // 超出表的范围的页帧不属于RAM
+    assert_eq!(
+        table.on_free(page(127), PageFrameCount::new(2)),
+        Err(FreeCheckError::NotAllocated(page(128)))
+    );
+}
+
+/// 检查`PageFrameUsage`的各个字段：先检查构造出的已知状态，再检查全局分配器在分配、预热缓存、固定页帧之后的变化
+pub fn test_frame_usage() {
+    let usage = PageFrameUsage::new(PageFrameCount::new(30), PageFrameCount::new(100))
+        .with_breakdown(
+            PageFrameCount::new(4),
+            PageFrameCount::new(5),
+            PageFrameCount::new(6),
+        );
+    assert_eq!(usage.total().data(), 100);
+    assert_eq!(usage.used().data(), 30);
+    assert_eq!(usage.free().data(), 70);
+    assert_eq!(usage.reserved().data(), 4);
+    assert_eq!(usage.percpu_cached().data(), 5);
+    assert_eq!(usage.pinned().data(), 6);
+    assert_eq!(usage.total_bytes(), 100 * MMArch::PAGE_SIZE);
+    assert_eq!(usage.used_bytes(), 30 * MMArch::PAGE_SIZE);
+    assert_eq!(usage.free_bytes(), 70 * MMArch::PAGE_SIZE);
+
+    let plain = PageFrameUsage::new(PageFrameCount::new(1), PageFrameCount::new(2));
+    assert_eq!(plain.reserved().data(), 0);
+    assert_eq!(plain.percpu_cached().data(), 0);
+    assert_eq!(plain.pinned().data(), 0);
+
+    let before = unsafe { LockedFrameAllocator.usage() };
+    assert_eq!(
+        before.reserved(),
+        LockedFrameAllocator::low_watermark(before.total())
+    );
+    assert_eq!(before.pinned(), pinned_frames());
+
+    // 直接从伙伴分配器分配，不经过缓存，使得已使用的数量恰好增加count
+    let count = PageFrameCount::new(4);
+    let (paddr, allocated) = lock_inner_allocator()
+        .as_mut()
+        .and_then(|allocator| unsafe { allocator.allocate(count) })
+        .expect("test_frame_usage: allocate failed");
+    pin_frames(allocated);
+    let after = unsafe { LockedFrameAllocator.usage() };
+    assert_eq!(after.total(), before.total());
+    assert_eq!(after.used().data(), before.used().data() + allocated.data());
+    assert_eq!(after.free().data(), before.free().data() - allocated.data());
+    assert_eq!(
+        after.pinned().data(),
+        before.pinned().data() + allocated.data()
+    );
+    unpin_frames(allocated);
+    unsafe { LockedFrameAllocator.free_to_buddy(paddr, allocated) };
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.pinned(),
+        before.pinned()
+    );
+
+    // 预热的页帧在伙伴分配器看来是已使用的，同时被统计在percpu_cached中
+    let cpu_id = smp_get_processor_id() as usize;
+    let warmed = LockedFrameAllocator.warmup_cpu(cpu_id, 2);
+    let warm = unsafe { LockedFrameAllocator.usage() };
+    assert_eq!(
+        warm.percpu_cached().data(),
+        before.percpu_cached().data() + warmed
+    );
+    assert_eq!(warm.used().data(), before.used().data() + warmed);
+    assert_eq!(warm.percpu_cached().data(), frame_cache_total());
+}
+
+/// 从大页池中取得一个预先清零的大页，以及在池为空时当场分配一个大页，检查它们都是全零并且按2M对齐的
+pub fn test_huge_pool() {
+    let is_zeroed_huge_page = |paddr: PhysAddr| {
+        let p = unsafe { MMArch::phys_2_virt(paddr) }
+            .unwrap()
+            .as_ptr::<u64>();
+        paddr.check_aligned(HUGE_PAGE_SIZE)
+            && (0..HUGE_PAGE_SIZE / 8).all(|i| unsafe { p.add(i).read_volatile() } == 0)
+    };
+    let old_size = huge_pool_size();
+    set_huge_pool_size(0);
+    assert_eq!(huge_pool_stats().pooled, 0);
+    // 池的大小为0时不会补充
+    assert_eq!(huge_pool_refill(1), 0);
+
+    // 先弄脏一个大页再释放，使得补充池时很可能重新得到它
+    let dirty = alloc_zeroed_huge_page().expect("test_huge_pool: allocate failed");
+    unsafe {
+        MMArch::write_bytes(MMArch::phys_2_virt(dirty).unwrap(), 0xa5, HUGE_PAGE_SIZE);
+        free_huge_page(dirty);
+    }
+
+    set_huge_pool_size(1);
+    let before = huge_pool_stats();
+    if huge_pool_refill(4) == 0 {
+        kwarn!("test_huge_pool: not enough memory to refill the pool, skipped");
+        set_huge_pool_size(old_size);
+        return;
+    }
+    // 池已满，不会补充超过池的大小的块
+    assert_eq!(huge_pool_refill(4), 0);
+    let stats = huge_pool_stats();
+    assert_eq!(stats.size, 1);
+    assert_eq!(stats.pooled, 1);
+    assert_eq!(stats.refilled, before.refilled + 1);
+
+    let pooled = alloc_zeroed_huge_page().unwrap();
+    assert!(is_zeroed_huge_page(pooled));
+    assert_eq!(huge_pool_stats().hits, before.hits + 1);
+    assert_eq!(huge_pool_stats().pooled, 0);
+
+    // 池为空时，退化为当场分配并清零
+    let fallback = alloc_zeroed_huge_page().expect("test_huge_pool: allocate failed");
+    assert!(is_zeroed_huge_page(fallback));
+    assert_eq!(huge_pool_stats().misses, before.misses + 1);
+
+    unsafe {
+        free_huge_page(pooled);
+        free_huge_page(fallback);
+    }
+    // 缩小池的大小时，池中的块被归还
+    huge_pool_refill(1);
+    set_huge_pool_size(0);
+    assert_eq!(huge_pool_stats().pooled, 0);
+    set_huge_pool_size(old_size);
+}
+
+/// 隔离一个空闲的页帧，检查它不再能被分配；隔离一个正在使用的页帧，检查它被释放时没有回到空闲链表。
+/// 两个页帧在测试之后一直保持隔离的状态
+pub fn test_quarantine() {
+    let state_of = |paddr: PhysAddr| {
+        quarantined_frames()
+            .iter()
+            .find(|frame| frame.paddr == paddr)
+            .map(|frame| frame.state)
+    };
+    let alloc_from_buddy = || {
+        lock_inner_allocator()
+            .as_mut()
+            .and_then(|allocator| unsafe { allocator.allocate(PageFrameCount::new(1)) })
+            .map(|(paddr, _)| paddr)
+            .expect("test_quarantine: allocate failed")
+    };
+
+    // 空闲的页帧：立即被取出
+    let free_paddr = alloc_from_buddy();
+    unsafe { LockedFrameAllocator.free_to_buddy(free_paddr, PageFrameCount::new(1)) };
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    assert_eq!(
+        quarantine_frame(free_paddr + 0x123),
+        Ok(QuarantineState::Isolated)
+    );
+    assert_eq!(state_of(free_paddr), Some(QuarantineState::Isolated));
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data(),
+        free_before.data() - 1
+    );
+    assert!(!LockedFrameAllocator.take_free_frame(free_paddr));
+    let frames: Vec<PhysAddr> = (0..16).map(|_| alloc_from_buddy()).collect();
+    assert!(!frames.contains(&free_paddr));
+    for paddr in frames {
+        unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+    }
+    // 重复报告同一个页帧
+    assert_eq!(quarantine_frame(free_paddr), Ok(QuarantineState::Isolated));
+
+    // 正在使用的页帧：被释放时才被隔离，不会回到空闲链表
+    let used_paddr = alloc_from_buddy();
+    assert_eq!(quarantine_frame(used_paddr), Ok(QuarantineState::Pending));
+    assert_eq!(state_of(used_paddr), Some(QuarantineState::Pending));
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    unsafe { LockedFrameAllocator.free_one(used_paddr) };
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+    assert_eq!(state_of(used_paddr), Some(QuarantineState::Isolated));
+    assert!(!LockedFrameAllocator.take_free_frame(used_paddr));
+}
+
+/// 检查分配页帧使空闲页帧的数量降到阈值以下时，内存压力的回调函数被调用一次，以及取消登记
+pub fn test_low_memory_callback() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn on_low_memory() {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    // 多页的分配总是经过伙伴分配器
+    let count = PageFrameCount::new(2);
+    let alloc = || unsafe { LockedFrameAllocator.allocate(count) }.unwrap().0;
+    let free = |paddr: PhysAddr| unsafe { LockedFrameAllocator.free(paddr, count) };
+
+    assert_eq!(
+        register_low_memory_callback(0, on_low_memory),
+        Err(SystemError::EINVAL)
+    );
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let threshold = unsafe { LockedFrameAllocator.usage() }.free().data() - 1;
+    let handle = register_low_memory_callback(threshold, on_low_memory).unwrap();
+
+    // 第一次分配越过阈值，第二次分配时已经低于阈值，不再调用
+    let a = alloc();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    let b = alloc();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    free(b);
+    free(a);
+    // 回到阈值之上之后，再次越过阈值时重新调用
+    let a = alloc();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+    free(a);
+
+    unregister_low_memory_callback(handle).unwrap();
+    assert_eq!(
+        unregister_low_memory_callback(handle),
+        Err(SystemError::EINVAL)
+    );
+    let a = alloc();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+    free(a);
+    drop(irq_guard);
+}
+
+/// 检查每个CPU的页帧缓存：为空时成批补充，单页的释放放入缓存，缓存已满时成批归还，以及归还所有缓存
+pub fn test_frame_cache_magazine() {
+    let cpu_id = smp_get_processor_id() as usize;
+    let single = PageFrameCount::new(1);
+    // 预先分配好内存：之后堆的分配也可能从页帧缓存中取得页帧
+    let mut frames = Vec::with_capacity(FRAME_CACHE_SIZE + 1);
+    let alloc_from_buddy = || {
+        lock_inner_allocator()
+            .as_mut()
+            .and_then(|allocator| unsafe { allocator.allocate(single) })
+            .map(|(paddr, _)| paddr)
+            .expect("test_frame_cache_magazine: allocate failed")
+    };
+
+    // 关闭中断，避免中断处理程序分配或者释放页帧
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    LockedFrameAllocator.drain_frame_caches();
+    assert_eq!(frame_cache_total(), 0);
+    assert_eq!(LockedFrameAllocator.drain_frame_caches(), 0);
+
+    // 缓存为空：只获取一次全局分配器的锁，补充一批页帧
+    let locks_before = LockedFrameAllocator::global_lock_count();
+    for _ in 0..FRAME_CACHE_BATCH {
+        frames.push(unsafe { LockedFrameAllocator.allocate(single) }.unwrap().0);
+    }
+    assert_eq!(LockedFrameAllocator::global_lock_count(), locks_before + 1);
+    assert_eq!(frame_cache_count(cpu_id), 0);
+
+    // 单页的释放放入当前CPU的缓存，不获取全局分配器的锁
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    let locks_before = LockedFrameAllocator::global_lock_count();
+    for paddr in frames.drain(..) {
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+    }
+    assert_eq!(LockedFrameAllocator::global_lock_count(), locks_before);
+    assert_eq!(frame_cache_count(cpu_id), FRAME_CACHE_BATCH);
+    assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+
+    // 缓存已满时，先把最早放入的一批页帧归还给全局分配器
+    for _ in 0..FRAME_CACHE_SIZE - FRAME_CACHE_BATCH + 1 {
+        frames.push(alloc_from_buddy());
+    }
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    for paddr in frames.drain(..) {
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+    }
+    assert_eq!(
+        frame_cache_count(cpu_id),
+        FRAME_CACHE_SIZE - FRAME_CACHE_BATCH + 1
+    );
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data(),
+        free_before.data() + FRAME_CACHE_BATCH
+    );
+
+    // 归还所有CPU的缓存
+    let cached = frame_cache_total();
+    let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+    assert_eq!(LockedFrameAllocator.drain_frame_caches(), cached);
+    assert_eq!(frame_cache_total(), 0);
+    assert_eq!(
+        unsafe { LockedFrameAllocator.usage() }.free().data(),
+        free_before.data() + cached
+    );
+    drop(irq_guard);
+}
+
+/// 检查当前CPU的紧急页帧池：补充到满、取出和归还页帧
+pub fn test_emergency_pool() {
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    emergency_refill();
+    let full = emergency_pool_count();
+    assert!(full > 0);
+    // 已经满了，不再补充
+    assert_eq!(emergency_refill(), 0);
+
+    let paddr = emergency_alloc().expect("test_emergency_pool: pool is empty");
+    assert_eq!(emergency_pool_count(), full - 1);
+    unsafe { emergency_free(paddr) };
+    assert_eq!(emergency_pool_count(), full);
+    drop(irq_guard);
+}
+
+/// 检查耗尽全局分配器之后，设置了`AllocFlags::CRITICAL`的单页分配从紧急页帧池中取得页帧，
+/// 而普通的分配失败
+pub fn test_emergency_critical_fallback() {
+    let flags = AllocFlags::CRITICAL | AllocFlags::NOWARN;
+    let single = PageFrameCount::new(1);
+    // 预先分配好内存：耗尽页帧之后，堆无法再扩展
+    let mut held: Vec<(PhysAddr, PageFrameCount)> = Vec::with_capacity(1 << 16);
+
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    emergency_refill();
+    let full = emergency_pool_count();
+    assert!(full > 0);
+
+    // 从大到小耗尽伙伴分配器（包括低水位线以下的保留部分），直到单页的分配用到了紧急页帧池
+    let mut fallback = None;
+    'exhaust: for order in (0..=10).rev() {
+        let count = PageFrameCount::new(1 << order);
+        while held.len() < held.capacity() {
+            let r =
+                unsafe { LockedFrameAllocator.allocate_flags(count, flags, FRAME_TAG_UNTAGGED) };
+            let r = match r {
+                Some(r) => r,
+                None => break,
+            };
+            if count == single && emergency_pool_count() < full {
+                fallback = Some(r.0);
+                break 'exhaust;
+            }
+            held.push(r);
+        }
+    }
+
+    let normal = fallback.map(|_| unsafe {
+        LockedFrameAllocator.allocate_flags(single, AllocFlags::NOWARN, FRAME_TAG_UNTAGGED)
+    });
+    if let Some(paddr) = fallback {
+        unsafe { emergency_free(paddr) };
+    }
+    for (paddr, count) in held {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+    drop(irq_guard);
+
+    match normal {
+        Some(r) => {
+            assert!(
+                r.is_none(),
+                "non-critical allocation succeeded after exhaustion"
+            );
+            assert_eq!(emergency_pool_count(), full);
+        }
+        None => kwarn!("test_emergency_critical_fallback: failed to exhaust memory, skipped"),
+    }
+}
+
+/// 检查低水位线：普通的分配在空闲页帧低于低水位线时失败，而设置了`AllocFlags::CRITICAL`的分配
+/// 仍然可以使用低水位线以下的保留页帧（不需要动用紧急页帧池）
+pub fn test_critical_bypasses_watermark() {
+    let single = PageFrameCount::new(1);
+    // 预先分配好内存：耗尽页帧之后，堆无法再扩展
+    let mut held: Vec<(PhysAddr, PageFrameCount)> = Vec::with_capacity(1 << 16);
+
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    emergency_refill();
+    let emergency = emergency_pool_count();
+
+    // 从大到小进行普通的分配，直到单页的分配也因为低水位线而失败
+    let mut reached = false;
+    for order in (0..=10).rev() {
+        let count = PageFrameCount::new(1 << order);
+        while held.len() < held.capacity() {
+            match unsafe {
+                LockedFrameAllocator.allocate_flags(count, AllocFlags::NOWARN, FRAME_TAG_UNTAGGED)
+            } {
+                Some(r) => held.push(r),
+                None => {
+                    reached = count == single;
+                    break;
+                }
+            }
+        }
+    }
+
+    let critical = if reached {
+        assert!(!LockedFrameAllocator::above_low_watermark(single));
+        unsafe {
+            LockedFrameAllocator.allocate_flags(
+                single,
+                AllocFlags::CRITICAL | AllocFlags::NOWARN,
+                FRAME_TAG_UNTAGGED,
+            )
+        }
+    } else {
+        None
+    };
+    let emergency_after = emergency_pool_count();
+    if let Some((paddr, count)) = critical {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+    for (paddr, count) in held {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+    drop(irq_guard);
+
+    if !reached {
+        kwarn!("test_critical_bypasses_watermark: failed to reach the low watermark, skipped");
+        return;
+    }
+    assert!(
+        critical.is_some(),
+        "critical allocation failed below the low watermark"
+    );
+    assert_eq!(emergency_after, emergency);
+}
+
+/// 检查后台清零：单页的释放放入“脏”链表，清零之后移动到“干净”链表，需要清零的分配从中取得页帧，
+/// 以及关闭时归还所有页帧
+pub fn test_scrub() {
+    let single = PageFrameCount::new(1);
+    // 关闭中断，避免中断处理程序分配或者释放页帧
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let was_enabled = scrub_enabled();
+    scrub_set_enabled(true);
+
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(single) }.unwrap();
+    unsafe { MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0xa5, MMArch::PAGE_SIZE) };
+    let before = scrub_stats();
+    unsafe { LockedFrameAllocator.free_one(paddr) };
+    assert_eq!(scrub_stats().dirty.data(), before.dirty.data() + 1);
+
+    while scrub_some(SCRUB_IDLE_BATCH) > 0 {}
+    let scrubbed = scrub_stats();
+    assert_eq!(scrubbed.dirty.data(), 0);
+    assert!(scrubbed.clean.data() >= 1);
+
+    let (zeroed, _) = unsafe {
+        LockedFrameAllocator.allocate_flags(single, AllocFlags::ZERO, FRAME_TAG_UNTAGGED)
+    }
+    .unwrap();
+    assert_eq!(scrub_stats().clean.data(), scrubbed.clean.data() - 1);
+    let vaddr = unsafe { MMArch::phys_2_virt(zeroed) }.unwrap();
+    for offset in (0..MMArch::PAGE_SIZE).step_by(core::mem::size_of::<u64>()) {
+        assert_eq!(unsafe { MMArch::read::<u64>(vaddr + offset) }, 0);
+    }
+    unsafe { LockedFrameAllocator.free_one(zeroed) };
+
+    // 关闭时，链表中的页帧被归还给伙伴分配器
+    scrub_set_enabled(false);
+    let stats = scrub_stats();
+    assert_eq!(stats.dirty.data() + stats.clean.data(), 0);
+    scrub_set_enabled(was_enabled);
+    drop(irq_guard);
+}
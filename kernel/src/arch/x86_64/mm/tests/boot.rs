@@ -0,0 +1,1181 @@
+//! 启动阶段的内存布局与初始映射的测试
+
+use super::*;
+
+/// 检查直接映射区域上方的保护页没有被映射
+pub fn test_direct_map_guard() {
+    let (high, _) = X86_64MMArch::direct_map_guard_ranges();
+    let high = high.expect("direct map guard is not set up");
+    let kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_ref();
+    // 最高的RAM地址的上一页是被映射的，而紧邻它的下一页不应该被映射
+    let last_ram_page = high.start - MMArch::PAGE_SIZE;
+    assert!(mapper.translate(last_ram_page).is_some());
+    let mut vaddr = high.start;
+    while vaddr < high.end {
+        assert!(
+            mapper.translate(vaddr).is_none(),
+            "direct map guard page {:?} is mapped",
+            vaddr
+        );
+        vaddr += MMArch::PAGE_SIZE;
+    }
+}
+
+/// 检查所有的RAM都已经被映射到直接映射区域（无论是否建立了低地址的重映射）
+///
+/// 需要遍历全部RAM，因此只在`mm_destructive_tests`中运行，启动时的自检运行抽样的版本
+pub fn test_direct_map_covers_ram() {
+    check_direct_map_covers_ram(1);
+}
+
+/// 抽样检查RAM被映射到直接映射区域：每个区域的首尾页面，以及每隔若干个页面检查一个页面
+pub fn test_direct_map_covers_ram_sampled() {
+    // 步长不是2的幂，使得抽样的页面落在大页中不同的偏移处
+    check_direct_map_covers_ram(509);
+}
+
+/// 每隔stride个页面检查一个页面（每个区域的最后一个页面总是被检查）
+fn check_direct_map_covers_ram(stride: usize) {
+    let kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_ref();
+    for area in X86_64MMArch::phys_memory_areas() {
+        let count = PageFrameCount::new(page_align_up(area.size) / MMArch::PAGE_SIZE);
+        let vbase = unsafe { MMArch::phys_2_virt(area.base) }.unwrap();
+        for (i, (vaddr, paddr)) in page_map_range(vbase, area.base, count).enumerate() {
+            if i % stride != 0 && i + 1 != count.data() {
+                continue;
+            }
+            let mapped = mapper.translate(vaddr).map(|(p, _)| p);
+            assert_eq!(
+                mapped,
+                Some(paddr),
+                "RAM page {:?} is not mapped at {:?}",
+                paddr,
+                vaddr
+            );
+        }
+    }
+}
+
+/// 检查通过GS基址和每CPU偏移量写入的数据，落在当前CPU的那一份数据的物理页上
+pub fn test_percpu_area() {
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let cpu_id = smp_get_processor_id() as usize;
+    let offset = alloc_percpu_for(8, 8, &[cpu_id]).expect("Failed to allocate percpu data");
+    let value: u64 = 0x5a5a_1234_dead_beef;
+
+    let old_base = X86_64MMArch::percpu_base();
+    unsafe {
+        X86_64MMArch::set_percpu_base(percpu_unit_base(cpu_id));
+        asm!("mov gs:[{0}], {1}", in(reg) offset.data(), in(reg) value, options(nostack, preserves_flags));
+        X86_64MMArch::set_percpu_base(old_base);
+    }
+    drop(irq_guard);
+
+    let vaddr = offset.ptr_for(cpu_id);
+    let (paddr, _) = KernelMapper::lock()
+        .translate(vaddr)
+        .expect("percpu data is not mapped");
+    let paddr = paddr + (vaddr.data() & MMArch::PAGE_OFFSET_MASK);
+    let read: u64 = unsafe { MMArch::read(MMArch::phys_2_virt(paddr).unwrap()) };
+    assert_eq!(read, value);
+}
+
+/// 检查初始页表的访问器：设置之前读取返回None，设置之后返回设置的值
+pub fn test_initial_page_table() {
+    let cell: Lazy<PhysAddr> = Lazy::new();
+    assert!(cell.try_get().is_none());
+    cell.init(PhysAddr::new(0x1000));
+    assert_eq!(cell.try_get().copied(), Some(PhysAddr::new(0x1000)));
+
+    let initial = X86_64MMArch::try_initial_page_table().expect("initial page table is not set");
+    assert!(!initial.is_null());
+    assert_eq!(MMArch::initial_page_table(), initial);
+}
+
+/// 统计页表中（从level级开始）所有有效的最后一级页表项，并把它们的虚拟地址放入out
+unsafe fn collect_leaf_mappings(table: &PageTable<MMArch>, out: &mut Vec<VirtAddr>) {
+    for i in 0..MMArch::PAGE_ENTRY_NUM {
+        match table.entry(i) {
+            Some(entry) if entry.present() => {}
+            _ => continue,
+        }
+        if table.level() == 0 {
+            out.push(table.entry_base(i).unwrap());
+        } else {
+            collect_leaf_mappings(&table.next_level_table(i).unwrap(), out);
+        }
+    }
+}
+
+/// 检查跳板页同时出现在完整的内核页表和精简的内核页表中，并且是精简的内核页表中唯一的内核映射
+pub fn test_trampoline() {
+    let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    let vaddr = TRAMPOLINE_AREA_BASE + MMArch::PAGE_SIZE;
+    map_trampoline(paddr, vaddr).expect("Failed to map trampoline");
+    assert_eq!(
+        map_trampoline(paddr, vaddr),
+        Err(MmError::AlreadyMapped(vaddr))
+    );
+    assert!(map_trampoline(paddr, VirtAddr::new(MMArch::PHYS_OFFSET)).is_err());
+
+    // 完整的内核页表
+    let (mapped, flags) = KernelMapper::lock().as_ref().translate(vaddr).unwrap();
+    assert_eq!(mapped, paddr);
+    assert!(flags.has_global() && flags.has_execute() && !flags.has_write() && !flags.has_user());
+
+    // 精简的内核页表
+    let trimmed = unsafe {
+        PageMapper::new(
+            PageTableKind::Kernel,
+            trimmed_kernel_table().unwrap(),
+            LockedFrameAllocator,
+        )
+    };
+    let (mapped, flags) = trimmed.translate(vaddr).unwrap();
+    assert_eq!(mapped, paddr);
+    assert!(flags.has_global());
+
+    let root = trimmed.table();
+    let mut leaves = Vec::new();
+    for i in KERNEL_PML4E_NO..MMArch::PAGE_ENTRY_NUM {
+        match unsafe { root.entry(i) } {
+            Some(entry) if entry.present() => unsafe {
+                collect_leaf_mappings(&root.next_level_table(i).unwrap(), &mut leaves)
+            },
+            _ => {}
+        }
+    }
+    let vaddr_low = VirtAddr::new(vaddr.data() & !MMArch::PAGE_NEGATIVE_MASK);
+    assert_eq!(leaves, [vaddr_low]);
+
+    assert_eq!(unmap_trampoline(vaddr), Ok(paddr));
+    assert!(KernelMapper::lock().as_ref().translate(vaddr).is_none());
+    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(1)) };
+}
+
+/// 使用模拟的模块内存区域，检查initrd的保留-映射-释放的流程
+pub fn test_initrd() {
+    if initrd_frames().is_some() {
+        kdebug!("test_initrd skipped: a real initrd is present");
+        return;
+    }
+    assert_eq!(map_initrd(), Err(SystemError::ENOENT));
+
+    // 模拟bootloader加载的模块：从页帧分配器分配（即“已分配”的状态），起始地址不按页对齐
+    let (paddr, count) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(4)) }.unwrap();
+    let start = paddr + 0x100;
+    let size = 3 * MMArch::PAGE_SIZE;
+    let pattern = |i: usize| (i * 7 + 3) as u8;
+    let vstart = unsafe { MMArch::phys_2_virt(start) }.unwrap();
+    for i in 0..size {
+        unsafe { MMArch::write(vstart + i, pattern(i)) };
+    }
+
+    initrd_reserve(start, size).unwrap();
+    assert_eq!(initrd_reserve(start, size), Err(SystemError::EEXIST));
+    assert_eq!(initrd_frames(), Some((paddr, count)));
+
+    let (vaddr, len) = map_initrd().unwrap();
+    assert_eq!(len, size);
+    assert_eq!(map_initrd(), Ok((vaddr, len)));
+    for i in (0..size).step_by(97) {
+        assert_eq!(unsafe { MMArch::read::<u8>(vaddr + i) }, pattern(i));
+    }
+    let (_, flags) = KernelMapper::lock().as_ref().translate(vaddr).unwrap();
+    assert!(!flags.has_write());
+    assert!(!flags.has_execute() || X86_64MMArch::is_xd_reserved());
+
+    assert_eq!(release_initrd(), Ok(count));
+    assert!(KernelMapper::lock().as_ref().translate(vaddr).is_none());
+    assert_eq!(release_initrd(), Err(SystemError::ENOENT));
+}
+
+/// 检查基地址没有对齐、大小不是页大小整数倍的内存区域被裁剪为完整的页
+pub fn test_area_trimming() {
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let trimmed = |base: usize, size: usize| {
+        area(base, size)
+            .page_trimmed()
+            .map(|a| (a.base.data(), a.size))
+    };
+
+    // 已经对齐的区域保持不变
+    assert_eq!(trimmed(0x10_0000, 0x4000), Some((0x10_0000, 0x4000)));
+    // 基地址没有对齐：向上对齐，大小相应减少
+    assert_eq!(trimmed(0x10_0400, 0x4000), Some((0x10_1000, 0x3000)));
+    // 大小不是页大小的整数倍：向下取整
+    assert_eq!(trimmed(0x10_0000, 0x4800), Some((0x10_0000, 0x4000)));
+    // 两者都没有对齐
+    assert_eq!(trimmed(0x9fc00, 0x10_0400), Some((0xa0000, 0x10_0000)));
+    // 裁剪之后为空的区域被丢弃
+    assert_eq!(trimmed(0x10_0400, 0x800), None);
+    assert_eq!(trimmed(0x10_0400, 0x1000), None);
+    assert_eq!(trimmed(0x10_0000, 0xfff), None);
+
+    // 内核实际使用的内存区域都是以页为粒度的
+    for a in MMArch::phys_memory_areas() {
+        assert!(a.base.check_aligned(MMArch::PAGE_SIZE));
+        assert_eq!(a.size & (MMArch::PAGE_SIZE - 1), 0);
+        assert_ne!(a.size, 0);
+    }
+}
+
+/// 检查内核镜像的物理地址范围的计算，以及伙伴分配器不会分配出内核镜像所在的页帧
+pub fn test_kernel_phys_range() {
+    let info = unsafe { BOOTSTRAP_MM_INFO }.expect("bootstrap info is not set");
+    let start = info
+        .kernel_phys_start()
+        .expect("kernel is not in the direct map");
+    let end = info
+        .kernel_phys_end()
+        .expect("kernel is not in the direct map");
+    assert!(start < end);
+    assert_eq!(Some(end), unsafe {
+        MMArch::virt_2_phys(VirtAddr::new(info.start_brk))
+    });
+
+    let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    assert!(
+        paddr + MMArch::PAGE_SIZE <= start || paddr >= PhysAddr::new(page_align_up(end.data()))
+    );
+    unsafe { LockedFrameAllocator.free_one(paddr) };
+
+    // 内核基址不在直接映射区域中（比如链接脚本被修改）
+    let moved = X86_64MMBootstrapInfo {
+        kernel_code_start: 0x10_0000,
+        start_brk: 0x20_0000,
+        ..info
+    };
+    assert_eq!(moved.kernel_phys_start(), None);
+    assert_eq!(moved.kernel_phys_end(), None);
+    let direct = X86_64MMBootstrapInfo {
+        kernel_code_start: X86_64MMArch::PHYS_OFFSET + 0x10_0000,
+        start_brk: X86_64MMArch::PHYS_OFFSET + 0x20_0000,
+        ..info
+    };
+    assert_eq!(direct.kernel_phys_start(), Some(PhysAddr::new(0x10_0000)));
+    assert_eq!(direct.kernel_phys_end(), Some(PhysAddr::new(0x20_0000)));
+}
+
+/// 检查内核各个段的顺序的校验：真实的布局通过校验，故意打乱顺序的布局被拒绝
+pub fn test_check_layout() {
+    let info = unsafe { BOOTSTRAP_MM_INFO }.expect("bootstrap info is not set");
+    assert_eq!(info.check_layout(), Ok(()));
+
+    let base = X86_64MMArch::PHYS_OFFSET + 0x10_0000;
+    let good = X86_64MMBootstrapInfo {
+        kernel_code_start: base,
+        kernel_code_end: base + 0x1000,
+        kernel_data_end: base + 0x2000,
+        kernel_rodata_end: base + 0x3000,
+        ro_after_init_start: base + 0x3000,
+        ro_after_init_end: base + 0x4000,
+        start_brk: base + 0x5000,
+    };
+    assert_eq!(good.check_layout(), Ok(()));
+
+    let empty_code = X86_64MMBootstrapInfo {
+        kernel_code_end: good.kernel_code_start,
+        ..good
+    };
+    assert_eq!(
+        empty_code.check_layout(),
+        Err("kernel_code_start >= kernel_code_end")
+    );
+    // 数据段与只读数据段的顺序颠倒时，只读数据段的判断永远不会成立，所有页面都会变成可写、可执行
+    let swapped = X86_64MMBootstrapInfo {
+        kernel_data_end: good.kernel_rodata_end,
+        kernel_rodata_end: good.kernel_data_end,
+        ..good
+    };
+    assert_eq!(
+        swapped.check_layout(),
+        Err("kernel_data_end > kernel_rodata_end")
+    );
+    let brk_before_end = X86_64MMBootstrapInfo {
+        start_brk: good.ro_after_init_start,
+        ..good
+    };
+    assert_eq!(
+        brk_before_end.check_layout(),
+        Err("ro_after_init_end > start_brk")
+    );
+}
+
+/// 检查内存区域数组已满时的处理：相邻的区域被合并以腾出空间，无法合并时新的区域被丢弃并报告
+pub fn test_memory_map_truncation() {
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let pairs = |areas: &[PhysMemoryArea]| {
+        areas
+            .iter()
+            .map(|a| (a.base.data(), a.size))
+            .collect::<Vec<_>>()
+    };
+    let mut areas = [area(0, 0); 2];
+
+    let count = push_memory_area(&mut areas, 0, area(0x10_0000, 0x1000)).unwrap();
+    let count = push_memory_area(&mut areas, count, area(0x10_1000, 0x1000)).unwrap();
+    assert_eq!(count, 2);
+    // 数组已满，但前两个区域首尾相接，合并之后还有空间
+    let count = push_memory_area(&mut areas, count, area(0x30_0000, 0x1000)).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(pairs(&areas), [(0x10_0000, 0x2000), (0x30_0000, 0x1000)]);
+
+    // 互不相邻的区域无法合并，新的区域被丢弃
+    assert_eq!(
+        push_memory_area(&mut areas, count, area(0x50_0000, 0x1000)),
+        Err(2)
+    );
+    assert_eq!(pairs(&areas), [(0x10_0000, 0x2000), (0x30_0000, 0x1000)]);
+}
+
+/// 检查启动阶段内存初始化失败信息的格式：区分内存不足与内部错误，并输出bump分配器的高水位以及内存布局
+pub fn test_boot_mm_failure_message() {
+    let areas = [
+        PhysMemoryArea {
+            base: PhysAddr::new(0x10_0000),
+            size: 0x20_0000,
+        },
+        PhysMemoryArea {
+            base: PhysAddr::new(0x100_0000),
+            size: 0x1000,
+        },
+    ];
+    let oom = MmError::OutOfMemory {
+        needed: PageFrameCount::new(1),
+    };
+    let mut msg = String::new();
+    format_boot_mm_failure(&mut msg, "map physical memory", &oom, 0x30_0000, &areas).unwrap();
+    let lines: Vec<&str> = msg.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "mm init failed at [map physical memory]: not enough RAM to map all RAM (out of memory, 1 page(s) needed)",
+            "bump allocator high-water mark: 0x300000",
+            "memory map (2 areas):",
+            "  [0] 0x0000000000100000 - 0x0000000000300000 (2048 KB)",
+            "  [1] 0x0000000001000000 - 0x0000000001001000 (4 KB)",
+        ]
+    );
+
+    let broken = MmError::Unaligned(0x123);
+    let mut msg = String::new();
+    format_boot_mm_failure(&mut msg, "remap low address", &broken, 0, &[]).unwrap();
+    assert_eq!(
+        msg.lines().next(),
+        Some("mm init failed at [remap low address]: internal invariant broken (address 0x123 is not page aligned)")
+    );
+    assert!(msg.contains("memory map (0 areas):"));
+}
+
+/// 检查内核镜像所在的物理内存是否被可用内存区域覆盖的判断
+pub fn test_kernel_ram_coverage() {
+    let info = unsafe { BOOTSTRAP_MM_INFO }.expect("bootstrap info is not set");
+    let start = info.kernel_phys_start().unwrap().data();
+    let end = info.kernel_phys_end().unwrap().data();
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+
+    // 真实的内存布局
+    assert_eq!(info.check_ram_coverage(MMArch::phys_memory_areas()), Ok(()));
+
+    // 内核镜像被一个区域完全覆盖
+    let page_start = start & !(MMArch::PAGE_SIZE - 1);
+    let whole = [area(page_start, page_align_up(end) - page_start)];
+    assert_eq!(info.check_ram_coverage(&whole), Ok(()));
+    // 被两个相邻的、乱序的区域覆盖
+    let mid = page_align_up((start + end) / 2);
+    let split = [
+        area(mid, page_align_up(end) - mid),
+        area(page_start, mid - page_start),
+    ];
+    assert_eq!(info.check_ram_coverage(&split), Ok(()));
+
+    // 内存布局中缺少内核所在的区域
+    let missing = [area(0, page_start), area(page_align_up(end), 0x1000_0000)];
+    assert_eq!(info.check_ram_coverage(&missing), Err(PhysAddr::new(start)));
+    // 中间有空洞
+    let hole = [
+        area(page_start, mid - page_start),
+        area(mid + MMArch::PAGE_SIZE, page_align_up(end) - mid),
+    ];
+    assert_eq!(info.check_ram_coverage(&hole), Err(PhysAddr::new(mid)));
+    // 区域在内核镜像结束之前就结束了
+    let short = [area(page_start, mid - page_start)];
+    assert_eq!(info.check_ram_coverage(&short), Err(PhysAddr::new(mid)));
+    assert!(info.check_ram_coverage(&[]).is_err());
+}
+
+/// 检查`map_phys_best`在地址按2M对齐时使用2M的大页，而设置了FORCE_4K_PAGES之后，
+/// 即使地址按1G对齐，较大的映射也只会产生4K的页表项，而不会产生大页
+pub fn test_force_4k_pages() {
+    unsafe fn check_no_huge_pages(table: &PageTable<MMArch>, leaves: &mut usize) {
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            let entry = match table.entry(i) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            if table.level() == 0 {
+                *leaves += 1;
+                continue;
+            }
+            assert_eq!(
+                entry.data() & MMArch::ENTRY_FLAG_HUGE_PAGE,
+                0,
+                "level {} entry {} is a huge page",
+                table.level(),
+                i
+            );
+            check_no_huge_pages(&table.next_level_table(i).unwrap(), leaves);
+        }
+    }
+
+    with_user_mapper(|umapper| {
+        let count = PageFrameCount::new(1024);
+        let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate(count) }.unwrap();
+        assert_eq!(allocated, count);
+        let vaddr = VirtAddr::new(0x4000_0000);
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+
+        let prev = force_4k_pages();
+        set_force_4k_pages(false);
+        let huge = PageSize::Size2M.bytes::<MMArch>();
+        assert_eq!(
+            umapper.utable.best_page_size(vaddr, paddr, count.bytes()),
+            huge
+        );
+        // 剩余不足2M，或者物理地址没有按2M对齐时，只能使用4K页
+        assert_eq!(
+            umapper
+                .utable
+                .best_page_size(vaddr, paddr, huge - MMArch::PAGE_SIZE),
+            MMArch::PAGE_SIZE
+        );
+        assert_eq!(
+            umapper
+                .utable
+                .best_page_size(vaddr, paddr + MMArch::PAGE_SIZE, count.bytes()),
+            MMArch::PAGE_SIZE
+        );
+        unsafe { umapper.utable.map_phys_best(vaddr, paddr, count, flags) }
+            .expect("Failed to map")
+            .commit();
+        for i in [0, 512] {
+            let (level, _) = umapper
+                .utable
+                .leaf_entry(vaddr + i * MMArch::PAGE_SIZE)
+                .unwrap();
+            assert_eq!(level, PageSize::Size2M.level());
+        }
+        let (mapped, _) = umapper
+            .utable
+            .translate(vaddr + 1023 * MMArch::PAGE_SIZE)
+            .unwrap();
+        assert_eq!(mapped, paddr + 1023 * MMArch::PAGE_SIZE);
+        for i in [0, 512] {
+            let (unmapped, _, flusher) = unsafe {
+                umapper
+                    .utable
+                    .unmap_phys_huge(vaddr + i * MMArch::PAGE_SIZE, PageSize::Size2M)
+            }
+            .unwrap();
+            flusher.flush();
+            assert_eq!(unmapped, paddr + i * MMArch::PAGE_SIZE);
+        }
+
+        set_force_4k_pages(true);
+        assert_eq!(
+            umapper.utable.best_page_size(vaddr, paddr, count.bytes()),
+            MMArch::PAGE_SIZE
+        );
+        unsafe { umapper.utable.map_phys_best(vaddr, paddr, count, flags) }
+            .expect("Failed to map")
+            .commit();
+        set_force_4k_pages(prev);
+
+        let mut leaves = 0;
+        unsafe { check_no_huge_pages(&umapper.utable.table(), &mut leaves) };
+        assert_eq!(leaves, count.data());
+        for i in [0, 511, 512, 1023] {
+            let (mapped, _) = umapper
+                .utable
+                .translate(vaddr + i * MMArch::PAGE_SIZE)
+                .unwrap();
+            assert_eq!(mapped, paddr + i * MMArch::PAGE_SIZE);
+        }
+
+        // 逐页释放映射的页帧
+    });
+}
+
+/// 检查AP处理器的启动代码所在的页帧位于1MB以下、按页对齐，并且不会被伙伴分配器分配出去
+pub fn test_ap_trampoline() {
+    let paddr = ap_trampoline_frame();
+    let size = AP_TRAMPOLINE_PAGES * MMArch::PAGE_SIZE;
+    assert!(paddr.check_aligned(MMArch::PAGE_SIZE));
+    assert!(paddr.data() >= AP_TRAMPOLINE_MIN);
+    assert!(paddr.data() + size <= AP_TRAMPOLINE_LIMIT);
+
+    let mut c_size: u64 = 0;
+    assert_eq!(rs_ap_trampoline_frame(&mut c_size), paddr.data() as u64);
+    assert_eq!(c_size as usize, size);
+
+    // 所有的页帧都不在伙伴分配器的空闲链表中
+    if let Some(ref allocator) = *INNER_ALLOCATOR.lock_irqsave() {
+        for i in 0..AP_TRAMPOLINE_PAGES {
+            assert!(!allocator.is_free(paddr + i * MMArch::PAGE_SIZE));
+        }
+    }
+    // 启动代码通过直接映射区域被复制过去
+    let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    let (mapped, _) = KernelMapper::lock().translate(vaddr).unwrap();
+    assert_eq!(mapped, paddr);
+}
+
+/// 检查`mem=`启动参数的解析，以及按照上限裁剪内存区域（优先裁剪地址最高的区域）
+pub fn test_mem_limit() {
+    const MB: usize = 1024 * 1024;
+    assert_eq!(parse_mem_size("64M"), Some(64 * MB));
+    assert_eq!(parse_mem_size("2g"), Some(2048 * MB));
+    assert_eq!(parse_mem_size("512k"), Some(512 * 1024));
+    assert_eq!(parse_mem_size("0x4000000"), Some(64 * MB));
+    assert_eq!(parse_mem_size("4096"), Some(4096));
+    assert_eq!(parse_mem_size(""), None);
+    assert_eq!(parse_mem_size("M"), None);
+    assert_eq!(parse_mem_size("12x"), None);
+    assert_eq!(parse_mem_size("99999999999999999999G"), None);
+    assert_eq!(
+        boot_cmdline_param("console=ttyS0 mem=128M quiet", "mem"),
+        Some("128M")
+    );
+    assert_eq!(boot_cmdline_param("memory=1G quiet", "mem"), None);
+
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let layout = [
+        area(0x1000, 0x9e000),
+        area(0x1_0000_0000, 1024 * MB),
+        area(0x10_0000, 2047 * MB),
+    ];
+    let total = |areas: &[PhysMemoryArea]| areas.iter().map(|a| a.size).sum::<usize>();
+
+    // 地址最高的区域被整个移除，其余区域的顺序不变，次高的区域被裁剪
+    let mut areas = layout;
+    let count = cap_memory_areas(&mut areas, 64 * MB, PhysAddr::new(0x20_0000));
+    assert_eq!(count, 2);
+    assert_eq!(total(&areas[0..count]), 64 * MB);
+    assert_eq!(areas[0].base, layout[0].base);
+    assert_eq!(areas[1].base, layout[2].base);
+    assert!(areas[0..count]
+        .iter()
+        .all(|a| a.base.data() + a.size <= 64 * MB + 0x10_0000));
+
+    // keep_below以下的内存不会被裁剪
+    let mut areas = layout;
+    let count = cap_memory_areas(&mut areas, 2 * MB, PhysAddr::new(16 * MB));
+    assert_eq!(count, 2);
+    assert_eq!(areas[1].base.data() + areas[1].size, 16 * MB);
+    // 上限足够大时不裁剪
+    let mut areas = layout;
+    assert_eq!(
+        cap_memory_areas(&mut areas, usize::MAX, PhysAddr::new(0)),
+        3
+    );
+    assert_eq!(total(&areas), total(&layout));
+
+    // 当前的内存布局
+    let ram = X86_64MMArch::total_ram_bytes();
+    assert_eq!(ram, total(X86_64MMArch::phys_memory_areas()));
+    if let Some(limit) = X86_64MMArch::mem_limit() {
+        assert!(
+            ram <= limit,
+            "total ram {:#x} exceeds mem= {:#x}",
+            ram,
+            limit
+        );
+    }
+    // 分配出的页帧都位于（被裁剪之后的）内存区域中
+    let guard = alloc_frames(PageFrameCount::new(1), 16).expect("alloc_frames failed");
+    for (paddr, _) in guard.frames() {
+        assert!(X86_64MMArch::phys_memory_areas()
+            .iter()
+            .any(|a| *paddr >= a.base && paddr.data() < a.base.data() + a.size));
+    }
+    drop(guard);
+}
+
+/// 检查bootloader没有报告可用内存时的检测，以及输出的错误信息
+pub fn test_no_usable_memory() {
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    assert_eq!(usable_memory_total(&[]), None);
+    assert_eq!(usable_memory_total(&[area(0x10_0000, 0)]), None);
+    assert_eq!(
+        usable_memory_total(&[area(0x1000, 0x9e000), area(0x10_0000, 0)]),
+        Some(0x9e000)
+    );
+    // 当前的内存布局中一定有可用的内存
+    assert_eq!(
+        usable_memory_total(X86_64MMArch::phys_memory_areas()),
+        Some(X86_64MMArch::total_ram_bytes())
+    );
+
+    let mut msg = String::new();
+    format_no_usable_memory(&mut msg, &[]).unwrap();
+    assert!(msg.starts_with(NO_USABLE_MEMORY_MSG));
+    assert!(msg.contains("0 usable areas"));
+    assert_eq!(
+        msg.contains("mem_fallback)"),
+        cfg!(feature = "mem_fallback")
+    );
+
+    // 回退的内存区域按页对齐，并且位于1MB以上（不与BIOS使用的低端内存重叠）
+    assert!(MEM_FALLBACK_AREA.base.check_aligned(MMArch::PAGE_SIZE));
+    assert_eq!(MEM_FALLBACK_AREA.size % MMArch::PAGE_SIZE, 0);
+    assert!(MEM_FALLBACK_AREA.base.data() >= 0x10_0000);
+}
+
+/// 使用人工构造的multiboot2标签，检查模块信息的解析：地址范围、命令行、截断以及非模块标签
+pub fn test_multiboot2_modules() {
+    /// 与`struct multiboot_tag_module_t`的布局相同，命令行紧跟在结构体之后
+    #[repr(C, align(8))]
+    struct ModuleTag {
+        tag_type: u32,
+        size: u32,
+        mod_start: u32,
+        mod_end: u32,
+        cmdline: [u8; 96],
+    }
+    // multiboot2.h中的MULTIBOOT_TAG_TYPE_MODULE以及MULTIBOOT_TAG_TYPE_CMDLINE
+    const TAG_MODULE: u32 = 3;
+    const TAG_CMDLINE: u32 = 1;
+
+    let tag = |tag_type: u32, start: u32, end: u32, cmdline: &[u8]| {
+        let mut t = ModuleTag {
+            tag_type,
+            size: (16 + cmdline.len() + 1) as u32,
+            mod_start: start,
+            mod_end: end,
+            cmdline: [0; 96],
+        };
+        t.cmdline[..cmdline.len()].copy_from_slice(cmdline);
+        t
+    };
+    let long = [b'x'; 80];
+    let tags = [
+        tag(TAG_MODULE, 0x20_0000, 0x20_3000, b"initrd.img"),
+        tag(TAG_CMDLINE, 0x1111, 0x2222, b"not a module"),
+        tag(TAG_MODULE, 0x30_0000, 0x30_0800, b""),
+        tag(TAG_MODULE, 0x40_0000, 0x40_1000, &long),
+    ];
+
+    let mut buffer = Mb2ModuleBuffer::new();
+    for t in tags.iter() {
+        unsafe { buffer.visit(t as *const ModuleTag as *const iter_data_t) };
+    }
+    assert_eq!(buffer.count, 3);
+    let modules: Vec<Mb2Module> = buffer.modules().collect();
+    assert_eq!(modules.len(), 3);
+
+    assert_eq!(modules[0].start(), PhysAddr::new(0x20_0000));
+    assert_eq!(modules[0].end(), PhysAddr::new(0x20_3000));
+    assert_eq!(modules[0].size(), 0x3000);
+    assert_eq!(modules[0].cmdline(), Some("initrd.img"));
+
+    assert_eq!(modules[1].size(), 0x800);
+    assert_eq!(modules[1].cmdline(), None);
+
+    // 过长的命令行被截断
+    let truncated = modules[2].cmdline().unwrap();
+    assert_eq!(truncated.len(), MB2_MODULE_CMDLINE_MAX - 1);
+    assert!(truncated.bytes().all(|b| b == b'x'));
+
+    // 超出缓冲区容量的模块只被计数
+    let mut buffer = Mb2ModuleBuffer::new();
+    for _ in 0..MB2_MAX_MODULES + 2 {
+        unsafe { buffer.visit(&tags[0] as *const ModuleTag as *const iter_data_t) };
+    }
+    assert_eq!(buffer.count as usize, MB2_MAX_MODULES + 2);
+    assert_eq!(buffer.modules().count(), MB2_MAX_MODULES);
+}
+
+/// 检查早期堆：切换之前按照对齐要求分配，空间不足或者切换之后分配失败，释放的内存不会被回收；
+/// 全局分配器已经切换到了真正的堆
+pub fn test_early_heap() {
+    let heap = EarlyHeap::<256>::new();
+    let a = heap.alloc(Layout::from_size_align(3, 1).unwrap()).unwrap();
+    let b = heap.alloc(Layout::from_size_align(8, 64).unwrap()).unwrap();
+    assert!(heap.contains(a) && heap.contains(b));
+    assert_eq!(b as usize % 64, 0);
+    assert!(b as usize >= a as usize + 3);
+    unsafe {
+        b.cast::<u64>().write(0x1234_5678);
+        assert_eq!(b.cast::<u64>().read(), 0x1234_5678);
+    }
+    assert_eq!(heap.allocations(), 2);
+    // 空间不足
+    assert!(heap
+        .alloc(Layout::from_size_align(256, 1).unwrap())
+        .is_none());
+
+    // 释放不会回收内存，不属于早期堆的指针交给真正的堆
+    let used = heap.used();
+    assert!(heap.dealloc(a));
+    assert_eq!(heap.leaked(), 1);
+    assert_eq!(heap.used(), used);
+    let outside = Box::new(0u64);
+    assert!(!heap.dealloc(&*outside as *const u64 as *mut u8));
+
+    // 切换之后，分配失败（由全局分配器转交给真正的堆），之前分配的内存仍然可用
+    assert!(heap.handoff());
+    assert!(!heap.handoff());
+    assert!(heap.alloc(Layout::from_size_align(1, 1).unwrap()).is_none());
+    assert_eq!(unsafe { b.cast::<u64>().read() }, 0x1234_5678);
+
+    // 全局分配器已经在内存管理初始化时切换到了真正的堆
+    assert!(EARLY_HEAP.handed_off());
+    let v: Vec<u8> = Vec::with_capacity(16);
+    assert!(!EARLY_HEAP.contains(v.as_ptr()));
+    kdebug!(
+        "test_early_heap passed, early heap: {} bytes in {} allocations, {} leaked",
+        EARLY_HEAP.used(),
+        EARLY_HEAP.allocations(),
+        EARLY_HEAP.leaked()
+    );
+}
+
+/// 检查保留区域的汇总：内核镜像总是存在，initrd在保留期间以正确的类型出现
+pub fn test_reserved_regions() {
+    let find = |kind: ReservedKind| reserved_regions().filter(move |(_, k)| *k == kind);
+
+    let info = BOOTSTRAP_MM_INFO.unwrap();
+    let (kernel, _) = find(ReservedKind::KernelImage)
+        .next()
+        .expect("kernel image is not reserved");
+    assert_eq!(Some(kernel.base), info.kernel_phys_start());
+    assert!(kernel.base.data() + kernel.size >= info.kernel_phys_end().unwrap().data());
+    if cfg!(feature = "smp_ap_bringup") {
+        assert_eq!(find(ReservedKind::ApTrampoline).count(), 1);
+    }
+    // 按照基地址从小到大排列
+    let bases: Vec<PhysAddr> = reserved_regions().map(|(area, _)| area.base).collect();
+    assert!(bases.windows(2).all(|w| w[0] <= w[1]));
+
+    if initrd_frames().is_some() {
+        assert_eq!(find(ReservedKind::Initrd).count(), 1);
+        kdebug!("test_reserved_regions: a real initrd is present, skipped the simulated initrd");
+        return;
+    }
+    assert_eq!(find(ReservedKind::Initrd).count(), 0);
+    let (paddr, count) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(2)) }.unwrap();
+    initrd_reserve(paddr + 0x10, count.bytes() - 0x10).unwrap();
+    let initrd: Vec<PhysMemoryArea> = find(ReservedKind::Initrd).map(|(area, _)| area).collect();
+    assert_eq!(initrd.len(), 1);
+    assert_eq!(initrd[0].base, paddr);
+    assert_eq!(initrd[0].size, count.bytes());
+
+    // 释放之后，initrd不再出现
+    assert_eq!(release_initrd(), Ok(count));
+    assert_eq!(find(ReservedKind::Initrd).count(), 0);
+}
+
+/// 检查mmio_init的初始化顺序：页帧分配器未初始化时、重复初始化时都会被拒绝，且不会替换已有的内存池
+pub fn test_mmio_init_order() {
+    use crate::mm::mmio_buddy::{mmio_pool, mmio_try_init};
+    assert!(LockedFrameAllocator::is_initialized());
+
+    let pool = mmio_pool() as *mut _;
+    assert_eq!(
+        mmio_try_init(false),
+        Err(SystemError::EAGAIN_OR_EWOULDBLOCK)
+    );
+    // mm_init已经初始化过mmio内存池
+    assert_eq!(mmio_try_init(true), Err(SystemError::EEXIST));
+    assert_eq!(mmio_pool() as *mut _, pool);
+}
+
+/// 在一段（不存在的）物理内存上创建CMA区域，分配超过伙伴分配器最大阶的连续内存。
+/// 位图只记录页帧的分配状态，不会访问这段内存
+pub fn test_cma() {
+    use crate::mm::allocator::{
+        buddy::BUDDY_MAX_BLOCK_SIZE,
+        cma::{cma_alloc, cma_area, cma_free, CmaArea},
+    };
+    let base = PhysAddr::new(0x1_0000_0000);
+    let count = PageFrameCount::new(2 * BUDDY_MAX_BLOCK_SIZE / MMArch::PAGE_SIZE);
+    let mut area = CmaArea::new(base + MMArch::PAGE_SIZE, count);
+
+    let big = BUDDY_MAX_BLOCK_SIZE + MMArch::PAGE_SIZE;
+    let align = 1 << 21;
+    let paddr = area.alloc(big, align).expect("cma alloc failed");
+    assert!(paddr.check_aligned(align));
+    assert!(paddr >= area.base() && paddr + big <= area.base() + count.bytes());
+    assert_eq!(area.used().data(), big / MMArch::PAGE_SIZE);
+    // 剩余的连续空闲内存不足以再分配一块
+    assert_eq!(area.alloc(big, align), Err(SystemError::ENOMEM));
+    let small = area.alloc(MMArch::PAGE_SIZE, 1).unwrap();
+    assert!(small < paddr || small >= paddr + big);
+
+    assert_eq!(area.alloc(0, 1), Err(SystemError::EINVAL));
+    assert_eq!(area.alloc(MMArch::PAGE_SIZE, 3), Err(SystemError::EINVAL));
+    assert_eq!(
+        area.free(paddr + big, MMArch::PAGE_SIZE),
+        Err(SystemError::EINVAL)
+    );
+    assert_eq!(area.free(base, MMArch::PAGE_SIZE), Err(SystemError::EINVAL));
+
+    area.free(paddr, big).unwrap();
+    area.free(small, MMArch::PAGE_SIZE).unwrap();
+    assert_eq!(area.used().data(), 0);
+    assert_eq!(area.alloc(big, align), Ok(paddr));
+
+    // 全局的CMA区域只有在通过cma=预留时才存在
+    match cma_area() {
+        Some((cma_base, cma_count)) => {
+            let p = cma_alloc(cma_count.bytes(), 1).expect("global cma alloc failed");
+            assert_eq!(p, cma_base);
+            cma_free(p, cma_count.bytes()).unwrap();
+        }
+        None => assert_eq!(cma_alloc(MMArch::PAGE_SIZE, 1), Err(SystemError::ENOMEM)),
+    }
+}
+
+/// 在一个已知的内存布局上，统计按2M、1G对齐的块
+pub fn test_direct_map_huge_opportunities() {
+    const M: usize = 1 << 20;
+    const G: usize = 1 << 30;
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let areas = [
+        // [1M, 639M)：只有[2M, 638M)中的2M块
+        area(M, 638 * M),
+        // [1G, 3G)被拆分成两个首尾相接的区域：合并之后包含两个1G块
+        area(G, G + 512 * M),
+        area(2 * G + 512 * M, 512 * M),
+        // 大小为0的区域被忽略
+        area(3 * G, 0),
+        // [4G+1M, 4G+5M)：只有[4G+2M, 4G+4M)
+        area(4 * G + M, 4 * M),
+    ];
+    let op = direct_map_huge_opportunities(&areas);
+    assert_eq!(
+        op,
+        HugeMapOpportunities {
+            runs_2m: 318 + 1024 + 1,
+            runs_1g: 2,
+            ram_bytes: 638 * M + 2 * G + 4 * M,
+        }
+    );
+    assert_eq!(op.coverage_1g_permille(), 2 * G * 1000 / op.ram_bytes);
+    assert_eq!(
+        op.coverage_2m_permille(),
+        1343 * 2 * M * 1000 / op.ram_bytes
+    );
+
+    // 没有按照1G对齐的区域不包含1G块
+    let op = direct_map_huge_opportunities(&[area(G + 2 * M, G)]);
+    assert_eq!((op.runs_2m, op.runs_1g), (512, 0));
+    assert_eq!(op.coverage_2m_permille(), 1000);
+    assert_eq!(
+        direct_map_huge_opportunities(&[]),
+        HugeMapOpportunities::default()
+    );
+    assert_eq!(HugeMapOpportunities::default().coverage_2m_permille(), 0);
+}
+
+/// 建立已知的映射（被固定的私有页面、两个页面共享的页帧、共享零页），检查内存使用情况的每一项
+pub fn test_memory_summary() {
+    use crate::mm::{
+        ksm::inc_ref,
+        ucontext::{zero_frame, MemSummary},
+    };
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    with_user_mapper(|umapper| {
+        assert_eq!(umapper.memory_summary(), MemSummary::default());
+
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        let base = 0x50_0000;
+        let private = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        let shared = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        let zero = zero_frame().expect("zero frame is not initialized");
+        for (i, paddr) in [private, shared, shared, zero].iter().enumerate() {
+            let flusher = unsafe {
+                umapper
+                    .utable
+                    .map_phys(VirtAddr::new(base + i * PAGE), *paddr, flags)
+            }
+            .expect("Failed to map user page");
+            unsafe { flusher.ignore_safe() };
+        }
+        // 第二个映射到shared的页面
+        inc_ref(shared);
+        pin_frame(private);
+
+        assert_eq!(
+            umapper.memory_summary(),
+            MemSummary {
+                rss_bytes: 4 * PAGE,
+                virtual_bytes: 4 * PAGE,
+                shared_bytes: 3 * PAGE,
+                locked_bytes: 0,
+                pinned_bytes: PAGE,
+            }
+        );
+        unpin_frame(private);
+        assert_eq!(umapper.memory_summary().pinned_bytes, 0);
+
+        // 零页帧不能在清空用户空间时被释放
+        let (_, _, flusher) = unsafe {
+            umapper
+                .utable
+                .unmap_phys(VirtAddr::new(base + 3 * PAGE), false)
+        }
+        .unwrap();
+        unsafe { flusher.ignore_safe() };
+        assert_eq!(umapper.memory_summary().shared_bytes, 2 * PAGE);
+
+        umapper.clear_user_space();
+        assert_eq!(umapper.memory_summary(), MemSummary::default());
+    });
+}
+
+/// 在内核页表中建立、取消一段恒等映射（只检查页表项，不会访问这段物理地址）
+pub fn test_identity_map() {
+    use crate::mm::kernel_mapper::IDENTITY_MAP_WINDOW;
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    let paddr = PhysAddr::new(0x4000_0000);
+    let count = PageFrameCount::new(3);
+    let flags = PageFlags::<MMArch>::new().set_write(true);
+
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper.as_mut().expect("kernel mapper is busy");
+    unsafe {
+        mapper.identity_map(paddr, count, flags).unwrap().flush();
+        for i in 0..count.data() {
+            let vaddr = VirtAddr::new(paddr.data() + i * PAGE);
+            assert_eq!(mapper.translate(vaddr).unwrap().0, paddr + i * PAGE);
+        }
+        // 与已有的恒等映射重叠
+        assert_eq!(
+            mapper.identity_map(paddr + 2 * PAGE, count, flags).err(),
+            Some(SystemError::EEXIST)
+        );
+        // 不在恒等映射的窗口内：低地址重映射、4G以上、直接映射区域
+        for bad in [
+            PhysAddr::new(0x1000),
+            PhysAddr::new(IDENTITY_MAP_WINDOW.end - PAGE),
+            PhysAddr::new(MMArch::PHYS_OFFSET),
+        ] {
+            assert_eq!(
+                mapper.identity_map(bad, count, flags).err(),
+                Some(SystemError::EINVAL)
+            );
+        }
+        assert_eq!(
+            mapper
+                .identity_map(paddr + 0x10, PageFrameCount::new(1), flags)
+                .err(),
+            Some(SystemError::EINVAL)
+        );
+        // 范围内有页面没有被映射时，不会取消任何映射
+        assert_eq!(
+            mapper.identity_unmap(paddr, PageFrameCount::new(4)).err(),
+            Some(SystemError::EINVAL)
+        );
+        assert!(mapper.translate(VirtAddr::new(paddr.data())).is_some());
+
+        mapper.identity_unmap(paddr, count).unwrap().flush();
+        for i in 0..count.data() {
+            assert!(mapper
+                .translate(VirtAddr::new(paddr.data() + i * PAGE))
+                .is_none());
+        }
+    }
+}
+
+/// 检查乱序、相邻、重叠的内存区域被排序并合并为最少的、互不重叠的区域，以及当前的内存布局已经是合并之后的
+pub fn test_merge_memory_areas() {
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let merged = |areas: &mut [PhysMemoryArea]| {
+        let count = merge_memory_areas(areas);
+        areas[..count]
+            .iter()
+            .map(|a| (a.base.data(), a.size))
+            .collect::<Vec<_>>()
+    };
+
+    let mut areas = [
+        // 与0x10_0000开始的区域重叠
+        area(0x20_0000, 0x30_0000),
+        area(0x1_0000_0000, 0x1000_0000),
+        area(0x1000, 0x9e000),
+        // 被上一个区域完全包含
+        area(0x1_0800_0000, 0x1000),
+        area(0x10_0000, 0x20_0000),
+        // 与0x1000开始的区域首尾相接
+        area(0x9f000, 0x1000),
+        area(0x5000_0000, 0),
+        // 首尾相接的三段
+        area(0x4000_0000, 0x100_0000),
+        area(0x4200_0000, 0x100_0000),
+        area(0x4100_0000, 0x100_0000),
+    ];
+    assert_eq!(
+        merged(&mut areas),
+        [
+            (0x1000, 0x9f000),
+            (0x10_0000, 0x40_0000),
+            (0x4000_0000, 0x300_0000),
+            (0x1_0000_0000, 0x1000_0000),
+        ]
+    );
+
+    // 结束地址超出usize的范围：截断到地址空间的末尾，并且不会重复计算重叠的部分
+    let top = usize::MAX - 0xfff;
+    let mut areas = [area(top, 0x10_0000), area(top - 0x1000, 0x2000)];
+    assert_eq!(
+        merged(&mut areas),
+        [(top - 0x1000, usize::MAX - (top - 0x1000))]
+    );
+    let trimmed = area(top - 0x1000, usize::MAX).page_trimmed().unwrap();
+    assert_eq!((trimmed.base.data(), trimmed.size), (top - 0x1000, 0x1000));
+    assert!(area(usize::MAX - 0x10, 0x100).page_trimmed().is_none());
+
+    // 空的输入
+    assert_eq!(merge_memory_areas(&mut []), 0);
+
+    // 当前的内存布局已经按基地址排序、互不重叠
+    let areas = X86_64MMArch::phys_memory_areas();
+    for pair in areas.windows(2) {
+        assert!(pair[0].end() < pair[1].base.data());
+    }
+}
+
+/// 检查`subtract_memory_areas`挖去重叠的部分，以及可用的内存不与保留的区域重叠
+pub fn test_reserved_areas() {
+    let area = |base: usize, size: usize| PhysMemoryArea {
+        base: PhysAddr::new(base),
+        size,
+    };
+    let subtracted = |areas: &mut [PhysMemoryArea], count: usize, holes: &[PhysMemoryArea]| {
+        let count = subtract_memory_areas(areas, count, holes);
+        areas[..count]
+            .iter()
+            .map(|a| (a.base.data(), a.size))
+            .collect::<Vec<_>>()
+    };
+
+    let holes = [
+        // 与第一个区域的开头重叠
+        area(0x0, 0x2000),
+        // 位于第二个区域的中间，把它拆分为两个
+        area(0x10_8000, 0x1000),
+        area(0x10_c000, 0x1000),
+        // 跨越第二、三个区域之间的空隙
+        area(0x1f_f000, 0x10_2000),
+        // 完全覆盖第四个区域
+        area(0x4000_0000, 0x100_0000),
+    ];
+    let mut areas = [area(0, 0); 8];
+    areas[..5].copy_from_slice(&[
+        area(0x1000, 0x9f000),
+        area(0x10_0000, 0x10_0000),
+        area(0x30_0000, 0x10_0000),
+        area(0x4000_1000, 0x1000),
+        area(0x1_0000_0000, 0x1000_0000),
+    ]);
+    assert_eq!(
+        subtracted(&mut areas, 5, &holes),
+        [
+            (0x2000, 0x9e000),
+            (0x10_0000, 0x8000),
+            (0x10_9000, 0x3000),
+            (0x10_d000, 0xf_2000),
+            (0x30_1000, 0xf_f000),
+            (0x1_0000_0000, 0x1000_0000),
+        ]
+    );
+
+    // 空间不足时，多出来的部分被丢弃
+    let mut areas = [area(0x10_0000, 0x10_0000)];
+    assert_eq!(
+        subtracted(&mut areas, 1, &holes[1..3]),
+        [(0x10_0000, 0x8000)]
+    );
+    // 没有空洞、没有区域
+    let mut areas = [area(0x1000, 0x1000), area(0, 0)];
+    assert_eq!(subtracted(&mut areas, 1, &[]), [(0x1000, 0x1000)]);
+    assert!(subtracted(&mut areas, 0, &holes).is_empty());
+
+    // 当前的可用内存与保留的区域互不重叠
+    let reserved = X86_64MMArch::reserved_areas();
+    for pair in reserved.windows(2) {
+        assert!(pair[0].end() < pair[1].base.data());
+    }
+    for ram in X86_64MMArch::phys_memory_areas() {
+        for r in reserved {
+            assert!(
+                ram.end() <= r.base.data() || r.end() <= ram.base.data(),
+                "RAM area {:?} overlaps reserved area {:?}",
+                ram,
+                r
+            );
+        }
+    }
+}
+
+/// 检查bump分配器回退到记录的位置之后，之后分配的页帧可以被再次分配（包括跨越内存区域的情况）
+pub fn test_bump_watermark() {
+    // bump分配器只计算地址，不会访问这些页帧
+    static AREAS: [PhysMemoryArea; 2] = [
+        PhysMemoryArea {
+            base: PhysAddr::new(0x10_0000),
+            size: 0x4000,
+        },
+        PhysMemoryArea {
+            base: PhysAddr::new(0x20_0000),
+            size: 0x4000,
+        },
+    ];
+    let mut bump = BumpAllocator::<MMArch>::new(&AREAS, 0x10_0000);
+    let alloc = |bump: &mut BumpAllocator<MMArch>, n: usize| {
+        unsafe { bump.allocate(PageFrameCount::new(n)) }.map(|(paddr, _)| paddr)
+    };
+
+    assert_eq!(alloc(&mut bump, 1), Some(PhysAddr::new(0x10_0000)));
+    let mark = bump.checkpoint();
+    assert_eq!(mark.offset(), 0x10_1000);
+    // 第一个区域剩下的3页放不下4页，分配到第二个区域
+    assert_eq!(alloc(&mut bump, 4), Some(PhysAddr::new(0x20_0000)));
+    assert_eq!(unsafe { bump.usage() }.used().data(), 8);
+    bump.rewind(mark);
+    assert_eq!(bump.offset(), 0x10_1000);
+    assert_eq!(alloc(&mut bump, 3), Some(PhysAddr::new(0x10_1000)));
+
+    // 交出之后，新的记录仍然可以回退
+    bump.hand_off();
+    let mark = bump.checkpoint();
+    assert_eq!(alloc(&mut bump, 2), Some(PhysAddr::new(0x20_0000)));
+    bump.rewind(mark);
+    assert_eq!(alloc(&mut bump, 4), Some(PhysAddr::new(0x20_0000)));
+    assert_eq!(alloc(&mut bump, 1), None);
+}
@@ -0,0 +1,1526 @@
+//! `PageMapper`的映射、取消映射与权限修改的测试
+
+use super::*;
+
+/// 检查批量刷新器记录的范围恰好是所有加入的范围的并集
+pub fn test_flush_batch() {
+    let page = |n: usize| VirtAddr::new(X86_64MMArch::PHYS_OFFSET + n * MMArch::PAGE_SIZE);
+    let mut batch = FlushBatch::<MMArch>::new();
+    batch.push_range(page(10), PageFrameCount::new(2)); // [10, 12)
+    batch.push_range(page(20), PageFrameCount::new(1)); // [20, 21)
+    batch.push_range(page(12), PageFrameCount::new(3)); // [12, 15)，与[10, 12)相邻
+    batch.push_range(page(4), PageFrameCount::new(1)); // [4, 5)
+    batch.push_range(page(14), PageFrameCount::new(7)); // [14, 21)，与[10, 15)和[20, 21)重叠
+    batch.consume(PageFlush::new(page(5))); // [5, 6)，与[4, 5)相邻
+
+    let expected = [(page(4), page(6)), (page(10), page(21))];
+    assert!(batch.ranges().eq(expected.iter().copied()));
+    assert_eq!(batch.pages(), 13);
+    assert!(!batch.needs_full_flush());
+    batch.commit();
+
+    // 页面过多时，退化为刷新整个TLB
+    let mut batch = FlushBatch::<MMArch>::new();
+    batch.push_range(page(0), PageFrameCount::new(1024));
+    assert!(batch.needs_full_flush());
+    batch.commit();
+}
+
+/// 检查影子栈页表项的编码为W=0, D=1
+pub fn test_shadow_stack_flags() {
+    let paddr = PhysAddr::new(0x1000);
+    let base = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+
+    let flags = unsafe { base.set_shadow_stack_unchecked(true) };
+    let entry = PageEntry::<MMArch>::new(paddr.data() | flags.data());
+    assert_eq!(entry.data() & X86_64MMArch::ENTRY_FLAG_READWRITE, 0);
+    assert_ne!(entry.data() & X86_64MMArch::ENTRY_FLAG_DIRTY, 0);
+    assert!(entry.is_shadow_stack());
+    assert!(!PageEntry::<MMArch>::new(paddr.data() | base.data()).is_shadow_stack());
+
+    // 不支持CET影子栈时，set_shadow_stack不做任何修改
+    let flags = base.set_shadow_stack(true);
+    if X86_64MMArch::shadow_stack_enabled() {
+        assert!(flags.has_shadow_stack());
+    } else {
+        assert_eq!(flags.data(), base.data());
+    }
+}
+
+/// 检查`PageMapper::protect_and_flush`能够收紧权限，并返回页面之前的脏位
+pub fn test_protect_and_flush() {
+    with_user_mapper(|umapper| {
+        let vaddr = VirtAddr::new(0x20_0000);
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        let rw = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        let ro = PageFlags::<MMArch>::new().set_user(true);
+        // 模拟页面已经被写入过（脏位为1）
+        let rw_dirty = rw.update_flags(X86_64MMArch::ENTRY_FLAG_DIRTY, true);
+        unsafe { umapper.utable.map_phys(vaddr, paddr, rw_dirty) }
+            .expect("Failed to map user page")
+            .ignore_safe();
+
+        let dirty = unsafe { umapper.utable.protect_and_flush(vaddr, ro) }.unwrap();
+        assert!(dirty);
+        let (mapped, flags) = umapper.utable.translate(vaddr).unwrap();
+        assert_eq!(mapped, paddr);
+        assert!(!flags.has_write());
+
+        // 权限收紧之后页面没有被写入，因此脏位为0
+        let dirty = unsafe { umapper.utable.protect_and_flush(vaddr, ro) }.unwrap();
+        assert!(!dirty);
+
+        assert_eq!(
+            unsafe {
+                umapper
+                    .utable
+                    .protect_and_flush(VirtAddr::new(0x40_0000), ro)
+            },
+            Err(MmError::NotMapped(VirtAddr::new(0x40_0000)))
+        );
+    });
+}
+
+/// 检查位于两个已映射区域之间的空洞中的地址，能够找到两侧最近的已映射区域
+pub fn test_nearest_mappings() {
+    with_user_mapper(|umapper| {
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        // 低处的区域：[0x40_0000, 0x40_2000)；高处的区域位于另一个顶层页表项中：[0x80_0000_0000, 0x80_0000_1000)
+        for vaddr in [0x40_0000, 0x40_1000, 0x80_0000_0000] {
+            let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+            let flusher = unsafe { umapper.utable.map_phys(VirtAddr::new(vaddr), paddr, flags) }
+                .expect("Failed to map user page");
+            unsafe { flusher.ignore_safe() };
+        }
+
+        assert_eq!(
+            umapper.nearest_mappings(VirtAddr::new(0x40_5123)),
+            (
+                Some(VirtAddr::new(0x40_2000)),
+                Some(VirtAddr::new(0x80_0000_0000))
+            )
+        );
+        // 紧挨着区域的末尾（典型的差一错误）
+        assert_eq!(
+            umapper.nearest_mappings(VirtAddr::new(0x40_2000)).0,
+            Some(VirtAddr::new(0x40_2000))
+        );
+        assert_eq!(
+            umapper.nearest_mappings(VirtAddr::new(0x1000)),
+            (None, Some(VirtAddr::new(0x40_0000)))
+        );
+        assert_eq!(
+            umapper.nearest_mappings(VirtAddr::new(0x100_0000_0000)),
+            (Some(VirtAddr::new(0x80_0000_1000)), None)
+        );
+    });
+
+    // 内核：直接映射区域上方的保护页位于直接映射区域与其他映射之间的空洞中
+    let (high, _) = X86_64MMArch::direct_map_guard_ranges();
+    let high = high.expect("direct map guard is not set up");
+    let (below, above) = KernelMapper::lock().nearest_mappings(high.start + MMArch::PAGE_SIZE);
+    assert_eq!(below, Some(high.start));
+    assert!(above.map_or(true, |above| above >= high.end));
+}
+
+/// 检查对齐与不对齐的范围、不同的页大小下，估算的页表数量，以及reserve_tables之后已经存在的页表不再被计入
+pub fn test_estimate_table_cost() {
+    with_user_mapper(|umapper| {
+        const SIZE_4K: usize = MMArch::PAGE_SIZE;
+        const SIZE_2M: usize = SIZE_4K << MMArch::PAGE_ENTRY_SHIFT;
+        const SIZE_1G: usize = SIZE_2M << MMArch::PAGE_ENTRY_SHIFT;
+        let base = VirtAddr::new(SIZE_1G);
+        let cost = |mapper: &UserMapper, vaddr: VirtAddr, count: usize, size: usize| {
+            mapper
+                .utable
+                .estimate_table_cost(vaddr, PageFrameCount::new(count), size)
+                .data()
+        };
+
+        // 新的用户地址空间中只有顶级页表：需要PDPT、PD、PT各一个
+        assert_eq!(cost(&umapper, base, 1, SIZE_4K), 3);
+        assert_eq!(cost(&umapper, base, 512, SIZE_4K), 3);
+        // 跨越2M边界：需要两个PT
+        assert_eq!(cost(&umapper, base + SIZE_2M - SIZE_4K, 2, SIZE_4K), 4);
+        // 跨越1G边界：需要两个PD以及两个PT
+        assert_eq!(cost(&umapper, base + SIZE_1G - SIZE_4K, 2, SIZE_4K), 5);
+
+        // 2M的大页不需要PT
+        assert_eq!(cost(&umapper, base, 512, SIZE_2M), 2);
+        assert_eq!(cost(&umapper, base + SIZE_1G - SIZE_2M, 2, SIZE_2M), 3);
+        // 不对齐的起始地址会被向下对齐到页大小
+        assert_eq!(cost(&umapper, base + SIZE_4K, 1, SIZE_2M), 2);
+        // 1G的大页只需要PDPT
+        assert_eq!(cost(&umapper, base, 2, SIZE_1G), 1);
+
+        // 预先分配页表之后，已经存在的页表不再被计入
+        let reserved =
+            unsafe { umapper.utable.reserve_tables(base, PageFrameCount::new(1)) }.unwrap();
+        assert_eq!(reserved.data(), 3);
+        assert_eq!(cost(&umapper, base, 512, SIZE_4K), 0);
+        assert_eq!(cost(&umapper, base + SIZE_2M, 1, SIZE_4K), 1);
+        assert_eq!(cost(&umapper, base + SIZE_1G, 1, SIZE_4K), 2);
+        assert_eq!(cost(&umapper, base, 512, SIZE_2M), 0);
+    });
+}
+
+/// 检查替换页面的物理页帧之后，通过同一个虚拟地址读到的是新页帧的内容
+pub fn test_replace_frame() {
+    let mut vaddr: u64 = 0;
+    let mut vaddr_len: u64 = 0;
+    mmio_pool()
+        .create_mmio(MMArch::PAGE_SIZE, 0, &mut vaddr, &mut vaddr_len)
+        .expect("Failed to allocate virtual address");
+    let vaddr = VirtAddr::new(vaddr as usize);
+
+    let old_paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    let new_paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    unsafe {
+        MMArch::write_bytes(
+            MMArch::phys_2_virt(old_paddr).unwrap(),
+            0x11,
+            MMArch::PAGE_SIZE,
+        );
+        MMArch::write_bytes(
+            MMArch::phys_2_virt(new_paddr).unwrap(),
+            0x22,
+            MMArch::PAGE_SIZE,
+        );
+    }
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_mut().unwrap();
+    let flags = PageFlags::<MMArch>::new().set_write(true);
+    unsafe { mapper.map_phys(vaddr, old_paddr, flags) }
+        .expect("Failed to map page")
+        .flush();
+    assert_eq!(unsafe { MMArch::read::<u8>(vaddr + 0x10) }, 0x11);
+
+    let replaced = unsafe { mapper.replace_frame(vaddr, new_paddr) };
+    assert_eq!(replaced, Ok(old_paddr));
+    assert_eq!(unsafe { MMArch::read::<u8>(vaddr + 0x10) }, 0x22);
+    let (mapped, new_flags) = mapper.translate(vaddr).unwrap();
+    assert_eq!(mapped, new_paddr);
+    assert!(new_flags.has_write());
+
+    // 未映射的页面、未对齐的地址
+    assert_eq!(
+        unsafe { mapper.replace_frame(vaddr + MMArch::PAGE_SIZE, new_paddr) },
+        Err(SystemError::EFAULT)
+    );
+    assert_eq!(
+        unsafe { mapper.replace_frame(vaddr + 0x10, new_paddr) },
+        Err(SystemError::EINVAL)
+    );
+
+    // 映射vaddr的最后一级页表，以及它在上一级页表中的下标
+    let pt_of = |mapper: &PageMapper| {
+        let mut table = mapper.table();
+        while table.level() > 1 {
+            let i = unsafe { table.index_of(vaddr) }.unwrap();
+            table = unsafe { table.next_level_table(i) }?;
+        }
+        let i = unsafe { table.index_of(vaddr) }.unwrap();
+        return unsafe { table.next_level_table(i) };
+    };
+    let pt = pt_of(mapper).unwrap();
+    // 同一张页表中没有其他的映射时，release_mmio会释放这张页表
+    let only_mapping = (0..MMArch::PAGE_ENTRY_NUM)
+        .filter(|i| unsafe { pt.entry(*i) }.map_or(false, |e| e.present()))
+        .count()
+        == 1;
+
+    // release_mmio会取消映射
+    drop(kernel_mapper);
+    mmio_pool()
+        .release_mmio(vaddr, vaddr_len as usize)
+        .expect("Failed to release virtual address");
+    let kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_ref();
+    assert!(mapper.translate(vaddr).is_none());
+    if only_mapping {
+        assert!(pt_of(mapper).is_none());
+    }
+    drop(kernel_mapper);
+    unsafe {
+        LockedFrameAllocator.free_one(old_paddr);
+        LockedFrameAllocator.free_one(new_paddr);
+    }
+}
+
+/// 检查修改页表项之后，`translate`不会返回缓存中过期的翻译，
+/// 以及只有启用`translate_cache`特性时，修改页表项才会增加翻译缓存的全局版本号
+pub fn test_translate_cache() {
+    with_user_mapper(|umapper| {
+        let vaddr = VirtAddr::new(0x4000_0000);
+        let flags = PageFlags::<MMArch>::new().set_user(true);
+        let (a, b) = (PhysAddr::new(0x10_0000), PhysAddr::new(0x20_0000));
+        // 只检查页表项，不会访问页面，因此可以映射任意的物理地址
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, a, flags)
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(umapper.utable.translate(vaddr).unwrap().0, a);
+        // 再次翻译时可能命中缓存
+        assert_eq!(umapper.utable.translate(vaddr).unwrap().0, a);
+
+        let gen = translate_cache_generation();
+        let (_, _, flush) = unsafe { umapper.utable.unmap_phys(vaddr, false) }.unwrap();
+        unsafe { flush.ignore_safe() };
+        if cfg!(feature = "translate_cache") {
+            assert!(translate_cache_generation() > gen);
+        } else {
+            assert_eq!(translate_cache_generation(), gen);
+        }
+        assert!(umapper.utable.translate(vaddr).is_none());
+
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, b, flags)
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(umapper.utable.translate(vaddr).unwrap().0, b);
+        let (_, _, flush) = unsafe { umapper.utable.unmap_phys(vaddr, true) }.unwrap();
+        unsafe { flush.ignore_safe() };
+    });
+}
+
+/// 检查PageFlags与页表项中的标志位之间的转换：对所有可以表示的标志位组合，在每一级页表中都能往返
+pub fn test_entry_bits() {
+    type Setter = fn(PageFlags<MMArch>, bool) -> PageFlags<MMArch>;
+    let setters: [Setter; 9] = [
+        |f, v| f.set_write(v),
+        |f, v| f.set_user(v),
+        |f, v| f.set_execute(v),
+        |f, v| f.set_page_write_through(v),
+        |f, v| f.set_page_cache_disable(v),
+        |f, v| f.set_page_attribute_table(v),
+        |f, v| f.set_global(v),
+        |f, v| f.update_flags(MMArch::ENTRY_FLAG_COW, v),
+        |f, v| f.update_flags(MMArch::ENTRY_FLAG_DIRTY, v),
+    ];
+    let xd_reserved = X86_64MMArch::is_xd_reserved();
+
+    for combination in 0..(1usize << setters.len()) {
+        let mut flags = PageFlags::<MMArch>::new();
+        for (i, set) in setters.iter().enumerate() {
+            flags = set(flags, combination & (1 << i) != 0);
+        }
+
+        for level in 0..MMArch::PAGE_LEVELS - 1 {
+            let bits = flags.to_entry_bits_at(level);
+            // 不可执行位位于第63位，XD被保留时总是被清除
+            assert_eq!(
+                bits & (1 << 63) != 0,
+                !flags.has_execute() && !xd_reserved,
+                "flags: {:?}, level: {}",
+                flags,
+                level
+            );
+            assert_eq!(bits & (1 << 8) != 0, flags.has_global());
+            if level == 0 {
+                assert_eq!(bits & (1 << 7) != 0, flags.has_page_attribute_table());
+                assert_eq!(bits & (1 << 12), 0);
+            } else {
+                assert_ne!(bits & MMArch::ENTRY_FLAG_HUGE_PAGE, 0);
+                assert_eq!(bits & (1 << 12) != 0, flags.has_page_attribute_table());
+            }
+
+            // 带上一个按照该级页面大小对齐的物理地址，解析出的标志位不变
+            let paddr = MMArch::PAGE_SIZE << (level * MMArch::PAGE_ENTRY_SHIFT);
+            let parsed = PageFlags::<MMArch>::from_entry_bits_at(level, paddr | bits);
+            assert_eq!(parsed.data(), flags.data(), "level: {}", level);
+            assert_eq!(parsed.to_entry_bits_at(level), bits);
+        }
+        assert_eq!(flags.to_entry_bits(), flags.to_entry_bits_at(0));
+    }
+
+    // XD被保留时，即使直接构造出带有不可执行位的flags，也不会写入页表项
+    let nx = unsafe { PageFlags::<MMArch>::from_data(MMArch::ENTRY_FLAG_NO_EXEC) };
+    assert_eq!(nx.to_entry_bits() & (1 << 63) != 0, !xd_reserved);
+}
+
+/// 检查按范围操作的映射函数在页数为0时不分配页表、不修改映射，并且返回空的刷新器
+pub fn test_zero_length_ranges() {
+    fn assert_empty(flusher: FlushBatch<MMArch>) {
+        assert_eq!(flusher.ranges().count(), 0);
+        assert!(!flusher.needs_full_flush());
+        flusher.commit();
+    }
+
+    with_user_mapper(|umapper| {
+        let zero = PageFrameCount::new(0);
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        // 未对齐的地址：页数为0时不检查参数
+        let unaligned = VirtAddr::new(0x4000_0123);
+        let vaddr = VirtAddr::new(0x4000_0000);
+        let paddr = PhysAddr::new(0x20_0000);
+
+        let free_before = unsafe { LockedFrameAllocator.usage() }.free();
+        let tables_before =
+            umapper
+                .utable
+                .estimate_table_cost(vaddr, PageFrameCount::new(1), MMArch::PAGE_SIZE);
+        for virt in [vaddr, unaligned] {
+            assert_empty(
+                unsafe { umapper.utable.map_phys_range(virt, paddr, zero, flags) }.unwrap(),
+            );
+            assert_empty(
+                unsafe { umapper.utable.map_phys_best(virt, paddr, zero, flags) }.unwrap(),
+            );
+            let (mapped, flusher) =
+                unsafe { umapper.utable.map_scatter(virt, &[(paddr, zero)], flags) }.unwrap();
+            assert_eq!(mapped, zero);
+            assert_empty(flusher);
+            let (unmapped, flusher) =
+                unsafe { umapper.utable.unmap_range(virt, zero, true) }.unwrap();
+            assert_eq!(unmapped, zero);
+            assert_empty(flusher);
+            let (changed, flusher) =
+                unsafe { umapper.utable.protect_range(virt, zero, flags) }.unwrap();
+            assert_eq!(changed, zero);
+            assert_empty(flusher);
+        }
+        // 没有分配任何页表
+        assert_eq!(unsafe { LockedFrameAllocator.usage() }.free(), free_before);
+        assert_eq!(
+            umapper
+                .utable
+                .estimate_table_cost(vaddr, PageFrameCount::new(1), MMArch::PAGE_SIZE),
+            tables_before
+        );
+        assert!(umapper.utable.translate(vaddr).is_none());
+
+        // 已经存在的映射不受页数为0的unmap_range、protect_range影响
+        unsafe {
+            umapper
+                .utable
+                .map_phys_range(vaddr, paddr, PageFrameCount::new(1), flags)
+        }
+        .unwrap()
+        .commit();
+        assert_empty(
+            unsafe { umapper.utable.unmap_range(vaddr, zero, true) }
+                .unwrap()
+                .1,
+        );
+        assert_empty(
+            unsafe { umapper.utable.protect_range(vaddr, zero, PageFlags::new()) }
+                .unwrap()
+                .1,
+        );
+        let (mapped, mapped_flags) = umapper.utable.translate(vaddr).unwrap();
+        assert_eq!(mapped, paddr);
+        assert!(mapped_flags.has_write());
+
+        let (unmapped, flusher) = unsafe {
+            umapper
+                .utable
+                .unmap_range(vaddr, PageFrameCount::new(1), true)
+        }
+        .unwrap();
+        assert_eq!(unmapped.data(), 1);
+        flusher.commit();
+    });
+}
+
+/// 检查`PageMapper::explain`对4K页面、2M大页，以及在中间某一级终止的遍历的描述
+pub fn test_explain() {
+    with_user_mapper(|umapper| {
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+
+        // 4K页面：0x4000_5000 = PML4[0], PDPT[1], PD[0], PT[5]
+        let vaddr = VirtAddr::new(0x4000_5000);
+        let paddr = PhysAddr::new(0x20_5000);
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, paddr, flags)
+                .unwrap()
+                .ignore_safe()
+        };
+        let (_, mapped_flags) = umapper.utable.translate(vaddr).unwrap();
+        assert!(mapped_flags.describe().starts_with("PRESENT|RW|USER"));
+        assert_eq!(
+            umapper.utable.explain(vaddr + 0x123),
+            format!(
+                "PML4[0]→PDPT[1]→PD[0]→PT[5]→phys {:#x}; flags {}",
+                paddr.data() + 0x123,
+                mapped_flags.describe()
+            )
+        );
+
+        // 2M大页：直接在PD[3]中填写一个带有PS位的页表项
+        let pd = unsafe {
+            umapper
+                .utable
+                .table()
+                .next_level_table(0)
+                .and_then(|pdpt| pdpt.next_level_table(1))
+                .unwrap()
+        };
+        let huge_paddr = 0x4000_0000;
+        unsafe {
+            pd.set_entry(
+                3,
+                PageEntry::new(
+                    huge_paddr | flags.set_page_attribute_table(true).to_entry_bits_at(1),
+                ),
+            )
+        };
+        assert_eq!(
+            umapper.utable.explain(VirtAddr::new(0x4060_1234)),
+            format!(
+                "PML4[0]→PDPT[1]→PD[3](2M huge)→phys {:#x}; flags {}",
+                huge_paddr + 0x1234,
+                flags.set_page_attribute_table(true).describe()
+            )
+        );
+        assert!(umapper
+            .utable
+            .explain(VirtAddr::new(0x4060_1234))
+            .contains("|PAT"));
+        unsafe { pd.set_entry(3, PageEntry::new(0)) };
+
+        // 在中间某一级终止的遍历
+        assert_eq!(
+            umapper.utable.explain(VirtAddr::new(0x4080_0000)),
+            "PML4[0]→PDPT[1]→PD[4] not present"
+        );
+        assert_eq!(
+            umapper.utable.explain(VirtAddr::new(0x8000_0000)),
+            "PML4[0]→PDPT[2] not present"
+        );
+        assert_eq!(
+            umapper.utable.explain(VirtAddr::new(0x4000_6000)),
+            "PML4[0]→PDPT[1]→PD[0]→PT[6] not present"
+        );
+
+        unsafe {
+            umapper
+                .utable
+                .unmap_phys(vaddr, true)
+                .unwrap()
+                .2
+                .ignore_safe()
+        };
+    });
+}
+
+/// 检查大页支持的检测结果：多次调用返回相同的值，并且与CPUID一致
+pub fn test_huge_page_support() {
+    let support = X86_64MMArch::huge_page_support();
+    for _ in 0..16 {
+        assert_eq!(X86_64MMArch::huge_page_support(), support);
+    }
+    assert!(support.supports_2m);
+
+    let pdpe1gb = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |info| info.has_1gib_pages());
+    assert_eq!(support.supports_1g, pdpe1gb);
+    kdebug!("test_huge_page_support passed: {:?}", support);
+}
+
+/// 检查启动阶段结束之后，通过`ignore()`忽略刷新器会被记录，而`ignore_safe()`以及空的批量刷新器不会
+pub fn test_ignored_flush() {
+    let vaddr = VirtAddr::new(0x40_0000);
+    let before = ignored_flushes_after_boot();
+
+    unsafe { PageFlush::<MMArch>::new(vaddr).ignore() };
+    assert_eq!(ignored_flushes_after_boot(), before + 1);
+    unsafe { PageFlushAll::<MMArch>::new().ignore() };
+    assert_eq!(ignored_flushes_after_boot(), before + 2);
+
+    let mut batch = FlushBatch::<MMArch>::new();
+    batch.consume(PageFlush::new(vaddr));
+    unsafe { batch.ignore() };
+    assert_eq!(ignored_flushes_after_boot(), before + 3);
+
+    // 被合并到其他刷新器中的刷新，以及显式地标记为安全的忽略，都不会被记录
+    let mut all = PageFlushAll::<MMArch>::new();
+    all.consume(PageFlush::new(vaddr));
+    unsafe { all.ignore_safe() };
+    unsafe { PageFlush::<MMArch>::new(vaddr).ignore_safe() };
+    unsafe { FlushBatch::<MMArch>::new().ignore() };
+    assert_eq!(ignored_flushes_after_boot(), before + 3);
+}
+
+/// 在没有开启内存加密的系统上，mark_shared/mark_private应当成功，并且不修改直接映射区域中的映射
+pub fn test_mem_encrypt_noop() {
+    if mem_encrypt_active() {
+        kinfo!("test_mem_encrypt_noop: memory encryption is active, skipped");
+        return;
+    }
+    const COUNT: usize = 4;
+    let paddr = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(COUNT)) }
+        .expect("test_mem_encrypt_noop: out of memory")
+        .0;
+    let entries = || -> Vec<Option<(PhysAddr, usize)>> {
+        let mapper = KernelMapper::lock();
+        (0..COUNT)
+            .map(|i| {
+                let virt = unsafe { MMArch::phys_2_virt(paddr + i * MMArch::PAGE_SIZE) }.unwrap();
+                mapper.translate(virt).map(|(p, f)| (p, f.data()))
+            })
+            .collect()
+    };
+    let before = entries();
+    assert!(before.iter().all(|e| e.is_some()));
+
+    mark_shared(paddr, PageFrameCount::new(COUNT)).expect("mark_shared failed");
+    assert_eq!(entries(), before);
+    mark_private(paddr, PageFrameCount::new(COUNT)).expect("mark_private failed");
+    assert_eq!(entries(), before);
+    // 即使在没有开启内存加密的系统上，也会检查参数
+    assert_eq!(
+        mark_shared(paddr + 1, PageFrameCount::new(1)),
+        Err(SystemError::EINVAL)
+    );
+
+    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(COUNT)) };
+    kinfo!("test_mem_encrypt_noop passed");
+}
+
+/// 检查页表项中的C位：映射RAM时被设置，映射设备内存时不被设置，并且不会出现在translate返回的物理地址中
+pub fn test_mem_encrypt_cbit() {
+    let mask = mem_encrypt_mask();
+    let bits = PageFlags::<MMArch>::new().set_write(true).to_entry_bits();
+    let ram = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    let mmio = PhysAddr::new(0xfee0_0000);
+
+    let entry = PageEntry::<MMArch>::new_mapping(ram, bits);
+    assert_eq!(entry.data() & mask, mask);
+    assert_eq!(entry.address(), Ok(ram));
+    let entry = PageEntry::<MMArch>::new_mapping(mmio, bits);
+    assert_eq!(entry.data() & mask, 0);
+    assert_eq!(entry.address(), Ok(mmio));
+
+    with_user_mapper(|umapper| {
+        let vaddr = VirtAddr::new(0x4000_0000);
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, ram, PageFlags::new().set_user(true))
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(umapper.utable.raw_entry(vaddr).unwrap().data() & mask, mask);
+        assert_eq!(umapper.utable.translate(vaddr).unwrap().0, ram);
+        unsafe {
+            umapper
+                .utable
+                .unmap_phys(vaddr, false)
+                .unwrap()
+                .2
+                .ignore_safe()
+        };
+    });
+    unsafe { LockedFrameAllocator.free_one(ram) };
+}
+
+/// 在碎片化的地址空间中查找空闲的虚拟地址范围
+pub fn test_find_free_range() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    with_user_mapper(|umapper| {
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        // 窗口内共10个页面，映射其中的第1、4、5、9个页面，剩下的空洞为：[0]、[2, 3]、[6, 8]
+        let base = 0x1000_0000;
+        for i in [1, 4, 5, 9] {
+            let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+            let flusher = unsafe {
+                umapper
+                    .utable
+                    .map_phys(VirtAddr::new(base + i * PAGE), paddr, flags)
+            }
+            .expect("Failed to map user page");
+            unsafe { flusher.ignore_safe() };
+        }
+        let start = VirtAddr::new(base);
+        let end = VirtAddr::new(base + 10 * PAGE);
+
+        // 只有一个空洞能放下3个页面
+        assert_eq!(
+            umapper.find_free_range(start, end, 3 * PAGE, 1),
+            Some(VirtAddr::new(base + 6 * PAGE))
+        );
+        // 从高地址向低地址查找：返回最高的位置
+        assert_eq!(
+            umapper.find_free_range(start, end, 2 * PAGE, 1),
+            Some(VirtAddr::new(base + 7 * PAGE))
+        );
+        // 长度不足一页的请求按一页处理
+        assert_eq!(
+            umapper.find_free_range(start, VirtAddr::new(base + 2 * PAGE), 1, 1),
+            Some(start)
+        );
+        // 没有空洞能放下4个页面
+        assert_eq!(umapper.find_free_range(start, end, 4 * PAGE, 1), None);
+        // 空洞足够大，但是所有按16K对齐的位置都不满足
+        assert_eq!(
+            umapper.find_free_range(start, end, 2 * PAGE, 4 * PAGE),
+            None
+        );
+        // 不合法的参数
+        assert_eq!(umapper.find_free_range(start, end, 0, 1), None);
+        assert_eq!(umapper.find_free_range(start, end, PAGE, 3), None);
+
+        // 窗口的上界被限制在USER_END_VADDR之下
+        let top = umapper
+            .find_free_range(VirtAddr::new(0), VirtAddr::new(usize::MAX), PAGE, 1)
+            .expect("user space should not be full");
+        assert_eq!(
+            top + PAGE,
+            VirtAddr::new(page_align_up(MMArch::USER_END_VADDR.data()))
+        );
+    });
+
+    // 内核：从低地址向高地址查找，第一个空洞是直接映射区域上方的保护页
+    let (high, _) = X86_64MMArch::direct_map_guard_ranges();
+    let high = high.expect("direct map guard is not set up");
+    let kernel_mapper = KernelMapper::lock();
+    let window_start = high.start - 4 * PAGE;
+    assert_eq!(
+        kernel_mapper.find_free_range(window_start, high.end, PAGE, 1),
+        Some(high.start)
+    );
+    assert_eq!(
+        kernel_mapper.find_free_range(window_start, high.end, high.end - high.start + PAGE, 1),
+        None
+    );
+    drop(kernel_mapper);
+}
+
+/// 在已知的PAT设置下，检查每一种PAT/PCD/PWT组合（包括大页）解析出的缓存类型
+pub fn test_effective_cache_type() {
+    use CacheType::*;
+    // 与Linux相同的PAT设置：WB, WC, UC-, UC, WB, WP, UC-, WT
+    let pat = X86_64MMArch::pat_decode(0x0407_0506_0007_0106);
+    assert_eq!(
+        pat,
+        [
+            WriteBack,
+            WriteCombining,
+            Uncacheable,
+            Uncacheable,
+            WriteBack,
+            WriteProtected,
+            Uncacheable,
+            WriteThrough
+        ]
+    );
+
+    with_user_mapper(|umapper| {
+        let base = VirtAddr::new(0x4000_0000);
+        let flags_of = |index: usize| {
+            PageFlags::<MMArch>::new()
+                .set_user(true)
+                .set_page_write_through(index & 1 != 0)
+                .set_page_cache_disable(index & 2 != 0)
+                .set_page_attribute_table(index & 4 != 0)
+        };
+        // 只检查页表项，不会访问页面，因此可以映射任意的物理地址
+        for index in 0..8 {
+            let vaddr = base + index * MMArch::PAGE_SIZE;
+            let flusher = unsafe {
+                umapper
+                    .utable
+                    .map_phys(vaddr, PhysAddr::new(0x10_0000), flags_of(index))
+            }
+            .unwrap();
+            unsafe { flusher.ignore_safe() };
+            assert_eq!(
+                umapper.utable.effective_cache_type_with_pat(vaddr, &pat),
+                Some(pat[index]),
+                "index {}",
+                index
+            );
+        }
+        assert_eq!(
+            umapper
+                .utable
+                .effective_cache_type_with_pat(base + 8 * MMArch::PAGE_SIZE, &pat),
+            None
+        );
+        // 映射的物理地址是任意的，需要在清空用户空间之前取消映射，否则它会被当作普通的页帧释放
+        for index in 0..8 {
+            let (_, _, flusher) = unsafe {
+                umapper
+                    .utable
+                    .unmap_phys(base + index * MMArch::PAGE_SIZE, false)
+            }
+            .unwrap();
+            unsafe { flusher.ignore_safe() };
+        }
+
+        // 2M的大页：PAT位位于第12位。手动在PD中写入映射大页的页表项
+        let huge_vaddr = VirtAddr::new(0x8000_0000);
+        let table = umapper.utable.table();
+        unsafe {
+            umapper
+                .utable
+                .reserve_tables(huge_vaddr, PageFrameCount::new(1))
+                .unwrap();
+            let pdpt = table
+                .next_level_table(table.index_of(huge_vaddr).unwrap())
+                .unwrap();
+            let pd = pdpt
+                .next_level_table(pdpt.index_of(huge_vaddr).unwrap())
+                .unwrap();
+            let i = pd.index_of(huge_vaddr).unwrap();
+            // 保留页表时创建的指向PT的表项，检查完毕之后恢复，以便清空用户空间时释放PT
+            let original = pd.entry(i).unwrap().data();
+            for index in [0b111, 0b101, 0b001] {
+                let bits = 0x4000_0000 | flags_of(index).to_entry_bits_at(1);
+                pd.set_entry(i, PageEntry::new(bits));
+                assert_eq!(
+                    umapper
+                        .utable
+                        .effective_cache_type_with_pat(huge_vaddr + 0x1234, &pat),
+                    Some(pat[index]),
+                    "huge page, index {}",
+                    index
+                );
+            }
+            pd.set_entry(i, PageEntry::new(original));
+        }
+    });
+
+    // 直接映射区域总是使用第0个PAT表项，按照当前的PAT设置为WB
+    let direct = unsafe { MMArch::phys_2_virt(X86_64MMArch::phys_memory_areas()[0].base) }.unwrap();
+    assert_eq!(
+        KernelMapper::lock().effective_cache_type(direct),
+        Some(X86_64MMArch::pat_entries()[0])
+    );
+    assert_eq!(X86_64MMArch::pat_entries()[0], WriteBack);
+}
+
+/// 把临时槽位映射到一个写入了标记的页帧，通过槽位的虚拟地址读出标记，然后取消映射
+pub fn test_fixmap() {
+    const MAGIC: u64 = 0x6669_786d_6170_2121;
+    let slot = FixmapSlot::Scratch;
+    let vaddr = slot.vaddr();
+    assert!(in_fixmap_area(vaddr));
+    // 临时槽位是最后一个槽位
+    assert!(!in_fixmap_area(VirtAddr::new(
+        vaddr.data() + MMArch::PAGE_SIZE
+    )));
+    assert_eq!(
+        set_fixmap(slot, PhysAddr::new(0x1010), PageFlags::new()).err(),
+        Some(SystemError::EINVAL)
+    );
+
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("test_fixmap: out of memory");
+    unsafe {
+        let direct = MMArch::phys_2_virt(paddr).unwrap();
+        MMArch::write::<u64>(direct, MAGIC);
+    }
+
+    assert_eq!(set_fixmap(slot, paddr, PageFlags::new()), Ok(vaddr));
+    // 槽位已经被映射
+    assert_eq!(
+        set_fixmap(slot, paddr, PageFlags::new()).err(),
+        Some(SystemError::EBUSY)
+    );
+    assert_eq!(unsafe { MMArch::read::<u64>(vaddr) }, MAGIC);
+
+    assert_eq!(clear_fixmap(slot), Ok(paddr));
+    assert!(KernelMapper::lock()
+        .as_mut()
+        .unwrap()
+        .translate(vaddr)
+        .is_none());
+    assert_eq!(clear_fixmap(slot).err(), Some(SystemError::EINVAL));
+    // 槽位可以被再次使用
+    assert_eq!(set_fixmap(slot, paddr, PageFlags::new()), Ok(vaddr));
+    assert_eq!(clear_fixmap(slot), Ok(paddr));
+
+    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(1)) };
+}
+
+/// 检查实际的内核页表，然后在一张模拟的页表中注入被破坏的页表项（保留位、RAM之外的地址），检查它们能够被发现
+pub fn test_verify_page_tables() {
+    const TABLE: usize = MMArch::ENTRY_FLAG_PRESENT | MMArch::ENTRY_FLAG_READWRITE;
+    assert_eq!(verify_kernel_page_tables(), Ok(()));
+
+    let ram = MMArch::phys_memory_areas();
+    let kernel_half = VirtAddr::new(MMArch::PHYS_OFFSET)..VirtAddr::new(usize::MAX);
+    let alloc_table = || unsafe {
+        let paddr = LockedFrameAllocator
+            .allocate_one()
+            .expect("test_verify_page_tables: out of memory");
+        MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0, MMArch::PAGE_SIZE);
+        paddr
+    };
+    let pml4 = alloc_table();
+    let pdpt = alloc_table();
+    let table =
+        unsafe { PageTable::<MMArch>::new(VirtAddr::new(0), pml4, MMArch::PAGE_LEVELS - 1) };
+    let set =
+        |i: usize, entry: usize| unsafe { table.set_entry(i, PageEntry::new(entry)) }.unwrap();
+    let verify = || verify_page_table(&table, kernel_half.clone(), ram);
+
+    // 模拟的页表中，直接映射区域之后的某个PML4页表项指向一张空的PDPT
+    let index = 300;
+    let vaddr = VirtAddr::new(MMArch::PAGE_NEGATIVE_MASK | (index << 39));
+    set(index, pdpt.data() | TABLE);
+    assert_eq!(verify(), Ok(()));
+
+    // PML4的页表项中设置了PS位
+    let entry = pdpt.data() | TABLE | MMArch::ENTRY_FLAG_HUGE_PAGE;
+    set(index, entry);
+    let location = match verify() {
+        Err(PageTableError::ReservedBits(location)) => location,
+        r => panic!("PS in PML4 not detected: {:?}", r),
+    };
+    assert_eq!(
+        (location.table, location.level, location.index),
+        (pml4, 3, index)
+    );
+    assert_eq!((location.vaddr, location.entry), (vaddr, entry));
+
+    // 未开启NX时，XD位是保留位
+    if X86_64MMArch::is_xd_reserved() {
+        set(index, pdpt.data() | TABLE | MMArch::ENTRY_FLAG_NO_EXEC);
+        assert!(matches!(verify(), Err(PageTableError::ReservedBits(_))));
+    }
+
+    // MAXPHYADDR以上的地址位
+    let phys_bits = X86_64MMArch::phys_address_bits();
+    if phys_bits < MMArch::ENTRY_ADDRESS_SHIFT && (1 << phys_bits) & mem_encrypt_mask() == 0 {
+        set(index, pdpt.data() | (1 << phys_bits) | TABLE);
+        assert!(matches!(verify(), Err(PageTableError::ReservedBits(_))));
+    }
+
+    // 指向RAM之外的下一级页表（不会访问这个地址）
+    let ram_top = ram
+        .iter()
+        .map(|area| area.base.data() + area.size)
+        .max()
+        .unwrap();
+    let outside = page_align_up(ram_top);
+    assert!(outside < 1 << phys_bits);
+    set(index, outside | TABLE);
+    match verify() {
+        Err(PageTableError::AddressOutOfRam(location)) => {
+            assert_eq!((location.level, location.index), (3, index));
+            assert_eq!(location.entry, outside | TABLE);
+        }
+        r => panic!("table outside RAM not detected: {:?}", r),
+    }
+
+    // 直接映射区域中的1G大页指向RAM之外的地址
+    let outside_1g = (ram_top + (1 << 30) - 1) & !((1 << 30) - 1);
+    if X86_64MMArch::huge_page_support().supports_1g && outside_1g < 1 << phys_bits {
+        set(index, 0);
+        let direct = (MMArch::PHYS_OFFSET >> 39) & (MMArch::PAGE_ENTRY_NUM - 1);
+        set(direct, pdpt.data() | TABLE);
+        let pdpt_table = unsafe { PageTable::<MMArch>::new(VirtAddr::new(0), pdpt, 2) };
+        let entry = outside_1g | TABLE | MMArch::ENTRY_FLAG_HUGE_PAGE;
+        unsafe { pdpt_table.set_entry(0, PageEntry::new(entry)) }.unwrap();
+        match verify() {
+            Err(PageTableError::AddressOutOfRam(location)) => {
+                assert_eq!((location.table, location.level), (pdpt, 2));
+                assert_eq!(location.vaddr, VirtAddr::new(MMArch::PHYS_OFFSET));
+            }
+            r => panic!("direct map outside RAM not detected: {:?}", r),
+        }
+    }
+
+    unsafe {
+        LockedFrameAllocator.free_one(pdpt);
+        LockedFrameAllocator.free_one(pml4);
+    }
+}
+
+/// 检查`map_one`与`map_phys`产生相同的最后一级页表项、刷新器以及错误，并比较两者在连续映射大量页面时的开销
+pub fn test_map_one() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    const BENCH_PAGES: usize = 256;
+    with_user_mapper(|umapper| {
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        let a = VirtAddr::new(0x60_0000);
+        let b = VirtAddr::new(0x7f_0000_0000);
+
+        for flags in [
+            PageFlags::new().set_user(true).set_write(true),
+            PageFlags::new().set_user(true).set_execute(true),
+            PageFlags::new().set_user(true).set_cow(true),
+        ] {
+            let fa = unsafe { umapper.utable.map_phys(a, paddr, flags) }.unwrap();
+            // b所在的各级页表都还不存在，map_one需要逐级分配
+            let fb = unsafe { umapper.utable.map_one(b, paddr, flags) }.unwrap();
+            assert_eq!(fa.virt(), a);
+            assert_eq!(fb.virt(), b);
+            unsafe {
+                fa.ignore_safe();
+                fb.ignore_safe();
+            }
+            let ea = umapper.utable.raw_entry(a).unwrap();
+            let eb = umapper.utable.raw_entry(b).unwrap();
+            assert_eq!(ea.data(), eb.data());
+            for vaddr in [a, b] {
+                unsafe {
+                    umapper
+                        .utable
+                        .unmap_phys(vaddr, false)
+                        .unwrap()
+                        .2
+                        .ignore_safe()
+                };
+            }
+        }
+
+        // 错误与map_phys相同
+        let flags = PageFlags::new().set_user(true);
+        for (virt, phys) in [
+            (a + 1, paddr),
+            (a, paddr + 1),
+            (VirtAddr::new(0x0000_8000_0000_0000), paddr),
+        ] {
+            let ra = unsafe { umapper.utable.map_phys(virt, phys, flags) }.map(|f| f.virt());
+            let rb = unsafe { umapper.utable.map_one(virt, phys, flags) }.map(|f| f.virt());
+            assert!(ra.is_err());
+            assert_eq!(ra, rb);
+        }
+
+        // 把同一个页帧映射到同一张最后一级页表中的BENCH_PAGES个页面（页表已经存在），比较两者的开销
+        let bench = |umapper: &mut UserMapper, one: bool| -> u64 {
+            let start = unsafe { rdtsc() };
+            for i in 0..BENCH_PAGES {
+                let vaddr = a + i * PAGE;
+                let flush = if one {
+                    unsafe { umapper.utable.map_one(vaddr, paddr, flags) }
+                } else {
+                    unsafe { umapper.utable.map_phys(vaddr, paddr, flags) }
+                };
+                unsafe { flush.unwrap().ignore_safe() };
+            }
+            let cycles = unsafe { rdtsc() } - start;
+            for i in 0..BENCH_PAGES {
+                unsafe {
+                    umapper
+                        .utable
+                        .unmap_phys(a + i * PAGE, false)
+                        .unwrap()
+                        .2
+                        .ignore_safe()
+                };
+            }
+            return cycles;
+        };
+        // 预先建立各级页表
+        bench(umapper, false);
+        let phys_cycles = bench(umapper, false);
+        let one_cycles = bench(umapper, true);
+        kdebug!(
+            "test_map_one: {} pages, map_phys: {} cycles, map_one: {} cycles",
+            BENCH_PAGES,
+            phys_cycles,
+            one_cycles
+        );
+    });
+    unsafe { LockedFrameAllocator.free_one(paddr) };
+}
+
+/// 检查2M大页的映射：建立大页不需要PT，地址翻译覆盖整个大页；修改、取消其中一个4K页面的映射会拆分大页，
+/// 其余的页面保持映射；`unmap_phys_huge`取消整个大页的映射。同时检查直接映射区域使用了大页
+pub fn test_map_phys_huge() {
+    let huge = PageSize::Size2M.bytes::<MMArch>();
+    with_user_mapper(|umapper| {
+        let (paddr, count) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(512)) }
+            .expect("Failed to allocate 2M");
+        assert!(paddr.check_aligned(huge));
+        let rw = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        let ro = PageFlags::<MMArch>::new().set_user(true);
+        let vaddr = VirtAddr::new(0x4000_0000);
+        let page = |i: usize| vaddr + i * MMArch::PAGE_SIZE;
+        let map_huge = |umapper: &mut UserMapper| {
+            unsafe {
+                umapper
+                    .utable
+                    .map_phys_huge(vaddr, paddr, rw, PageSize::Size2M)
+            }
+            .expect("Failed to map huge page")
+            .flush();
+        };
+        // 逐页取消映射，并释放变为空的页表
+        let unmap_pages = |umapper: &mut UserMapper, skip: usize| {
+            for i in (0..count.data()).filter(|i| *i != skip) {
+                let (mapped, _, flusher) =
+                    unsafe { umapper.utable.unmap_phys(page(i), true) }.unwrap();
+                assert_eq!(mapped, paddr + i * MMArch::PAGE_SIZE);
+                flusher.flush();
+            }
+        };
+
+        // 地址没有按2M对齐
+        assert_eq!(
+            unsafe {
+                umapper
+                    .utable
+                    .map_phys_huge(page(1), paddr, rw, PageSize::Size2M)
+            }
+            .err(),
+            Some(MmError::Unaligned(page(1).data()))
+        );
+
+        let before = PageTableAllocStats::snapshot();
+        map_huge(umapper);
+        let mapped = PageTableAllocStats::snapshot().since(&before);
+        assert_eq!(mapped.frames_at(0), 0);
+        assert_eq!(mapped.total().data(), 2);
+        assert_eq!(
+            umapper.utable.leaf_entry(vaddr).map(|(level, _)| level),
+            Some(1)
+        );
+        assert!(umapper.utable.raw_entry(vaddr).is_none());
+        for i in [0, 1, 255, 511] {
+            let (mapped, flags) = umapper.utable.translate(page(i) + 0x123).unwrap();
+            assert_eq!(mapped, paddr + i * MMArch::PAGE_SIZE);
+            assert!(flags.has_user() && flags.has_write());
+        }
+
+        // 取消其中一页的映射：大页被拆分为一张PT，其余的页面保持映射
+        let before = PageTableAllocStats::snapshot();
+        let (unmapped, _, flusher) = unsafe { umapper.utable.unmap_phys(page(5), true) }.unwrap();
+        flusher.flush();
+        assert_eq!(unmapped, paddr + 5 * MMArch::PAGE_SIZE);
+        assert_eq!(
+            PageTableAllocStats::snapshot().since(&before).frames_at(0),
+            1
+        );
+        assert!(umapper.utable.translate(page(5)).is_none());
+        for i in [0, 4, 6, 511] {
+            assert_eq!(
+                umapper.utable.leaf_entry(page(i)).map(|(level, _)| level),
+                Some(0)
+            );
+            let (mapped, flags) = umapper.utable.translate(page(i)).unwrap();
+            assert_eq!(mapped, paddr + i * MMArch::PAGE_SIZE);
+            assert!(flags.has_user() && flags.has_write());
+        }
+        assert_eq!(
+            unsafe { umapper.utable.unmap_phys_huge(vaddr, PageSize::Size2M) }.err(),
+            Some(MmError::NotMapped(vaddr))
+        );
+        unmap_pages(umapper, 5);
+
+        // 修改其中一页的权限：同样拆分大页，只有这一页变为只读
+        map_huge(umapper);
+        unsafe { umapper.utable.remap(page(7), ro) }
+            .unwrap()
+            .flush();
+        assert!(!umapper.utable.translate(page(7)).unwrap().1.has_write());
+        assert!(umapper.utable.translate(page(8)).unwrap().1.has_write());
+        unmap_pages(umapper, usize::MAX);
+
+        // 取消整个大页的映射
+        map_huge(umapper);
+        let (unmapped, flags, flusher) =
+            unsafe { umapper.utable.unmap_phys_huge(vaddr, PageSize::Size2M) }.unwrap();
+        flusher.flush();
+        assert_eq!(unmapped, paddr);
+        assert!(flags.has_user() && flags.has_write());
+        assert!(umapper.utable.translate(page(511)).is_none());
+
+        // 页帧都已经被取消映射，clear_user_space只会释放页表
+    });
+    unsafe { LockedFrameAllocator.free(paddr, count) };
+
+    // 直接映射区域：按2M对齐、完整地位于RAM区域中、其中每一页的flags都相同的2M范围使用大页映射
+    // （运行时修改其中的页面会拆分大页，因此只检查至少有一个大页）
+    if !force_4k_pages() {
+        let kernel_mapper = KernelMapper::lock();
+        let mut expected = 0;
+        let mut found = 0;
+        for area in X86_64MMArch::phys_memory_areas() {
+            let start = (area.base.data() + huge - 1) & !(huge - 1);
+            let end = (area.base.data() + area.size) & !(huge - 1);
+            for p in (start..end.max(start)).step_by(huge) {
+                let p = PhysAddr::new(p);
+                let v = unsafe { MMArch::phys_2_virt(p) }.unwrap();
+                let flags = unsafe { kernel_page_flags::<MMArch>(v) };
+                if direct_map_page_size(v, p, huge, flags) != PageSize::Size2M {
+                    continue;
+                }
+                expected += 1;
+                if kernel_mapper.as_ref().leaf_entry(v).map(|(level, _)| level) == Some(1) {
+                    found += 1;
+                }
+            }
+        }
+        assert!(expected == 0 || found > 0);
+    }
+}
+
+/// 检查`dump_page_table`只打印与范围相交的页表项，并且不会进入大页
+pub fn test_dump_page_table() {
+    with_user_mapper(|umapper| {
+        let (paddr, count) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(512)) }
+            .expect("Failed to allocate 2M");
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        let vaddr = VirtAddr::new(0x4000_0000);
+        let huge_vaddr = vaddr + PageSize::Size2M.bytes::<MMArch>();
+        unsafe {
+            for i in 0..3 {
+                let offset = i * MMArch::PAGE_SIZE;
+                umapper
+                    .utable
+                    .map_phys(vaddr + offset, paddr + offset, flags)
+                    .unwrap()
+                    .ignore_safe();
+            }
+            umapper
+                .utable
+                .map_phys_huge(huge_vaddr, paddr, flags, PageSize::Size2M)
+                .unwrap()
+                .ignore_safe();
+        }
+
+        // PML4[0]、PDPT[1]、PD[0]以及其中的3个PT页表项、映射大页的PD[1]
+        let range = vaddr..vaddr + 0x100_0000;
+        assert_eq!(dump_page_table(&umapper.utable, range), 7);
+        // 只与大页相交
+        let range = huge_vaddr + 0x1000..huge_vaddr + 0x2000;
+        assert_eq!(dump_page_table(&umapper.utable, range), 3);
+        // 没有映射的范围，以及空的范围
+        let range = VirtAddr::new(0x8000_0000)..VirtAddr::new(0x8000_1000);
+        assert_eq!(dump_page_table(&umapper.utable, range), 0);
+        assert_eq!(dump_page_table(&umapper.utable, vaddr..vaddr), 0);
+
+        // 内核的直接映射区域
+        let kernel_vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+        let printed = dump_page_table(
+            KernelMapper::lock().as_ref(),
+            kernel_vaddr..kernel_vaddr + MMArch::PAGE_SIZE,
+        );
+        assert!(printed >= 3);
+
+        // 取消所有的映射之后，一次性释放整个2M的块
+        unsafe {
+            for i in 0..3 {
+                let (_, _, flusher) = umapper
+                    .utable
+                    .unmap_phys(vaddr + i * MMArch::PAGE_SIZE, true)
+                    .unwrap();
+                flusher.ignore_safe();
+            }
+            let (_, _, flusher) = umapper
+                .utable
+                .unmap_phys_huge(huge_vaddr, PageSize::Size2M)
+                .unwrap();
+            flusher.ignore_safe();
+        }
+    });
+    unsafe { LockedFrameAllocator.free(paddr, count) };
+}
+
+/// 检查`map_with_guard`留出的保护页不存在，以及出错时不留下映射
+pub fn test_map_with_guard() {
+    with_user_mapper(|umapper| {
+        let mapper = &mut umapper.utable;
+        let flags = PageFlags::new().set_user(true).set_write(true);
+        let base = VirtAddr::new(0x40_0000);
+        let count = PageFrameCount::new(4);
+
+        let (usable, flusher) =
+            unsafe { map_with_guard(mapper, base, None, count, flags, true, true) }
+                .expect("Failed to map with guard");
+        unsafe { flusher.ignore_safe() };
+        assert_eq!(
+            usable,
+            base + MMArch::PAGE_SIZE..base + 5 * MMArch::PAGE_SIZE
+        );
+        for i in 0..count.data() {
+            assert!(mapper
+                .translate(usable.start + i * MMArch::PAGE_SIZE)
+                .is_some());
+        }
+        // 保护页的页表项不存在，而不是只读
+        for guard in [base, usable.end] {
+            assert!(mapper.translate(guard).is_none());
+            assert!(mapper.raw_entry(guard).map_or(true, |e| !e.present()));
+        }
+
+        // 可用页面或者保护页与已经映射的页面重叠时失败，并且不建立新的映射
+        let last = usable.end - MMArch::PAGE_SIZE;
+        let r = unsafe { map_with_guard(mapper, last, None, count, flags, false, false) };
+        assert_eq!(r.err(), Some(MmError::AlreadyMapped(last)));
+        let r = unsafe { map_with_guard(mapper, last, None, count, flags, true, false) };
+        assert_eq!(r.err(), Some(MmError::AlreadyMapped(last)));
+        assert!(mapper.translate(usable.end).is_none());
+        let r = unsafe { map_with_guard(mapper, base + 1, None, count, flags, false, false) };
+        assert_eq!(r.err(), Some(MmError::Unaligned(base.data() + 1)));
+
+        let freed = umapper.clear_user_space();
+        assert_eq!(freed.data(), count.data());
+    });
+}
+
+/// 检查按页遍历地址范围的迭代器恰好产生count个地址之后停止（包括count为0，以及范围到达地址空间末尾的情况）
+pub fn test_page_range() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    let vbase = VirtAddr::new(0x4000_0000);
+    let pbase = PhysAddr::new(0x20_0000);
+
+    let mut range = PageRange::virt(vbase, PageFrameCount::new(3));
+    assert_eq!(range.len(), 3);
+    assert_eq!(range.next(), Some(vbase));
+    assert_eq!(range.next(), Some(vbase + PAGE));
+    assert_eq!(range.next(), Some(vbase + 2 * PAGE));
+    assert_eq!(range.len(), 0);
+    assert_eq!(range.next(), None);
+    assert_eq!(range.next(), None);
+
+    assert_eq!(PageRange::virt(vbase, PageFrameCount::new(0)).next(), None);
+    assert_eq!(PageRange::phys(pbase, PageFrameCount::new(0)).count(), 0);
+    assert_eq!(
+        PageRange::phys(pbase, PageFrameCount::new(2)).last(),
+        Some(pbase + PAGE)
+    );
+
+    // 最后一个页面之后不再计算下一个地址，因此不会溢出
+    let top = VirtAddr::new(usize::MAX & !(PAGE - 1));
+    assert_eq!(
+        PageRange::virt(top, PageFrameCount::new(1)).collect::<Vec<_>>(),
+        [top]
+    );
+
+    let pairs: Vec<(VirtAddr, PhysAddr)> =
+        page_map_range(vbase, pbase, PageFrameCount::new(4)).collect();
+    assert_eq!(pairs.len(), 4);
+    for (i, (vaddr, paddr)) in pairs.into_iter().enumerate() {
+        assert_eq!(vaddr, vbase + i * PAGE);
+        assert_eq!(paddr, pbase + i * PAGE);
+    }
+}
+
+/// 把三段不连续的物理内存映射到一段连续的虚拟地址空间，检查每一页的翻译，以及中途失败时回滚已经建立的映射
+pub fn test_map_scatter() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    let counts = [1, 2, 1];
+    let segments: Vec<(PhysAddr, PageFrameCount)> = counts
+        .iter()
+        .map(|n| {
+            let count = PageFrameCount::new(*n);
+            let (paddr, _) = unsafe { LockedFrameAllocator.allocate(count) }.unwrap();
+            (paddr, count)
+        })
+        .collect();
+    let total: usize = counts.iter().sum();
+    let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+    let vaddr = VirtAddr::new(0x4000_0000);
+
+    with_user_mapper(|umapper| {
+        let (mapped, flusher) =
+            unsafe { umapper.utable.map_scatter(vaddr, &segments, flags) }.unwrap();
+        flusher.commit();
+        assert_eq!(mapped.data(), total);
+        let mut page = 0;
+        for (paddr, count) in segments.iter() {
+            for i in 0..count.data() {
+                let translated = umapper
+                    .utable
+                    .translate(vaddr + page * PAGE)
+                    .map(|(p, _)| p);
+                assert_eq!(translated, Some(*paddr + i * PAGE));
+                page += 1;
+            }
+        }
+        assert!(umapper.utable.translate(vaddr + total * PAGE).is_none());
+        let (unmapped, flusher) = unsafe {
+            umapper
+                .utable
+                .unmap_range(vaddr, PageFrameCount::new(total), false)
+        }
+        .unwrap();
+        flusher.commit();
+        assert_eq!(unmapped.data(), total);
+
+        // 最后一页已经被映射，此时之前映射的页面都会被取消映射，已有的映射保持不变
+        let (last, _) = segments[2];
+        let last_vaddr = vaddr + (total - 1) * PAGE;
+        let flusher = unsafe { umapper.utable.map_phys(last_vaddr, last, flags) }.unwrap();
+        unsafe { flusher.ignore_safe() };
+        let r = unsafe { umapper.utable.map_scatter(vaddr, &segments, flags) };
+        assert_eq!(r.err(), Some(SystemError::EEXIST));
+        for page in 0..total - 1 {
+            assert!(umapper.utable.translate(vaddr + page * PAGE).is_none());
+        }
+        assert_eq!(
+            umapper.utable.translate(last_vaddr).map(|(p, _)| p),
+            Some(last)
+        );
+        let (_, _, flusher) = unsafe { umapper.utable.unmap_phys(last_vaddr, false) }.unwrap();
+        unsafe { flusher.ignore_safe() };
+
+        // 没有按页对齐的物理地址
+        let unaligned = [(segments[0].0 + 0x10, PageFrameCount::new(1))];
+        let r = unsafe { umapper.utable.map_scatter(vaddr, &unaligned, flags) };
+        assert_eq!(r.err(), Some(SystemError::EINVAL));
+    });
+
+    for (paddr, count) in segments {
+        unsafe { LockedFrameAllocator.free(paddr, count) };
+    }
+}
+
+/// 检查`map_phys`、`unmap_phys`拒绝非规范的虚拟地址、没有按页对齐的地址以及已经映射的地址，并且不修改页表
+pub fn test_map_phys_bad_addr() {
+    with_user_mapper(|umapper| {
+        let mapper = &mut umapper.utable;
+        let flags = PageFlags::new().set_user(true).set_write(true);
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+
+        // 去掉符号扩展之后，这个地址在页表中的下标与直接映射区域的起始地址相同
+        let bad = VirtAddr::new(0x0000_8000_0000_0000);
+        let alias = VirtAddr::new(X86_64MMArch::PHYS_OFFSET);
+        assert!(!MMArch::virt_is_valid(bad));
+        let alias_before = mapper.translate(alias).map(|(paddr, _)| paddr);
+        assert_eq!(
+            unsafe { mapper.map_phys(bad, paddr, flags) }.err(),
+            Some(MmError::NotCanonical(bad))
+        );
+        assert_eq!(
+            mapper.translate(alias).map(|(paddr, _)| paddr),
+            alias_before
+        );
+        assert_eq!(
+            unsafe { mapper.unmap_phys(bad, false) }.err(),
+            Some(MmError::NotCanonical(bad))
+        );
+        assert_eq!(
+            mapper.translate(alias).map(|(paddr, _)| paddr),
+            alias_before
+        );
+        assert_eq!(
+            SystemError::from(MmError::NotCanonical(bad)),
+            SystemError::EINVAL
+        );
+
+        // 没有按页对齐的虚拟地址或者物理地址
+        let vaddr = VirtAddr::new(0x40_0000);
+        assert_eq!(
+            unsafe { mapper.map_phys(vaddr + 0x10, paddr, flags) }.err(),
+            Some(MmError::Unaligned(vaddr.data() + 0x10))
+        );
+        assert_eq!(
+            unsafe { mapper.map_phys(vaddr, paddr + 0x10, flags) }.err(),
+            Some(MmError::Unaligned(paddr.data() + 0x10))
+        );
+        assert_eq!(
+            unsafe { mapper.unmap_phys(vaddr + 0x10, false) }.err(),
+            Some(MmError::Unaligned(vaddr.data() + 0x10))
+        );
+        assert!(mapper.translate(vaddr).is_none());
+
+        // 合法的地址不受影响
+        let flusher = unsafe { mapper.map_phys(vaddr, paddr, flags) }.expect("map_phys failed");
+        unsafe { flusher.ignore_safe() };
+
+        // 已经映射的页面不会被覆盖，原有的映射保持不变
+        let other = PhysAddr::new(paddr.data() + MMArch::PAGE_SIZE);
+        assert_eq!(
+            unsafe { mapper.map_phys(vaddr, other, flags) }.err(),
+            Some(MmError::AlreadyMapped(vaddr))
+        );
+        assert_eq!(
+            unsafe { mapper.map_one(vaddr, other, flags) }.err(),
+            Some(MmError::AlreadyMapped(vaddr))
+        );
+        assert_eq!(mapper.translate(vaddr).map(|(p, _)| p), Some(paddr));
+        assert_eq!(
+            SystemError::from(MmError::AlreadyMapped(vaddr)),
+            SystemError::EEXIST
+        );
+
+        let (unmapped, _, flusher) = unsafe { mapper.unmap_phys(vaddr, true) }.unwrap();
+        unsafe { flusher.ignore_safe() };
+        assert_eq!(unmapped, paddr);
+
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+    });
+}
+
+/// 检查`map_elf_segment`：不从页的起始处开始的段被映射到包含它的页面，BSS被清零，页面的权限来自p_flags
+pub fn test_map_elf_segment() {
+    use crate::libs::elf::map_elf_segment;
+    use elf::abi::{PF_R, PF_W, PF_X};
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    with_user_mapper(|umapper| {
+        let mapper = &mut umapper.utable;
+        // 文件内容占用两个页帧，段从页内偏移0x123处开始
+        let (file, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(2)) }.unwrap();
+        unsafe { MMArch::write_bytes(MMArch::phys_2_virt(file).unwrap(), 0xaa, 2 * PAGE) };
+        let offset = 0x123;
+        let vaddr = VirtAddr::new(0x40_0000 + offset);
+        let file_len = PAGE;
+        let mem_len = 3 * PAGE;
+
+        let (range, flusher) = unsafe {
+            map_elf_segment(mapper, vaddr, file + offset, file_len, mem_len, PF_R | PF_X)
+        }
+        .expect("map_elf_segment failed");
+        unsafe { flusher.ignore_safe() };
+        assert_eq!(range, VirtAddr::new(0x40_0000)..VirtAddr::new(0x40_4000));
+        for i in 0..4 {
+            let (paddr, flags) = mapper.translate(range.start + i * PAGE).unwrap();
+            if i < 2 {
+                assert_eq!(paddr, file + i * PAGE);
+            }
+            assert!(flags.has_user());
+            assert!(!flags.has_write());
+            assert!(flags.has_execute() || X86_64MMArch::is_xd_reserved());
+        }
+        // 文件内容保持不变，其后的部分（BSS）被清零
+        let read = |virt: VirtAddr| {
+            let (paddr, _) = mapper.translate(virt).unwrap();
+            unsafe { MMArch::read::<u8>(MMArch::phys_2_virt(paddr).unwrap()) }
+        };
+        assert_eq!(read(vaddr), 0xaa);
+        assert_eq!(read(vaddr + file_len - 1), 0xaa);
+        assert_eq!(read(vaddr + file_len), 0);
+        assert_eq!(read(range.start + 2 * PAGE - 1), 0);
+        assert_eq!(read(vaddr + mem_len - 1), 0);
+
+        // 同时请求可写和可执行的段：能够实施W^X时，被映射为可写但不可执行
+        let wx = VirtAddr::new(0x80_0000);
+        let (wx_range, flusher) =
+            unsafe { map_elf_segment(mapper, wx, file, 0, PAGE, PF_R | PF_W | PF_X) }.unwrap();
+        unsafe { flusher.ignore_safe() };
+        let (_, flags) = mapper.translate(wx).unwrap();
+        assert!(flags.has_write());
+        assert_eq!(flags.has_execute(), !MMArch::can_enforce_wx());
+
+        // 页内偏移不同、文件大小超过内存大小时失败，并且不建立映射
+        let bad = VirtAddr::new(0xc0_0000 + offset);
+        assert_eq!(
+            unsafe { map_elf_segment(mapper, bad, file, PAGE, PAGE, PF_R) }.err(),
+            Some(SystemError::EINVAL)
+        );
+        assert_eq!(
+            unsafe { map_elf_segment(mapper, bad, file + offset, 2 * PAGE, PAGE, PF_R) }.err(),
+            Some(SystemError::EINVAL)
+        );
+        assert!(mapper.translate(bad).is_none());
+
+        // 文件内容所在的页帧由这里释放，BSS的页帧由clear_user_space释放
+        for i in 0..2 {
+            let (_, _, flush) =
+                unsafe { mapper.unmap_phys(range.start + i * PAGE, false) }.unwrap();
+            unsafe { flush.ignore_safe() };
+        }
+        assert_eq!(
+            umapper.clear_user_space().data(),
+            (range.end - range.start) / PAGE - 2 + (wx_range.end - wx_range.start) / PAGE
+        );
+        unsafe { LockedFrameAllocator.free(file, PageFrameCount::new(2)) };
+    });
+}
@@ -0,0 +1,233 @@
+//! 内存管理的启动时自检
+//!
+//! 这些测试在`process_init`中通过`rs_mm_boot_tests`运行，只有启用`mm_boot_tests`特性时才会被编译进调用路径。
+//! 其中一部分测试会改变全局状态、且无法恢复（永久隔离页帧、模拟内存不足、翻转XD_RESERVED、
+//! 故意保留过期的TLB条目），或者需要遍历全部RAM、测量耗时，它们还需要启用`mm_destructive_tests`特性。
+//!
+//! 测试按照被测试的功能分布在各个子模块中。
+
+pub mod allocator;
+pub mod boot;
+pub mod mapper;
+pub mod protect;
+pub mod tlb;
+pub mod user;
+
+use super::*;
+use crate::arch::mm::mem_encrypt::mark_private;
+use crate::arch::mm::mem_encrypt::mark_shared;
+use crate::arch::mm::mem_encrypt::mem_encrypt_active;
+use crate::arch::mm::mem_encrypt::mem_encrypt_mask;
+use crate::arch::mm::pcid::pcid_allocated;
+use crate::arch::mm::verify::verify_kernel_page_tables;
+use crate::arch::mm::verify::verify_page_table;
+use crate::arch::mm::verify::PageTableError;
+use crate::mm::allocator::buddy::PAGE_COLORS;
+use crate::mm::allocator::early_heap::EarlyHeap;
+use crate::mm::allocator::early_heap::EARLY_HEAP;
+use crate::mm::allocator::emergency::emergency_free;
+use crate::mm::allocator::emergency::emergency_pool_count;
+use crate::mm::allocator::frame_cache::frame_cache_count;
+use crate::mm::allocator::frame_tag::frame_tag_of;
+use crate::mm::allocator::frame_tag::leak_report;
+use crate::mm::allocator::frame_tag::FRAME_TAG_DMA;
+use crate::mm::allocator::frame_tag::FRAME_TAG_PAGE_TABLE;
+use crate::mm::allocator::frame_tag::FRAME_TAG_SLAB;
+use crate::mm::allocator::frame_tag::FRAME_TAG_USER_ANON;
+use crate::mm::allocator::huge_pool::alloc_zeroed_huge_page;
+use crate::mm::allocator::huge_pool::free_huge_page;
+use crate::mm::allocator::huge_pool::huge_pool_refill;
+use crate::mm::allocator::huge_pool::huge_pool_size;
+use crate::mm::allocator::huge_pool::huge_pool_stats;
+use crate::mm::allocator::huge_pool::set_huge_pool_size;
+use crate::mm::allocator::huge_pool::HUGE_PAGE_SIZE;
+use crate::mm::allocator::loworder_pool::set_loworder_pool_size;
+use crate::mm::allocator::page_frame::alloc_frames;
+use crate::mm::allocator::page_frame::pin_frames;
+use crate::mm::allocator::page_frame::unpin_frames;
+use crate::mm::allocator::page_frame::FrameGuard;
+use crate::mm::allocator::page_frame::{frame_node, pin_frame, unpin_frame, NUMA_NODES};
+use crate::mm::allocator::pressure::register_low_memory_callback;
+use crate::mm::allocator::pressure::unregister_low_memory_callback;
+use crate::mm::allocator::scrub::scrub_stats;
+use crate::mm::cache_type::cache_type_of;
+use crate::mm::fixmap::clear_fixmap;
+use crate::mm::fixmap::in_fixmap_area;
+use crate::mm::fixmap::set_fixmap;
+use crate::mm::fixmap::FixmapSlot;
+use crate::mm::initrd::map_initrd;
+use crate::mm::initrd::release_initrd;
+use crate::mm::ksm::dec_ref;
+use crate::mm::ksm::dec_ref_bulk;
+use crate::mm::ksm::inc_ref;
+use crate::mm::ksm::inc_ref_bulk;
+use crate::mm::ksm::ksm_frame_refcount;
+use crate::mm::ksm::try_merge;
+use crate::mm::mmio_buddy::mmio_pool;
+use crate::mm::page::ignored_flushes_after_boot;
+use crate::mm::page::set_force_4k_pages;
+use crate::mm::page::translate_cache_generation;
+use crate::mm::page::PageFlush;
+use crate::mm::percpu::alloc_percpu_for;
+use crate::mm::quarantine::quarantine_frame;
+use crate::mm::quarantine::quarantined_frames;
+use crate::mm::quarantine::QuarantineState;
+use crate::mm::reserved::reserved_regions;
+use crate::mm::scratch::free_uncached_page;
+use crate::mm::scratch::uncached_page;
+use crate::mm::scratch::uncached_pages;
+use crate::mm::stack_guard::map_with_guard;
+use crate::mm::stack_guard::register_stack_guard;
+use crate::mm::stack_guard::unregister_stack_guard;
+use crate::mm::trampoline::map_trampoline;
+use crate::mm::trampoline::trimmed_kernel_table;
+use crate::mm::trampoline::unmap_trampoline;
+use crate::mm::trampoline::TRAMPOLINE_AREA_BASE;
+use crate::mm::ucontext::zero_frame;
+use crate::mm::ucontext::{AddressSpace, UserMapper};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::alloc::Layout;
+
+/// 创建一个新的用户页表，在其上运行f，之后清空用户空间并释放页表
+///
+/// ## 返回值
+///
+/// f的返回值
+pub fn with_user_mapper<R>(f: impl FnOnce(&mut UserMapper) -> R) -> R {
+    let mut umapper = X86_64MMArch::setup_new_usermapper().expect("Failed to create user mapper");
+    let ret = f(&mut umapper);
+    umapper.clear_user_space();
+    drop(umapper);
+    return ret;
+}
+
+/// 依次运行测试，每个测试通过之后输出一行日志
+#[allow(unused_macros)]
+macro_rules! run_tests {
+    ($($test:path),* $(,)?) => {
+        $(
+            $test();
+            kdebug!("{} passed", stringify!($test));
+        )*
+    };
+}
+
+/// 运行内存管理的启动时自检。没有启用`mm_boot_tests`特性时什么也不做
+#[no_mangle]
+pub extern "C" fn rs_mm_boot_tests() {
+    #[cfg(feature = "mm_boot_tests")]
+    {
+        run_tests!(
+            boot::test_direct_map_guard,
+            boot::test_direct_map_covers_ram_sampled,
+            boot::test_kernel_phys_range,
+            boot::test_check_layout,
+            boot::test_memory_map_truncation,
+            boot::test_boot_mm_failure_message,
+            tlb::test_active_table_tracker,
+            tlb::test_tlb_shootdown_sync,
+            user::test_clear_user_space,
+            user::test_user_mapper_guard,
+            mapper::test_flush_batch,
+            mapper::test_shadow_stack_flags,
+            user::test_user_access_ok,
+            allocator::test_page_table_alloc_stats,
+            allocator::test_buddy_alignment,
+            allocator::test_allocate_kernel,
+            allocator::test_free_non_power_of_two,
+            allocator::test_allocate_colored,
+            allocator::test_buddy_invariants,
+            allocator::test_random_fallback,
+            boot::test_percpu_area,
+            boot::test_initial_page_table,
+            mapper::test_protect_and_flush,
+            boot::test_trampoline,
+            allocator::test_frame_tag,
+            allocator::test_frame_tag_sites,
+            boot::test_initrd,
+            allocator::test_loworder_pool,
+            mapper::test_nearest_mappings,
+            mapper::test_translate_cache,
+            user::test_write_fault,
+            mapper::test_estimate_table_cost,
+            mapper::test_replace_frame,
+            user::test_ksm_merge,
+            boot::test_area_trimming,
+            allocator::test_frame_guard,
+            boot::test_kernel_ram_coverage,
+            boot::test_force_4k_pages,
+            boot::test_ap_trampoline,
+            mapper::test_entry_bits,
+            mapper::test_zero_length_ranges,
+            mapper::test_page_range,
+            mapper::test_map_scatter,
+            boot::test_mem_limit,
+            mapper::test_explain,
+            allocator::test_uncached_page,
+            boot::test_no_usable_memory,
+            protect::test_kernel_wx,
+            mapper::test_huge_page_support,
+            mapper::test_ignored_flush,
+            boot::test_multiboot2_modules,
+            boot::test_early_heap,
+            protect::test_fault_address,
+            protect::test_fault_error_code,
+            allocator::test_frame_cache_warmup,
+            mapper::test_mem_encrypt_noop,
+            mapper::test_mem_encrypt_cbit,
+            mapper::test_find_free_range,
+            allocator::test_zero_frames_nt,
+            protect::test_stack_guard,
+            protect::test_kernel_stack_alloc,
+            user::test_ref_bulk,
+            boot::test_reserved_regions,
+            mapper::test_effective_cache_type,
+            boot::test_mmio_init_order,
+            boot::test_cma,
+            boot::test_direct_map_huge_opportunities,
+            allocator::test_free_check,
+            boot::test_memory_summary,
+            boot::test_identity_map,
+            mapper::test_fixmap,
+            protect::test_ro_after_init,
+            protect::test_writable_alias,
+            protect::test_wp_disabled,
+            mapper::test_verify_page_tables,
+            user::test_swap,
+            user::test_migrate_page,
+            allocator::test_frame_usage,
+            allocator::test_huge_pool,
+            mapper::test_map_phys_huge,
+            boot::test_merge_memory_areas,
+            mapper::test_dump_page_table,
+            tlb::test_pcid,
+            boot::test_reserved_areas,
+            mapper::test_map_with_guard,
+            user::test_clone_user_mapping,
+            user::test_huge_mmap,
+            user::test_user_stack_guard,
+            boot::test_bump_watermark,
+            allocator::test_frame_cache_magazine,
+            mapper::test_map_phys_bad_addr,
+            mapper::test_map_elf_segment,
+            allocator::test_emergency_pool,
+            allocator::test_scrub,
+        );
+    }
+
+    #[cfg(feature = "mm_destructive_tests")]
+    {
+        run_tests!(
+            boot::test_direct_map_covers_ram,
+            tlb::test_tlb_stale,
+            mapper::test_map_one,
+            allocator::test_quarantine,
+            tlb::test_invalidate_range,
+            allocator::test_low_memory_callback,
+            protect::test_xd_reserved_fallback,
+            allocator::test_emergency_critical_fallback,
+            allocator::test_critical_bypasses_watermark,
+        );
+    }
+}
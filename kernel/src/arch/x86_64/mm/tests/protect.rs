@@ -0,0 +1,389 @@
+//! 内核地址空间的保护（W^X、只读、保护页、缺页错误码）的测试
+
+use super::*;
+
+/// 检查内核地址的默认权限：直接映射区域不可执行，只有内核代码段可执行，并且内核地址空间中没有W^X的违例
+pub fn test_kernel_wx() {
+    let info: X86_64MMBootstrapInfo = BOOTSTRAP_MM_INFO.clone().unwrap();
+    let xd_reserved = X86_64MMArch::is_xd_reserved();
+
+    // 动态分配的页帧位于直接映射区域中
+    let paddr = unsafe { LockedFrameAllocator.allocate_one() }.expect("allocate_one failed");
+    let direct = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    assert_eq!(KernelRegion::of(direct), KernelRegion::DirectMap);
+    {
+        let (_, flags) = KernelMapper::lock().translate(direct).unwrap();
+        assert!(flags.has_write());
+        assert_eq!(flags.has_execute(), xd_reserved);
+    }
+    unsafe { LockedFrameAllocator.free_one(paddr) };
+
+    // 内核代码段可执行、只读
+    let text = VirtAddr::new(test_kernel_wx as usize);
+    assert_eq!(KernelRegion::of(text), KernelRegion::Text);
+    let (_, flags) = KernelMapper::lock().translate(text).unwrap();
+    assert!(flags.has_execute() && !flags.has_write());
+
+    // 内核的数据段位于直接映射区域中，不可执行
+    static DATA: AtomicUsize = AtomicUsize::new(0);
+    let data = VirtAddr::new(&DATA as *const AtomicUsize as usize);
+    assert_eq!(KernelRegion::of(data), KernelRegion::DirectMap);
+    let (_, flags) = KernelMapper::lock().translate(data).unwrap();
+    assert!(flags.has_write());
+    assert_eq!(flags.has_execute(), xd_reserved);
+
+    // 只读数据段不可写、不可执行
+    if info.kernel_data_end < info.kernel_rodata_end {
+        let rodata = VirtAddr::new(info.kernel_data_end);
+        assert_eq!(KernelRegion::of(rodata), KernelRegion::Rodata);
+        let flags = unsafe { kernel_page_flags::<MMArch>(rodata) };
+        assert!(!flags.has_write());
+        assert_eq!(flags.has_execute(), xd_reserved);
+    }
+
+    // 低地址的恒等映射保持可写、可执行
+    assert_eq!(KernelRegion::of(VirtAddr::new(0)), KernelRegion::LowRemap);
+    let flags = unsafe { kernel_page_flags::<MMArch>(VirtAddr::new(0x8000)) };
+    assert!(flags.has_write() && flags.has_execute());
+
+    let wx = check_kernel_wx();
+    assert!(wx.is_empty(), "W^X violations: {:?}", wx);
+}
+
+/// 检查`fault_address`读取的是CR2：内核态可以写入CR2，写入一个已知的值之后读取，最后恢复原来的值
+///
+/// 真正的缺页异常由缺页处理程序处理，无法在这里触发，因此不检查缺页时CR2的内容
+pub fn test_fault_address() {
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let old = unsafe { MMArch::fault_address() };
+    for value in [0x4000_1234usize, X86_64MMArch::PHYS_OFFSET + 0x5678] {
+        unsafe {
+            asm!("mov cr2, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+            assert_eq!(MMArch::fault_address(), VirtAddr::new(value));
+        }
+    }
+    unsafe { asm!("mov cr2, {}", in(reg) old.data(), options(nomem, nostack, preserves_flags)) };
+    drop(irq_guard);
+}
+
+/// 检查缺页异常错误码的解码以及文字描述
+pub fn test_fault_error_code() {
+    type Code = X86PageFaultErrorCode;
+    let describe = |error_code: u64| Code::decode(error_code).describe();
+
+    assert_eq!(describe(0x0), "read from non-present kernel page");
+    assert_eq!(describe(0x6), "write to non-present user page");
+    assert_eq!(describe(0x7), "write to present user page");
+    assert_eq!(describe(0x5), "read from present user page");
+    assert_eq!(describe(0x11), "instruction fetch from present kernel page");
+    assert_eq!(
+        describe(0x14),
+        "instruction fetch from non-present user page"
+    );
+    assert_eq!(describe(0x47), "shadow stack write to present user page");
+    assert_eq!(
+        describe(0x9),
+        "read from present kernel page, reserved bit set (corrupted page table)"
+    );
+    assert_eq!(
+        describe(0x27),
+        "write to present user page, protection key violation"
+    );
+    assert_eq!(
+        describe(0x8005),
+        "read from present user page, SGX access control"
+    );
+
+    let code = Code::decode(0xb);
+    assert!(code.is_corrupted_table());
+    assert_eq!(code, Code::PRESENT | Code::WRITE | Code::RESERVED_BIT);
+    assert!(!Code::decode(0x7).is_corrupted_table());
+    // 未定义的位被忽略
+    assert_eq!(
+        Code::decode(0xffff_0000_0000_0006),
+        Code::WRITE | Code::USER
+    );
+
+    let mut regs: pt_regs = unsafe { mem::zeroed() };
+    regs.errcode = 0x6;
+    assert_eq!(
+        X86_64MMArch::fault_error_code(&regs),
+        Code::WRITE | Code::USER
+    );
+}
+
+/// 检查内核栈保护页的登记，以及缺页地址的分类
+pub fn test_stack_guard() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    // 保护页只是被登记，不需要真的存在，因此使用直接映射区域上方的一段地址
+    let (high, _) = X86_64MMArch::direct_map_guard_ranges();
+    let base = high.expect("direct map guard is not set up").start;
+    let guard = base..base + 2 * PAGE;
+    let stack = guard.end;
+    let pid = 12345;
+
+    assert_eq!(classify_stack_fault(guard.start), None);
+    register_stack_guard(stack, guard.clone(), pid).expect("Failed to register stack guard");
+
+    // 保护页内的任意地址都被识别为栈溢出
+    for vaddr in [guard.start, guard.start + PAGE + 0x123, guard.end - 8] {
+        let hit = classify_stack_fault(vaddr).expect("guard fault is not classified");
+        assert_eq!(hit.pid, pid);
+        assert_eq!(hit.stack, stack);
+        assert_eq!(hit.guard, guard);
+    }
+    // 栈本身以及保护页下方的地址不是栈溢出
+    assert_eq!(classify_stack_fault(stack), None);
+    assert_eq!(classify_stack_fault(guard.start - 8), None);
+
+    // 重复登记、重叠的保护页、不合法的范围
+    assert_eq!(
+        register_stack_guard(stack, guard.clone(), pid),
+        Err(SystemError::EEXIST)
+    );
+    assert_eq!(
+        register_stack_guard(stack + 4 * PAGE, guard.start + PAGE..stack + PAGE, pid),
+        Err(SystemError::EEXIST)
+    );
+    assert_eq!(
+        register_stack_guard(stack + 4 * PAGE, stack..stack, pid),
+        Err(SystemError::EINVAL)
+    );
+    assert_eq!(
+        register_stack_guard(stack + 4 * PAGE, stack + 8..stack + PAGE, pid),
+        Err(SystemError::EINVAL)
+    );
+
+    let removed = unregister_stack_guard(stack).expect("stack guard is not registered");
+    assert_eq!(removed.guard, guard);
+    assert_eq!(classify_stack_fault(guard.start), None);
+    assert_eq!(unregister_stack_guard(stack), None);
+}
+
+/// 以两个动态分配的页帧在直接映射区域中的映射作为模拟的ro_after_init段，检查重新映射为只读，以及只读检查
+pub fn test_ro_after_init() {
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    let count = PageFrameCount::new(2);
+    let (paddr, _) =
+        unsafe { LockedFrameAllocator.allocate(count) }.expect("test_ro_after_init: out of memory");
+    let start = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    let range = start..start + count.bytes();
+    assert_eq!(check_kernel_readonly(range.clone()).len(), 2);
+
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper.as_mut().expect("kernel mapper is busy");
+        // 没有按页对齐
+        assert_eq!(
+            unsafe { remap_readonly(mapper, start..start + 0x10) }.err(),
+            Some(SystemError::EINVAL)
+        );
+        // 范围内有页面没有被映射时，不会修改任何页面
+        let fixmap = FixmapSlot::Scratch.vaddr();
+        assert_eq!(
+            unsafe { remap_readonly(mapper, fixmap..fixmap + PAGE) }.err(),
+            Some(SystemError::EFAULT)
+        );
+
+        let (_, before) = mapper.translate(start).unwrap();
+        unsafe { remap_readonly(mapper, range.clone()) }
+            .unwrap()
+            .flush();
+        for i in 0..count.data() {
+            let (_, flags) = mapper.translate(start + i * PAGE).unwrap();
+            assert!(!flags.has_write());
+            // 其他标志保持不变
+            assert_eq!(flags.has_execute(), before.has_execute());
+        }
+    }
+    assert!(check_kernel_readonly(range.clone()).is_empty());
+
+    // 恢复为可写，然后释放页帧
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper.as_mut().expect("kernel mapper is busy");
+        for i in 0..count.data() {
+            let vaddr = start + i * PAGE;
+            unsafe { mapper.remap(vaddr, kernel_page_flags(vaddr)) }
+                .unwrap()
+                .flush();
+        }
+    }
+    assert_eq!(check_kernel_readonly(range).len(), 2);
+    unsafe { LockedFrameAllocator.free(paddr, count) };
+
+    // 链接脚本中的ro_after_init段位于只读数据段之后，在初始化完成之前保持可写
+    let info: X86_64MMBootstrapInfo = BOOTSTRAP_MM_INFO.clone().unwrap();
+    assert!(info.check_layout().is_ok());
+    if info.ro_after_init_start < info.ro_after_init_end && !ro_after_init_sealed() {
+        let vaddr = VirtAddr::new(info.ro_after_init_start);
+        assert_eq!(KernelRegion::of(vaddr), KernelRegion::RoAfterInit);
+        assert!(unsafe { kernel_page_flags::<MMArch>(vaddr) }.has_write());
+    }
+}
+
+/// 通过可写别名修改一个被映射为只读的页帧，检查写入可见、原有的只读映射不变，并且别名在返回后被取消映射
+pub fn test_writable_alias() {
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("test_writable_alias: out of memory");
+    let direct = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    let value: u64 = 0x0a11_a5ed_f00d_cafe;
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_mut().expect("kernel mapper is busy");
+    unsafe { remap_readonly(mapper, direct..direct + MMArch::PAGE_SIZE) }
+        .unwrap()
+        .flush();
+
+    let alias = kernel_mapper
+        .with_writable_alias(paddr, |alias| {
+            unsafe { core::ptr::write_volatile(alias.as_ptr::<u64>(), value) };
+            alias
+        })
+        .expect("with_writable_alias failed");
+    assert_ne!(alias, direct);
+    assert!(kernel_mapper.translate(alias).is_none());
+    assert_eq!(
+        unsafe { core::ptr::read_volatile(direct.as_ptr::<u64>()) },
+        value
+    );
+    let (_, flags) = kernel_mapper.translate(direct).unwrap();
+    assert!(!flags.has_write());
+    assert_eq!(
+        kernel_mapper.with_writable_alias(paddr + 8, |_| ()),
+        Err(SystemError::EINVAL)
+    );
+
+    let mapper = kernel_mapper.as_mut().expect("kernel mapper is busy");
+    unsafe { mapper.remap(direct, kernel_page_flags(direct)) }
+        .unwrap()
+        .flush();
+    drop(kernel_mapper);
+    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(1)) };
+}
+
+/// 在关闭CR0.WP的情况下写入只读页面，检查WP在返回后被恢复，以及闭包修改WP时返回错误
+pub fn test_wp_disabled() {
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("test_wp_disabled: out of memory");
+    let direct = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    let value: u64 = 0x0b5e_55ed_0dd_ba11;
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper.as_mut().expect("kernel mapper is busy");
+        unsafe { remap_readonly(mapper, direct..direct + MMArch::PAGE_SIZE) }
+            .unwrap()
+            .flush();
+    }
+
+    assert!(X86_64MMArch::wp_enabled());
+    let r = unsafe {
+        X86_64MMArch::with_wp_disabled(|| {
+            core::ptr::write_volatile(direct.as_ptr::<u64>(), value);
+            X86_64MMArch::wp_enabled()
+        })
+    };
+    assert_eq!(r, Ok(false));
+    assert!(X86_64MMArch::wp_enabled());
+    assert_eq!(
+        unsafe { core::ptr::read_volatile(direct.as_ptr::<u64>()) },
+        value
+    );
+
+    // 闭包自己重新打开了WP
+    let r = unsafe { X86_64MMArch::with_wp_disabled(|| cr0_write(cr0() | Cr0::CR0_WRITE_PROTECT)) };
+    assert_eq!(r, Err(SystemError::EFAULT));
+    assert!(X86_64MMArch::wp_enabled());
+
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper.as_mut().expect("kernel mapper is busy");
+        unsafe { mapper.remap(direct, kernel_page_flags(direct)) }
+            .unwrap()
+            .flush();
+    }
+    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(1)) };
+}
+
+/// 模拟XD位被保留的情况，检查生成的页表项中不会出现第63位，并且`can_enforce_wx`返回false
+pub fn test_xd_reserved_fallback() {
+    let no_exec = MMArch::ENTRY_FLAG_NO_EXEC;
+    assert_eq!(no_exec, 1 << 63);
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let prev = XD_RESERVED.swap(true, Ordering::SeqCst);
+
+    assert!(!MMArch::can_enforce_wx());
+    let flags = PageFlags::<MMArch>::new()
+        .set_write(true)
+        .set_execute(false);
+    assert!(flags.has_execute());
+    // 直接构造的带有不可执行标志的PageFlags，生成页表项时同样丢弃它
+    let raw = unsafe { PageFlags::<MMArch>::from_data(MMArch::ENTRY_FLAG_DEFAULT_PAGE | no_exec) };
+    for level in 0..3 {
+        assert_eq!(
+            flags.to_entry_bits_at(level) & no_exec,
+            0,
+            "level {}",
+            level
+        );
+        assert_eq!(raw.to_entry_bits_at(level) & no_exec, 0, "level {}", level);
+    }
+    let direct_map = VirtAddr::new(MMArch::PHYS_OFFSET + (1 << 30));
+    let kflags = unsafe { kernel_page_flags::<MMArch>(direct_map) };
+    assert!(kflags.has_write() && kflags.has_execute());
+    assert_eq!(kflags.to_entry_bits() & no_exec, 0);
+    let mut entry = PageEntry::<MMArch>::new(0);
+    entry.set_flags(raw);
+    assert_eq!(entry.data() & no_exec, 0);
+    assert!(KernelMapper::lock()
+        .find_wx(VirtAddr::new(MMArch::PHYS_OFFSET)..VirtAddr::new(usize::MAX))
+        .is_empty());
+
+    XD_RESERVED.store(prev, Ordering::SeqCst);
+    drop(irq_guard);
+    assert_eq!(MMArch::can_enforce_wx(), !prev);
+    if !prev {
+        assert_ne!(raw.to_entry_bits() & no_exec, 0);
+    }
+}
+
+/// 检查`alloc_kernel_stack`分配的内核栈：按栈的大小对齐、已经清零，下方的保护页不存在并且已经登记，
+/// 以及释放之后取消登记
+pub fn test_kernel_stack_alloc() {
+    use crate::mm::stack_guard::{alloc_kernel_stack, free_kernel_stack, KERNEL_STACK_SIZE};
+    const PAGE: usize = MMArch::PAGE_SIZE;
+    let pid = 54321;
+    let vmap_before = kmem_stat_get(KernelMemPurpose::Vmap).data();
+
+    let top = alloc_kernel_stack(pid).expect("Failed to allocate kernel stack");
+    assert_eq!(
+        kmem_stat_get(KernelMemPurpose::Vmap).data(),
+        vmap_before + KERNEL_STACK_SIZE / PAGE
+    );
+    let stack = top - KERNEL_STACK_SIZE;
+    assert!(stack.check_aligned(KERNEL_STACK_SIZE));
+    let mapper = KernelMapper::lock();
+    for i in 0..KERNEL_STACK_SIZE / PAGE {
+        let (paddr, flags) = mapper
+            .translate(stack + i * PAGE)
+            .expect("stack page is not mapped");
+        assert!(flags.has_write() && !flags.has_user());
+        let p = unsafe { MMArch::phys_2_virt(paddr) }
+            .unwrap()
+            .as_ptr::<u64>();
+        assert!((0..PAGE / 8).all(|j| unsafe { p.add(j).read_volatile() } == 0));
+    }
+    assert!(mapper.translate(stack - PAGE).is_none());
+    drop(mapper);
+
+    let hit = classify_stack_fault(stack - 8).expect("guard fault is not classified");
+    assert_eq!(hit.pid, pid);
+    assert_eq!(hit.stack, stack);
+    assert_eq!(hit.guard, stack - PAGE..stack);
+
+    unsafe { free_kernel_stack(top) }.expect("Failed to free kernel stack");
+    assert_eq!(kmem_stat_get(KernelMemPurpose::Vmap).data(), vmap_before);
+    assert_eq!(classify_stack_fault(stack - 8), None);
+    assert!(KernelMapper::lock().translate(stack).is_none());
+    assert_eq!(unsafe { free_kernel_stack(top) }, Err(SystemError::EINVAL));
+}
@@ -0,0 +1,178 @@
+//! TLB刷新与PCID的测试
+
+use super::*;
+
+/// 检查切换页表之后，记录的当前页表与CR3寄存器的值一致
+pub fn test_active_table_tracker() {
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let prev = unsafe { MMArch::table(PageTableKind::User) };
+    for table in [MMArch::initial_page_table(), prev] {
+        unsafe { MMArch::set_table(PageTableKind::User, table) };
+        let cr3 = unsafe { MMArch::table(PageTableKind::User) };
+        assert_eq!(cr3, table);
+        assert_eq!(X86_64MMArch::current_table_phys(), Some(cr3));
+    }
+    drop(irq_guard);
+}
+
+/// 检查`tlb_shootdown_sync`返回时，其他所有CPU都已经确认了这次shootdown，
+/// 以及在关中断的情况下调用也能返回（当前CPU会确认其他CPU发出的shootdown）
+pub fn test_tlb_shootdown_sync() {
+    let cpus = (unsafe { smp_get_total_cpu() } as usize).min(PerCpu::MAX_CPU_NUM);
+    let cpu_id = smp_get_processor_id() as usize;
+    for _ in 0..2 {
+        // 这次shootdown的序号至少是before + 1
+        let before = TLB_SHOOTDOWN_GEN.load(Ordering::SeqCst);
+        let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+        tlb_shootdown_sync();
+        drop(irq_guard);
+        if cpus <= 1 {
+            // 只有一个CPU时不会发出shootdown
+            assert_eq!(TLB_SHOOTDOWN_GEN.load(Ordering::SeqCst), before);
+            continue;
+        }
+        for cpu in (0..cpus).filter(|cpu| *cpu != cpu_id) {
+            assert!(TLB_SHOOTDOWN_ACKS[cpu].load(Ordering::SeqCst) > before);
+        }
+    }
+}
+
+/// 抑制TLB刷新，检查替换页面的映射时恰好刷新了一次，并且真正的刷新之后读到新的页帧
+///
+/// 处理器是否仍然缓存着原来的翻译是不确定的，因此用软件TLB检查：被抑制的刷新之后，
+/// 软件TLB中仍然是frame_a的翻译，而真正的刷新会清除它
+#[cfg(feature = "tlb_fault_inject")]
+pub fn test_tlb_stale() {
+    let frame_a = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    let frame_b = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    unsafe {
+        MMArch::write(MMArch::phys_2_virt(frame_a).unwrap(), 0xaau8);
+        MMArch::write(MMArch::phys_2_virt(frame_b).unwrap(), 0xbbu8);
+    }
+    // 低半部分中没有被内核页表使用的地址
+    let vaddr = VirtAddr::new(0x7000_0000);
+    let flags = PageFlags::<MMArch>::new().set_write(true);
+    let read = || unsafe { core::ptr::read_volatile(vaddr.data() as *const u8) };
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper.as_mut().expect("kernel mapper is busy");
+    unsafe { mapper.map_phys(vaddr, frame_a, flags).unwrap().flush() };
+    assert_eq!(shadow_tlb_lookup(vaddr), None);
+
+    let ((before, stale, walked), suppressed) = with_tlb_invalidate_suppressed(|| {
+        // 让翻译进入TLB
+        let before = read();
+        shadow_tlb_fill(vaddr, frame_a);
+        let (old, flusher) = unsafe { mapper.replace_phys(vaddr, frame_b) }.unwrap();
+        assert_eq!(old, frame_a);
+        // 刷新被抑制
+        flusher.flush();
+        // 页表已经指向frame_b，但TLB中仍然是frame_a的翻译
+        let walked = mapper.translate(vaddr).unwrap().0;
+        (before, shadow_tlb_lookup(vaddr), walked)
+    });
+    assert_eq!(suppressed, 1, "replace_phys should flush exactly one page");
+    assert_eq!(before, 0xaa);
+    assert_eq!(walked, frame_b);
+    assert_eq!(
+        stale,
+        Some(frame_a),
+        "a suppressed flush must leave the stale translation in place"
+    );
+
+    // 真正地刷新之后，旧的翻译被清除，读到新的页帧
+    unsafe { MMArch::invalidate_page(vaddr) };
+    assert_eq!(shadow_tlb_lookup(vaddr), None);
+    assert_eq!(read(), 0xbb);
+
+    unsafe { mapper.unmap_phys(vaddr, true).unwrap().2.flush() };
+    drop(kernel_mapper);
+    unsafe {
+        LockedFrameAllocator.free(frame_a, PageFrameCount::new(1));
+        LockedFrameAllocator.free(frame_b, PageFrameCount::new(1));
+    }
+}
+
+/// 检查PCID的分配与归还，以及切换到同一个地址空间时只有在它的条目仍然有效时才不刷新TLB
+pub fn test_pcid() {
+    let allocated = pcid_allocated();
+    let umapper = X86_64MMArch::setup_new_usermapper().expect("Failed to create user mapper");
+    let pcid = umapper.pcid();
+    if !pcid_enabled() {
+        assert_eq!(pcid, 0);
+        drop(umapper);
+        kdebug!("test_pcid skipped: PCID is not supported");
+        return;
+    }
+    if pcid == 0 {
+        // PCID池已经耗尽
+        assert_eq!(allocated, pcid::PCID_COUNT - 1);
+    } else {
+        assert_eq!(pcid_allocated(), allocated + 1);
+    }
+
+    let table = umapper.utable.table().phys();
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let prev = unsafe { MMArch::table(PageTableKind::User) };
+    unsafe { umapper.make_current() };
+    assert_eq!(unsafe { MMArch::table(PageTableKind::User) }, table);
+    assert_eq!(current_pcid(), pcid);
+    unsafe { MMArch::set_table(PageTableKind::User, prev) };
+    assert_eq!(current_pcid(), 0);
+
+    if pcid != 0 {
+        // 切换回去之后，条目仍然有效
+        let cr3 = pcid_switch_cr3(table, pcid);
+        assert_eq!(cr3, table.data() | pcid | CR3_NOFLUSH);
+        // 刷新了这个地址空间中的地址之后：支持INVPCID时，条目已经被直接刷新；否则下一次切换需要刷新
+        unsafe { X86_64MMArch::invalidate_page_pcid(pcid, VirtAddr::new(0x1000)) };
+        let noflush = pcid_switch_cr3(table, pcid) & CR3_NOFLUSH != 0;
+        assert_eq!(noflush, invpcid_supported());
+        assert_ne!(pcid_switch_cr3(table, pcid) & CR3_NOFLUSH, 0);
+        // 刷新整个TLB之后，其他PCID的条目也需要刷新
+        unsafe { X86_64MMArch::flush_tlb_local() };
+        assert_eq!(pcid_switch_cr3(table, pcid) & CR3_NOFLUSH, 0);
+    }
+    drop(irq_guard);
+
+    drop(umapper);
+    assert_eq!(pcid_allocated(), allocated);
+}
+
+/// 检查`invalidate_range`对于较小的范围逐页刷新，对于较大的范围刷新整个TLB，并打印两种方式的开销
+pub fn test_invalidate_range() {
+    let start = VirtAddr::new(X86_64MMArch::PHYS_OFFSET);
+    let threshold = tlb_flush_range_threshold();
+    let irq_guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let flush = |pages: usize| {
+        let before = tlb_range_flush_stats();
+        let tsc = unsafe { rdtsc() };
+        unsafe { MMArch::invalidate_range(start, PageFrameCount::new(pages)) };
+        let cycles = unsafe { rdtsc() } - tsc;
+        let after = tlb_range_flush_stats();
+        (after.paged - before.paged, after.full - before.full, cycles)
+    };
+
+    let (paged, full, small_cycles) = flush(8);
+    assert_eq!((paged, full), (1, 0));
+    let (paged, full, _) = flush(threshold);
+    assert_eq!((paged, full), (1, 0));
+    let (paged, full, large_cycles) = flush(threshold + 1);
+    assert_eq!((paged, full), (0, 1));
+    let (paged, full, _) = flush(0);
+    assert_eq!((paged, full), (0, 0));
+
+    // 上限是可以调整的
+    assert_eq!(set_tlb_flush_range_threshold(4), threshold);
+    let (paged, full, _) = flush(8);
+    assert_eq!((paged, full), (0, 1));
+    set_tlb_flush_range_threshold(threshold);
+    drop(irq_guard);
+
+    kdebug!(
+        "test_invalidate_range passed: 8 pages (invlpg): {} cycles, {} pages (cr3 reload): {} cycles",
+        small_cycles,
+        threshold + 1,
+        large_cycles
+    );
+}
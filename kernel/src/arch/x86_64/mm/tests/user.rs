@@ -0,0 +1,713 @@
+//! 用户地址空间（用户页表、写时复制、KSM、换出）的测试
+
+use super::*;
+
+/// 检查`UserMapper::clear_user_space`能够清空用户空间，并且清空之后的页表可以被重新使用
+pub fn test_clear_user_space() {
+    with_user_mapper(|umapper| {
+        // 分布在不同的顶层页表项中的若干个用户地址
+        let vaddrs = [
+            VirtAddr::new(0x1000),
+            VirtAddr::new(0x40_0000),
+            VirtAddr::new(0x80_0000_0000),
+            VirtAddr::new(0x7fff_ffff_f000),
+        ];
+        let flags = PageFlags::new().set_user(true).set_write(true);
+
+        for round in 0..2 {
+            for vaddr in vaddrs.iter() {
+                let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+                let flusher = unsafe { umapper.utable.map_phys(*vaddr, paddr, flags) }
+                    .expect("Failed to map user page");
+                flusher.ignore_safe();
+                assert!(umapper.utable.translate(*vaddr).is_some());
+            }
+
+            let freed = umapper.clear_user_space();
+            assert_eq!(freed.data(), vaddrs.len(), "round {}", round);
+            for vaddr in vaddrs.iter() {
+                assert!(umapper.utable.translate(*vaddr).is_none());
+            }
+            // 内核部分的映射仍然存在
+            let ram_base = X86_64MMArch::phys_memory_areas()[0].base;
+            let kernel_vaddr = unsafe { MMArch::phys_2_virt(ram_base) }.unwrap();
+            assert!(umapper.utable.translate(kernel_vaddr).is_some());
+        }
+
+        // 2M大页没有下一级页表，设备内存（不在RAM中的物理地址）不会被释放到页帧分配器
+        let huge_vaddr = VirtAddr::new(0x4000_0000);
+        let (huge_paddr, _) =
+            unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(MMArch::PAGE_ENTRY_NUM)) }
+                .unwrap();
+        unsafe {
+            umapper
+                .utable
+                .map_phys_huge(huge_vaddr, huge_paddr, flags, PageSize::Size2M)
+                .unwrap()
+                .ignore_safe()
+        };
+        let mmio_vaddr = VirtAddr::new(0x8000_0000);
+        let mmio_paddr = PhysAddr::new(0xfee0_0000);
+        assert!(!crate::mm::cache_type::is_ram(mmio_paddr));
+        unsafe {
+            umapper
+                .utable
+                .map_phys(mmio_vaddr, mmio_paddr, flags.set_page_cache_disable(true))
+                .unwrap()
+                .ignore_safe()
+        };
+        let freed = umapper.clear_user_space();
+        assert_eq!(freed.data(), MMArch::PAGE_ENTRY_NUM + 1);
+        assert!(umapper.utable.translate(huge_vaddr).is_none());
+        assert!(umapper.utable.translate(mmio_vaddr).is_none());
+    });
+}
+
+/// 检查`pin_alive`返回的守卫：UserMapper被drop之后，各级页表仍然可以被遍历，PCID也没有被归还，
+/// 直到最后一个守卫被drop
+pub fn test_user_mapper_guard() {
+    let pcids = pcid_allocated();
+    let mut umapper = X86_64MMArch::setup_new_usermapper().expect("Failed to create user mapper");
+    let vaddr = VirtAddr::new(0x40_0000);
+    let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+    unsafe {
+        umapper
+            .utable
+            .map_phys(vaddr, paddr, PageFlags::new().set_user(true))
+            .unwrap()
+            .ignore_safe()
+    };
+    let has_pcid = umapper.pcid() != 0;
+
+    let guard = umapper.pin_alive();
+    let guard2 = umapper.pin_alive();
+    assert!(!guard.is_dead());
+    drop(umapper);
+    assert!(guard.is_dead());
+
+    // 页表都还没有被释放，通过守卫记录的顶层页表仍然能够找到原来的映射
+    let walker = unsafe {
+        PageMapper::new(
+            PageTableKind::User,
+            guard.table_paddr(),
+            LockedFrameAllocator,
+        )
+    };
+    assert_eq!(walker.translate(vaddr).map(|(p, _)| p), Some(paddr));
+    assert_eq!(pcid_allocated(), pcids + has_pcid as usize);
+
+    drop(guard2);
+    assert_eq!(walker.translate(vaddr).map(|(p, _)| p), Some(paddr));
+    // 最后一个守卫销毁页表（包括仍然被映射的页帧），并归还PCID
+    drop(guard);
+    assert_eq!(pcid_allocated(), pcids);
+}
+
+/// 检查`UserMapper::access_ok`对跨越用户/内核边界、以及包含未映射空洞的范围的处理
+pub fn test_user_access_ok() {
+    with_user_mapper(|umapper| {
+        let page = MMArch::PAGE_SIZE;
+        let rw = PageFlags::new().set_user(true).set_write(true);
+        let ro = PageFlags::new().set_user(true);
+        // 用户空间最高的一页（USER_END_VADDR没有按页对齐）
+        let user_top = VirtAddr::new(MMArch::USER_END_VADDR.data() & !MMArch::PAGE_OFFSET_MASK);
+        let user_tail = MMArch::USER_END_VADDR - user_top;
+        // [0x10000, 0x12000)可写，[0x12000, 0x13000)只读，[0x13000, 0x14000)未映射，[0x14000, 0x15000)可写
+        let layout = [
+            (VirtAddr::new(0x10000), rw),
+            (VirtAddr::new(0x11000), rw),
+            (VirtAddr::new(0x12000), ro),
+            (VirtAddr::new(0x14000), rw),
+            (user_top, rw),
+        ];
+        for (vaddr, flags) in layout.iter() {
+            let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+            unsafe { umapper.utable.map_phys(*vaddr, paddr, *flags) }
+                .expect("Failed to map user page")
+                .flush();
+        }
+
+        assert!(umapper.access_ok(VirtAddr::new(0x10000), 2 * page, true));
+        assert!(umapper.access_ok(VirtAddr::new(0x10800), page, true));
+        assert!(umapper.access_ok(VirtAddr::new(0x10000), 3 * page, false));
+        assert!(!umapper.access_ok(VirtAddr::new(0x10000), 3 * page, true));
+        // 未映射的空洞
+        assert!(!umapper.access_ok(VirtAddr::new(0x12000), 3 * page, false));
+        assert!(!umapper.access_ok(VirtAddr::new(0x13fff), 2, false));
+        // 跨越用户/内核边界
+        assert!(umapper.access_ok(user_top, user_tail, true));
+        assert!(!umapper.access_ok(user_top, page, false));
+        assert!(!umapper.access_ok(user_top - page, 2 * page, false));
+        assert!(!umapper.access_ok(MMArch::USER_END_VADDR, 1, false));
+        assert!(!umapper.access_ok(VirtAddr::new(X86_64MMArch::PHYS_OFFSET), page, false));
+        // 地址溢出
+        assert!(!umapper.access_ok(VirtAddr::new(0x10000), usize::MAX, false));
+    });
+}
+
+/// 检查写入只读页面时的三种情况：写时复制页面、程序自己设置的只读页面、共享零页
+pub fn test_write_fault() {
+    with_user_mapper(|umapper| {
+        let ro = PageFlags::<MMArch>::new().set_user(true);
+        let read_byte = |paddr: PhysAddr, offset: usize| unsafe {
+            MMArch::read::<u8>(MMArch::phys_2_virt(paddr).unwrap() + offset)
+        };
+
+        // 写时复制页面
+        let cow_vaddr = VirtAddr::new(0x40_0000);
+        let cow_paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        unsafe {
+            MMArch::write_bytes(
+                MMArch::phys_2_virt(cow_paddr).unwrap(),
+                0x5a,
+                MMArch::PAGE_SIZE,
+            );
+            umapper
+                .utable
+                .map_phys(cow_vaddr, cow_paddr, ro.set_cow(true))
+                .unwrap()
+                .ignore_safe();
+        }
+        assert_eq!(
+            umapper.handle_write_fault(cow_vaddr + 0x123),
+            Ok(WriteFaultOutcome::CowResolved)
+        );
+        let (paddr, flags) = umapper.utable.translate(cow_vaddr).unwrap();
+        assert_ne!(paddr, cow_paddr);
+        assert!(flags.has_write() && !flags.has_cow());
+        assert_eq!(read_byte(paddr, 0x123), 0x5a);
+        // 原页帧仍然属于与之共享的地址空间（这里由测试自己释放）
+        unsafe { LockedFrameAllocator.free_one(cow_paddr) };
+        assert_eq!(
+            umapper.handle_write_fault(cow_vaddr),
+            Ok(WriteFaultOutcome::Spurious)
+        );
+
+        // 程序自己设置的只读页面
+        let ro_vaddr = VirtAddr::new(0x40_1000);
+        let ro_paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        unsafe {
+            umapper
+                .utable
+                .map_phys(ro_vaddr, ro_paddr, ro)
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(
+            umapper.handle_write_fault(ro_vaddr),
+            Ok(WriteFaultOutcome::AccessViolation)
+        );
+        assert_eq!(umapper.utable.translate(ro_vaddr).unwrap().0, ro_paddr);
+
+        // 共享零页的第一次写入
+        let zero_vaddr = VirtAddr::new(0x40_2000);
+        let zero = zero_frame().expect("zero frame is not initialized");
+        unsafe {
+            umapper
+                .utable
+                .map_phys(zero_vaddr, zero, ro)
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(
+            umapper.handle_write_fault(zero_vaddr),
+            Ok(WriteFaultOutcome::ZeroPageReplaced)
+        );
+        let (paddr, flags) = umapper.utable.translate(zero_vaddr).unwrap();
+        assert_ne!(paddr, zero);
+        assert!(flags.has_write());
+        assert_eq!(read_byte(paddr, 0), 0);
+
+        assert_eq!(
+            umapper.handle_write_fault(VirtAddr::new(0x40_3000)),
+            Ok(WriteFaultOutcome::NotMapped)
+        );
+    });
+}
+
+/// 检查两个内容相同的页面能够被合并，并且之后写入其中一个页面时会触发写时复制
+pub fn test_ksm_merge() {
+    let as_a = AddressSpace::new(false).expect("Failed to create address space");
+    let as_b = AddressSpace::new(false).expect("Failed to create address space");
+    let rw = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+    let vaddr = VirtAddr::new(0x40_0000);
+    let filled_frame = |value: u8| {
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        unsafe {
+            MMArch::write_bytes(
+                MMArch::phys_2_virt(paddr).unwrap(),
+                value,
+                MMArch::PAGE_SIZE,
+            )
+        };
+        paddr
+    };
+    let map = |space: &Arc<AddressSpace>, vaddr: VirtAddr, paddr: PhysAddr| unsafe {
+        space
+            .write()
+            .user_mapper
+            .utable
+            .map_phys(vaddr, paddr, rw)
+            .expect("Failed to map user page")
+            .ignore_safe()
+    };
+    let translate = |space: &Arc<AddressSpace>, vaddr: VirtAddr| {
+        space.read().user_mapper.utable.translate(vaddr).unwrap()
+    };
+
+    let paddr_a = filled_frame(0x33);
+    let paddr_b = filled_frame(0x33);
+    let paddr_c = filled_frame(0x44);
+    map(&as_a, vaddr, paddr_a);
+    map(&as_b, vaddr, paddr_b);
+    map(&as_a, vaddr + MMArch::PAGE_SIZE, paddr_c);
+
+    // 内容不同的页面不会被合并，并且恢复原来的权限
+    assert_eq!(
+        try_merge(&as_a, vaddr + MMArch::PAGE_SIZE, &as_b, vaddr),
+        Ok(false)
+    );
+    assert!(translate(&as_a, vaddr + MMArch::PAGE_SIZE).1.has_write());
+    assert!(translate(&as_b, vaddr).1.has_write());
+
+    assert_eq!(try_merge(&as_a, vaddr, &as_b, vaddr), Ok(true));
+    for space in [&as_a, &as_b] {
+        let (paddr, flags) = translate(space, vaddr);
+        assert_eq!(paddr, paddr_a);
+        assert!(flags.has_cow() && !flags.has_write());
+    }
+    assert_eq!(ksm_frame_refcount(paddr_a), 2);
+    // 已经映射到同一个页帧
+    assert_eq!(try_merge(&as_b, vaddr, &as_a, vaddr), Ok(false));
+    assert_eq!(
+        try_merge(&as_a, vaddr + 1, &as_b, vaddr),
+        Err(SystemError::EINVAL)
+    );
+
+    // 写入其中一个页面时触发写时复制
+    assert_eq!(
+        as_b.write().user_mapper.handle_write_fault(vaddr),
+        Ok(WriteFaultOutcome::CowResolved)
+    );
+    let (paddr, flags) = translate(&as_b, vaddr);
+    assert_ne!(paddr, paddr_a);
+    assert!(flags.has_write());
+    assert_eq!(
+        unsafe { MMArch::read::<u8>(MMArch::phys_2_virt(paddr).unwrap()) },
+        0x33
+    );
+    assert_eq!(ksm_frame_refcount(paddr_a), 1);
+    assert_eq!(translate(&as_a, vaddr).0, paddr_a);
+
+    // 最后一个页面被取消映射时，KSM页帧被释放
+    as_a.write().user_mapper.clear_user_space();
+    as_b.write().user_mapper.clear_user_space();
+    assert_eq!(ksm_frame_refcount(paddr_a), 0);
+    drop(as_a);
+    drop(as_b);
+}
+
+/// 检查批量的引用计数操作与逐个页帧的操作结果相同
+pub fn test_ref_bulk() {
+    // 只修改引用计数表，不会访问或者释放页帧，因此使用不存在的物理地址
+    let frame = |base: usize, i: usize| PhysAddr::new(base + i * MMArch::PAGE_SIZE);
+    let (base_single, base_bulk) = (0x7fff_0000_0000, 0x7ffe_0000_0000);
+    let incs = [0, 0, 1, 2, 2, 2];
+    let decs = [0, 1, 1, 3, 2];
+
+    for i in incs {
+        inc_ref(frame(base_single, i));
+    }
+    let freed_single: Vec<usize> = decs
+        .iter()
+        .copied()
+        .filter(|i| dec_ref(frame(base_single, *i)))
+        .collect();
+
+    let frames: Vec<PhysAddr> = incs.iter().map(|i| frame(base_bulk, *i)).collect();
+    inc_ref_bulk(&frames);
+    let frames: Vec<PhysAddr> = decs.iter().map(|i| frame(base_bulk, *i)).collect();
+    let freed_bulk: Vec<usize> = dec_ref_bulk(&frames)
+        .iter()
+        .map(|p| (p.data() - base_bulk) / MMArch::PAGE_SIZE)
+        .collect();
+
+    // 页帧0：1 + 2 - 1 = 2；页帧1：1 + 1 - 2 = 0；页帧2：1 + 3 - 1 = 3；页帧3：1 - 1 = 0
+    assert_eq!(freed_single, [1, 3]);
+    assert_eq!(freed_bulk, freed_single);
+    for (i, expected) in [(0, 2), (1, 0), (2, 3), (3, 0)] {
+        assert_eq!(ksm_frame_refcount(frame(base_single, i)), expected);
+        assert_eq!(ksm_frame_refcount(frame(base_bulk, i)), expected);
+    }
+
+    // 清理：把剩下的引用计数降为0
+    let rest: Vec<PhysAddr> = [base_single, base_bulk]
+        .iter()
+        .flat_map(|base| [0, 0, 2, 2, 2].map(|i| frame(*base, i)))
+        .collect();
+    assert_eq!(dec_ref_bulk(&rest).len(), 4);
+    for i in 0..4 {
+        assert_eq!(ksm_frame_refcount(frame(base_single, i)), 0);
+        assert_eq!(ksm_frame_refcount(frame(base_bulk, i)), 0);
+    }
+}
+
+/// 检查槽位的分配与释放、换出页表项的编码，然后通过内存中的后端把一个用户页面换出再换入，检查内容不变
+pub fn test_swap() {
+    use crate::mm::swap::{
+        swap_entry_decode, swap_entry_encode, swap_off, swap_on, swap_usage, RamSwapBackend,
+        SwapSlot, SwapSpace,
+    };
+    const PAGE: usize = MMArch::PAGE_SIZE;
+
+    let mut space = SwapSpace::new(Arc::new(RamSwapBackend::new(3)));
+    for i in 0..3 {
+        assert_eq!(space.alloc_slot(), Ok(SwapSlot::new(i)));
+    }
+    assert_eq!(space.alloc_slot(), Err(SystemError::ENOSPC));
+    space.free_slot(SwapSlot::new(1)).unwrap();
+    assert_eq!(space.free_slot(SwapSlot::new(1)), Err(SystemError::EINVAL));
+    assert_eq!(space.free_slot(SwapSlot::new(3)), Err(SystemError::EINVAL));
+    assert_eq!(space.alloc_slot(), Ok(SwapSlot::new(1)));
+    assert_eq!(space.used(), 3);
+
+    let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+    let entry = swap_entry_encode(SwapSlot::new(5), flags);
+    assert!(!entry.present());
+    let (slot, decoded) = swap_entry_decode(entry).unwrap();
+    assert_eq!(slot, SwapSlot::new(5));
+    assert!(decoded.has_user() && decoded.has_write());
+    assert!(swap_entry_decode(PageEntry::new(0)).is_none());
+
+    if swap_on(Arc::new(RamSwapBackend::new(4))).is_err() {
+        kwarn!("test_swap: a swap space is already registered, skip the round trip");
+        return;
+    }
+    with_user_mapper(|umapper| {
+        let vaddr = VirtAddr::new(0x50_0000);
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        let fill = |paddr: PhysAddr| unsafe {
+            let p = MMArch::phys_2_virt(paddr).unwrap().as_ptr::<u64>();
+            for i in 0..PAGE / 8 {
+                p.add(i).write_volatile(0x5a5a_0000_0000_0000 | i as u64);
+            }
+        };
+        let check = |paddr: PhysAddr| unsafe {
+            let p = MMArch::phys_2_virt(paddr).unwrap().as_ptr::<u64>();
+            (0..PAGE / 8).all(|i| p.add(i).read_volatile() == 0x5a5a_0000_0000_0000 | i as u64)
+        };
+        fill(paddr);
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, paddr, flags)
+                .unwrap()
+                .ignore_safe()
+        };
+
+        assert_eq!(umapper.swap_in(vaddr), Err(SystemError::EFAULT));
+        let slot = umapper.swap_out(vaddr).unwrap();
+        assert_eq!(swap_usage(), Some((1, 4)));
+        assert!(umapper.utable.translate(vaddr).is_none());
+        assert_eq!(
+            swap_entry_decode(umapper.utable.raw_entry(vaddr).unwrap()).map(|(s, _)| s),
+            Some(slot)
+        );
+        assert_eq!(umapper.swap_out(vaddr), Err(SystemError::EFAULT));
+
+        umapper.swap_in(vaddr).unwrap();
+        assert_eq!(swap_usage(), Some((0, 4)));
+        let (new_paddr, new_flags) = umapper.utable.translate(vaddr).unwrap();
+        assert!(new_flags.has_user() && new_flags.has_write());
+        assert!(check(new_paddr));
+
+        // 共享零页不能被换出
+        let zero_vaddr = vaddr + PAGE;
+        let zero = crate::mm::ucontext::zero_frame().expect("zero frame is not initialized");
+        unsafe {
+            umapper
+                .utable
+                .map_phys(zero_vaddr, zero, PageFlags::new().set_user(true))
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(umapper.swap_out(zero_vaddr), Err(SystemError::EBUSY));
+        // 零页帧不能在清空用户空间时被释放
+        unsafe {
+            umapper
+                .utable
+                .unmap_phys(zero_vaddr, false)
+                .unwrap()
+                .2
+                .ignore_safe()
+        };
+
+        // 清空用户空间时，换出的页面所占用的槽位被释放
+        umapper.swap_out(vaddr).unwrap();
+        assert_eq!(swap_usage(), Some((1, 4)));
+        umapper.clear_user_space();
+        assert_eq!(swap_usage(), Some((0, 4)));
+    });
+
+    assert!(swap_off().is_ok());
+    assert_eq!(swap_usage(), None);
+}
+
+/// 检查页面迁移：私有页面被迁移到新的页帧并保留内容与权限；共享的、被固定的页帧，以及不存在的节点被拒绝
+pub fn test_migrate_page() {
+    with_user_mapper(|umapper| {
+        let vaddr = VirtAddr::new(0x60_0000);
+        let flags = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        unsafe {
+            MMArch::phys_2_virt(paddr)
+                .unwrap()
+                .as_ptr::<u64>()
+                .write_volatile(0x1234_5678)
+        };
+        unsafe {
+            umapper
+                .utable
+                .map_phys(vaddr, paddr, flags)
+                .unwrap()
+                .ignore_safe()
+        };
+
+        assert_eq!(
+            umapper.migrate_page(vaddr + 1usize, 0),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            umapper.migrate_page(vaddr, NUMA_NODES),
+            Err(SystemError::EINVAL)
+        );
+        assert_eq!(
+            umapper.migrate_page(vaddr + MMArch::PAGE_SIZE, 0),
+            Err(SystemError::EFAULT)
+        );
+
+        // 被固定的页帧
+        pin_frame(paddr);
+        assert_eq!(umapper.migrate_page(vaddr, 0), Err(SystemError::EBUSY));
+        unpin_frame(paddr);
+
+        // 被另一个页面共享的页帧
+        inc_ref(paddr);
+        assert_eq!(umapper.migrate_page(vaddr, 0), Err(SystemError::EBUSY));
+        assert!(!dec_ref(paddr));
+        assert_eq!(umapper.utable.translate(vaddr).unwrap().0, paddr);
+
+        umapper.migrate_page(vaddr, 0).unwrap();
+        let (new_paddr, new_flags) = umapper.utable.translate(vaddr).unwrap();
+        assert_ne!(new_paddr, paddr);
+        assert_eq!(frame_node(new_paddr), 0);
+        assert!(new_flags.has_user() && new_flags.has_write());
+        assert_eq!(
+            unsafe {
+                MMArch::phys_2_virt(new_paddr)
+                    .unwrap()
+                    .as_ptr::<u64>()
+                    .read_volatile()
+            },
+            0x1234_5678
+        );
+        // 原来的页帧只剩下这个页面的引用，迁移之后被释放
+        assert_eq!(ksm_frame_refcount(paddr), 0);
+
+        // 共享零页
+        let zero_vaddr = vaddr + MMArch::PAGE_SIZE;
+        let zero = zero_frame().expect("zero frame is not initialized");
+        unsafe {
+            umapper
+                .utable
+                .map_phys(zero_vaddr, zero, PageFlags::new().set_user(true))
+                .unwrap()
+                .ignore_safe()
+        };
+        assert_eq!(umapper.migrate_page(zero_vaddr, 0), Err(SystemError::EBUSY));
+        unsafe {
+            umapper
+                .utable
+                .unmap_phys(zero_vaddr, false)
+                .unwrap()
+                .2
+                .ignore_safe()
+        };
+    });
+}
+
+/// 检查`clone_user_mapping`以写时复制的方式共享可写页面，以及共享页帧的引用计数
+pub fn test_clone_user_mapping() {
+    let mut parent = X86_64MMArch::setup_new_usermapper().expect("Failed to create user mapper");
+    let rw = PageFlags::<MMArch>::new().set_user(true).set_write(true);
+    let ro = PageFlags::<MMArch>::new().set_user(true);
+    let rw_vaddr = VirtAddr::new(0x40_0000);
+    let ro_vaddr = VirtAddr::new(0x40_1000);
+    let read_byte = |paddr: PhysAddr| unsafe {
+        MMArch::read::<u8>(MMArch::phys_2_virt(paddr).unwrap() + 0x123)
+    };
+
+    let mut frames = [PhysAddr::new(0); 2];
+    for (i, (vaddr, flags)) in [(rw_vaddr, rw), (ro_vaddr, ro)].iter().enumerate() {
+        let paddr = unsafe { LockedFrameAllocator.allocate_one() }.unwrap();
+        unsafe {
+            MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0x5a, MMArch::PAGE_SIZE);
+            parent
+                .utable
+                .map_phys(*vaddr, paddr, *flags)
+                .unwrap()
+                .ignore_safe();
+        }
+        frames[i] = paddr;
+    }
+
+    let mut child = UserMapper::clone_user_mapping(&parent).expect("Failed to clone user mapping");
+    for mapper in [&parent, &child] {
+        // 可写的页面在两边都变为写时复制，只读的页面直接共享
+        let (paddr, flags) = mapper.utable.translate(rw_vaddr).unwrap();
+        assert_eq!(paddr, frames[0]);
+        assert!(!flags.has_write() && flags.has_cow());
+        let (paddr, flags) = mapper.utable.translate(ro_vaddr).unwrap();
+        assert_eq!(paddr, frames[1]);
+        assert!(!flags.has_write() && !flags.has_cow());
+    }
+    assert_eq!(ksm_frame_refcount(frames[0]), 2);
+    assert_eq!(ksm_frame_refcount(frames[1]), 2);
+
+    // 子进程写入：复制到新的页帧，父进程仍然映射原来的页帧
+    assert_eq!(
+        child.handle_write_fault(rw_vaddr),
+        Ok(WriteFaultOutcome::CowResolved)
+    );
+    let (paddr, flags) = child.utable.translate(rw_vaddr).unwrap();
+    assert_ne!(paddr, frames[0]);
+    assert!(flags.has_write() && !flags.has_cow());
+    assert_eq!(read_byte(paddr), 0x5a);
+    assert_eq!(parent.utable.translate(rw_vaddr).unwrap().0, frames[0]);
+    assert_eq!(ksm_frame_refcount(frames[0]), 1);
+    assert_eq!(
+        child.handle_write_fault(ro_vaddr),
+        Ok(WriteFaultOutcome::AccessViolation)
+    );
+
+    // 父进程写入：原页帧的引用计数降为0，被释放
+    assert_eq!(
+        parent.handle_write_fault(rw_vaddr),
+        Ok(WriteFaultOutcome::CowResolved)
+    );
+    assert_eq!(ksm_frame_refcount(frames[0]), 0);
+
+    // 只读的页帧在最后一个映射它的地址空间被清空时释放
+    assert_eq!(child.clear_user_space().data(), 2);
+    assert_eq!(ksm_frame_refcount(frames[1]), 1);
+    assert_eq!(parent.clear_user_space().data(), 2);
+    assert_eq!(ksm_frame_refcount(frames[1]), 0);
+    drop(child);
+    drop(parent);
+}
+
+/// 检查`MAP_HUGETLB`的匿名映射：按2M对齐的完整2M范围使用大页池中的大页，末尾不足2M的部分使用4K页面，
+/// 以及取消映射时整块释放大页
+pub fn test_huge_mmap() {
+    use crate::mm::allocator::huge_pool::HUGE_PAGE_FRAMES;
+    use crate::mm::allocator::page_frame::VirtPageFrame;
+    use crate::mm::page::InactiveFlusher;
+    use crate::mm::ucontext::VMA;
+
+    let old_size = huge_pool_size();
+    set_huge_pool_size(1);
+    huge_pool_refill(1);
+    if huge_pool_stats().pooled == 0 {
+        kwarn!("test_huge_mmap: not enough memory to refill the pool, skipped");
+        set_huge_pool_size(old_size);
+        return;
+    }
+    let hits = huge_pool_stats().hits;
+
+    with_user_mapper(|umapper| {
+        let start = VirtAddr::new(0x4000_0000);
+        let tail = start + HUGE_PAGE_SIZE;
+        let count = PageFrameCount::new(HUGE_PAGE_FRAMES.data() + 1);
+        let flags = PageFlags::new().set_user(true).set_write(true);
+        let vma = VMA::zeroed_huge(
+            VirtPageFrame::new(start),
+            count,
+            flags,
+            &mut umapper.utable,
+            InactiveFlusher::new(),
+        )
+        .expect("test_huge_mmap: map failed");
+        assert_eq!(huge_pool_stats().hits, hits + 1);
+
+        let (level, _) = umapper.utable.leaf_entry(start).unwrap();
+        assert_eq!(level, PageSize::Size2M.level());
+        let (base, _) = umapper.utable.translate(start).unwrap();
+        assert!(base.check_aligned(HUGE_PAGE_SIZE));
+        assert_eq!(
+            umapper
+                .utable
+                .translate(start + MMArch::PAGE_SIZE)
+                .unwrap()
+                .0,
+            base + MMArch::PAGE_SIZE
+        );
+        let (level, _) = umapper.utable.leaf_entry(tail).unwrap();
+        assert_eq!(level, PageSize::Size4K.level());
+        let (tail_paddr, _) = umapper.utable.translate(tail).unwrap();
+        let p = unsafe { MMArch::phys_2_virt(tail_paddr) }
+            .unwrap()
+            .as_ptr::<u64>();
+        assert!((0..MMArch::PAGE_SIZE / 8).all(|i| unsafe { p.add(i).read_volatile() } == 0));
+
+        vma.unmap(&mut umapper.utable, InactiveFlusher::new());
+        assert!(umapper.utable.translate(start).is_none());
+        assert!(umapper.utable.translate(tail).is_none());
+    });
+    set_huge_pool_size(old_size);
+}
+
+/// 检查用户栈的保护页在映射时被登记：按地址空间区分，扩展栈时被替换，fork时被复制，销毁地址空间时被取消登记
+pub fn test_user_stack_guard() {
+    use crate::mm::stack_guard::{classify_user_stack_fault, user_stack_guard};
+    const PAGE: usize = MMArch::PAGE_SIZE;
+
+    let space = AddressSpace::new(true).expect("Failed to create address space");
+    let mut guard = space.write();
+    let table = guard.user_mapper.utable.table().phys();
+    let g = user_stack_guard(table).expect("user stack guard is not registered");
+    assert_eq!(g.guard, g.stack - PAGE..g.stack);
+    assert_eq!(g.table, Some(table));
+    assert!(guard.user_mapper.utable.translate(g.stack).is_some());
+    assert!(guard.user_mapper.utable.translate(g.guard.start).is_none());
+    assert_eq!(
+        classify_user_stack_fault(table, g.guard.start + 8).map(|hit| hit.stack),
+        Some(g.stack)
+    );
+    assert_eq!(classify_user_stack_fault(table, g.stack), None);
+    // 用户地址不会被当作内核栈的保护页
+    assert_eq!(classify_stack_fault(g.guard.start + 8), None);
+
+    // 扩展栈之后，保护页移动到新页面的下方
+    let mut stack = guard.user_stack.take().unwrap();
+    stack
+        .extend(&mut guard, 2 * PAGE)
+        .expect("Failed to extend user stack");
+    guard.user_stack = Some(stack);
+    let extended = user_stack_guard(table).unwrap();
+    assert_eq!(extended.stack, g.stack - 2 * PAGE);
+    assert_eq!(classify_user_stack_fault(table, g.guard.start + 8), None);
+
+    // 新的地址空间有自己的登记
+    let child = guard.try_clone().expect("Failed to clone address space");
+    let child_table = child.read().user_mapper.utable.table().phys();
+    assert_eq!(
+        user_stack_guard(child_table).map(|c| c.guard),
+        Some(extended.guard.clone())
+    );
+    drop(guard);
+
+    drop(child);
+    assert_eq!(user_stack_guard(child_table), None);
+    drop(space);
+    assert_eq!(user_stack_guard(table), None);
+}
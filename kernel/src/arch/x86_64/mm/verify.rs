@@ -0,0 +1,218 @@
+//! 内核页表的完整性检查
+//!
+//! 页表被破坏时（比如野指针写入了页表所在的页帧），往往要等到访问对应的地址时才会出错，而且出错的位置与破坏发生的位置相距很远。
+//! `verify_kernel_page_tables`主动遍历内核部分的页表，检查：
+//!
+//! - 页表项中没有设置保留位（MAXPHYADDR以上的地址位、未开启NX时的XD位、PML4中的PS位、大页中的地址低位）
+//! - 指向下一级页表的页表项，以及直接映射区域中的页面，都指向RAM中的页帧
+//! - 没有同时可写、可执行的映射（W^X）
+//! - 直接映射区域覆盖了所有的RAM，并且每个页帧都被映射到它所对应的虚拟地址
+//!
+//! 并报告发现的第一个不一致之处。MMIO、固定映射等区域中的页面可以指向任意的物理地址，只检查它们的保留位。
+
+use core::{ops::Range, sync::atomic::Ordering};
+
+use crate::{
+    arch::MMArch,
+    mm::{
+        kernel_mapper::KernelMapper, page::PageTable, MemoryManagementArch, PhysAddr,
+        PhysMemoryArea, VirtAddr,
+    },
+};
+
+use super::{mem_encrypt::mem_encrypt_mask, X86_64MMArch, DIRECT_MAP_TOP};
+
+/// 页表项在页表树中的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageTableLocation {
+    /// 页表项所在的页表的物理地址
+    pub table: PhysAddr,
+    /// 页表的层级（0为最后一级）
+    pub level: usize,
+    /// 页表项在页表中的下标
+    pub index: usize,
+    /// 页表项所映射的虚拟地址范围的起始地址
+    pub vaddr: VirtAddr,
+    /// 页表项的值
+    pub entry: usize,
+}
+
+/// 页表的完整性检查发现的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageTableError {
+    /// 页表项中设置了保留位
+    ReservedBits(PageTableLocation),
+    /// 页表项指向的物理地址不在RAM中（下一级页表，或者直接映射区域中的页面）
+    AddressOutOfRam(PageTableLocation),
+    /// 同时可写、可执行的映射
+    WxViolation { vaddr: VirtAddr, size: usize },
+    /// RAM中的页帧在直接映射区域中没有被映射
+    DirectMapHole(PhysAddr),
+    /// RAM中的页帧在直接映射区域中所对应的虚拟地址，被映射到了另一个物理地址
+    DirectMapMismatch { paddr: PhysAddr, mapped: PhysAddr },
+}
+
+/// 检查一张页表（以及它的所有下一级页表）中，位于range范围内的页表项
+///
+/// 指向下一级页表的页表项在通过检查之后，才会访问下一级页表，因此被破坏的页表项不会导致访问任意的物理地址
+///
+/// ## 参数
+///
+/// - `table`：页表
+/// - `range`：检查的虚拟地址范围（规范地址）
+/// - `ram`：RAM所在的物理内存区域
+///
+/// ## 返回值
+///
+/// 按照地址从小到大的顺序，发现的第一个错误
+pub fn verify_page_table(
+    table: &PageTable<MMArch>,
+    range: Range<VirtAddr>,
+    ram: &[PhysMemoryArea],
+) -> Result<(), PageTableError> {
+    let linear = |v: VirtAddr| v.data() & !MMArch::PAGE_NEGATIVE_MASK;
+    let (lo, hi) = (linear(range.start), linear(range.end));
+    let direct_map = MMArch::PHYS_OFFSET..DIRECT_MAP_TOP.load(Ordering::SeqCst);
+    return unsafe { verify_table_inner(table, lo, hi, ram, &direct_map) };
+}
+
+unsafe fn verify_table_inner(
+    table: &PageTable<MMArch>,
+    lo: usize,
+    hi: usize,
+    ram: &[PhysMemoryArea],
+    direct_map: &Range<usize>,
+) -> Result<(), PageTableError> {
+    let level = table.level();
+    let size = MMArch::PAGE_SIZE << (level * MMArch::PAGE_ENTRY_SHIFT);
+    for i in 0..MMArch::PAGE_ENTRY_NUM {
+        let base = match table.entry_base(i) {
+            Some(base) => base.data(),
+            None => continue,
+        };
+        if base + size <= lo || base >= hi {
+            continue;
+        }
+        let entry = match table.entry(i) {
+            Some(entry) if entry.present() => entry.data(),
+            _ => continue,
+        };
+        let location = PageTableLocation {
+            table: table.phys(),
+            level,
+            index: i,
+            vaddr: canonical(base),
+            entry,
+        };
+
+        let huge = level > 0
+            && level < MMArch::PAGE_LEVELS - 1
+            && entry & MMArch::ENTRY_FLAG_HUGE_PAGE != 0;
+        if entry & reserved_mask(level, entry) != 0 {
+            return Err(PageTableError::ReservedBits(location));
+        }
+
+        let mut paddr = entry & MMArch::ENTRY_ADDRESS_MASK & !mem_encrypt_mask();
+        if level == 0 || huge {
+            if huge {
+                // 大页的PAT位位于地址字段的最低位
+                paddr &= !(size - 1);
+            }
+            let vaddr = location.vaddr.data();
+            if direct_map.contains(&vaddr) && !in_ram(PhysAddr::new(paddr), ram) {
+                return Err(PageTableError::AddressOutOfRam(location));
+            }
+        } else {
+            if !in_ram(PhysAddr::new(paddr), ram) {
+                return Err(PageTableError::AddressOutOfRam(location));
+            }
+            if let Some(next) = table.next_level_table(i) {
+                verify_table_inner(&next, lo, hi, ram, direct_map)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// 层级为level的页表项中的保留位
+fn reserved_mask(level: usize, entry: usize) -> usize {
+    let phys_bits = X86_64MMArch::phys_address_bits();
+    // MAXPHYADDR到第51位之间的地址位（内存加密的C位除外）
+    let mut mask = if phys_bits < MMArch::ENTRY_ADDRESS_SHIFT {
+        (MMArch::ENTRY_ADDRESS_SIZE - 1) & !((1 << phys_bits) - 1)
+    } else {
+        0
+    };
+    mask &= !mem_encrypt_mask();
+    if X86_64MMArch::is_xd_reserved() {
+        mask |= MMArch::ENTRY_FLAG_NO_EXEC;
+    }
+
+    let huge = entry & MMArch::ENTRY_FLAG_HUGE_PAGE != 0;
+    match level {
+        // PML4的页表项不能映射大页
+        3 => mask |= MMArch::ENTRY_FLAG_HUGE_PAGE,
+        // 1G的大页：第13-29位是保留位（第12位是PAT位）
+        2 if huge => {
+            if !X86_64MMArch::huge_page_support().supports_1g {
+                mask |= MMArch::ENTRY_FLAG_HUGE_PAGE;
+            }
+            mask |= ((1 << 30) - 1) & !((1 << 13) - 1);
+        }
+        // 2M的大页：第13-20位是保留位
+        1 if huge => mask |= ((1 << 21) - 1) & !((1 << 13) - 1),
+        _ => {}
+    }
+    return mask;
+}
+
+/// 判断物理页帧是否位于RAM中
+fn in_ram(paddr: PhysAddr, ram: &[PhysMemoryArea]) -> bool {
+    return ram
+        .iter()
+        .any(|area| paddr >= area.base && paddr.data() < area.base.data() + area.size);
+}
+
+/// 把线性地址转换为规范地址（对高半部分的地址进行符号扩展）
+fn canonical(linear: usize) -> VirtAddr {
+    if linear & (MMArch::PAGE_ADDRESS_SIZE >> 1) != 0 {
+        return VirtAddr::new(linear | MMArch::PAGE_NEGATIVE_MASK);
+    }
+    return VirtAddr::new(linear);
+}
+
+/// 检查内核页表的完整性（参见模块的文档）
+///
+/// 遍历所有的RAM，开销较大，适合在可疑的操作之后，或者在调试时按需调用
+///
+/// ## 返回值
+///
+/// 发现的第一个不一致之处
+pub fn verify_kernel_page_tables() -> Result<(), PageTableError> {
+    let ram = X86_64MMArch::phys_memory_areas();
+    let kernel_half = VirtAddr::new(MMArch::PHYS_OFFSET)..VirtAddr::new(usize::MAX);
+    let mapper = KernelMapper::lock();
+
+    verify_page_table(&mapper.table(), kernel_half.clone(), ram)?;
+
+    if let Some(&(vaddr, size)) = mapper.find_wx(kernel_half).first() {
+        return Err(PageTableError::WxViolation { vaddr, size });
+    }
+
+    for area in ram.iter() {
+        let mut paddr = area.base;
+        while paddr.data() < area.base.data() + area.size {
+            let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+            match mapper.translate(vaddr) {
+                None => return Err(PageTableError::DirectMapHole(paddr)),
+                // 忽略内存加密的C位
+                Some((mapped, _)) if mapped.data() & !mem_encrypt_mask() != paddr.data() => {
+                    return Err(PageTableError::DirectMapMismatch { paddr, mapped });
+                }
+                _ => {}
+            }
+            paddr += MMArch::PAGE_SIZE;
+        }
+    }
+    return Ok(());
+}
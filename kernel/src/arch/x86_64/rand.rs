@@ -1,5 +1,113 @@
-use core::arch::x86_64::_rdtsc;
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use x86::cpuid::CpuId;
 
 pub fn rand() -> usize {
     return unsafe { (_rdtsc() * _rdtsc() + 998244353_u64 * _rdtsc()) as usize };
 }
+
+/// RDRAND指令的检测状态：尚未检测
+const RDRAND_UNKNOWN: u8 = 0;
+/// RDRAND指令的检测状态：可用
+const RDRAND_AVAILABLE: u8 = 1;
+/// RDRAND指令的检测状态：不可用
+const RDRAND_UNAVAILABLE: u8 = 2;
+
+/// 当前CPU是否支持RDRAND指令（通过CPUID检测，结果会被缓存）
+static RDRAND_STATE: AtomicU8 = AtomicU8::new(RDRAND_UNKNOWN);
+
+/// 软件伪随机数生成器的状态（为0表示尚未使用rdtsc初始化种子）
+static SOFT_RNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// RDRAND指令在熵不足时可能会暂时失败，最多重试的次数
+const RDRAND_RETRY: usize = 10;
+
+/// 判断当前CPU是否支持RDRAND指令
+pub fn rdrand_available() -> bool {
+    match RDRAND_STATE.load(Ordering::Relaxed) {
+        RDRAND_AVAILABLE => return true,
+        RDRAND_UNAVAILABLE => return false,
+        _ => {}
+    }
+
+    let available = CpuId::new()
+        .get_feature_info()
+        .map(|info| info.has_rdrand())
+        .unwrap_or(false);
+    RDRAND_STATE.store(
+        if available {
+            RDRAND_AVAILABLE
+        } else {
+            RDRAND_UNAVAILABLE
+        },
+        Ordering::Relaxed,
+    );
+    return available;
+}
+
+/// 禁用RDRAND指令，使`random_u64`总是使用软件伪随机数生成器（用于测试回退的路径）
+///
+/// 为false时，重新通过CPUID检测RDRAND是否可用
+pub fn rdrand_set_disabled(disabled: bool) {
+    RDRAND_STATE.store(
+        if disabled {
+            RDRAND_UNAVAILABLE
+        } else {
+            RDRAND_UNKNOWN
+        },
+        Ordering::Relaxed,
+    );
+}
+
+/// 获取一个64位的随机数
+///
+/// 如果CPU支持RDRAND指令，则使用RDRAND获取随机数。
+/// 否则（或者RDRAND多次重试后仍然失败），回退到以rdtsc为种子的软件伪随机数生成器。
+///
+/// 请注意，本函数的结果不具备密码学安全性，仅可用于测试等场景。
+pub fn random_u64() -> u64 {
+    if rdrand_available() {
+        for _ in 0..RDRAND_RETRY {
+            let mut value = 0u64;
+            if unsafe { x86::random::rdrand64(&mut value) } {
+                return value;
+            }
+        }
+    }
+
+    return soft_random_u64();
+}
+
+/// 设置软件伪随机数生成器的种子，使之后的序列可以重现。为0时，在下一次使用时重新通过rdtsc获取种子
+pub fn soft_random_seed(seed: u64) {
+    SOFT_RNG_STATE.store(seed, Ordering::Relaxed);
+}
+
+/// 使用软件伪随机数生成器（xorshift64*）获取一个64位的随机数
+///
+/// 种子在第一次调用时，通过rdtsc获取。
+pub fn soft_random_u64() -> u64 {
+    let mut current = SOFT_RNG_STATE.load(Ordering::Relaxed);
+    loop {
+        let seed = if current == 0 {
+            // xorshift的状态不能为0
+            (unsafe { _rdtsc() }) | 1
+        } else {
+            current
+        };
+
+        let mut x = seed;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+
+        match SOFT_RNG_STATE.compare_exchange_weak(current, x, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => return x.wrapping_mul(0x2545_f491_4f6c_dd1d),
+            Err(v) => current = v,
+        }
+    }
+}
@@ -206,7 +206,7 @@ fn tmp_rs_execve(
     // kdebug!("Switch to new address space");
 
     // 切换到新的用户地址空间
-    unsafe { address_space.read().user_mapper.utable.make_current() };
+    unsafe { address_space.read().user_mapper.make_current() };
 
     drop(old_address_space);
     drop(irq_guard);
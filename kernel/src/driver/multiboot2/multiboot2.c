@@ -49,7 +49,7 @@ void multiboot2_iter(bool (*_fun)(const struct iter_data_t *, void *, unsigned i
  *
  * @param _iter_data 要被迭代的信息的结构体
  * @param _data 返回信息的结构体指针
- * @param count 返回数组的长度
+ * @param count 传入时为_data数组的容量（为0则表示不限制），返回时为内存区域的总数（可能大于容量，此时超出容量的部分不会被写入_data）
  * @return true
  * @return false
  */
@@ -60,13 +60,18 @@ bool multiboot2_get_memory(const struct iter_data_t *_iter_data, void *data, uns
 
     struct multiboot_mmap_entry_t *resource = (struct multiboot_mmap_entry_t *)data;
     struct multiboot_mmap_entry_t *mmap = ((struct multiboot_tag_mmap_t *)_iter_data)->entries;
+    unsigned int capacity = *count;
     *count = 0;
     for (; (uint8_t *)mmap < (uint8_t *)_iter_data + _iter_data->size;
          mmap = (struct multiboot_mmap_entry_t *)((uint8_t *)mmap + ((struct multiboot_tag_mmap_t *)_iter_data)->entry_size))
     {
-        *resource = *mmap;
-        // 将指针进行增加
-        resource = (struct multiboot_mmap_entry_t *)((uint8_t *)resource + ((struct multiboot_tag_mmap_t *)_iter_data)->entry_size);
+        // 超出容量的部分只计数，不写入，由调用者检测截断
+        if (capacity == 0 || *count < capacity)
+        {
+            *resource = *mmap;
+            // 将指针进行增加
+            resource = (struct multiboot_mmap_entry_t *)((uint8_t *)resource + ((struct multiboot_tag_mmap_t *)_iter_data)->entry_size);
+        }
         ++(*count);
     }
     return true;
@@ -133,4 +138,67 @@ bool multiboot2_get_acpi_new_RSDP(const struct iter_data_t *_iter_data, void *da
         return false;
     *(struct multiboot_tag_new_acpi_t *)data = *(struct multiboot_tag_new_acpi_t *)_iter_data;
     return true;
-}
\ No newline at end of file
+}
+/**
+ * @brief 获取所有模块的信息
+ *
+ * @param _iter_data 要被迭代的信息的结构体
+ * @param data 返回信息的缓冲区（struct multiboot2_module_list_t）
+ * @param count 传入时为0，返回时为模块的总数（可能大于容量，此时超出容量的部分不会被写入缓冲区）
+ * @return false 总是继续迭代，以便访问所有的模块标签
+ */
+bool multiboot2_get_modules(const struct iter_data_t *_iter_data, void *data, unsigned int *count)
+{
+    if (_iter_data->type != MULTIBOOT_TAG_TYPE_MODULE)
+        return false;
+
+    struct multiboot2_module_list_t *list = (struct multiboot2_module_list_t *)data;
+    const struct multiboot_tag_module_t *tag = (const struct multiboot_tag_module_t *)_iter_data;
+    // 超出容量的部分只计数，不写入，由调用者检测截断
+    if (*count < list->capacity)
+    {
+        struct multiboot2_module_info_t *info = &list->modules[*count];
+        info->mod_start = tag->mod_start;
+        info->mod_end = tag->mod_end;
+        // 命令行以'\0'结尾，但不能超出标签的范围
+        const char *end = (const char *)_iter_data + _iter_data->size;
+        unsigned int i = 0;
+        while (i + 1 < MULTIBOOT2_MODULE_CMDLINE_MAX && tag->cmdline + i < end && tag->cmdline[i] != '\0')
+        {
+            info->cmdline[i] = tag->cmdline[i];
+            ++i;
+        }
+        info->cmdline[i] = '\0';
+        info->cmdline_len = i;
+    }
+    ++(*count);
+    return false;
+}
+
+/**
+ * @brief 获取内核的命令行参数
+ *
+ * @param _iter_data 要被迭代的信息的结构体
+ * @param data 返回命令行参数的缓冲区（char数组，总是以'\0'结尾）
+ * @param count 传入时为缓冲区的容量，返回时为复制的字节数（不包括'\0'）
+ * @return true
+ * @return false
+ */
+bool multiboot2_get_cmdline(const struct iter_data_t *_iter_data, void *data, unsigned int *count)
+{
+    if (_iter_data->type != MULTIBOOT_TAG_TYPE_CMDLINE)
+        return false;
+    if (*count == 0)
+        return true;
+    const char *src = ((struct multiboot_tag_string_t *)_iter_data)->string;
+    char *dst = (char *)data;
+    unsigned int i = 0;
+    while (i + 1 < *count && src[i] != '\0')
+    {
+        dst[i] = src[i];
+        ++i;
+    }
+    dst[i] = '\0';
+    *count = i;
+    return true;
+}
@@ -431,7 +431,7 @@ void multiboot2_iter(bool (*_fun)(const struct iter_data_t *, void *, unsigned i
  *
  * @param _iter_data 要被迭代的信息的结构体
  * @param _data 返回信息的结构体指针
- * @param count 返回数组的长度
+ * @param count 传入时为_data数组的容量（为0则表示不限制），返回时为内存区域的总数（可能大于容量，此时超出容量的部分不会被写入_data）
  * @return true
  * @return false
  */
@@ -471,4 +471,48 @@ bool multiboot2_get_acpi_old_RSDP(const struct iter_data_t *_iter_data, void *da
  * @param reserved
  * @return uint8_t*  struct multiboot_tag_old_acpi_t
  */
-bool multiboot2_get_acpi_new_RSDP(const struct iter_data_t *_iter_data, void *data, unsigned int *reserved);
\ No newline at end of file
+bool multiboot2_get_acpi_new_RSDP(const struct iter_data_t *_iter_data, void *data, unsigned int *reserved);
+
+// 模块命令行的最大长度（包括'\0'），超出的部分会被截断
+#define MULTIBOOT2_MODULE_CMDLINE_MAX 64
+
+/**
+ * @brief 一个multiboot2模块的信息（从模块标签中复制）
+ */
+struct multiboot2_module_info_t
+{
+    unsigned int mod_start;
+    unsigned int mod_end;
+    // 命令行的长度（不包括'\0'）
+    unsigned int cmdline_len;
+    char cmdline[MULTIBOOT2_MODULE_CMDLINE_MAX];
+};
+
+/**
+ * @brief multiboot2_get_modules使用的缓冲区
+ */
+struct multiboot2_module_list_t
+{
+    // modules数组的容量
+    unsigned int capacity;
+    struct multiboot2_module_info_t *modules;
+};
+
+/**
+ * @brief 获取所有模块的信息
+ *
+ * @param _iter_data 要被迭代的信息的结构体
+ * @param _data 返回信息的缓冲区（struct multiboot2_module_list_t）
+ * @param count 传入时为0，返回时为模块的总数（可能大于容量，此时超出容量的部分不会被写入缓冲区）
+ * @return false 总是继续迭代，以便访问所有的模块标签
+ */
+bool multiboot2_get_modules(const struct iter_data_t *_iter_data, void *_data, unsigned int *count);
+
+/**
+ * @brief 获取内核的命令行参数
+ *
+ * @param _iter_data 要被迭代的信息的结构体
+ * @param _data 返回命令行参数的缓冲区（char数组，总是以'\0'结尾）
+ * @param count 传入时为缓冲区的容量，返回时为复制的字节数（不包括'\0'）
+ */
+bool multiboot2_get_cmdline(const struct iter_data_t *_iter_data, void *_data, unsigned int *count);
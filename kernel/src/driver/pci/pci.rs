@@ -1436,7 +1436,6 @@ pub fn pci_bar_init(
                 let paddr = PhysAddr::new(address as usize);
                 let page_flags = PageFlags::new()
                     .set_write(true)
-                    .set_execute(true)
                     .set_page_cache_disable(true)
                     .set_page_write_through(true);
                 kdebug!("Pci bar init: vaddr={vaddr:?}, paddr={paddr:?}, size_want={size_want}, page_flags={page_flags:?}");
@@ -14,6 +14,7 @@
 #include <time/timer.h>
 
 extern void rs_register_softirq_video();
+extern void rs_mm_reserve_framebuffer(uint64_t paddr, uint64_t size);
 
 uint64_t video_refresh_expire_jiffies = 0;
 uint64_t video_last_refresh_pid = -1;
@@ -37,6 +38,7 @@ void init_frame_buffer()
     video_frame_buffer_info.vaddr = SPECIAL_MEMOEY_MAPPING_VIRT_ADDR_BASE + FRAME_BUFFER_MAPPING_OFFSET;
 
     rs_map_phys(video_frame_buffer_info.vaddr, __fb_info.framebuffer_addr, video_frame_buffer_info.size, PAGE_KERNEL_PAGE | PAGE_PWT | PAGE_PCD);
+    rs_mm_reserve_framebuffer(__fb_info.framebuffer_addr, video_frame_buffer_info.size);
 
     kinfo("VBE frame buffer successfully Re-mapped!");
 }
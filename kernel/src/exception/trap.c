@@ -7,6 +7,13 @@
 #include <sched/sched.h>
 
 extern void ignore_int();
+extern bool rs_mm_is_direct_map_guard(uint64_t vaddr);
+extern void rs_mm_report_nearest_mappings(uint64_t vaddr);
+extern void rs_mm_report_fault_error_code(uint64_t error_code);
+extern void rs_mm_check_stack_overflow(uint64_t vaddr, uint64_t rsp);
+extern void rs_mm_check_user_stack_overflow(uint64_t vaddr, uint64_t rsp);
+extern int rs_handle_user_write_fault(uint64_t vaddr);
+extern void rs_emergency_refill();
 
 // 0 #DE 除法错误
 void do_divide_error(struct pt_regs *regs, unsigned long error_code)
@@ -218,31 +225,31 @@ void do_page_fault(struct pt_regs *regs, unsigned long error_code)
 
     __asm__ __volatile__("movq	%%cr2,	%0" : "=r"(cr2)::"memory");
 
-    kerror("do_page_fault(14),Error code :%#018lx,RSP:%#018lx, RBP=%#018lx, RIP:%#018lx CPU:%d, pid=%d\n", error_code,
-           regs->rsp, regs->rbp, regs->rip, proc_current_cpu_id, current_pcb->pid);
-    kerror("regs->rax = %#018lx\n", regs->rax);
-    if (!(error_code & 0x01))
-        printk_color(RED, BLACK, "Page Not-Present,\t");
-
-    if (error_code & 0x02)
-        printk_color(RED, BLACK, "Write Cause Fault,\t");
-    else
-        printk_color(RED, BLACK, "Read Cause Fault,\t");
+    // 写入已映射的只读用户页面（包括内核态写入用户缓冲区）：可能是写时复制页面或者共享零页，处理成功之后直接返回
+    if ((error_code & 0x03) == 0x03 && rs_handle_user_write_fault(cr2) == 0)
+    {
+        // 处理时可能用掉了紧急页帧池中的页帧。只有被中断的是用户态时，才能安全地获取全局页帧分配器的锁
+        if (error_code & 0x04)
+            rs_emergency_refill();
+        return;
+    }
 
-    if (error_code & 0x04)
-        printk_color(RED, BLACK, "Fault in user(3)\t");
+    // 内核态访问了内核栈的保护页：报告栈溢出（不会返回）
+    if (!(error_code & 0x04))
+        rs_mm_check_stack_overflow(cr2, regs->rsp);
     else
-        printk_color(RED, BLACK, "Fault in supervisor(0,1,2)\t");
-
-    if (error_code & 0x08)
-        printk_color(RED, BLACK, ",Reserved Bit Cause Fault\t");
-
-    if (error_code & 0x10)
-        printk_color(RED, BLACK, ",Instruction fetch Cause Fault");
+        rs_mm_check_user_stack_overflow(cr2, regs->rsp);
 
-    printk_color(RED, BLACK, "\n");
+    kerror("do_page_fault(14),Error code :%#018lx,RSP:%#018lx, RBP=%#018lx, RIP:%#018lx CPU:%d, pid=%d\n", error_code,
+           regs->rsp, regs->rbp, regs->rip, proc_current_cpu_id, current_pcb->pid);
+    kerror("regs->rax = %#018lx\n", regs->rax);
+    rs_mm_report_fault_error_code(error_code);
 
     printk_color(RED, BLACK, "CR2:%#018lx\n", cr2);
+    if (rs_mm_is_direct_map_guard(cr2))
+        printk_color(RED, BLACK, "Direct-map overrun: CR2 is in the guard region of the kernel direct map\n");
+    if (!(error_code & 0x01))
+        rs_mm_report_nearest_mappings(cr2);
 
     traceback(regs);
     process_do_exit(-1);
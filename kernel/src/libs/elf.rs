@@ -11,13 +11,14 @@ use crate::{
     arch::MMArch,
     current_pcb,
     io::SeekFrom,
-    kerror,
+    kerror, kwarn,
     libs::align::page_align_up,
     mm::{
-        allocator::page_frame::{PageFrameCount, VirtPageFrame},
+        allocator::page_frame::{FrameAllocator, PageFrameCount, VirtPageFrame},
+        page::{FlushBatch, Flusher, PageFlags, PageMapper},
         syscall::{MapFlags, ProtFlags},
         ucontext::InnerAddressSpace,
-        MemoryManagementArch, VirtAddr,
+        MemoryManagementArch, PhysAddr, VirtAddr,
     },
     process::{
         abi::AtType,
@@ -489,6 +490,7 @@ impl BinaryLoader for ElfLoader {
         // kdebug!("ehdr = {:?}", ehdr);
 
         let binding = param.vm().clone();
+        // 持有写锁期间写入的用户页面必须是可写的私有页面，参见`rs_handle_user_write_fault`
         let mut user_vm = binding.write();
 
         // todo: 增加对user stack上的内存是否具有可执行权限的处理（方法：寻找phdr里面的PT_GNU_STACK段）
@@ -712,6 +714,115 @@ impl BinaryLoader for ElfLoader {
     }
 }
 
+/// 根据ELF程序头的p_flags生成用户页面的页表项标志
+///
+/// 同时请求可写和可执行的段会产生一条警告。如果能够实施W^X（`can_enforce_wx`），这样的段被映射为可写但不可执行
+pub fn elf_segment_page_flags(elf_flags: u32) -> PageFlags<MMArch> {
+    let write = elf_flags & elf::abi::PF_W != 0;
+    let mut execute = elf_flags & elf::abi::PF_X != 0;
+    if write && execute {
+        if MMArch::can_enforce_wx() {
+            kwarn!("ELF segment requests both write and execute, mapping it as non-executable");
+            execute = false;
+        } else {
+            kwarn!("ELF segment requests both write and execute, W^X cannot be enforced");
+        }
+    }
+    return PageFlags::new()
+        .set_user(true)
+        .set_write(write)
+        .set_execute(execute);
+}
+
+/// 把一个ELF段映射到用户页表中
+///
+/// 段的文件内容已经被放在从`paddr`开始的连续物理内存中，`paddr`与`vaddr`在页内的偏移必须相同
+/// （ELF要求p_vaddr与p_offset模页大小同余）。段不需要从页的起始处开始：包含段的所有页面都会被映射，
+/// 文件内容所在的页面映射到`paddr`所在的页帧，超出文件大小的部分（BSS）由新分配的、已经清零的页帧填充。
+/// 最后一个文件页面中位于文件内容之后的部分会被清零。
+///
+/// ## 参数
+///
+/// - `mapper`：用户页表的映射器
+/// - `vaddr`：段的起始虚拟地址（p_vaddr）
+/// - `paddr`：段的文件内容的起始物理地址
+/// - `file_len`：段在文件中的大小（p_filesz）
+/// - `mem_len`：段在内存中的大小（p_memsz）
+/// - `elf_flags`：段的标志（p_flags），参见`elf_segment_page_flags`
+///
+/// ## 返回值
+///
+/// - `Ok((range, flusher))`：被映射的页面的地址范围（按页对齐），以及需要提交的刷新器
+/// - `EINVAL`：`file_len`大于`mem_len`，`paddr`与`vaddr`的页内偏移不同，或者地址不是规范地址
+/// - `ENOMEM`：无法分配页帧（已经建立的映射会被撤销，分配的页帧会被释放）
+/// - 其他：`map_phys`失败时，由`MmError`转换而来的错误（同样会撤销已经建立的映射）
+pub unsafe fn map_elf_segment<F: FrameAllocator>(
+    mapper: &mut PageMapper<MMArch, F>,
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    file_len: usize,
+    mem_len: usize,
+    elf_flags: u32,
+) -> Result<(Range<VirtAddr>, FlushBatch<MMArch>), SystemError> {
+    let offset = vaddr.data() & (MMArch::PAGE_SIZE - 1);
+    if file_len > mem_len || paddr.data() & (MMArch::PAGE_SIZE - 1) != offset {
+        return Err(SystemError::EINVAL);
+    }
+
+    let start = vaddr - offset;
+    let file_pages = page_align_up(offset + file_len) / MMArch::PAGE_SIZE;
+    let total_pages = page_align_up(offset + mem_len) / MMArch::PAGE_SIZE;
+    let phys_start = paddr - offset;
+    let flags = elf_segment_page_flags(elf_flags);
+
+    // BSS的起始部分与文件内容共用最后一个文件页面，需要清零
+    let file_end = offset + file_len;
+    if mem_len > file_len && file_end & (MMArch::PAGE_SIZE - 1) != 0 {
+        let tail = page_align_up(file_end) - file_end;
+        MMArch::write_bytes(MMArch::phys_2_virt(phys_start + file_end).unwrap(), 0, tail);
+    }
+
+    let mut flusher = FlushBatch::new();
+    for i in 0..total_pages {
+        let virt = start + i * MMArch::PAGE_SIZE;
+        let r = if i < file_pages {
+            mapper
+                .map_phys(virt, phys_start + i * MMArch::PAGE_SIZE, flags)
+                .map_err(SystemError::from)
+        } else {
+            match mapper.allocator_mut().allocate_one() {
+                Some(frame) => {
+                    MMArch::write_bytes(MMArch::phys_2_virt(frame).unwrap(), 0, MMArch::PAGE_SIZE);
+                    mapper.map_phys(virt, frame, flags).map_err(|e| {
+                        mapper.allocator_mut().free_one(frame);
+                        SystemError::from(e)
+                    })
+                }
+                None => Err(SystemError::ENOMEM),
+            }
+        };
+        match r {
+            Ok(flush) => flusher.consume(flush),
+            Err(e) => {
+                // 撤销已经建立的映射。BSS的页帧是由这里分配的，需要一起释放
+                for j in 0..i {
+                    let virt = start + j * MMArch::PAGE_SIZE;
+                    if j < file_pages {
+                        if let Ok((_, _, flush)) = mapper.unmap_phys(virt, true) {
+                            flusher.consume(flush);
+                        }
+                    } else if let Some(flush) = mapper.unmap(virt, true) {
+                        flusher.consume(flush);
+                    }
+                }
+                flusher.commit();
+                return Err(e);
+            }
+        }
+    }
+    return Ok((start..start + total_pages * MMArch::PAGE_SIZE, flusher));
+}
+
 /// Elf机器架构，对应于e_machine字段。在ABI中，以EM_开头的常量是e_machine字段的值。
 #[derive(Debug, Eq, PartialEq)]
 pub enum ElfMachine {
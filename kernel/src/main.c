@@ -38,6 +38,7 @@
 extern int rs_tty_init();
 extern void rs_softirq_init();
 extern void rs_mm_init();
+extern void rs_mm_idle_work();
 
 ul bsp_idt_size, bsp_gdt_size;
 
@@ -224,6 +225,7 @@ void Start_Kernel(void)
         if (get_rflags() & 0x200)
         {
             // kdebug("hlt");
+            rs_mm_idle_work();
             hlt();
         }
         else
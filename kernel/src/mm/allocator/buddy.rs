@@ -5,6 +5,7 @@
 /// @Description: 伙伴分配器
 use crate::arch::MMArch;
 use crate::mm::allocator::bump::BumpAllocator;
+use crate::mm::allocator::free_check::{free_check_alloc, free_check_free};
 use crate::mm::allocator::page_frame::{FrameAllocator, PageFrameCount, PageFrameUsage};
 use crate::mm::{MemoryManagementArch, PhysAddr, VirtAddr};
 use crate::{kdebug, kwarn};
@@ -18,6 +19,14 @@ use core::{marker::PhantomData, mem};
 const MAX_ORDER: usize = 31;
 // 4KB
 const MIN_ORDER: usize = 12;
+/// 伙伴分配器一次能够分配的最大的连续内存（字节），更大的连续分配需要使用CMA
+pub const BUDDY_MAX_BLOCK_SIZE: usize = 1 << (MAX_ORDER - 1);
+
+/// 页着色所使用的颜色数量（必须是2的幂）
+///
+/// 物理页帧的颜色由其页帧号的低位决定，颜色相同的页帧会映射到相同的缓存组。
+/// 这里的取值对应于常见的 L2 缓存（1MB，16路，4K页）的配置
+pub const PAGE_COLORS: usize = 16;
 
 /// 保存buddy算法中每一页存放的BuddyEntry的信息，占据每个页的起始位置
 #[derive(Debug)]
@@ -65,6 +74,10 @@ impl<A> PageList<A> {
 pub struct BuddyAllocator<A> {
     // 存放每个阶的空闲“链表”的头部地址
     free_area: [PhysAddr; (MAX_ORDER - MIN_ORDER) as usize],
+    /// 交由伙伴系统管理的总页数
+    total: PageFrameCount,
+    /// 已经分配出去的页数
+    used: PageFrameCount,
     phantom: PhantomData<A>,
 }
 
@@ -74,6 +87,8 @@ impl<A: MemoryManagementArch> BuddyAllocator<A> {
         (A::PAGE_SIZE - mem::size_of::<PageList<A>>()) / mem::size_of::<PhysAddr>();
 
     pub unsafe fn new(mut bump_allocator: BumpAllocator<A>) -> Option<Self> {
+        // bump分配器剩余的页帧都会交给伙伴分配器，之后不能再回退
+        bump_allocator.hand_off();
         let initial_free_pages = bump_allocator.usage().free();
         kdebug!("Free pages before init buddy: {:?}", initial_free_pages);
         kdebug!("Buddy entries: {}", Self::BUDDY_ENTRIES);
@@ -230,6 +245,8 @@ impl<A: MemoryManagementArch> BuddyAllocator<A> {
         // Self::print_free_area(free_area);
         let allocator = Self {
             free_area,
+            total: pages_to_buddy,
+            used: PageFrameCount::new(0),
             phantom: PhantomData,
         };
 
@@ -428,6 +445,314 @@ impl<A: MemoryManagementArch> BuddyAllocator<A> {
             .map(|addr| (addr, PageFrameCount::new(1 << (order as usize - MIN_ORDER))));
     }
 
+    /// 获取物理页帧的颜色
+    #[inline]
+    pub fn page_color(paddr: PhysAddr) -> usize {
+        return (paddr.data() >> A::PAGE_SHIFT) & (PAGE_COLORS - 1);
+    }
+
+    /// 在order阶的空闲链表中，查找并取出第一个满足条件的伙伴块
+    ///
+    /// ## 参数
+    ///
+    /// - `order` - 伙伴块的阶数
+    /// - `pred` - 判断伙伴块是否满足条件的函数
+    ///
+    /// ## 返回值
+    ///
+    /// 找到时，返回伙伴块的起始地址，否则返回None
+    fn take_matching(&mut self, order: u8, pred: impl Fn(PhysAddr) -> bool) -> Option<PhysAddr> {
+        let mut found = None;
+        let mut page_list_paddr = self.free_area[Self::order2index(order)];
+        'outer: loop {
+            let page_list: PageList<A> = Self::read_page(page_list_paddr);
+            for i in 0..page_list.entry_num {
+                let entry_virt_addr = Self::entry_virt_addr(page_list_paddr, i);
+                let entry: PhysAddr = unsafe { A::read(entry_virt_addr) };
+                if pred(entry) {
+                    found = Some((entry, entry_virt_addr));
+                    break 'outer;
+                }
+            }
+            if page_list.next_page.is_null() {
+                break;
+            }
+            page_list_paddr = page_list.next_page;
+        }
+        let (entry, entry_virt_addr) = found?;
+
+        // 找到第一个有空闲块的链表页，用它的最后一个表项填补被取出的表项
+        let mut first_paddr = self.free_area[Self::order2index(order)];
+        let mut first: PageList<A> = Self::read_page(first_paddr);
+        while first.entry_num == 0 {
+            first_paddr = first.next_page;
+            assert!(!first_paddr.is_null());
+            first = Self::read_page(first_paddr);
+        }
+        let last_virt_addr = Self::entry_virt_addr(first_paddr, first.entry_num - 1);
+        unsafe {
+            if last_virt_addr != entry_virt_addr {
+                let last: PhysAddr = A::read(last_virt_addr);
+                A::write(entry_virt_addr, last);
+            }
+            A::write(last_virt_addr, PhysAddr::new(0));
+        }
+        first.entry_num -= 1;
+        Self::write_page(first_paddr, first);
+        return Some(entry);
+    }
+
+    /// 从伙伴系统中分配count个页面，并尽量使得第一个页面的颜色为color
+    ///
+    /// 着色分配是可选的：它会在空闲链表中查找颜色匹配的块，因此比普通的分配更慢，
+    /// 并且可能会增加内存碎片。如果找不到颜色匹配的块，则退化为普通的分配。
+    ///
+    /// ## 参数
+    ///
+    /// - `count`：需要分配的页面数（必须是2的幂）
+    /// - `color`：期望的颜色，范围为[0, PAGE_COLORS)
+    ///
+    /// ## 返回值
+    ///
+    /// 返回分配的页面的物理地址和页面数
+    pub fn allocate_colored(
+        &mut self,
+        count: PageFrameCount,
+        color: usize,
+    ) -> Option<(PhysAddr, PageFrameCount)> {
+        assert!(count.data().is_power_of_two());
+        let color = color & (PAGE_COLORS - 1);
+        let order = (log2(count.data()) + MIN_ORDER) as u8;
+        if order as usize >= MAX_ORDER {
+            return None;
+        }
+
+        // 块的起始地址总是按块的大小对齐，因此只有与count对齐的颜色才可能被满足
+        if color % min(count.data(), PAGE_COLORS) == 0 {
+            if let Some(paddr) = self.allocate_colored_inner(order, color) {
+                self.used += count;
+                free_check_alloc(paddr, count);
+                return Some((paddr, count));
+            }
+        }
+
+        // 找不到颜色匹配的块，退化为普通的分配
+        return unsafe { self.allocate(count) };
+    }
+
+    fn allocate_colored_inner(&mut self, order: u8, color: usize) -> Option<PhysAddr> {
+        // 先尝试在order阶的空闲链表中查找颜色匹配的块
+        if let Some(paddr) = self.take_matching(order, |entry| Self::page_color(entry) == color) {
+            return Some(paddr);
+        }
+
+        // 在更高阶的空闲链表中，查找包含目标颜色的块，然后进行分裂
+        for high in (order as usize + 1)..MAX_ORDER {
+            let block_pages = 1usize << (high - MIN_ORDER);
+            let contains_color = |entry: PhysAddr| {
+                let offset = color.wrapping_sub(Self::page_color(entry)) & (PAGE_COLORS - 1);
+                return offset < block_pages;
+            };
+            let block = if block_pages >= PAGE_COLORS {
+                // 块中包含了所有的颜色
+                self.pop_front(high as u8)
+            } else {
+                self.take_matching(high as u8, contains_color)
+            };
+            let block = match block {
+                Some(block) => block,
+                None => continue,
+            };
+
+            // 目标颜色与count对齐，块的起始颜色与块的大小对齐，因此目标一定位于块内，且按order对齐
+            let offset = color.wrapping_sub(Self::page_color(block)) & (PAGE_COLORS - 1);
+            let target = block + offset * A::PAGE_SIZE;
+
+            // 把块中不包含目标的那一半，逐级放回空闲链表
+            let mut base = block;
+            let mut current_order = high;
+            while current_order > order as usize {
+                current_order -= 1;
+                let half = 1usize << current_order;
+                if target.data() >= base.data() + half {
+                    unsafe { self.buddy_free(base, current_order as u8) };
+                    base = base + half;
+                } else {
+                    unsafe { self.buddy_free(base + half, current_order as u8) };
+                }
+            }
+            debug_assert!(base == target);
+            return Some(target);
+        }
+        return None;
+    }
+
+    /// 把count个不属于伙伴分配器的页帧，以“已分配”的状态交给伙伴分配器管理
+    ///
+    /// 调用之后，这些页帧可以像普通的已分配页帧一样，通过`free`归还给伙伴分配器。
+    /// 用于在伙伴分配器初始化之后，接管启动阶段被保留的内存（比如initrd）
+    pub fn adopt_reserved(&mut self, count: PageFrameCount) {
+        self.total += count;
+        self.used += count;
+    }
+
+    /// 把一个空闲的页帧从空闲链表中取出（之后它被统计为已使用），它所在的空闲块的其余部分被逐级放回空闲链表
+    ///
+    /// 需要遍历空闲链表，开销较大，只用于隔离出现内存错误的页帧等罕见的情况
+    ///
+    /// ## 返回值
+    ///
+    /// 如果页帧位于某个空闲块中，返回true。如果页帧已被分配，或者不由伙伴分配器管理，返回false
+    pub fn take_frame(&mut self, target: PhysAddr) -> bool {
+        let target = PhysAddr::new(target.data() & !(A::PAGE_SIZE - 1));
+        for order in MIN_ORDER..MAX_ORDER {
+            let block = PhysAddr::new(target.data() & !((1 << order) - 1));
+            if self
+                .take_matching(order as u8, |entry| entry == block)
+                .is_none()
+            {
+                continue;
+            }
+
+            // 把块中不包含目标的那一半，逐级放回空闲链表
+            let mut base = block;
+            let mut current_order = order;
+            while current_order > MIN_ORDER {
+                current_order -= 1;
+                let half = 1usize << current_order;
+                if target.data() >= base.data() + half {
+                    unsafe { self.buddy_free(base, current_order as u8) };
+                    base = base + half;
+                } else {
+                    unsafe { self.buddy_free(base + half, current_order as u8) };
+                }
+            }
+            debug_assert!(base == target);
+            self.used += 1;
+            free_check_alloc(target, PageFrameCount::new(1));
+            return true;
+        }
+        return false;
+    }
+
+    /// 遍历所有的空闲链表，检查伙伴分配器的不变量。如果不变量被破坏，会panic
+    ///
+    /// 检查的内容：
+    /// - 每个空闲块都按照其阶数对齐，并且链表中没有空的表项
+    /// - 同一阶中，不存在互为伙伴的两个空闲块（它们应当已经被合并）
+    /// - 空闲块的页数之和与`total - used`一致。由于链表页本身可能是从伙伴系统中分配的（不计入`used`），
+    ///   因此允许两者相差不超过链表页的数量
+    ///
+    /// 这个检查的开销很大（每一阶都是O(n^2)），只应当在调试伙伴分配器时使用（见`buddy_verify` feature）。
+    /// 检查过程中不会分配内存
+    pub fn verify_invariants(&self) {
+        let mut free_pages = 0usize;
+        let mut list_pages = 0usize;
+
+        for order in MIN_ORDER..MAX_ORDER {
+            let mut page_list_paddr = self.free_area[Self::order2index(order as u8)];
+            while !page_list_paddr.is_null() {
+                list_pages += 1;
+                let page_list: PageList<A> = Self::read_page(page_list_paddr);
+                assert!(
+                    page_list.entry_num <= Self::BUDDY_ENTRIES,
+                    "buddy verify: order {} list page {:?} has {} entries",
+                    order,
+                    page_list_paddr,
+                    page_list.entry_num
+                );
+                for i in 0..page_list.entry_num {
+                    let entry: PhysAddr =
+                        unsafe { A::read(Self::entry_virt_addr(page_list_paddr, i)) };
+                    assert!(
+                        !entry.is_null(),
+                        "buddy verify: order {} list page {:?} entry {} is null",
+                        order,
+                        page_list_paddr,
+                        i
+                    );
+                    assert!(
+                        entry.check_aligned(1 << order),
+                        "buddy verify: free block {:?} is not aligned to order {}",
+                        entry,
+                        order
+                    );
+                    if order != MAX_ORDER - 1 {
+                        let buddy = PhysAddr::new(entry.data() ^ (1 << order));
+                        assert!(
+                            !self.free_list_contains(order, buddy),
+                            "buddy verify: free blocks {:?} and {:?} of order {} are buddies but not merged",
+                            entry,
+                            buddy,
+                            order
+                        );
+                    }
+                    free_pages += 1 << (order - MIN_ORDER);
+                }
+                page_list_paddr = page_list.next_page;
+            }
+        }
+
+        let expected = self.total.data() - self.used.data();
+        assert!(
+            free_pages <= expected && expected - free_pages <= list_pages,
+            "buddy verify: free lists hold {} pages, but total - used = {} ({} list pages)",
+            free_pages,
+            expected,
+            list_pages
+        );
+    }
+
+    /// 判断页帧是否空闲（位于某一阶的空闲链表中的某个块内）
+    ///
+    /// 需要遍历所有的空闲链表，开销较大，仅用于调试和测试
+    pub fn is_free(&self, paddr: PhysAddr) -> bool {
+        for order in MIN_ORDER..MAX_ORDER {
+            let block = PhysAddr::new(paddr.data() & !((1 << order) - 1));
+            if self.free_list_contains(order, block) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    /// 遍历所有空闲链表中的块（不会分配内存）
+    ///
+    /// ## 参数
+    ///
+    /// - `f`：对每个空闲块调用，参数为块的起始地址和页数
+    pub fn for_each_free_block(&self, mut f: impl FnMut(PhysAddr, PageFrameCount)) {
+        for order in MIN_ORDER..MAX_ORDER {
+            let count = PageFrameCount::new(1 << (order - MIN_ORDER));
+            let mut page_list_paddr = self.free_area[Self::order2index(order as u8)];
+            while !page_list_paddr.is_null() {
+                let page_list: PageList<A> = Self::read_page(page_list_paddr);
+                for i in 0..page_list.entry_num {
+                    let entry: PhysAddr =
+                        unsafe { A::read(Self::entry_virt_addr(page_list_paddr, i)) };
+                    f(entry, count);
+                }
+                page_list_paddr = page_list.next_page;
+            }
+        }
+    }
+
+    /// 判断order阶的空闲链表中是否包含指定的块
+    fn free_list_contains(&self, order: usize, paddr: PhysAddr) -> bool {
+        let mut page_list_paddr = self.free_area[Self::order2index(order as u8)];
+        while !page_list_paddr.is_null() {
+            let page_list: PageList<A> = Self::read_page(page_list_paddr);
+            for i in 0..page_list.entry_num {
+                let entry: PhysAddr = unsafe { A::read(Self::entry_virt_addr(page_list_paddr, i)) };
+                if entry == paddr {
+                    return true;
+                }
+            }
+            page_list_paddr = page_list.next_page;
+        }
+        return false;
+    }
+
     /// 释放一个块
     ///
     /// ## 参数
@@ -634,7 +959,18 @@ impl<A: MemoryManagementArch> BuddyAllocator<A> {
 
 impl<A: MemoryManagementArch> FrameAllocator for BuddyAllocator<A> {
     unsafe fn allocate(&mut self, count: PageFrameCount) -> Option<(PhysAddr, PageFrameCount)> {
-        return self.buddy_alloc(count);
+        let r = self.buddy_alloc(count);
+        if let Some((paddr, allocated)) = r {
+            debug_assert!(
+                paddr.data() % Self::alignment_of(count) == 0,
+                "buddy allocate: {:?} is not aligned to {:#x}",
+                paddr,
+                Self::alignment_of(count)
+            );
+            self.used += allocated;
+            free_check_alloc(paddr, allocated);
+        }
+        return r;
     }
 
     /// 释放一个块
@@ -657,12 +993,21 @@ impl<A: MemoryManagementArch> FrameAllocator for BuddyAllocator<A> {
             order += 1;
         }
         let order = (order + MIN_ORDER) as u8;
+        let freed = 1usize << (order as usize - MIN_ORDER);
+        // 在修改空闲链表之前检查，避免重复释放破坏伙伴分配器
+        free_check_free(base, PageFrameCount::new(freed));
         // kdebug!("free: base={:?}, count={:?}", base, count);
         self.buddy_free(base, order);
+        self.used = PageFrameCount::new(self.used.data().saturating_sub(freed));
     }
 
     unsafe fn usage(&self) -> PageFrameUsage {
-        todo!("BuddyAllocator::usage")
+        return PageFrameUsage::new(self.used, self.total);
+    }
+
+    /// 伙伴分配器分配的块总是按照块的大小自然对齐
+    fn alignment_of(count: PageFrameCount) -> usize {
+        return count.data().max(1).next_power_of_two() * A::PAGE_SIZE;
     }
 }
 
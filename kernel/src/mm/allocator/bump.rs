@@ -6,12 +6,27 @@ use super::page_frame::{FrameAllocator, PageFrameCount, PageFrameUsage};
 use crate::mm::{MemoryManagementArch, PhysAddr, PhysMemoryArea};
 use core::marker::PhantomData;
 
+/// 线性分配器的分配位置的记录，由`BumpAllocator::checkpoint`返回，用于`BumpAllocator::rewind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermark {
+    offset: usize,
+}
+
+impl Watermark {
+    /// @brief 获取记录时的偏移量
+    pub fn offset(&self) -> usize {
+        return self.offset;
+    }
+}
+
 /// 线性分配器
 pub struct BumpAllocator<MMA> {
     // 表示可用物理内存区域的数组。每个 PhysMemoryArea 结构体描述一个物理内存区域的起始地址和大小。
     areas: &'static [PhysMemoryArea],
     // 表示当前分配的物理内存的偏移量.
     offset: usize,
+    // 这个偏移量之前的页帧已经被交给了其他的分配器（或者在创建分配器之前就已经被使用），不能被回退
+    handed_off: usize,
     // 一个占位类型，用于标记 A 类型在结构体中的存在。但是，它并不会占用任何内存空间，因为它的大小为 0。
     phantom: PhantomData<MMA>,
 }
@@ -26,6 +41,7 @@ impl<MMA: MemoryManagementArch> BumpAllocator<MMA> {
         Self {
             areas,
             offset,
+            handed_off: offset,
             phantom: PhantomData,
         }
     }
@@ -37,6 +53,45 @@ impl<MMA: MemoryManagementArch> BumpAllocator<MMA> {
     pub fn offset(&self) -> usize {
         return self.offset;
     }
+
+    /// @brief 记录当前的分配位置
+    ///
+    /// 之后的分配如果只是临时使用的（比如只在建立直接映射区域的过程中使用的页表），
+    /// 可以在不再需要它们的时候，通过`rewind`回退到这个位置，使这些页帧可以被再次分配（最终交给伙伴分配器）
+    pub fn checkpoint(&self) -> Watermark {
+        return Watermark {
+            offset: self.offset,
+        };
+    }
+
+    /// @brief 回退到`checkpoint`记录的分配位置，释放在那之后分配的所有页帧
+    ///
+    /// 调用者需要保证这些页帧已经不再被使用
+    ///
+    /// ## Panic
+    ///
+    /// - 分配位置已经在记录之前（比如已经回退过一次更早的记录）
+    /// - 记录之后分配的页帧已经通过`hand_off`交给了其他的分配器
+    pub fn rewind(&mut self, mark: Watermark) {
+        assert!(
+            mark.offset <= self.offset,
+            "BumpAllocator::rewind: watermark {:#x} is ahead of the current offset {:#x}",
+            mark.offset,
+            self.offset
+        );
+        assert!(
+            mark.offset >= self.handed_off,
+            "BumpAllocator::rewind: watermark {:#x} is before {:#x}, frames up to there were already handed off",
+            mark.offset,
+            self.handed_off
+        );
+        self.offset = mark.offset;
+    }
+
+    /// @brief 标记当前位置之前分配的页帧已经被交给了其他的分配器（比如CMA、伙伴分配器），之后不能再回退到这之前
+    pub fn hand_off(&mut self) {
+        self.handed_off = self.offset;
+    }
 }
 
 impl<MMA: MemoryManagementArch> FrameAllocator for BumpAllocator<MMA> {
@@ -0,0 +1,220 @@
+//! 连续内存分配器（CMA）
+//!
+//! 伙伴分配器一次最多只能分配一个最大阶的块（`BUDDY_MAX_BLOCK_SIZE`），并且在内存碎片化之后，
+//! 即使是较小的连续分配也可能失败。对于很大的DMA缓冲区，启动时通过`cma=`参数预留一段连续的物理内存，
+//! 这段内存不交给伙伴分配器，而是由一个简单的位图管理，只用于大块的连续分配（`cma_alloc`/`cma_free`）。
+//!
+//! 初始化分为两步：`allocator_init`在创建伙伴分配器之前，从bump分配器中取出一段连续的页帧并调用`cma_reserve`；
+//! 堆可用之后，`cma_init`为这段内存创建位图。在此之前（以及没有预留CMA区域时），`cma_alloc`返回ENOMEM。
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::MMArch,
+    kinfo,
+    libs::spinlock::SpinLock,
+    mm::{MemoryManagementArch, PhysAddr},
+    syscall::SystemError,
+};
+
+use super::page_frame::PageFrameCount;
+
+/// 由位图管理的一段连续的物理内存
+pub struct CmaArea {
+    base: PhysAddr,
+    count: PageFrameCount,
+    /// 每一位对应一个页帧，为1表示已被分配
+    bitmap: Vec<u64>,
+    /// 已分配的页帧数量
+    used: usize,
+}
+
+impl CmaArea {
+    /// 为一段连续的物理内存创建位图（所有页帧都是空闲的）
+    ///
+    /// ## 参数
+    ///
+    /// - `base`：起始物理地址（必须按页对齐）
+    /// - `count`：页帧的数量
+    pub fn new(base: PhysAddr, count: PageFrameCount) -> Self {
+        assert!(base.check_aligned(MMArch::PAGE_SIZE));
+        let mut bitmap = Vec::new();
+        bitmap.resize((count.data() + 63) / 64, 0);
+        return Self {
+            base,
+            count,
+            bitmap,
+            used: 0,
+        };
+    }
+
+    /// 区域的起始物理地址
+    pub fn base(&self) -> PhysAddr {
+        return self.base;
+    }
+
+    /// 区域中页帧的总数
+    pub fn count(&self) -> PageFrameCount {
+        return self.count;
+    }
+
+    /// 已分配的页帧数量
+    pub fn used(&self) -> PageFrameCount {
+        return PageFrameCount::new(self.used);
+    }
+
+    fn test(&self, index: usize) -> bool {
+        return self.bitmap[index / 64] & (1 << (index % 64)) != 0;
+    }
+
+    fn set_range(&mut self, start: usize, count: usize, value: bool) {
+        for index in start..start + count {
+            if value {
+                self.bitmap[index / 64] |= 1 << (index % 64);
+            } else {
+                self.bitmap[index / 64] &= !(1 << (index % 64));
+            }
+        }
+    }
+
+    /// 从区域中分配一段连续的物理内存（首次适应）
+    ///
+    /// ## 参数
+    ///
+    /// - `bytes`：大小（向上取整到页的大小）
+    /// - `align`：起始物理地址的对齐要求（必须是2的幂，小于页的大小时按页对齐）
+    ///
+    /// ## 返回值
+    ///
+    /// - `EINVAL`：bytes为0，或者align不是2的幂
+    /// - `ENOMEM`：区域中没有足够大的连续空闲内存
+    pub fn alloc(&mut self, bytes: usize, align: usize) -> Result<PhysAddr, SystemError> {
+        if bytes == 0 || !align.is_power_of_two() {
+            return Err(SystemError::EINVAL);
+        }
+        let count = (bytes + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE;
+        let align = core::cmp::max(align, MMArch::PAGE_SIZE);
+        let total = self.count.data();
+        let base = self.base.data();
+
+        // 从下标from开始，第一个满足对齐要求的页帧的下标
+        let first_aligned = |from: usize| -> usize {
+            let paddr = base + from * MMArch::PAGE_SIZE;
+            let aligned = (paddr + align - 1) & !(align - 1);
+            return (aligned - base) / MMArch::PAGE_SIZE;
+        };
+
+        let mut start = first_aligned(0);
+        while start + count <= total {
+            // 从后往前检查，遇到已分配的页帧时，直接跳到它之后的下一个对齐的位置
+            match (start..start + count).rev().find(|&i| self.test(i)) {
+                Some(busy) => start = first_aligned(busy + 1),
+                None => {
+                    self.set_range(start, count, true);
+                    self.used += count;
+                    return Ok(self.base + start * MMArch::PAGE_SIZE);
+                }
+            }
+        }
+        return Err(SystemError::ENOMEM);
+    }
+
+    /// 释放`alloc`分配的内存
+    ///
+    /// ## 参数
+    ///
+    /// - `paddr`：`alloc`返回的物理地址
+    /// - `bytes`：分配时的大小
+    ///
+    /// ## 返回值
+    ///
+    /// - `EINVAL`：这段内存不在区域中，或者其中有页帧没有被分配
+    pub fn free(&mut self, paddr: PhysAddr, bytes: usize) -> Result<(), SystemError> {
+        let count = (bytes + MMArch::PAGE_SIZE - 1) / MMArch::PAGE_SIZE;
+        if count == 0
+            || paddr < self.base
+            || !paddr.check_aligned(MMArch::PAGE_SIZE)
+            || (paddr.data() - self.base.data()) / MMArch::PAGE_SIZE + count > self.count.data()
+        {
+            return Err(SystemError::EINVAL);
+        }
+        let start = (paddr.data() - self.base.data()) / MMArch::PAGE_SIZE;
+        if !(start..start + count).all(|i| self.test(i)) {
+            return Err(SystemError::EINVAL);
+        }
+        self.set_range(start, count, false);
+        self.used -= count;
+        return Ok(());
+    }
+}
+
+/// 在`allocator_init`中预留的区域（起始物理地址，页帧数量），等待`cma_init`创建位图
+static CMA_RESERVED: SpinLock<Option<(PhysAddr, PageFrameCount)>> = SpinLock::new(None);
+/// 全局的CMA区域
+static CMA: SpinLock<Option<CmaArea>> = SpinLock::new(None);
+
+/// 记录启动时预留的CMA区域（这段内存不能交给伙伴分配器）
+///
+/// 在动态内存分配可用之前调用，只能调用一次
+pub fn cma_reserve(base: PhysAddr, count: PageFrameCount) {
+    let mut reserved = CMA_RESERVED.lock_irqsave();
+    assert!(reserved.is_none(), "cma_reserve: CMA area already reserved");
+    *reserved = Some((base, count));
+}
+
+/// 为预留的CMA区域创建位图，之后`cma_alloc`才可以使用（需要在堆可用之后调用）
+pub fn cma_init() {
+    let (base, count) = match CMA_RESERVED.lock_irqsave().take() {
+        Some(reserved) => reserved,
+        None => return,
+    };
+    let area = CmaArea::new(base, count);
+    *CMA.lock_irqsave() = Some(area);
+    kinfo!(
+        "CMA: {} MB reserved at {:?}",
+        count.bytes() / 1024 / 1024,
+        base
+    );
+}
+
+/// 从CMA区域中分配一段物理上连续的内存，大小可以超过伙伴分配器的最大阶
+///
+/// 分配得到的内存没有被清零
+///
+/// ## 参数
+///
+/// - `bytes`：大小（向上取整到页的大小）
+/// - `align`：起始物理地址的对齐要求（必须是2的幂）
+///
+/// ## 返回值
+///
+/// - `EINVAL`：bytes为0，或者align不是2的幂
+/// - `ENOMEM`：没有预留CMA区域，或者区域中没有足够大的连续空闲内存
+pub fn cma_alloc(bytes: usize, align: usize) -> Result<PhysAddr, SystemError> {
+    return CMA
+        .lock_irqsave()
+        .as_mut()
+        .ok_or(SystemError::ENOMEM)?
+        .alloc(bytes, align);
+}
+
+/// 释放`cma_alloc`分配的内存
+///
+/// ## 返回值
+///
+/// - `EINVAL`：这段内存不是由`cma_alloc`分配的
+pub fn cma_free(paddr: PhysAddr, bytes: usize) -> Result<(), SystemError> {
+    return CMA
+        .lock_irqsave()
+        .as_mut()
+        .ok_or(SystemError::EINVAL)?
+        .free(paddr, bytes);
+}
+
+/// CMA区域的起始物理地址和页帧数量。没有预留CMA区域时，返回None
+pub fn cma_area() -> Option<(PhysAddr, PageFrameCount)> {
+    if let Some(area) = CMA.lock_irqsave().as_ref() {
+        return Some((area.base(), area.count()));
+    }
+    return *CMA_RESERVED.lock_irqsave();
+}
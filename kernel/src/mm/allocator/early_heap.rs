@@ -0,0 +1,148 @@
+//! 启动阶段的早期堆
+//!
+//! 在伙伴分配器初始化完成之前，全局分配器无法从页帧分配器中获取内存。早期堆是一个位于内核.bss段中的
+//! 固定大小的字节数组，在这段时间内以bump的方式满足全局分配器的请求，因此启动阶段的代码也可以使用
+//! `Vec`、`String`等类型。
+//!
+//! 内存管理初始化完成之后，`early_heap_handoff`把全局分配器切换到真正的堆上（这个切换是显式的，
+//! 并且只能进行一次）。早期堆中的内存永远不会被回收：之后释放这些内存时，只会被记录下来。
+
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// 全局早期堆的大小（字节）
+pub const EARLY_HEAP_SIZE: usize = 64 * 1024;
+
+/// 以bump的方式分配内存的早期堆
+///
+/// 分配只需要一次CAS操作，不需要加锁。被释放的内存不会被回收
+#[repr(C, align(4096))]
+pub struct EarlyHeap<const N: usize> {
+    storage: UnsafeCell<[u8; N]>,
+    /// 下一次分配的起始偏移量
+    next: AtomicUsize,
+    /// 是否已经切换到了真正的堆
+    handed_off: AtomicBool,
+    /// 在早期堆中分配的次数
+    allocations: AtomicUsize,
+    /// 早期堆中的内存被释放的次数（这些内存不会被回收）
+    leaked: AtomicUsize,
+}
+
+unsafe impl<const N: usize> Sync for EarlyHeap<N> {}
+
+impl<const N: usize> EarlyHeap<N> {
+    pub const fn new() -> Self {
+        return Self {
+            storage: UnsafeCell::new([0; N]),
+            next: AtomicUsize::new(0),
+            handed_off: AtomicBool::new(false),
+            allocations: AtomicUsize::new(0),
+            leaked: AtomicUsize::new(0),
+        };
+    }
+
+    fn base(&self) -> usize {
+        return self.storage.get() as usize;
+    }
+
+    /// 从早期堆中分配内存
+    ///
+    /// ## 返回值
+    ///
+    /// - `Some(ptr)`：分配成功
+    /// - `None`：已经切换到了真正的堆，或者早期堆的空间不足
+    pub fn alloc(&self, layout: Layout) -> Option<*mut u8> {
+        if self.handed_off() {
+            return None;
+        }
+        let base = self.base();
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next).checked_add(layout.align() - 1)? & !(layout.align() - 1);
+            let end = start.checked_add(layout.size())?;
+            if end > base + N {
+                return None;
+            }
+            match self.next.compare_exchange_weak(
+                next,
+                end - base,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.allocations.fetch_add(1, Ordering::Relaxed);
+                    return Some(start as *mut u8);
+                }
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    /// 释放早期堆中的内存。内存不会被回收，只会被记录下来
+    ///
+    /// ## 返回值
+    ///
+    /// 如果ptr不属于早期堆，返回false，此时调用者应当把它交给真正的堆
+    pub fn dealloc(&self, ptr: *mut u8) -> bool {
+        if !self.contains(ptr) {
+            return false;
+        }
+        self.leaked.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+
+    /// 判断指针是否属于早期堆
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let addr = ptr as usize;
+        return addr >= self.base() && addr < self.base() + N;
+    }
+
+    /// 切换到真正的堆。之后的分配都会失败（由调用者转交给真正的堆）
+    ///
+    /// ## 返回值
+    ///
+    /// 如果已经切换过，返回false
+    pub fn handoff(&self) -> bool {
+        return self
+            .handed_off
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+    }
+
+    /// 是否已经切换到了真正的堆
+    #[inline(always)]
+    pub fn handed_off(&self) -> bool {
+        return self.handed_off.load(Ordering::Acquire);
+    }
+
+    /// 已经使用的字节数（包括对齐所浪费的空间）
+    pub fn used(&self) -> usize {
+        return self.next.load(Ordering::Relaxed);
+    }
+
+    /// 在早期堆中分配的次数
+    pub fn allocations(&self) -> usize {
+        return self.allocations.load(Ordering::Relaxed);
+    }
+
+    /// 早期堆中的内存被释放的次数
+    pub fn leaked(&self) -> usize {
+        return self.leaked.load(Ordering::Relaxed);
+    }
+}
+
+/// 全局分配器在伙伴分配器初始化完成之前使用的早期堆
+pub static EARLY_HEAP: EarlyHeap<EARLY_HEAP_SIZE> = EarlyHeap::new();
+
+/// 把全局分配器切换到真正的堆上（在伙伴分配器初始化完成、新的内核页表被激活之后调用）
+///
+/// 只能调用一次
+pub fn early_heap_handoff() {
+    if !EARLY_HEAP.handoff() {
+        panic!("early_heap_handoff() can only be called once");
+    }
+}
@@ -0,0 +1,165 @@
+//! 每个CPU的紧急页帧池
+//!
+//! 缺页异常处理等关键路径在需要页帧的时候，全局的页帧分配器可能暂时耗尽，
+//! 或者其锁正被当前上下文持有。为了避免死锁或者分配失败，每个CPU都预留少量的页帧，
+//! 关键路径可以在不获取全局分配器锁的情况下，从这里取得页帧。
+//!
+//! 设置了`AllocFlags::CRITICAL`的单页分配在全局分配器中没有空闲页帧时，从当前CPU的池中取得页帧。
+//!
+//! 紧急页帧池只能在非缺页异常的上下文中（通过`emergency_refill`）补充：BSP的池在内存管理初始化时补充，
+//! AP的池在它启动之前由BSP补充（`emergency_refill_cpu`），用户态的缺页异常处理完成之后，补充当前CPU的池。
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, CurrentIrqArch},
+    exception::InterruptArch,
+    mm::{percpu::PerCpu, PhysAddr},
+    smp::core::smp_get_processor_id,
+};
+
+use super::{
+    frame_tag::FRAME_TAG_UNTAGGED,
+    page_frame::{AllocFlags, FrameAllocator, PageFrameCount},
+};
+
+/// 每个CPU的紧急页帧池的容量
+const EMERGENCY_POOL_SIZE: usize = 8;
+
+#[derive(Clone, Copy)]
+struct EmergencyPool {
+    frames: [PhysAddr; EMERGENCY_POOL_SIZE],
+    count: usize,
+}
+
+impl EmergencyPool {
+    const fn new() -> Self {
+        return Self {
+            frames: [PhysAddr::new(0); EMERGENCY_POOL_SIZE],
+            count: 0,
+        };
+    }
+
+    fn pop(&mut self) -> Option<PhysAddr> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        return Some(self.frames[self.count]);
+    }
+
+    fn push(&mut self, paddr: PhysAddr) -> bool {
+        if self.count == EMERGENCY_POOL_SIZE {
+            return false;
+        }
+        self.frames[self.count] = paddr;
+        self.count += 1;
+        return true;
+    }
+}
+
+/// 每个CPU的紧急页帧池。
+///
+/// 每个池只会被它所属的CPU访问，并且访问时会关闭中断，因此不需要加锁
+static mut EMERGENCY_POOLS: [EmergencyPool; PerCpu::MAX_CPU_NUM] =
+    [EmergencyPool::new(); PerCpu::MAX_CPU_NUM];
+
+/// 在关中断的情况下，访问当前CPU的紧急页帧池
+fn with_current_pool<R>(f: impl FnOnce(&mut EmergencyPool) -> R) -> R {
+    let guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let cpu_id = smp_get_processor_id() as usize;
+    assert!(cpu_id < PerCpu::MAX_CPU_NUM, "cpu id {cpu_id} out of range");
+    let r = f(unsafe { &mut EMERGENCY_POOLS[cpu_id] });
+    drop(guard);
+    return r;
+}
+
+/// 获取当前CPU的紧急页帧池中页帧的数量
+pub fn emergency_pool_count() -> usize {
+    return with_current_pool(|pool| pool.count);
+}
+
+/// 从当前CPU的紧急页帧池中取出一个页帧
+///
+/// 该函数不会获取全局页帧分配器的锁，因此可以在缺页异常处理等关键路径中使用。
+///
+/// ## 返回值
+///
+/// 如果紧急页帧池不为空，返回页帧的物理地址，否则返回None
+pub fn emergency_alloc() -> Option<PhysAddr> {
+    return with_current_pool(|pool| pool.pop());
+}
+
+/// 把一个页帧归还到当前CPU的紧急页帧池
+///
+/// 如果紧急页帧池已满，则归还到全局页帧分配器。
+pub unsafe fn emergency_free(paddr: PhysAddr) {
+    let pushed = with_current_pool(|pool| pool.push(paddr));
+    if !pushed {
+        LockedFrameAllocator.free_one(paddr);
+    }
+}
+
+/// 补充当前CPU的紧急页帧池
+///
+/// 只有在空闲内存充足（高于低水位线）的情况下，才会从全局分配器中补充页帧。
+///
+/// 请注意，该函数会获取全局页帧分配器的锁，因此不能在缺页异常的上下文中调用。
+///
+/// ## 返回值
+///
+/// 本次补充的页帧数量
+pub fn emergency_refill() -> usize {
+    let mut refilled = 0;
+    loop {
+        let missing = with_current_pool(|pool| EMERGENCY_POOL_SIZE - pool.count);
+        if missing == 0 {
+            break;
+        }
+
+        let paddr = match refill_frame() {
+            Some(paddr) => paddr,
+            None => break,
+        };
+
+        // 在分配的过程中，可能有中断处理程序向池中归还了页帧，因此需要检查是否成功放入
+        if !with_current_pool(|pool| pool.push(paddr)) {
+            unsafe { LockedFrameAllocator.free_one(paddr) };
+            break;
+        }
+        refilled += 1;
+    }
+    return refilled;
+}
+
+/// 在CPU启动之前（由其他CPU）补充它的紧急页帧池
+///
+/// 调用者需要保证目标CPU还没有开始运行，此时没有其他人访问它的池
+///
+/// ## 返回值
+///
+/// 本次补充的页帧数量
+pub fn emergency_refill_cpu(cpu_id: usize) -> usize {
+    assert!(cpu_id < PerCpu::MAX_CPU_NUM, "cpu id {cpu_id} out of range");
+    let pool = unsafe { &mut EMERGENCY_POOLS[cpu_id] };
+    let mut refilled = 0;
+    while pool.count < EMERGENCY_POOL_SIZE {
+        let paddr = match refill_frame() {
+            Some(paddr) => paddr,
+            None => break,
+        };
+        pool.push(paddr);
+        refilled += 1;
+    }
+    return refilled;
+}
+
+/// 从全局分配器中取得一个用于补充紧急页帧池的页帧（低于低水位线时失败）
+fn refill_frame() -> Option<PhysAddr> {
+    return unsafe {
+        LockedFrameAllocator.allocate_flags(
+            PageFrameCount::new(1),
+            AllocFlags::NOWARN,
+            FRAME_TAG_UNTAGGED,
+        )
+    }
+    .map(|(paddr, _)| paddr);
+}
@@ -0,0 +1,147 @@
+//! 每个CPU的单页帧缓存
+//!
+//! 所有的单页分配如果都经过伙伴分配器，就都需要获取全局页帧分配器的锁。为了减少对这把锁的竞争，
+//! 每个CPU在伙伴分配器前面维护一个单页帧的缓存：
+//!
+//! - 单页分配优先从当前CPU的缓存中取得页帧，不需要获取全局分配器的锁；
+//! - 缓存为空时，只获取一次全局分配器的锁，一次性地补充`FRAME_CACHE_BATCH`个页帧；
+//! - 被释放的单个页帧放入当前CPU的缓存，缓存已满时，先把`FRAME_CACHE_BATCH`个最早放入的页帧
+//!   归还给全局分配器（同样只获取一次锁）；
+//! - 内存不足时，`LockedFrameAllocator::drain_frame_caches`把所有CPU的缓存归还给全局分配器。
+//!
+//! 在AP处理器启动的过程中，由BSP调用`LockedFrameAllocator::warmup_cpu`预先填充这个CPU的缓存（预热）。
+//!
+//! 缓存中的页帧在伙伴分配器看来是已分配的，因此会被统计在`usage`的已使用部分中。
+//!
+//! 每个CPU的缓存由一把独立的锁保护（`lock_irqsave`）：预热可能发生在目标CPU上线之前，并且由其他CPU执行，
+//! 归还所有缓存时也会访问其他CPU的缓存。访问当前CPU的缓存时还需要关闭抢占，
+//! 避免在获取CPU的id之后被迁移到其他CPU上。
+
+use crate::{
+    libs::spinlock::SpinLock,
+    mm::{percpu::PerCpu, PhysAddr},
+    process::preempt::{preempt_disable, preempt_enable},
+    smp::core::smp_get_processor_id,
+};
+
+/// 每个CPU的缓存的容量
+pub const FRAME_CACHE_SIZE: usize = 64;
+/// AP处理器启动时，预热的页帧数量
+pub const FRAME_CACHE_WARMUP: usize = 16;
+/// 缓存为空时补充的页帧数量，也是缓存已满时归还给全局分配器的页帧数量
+pub const FRAME_CACHE_BATCH: usize = 16;
+
+#[derive(Clone, Copy)]
+struct FrameCache {
+    frames: [PhysAddr; FRAME_CACHE_SIZE],
+    count: usize,
+}
+
+impl FrameCache {
+    const fn new() -> Self {
+        return Self {
+            frames: [PhysAddr::new(0); FRAME_CACHE_SIZE],
+            count: 0,
+        };
+    }
+
+    fn pop(&mut self) -> Option<PhysAddr> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        return Some(self.frames[self.count]);
+    }
+
+    fn push(&mut self, paddr: PhysAddr) -> bool {
+        if self.count == FRAME_CACHE_SIZE {
+            return false;
+        }
+        self.frames[self.count] = paddr;
+        self.count += 1;
+        return true;
+    }
+}
+
+const FRAME_CACHE_INIT: SpinLock<FrameCache> = SpinLock::new(FrameCache::new());
+static FRAME_CACHES: [SpinLock<FrameCache>; PerCpu::MAX_CPU_NUM] =
+    [FRAME_CACHE_INIT; PerCpu::MAX_CPU_NUM];
+
+/// 从当前CPU的缓存中取出一个页帧（不获取全局分配器的锁）
+pub fn frame_cache_pop() -> Option<PhysAddr> {
+    preempt_disable();
+    let cpu_id = smp_get_processor_id() as usize;
+    let r = FRAME_CACHES
+        .get(cpu_id)
+        .and_then(|cache| cache.lock_irqsave().pop());
+    preempt_enable();
+    return r;
+}
+
+/// 把一个页帧放入指定CPU的缓存中
+///
+/// ## 返回值
+///
+/// 如果缓存已满（或者cpu_id超出范围），返回false，此时调用者需要自己处理这个页帧
+pub fn frame_cache_push(cpu_id: usize, paddr: PhysAddr) -> bool {
+    return FRAME_CACHES
+        .get(cpu_id)
+        .map_or(false, |cache| cache.lock_irqsave().push(paddr));
+}
+
+/// 把一个页帧放入当前CPU的缓存中
+///
+/// ## 返回值
+///
+/// 如果缓存已满，返回false，此时调用者需要自己处理这个页帧
+pub fn frame_cache_push_local(paddr: PhysAddr) -> bool {
+    preempt_disable();
+    let r = frame_cache_push(smp_get_processor_id() as usize, paddr);
+    preempt_enable();
+    return r;
+}
+
+/// 从指定CPU的缓存中取出最早放入的若干个页帧（最近放入的页帧更可能还在CPU的高速缓存中，留在缓存里）
+///
+/// ## 参数
+///
+/// - `cpu_id`：CPU的id
+/// - `frames`：用于存放取出的页帧，最多取出`frames.len()`个
+///
+/// ## 返回值
+///
+/// 实际取出的页帧数量
+pub fn frame_cache_take(cpu_id: usize, frames: &mut [PhysAddr]) -> usize {
+    let mut cache = match FRAME_CACHES.get(cpu_id) {
+        Some(cache) => cache.lock_irqsave(),
+        None => return 0,
+    };
+    let count = cache.count;
+    let taken = core::cmp::min(frames.len(), count);
+    frames[..taken].copy_from_slice(&cache.frames[..taken]);
+    cache.frames.copy_within(taken..count, 0);
+    cache.count -= taken;
+    return taken;
+}
+
+/// 获取指定CPU的缓存中的页帧数量
+pub fn frame_cache_count(cpu_id: usize) -> usize {
+    return FRAME_CACHES
+        .get(cpu_id)
+        .map_or(0, |cache| cache.lock_irqsave().count);
+}
+
+/// 获取指定CPU的缓存还能放入的页帧数量
+pub fn frame_cache_room(cpu_id: usize) -> usize {
+    return FRAME_CACHES
+        .get(cpu_id)
+        .map_or(0, |cache| FRAME_CACHE_SIZE - cache.lock_irqsave().count);
+}
+
+/// 获取所有CPU的缓存中的页帧总数
+pub fn frame_cache_total() -> usize {
+    return FRAME_CACHES
+        .iter()
+        .map(|cache| cache.lock_irqsave().count)
+        .sum();
+}
@@ -0,0 +1,172 @@
+//! 页帧的分配标签
+//!
+//! 启用`frame_tag`特性之后，每个页帧在被分配时，都会在一张旁路表中记录调用者传入的标签（`FrameTag`），
+//! 并在释放时清除。发生内存泄漏时，可以通过`leak_report`统计每个标签下尚未释放的页帧数量，
+//! 从而找到泄漏页帧的子系统。
+//!
+//! 旁路表是一个以物理页号为下标的`AtomicU16`数组，在伙伴分配器初始化之后由`frame_tag_init`分配。
+//! 由于分配和释放路径上不需要加锁，也不需要在堆上分配内存，因此不会与页帧分配器产生递归。
+//!
+//! 未启用`frame_tag`特性时，本模块的所有函数都不做任何事情。
+
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    kinfo,
+};
+
+use super::page_frame::{FrameAllocator, PageFrameCount};
+use crate::mm::{MemoryManagementArch, PhysAddr};
+
+/// 页帧的分配标签
+pub type FrameTag = u16;
+
+/// 页帧空闲（或者在旁路表初始化之前被分配）
+const FRAME_TAG_FREE: FrameTag = 0;
+/// 调用者没有指定标签
+pub const FRAME_TAG_UNTAGGED: FrameTag = 1;
+/// 页表
+pub const FRAME_TAG_PAGE_TABLE: FrameTag = 2;
+/// 用户空间的匿名页
+pub const FRAME_TAG_USER_ANON: FrameTag = 3;
+/// slab分配器
+pub const FRAME_TAG_SLAB: FrameTag = 4;
+/// DMA缓冲区
+pub const FRAME_TAG_DMA: FrameTag = 5;
+/// MMIO
+pub const FRAME_TAG_MMIO: FrameTag = 6;
+
+/// 旁路表的起始虚拟地址（为0表示尚未初始化）
+static TAG_TABLE: AtomicUsize = AtomicUsize::new(0);
+/// 旁路表所覆盖的页帧数量
+static TAG_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// 获取页帧的标签在旁路表中的表项
+fn tag_entry(pfn: usize) -> Option<&'static AtomicU16> {
+    let table = TAG_TABLE.load(Ordering::Acquire);
+    if table == 0 || pfn >= TAG_TABLE_FRAMES.load(Ordering::Relaxed) {
+        return None;
+    }
+    return Some(unsafe { &*(table as *const AtomicU16).add(pfn) });
+}
+
+/// 初始化旁路表（需要在伙伴分配器初始化之后调用）
+pub fn frame_tag_init() {
+    if !cfg!(feature = "frame_tag") {
+        return;
+    }
+    let max_paddr = MMArch::phys_memory_areas()
+        .iter()
+        .map(|area| area.base.data() + area.size)
+        .max()
+        .unwrap_or(0);
+    let frames = max_paddr >> MMArch::PAGE_SHIFT;
+    let table_bytes = frames * core::mem::size_of::<AtomicU16>();
+    let count =
+        PageFrameCount::new(((table_bytes + MMArch::PAGE_SIZE - 1) >> MMArch::PAGE_SHIFT).max(1));
+    let (paddr, allocated) =
+        unsafe { LockedFrameAllocator.allocate(count) }.expect("frame_tag_init: out of memory");
+    let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    unsafe { MMArch::write_bytes(vaddr, 0, allocated.bytes()) };
+
+    TAG_TABLE_FRAMES.store(frames, Ordering::Relaxed);
+    TAG_TABLE.store(vaddr.data(), Ordering::Release);
+    kinfo!(
+        "frame_tag: side table covers {} frames, uses {} pages",
+        frames,
+        allocated.data()
+    );
+}
+
+/// 把一段页帧标记为由tag所分配
+pub fn frame_tag_set(paddr: PhysAddr, count: PageFrameCount, tag: FrameTag) {
+    if !cfg!(feature = "frame_tag") {
+        return;
+    }
+    let tag = if tag == FRAME_TAG_FREE {
+        FRAME_TAG_UNTAGGED
+    } else {
+        tag
+    };
+    let pfn = paddr.data() >> MMArch::PAGE_SHIFT;
+    for i in 0..count.data() {
+        if let Some(entry) = tag_entry(pfn + i) {
+            entry.store(tag, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 清除一段页帧的标签（在释放页帧时调用）
+pub fn frame_tag_clear(paddr: PhysAddr, count: PageFrameCount) {
+    if !cfg!(feature = "frame_tag") {
+        return;
+    }
+    let pfn = paddr.data() >> MMArch::PAGE_SHIFT;
+    for i in 0..count.data() {
+        if let Some(entry) = tag_entry(pfn + i) {
+            entry.store(FRAME_TAG_FREE, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 获取页帧的标签
+///
+/// ## 返回值
+///
+/// 如果页帧空闲，或者未启用`frame_tag`特性，返回None
+pub fn frame_tag_of(paddr: PhysAddr) -> Option<FrameTag> {
+    let tag = tag_entry(paddr.data() >> MMArch::PAGE_SHIFT)?.load(Ordering::Relaxed);
+    if tag == FRAME_TAG_FREE {
+        return None;
+    }
+    return Some(tag);
+}
+
+/// 标签的名称（用于打印）
+fn frame_tag_name(tag: FrameTag) -> &'static str {
+    match tag {
+        FRAME_TAG_UNTAGGED => "untagged",
+        FRAME_TAG_PAGE_TABLE => "page-table",
+        FRAME_TAG_USER_ANON => "user-anon",
+        FRAME_TAG_SLAB => "slab",
+        FRAME_TAG_DMA => "dma",
+        FRAME_TAG_MMIO => "mmio",
+        _ => "other",
+    }
+}
+
+/// 统计每个标签下尚未释放的页帧数量，并打印出来
+///
+/// ## 返回值
+///
+/// 按照标签从小到大排列的（标签, 尚未释放的页帧数量）。未启用`frame_tag`特性时，返回空的数组
+pub fn leak_report() -> Vec<(FrameTag, PageFrameCount)> {
+    let mut totals: BTreeMap<FrameTag, usize> = BTreeMap::new();
+    let frames = TAG_TABLE_FRAMES.load(Ordering::Relaxed);
+    for pfn in 0..frames {
+        let tag = match tag_entry(pfn) {
+            Some(entry) => entry.load(Ordering::Relaxed),
+            None => break,
+        };
+        if tag != FRAME_TAG_FREE {
+            *totals.entry(tag).or_insert(0) += 1;
+        }
+    }
+
+    let report: Vec<(FrameTag, PageFrameCount)> = totals
+        .into_iter()
+        .map(|(tag, count)| (tag, PageFrameCount::new(count)))
+        .collect();
+    for (tag, count) in report.iter() {
+        kinfo!(
+            "leak_report: tag {} ({}): {} frame(s) outstanding",
+            tag,
+            frame_tag_name(*tag),
+            count.data()
+        );
+    }
+    return report;
+}
@@ -0,0 +1,193 @@
+//! 页帧释放的检查
+//!
+//! `BuddyAllocator::free`相信调用者传入的`(paddr, count)`是之前分配得到的。重复释放、或者释放从未分配的页帧，
+//! 会把同一个块两次放入空闲链表，破坏伙伴分配器。启用`frame_free_check`特性之后，伙伴分配器用两张以物理页号为下标的位图
+//! 记录每个页帧的状态：分配时检查并清除“空闲”位，释放时检查“空闲”位是否已经被清除，否则以出错的物理地址panic
+//! （相当于页帧级别的double-free检测）。
+//!
+//! 位图在伙伴分配器初始化之后由`free_check_init`分配，并根据当时的空闲链表初始化。在此之前被分配出去的页帧
+//! （比如启动阶段的页表、initrd）被视为已分配，之后可以被正常地释放。
+//!
+//! 单个页帧被释放时，可能先进入待清零链表等页帧池，在它们被归还给伙伴分配器的时候才会被检查。
+//!
+//! 未启用`frame_free_check`特性时，本模块的所有函数都不做任何事情。
+
+use crate::{
+    arch::MMArch,
+    kinfo,
+    libs::spinlock::SpinLock,
+    mm::{MemoryManagementArch, PhysAddr},
+};
+
+use super::{
+    buddy::BuddyAllocator,
+    page_frame::{FrameAllocator, PageFrameCount},
+};
+
+/// 释放页帧时发现的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeCheckError {
+    /// 页帧已经被释放过
+    DoubleFree(PhysAddr),
+    /// 页帧从来没有被分配过（或者不属于RAM）
+    NotAllocated(PhysAddr),
+}
+
+/// 记录每个页帧的分配状态的位图
+pub struct FreeCheckTable<'a> {
+    /// 为1表示页帧位于伙伴分配器中（空闲）
+    free: &'a mut [u64],
+    /// 为1表示页帧在启用检查之后，曾经处于已分配的状态
+    seen_allocated: &'a mut [u64],
+}
+
+impl<'a> FreeCheckTable<'a> {
+    /// 在两张同样大小的位图上创建表（所有的页帧都被视为已分配）
+    pub fn new(free: &'a mut [u64], seen_allocated: &'a mut [u64]) -> Self {
+        assert_eq!(free.len(), seen_allocated.len());
+        free.fill(0);
+        seen_allocated.fill(0);
+        return Self {
+            free,
+            seen_allocated,
+        };
+    }
+
+    /// 表所覆盖的页帧数量
+    pub fn frames(&self) -> usize {
+        return self.free.len() * 64;
+    }
+
+    fn test(bitmap: &[u64], pfn: usize) -> bool {
+        return bitmap[pfn / 64] & (1 << (pfn % 64)) != 0;
+    }
+
+    fn set(bitmap: &mut [u64], pfn: usize, value: bool) {
+        if value {
+            bitmap[pfn / 64] |= 1 << (pfn % 64);
+        } else {
+            bitmap[pfn / 64] &= !(1 << (pfn % 64));
+        }
+    }
+
+    /// 把一段页帧标记为空闲（用于根据伙伴分配器的空闲链表初始化）。超出表的范围的页帧被忽略
+    pub fn mark_free(&mut self, paddr: PhysAddr, count: PageFrameCount) {
+        let pfn = paddr.data() >> MMArch::PAGE_SHIFT;
+        for i in pfn..core::cmp::min(pfn + count.data(), self.frames()) {
+            Self::set(self.free, i, true);
+        }
+    }
+
+    /// 记录一次分配
+    pub fn on_alloc(&mut self, paddr: PhysAddr, count: PageFrameCount) {
+        let pfn = paddr.data() >> MMArch::PAGE_SHIFT;
+        for i in pfn..core::cmp::min(pfn + count.data(), self.frames()) {
+            Self::set(self.free, i, false);
+            Self::set(self.seen_allocated, i, true);
+        }
+    }
+
+    /// 检查并记录一次释放。如果发现错误，表不会被修改
+    ///
+    /// ## 返回值
+    ///
+    /// 第一个出错的页帧
+    pub fn on_free(
+        &mut self,
+        paddr: PhysAddr,
+        count: PageFrameCount,
+    ) -> Result<(), FreeCheckError> {
+        let pfn = paddr.data() >> MMArch::PAGE_SHIFT;
+        for i in pfn..pfn + count.data() {
+            let frame = PhysAddr::new(i << MMArch::PAGE_SHIFT);
+            if i >= self.frames() {
+                return Err(FreeCheckError::NotAllocated(frame));
+            }
+            if Self::test(self.free, i) {
+                if Self::test(self.seen_allocated, i) {
+                    return Err(FreeCheckError::DoubleFree(frame));
+                }
+                return Err(FreeCheckError::NotAllocated(frame));
+            }
+        }
+        for i in pfn..pfn + count.data() {
+            Self::set(self.free, i, true);
+            // 在启用检查之前被分配的页帧，被释放之后同样可以检测到重复释放
+            Self::set(self.seen_allocated, i, true);
+        }
+        return Ok(());
+    }
+}
+
+/// 全局的检查表（为None表示尚未初始化）
+///
+/// 只会在持有伙伴分配器的锁时被访问
+static FREE_CHECK: SpinLock<Option<FreeCheckTable<'static>>> = SpinLock::new(None);
+
+/// 分配检查表，并根据伙伴分配器当前的空闲链表初始化（需要在持有伙伴分配器的锁时调用）
+pub fn free_check_init<A: MemoryManagementArch>(buddy: &mut BuddyAllocator<A>) {
+    if !cfg!(feature = "frame_free_check") {
+        return;
+    }
+    let max_paddr = MMArch::phys_memory_areas()
+        .iter()
+        .map(|area| area.base.data() + area.size)
+        .max()
+        .unwrap_or(0);
+    let words = ((max_paddr >> MMArch::PAGE_SHIFT) + 63) / 64;
+    let bitmap_bytes = words * core::mem::size_of::<u64>();
+    let count = PageFrameCount::new(
+        ((2 * bitmap_bytes + MMArch::PAGE_SIZE - 1) >> MMArch::PAGE_SHIFT).max(1),
+    );
+    // 检查表还没有被设置，这次分配不会被记录
+    let (paddr, allocated) =
+        unsafe { buddy.allocate(count) }.expect("free_check_init: out of memory");
+    let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    let (free, seen_allocated) = unsafe {
+        core::slice::from_raw_parts_mut(vaddr.data() as *mut u64, 2 * words).split_at_mut(words)
+    };
+    let mut table = FreeCheckTable::new(free, seen_allocated);
+    // 持有伙伴分配器的锁时不能在堆上分配内存，因此直接在遍历空闲链表时标记
+    buddy.for_each_free_block(|base, count| table.mark_free(base, count));
+
+    *FREE_CHECK.lock_irqsave() = Some(table);
+    kinfo!(
+        "frame_free_check: tracking {} frames, uses {} pages",
+        words * 64,
+        allocated.data()
+    );
+}
+
+/// 记录伙伴分配器的一次分配
+pub fn free_check_alloc(paddr: PhysAddr, count: PageFrameCount) {
+    if !cfg!(feature = "frame_free_check") {
+        return;
+    }
+    if let Some(table) = FREE_CHECK.lock_irqsave().as_mut() {
+        table.on_alloc(paddr, count);
+    }
+}
+
+/// 检查伙伴分配器的一次释放。如果这是重复释放，或者释放了从未分配的页帧，panic
+pub fn free_check_free(paddr: PhysAddr, count: PageFrameCount) {
+    if !cfg!(feature = "frame_free_check") {
+        return;
+    }
+    if let Some(table) = FREE_CHECK.lock_irqsave().as_mut() {
+        match table.on_free(paddr, count) {
+            Ok(()) => {}
+            Err(FreeCheckError::DoubleFree(frame)) => panic!(
+                "buddy free: double free of frame {:?} (freeing {:?}, {} pages)",
+                frame,
+                paddr,
+                count.data()
+            ),
+            Err(FreeCheckError::NotAllocated(frame)) => panic!(
+                "buddy free: frame {:?} was never allocated (freeing {:?}, {} pages)",
+                frame,
+                paddr,
+                count.data()
+            ),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! 预先清零的大页池
+//!
+//! 在缺页处理中清零一个2M的大页的开销很大，会造成明显的延迟尖峰。
+//! 本模块维护一个小的池，其中的每一项都是一个按2M对齐、已经清零的连续块（从伙伴分配器中分配，伙伴分配器的块总是自然对齐的）。
+//! 大页的分配（`MAP_HUGETLB`的匿名映射，参见`VMA::zeroed_huge`）优先从池中取得块，池为空时才退化为当场分配并清零。
+//!
+//! 池由idle循环（`rs_mm_idle_work`，与`scrub_some`相同）调用`huge_pool_refill`补充，
+//! 清零使用非临时存储，并且不持有池的锁。池中的块通过侵入式链表连接（参见`scrub::FrameList`），
+//! 取出时链表指针会被重新清零，因此取出的块仍然是全零的。
+//!
+//! 池的大小为0时（默认），不会补充池，分配总是当场进行。池的大小通过`hugepool=`启动参数设置。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    libs::spinlock::SpinLock,
+    mm::{MemoryManagementArch, PhysAddr},
+};
+
+use super::{
+    frame_tag::FRAME_TAG_UNTAGGED,
+    page_frame::{AllocFlags, FrameAllocator, PageFrameCount},
+    scrub::FrameList,
+};
+
+/// 大页的大小（2M，即一个倒数第二级页表项所映射的范围）
+pub const HUGE_PAGE_SIZE: usize = MMArch::PAGE_SIZE << MMArch::PAGE_ENTRY_SHIFT;
+/// 一个大页所包含的页帧数量
+pub const HUGE_PAGE_FRAMES: PageFrameCount =
+    PageFrameCount::new(HUGE_PAGE_SIZE / MMArch::PAGE_SIZE);
+
+/// 池的最大容量（块的数量）
+const HUGE_POOL_MAX_BLOCKS: usize = 64;
+
+/// 池的大小（块的数量），为0表示不启用
+static HUGE_POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// 池中已经清零的块
+static HUGE_POOL: SpinLock<FrameList> = SpinLock::new(FrameList::new());
+
+/// 从池中取得块的次数
+static HUGE_POOL_HITS: AtomicUsize = AtomicUsize::new(0);
+/// 池为空，当场分配并清零的次数
+static HUGE_POOL_MISSES: AtomicUsize = AtomicUsize::new(0);
+/// 补充到池中的块的总数
+static HUGE_POOL_REFILLED: AtomicUsize = AtomicUsize::new(0);
+
+/// 大页池的统计信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugePoolStats {
+    /// 池的大小（块的数量）
+    pub size: usize,
+    /// 池中已经清零的块的数量
+    pub pooled: usize,
+    /// 从池中取得块的次数
+    pub hits: usize,
+    /// 池为空，当场分配并清零的次数
+    pub misses: usize,
+    /// 补充到池中的块的总数
+    pub refilled: usize,
+}
+
+/// 设置大页池的大小
+///
+/// 缩小池的大小时，超出的块会被归还给伙伴分配器。增大池的大小不会立即分配，而是由`huge_pool_refill`补充
+///
+/// ## 参数
+///
+/// - `blocks`：池的大小（块的数量），为0表示不启用。超过`HUGE_POOL_MAX_BLOCKS`时会被截断
+pub fn set_huge_pool_size(blocks: usize) {
+    let blocks = blocks.min(HUGE_POOL_MAX_BLOCKS);
+    HUGE_POOL_SIZE.store(blocks, Ordering::SeqCst);
+
+    loop {
+        let paddr = {
+            let mut pool = HUGE_POOL.lock_irqsave();
+            if pool.count() <= blocks {
+                break;
+            }
+            pool.pop().unwrap()
+        };
+        unsafe { LockedFrameAllocator.free_to_buddy(paddr, HUGE_PAGE_FRAMES) };
+    }
+}
+
+/// 获取大页池的大小
+pub fn huge_pool_size() -> usize {
+    return HUGE_POOL_SIZE.load(Ordering::Relaxed);
+}
+
+/// 补充大页池：分配并清零最多max_blocks个块，直到池的数量达到池的大小
+///
+/// 该函数应当在低优先级的上下文中（比如idle循环）调用。空闲页帧低于低水位线时，停止补充
+///
+/// ## 返回值
+///
+/// 本次补充的块的数量
+pub fn huge_pool_refill(max_blocks: usize) -> usize {
+    let mut refilled = 0;
+    while refilled < max_blocks && HUGE_POOL.lock_irqsave().count() < huge_pool_size() {
+        let (paddr, _) = match unsafe {
+            LockedFrameAllocator.allocate_flags(
+                HUGE_PAGE_FRAMES,
+                AllocFlags::NOWARN,
+                FRAME_TAG_UNTAGGED,
+            )
+        } {
+            Some(r) => r,
+            None => break,
+        };
+        debug_assert!(paddr.check_aligned(HUGE_PAGE_SIZE));
+
+        // 在不持有锁的情况下清零。池中的块不一定很快会被使用，因此使用非临时存储，避免污染缓存
+        unsafe { MMArch::zero_frames_nt(paddr, HUGE_PAGE_FRAMES) };
+
+        // 在清零的过程中，池的大小可能被缩小了
+        let pushed = {
+            let mut pool = HUGE_POOL.lock_irqsave();
+            pool.count() < huge_pool_size() && pool.push(paddr)
+        };
+        if !pushed {
+            unsafe { LockedFrameAllocator.free_to_buddy(paddr, HUGE_PAGE_FRAMES) };
+            break;
+        }
+        refilled += 1;
+    }
+    HUGE_POOL_REFILLED.fetch_add(refilled, Ordering::Relaxed);
+    return refilled;
+}
+
+/// 分配一个已经清零、按2M对齐的大页
+///
+/// 优先从大页池中取得，池为空时当场分配并清零
+///
+/// ## 返回值
+///
+/// 大页的起始物理地址。如果无法分配，返回None
+pub fn alloc_zeroed_huge_page() -> Option<PhysAddr> {
+    if let Some(paddr) = HUGE_POOL.lock_irqsave().pop() {
+        HUGE_POOL_HITS.fetch_add(1, Ordering::Relaxed);
+        return Some(paddr);
+    }
+
+    HUGE_POOL_MISSES.fetch_add(1, Ordering::Relaxed);
+    let (paddr, _) = unsafe {
+        LockedFrameAllocator.allocate_flags(HUGE_PAGE_FRAMES, AllocFlags::ZERO, FRAME_TAG_UNTAGGED)
+    }?;
+    debug_assert!(paddr.check_aligned(HUGE_PAGE_SIZE));
+    return Some(paddr);
+}
+
+/// 释放由`alloc_zeroed_huge_page`分配的大页
+///
+/// 被释放的大页可能已经被写入，因此直接归还给伙伴分配器，而不是放回池中
+pub unsafe fn free_huge_page(paddr: PhysAddr) {
+    LockedFrameAllocator.free(paddr, HUGE_PAGE_FRAMES);
+}
+
+/// 获取大页池的统计信息
+pub fn huge_pool_stats() -> HugePoolStats {
+    return HugePoolStats {
+        size: huge_pool_size(),
+        pooled: HUGE_POOL.lock_irqsave().count(),
+        hits: HUGE_POOL_HITS.load(Ordering::Relaxed),
+        misses: HUGE_POOL_MISSES.load(Ordering::Relaxed),
+        refilled: HUGE_POOL_REFILLED.load(Ordering::Relaxed),
+    };
+}
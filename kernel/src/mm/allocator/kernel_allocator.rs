@@ -1,7 +1,10 @@
 use crate::{
     arch::mm::LockedFrameAllocator,
     libs::align::page_align_up,
-    mm::{MMArch, MemoryManagementArch, VirtAddr},
+    mm::{
+        kmem_stat::{kmem_stat_add, kmem_stat_sub, KernelMemPurpose},
+        MMArch, MemoryManagementArch, VirtAddr,
+    },
 };
 
 use core::{
@@ -10,7 +13,7 @@ use core::{
     ptr::NonNull,
 };
 
-use super::page_frame::{FrameAllocator, PageFrameCount};
+use super::{early_heap::EARLY_HEAP, frame_tag::FRAME_TAG_SLAB, page_frame::PageFrameCount};
 
 /// 类kmalloc的分配器应当实现的trait
 pub trait LocalAlloc {
@@ -26,19 +29,19 @@ impl KernelAllocator {
         // 计算需要申请的页数，向上取整
         let count = (page_align_up(layout.size()) / MMArch::PAGE_SIZE).next_power_of_two();
         let page_frame_count = PageFrameCount::new(count);
-        let (phy_addr, allocated_frame_count) = LockedFrameAllocator
-            .allocate(page_frame_count)
+        let (_, virt_addr) = LockedFrameAllocator
+            .allocate_kernel(page_frame_count, FRAME_TAG_SLAB)
             .ok_or(AllocError)?;
 
-        let virt_addr = unsafe { MMArch::phys_2_virt(phy_addr).ok_or(AllocError)? };
         if unlikely(virt_addr.is_null()) {
             return Err(AllocError);
         }
+        kmem_stat_add(KernelMemPurpose::Slab, page_frame_count);
 
         let slice = unsafe {
             core::slice::from_raw_parts_mut(
                 virt_addr.data() as *mut u8,
-                allocated_frame_count.data() * MMArch::PAGE_SIZE,
+                page_frame_count.data() * MMArch::PAGE_SIZE,
             )
         };
         return Ok(NonNull::from(slice));
@@ -48,8 +51,8 @@ impl KernelAllocator {
         // 由于buddy分配的页数量是2的幂，因此释放的时候也需要按照2的幂向上取整。
         let count = (page_align_up(layout.size()) / MMArch::PAGE_SIZE).next_power_of_two();
         let page_frame_count = PageFrameCount::new(count);
-        let phy_addr = MMArch::virt_2_phys(VirtAddr::new(ptr as usize)).unwrap();
-        LockedFrameAllocator.free(phy_addr, page_frame_count);
+        LockedFrameAllocator.free_kernel(VirtAddr::new(ptr as usize), page_frame_count);
+        kmem_stat_sub(KernelMemPurpose::Slab, page_frame_count);
     }
 }
 
@@ -79,17 +82,29 @@ impl LocalAlloc for KernelAllocator {
 }
 
 /// 为内核slab分配器实现GlobalAlloc特性
+///
+/// 在切换到真正的堆之前（参见`early_heap_handoff`），内存从早期堆中分配
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = EARLY_HEAP.alloc(layout) {
+            return ptr;
+        }
         return self.local_alloc(layout);
         // self.local_alloc_zeroed(layout, 0)
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // 早期堆中的内存不会被重复使用，因此总是为0
+        if let Some(ptr) = EARLY_HEAP.alloc(layout) {
+            return ptr;
+        }
         self.local_alloc_zeroed(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if EARLY_HEAP.dealloc(ptr) {
+            return;
+        }
         self.local_dealloc(ptr, layout);
     }
 }
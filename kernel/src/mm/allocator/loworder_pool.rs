@@ -0,0 +1,132 @@
+//! 单页分配的低阶页帧池
+//!
+//! 伙伴分配器在满足单页的请求时，会从当前最小的空闲块中分裂出一页。
+//! 在单页分配与大块分配交替进行的负载下，单页的分配可能分散在许多不同的大块中，
+//! 使得之后的大块分配找不到连续的内存。
+//!
+//! 启用低阶页帧池之后，每个CPU的页帧缓存（参见`frame_cache`）从池中补充，而不是直接从伙伴分配器中补充。
+//! 池为空时，一次性从伙伴分配器中分配一个连续的块，并把它拆分成单页放入池中（批量补充），
+//! 因此单页的分配集中在少数的几个块中，其余的大块能够更长时间地保持完整。
+//! 页帧缓存已满时被归还的单页，在池未满时也会放回池中。
+//!
+//! 也就是说，单页的分配与释放按照 页帧缓存 → 低阶页帧池 → 伙伴分配器 的顺序进行。
+//!
+//! 池的大小为0时（默认），本模块不做任何事情。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    libs::spinlock::SpinLock,
+    mm::{MemoryManagementArch, PhysAddr},
+};
+
+use super::{
+    frame_tag::{frame_tag_clear, FRAME_TAG_UNTAGGED},
+    page_frame::{AllocFlags, PageFrameCount},
+    scrub::FrameList,
+};
+
+/// 池的最大容量（与侵入式链表的容量一致）
+const LOWORDER_POOL_MAX_FRAMES: usize = 4096;
+
+/// 池的大小（页帧数量），为0表示不启用
+static LOWORDER_POOL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// 池中的空闲页帧
+static LOWORDER_POOL: SpinLock<FrameList> = SpinLock::new(FrameList::new());
+
+/// 设置低阶页帧池的大小
+///
+/// 池每次补充时，从伙伴分配器中分配一个大小为`frames`（向上取整到2的幂，至少为2）的连续块。
+/// 缩小池的大小时，超出的页帧会被归还给伙伴分配器。
+///
+/// ## 参数
+///
+/// - `frames`：池的大小（页帧数量），为0表示不启用。超过`LOWORDER_POOL_MAX_FRAMES`时会被截断
+pub fn set_loworder_pool_size(frames: usize) {
+    let frames = frames.min(LOWORDER_POOL_MAX_FRAMES);
+    LOWORDER_POOL_SIZE.store(frames, Ordering::SeqCst);
+
+    let mut pool = LOWORDER_POOL.lock_irqsave();
+    while pool.count() > frames {
+        let paddr = pool.pop().unwrap();
+        unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+    }
+}
+
+/// 获取低阶页帧池的大小
+pub fn loworder_pool_size() -> usize {
+    return LOWORDER_POOL_SIZE.load(Ordering::Relaxed);
+}
+
+/// 获取池中空闲页帧的数量
+pub fn loworder_pool_free() -> PageFrameCount {
+    return PageFrameCount::new(LOWORDER_POOL.lock_irqsave().count());
+}
+
+/// 把池中所有的页帧归还给伙伴分配器（池仍然保持启用），在内存不足时使用
+///
+/// ## 返回值
+///
+/// 归还的页帧数量
+pub fn loworder_pool_drain() -> usize {
+    let mut pool = LOWORDER_POOL.lock_irqsave();
+    let mut drained = 0;
+    while let Some(paddr) = pool.pop() {
+        unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+        drained += 1;
+    }
+    return drained;
+}
+
+/// 从池中分配一个页帧。如果池为空，先从伙伴分配器中批量补充
+///
+/// ## 返回值
+///
+/// 如果池未启用，或者补充失败，返回None，此时调用者应当直接从伙伴分配器中分配
+pub fn loworder_pool_pop() -> Option<PhysAddr> {
+    let size = loworder_pool_size();
+    if size == 0 {
+        return None;
+    }
+
+    if let Some(paddr) = LOWORDER_POOL.lock_irqsave().pop() {
+        return Some(paddr);
+    }
+
+    // 批量补充：从伙伴分配器中分配一个连续的块（至少为2页，因此不会递归地进入本函数）。
+    // 分配时不能持有池的锁：空闲页帧低于低水位线时，分配器会通过`loworder_pool_drain`清空池
+    let batch = PageFrameCount::new(size.next_power_of_two().max(2));
+    let (base, allocated) = unsafe {
+        LockedFrameAllocator.allocate_flags(batch, AllocFlags::NOWARN, FRAME_TAG_UNTAGGED)
+    }?;
+    // 池中的页帧不属于任何调用者
+    frame_tag_clear(base, allocated);
+    // 第一页直接返回给调用者，其余的放入池中
+    let mut pool = LOWORDER_POOL.lock_irqsave();
+    for i in 1..allocated.data() {
+        let paddr = base + i * MMArch::PAGE_SIZE;
+        if !pool.push(paddr) {
+            unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+        }
+    }
+    return Some(base);
+}
+
+/// 把一个被释放的页帧放回池中
+///
+/// ## 返回值
+///
+/// 如果池未启用，或者池已满，返回false，此时调用者应当把页帧归还给伙伴分配器
+pub fn loworder_pool_push(paddr: PhysAddr) -> bool {
+    let size = loworder_pool_size();
+    if size == 0 {
+        return false;
+    }
+    let mut pool = LOWORDER_POOL.lock_irqsave();
+    if pool.count() >= size {
+        return false;
+    }
+    return pool.push(paddr);
+}
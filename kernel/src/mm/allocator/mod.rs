@@ -1,5 +1,15 @@
 pub mod buddy;
 pub mod bump;
+pub mod cma;
+pub mod early_heap;
+pub mod emergency;
+pub mod frame_cache;
+pub mod frame_tag;
+pub mod free_check;
+pub mod huge_pool;
 pub mod kernel_allocator;
+pub mod loworder_pool;
 pub mod page_frame;
+pub mod pressure;
+pub mod scrub;
 pub mod slab;
@@ -1,13 +1,21 @@
 use core::{
     intrinsics::unlikely,
+    iter::Zip,
     ops::{Add, AddAssign, Mul, Sub, SubAssign},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
+use alloc::{collections::BTreeMap, vec::Vec};
+
 use crate::{
     arch::{mm::LockedFrameAllocator, MMArch},
+    libs::spinlock::SpinLock,
     mm::{MemoryManagementArch, PhysAddr, VirtAddr},
+    syscall::SystemError,
 };
 
+use super::frame_tag::{FrameTag, FRAME_TAG_UNTAGGED};
+
 /// @brief 物理页帧的表示
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct PhysPageFrame {
@@ -148,6 +156,92 @@ impl Iterator for VirtPageFrameIter {
     }
 }
 
+/// 按页遍历一段地址范围的迭代器
+///
+/// 从起始地址开始，依次产生count个按页对齐的地址
+#[derive(Debug, Clone)]
+pub struct PageRange<T> {
+    current: T,
+    /// 剩余的页面数量
+    remain: usize,
+}
+
+impl PageRange<VirtAddr> {
+    /// 构造虚拟地址的页迭代器
+    ///
+    /// ## 参数
+    ///
+    /// - `start`：起始虚拟地址（必须按页对齐）
+    /// - `count`：页面数量
+    pub fn virt(start: VirtAddr, count: PageFrameCount) -> Self {
+        assert!(
+            start.check_aligned(MMArch::PAGE_SIZE),
+            "PageRange: unaligned start {:?}",
+            start
+        );
+        return Self {
+            current: start,
+            remain: count.data(),
+        };
+    }
+}
+
+impl PageRange<PhysAddr> {
+    /// 构造物理地址的页迭代器
+    ///
+    /// ## 参数
+    ///
+    /// - `start`：起始物理地址（必须按页对齐）
+    /// - `count`：页面数量
+    pub fn phys(start: PhysAddr, count: PageFrameCount) -> Self {
+        assert!(
+            start.check_aligned(MMArch::PAGE_SIZE),
+            "PageRange: unaligned start {:?}",
+            start
+        );
+        return Self {
+            current: start,
+            remain: count.data(),
+        };
+    }
+}
+
+impl<T: Copy + Add<usize, Output = T>> Iterator for PageRange<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if unlikely(self.remain == 0) {
+            return None;
+        }
+        let current = self.current;
+        self.remain -= 1;
+        if self.remain != 0 {
+            self.current = self.current + MMArch::PAGE_SIZE;
+        }
+        return Some(current);
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        return (self.remain, Some(self.remain));
+    }
+}
+
+impl<T: Copy + Add<usize, Output = T>> ExactSizeIterator for PageRange<T> {}
+
+/// 同时遍历一段虚拟地址与其对应的物理地址的迭代器，每次产生(虚拟地址, 物理地址)
+pub type PageMapRange = Zip<PageRange<VirtAddr>, PageRange<PhysAddr>>;
+
+/// 构造同时遍历虚拟地址与物理地址的页迭代器（用于恒等映射、线性偏移映射等场景）
+///
+/// ## 参数
+///
+/// - `virt`：起始虚拟地址（必须按页对齐）
+/// - `phys`：起始物理地址（必须按页对齐）
+/// - `count`：页面数量
+pub fn page_map_range(virt: VirtAddr, phys: PhysAddr, count: PageFrameCount) -> PageMapRange {
+    return PageRange::virt(virt, count).zip(PageRange::phys(phys, count));
+}
+
 /// 页帧使用的数量
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 #[repr(transparent)]
@@ -253,20 +347,54 @@ impl Mul<usize> for PageFrameCount {
 }
 
 // 页帧使用情况
-#[derive(Debug)]
+//
+// 除了总数和已使用的数量之外，还包含分配器能够以较小的开销提供的明细，用于构建`meminfo`/`sysinfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PageFrameUsage {
     used: PageFrameCount,
     total: PageFrameCount,
+    /// 保留给关键分配的页帧数量（低水位线），普通的分配不能使用它们
+    reserved: PageFrameCount,
+    /// 位于每个CPU的页帧缓存中的页帧数量（被统计在已使用的部分中，但可以立即被分配）
+    percpu_cached: PageFrameCount,
+    /// 被固定（不能被换出或者迁移）的页帧数量
+    pinned: PageFrameCount,
 }
 
 #[allow(dead_code)]
 impl PageFrameUsage {
-    /// @brief:  初始化FrameUsage
+    /// @brief:  初始化FrameUsage（明细均为0）
     /// @param PageFrameCount used 已使用的页帧数量
     /// @param PageFrameCount total 总的页帧数量
     pub fn new(used: PageFrameCount, total: PageFrameCount) -> Self {
-        return Self { used, total };
+        return Self {
+            used,
+            total,
+            reserved: PageFrameCount::new(0),
+            percpu_cached: PageFrameCount::new(0),
+            pinned: PageFrameCount::new(0),
+        };
     }
+
+    /// 填入使用情况的明细
+    ///
+    /// ## 参数
+    ///
+    /// - `reserved`：保留给关键分配的页帧数量
+    /// - `percpu_cached`：位于每个CPU的页帧缓存中的页帧数量
+    /// - `pinned`：被固定的页帧数量
+    pub fn with_breakdown(
+        mut self,
+        reserved: PageFrameCount,
+        percpu_cached: PageFrameCount,
+        pinned: PageFrameCount,
+    ) -> Self {
+        self.reserved = reserved;
+        self.percpu_cached = percpu_cached;
+        self.pinned = pinned;
+        return self;
+    }
+
     // @brief 获取已使用的页帧数量
     pub fn used(&self) -> PageFrameCount {
         return self.used;
@@ -279,6 +407,104 @@ impl PageFrameUsage {
     pub fn total(&self) -> PageFrameCount {
         return self.total;
     }
+    /// 获取保留给关键分配的页帧数量
+    pub fn reserved(&self) -> PageFrameCount {
+        return self.reserved;
+    }
+    /// 获取位于每个CPU的页帧缓存中的页帧数量
+    pub fn percpu_cached(&self) -> PageFrameCount {
+        return self.percpu_cached;
+    }
+    /// 获取被固定的页帧数量
+    pub fn pinned(&self) -> PageFrameCount {
+        return self.pinned;
+    }
+    /// 获取已使用的内存大小（字节）
+    pub fn used_bytes(&self) -> usize {
+        return self.used.bytes();
+    }
+    /// 获取空闲的内存大小（字节）
+    pub fn free_bytes(&self) -> usize {
+        return self.free().bytes();
+    }
+    /// 获取总的内存大小（字节）
+    pub fn total_bytes(&self) -> usize {
+        return self.total.bytes();
+    }
+}
+
+/// 被固定的页帧数量
+static PINNED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// 记录count个页帧被固定（比如正在进行DMA的用户页帧），它们会被统计在`PageFrameUsage::pinned`中
+pub fn pin_frames(count: PageFrameCount) {
+    PINNED_FRAMES.fetch_add(count.data(), Ordering::Relaxed);
+}
+
+/// 记录count个页帧被解除固定，与`pin_frames`配对使用
+pub fn unpin_frames(count: PageFrameCount) {
+    let old = PINNED_FRAMES.fetch_sub(count.data(), Ordering::Relaxed);
+    debug_assert!(old >= count.data(), "unpin_frames: unbalanced unpin");
+}
+
+/// 获取被固定的页帧数量
+pub fn pinned_frames() -> PageFrameCount {
+    return PageFrameCount::new(PINNED_FRAMES.load(Ordering::Relaxed));
+}
+
+/// 被`pin_frame`固定的页帧，以及它们被固定的次数
+static PINNED_FRAME_TABLE: SpinLock<BTreeMap<PhysAddr, usize>> = SpinLock::new(BTreeMap::new());
+
+/// 固定一个页帧（比如对它发起DMA之前）。被固定的页帧不能被迁移或者换出，直到对应次数的`unpin_frame`
+///
+/// 页帧第一次被固定时，会被统计在`PageFrameUsage::pinned`中
+pub fn pin_frame(paddr: PhysAddr) {
+    let mut table = PINNED_FRAME_TABLE.lock_irqsave();
+    let count = table.entry(paddr).or_insert(0);
+    if *count == 0 {
+        pin_frames(PageFrameCount::new(1));
+    }
+    *count += 1;
+}
+
+/// 解除`pin_frame`对页帧的一次固定
+pub fn unpin_frame(paddr: PhysAddr) {
+    let mut table = PINNED_FRAME_TABLE.lock_irqsave();
+    let count = table
+        .get_mut(&paddr)
+        .expect("unpin_frame: frame is not pinned");
+    *count -= 1;
+    if *count == 0 {
+        table.remove(&paddr);
+        unpin_frames(PageFrameCount::new(1));
+    }
+}
+
+/// 页帧是否被`pin_frame`固定
+pub fn frame_pinned(paddr: PhysAddr) -> bool {
+    return PINNED_FRAME_TABLE.lock_irqsave().contains_key(&paddr);
+}
+
+/// NUMA节点的数量。内核目前还不解析ACPI SRAT，所有的物理内存都属于节点0
+pub const NUMA_NODES: usize = 1;
+
+/// 获取物理地址所在的NUMA节点
+pub fn frame_node(_paddr: PhysAddr) -> usize {
+    return 0;
+}
+
+bitflags! {
+    /// 页帧分配的标志
+    pub struct AllocFlags: u32 {
+        /// 关键的分配（比如为了处理缺页异常而分配页表），允许使用低水位线以下的保留页帧
+        const CRITICAL = 1 << 0;
+        /// 分配失败时不打印警告信息
+        const NOWARN = 1 << 1;
+        /// 分配之后，将页帧清零
+        const ZERO = 1 << 2;
+        /// 分配的页帧必须全部位于4GB以下的物理地址空间
+        const DMA32 = 1 << 3;
+    }
 }
 
 /// 能够分配页帧的分配器需要实现的trait
@@ -298,6 +524,16 @@ pub trait FrameAllocator {
     }
     // @brief 获取页帧使用情况
     unsafe fn usage(&self) -> PageFrameUsage;
+
+    /// 获取分配count个页帧时，分配器所保证的物理地址对齐（字节）
+    ///
+    /// 默认只保证按页对齐。调用者不应假定比这个值更强的对齐
+    fn alignment_of(_count: PageFrameCount) -> usize
+    where
+        Self: Sized,
+    {
+        return MMArch::PAGE_SIZE;
+    }
 }
 
 /// @brief 通过一个 &mut T 的引用来对一个实现了 FrameAllocator trait 的类型进行调用，使代码更加灵活
@@ -317,6 +553,9 @@ impl<T: FrameAllocator> FrameAllocator for &mut T {
     unsafe fn usage(&self) -> PageFrameUsage {
         return T::usage(self);
     }
+    fn alignment_of(count: PageFrameCount) -> usize {
+        return T::alignment_of(count);
+    }
 }
 
 /// @brief 从全局的页帧分配器中分配连续count个页帧
@@ -336,3 +575,101 @@ pub unsafe fn deallocate_page_frames(frame: PhysPageFrame, count: PageFrameCount
         LockedFrameAllocator.free(frame.phys_address(), count);
     }
 }
+
+/// 一组已经从全局页帧分配器中分配的页帧块的所有者
+///
+/// 被drop时，会把所有的页帧块归还给页帧分配器，除非调用了`into_inner`取走它们的所有权。
+/// 因此在“分配若干页帧，中途失败时全部释放”的代码中，提前返回时不需要手动释放。
+#[derive(Debug, Default)]
+pub struct FrameGuard {
+    /// （起始物理地址，页帧数量）
+    frames: Vec<(PhysAddr, PageFrameCount)>,
+}
+
+impl FrameGuard {
+    pub fn new() -> Self {
+        return Self { frames: Vec::new() };
+    }
+
+    /// 把一个页帧块交给守卫管理
+    pub fn push(&mut self, paddr: PhysAddr, count: PageFrameCount) {
+        self.frames.push((paddr, count));
+    }
+
+    /// 获取守卫所管理的页帧块
+    pub fn frames(&self) -> &[(PhysAddr, PageFrameCount)] {
+        return &self.frames;
+    }
+
+    /// 守卫所管理的页帧块的数量
+    pub fn len(&self) -> usize {
+        return self.frames.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.frames.is_empty();
+    }
+
+    /// 取走所有页帧块的所有权，之后守卫被drop时不会释放它们
+    pub fn into_inner(mut self) -> Vec<(PhysAddr, PageFrameCount)> {
+        return core::mem::take(&mut self.frames);
+    }
+}
+
+impl Drop for FrameGuard {
+    fn drop(&mut self) {
+        for (paddr, count) in self.frames.drain(..) {
+            unsafe { LockedFrameAllocator.free(paddr, count) };
+        }
+    }
+}
+
+/// 从全局的页帧分配器中分配n个页帧块，每块包含count个页帧
+///
+/// 如果其中某一块分配失败，已经分配的块会被全部释放。
+///
+/// ## 返回值
+///
+/// - 成功：返回管理这些页帧块的守卫
+/// - `ENOMEM`：没有足够的物理内存
+pub fn alloc_frames(count: PageFrameCount, n: usize) -> Result<FrameGuard, SystemError> {
+    return alloc_frames_flags(count, n, AllocFlags::empty(), FRAME_TAG_UNTAGGED);
+}
+
+/// 以指定的分配标志和标签（参见`frame_tag`）分配n个页帧块，每块包含count个页帧（参见`alloc_frames`）
+pub fn alloc_frames_flags(
+    count: PageFrameCount,
+    n: usize,
+    flags: AllocFlags,
+    tag: FrameTag,
+) -> Result<FrameGuard, SystemError> {
+    let mut guard = FrameGuard::new();
+    for _ in 0..n {
+        let (paddr, allocated) = unsafe { LockedFrameAllocator.allocate_flags(count, flags, tag) }
+            .ok_or(SystemError::ENOMEM)?;
+        guard.push(paddr, allocated);
+    }
+    return Ok(guard);
+}
+
+/// 从指定的NUMA节点上分配n个页帧块，每块包含count个页帧（参见`alloc_frames`）
+///
+/// ## 返回值
+///
+/// - 成功：返回管理这些页帧块的守卫，所有页帧都位于node上
+/// - `EINVAL`：节点不存在
+/// - `ENOMEM`：节点上没有足够的物理内存
+pub fn alloc_frames_on_node(
+    count: PageFrameCount,
+    n: usize,
+    node: usize,
+    tag: FrameTag,
+) -> Result<FrameGuard, SystemError> {
+    if node >= NUMA_NODES {
+        return Err(SystemError::EINVAL);
+    }
+    // 只有一个节点，全局分配器分配的页帧都位于这个节点上
+    let guard = alloc_frames_flags(count, n, AllocFlags::empty(), tag)?;
+    debug_assert!(guard.frames().iter().all(|(p, _)| frame_node(*p) == node));
+    return Ok(guard);
+}
@@ -0,0 +1,122 @@
+//! 内存压力的回调
+//!
+//! 缓存等可以被收缩的内存使用者通过`register_low_memory_callback`登记一个阈值（页帧数）以及回调函数。
+//! 当某一次从伙伴分配器分配页帧使空闲页帧的数量从不低于阈值变为低于阈值时，回调函数会被调用。
+//! 空闲页帧的数量来自伙伴分配器在`usage()`中使用的已分配页帧的计数。
+//!
+//! 分配器在释放全局分配器的锁之后才调用`low_memory_check`，回调函数被调用时也不持有登记表的锁，
+//! 因此回调函数中可以分配、释放页帧，也可以登记或者取消登记回调函数。
+//! 回调函数在分配页帧的上下文中被调用（可能关闭了中断），不能睡眠。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{libs::spinlock::SpinLock, syscall::SystemError};
+
+use super::page_frame::PageFrameCount;
+
+/// 最多可以登记的回调函数的数量
+pub const MAX_LOW_MEMORY_CALLBACKS: usize = 16;
+
+/// `register_low_memory_callback`返回的句柄，用于取消登记
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LowMemoryHandle(usize);
+
+#[derive(Clone, Copy)]
+struct LowMemoryCallback {
+    handle: LowMemoryHandle,
+    threshold: usize,
+    callback: fn(),
+}
+
+/// 登记表。分配页帧的路径上不能分配堆内存，因此使用固定大小的数组
+static LOW_MEMORY_CALLBACKS: SpinLock<[Option<LowMemoryCallback>; MAX_LOW_MEMORY_CALLBACKS]> =
+    SpinLock::new([None; MAX_LOW_MEMORY_CALLBACKS]);
+/// 已经登记的回调函数的数量（没有登记时，`low_memory_check`不需要获取锁）
+static LOW_MEMORY_CALLBACKS_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// 下一个句柄的值
+static NEXT_LOW_MEMORY_HANDLE: AtomicUsize = AtomicUsize::new(1);
+
+/// 登记一个内存压力的回调函数
+///
+/// ## 参数
+///
+/// - `threshold_frames`：阈值（页帧数）。分配页帧使空闲页帧的数量降到这个值以下时，调用回调函数
+/// - `callback`：回调函数
+///
+/// ## 返回值
+///
+/// - 成功：用于取消登记的句柄
+/// - `EINVAL`：阈值为0
+/// - `ENOSPC`：登记的回调函数已经达到`MAX_LOW_MEMORY_CALLBACKS`个
+pub fn register_low_memory_callback(
+    threshold_frames: usize,
+    callback: fn(),
+) -> Result<LowMemoryHandle, SystemError> {
+    if threshold_frames == 0 {
+        return Err(SystemError::EINVAL);
+    }
+    let mut callbacks = LOW_MEMORY_CALLBACKS.lock_irqsave();
+    let slot = callbacks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(SystemError::ENOSPC)?;
+    let handle = LowMemoryHandle(NEXT_LOW_MEMORY_HANDLE.fetch_add(1, Ordering::Relaxed));
+    *slot = Some(LowMemoryCallback {
+        handle,
+        threshold: threshold_frames,
+        callback,
+    });
+    LOW_MEMORY_CALLBACKS_COUNT.fetch_add(1, Ordering::Release);
+    return Ok(handle);
+}
+
+/// 取消登记一个内存压力的回调函数
+///
+/// 请注意，其他CPU上可能正在调用这个回调函数，取消登记之后它仍然可能被调用一次
+///
+/// ## 返回值
+///
+/// - `EINVAL`：句柄没有被登记（或者已经被取消登记）
+pub fn unregister_low_memory_callback(handle: LowMemoryHandle) -> Result<(), SystemError> {
+    let mut callbacks = LOW_MEMORY_CALLBACKS.lock_irqsave();
+    let slot = callbacks
+        .iter_mut()
+        .find(|slot| slot.map_or(false, |c| c.handle == handle))
+        .ok_or(SystemError::EINVAL)?;
+    *slot = None;
+    LOW_MEMORY_CALLBACKS_COUNT.fetch_sub(1, Ordering::Release);
+    return Ok(());
+}
+
+/// 一次分配使空闲页帧的数量从`free_before`变为`free_after`之后调用，调用所有阈值位于两者之间的回调函数
+///
+/// 调用者不能持有全局分配器的锁
+///
+/// ## 返回值
+///
+/// 被调用的回调函数的数量
+pub fn low_memory_check(free_before: PageFrameCount, free_after: PageFrameCount) -> usize {
+    if LOW_MEMORY_CALLBACKS_COUNT.load(Ordering::Acquire) == 0
+        || free_after.data() >= free_before.data()
+    {
+        return 0;
+    }
+
+    let mut fired: [Option<fn()>; MAX_LOW_MEMORY_CALLBACKS] = [None; MAX_LOW_MEMORY_CALLBACKS];
+    let mut count = 0;
+    {
+        let callbacks = LOW_MEMORY_CALLBACKS.lock_irqsave();
+        for c in callbacks.iter().flatten() {
+            if free_before.data() >= c.threshold && free_after.data() < c.threshold {
+                fired[count] = Some(c.callback);
+                count += 1;
+            }
+        }
+    }
+
+    // 不持有登记表的锁，回调函数中可以再次分配页帧
+    for callback in fired.iter().flatten() {
+        callback();
+    }
+    return count;
+}
@@ -0,0 +1,170 @@
+//! 空闲页帧的后台清零（scrub）
+//!
+//! 在释放页帧时清零会增加释放路径的延迟，在分配时清零则会增加分配路径的延迟。
+//! 启用后台清零之后，被释放的单个页帧会先被放入“脏”链表，
+//! 然后由低优先级的例程（比如idle循环）调用`scrub_some`，把它们清零并移动到“干净”链表。
+//! 需要清零的单页分配会优先使用“干净”链表中的页帧，从而跳过清零的步骤。
+//!
+//! 后台清零默认关闭，通过`scrub=on`启动参数启用。idle循环通过`rs_mm_idle_work`调用`scrub_some`。
+//!
+//! 链表是侵入式的：每个页帧的前8个字节用于存放下一个页帧的物理地址。
+//! 从“干净”链表中取出页帧时，会把这8个字节重新清零。
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    libs::spinlock::SpinLock,
+    mm::{MemoryManagementArch, PhysAddr},
+};
+
+use super::page_frame::PageFrameCount;
+
+/// 每个链表最多容纳的页帧数量，超出的部分直接归还给伙伴分配器
+const SCRUB_LIST_CAPACITY: usize = 4096;
+
+/// 是否启用后台清零
+static SCRUB_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 待清零的空闲页帧
+static DIRTY_FRAMES: SpinLock<FrameList> = SpinLock::new(FrameList::new());
+/// 已经清零的空闲页帧
+static CLEAN_FRAMES: SpinLock<FrameList> = SpinLock::new(FrameList::new());
+
+/// 侵入式的页帧链表
+pub(super) struct FrameList {
+    head: PhysAddr,
+    count: usize,
+}
+
+impl FrameList {
+    pub(super) const fn new() -> Self {
+        return Self {
+            head: PhysAddr::new(0),
+            count: 0,
+        };
+    }
+
+    pub(super) fn push(&mut self, paddr: PhysAddr) -> bool {
+        if self.count >= SCRUB_LIST_CAPACITY {
+            return false;
+        }
+        unsafe { MMArch::write(MMArch::phys_2_virt(paddr).unwrap(), self.head) };
+        self.head = paddr;
+        self.count += 1;
+        return true;
+    }
+
+    pub(super) fn pop(&mut self) -> Option<PhysAddr> {
+        if self.count == 0 {
+            return None;
+        }
+        let paddr = self.head;
+        let vaddr = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+        self.head = unsafe { MMArch::read(vaddr) };
+        // 清除存放在页帧开头的链表指针
+        unsafe { MMArch::write(vaddr, PhysAddr::new(0)) };
+        self.count -= 1;
+        return Some(paddr);
+    }
+
+    pub(super) fn count(&self) -> usize {
+        return self.count;
+    }
+}
+
+/// 后台清零的统计信息
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubStats {
+    /// 待清零的空闲页帧数量
+    pub dirty: PageFrameCount,
+    /// 已经清零的空闲页帧数量
+    pub clean: PageFrameCount,
+}
+
+/// 启用或者关闭后台清零
+///
+/// 关闭时，链表中剩余的页帧会被归还给伙伴分配器
+pub fn scrub_set_enabled(enabled: bool) {
+    SCRUB_ENABLED.store(enabled, Ordering::SeqCst);
+    if !enabled {
+        for list in [&DIRTY_FRAMES, &CLEAN_FRAMES] {
+            loop {
+                let paddr = list.lock_irqsave().pop();
+                match paddr {
+                    Some(paddr) => unsafe {
+                        LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1))
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// 判断后台清零是否已经启用
+#[inline(always)]
+pub fn scrub_enabled() -> bool {
+    return SCRUB_ENABLED.load(Ordering::Relaxed);
+}
+
+/// 把一个被释放的页帧放入“脏”链表
+///
+/// ## 返回值
+///
+/// 如果后台清零未启用，或者链表已满，返回false，此时调用者应当把页帧归还给伙伴分配器
+pub fn scrub_push_dirty(paddr: PhysAddr) -> bool {
+    if !scrub_enabled() {
+        return false;
+    }
+    return DIRTY_FRAMES.lock_irqsave().push(paddr);
+}
+
+/// 从“干净”链表中取出一个已经清零的页帧
+pub fn scrub_pop_clean() -> Option<PhysAddr> {
+    return CLEAN_FRAMES.lock_irqsave().pop();
+}
+
+/// 从“脏”链表或者“干净”链表中取出一个页帧（不保证已经清零）
+///
+/// 当伙伴分配器中没有空闲页帧时，用于回收链表中的页帧
+pub fn scrub_pop_any() -> Option<PhysAddr> {
+    return DIRTY_FRAMES
+        .lock_irqsave()
+        .pop()
+        .or_else(|| CLEAN_FRAMES.lock_irqsave().pop());
+}
+
+/// 清零最多max_frames个“脏”页帧，并把它们移动到“干净”链表
+///
+/// 该函数应当在低优先级的上下文中（比如idle循环）调用
+///
+/// ## 返回值
+///
+/// 本次清零的页帧数量
+pub fn scrub_some(max_frames: usize) -> usize {
+    let mut scrubbed = 0;
+    while scrubbed < max_frames {
+        let paddr = match DIRTY_FRAMES.lock_irqsave().pop() {
+            Some(paddr) => paddr,
+            None => break,
+        };
+
+        // 在不持有锁的情况下清零。被清零的页帧不一定很快会被使用，因此使用非临时存储，避免污染缓存
+        unsafe { MMArch::zero_frames_nt(paddr, PageFrameCount::new(1)) };
+
+        if !CLEAN_FRAMES.lock_irqsave().push(paddr) {
+            unsafe { LockedFrameAllocator.free_to_buddy(paddr, PageFrameCount::new(1)) };
+        }
+        scrubbed += 1;
+    }
+    return scrubbed;
+}
+
+/// 获取后台清零的统计信息
+pub fn scrub_stats() -> ScrubStats {
+    return ScrubStats {
+        dirty: PageFrameCount::new(DIRTY_FRAMES.lock_irqsave().count),
+        clean: PageFrameCount::new(CLEAN_FRAMES.lock_irqsave().count),
+    };
+}
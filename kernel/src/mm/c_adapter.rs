@@ -6,7 +6,7 @@ use alloc::vec::Vec;
 use hashbrown::HashMap;
 
 use crate::{
-    arch::mm::LowAddressRemapping,
+    arch::mm::{mark_ro_after_init, LowAddressRemapping},
     include::bindings::bindings::{gfp_t, PAGE_U_S},
     kerror,
     libs::{align::page_align_up, spinlock::SpinLock},
@@ -15,8 +15,8 @@ use crate::{
 };
 
 use super::{
-    allocator::page_frame::PageFrameCount, kernel_mapper::KernelMapper, no_init::pseudo_map_phys,
-    page::PageFlags, MemoryManagementArch, PhysAddr, VirtAddr,
+    allocator::page_frame::PageFrameCount, error::MmError, kernel_mapper::KernelMapper,
+    no_init::pseudo_map_phys, page::PageFlags, MemoryManagementArch, PhysAddr, VirtAddr,
 };
 
 lazy_static! {
@@ -41,7 +41,8 @@ pub unsafe extern "C" fn rs_map_phys(vaddr: usize, paddr: usize, size: usize, fl
     let count = PageFrameCount::new(page_align_up(size) / MMArch::PAGE_SIZE);
     // kdebug!("rs_map_phys: vaddr: {vaddr:?}, paddr: {paddr:?}, count: {count:?}, flags: {flags:?}");
 
-    let mut page_flags: PageFlags<MMArch> = PageFlags::new().set_execute(true).set_write(true);
+    // C代码通过这个函数映射的都是MMIO或者ACPI表之类的数据，不需要可执行
+    let mut page_flags: PageFlags<MMArch> = PageFlags::new().set_write(true);
     if flags & PAGE_U_S as usize != 0 {
         page_flags = page_flags.set_user(true);
     }
@@ -50,11 +51,15 @@ pub unsafe extern "C" fn rs_map_phys(vaddr: usize, paddr: usize, size: usize, fl
     let mut kernel_mapper = kernel_mapper.as_mut();
     assert!(kernel_mapper.is_some());
     for _i in 0..count.data() {
-        let flusher = kernel_mapper
-            .as_mut()
-            .unwrap()
-            .map_phys(vaddr, paddr, page_flags)
-            .unwrap();
+        let mapper = kernel_mapper.as_mut().unwrap();
+        // C代码可能会重复映射同一段地址（比如在启动早期已经映射过的帧缓冲区），此时用新的映射替换原有的映射
+        let flusher = match mapper.map_phys(vaddr, paddr, page_flags) {
+            Err(MmError::AlreadyMapped(_)) => {
+                mapper.unmap_phys(vaddr, false).unwrap().2.ignore();
+                mapper.map_phys(vaddr, paddr, page_flags).unwrap()
+            }
+            r => r.unwrap(),
+        };
 
         flusher.flush();
 
@@ -133,3 +138,8 @@ pub unsafe extern "C" fn rs_unmap_at_low_addr() -> usize {
     LowAddressRemapping::unmap_at_low_address(true);
     return 0;
 }
+
+#[no_mangle]
+pub extern "C" fn rs_mark_ro_after_init() {
+    mark_ro_after_init();
+}
@@ -0,0 +1,189 @@
+//! 物理页帧的缓存类型检查
+//!
+//! 以不同的缓存类型（比如直接映射区域的WB，与MMIO映射的UC）映射同一个物理页帧，
+//! 在体系结构上是未定义行为。这里记录以非默认缓存类型映射的物理内存区域，
+//! 并在建立映射的时候检查是否存在冲突。
+//!
+//! RAM默认的缓存类型为WB（所有的RAM都在直接映射区域中以WB的方式映射），
+//! 因此以UC/WT的方式映射RAM页帧总是被视为冲突，除非同时修改了该页帧在直接映射区域中的缓存类型，
+//! 并通过`cache_type_annotate_ram`记录下来（参见`mm::scratch`）。
+
+use alloc::vec::Vec;
+
+use crate::{arch::MMArch, kwarn, libs::spinlock::SpinLock};
+
+use super::{error::MmError, page::PageFlags, MemoryManagementArch, PhysAddr};
+
+/// 页面的缓存类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// 回写（默认）
+    WriteBack,
+    /// 写透
+    WriteThrough,
+    /// 不可缓存
+    Uncacheable,
+    /// 写合并（只能通过PAT选择）
+    WriteCombining,
+    /// 写保护（只能通过PAT选择）
+    WriteProtected,
+}
+
+impl CacheType {
+    /// 根据页表项的flags，获取页面的缓存类型
+    ///
+    /// 这里只考虑PCD、PWT位，并假设PAT为上电时的默认值。需要考虑PAT位以及实际的PAT设置时，
+    /// 使用`PageMapper::effective_cache_type`
+    pub fn from_flags<Arch: MemoryManagementArch>(flags: &PageFlags<Arch>) -> Self {
+        if flags.has_page_cache_disable() {
+            return CacheType::Uncacheable;
+        } else if flags.has_page_write_through() {
+            return CacheType::WriteThrough;
+        } else {
+            return CacheType::WriteBack;
+        }
+    }
+}
+
+/// 以非默认缓存类型映射的物理内存区域
+#[derive(Debug, Clone, Copy)]
+struct CacheTypeAnnotation {
+    base: PhysAddr,
+    size: usize,
+    cache_type: CacheType,
+}
+
+/// 所有以非默认缓存类型映射的物理内存区域（区域之间互不重叠）
+static CACHE_TYPE_ANNOTATIONS: SpinLock<Vec<CacheTypeAnnotation>> = SpinLock::new(Vec::new());
+
+/// 判断物理页帧是否位于RAM中（由页帧分配器管理），而不是MMIO等设备内存
+pub fn is_ram(paddr: PhysAddr) -> bool {
+    return MMArch::phys_memory_areas()
+        .iter()
+        .any(|area| paddr >= area.base && paddr.data() < area.base.data() + area.size);
+}
+
+/// 在记录中查找物理页帧的缓存类型
+fn lookup_annotation(paddr: PhysAddr) -> Option<CacheType> {
+    return CACHE_TYPE_ANNOTATIONS
+        .lock_irqsave()
+        .iter()
+        .find(|a| paddr >= a.base && paddr.data() < a.base.data() + a.size)
+        .map(|a| a.cache_type);
+}
+
+/// 检查以flags映射物理页帧是否会与已有的映射产生缓存类型的冲突
+///
+/// 在debug模式下，冲突会导致返回错误；在release模式下，只打印警告
+pub fn check_cache_type<Arch: MemoryManagementArch>(
+    paddr: PhysAddr,
+    flags: &PageFlags<Arch>,
+) -> Result<(), MmError> {
+    let requested = CacheType::from_flags(flags);
+    // 以WB的方式映射RAM是最常见的情况（比如建立直接映射区域），不需要遍历RAM区域。
+    // 被记录过的RAM页帧（直接映射区域中的缓存类型已经被修改）以记录为准
+    let existing = match lookup_annotation(paddr) {
+        Some(annotated) => Some(annotated),
+        None if requested != CacheType::WriteBack && is_ram(paddr) => Some(CacheType::WriteBack),
+        None => None,
+    };
+    let existing = match existing {
+        Some(existing) if existing != requested => existing,
+        _ => return Ok(()),
+    };
+
+    let err = MmError::CacheTypeConflict {
+        phys: paddr,
+        existing,
+        requested,
+    };
+    if cfg!(debug_assertions) {
+        return Err(err);
+    }
+    kwarn!("{}", err);
+    return Ok(());
+}
+
+/// 记录一段物理内存以指定的缓存类型被映射
+///
+/// ## 返回值
+///
+/// 如果与已有的记录（或者RAM）冲突，返回`MmError::CacheTypeConflict`
+pub fn cache_type_annotate(
+    base: PhysAddr,
+    size: usize,
+    cache_type: CacheType,
+) -> Result<(), MmError> {
+    let mut annotations = CACHE_TYPE_ANNOTATIONS.lock_irqsave();
+    let end = base.data() + size;
+    let conflict = annotations
+        .iter()
+        .find(|a| base.data() < a.base.data() + a.size && a.base.data() < end)
+        .map(|a| (a.base, a.cache_type));
+    if let Some((phys, existing)) = conflict {
+        return Err(MmError::CacheTypeConflict {
+            phys,
+            existing,
+            requested: cache_type,
+        });
+    }
+    if cache_type != CacheType::WriteBack && is_ram(base) {
+        return Err(MmError::CacheTypeConflict {
+            phys: base,
+            existing: CacheType::WriteBack,
+            requested: cache_type,
+        });
+    }
+
+    annotations.push(CacheTypeAnnotation {
+        base,
+        size,
+        cache_type,
+    });
+    return Ok(());
+}
+
+/// 记录一段RAM以指定的缓存类型被映射
+///
+/// 与`cache_type_annotate`不同，这里允许记录RAM。调用者需要保证这段RAM在直接映射区域中的映射
+/// 已经（或者即将在建立其他映射之前）被修改为相同的缓存类型
+///
+/// ## 返回值
+///
+/// 如果与已有的记录冲突，返回`MmError::CacheTypeConflict`
+pub fn cache_type_annotate_ram(
+    base: PhysAddr,
+    size: usize,
+    cache_type: CacheType,
+) -> Result<(), MmError> {
+    let mut annotations = CACHE_TYPE_ANNOTATIONS.lock_irqsave();
+    let end = base.data() + size;
+    if let Some(a) = annotations
+        .iter()
+        .find(|a| base.data() < a.base.data() + a.size && a.base.data() < end)
+    {
+        return Err(MmError::CacheTypeConflict {
+            phys: a.base,
+            existing: a.cache_type,
+            requested: cache_type,
+        });
+    }
+    annotations.push(CacheTypeAnnotation {
+        base,
+        size,
+        cache_type,
+    });
+    return Ok(());
+}
+
+/// 获取一个物理页帧被记录的缓存类型。如果没有被记录，返回None
+pub fn cache_type_of(paddr: PhysAddr) -> Option<CacheType> {
+    return lookup_annotation(paddr);
+}
+
+/// 删除由`cache_type_annotate`记录的物理内存区域
+pub fn cache_type_release(base: PhysAddr) {
+    CACHE_TYPE_ANNOTATIONS
+        .lock_irqsave()
+        .retain(|a| a.base != base);
+}
@@ -0,0 +1,71 @@
+use core::fmt;
+
+use crate::syscall::SystemError;
+
+use super::{allocator::page_frame::PageFrameCount, cache_type::CacheType, PhysAddr, VirtAddr};
+
+/// 内存管理模块内部使用的错误类型
+///
+/// 与`SystemError`相比，`MmError`携带了出错的地址、所需的页数等上下文信息，便于调试。
+/// 在返回给系统调用等外部调用者时，可以通过`From`转换为对应的`SystemError`。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmError {
+    /// 内存不足
+    OutOfMemory { needed: PageFrameCount },
+    /// 虚拟地址不是规范地址
+    NotCanonical(VirtAddr),
+    /// 地址没有按页对齐
+    Unaligned(usize),
+    /// 虚拟地址已经被映射
+    AlreadyMapped(VirtAddr),
+    /// 虚拟地址没有被映射
+    NotMapped(VirtAddr),
+    /// 虚拟地址不在页表所管理的范围内（比如在用户页表中操作内核地址）
+    KernelRangeViolation(VirtAddr),
+    /// 物理页帧已经以另一种缓存类型被映射
+    CacheTypeConflict {
+        phys: PhysAddr,
+        existing: CacheType,
+        requested: CacheType,
+    },
+}
+
+impl From<MmError> for SystemError {
+    fn from(value: MmError) -> Self {
+        match value {
+            MmError::OutOfMemory { .. } => SystemError::ENOMEM,
+            MmError::NotCanonical(_) => SystemError::EINVAL,
+            MmError::Unaligned(_) => SystemError::EINVAL,
+            MmError::AlreadyMapped(_) => SystemError::EEXIST,
+            MmError::NotMapped(_) => SystemError::EFAULT,
+            MmError::KernelRangeViolation(_) => SystemError::EPERM,
+            MmError::CacheTypeConflict { .. } => SystemError::EBUSY,
+        }
+    }
+}
+
+impl fmt::Display for MmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MmError::OutOfMemory { needed } => {
+                write!(f, "out of memory, {} page(s) needed", needed.data())
+            }
+            MmError::NotCanonical(virt) => write!(f, "{:?} is not canonical", virt),
+            MmError::Unaligned(addr) => write!(f, "address {:#x} is not page aligned", addr),
+            MmError::AlreadyMapped(virt) => write!(f, "{:?} is already mapped", virt),
+            MmError::NotMapped(virt) => write!(f, "{:?} is not mapped", virt),
+            MmError::KernelRangeViolation(virt) => {
+                write!(f, "{:?} is out of the range of the page table", virt)
+            }
+            MmError::CacheTypeConflict {
+                phys,
+                existing,
+                requested,
+            } => write!(
+                f,
+                "{:?} is already mapped as {:?}, but {:?} is requested",
+                phys, existing, requested
+            ),
+        }
+    }
+}
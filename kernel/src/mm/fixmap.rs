@@ -0,0 +1,134 @@
+//! 固定映射（fixmap）
+//!
+//! 一组在编译时就确定了虚拟地址的槽位，可以在运行时映射到任意的物理页帧。
+//! 早期控制台、APIC等驱动可以在MMIO伙伴分配器可用之前，通过它们获得稳定的虚拟地址。
+//!
+//! 固定映射的虚拟地址空间位于跳板页之后，不会被其他模块使用。
+//! `fixmap_init`预先为它创建中间级的页表，使得之后创建的用户地址空间在复制内核部分的顶级页表项时，
+//! 能够看到固定映射。
+
+use crate::{
+    arch::{interrupt::ipi::send_ipi, mm::LockedFrameAllocator, MMArch},
+    exception::ipi::{IpiKind, IpiTarget},
+    include::bindings::bindings::smp_get_total_cpu,
+    syscall::SystemError,
+};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount},
+    kernel_mapper::KernelMapper,
+    page::PageFlags,
+    MemoryManagementArch, PhysAddr, VirtAddr,
+};
+
+/// 固定映射的虚拟地址空间的起始地址（位于跳板页的虚拟地址空间之后，独占一个顶级页表项）
+pub const FIXMAP_BASE: VirtAddr = VirtAddr::new(0xffffa40000000000);
+
+/// 固定映射的槽位，每个槽位占用一个页面
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum FixmapSlot {
+    /// 早期控制台
+    EarlyConsole = 0,
+    /// Local APIC的寄存器
+    LocalApic = 1,
+    /// I/O APIC的寄存器
+    IoApic = 2,
+    /// 临时使用（比如测试）
+    Scratch = 3,
+}
+
+impl FixmapSlot {
+    /// 槽位的数量
+    pub const COUNT: usize = 4;
+
+    /// 槽位的虚拟地址
+    pub const fn vaddr(self) -> VirtAddr {
+        return VirtAddr::new(FIXMAP_BASE.data() + self as usize * MMArch::PAGE_SIZE);
+    }
+}
+
+/// 固定映射的虚拟地址空间的大小
+pub const FIXMAP_SIZE: usize = FixmapSlot::COUNT * MMArch::PAGE_SIZE;
+
+/// 判断虚拟地址是否位于固定映射的虚拟地址空间中
+pub fn in_fixmap_area(vaddr: VirtAddr) -> bool {
+    return vaddr >= FIXMAP_BASE && vaddr.data() < FIXMAP_BASE.data() + FIXMAP_SIZE;
+}
+
+/// 初始化固定映射的虚拟地址空间（预先创建中间级的页表），需要在创建第一个用户地址空间之前调用
+pub fn fixmap_init() {
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper
+        .as_mut()
+        .expect("fixmap_init: kernel mapper is readonly");
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("fixmap_init: out of memory");
+    unsafe {
+        mapper
+            .map_phys(FIXMAP_BASE, paddr, PageFlags::new())
+            .expect("fixmap_init: failed to map")
+            .flush();
+        // 只取消最后一级的映射，保留中间级的页表
+        let (_, _, flusher) = mapper
+            .unmap_phys(FIXMAP_BASE, false)
+            .expect("fixmap_init: failed to unmap");
+        flusher.flush();
+        LockedFrameAllocator.free(paddr, PageFrameCount::new(1));
+    }
+}
+
+/// 把固定映射的槽位映射到物理页帧
+///
+/// ## 参数
+///
+/// - `slot`：槽位
+/// - `paddr`：物理地址（必须按页对齐）
+/// - `flags`：页面标志（对于MMIO，通常为`PageFlags::mmio_flags()`）
+///
+/// ## 返回值
+///
+/// - 成功：返回槽位的虚拟地址
+/// - `EINVAL`：paddr没有按页对齐
+/// - `EBUSY`：槽位已经被映射
+/// - `EAGAIN_OR_EWOULDBLOCK`：当前CPU已经持有内核映射器的锁
+/// - `ENOMEM`：无法分配页表
+pub fn set_fixmap(
+    slot: FixmapSlot,
+    paddr: PhysAddr,
+    flags: PageFlags<MMArch>,
+) -> Result<VirtAddr, SystemError> {
+    if !paddr.check_aligned(MMArch::PAGE_SIZE) {
+        return Err(SystemError::EINVAL);
+    }
+    let vaddr = slot.vaddr();
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper.as_mut().ok_or(SystemError::EAGAIN_OR_EWOULDBLOCK)?;
+    if mapper.translate(vaddr).is_some() {
+        return Err(SystemError::EBUSY);
+    }
+    // 槽位之前没有被映射，TLB中不会有它的翻译，只需要刷新本地的TLB
+    unsafe { mapper.map_phys(vaddr, paddr, flags) }?.flush();
+    return Ok(vaddr);
+}
+
+/// 取消固定映射的槽位的映射（物理页帧不会被释放）
+///
+/// ## 返回值
+///
+/// - 成功：返回槽位原来映射到的物理地址
+/// - `EINVAL`：槽位没有被映射
+/// - `EAGAIN_OR_EWOULDBLOCK`：当前CPU已经持有内核映射器的锁
+pub fn clear_fixmap(slot: FixmapSlot) -> Result<PhysAddr, SystemError> {
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper.as_mut().ok_or(SystemError::EAGAIN_OR_EWOULDBLOCK)?;
+    // 保留中间级的页表，以便之后复用
+    let (paddr, _, flusher) =
+        unsafe { mapper.unmap_phys(slot.vaddr(), false) }.map_err(|_| SystemError::EINVAL)?;
+    flusher.flush();
+    // 其他CPU可能访问过这个槽位
+    if unsafe { smp_get_total_cpu() } > 1 {
+        send_ipi(IpiKind::FlushTLB, IpiTarget::Other);
+    }
+    return Ok(paddr);
+}
@@ -0,0 +1,147 @@
+//! initrd的内核映射
+//!
+//! initrd由bootloader加载到物理内存中（multiboot2的模块）。启动时，体系结构相关的代码解析出它的物理地址范围，
+//! 通过`initrd_reserve`记录下来，并保证这些页帧不会被页帧分配器分配出去：
+//! 伙伴分配器初始化之后，这些页帧以“已分配”的状态属于页帧分配器。
+//!
+//! 内核通过`map_initrd`获得initrd的只读映射，使用完毕之后，调用`release_initrd`取消映射并释放页帧。
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    kinfo,
+    libs::{align::page_align_up, spinlock::SpinLock},
+    syscall::SystemError,
+};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount},
+    kernel_mapper::KernelMapper,
+    mmio_buddy::mmio_pool,
+    page::PageFlags,
+    MemoryManagementArch, PhysAddr, VirtAddr,
+};
+
+/// initrd所在的内存区域
+#[derive(Debug, Clone, Copy)]
+struct Initrd {
+    /// 起始物理地址（不一定按页对齐）
+    start: PhysAddr,
+    /// 大小（字节）
+    size: usize,
+    /// 映射的MMIO虚拟地址空间的起始地址以及长度
+    mapping: Option<(VirtAddr, usize)>,
+}
+
+impl Initrd {
+    /// initrd所占用的第一个页帧
+    fn frame_base(&self) -> PhysAddr {
+        return PhysAddr::new(self.start.data() & !(MMArch::PAGE_SIZE - 1));
+    }
+
+    /// initrd所占用的页帧的数量
+    fn frame_count(&self) -> PageFrameCount {
+        let end = page_align_up(self.start.data() + self.size);
+        return PageFrameCount::new((end - self.frame_base().data()) / MMArch::PAGE_SIZE);
+    }
+}
+
+static INITRD: SpinLock<Option<Initrd>> = SpinLock::new(None);
+
+/// 记录initrd所在的物理内存范围
+///
+/// 调用者需要保证这段内存所在的页帧以“已分配”的状态属于页帧分配器（或者在伙伴分配器初始化时被这样处理），
+/// 因为`release_initrd`会把它们归还给页帧分配器。
+///
+/// ## 返回值
+///
+/// - `EINVAL`：大小为0
+/// - `EEXIST`：已经记录了initrd
+pub fn initrd_reserve(start: PhysAddr, size: usize) -> Result<(), SystemError> {
+    if size == 0 {
+        return Err(SystemError::EINVAL);
+    }
+    let mut initrd = INITRD.lock_irqsave();
+    if initrd.is_some() {
+        return Err(SystemError::EEXIST);
+    }
+    *initrd = Some(Initrd {
+        start,
+        size,
+        mapping: None,
+    });
+    return Ok(());
+}
+
+/// 获取initrd所占用的页帧的范围（起始页帧的物理地址，页帧数量）
+pub fn initrd_frames() -> Option<(PhysAddr, PageFrameCount)> {
+    return INITRD
+        .lock_irqsave()
+        .map(|initrd| (initrd.frame_base(), initrd.frame_count()));
+}
+
+/// 把initrd以只读、不可执行的方式映射到内核地址空间中
+///
+/// 重复调用时，返回已经建立的映射
+///
+/// ## 返回值
+///
+/// - 成功：返回（initrd的起始虚拟地址，initrd的大小）
+/// - `ENOENT`：没有initrd
+/// - `ENOMEM`：MMIO地址空间不足
+pub fn map_initrd() -> Result<(VirtAddr, usize), SystemError> {
+    let mut guard = INITRD.lock_irqsave();
+    let initrd = guard.as_mut().ok_or(SystemError::ENOENT)?;
+    let offset = initrd.start.data() - initrd.frame_base().data();
+    if let Some((vaddr, _)) = initrd.mapping {
+        return Ok((vaddr + offset, initrd.size));
+    }
+
+    let map_size = initrd.frame_count().bytes();
+    let mut vaddr: u64 = 0;
+    let mut vaddr_len: u64 = 0;
+    mmio_pool().create_mmio(map_size, 0, &mut vaddr, &mut vaddr_len)?;
+    let vaddr = VirtAddr::new(vaddr as usize);
+
+    // PageFlags::new()默认为只读、不可执行
+    unsafe {
+        KernelMapper::lock().map_phys_with_size(
+            vaddr,
+            initrd.frame_base(),
+            map_size,
+            PageFlags::new(),
+            true,
+        )
+    }?;
+    initrd.mapping = Some((vaddr, vaddr_len as usize));
+    kinfo!(
+        "initrd: {:?}+{:#x} mapped at {:?}",
+        initrd.start,
+        initrd.size,
+        vaddr + offset
+    );
+    return Ok((vaddr + offset, initrd.size));
+}
+
+/// 取消initrd的映射，并把它所占用的页帧归还给页帧分配器
+///
+/// 调用之后，之前由`map_initrd`返回的地址不再有效
+///
+/// ## 返回值
+///
+/// - 成功：返回被释放的页帧数量
+/// - `ENOENT`：没有initrd（或者已经被释放）
+pub fn release_initrd() -> Result<PageFrameCount, SystemError> {
+    let initrd = INITRD.lock_irqsave().take().ok_or(SystemError::ENOENT)?;
+    if let Some((vaddr, vaddr_len)) = initrd.mapping {
+        mmio_pool().release_mmio(vaddr, vaddr_len)?;
+    }
+
+    // initrd的大小不一定是2的幂，因此逐页释放（伙伴分配器会合并相邻的页帧）
+    let base = initrd.frame_base();
+    let count = initrd.frame_count();
+    for i in 0..count.data() {
+        unsafe { LockedFrameAllocator.free(base + i * MMArch::PAGE_SIZE, PageFrameCount::new(1)) };
+    }
+    kinfo!("initrd: released {} frame(s)", count.data());
+    return Ok(count);
+}
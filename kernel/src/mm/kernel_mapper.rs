@@ -1,24 +1,34 @@
-use super::{page::PageFlags, PageTableKind, PhysAddr, VirtAddr};
+use super::{
+    page::{Flusher, PageFlags, PageFlushAll},
+    PageTableKind, PhysAddr, VirtAddr,
+};
 use crate::{
     arch::{
-        mm::{LockedFrameAllocator, PageMapper},
+        mm::{LockedFrameAllocator, LowAddressRemapping, PageMapper},
         CurrentIrqArch,
     },
     exception::InterruptArch,
     libs::align::page_align_up,
-    mm::allocator::page_frame::PageFrameCount,
+    mm::allocator::page_frame::{FrameAllocator, PageFrameCount},
+    mm::mmio_buddy::mmio_pool,
     mm::{MMArch, MemoryManagementArch},
     smp::core::smp_get_processor_id,
     syscall::SystemError,
 };
 use core::{
-    ops::Deref,
+    ops::{Deref, Range},
     sync::atomic::{compiler_fence, AtomicUsize, Ordering},
 };
 
 /// 标志当前没有处理器持有内核映射器的锁
 /// 之所以需要这个标志，是因为AtomicUsize::new(0)会把0当作一个处理器的id
 const KERNEL_MAPPER_NO_PROCESSOR: usize = !0;
+/// 按需建立的恒等映射（虚拟地址 == 物理地址）所允许使用的地址范围
+///
+/// 位于低地址重映射之上，直到4G（固件调用、设备初始化所需的物理地址通常在4G以下）。
+/// 这个范围位于低半部分，不会与直接映射区域以及内核镜像重叠
+pub const IDENTITY_MAP_WINDOW: Range<usize> = LowAddressRemapping::REMAP_SIZE..0x1_0000_0000;
+
 /// 当前持有内核映射器锁的处理器
 static KERNEL_MAPPER_LOCK_OWNER: AtomicUsize = AtomicUsize::new(KERNEL_MAPPER_NO_PROCESSOR);
 /// 内核映射器的锁计数器
@@ -81,6 +91,44 @@ impl KernelMapper {
         return &self.mapper;
     }
 
+    /// 查找与内核空间的虚拟地址最近的已映射区域，用于在缺页异常时报告访问越界之类的错误
+    ///
+    /// ## 返回值
+    ///
+    /// (下方最近的已映射区域的结束地址, 上方最近的已映射区域的起始地址)
+    pub fn nearest_mappings(&self, vaddr: VirtAddr) -> (Option<VirtAddr>, Option<VirtAddr>) {
+        // 内核空间从直接映射区域的起始地址开始，直到地址空间的最高处
+        return self.mapper.nearest_mappings(
+            vaddr,
+            VirtAddr::new(MMArch::PHYS_OFFSET)..VirtAddr::new(usize::MAX),
+        );
+    }
+
+    /// 在内核空间的[window_start, window_end)范围内，从低地址向高地址查找一段没有被映射的虚拟地址范围（用于vmap）
+    ///
+    /// ## 参数
+    ///
+    /// - `window_start`：查找范围的起始地址（不能低于直接映射区域的起始地址）
+    /// - `window_end`：查找范围的结束地址
+    /// - `len`：长度（字节）
+    /// - `align`：起始地址的对齐（必须是2的幂）
+    ///
+    /// ## 返回值
+    ///
+    /// 找到的范围的起始地址。如果没有足够大的空洞，返回None
+    pub fn find_free_range(
+        &self,
+        window_start: VirtAddr,
+        window_end: VirtAddr,
+        len: usize,
+        align: usize,
+    ) -> Option<VirtAddr> {
+        let window_start = window_start.max(VirtAddr::new(MMArch::PHYS_OFFSET));
+        return self
+            .mapper
+            .find_free_range(window_start..window_end, len, align, false);
+    }
+
     /// 映射一段物理地址到指定的虚拟地址。
     ///
     /// ## 参数
@@ -94,7 +142,7 @@ impl KernelMapper {
     /// ## 返回
     ///
     /// - 成功：返回Ok(())
-    /// - 失败： 如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK
+    /// - 失败： 如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK；如果空闲的页帧不足以分配所需的页表，则返回ENOMEM
     pub unsafe fn map_phys_with_size(
         &mut self,
         mut vaddr: VirtAddr,
@@ -110,6 +158,16 @@ impl KernelMapper {
         let count = PageFrameCount::new(page_align_up(size) / MMArch::PAGE_SIZE);
         // kdebug!("kernel mapper: map_phys: vaddr: {vaddr:?}, paddr: {paddr:?}, count: {count:?}, flags: {flags:?}");
 
+        // 映射的范围较大时，预先检查空闲的页帧是否足够分配所需的页表，避免映射到一半时失败
+        if count.data() > MMArch::PAGE_ENTRY_NUM {
+            let needed = self
+                .mapper
+                .estimate_table_cost(vaddr, count, MMArch::PAGE_SIZE);
+            if self.mapper.allocator_ref().usage().free().data() < needed.data() {
+                return Err(SystemError::ENOMEM);
+            }
+        }
+
         for _ in 0..count.data() {
             let flusher = self.mapper.map_phys(vaddr, paddr, flags).unwrap();
 
@@ -122,6 +180,210 @@ impl KernelMapper {
         }
         return Ok(());
     }
+
+    /// 检查恒等映射的范围，返回虚拟地址的范围
+    fn identity_range(
+        paddr: PhysAddr,
+        count: PageFrameCount,
+    ) -> Result<Range<VirtAddr>, SystemError> {
+        if count.data() == 0 || !paddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+        let end = paddr
+            .data()
+            .checked_add(count.bytes())
+            .ok_or(SystemError::EINVAL)?;
+        if paddr.data() < IDENTITY_MAP_WINDOW.start || end > IDENTITY_MAP_WINDOW.end {
+            return Err(SystemError::EINVAL);
+        }
+        // 直接映射区域以及内核镜像都位于PHYS_OFFSET之上
+        assert!(end <= MMArch::PHYS_OFFSET && MMArch::virt_is_valid(VirtAddr::new(end - 1)));
+        return Ok(VirtAddr::new(paddr.data())..VirtAddr::new(end));
+    }
+
+    /// 为一段物理地址建立临时的恒等映射（虚拟地址 == 物理地址），用于固件调用、设备初始化等
+    ///
+    /// 映射只存在于内核页表中（不会出现在用户进程的页表里），使用完毕之后需要通过`identity_unmap`取消
+    ///
+    /// ## 参数
+    ///
+    /// - `paddr`：起始物理地址（必须按页对齐）
+    /// - `count`：页帧的数量
+    /// - `flags`：页面标志
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回刷新器，调用者需要刷新TLB
+    /// - `EAGAIN_OR_EWOULDBLOCK`：当前映射器为只读
+    /// - `EINVAL`：范围为空、没有按页对齐，或者不在`IDENTITY_MAP_WINDOW`之内
+    /// - `EEXIST`：范围内已经有页面被映射
+    /// - `ENOMEM`：无法分配页表（已经建立的映射会被取消）
+    pub unsafe fn identity_map(
+        &mut self,
+        paddr: PhysAddr,
+        count: PageFrameCount,
+        flags: PageFlags<MMArch>,
+    ) -> Result<PageFlushAll<MMArch>, SystemError> {
+        if self.readonly {
+            return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+        }
+        let range = Self::identity_range(paddr, count)?;
+        for i in 0..count.data() {
+            if self
+                .mapper
+                .translate(range.start + i * MMArch::PAGE_SIZE)
+                .is_some()
+            {
+                return Err(SystemError::EEXIST);
+            }
+        }
+
+        let mut flusher = PageFlushAll::new();
+        for i in 0..count.data() {
+            let vaddr = range.start + i * MMArch::PAGE_SIZE;
+            match self
+                .mapper
+                .map_phys(vaddr, paddr + i * MMArch::PAGE_SIZE, flags)
+            {
+                Ok(f) => flusher.consume(f),
+                Err(_) => {
+                    for k in 0..i {
+                        if let Ok((_, _, f)) = self
+                            .mapper
+                            .unmap_phys(range.start + k * MMArch::PAGE_SIZE, true)
+                        {
+                            flusher.consume(f);
+                        }
+                    }
+                    flusher.flush();
+                    return Err(SystemError::ENOMEM);
+                }
+            }
+        }
+        return Ok(flusher);
+    }
+
+    /// 取消`identity_map`建立的恒等映射，并释放不再使用的页表
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回刷新器，调用者需要刷新TLB
+    /// - `EAGAIN_OR_EWOULDBLOCK`：当前映射器为只读
+    /// - `EINVAL`：范围不合法，或者范围内有页面没有被恒等映射（此时不会取消任何映射）
+    pub unsafe fn identity_unmap(
+        &mut self,
+        paddr: PhysAddr,
+        count: PageFrameCount,
+    ) -> Result<PageFlushAll<MMArch>, SystemError> {
+        if self.readonly {
+            return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+        }
+        let range = Self::identity_range(paddr, count)?;
+        for i in 0..count.data() {
+            let vaddr = range.start + i * MMArch::PAGE_SIZE;
+            match self.mapper.translate(vaddr) {
+                Some((mapped, _)) if mapped.data() == vaddr.data() => {}
+                _ => return Err(SystemError::EINVAL),
+            }
+        }
+
+        let mut flusher = PageFlushAll::new();
+        for i in 0..count.data() {
+            let (_, _, f) = self
+                .mapper
+                .unmap_phys(range.start + i * MMArch::PAGE_SIZE, true)
+                .expect("identity mapped page should be unmappable");
+            flusher.consume(f);
+        }
+        return Ok(flusher);
+    }
+
+    /// 为指定的物理页帧建立一个临时的可写别名映射，并在该映射上执行闭包。
+    ///
+    /// 当内核需要修改一个通常以只读方式映射的页面（比如热补丁时修改内核代码段）时，
+    /// 应当使用这个方法，而不是通过清除CR0.WP来全局关闭写保护。
+    /// 原有的只读映射不会被修改。
+    ///
+    /// 别名映射所使用的虚拟地址槽位由内核映射器的锁保护，因此同一时刻只会有一个使用者。
+    ///
+    /// ## 参数
+    ///
+    /// - `paddr`: 要写入的物理页帧的地址（必须按页对齐）
+    /// - `f`: 要执行的闭包，参数为可写别名的虚拟地址
+    ///
+    /// ## 返回
+    ///
+    /// - 成功：返回闭包的返回值
+    /// - 失败：如果当前映射器为只读，则返回EAGAIN_OR_EWOULDBLOCK；如果paddr未对齐，则返回EINVAL；
+    ///   如果无法分配槽位，则返回ENOMEM
+    pub fn with_writable_alias<R>(
+        &mut self,
+        paddr: PhysAddr,
+        f: impl FnOnce(VirtAddr) -> R,
+    ) -> Result<R, SystemError> {
+        if self.readonly {
+            return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+        }
+        if !paddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+
+        let slot = writable_alias_slot()?;
+        let flags = PageFlags::new().set_write(true);
+        unsafe {
+            self.mapper.map_phys(slot, paddr, flags)?.flush();
+        }
+
+        // 即使闭包提前退出（比如发生panic），守卫也会在被drop时清除槽位的映射
+        let guard = WritableAliasGuard {
+            mapper: &mut self.mapper,
+            slot,
+        };
+        let r = f(guard.slot);
+        drop(guard);
+
+        return Ok(r);
+    }
+}
+
+/// 可写别名槽位的守卫，被drop时取消槽位的映射
+struct WritableAliasGuard<'a> {
+    mapper: &'a mut PageMapper,
+    slot: VirtAddr,
+}
+
+impl Drop for WritableAliasGuard<'_> {
+    fn drop(&mut self) {
+        // 只取消槽位的映射，不释放物理页帧，也不回收中间页表（以便下次复用）
+        let (_, _, flusher) = unsafe { self.mapper.unmap_phys(self.slot, false) }
+            .expect("writable alias slot should be mapped");
+        // 槽位只会在持有内核映射器锁的情况下被访问，因此只需要刷新本地的TLB
+        flusher.flush();
+    }
+}
+
+/// 可写别名所使用的虚拟地址槽位（0表示尚未分配）
+static WRITABLE_ALIAS_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// 获取可写别名所使用的虚拟地址槽位。第一次调用时，从MMIO地址空间中分配一个页面大小的槽位。
+///
+/// 调用者必须持有内核映射器的锁
+fn writable_alias_slot() -> Result<VirtAddr, SystemError> {
+    let slot = WRITABLE_ALIAS_SLOT.load(Ordering::Relaxed);
+    if slot != 0 {
+        return Ok(VirtAddr::new(slot));
+    }
+
+    let mut vaddr: u64 = 0;
+    let mut len: u64 = 0;
+    mmio_pool().create_mmio(
+        MMArch::PAGE_SIZE,
+        0,
+        &mut vaddr as *mut u64,
+        &mut len as *mut u64,
+    )?;
+    WRITABLE_ALIAS_SLOT.store(vaddr as usize, Ordering::Relaxed);
+    return Ok(VirtAddr::new(vaddr as usize));
 }
 
 impl Drop for KernelMapper {
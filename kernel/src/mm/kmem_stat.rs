@@ -0,0 +1,100 @@
+//! 内核自身内存使用情况的统计
+//!
+//! 各个子系统在为自身分配/释放页帧的时候，更新对应用途的计数器，
+//! 从而可以知道内核的内存都被用在了哪些地方。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kinfo;
+
+use super::allocator::page_frame::PageFrameCount;
+
+/// 内核内存的用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KernelMemPurpose {
+    /// 页表（不包括直接映射区域的页表）
+    PageTable = 0,
+    /// 内核堆（slab）
+    Slab = 1,
+    /// 从页帧分配器中分配、并映射到直接映射区域以外（MMIO地址空间）的页帧，
+    /// 比如不可缓存的临时页面（`scratch`）以及带保护页的内核栈（`stack_guard`）
+    Vmap = 2,
+    /// 直接映射区域所使用的页表
+    DirectMap = 3,
+    /// 保留的内存（内核镜像、伙伴分配器的元数据等）
+    Reserved = 4,
+}
+
+impl KernelMemPurpose {
+    const COUNT: usize = 5;
+}
+
+/// 每种用途所占用的页帧数量
+static KMEM_COUNTERS: [AtomicUsize; KernelMemPurpose::COUNT] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// 内核内存使用情况的统计信息
+#[derive(Debug, Clone, Copy)]
+pub struct KernelMemStats {
+    pub page_table: PageFrameCount,
+    pub slab: PageFrameCount,
+    pub vmap: PageFrameCount,
+    pub direct_map: PageFrameCount,
+    pub reserved: PageFrameCount,
+}
+
+impl KernelMemStats {
+    /// 内核自身占用的总页帧数
+    pub fn total(&self) -> PageFrameCount {
+        return self.page_table + self.slab + self.vmap + self.direct_map + self.reserved;
+    }
+}
+
+/// 记录指定用途新增占用了count个页帧
+#[inline(always)]
+pub fn kmem_stat_add(purpose: KernelMemPurpose, count: PageFrameCount) {
+    KMEM_COUNTERS[purpose as usize].fetch_add(count.data(), Ordering::Relaxed);
+}
+
+/// 记录指定用途释放了count个页帧
+#[inline(always)]
+pub fn kmem_stat_sub(purpose: KernelMemPurpose, count: PageFrameCount) {
+    KMEM_COUNTERS[purpose as usize].fetch_sub(count.data(), Ordering::Relaxed);
+}
+
+/// 获取指定用途当前占用的页帧数
+#[inline(always)]
+pub fn kmem_stat_get(purpose: KernelMemPurpose) -> PageFrameCount {
+    return PageFrameCount::new(KMEM_COUNTERS[purpose as usize].load(Ordering::Relaxed));
+}
+
+/// 获取内核内存使用情况的分类统计
+pub fn kernel_mem_breakdown() -> KernelMemStats {
+    return KernelMemStats {
+        page_table: kmem_stat_get(KernelMemPurpose::PageTable),
+        slab: kmem_stat_get(KernelMemPurpose::Slab),
+        vmap: kmem_stat_get(KernelMemPurpose::Vmap),
+        direct_map: kmem_stat_get(KernelMemPurpose::DirectMap),
+        reserved: kmem_stat_get(KernelMemPurpose::Reserved),
+    };
+}
+
+/// 打印内核内存使用情况的分类统计（用于调试）
+#[no_mangle]
+pub extern "C" fn rs_dump_kernel_mem_breakdown() {
+    let stats = kernel_mem_breakdown();
+    kinfo!(
+        "Kernel memory: page_table={} KB, slab={} KB, vmap={} KB, direct_map={} KB, reserved={} KB, total={} KB",
+        stats.page_table.bytes() / 1024,
+        stats.slab.bytes() / 1024,
+        stats.vmap.bytes() / 1024,
+        stats.direct_map.bytes() / 1024,
+        stats.reserved.bytes() / 1024,
+        stats.total().bytes() / 1024
+    );
+}
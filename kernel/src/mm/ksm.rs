@@ -0,0 +1,261 @@
+//! 相同页面合并（KSM）
+//!
+//! 本模块只提供合并两个页面的机制（`try_merge`），扫描页面、选择合并对象的策略不在这里实现。
+//!
+//! 两个内容相同的匿名页面被合并之后，它们以只读、写时复制的方式映射到同一个页帧上（KSM页帧），
+//! 另一个页帧被释放。KSM页帧的引用计数（映射到它的页面数量）记录在本模块中：
+//! - 写入被合并的页面时，`UserMapper::handle_write_fault`会把内容复制到新的页帧，并调用`ksm_put`
+//! - 清空用户地址空间时，`UserMapper::clear_user_space`同样调用`ksm_put`，而不是直接释放KSM页帧
+//!
+//! 引用计数降为0时，由`ksm_put`的调用者释放KSM页帧。
+//!
+//! 这张表也是通用的页帧引用计数表：`inc_ref`/`dec_ref`（以及批量的`inc_ref_bulk`/`dec_ref_bulk`）
+//! 把不在表中的页帧视为只被一个页面映射（引用计数为1）。批量的版本对整批页帧只获取一次锁，
+//! 供需要处理整个地址空间的代码（比如清空用户空间）使用。fork时，`UserMapper::clone_user_mapping`
+//! 通过`inc_ref_bulk`记录被父子进程共享的页帧，取消映射时，页帧在引用计数降为0时才被释放。
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use crate::{arch::MMArch, libs::spinlock::SpinLock, syscall::SystemError};
+
+use super::{
+    allocator::page_frame::{deallocate_page_frames, PageFrameCount, PhysPageFrame},
+    page::PageFlags,
+    ucontext::{zero_frame, AddressSpace, UserMapper},
+    MemoryManagementArch, PhysAddr, VirtAddr,
+};
+
+/// KSM页帧 -> 映射到它的页面数量
+static KSM_FRAMES: SpinLock<BTreeMap<PhysAddr, usize>> = SpinLock::new(BTreeMap::new());
+
+/// 获取KSM页帧的引用计数
+///
+/// ## 返回值
+///
+/// 映射到这个页帧的页面数量。如果页帧不是KSM页帧，返回0
+pub fn ksm_frame_refcount(paddr: PhysAddr) -> usize {
+    return KSM_FRAMES.lock_irqsave().get(&paddr).copied().unwrap_or(0);
+}
+
+/// 减少KSM页帧的引用计数（一个映射到它的页面被取消映射，或者被复制到了新的页帧）
+///
+/// ## 返回值
+///
+/// - `None`：页帧不是KSM页帧，调用者按照通常的方式处理
+/// - `Some(true)`：引用计数降为0，调用者需要释放这个页帧
+/// - `Some(false)`：还有其他页面映射到这个页帧
+pub fn ksm_put(paddr: PhysAddr) -> Option<bool> {
+    let mut frames = KSM_FRAMES.lock_irqsave();
+    let count = frames.get_mut(&paddr)?;
+    *count -= 1;
+    if *count == 0 {
+        frames.remove(&paddr);
+        return Some(true);
+    }
+    return Some(false);
+}
+
+/// 增加页帧的引用计数（又有一个页面映射到这个页帧）
+///
+/// 不在表中的页帧视为只被一个页面映射，增加之后引用计数为2
+pub fn inc_ref(paddr: PhysAddr) {
+    *KSM_FRAMES.lock_irqsave().entry(paddr).or_insert(1) += 1;
+}
+
+/// 减少页帧的引用计数（一个映射到它的页面被取消映射）
+///
+/// ## 返回值
+///
+/// 如果引用计数降为0（包括不在表中的页帧），返回true，调用者需要释放这个页帧
+pub fn dec_ref(paddr: PhysAddr) -> bool {
+    return ksm_put(paddr).unwrap_or(true);
+}
+
+/// 批量增加页帧的引用计数，与对每个页帧调用`inc_ref`的结果相同，但只获取一次锁
+///
+/// 同一个页帧可以在frames中出现多次
+pub fn inc_ref_bulk(frames: &[PhysAddr]) {
+    let mut table = KSM_FRAMES.lock_irqsave();
+    for paddr in frames {
+        *table.entry(*paddr).or_insert(1) += 1;
+    }
+}
+
+/// 批量减少页帧的引用计数，与对每个页帧调用`dec_ref`的结果相同，但只获取一次锁
+///
+/// ## 返回值
+///
+/// 引用计数降为0、需要被释放的页帧（按照在frames中出现的顺序）
+pub fn dec_ref_bulk(frames: &[PhysAddr]) -> Vec<PhysAddr> {
+    let mut table = KSM_FRAMES.lock_irqsave();
+    // 没有共享的页帧时（最常见的情况），不需要逐个查找
+    if table.is_empty() {
+        return frames.to_vec();
+    }
+
+    let mut to_free = Vec::with_capacity(frames.len());
+    for paddr in frames {
+        match table.get_mut(paddr) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    table.remove(paddr);
+                    to_free.push(*paddr);
+                }
+            }
+            None => to_free.push(*paddr),
+        }
+    }
+    return to_free;
+}
+
+/// 尝试合并两个页面
+///
+/// 如果两个页面的内容相同，那么把它们都以只读、写时复制的方式映射到同一个页帧上，增加这个页帧的引用计数，
+/// 并释放另一个页帧。页面必须是已映射的、可写的私有匿名页面，或者已经被合并过的页面（映射到KSM页帧）。
+///
+/// 两个地址空间的锁按照`AddressSpace`的地址从小到大的顺序获取，因此并发的合并操作不会死锁。
+/// 比较内容之前，两个页面都已经被设置为只读，因此比较的结果在合并完成之前不会失效。
+///
+/// 请注意，目前的TLB刷新IPI不会等待其他CPU完成刷新，因此在多核的情况下，
+/// 被释放的页帧在很短的时间内仍然可能被其他CPU上的旧TLB条目访问
+///
+/// ## 参数
+///
+/// - `as_a`, `vaddr_a`：第一个页面所在的地址空间以及虚拟地址（必须按页对齐）
+/// - `as_b`, `vaddr_b`：第二个页面所在的地址空间以及虚拟地址（必须按页对齐）
+///
+/// ## 返回值
+///
+/// - `Ok(true)`：合并成功
+/// - `Ok(false)`：内容不同，或者两个页面已经映射到同一个页帧
+/// - `EINVAL`：虚拟地址没有按页对齐
+/// - `EFAULT`：页面没有被映射
+/// - `EPERM`：页面不能被合并（参见`mergeable_page`）
+pub fn try_merge(
+    as_a: &Arc<AddressSpace>,
+    vaddr_a: VirtAddr,
+    as_b: &Arc<AddressSpace>,
+    vaddr_b: VirtAddr,
+) -> Result<bool, SystemError> {
+    if !(vaddr_a.check_aligned(MMArch::PAGE_SIZE) && vaddr_b.check_aligned(MMArch::PAGE_SIZE)) {
+        return Err(SystemError::EINVAL);
+    }
+
+    if Arc::ptr_eq(as_a, as_b) {
+        if vaddr_a == vaddr_b {
+            return Ok(false);
+        }
+        let mut guard = as_a.write();
+        let mapper = &mut guard.user_mapper;
+        let (paddr_a, flags_a) = mergeable_page(mapper, vaddr_a)?;
+        let (paddr_b, flags_b) = mergeable_page(mapper, vaddr_b)?;
+        if paddr_a == paddr_b {
+            return Ok(false);
+        }
+        write_protect(mapper, vaddr_a, flags_a);
+        write_protect(mapper, vaddr_b, flags_b);
+        if !same_contents(paddr_a, paddr_b) {
+            restore(mapper, vaddr_a, flags_a);
+            restore(mapper, vaddr_b, flags_b);
+            return Ok(false);
+        }
+        share_frame(mapper, vaddr_b, paddr_a, paddr_b)?;
+        return Ok(true);
+    }
+
+    // 按照地址空间的地址，以固定的顺序加锁
+    let a_first = Arc::as_ptr(as_a) < Arc::as_ptr(as_b);
+    let (mut guard_a, mut guard_b) = if a_first {
+        let guard_a = as_a.write();
+        (guard_a, as_b.write())
+    } else {
+        let guard_b = as_b.write();
+        (as_a.write(), guard_b)
+    };
+    let mapper_a = &mut guard_a.user_mapper;
+    let mapper_b = &mut guard_b.user_mapper;
+
+    let (paddr_a, flags_a) = mergeable_page(mapper_a, vaddr_a)?;
+    let (paddr_b, flags_b) = mergeable_page(mapper_b, vaddr_b)?;
+    if paddr_a == paddr_b {
+        return Ok(false);
+    }
+    write_protect(mapper_a, vaddr_a, flags_a);
+    write_protect(mapper_b, vaddr_b, flags_b);
+    if !same_contents(paddr_a, paddr_b) {
+        restore(mapper_a, vaddr_a, flags_a);
+        restore(mapper_b, vaddr_b, flags_b);
+        return Ok(false);
+    }
+    share_frame(mapper_b, vaddr_b, paddr_a, paddr_b)?;
+    return Ok(true);
+}
+
+/// 检查页面是否能被合并
+///
+/// ## 返回值
+///
+/// - 成功：返回页面映射到的页帧以及页表项的flags
+/// - `EFAULT`：页面没有被映射
+/// - `EPERM`：页面不能被合并（不是用户页面，映射到共享零页帧，只读，或者是不属于KSM的写时复制页面）
+fn mergeable_page(
+    mapper: &UserMapper,
+    vaddr: VirtAddr,
+) -> Result<(PhysAddr, PageFlags<MMArch>), SystemError> {
+    let (paddr, flags) = mapper.utable.translate(vaddr).ok_or(SystemError::EFAULT)?;
+    if !flags.has_user() || zero_frame() == Some(paddr) {
+        return Err(SystemError::EPERM);
+    }
+    let is_ksm = ksm_frame_refcount(paddr) != 0;
+    if !(is_ksm || (flags.has_write() && !flags.has_cow())) {
+        return Err(SystemError::EPERM);
+    }
+    return Ok((paddr, flags));
+}
+
+/// 把页面设置为只读的写时复制页面，并刷新TLB
+fn write_protect(mapper: &mut UserMapper, vaddr: VirtAddr, flags: PageFlags<MMArch>) {
+    unsafe { mapper.utable.protect_and_flush(vaddr, flags.set_cow(true)) }
+        .expect("ksm: page was unmapped while holding the address space lock");
+}
+
+/// 恢复页面原来的flags（内容不同，放弃合并）
+fn restore(mapper: &mut UserMapper, vaddr: VirtAddr, flags: PageFlags<MMArch>) {
+    unsafe { mapper.utable.protect_and_flush(vaddr, flags) }
+        .expect("ksm: page was unmapped while holding the address space lock");
+}
+
+/// 比较两个页帧的内容是否相同
+fn same_contents(a: PhysAddr, b: PhysAddr) -> bool {
+    let words = MMArch::PAGE_SIZE / core::mem::size_of::<u64>();
+    let (a, b) = unsafe {
+        (
+            core::slice::from_raw_parts(MMArch::phys_2_virt(a).unwrap().as_ptr::<u64>(), words),
+            core::slice::from_raw_parts(MMArch::phys_2_virt(b).unwrap().as_ptr::<u64>(), words),
+        )
+    };
+    return a == b;
+}
+
+/// 把页面（已经被设置为写时复制）改为映射到`shared`，增加`shared`的引用计数，并释放页面原来的页帧
+fn share_frame(
+    mapper: &mut UserMapper,
+    vaddr: VirtAddr,
+    shared: PhysAddr,
+    old: PhysAddr,
+) -> Result<(), SystemError> {
+    unsafe { mapper.utable.replace_frame(vaddr, shared) }?;
+
+    {
+        let mut frames = KSM_FRAMES.lock_irqsave();
+        // 第一次合并时，页帧被两个页面映射
+        *frames.entry(shared).or_insert(1) += 1;
+    }
+
+    let free_old = ksm_put(old).unwrap_or(true);
+    if free_old {
+        unsafe { deallocate_page_frames(PhysPageFrame::new(old), PageFrameCount::new(1)) };
+    }
+    return Ok(());
+}
@@ -8,6 +8,7 @@
 extern void rs_pseudo_map_phys(uint64_t virt_addr, uint64_t phys_addr, uint64_t size);
 extern void rs_map_phys(uint64_t virt_addr, uint64_t phys_addr, uint64_t size, uint64_t flags);
 extern uint64_t rs_unmap_at_low_addr();
+extern void rs_mark_ro_after_init();
 
 // 内核层的起始地址
 #define PAGE_OFFSET 0xffff800000000000UL
@@ -16,6 +17,12 @@ extern uint64_t rs_unmap_at_low_addr();
 // MMIO虚拟地址空间：1TB
 #define MMIO_BASE 0xffffa10000000000UL
 #define MMIO_TOP 0xffffa20000000000UL
+// 每CPU区域的虚拟地址空间（紧跟在MMIO虚拟地址空间之后，每个CPU 2M）
+#define PERCPU_AREA_BASE 0xffffa20000000000UL
+// KPTI跳板页的虚拟地址空间（2M）
+#define TRAMPOLINE_AREA_BASE 0xffffa30000000000UL
+// 固定映射（fixmap）的虚拟地址空间（每个槽位一个4K页面）
+#define FIXMAP_BASE 0xffffa40000000000UL
 
 #define PAGE_4K_SHIFT 12
 #define PAGE_2M_SHIFT 21
@@ -1,8 +1,12 @@
+use crate::libs::align::page_align_up;
 use crate::libs::spinlock::{SpinLock, SpinLockGuard};
 use crate::mm::kernel_mapper::KernelMapper;
 use crate::syscall::SystemError;
 use crate::{
-    arch::asm::current::current_pcb,
+    arch::{
+        asm::current::current_pcb,
+        mm::{tlb_shootdown_sync, LockedFrameAllocator},
+    },
     include::bindings::bindings::{vm_flags_t, PAGE_1G_SHIFT, PAGE_4K_SHIFT, PAGE_4K_SIZE},
     kdebug,
     mm::{MMArch, MemoryManagementArch},
@@ -11,9 +15,14 @@ use crate::{kerror, kinfo, kwarn};
 use alloc::{collections::LinkedList, vec::Vec};
 use core::mem;
 use core::mem::MaybeUninit;
-use core::sync::atomic::{compiler_fence, Ordering};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
-use super::VirtAddr;
+use super::{
+    allocator::page_frame::PageFrameCount,
+    cache_type::{cache_type_annotate, cache_type_release, CacheType},
+    page::PageFlags,
+    PhysAddr, VirtAddr,
+};
 
 // 最大的伙伴块的幂
 const MMIO_BUDDY_MAX_EXP: u32 = PAGE_1G_SHIFT;
@@ -28,9 +37,15 @@ const MMIO_TOP: VirtAddr = VirtAddr::new(0xffffa20000000000);
 const PAGE_1G_SIZE: usize = 1 << 30;
 
 static mut __MMIO_POOL: Option<MmioBuddyMemPool> = None;
+/// mmio内存池是否已经（或者正在）被初始化
+static MMIO_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 pub fn mmio_pool() -> &'static mut MmioBuddyMemPool {
-    unsafe { __MMIO_POOL.as_mut().unwrap() }
+    unsafe {
+        __MMIO_POOL
+            .as_mut()
+            .expect("mmio pool is not initialized, mmio_init() must be called first")
+    }
 }
 
 pub enum MmioResult {
@@ -41,12 +56,27 @@ pub enum MmioResult {
     ISEMPTY,
 }
 
+/// 通过`map_device`映射的设备内存区域
+#[derive(Debug, Clone, Copy)]
+struct DeviceMmioRegion {
+    /// 设备内存的物理地址
+    paddr: PhysAddr,
+    /// 设备内存的大小（字节，按页对齐）
+    size: usize,
+    /// 映射到的虚拟地址
+    vaddr: VirtAddr,
+    /// 从MMIO地址空间中分配的虚拟地址空间的长度
+    vaddr_len: usize,
+}
+
 /// @brief buddy内存池
 #[derive(Debug)]
 pub struct MmioBuddyMemPool {
     pool_start_addr: VirtAddr,
     pool_size: usize,
     free_regions: [SpinLock<MmioFreeRegionList>; MMIO_BUDDY_REGION_COUNT as usize],
+    /// 已经映射的设备内存区域
+    device_regions: SpinLock<Vec<DeviceMmioRegion>>,
 }
 
 impl MmioBuddyMemPool {
@@ -66,6 +96,7 @@ impl MmioBuddyMemPool {
             pool_start_addr: MMIO_BASE,
             pool_size: MMIO_TOP - MMIO_BASE,
             free_regions,
+            device_regions: SpinLock::new(Vec::new()),
         };
         kdebug!("MMIO buddy pool init: created");
 
@@ -549,18 +580,132 @@ impl MmioBuddyMemPool {
         }
 
         for i in 0..page_count {
-            unsafe {
+            // mmio区域映射的是设备内存（或者固件保留的内存），不属于页帧分配器，因此不能释放物理页
+            let r = unsafe {
                 kernel_mapper
                     .as_mut()
                     .unwrap()
-                    .unmap(vaddr + i * MMArch::PAGE_SIZE, true)
+                    .unmap_phys(vaddr + i * MMArch::PAGE_SIZE, false)
             };
+            if let Ok((_, _, flusher)) = r {
+                flusher.flush();
+            }
+        }
+        // 回收已经变为空的页表（区域中可能有没有被映射的页面，因此不能依赖unmap_parents）
+        let freed = unsafe {
+            kernel_mapper
+                .as_mut()
+                .unwrap()
+                .reclaim_empty_tables(vaddr, PageFrameCount::new(page_count))
+        };
+        if freed > 0 {
+            // 被释放的页表可能还缓存在各个CPU的分页结构缓存中，必须在它被重新分配之前刷新
+            unsafe { MMArch::invalidate_all() };
+            tlb_shootdown_sync();
         }
 
         // todo: 归还到buddy
 
         return Ok(0);
     }
+
+    /// 把一段设备内存（比如PCIe BAR）映射到MMIO地址空间中
+    ///
+    /// 映射默认是不可缓存的。物理地址可以位于4GB以上，只要不超过处理器支持的物理地址位数。
+    ///
+    /// ## 参数
+    ///
+    /// - `paddr`：设备内存的物理地址（必须按页对齐）
+    /// - `size`：设备内存的大小（字节，会向上对齐到页大小）
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回映射得到的虚拟地址
+    /// - `EINVAL`：地址未对齐、大小为0，或者超出了处理器支持的物理地址范围
+    /// - `EBUSY`：与RAM或者已经映射的设备内存区域重叠
+    /// - `ENOMEM`：MMIO地址空间不足
+    pub fn map_device(&self, paddr: PhysAddr, size: usize) -> Result<VirtAddr, SystemError> {
+        if size == 0 || !paddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+        let size = page_align_up(size);
+        let end = paddr.data().checked_add(size).ok_or(SystemError::EINVAL)?;
+        let phys_bits = MMArch::phys_address_bits();
+        if phys_bits < usize::BITS as usize && end > (1usize << phys_bits) {
+            kwarn!(
+                "map_device: {:?}+{:#x} exceeds MAXPHYADDR ({} bits)",
+                paddr,
+                size,
+                phys_bits
+            );
+            return Err(SystemError::EINVAL);
+        }
+
+        let overlaps = |base: usize, len: usize| base < end && paddr.data() < base + len;
+        if MMArch::phys_memory_areas()
+            .iter()
+            .any(|area| overlaps(area.base.data(), area.size))
+        {
+            kwarn!("map_device: {:?}+{:#x} overlaps RAM", paddr, size);
+            return Err(SystemError::EBUSY);
+        }
+
+        let mut regions = self.device_regions.lock();
+        if regions.iter().any(|r| overlaps(r.paddr.data(), r.size)) {
+            kwarn!(
+                "map_device: {:?}+{:#x} overlaps another mapping",
+                paddr,
+                size
+            );
+            return Err(SystemError::EBUSY);
+        }
+
+        let flags = PageFlags::mmio_flags();
+        cache_type_annotate(paddr, size, CacheType::from_flags(&flags))?;
+
+        let mut vaddr: u64 = 0;
+        let mut vaddr_len: u64 = 0;
+        if let Err(e) = self.create_mmio(size, 0, &mut vaddr, &mut vaddr_len) {
+            cache_type_release(paddr);
+            return Err(e);
+        }
+        let vaddr = VirtAddr::new(vaddr as usize);
+        let vaddr_len = vaddr_len as usize;
+
+        let r = unsafe { KernelMapper::lock().map_phys_with_size(vaddr, paddr, size, flags, true) };
+        if let Err(e) = r {
+            self.give_back_block(vaddr, vaddr_len.trailing_zeros()).ok();
+            cache_type_release(paddr);
+            return Err(e);
+        }
+
+        regions.push(DeviceMmioRegion {
+            paddr,
+            size,
+            vaddr,
+            vaddr_len,
+        });
+        return Ok(vaddr);
+    }
+
+    /// 取消由`map_device`建立的设备内存映射
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：`map_device`返回的虚拟地址
+    pub fn unmap_device(&self, vaddr: VirtAddr) -> Result<(), SystemError> {
+        let mut regions = self.device_regions.lock();
+        let index = regions
+            .iter()
+            .position(|r| r.vaddr == vaddr)
+            .ok_or(SystemError::EINVAL)?;
+        let region = regions.remove(index);
+        drop(regions);
+
+        self.release_mmio(region.vaddr, region.vaddr_len)?;
+        cache_type_release(region.paddr);
+        return Ok(());
+    }
 }
 
 /// @brief mmio伙伴系统内部的地址区域结构体
@@ -614,7 +759,46 @@ fn exp2index(exp: u32) -> usize {
     return (exp - 12) as usize;
 }
 
+/// 初始化mmio内存池
+///
+/// 初始化的顺序（见`mm_init`）：`allocator_init`设置全局页帧分配器之后，才能调用本函数，
+/// 因为创建内存池需要在堆上分配内存，而映射mmio区域需要从页帧分配器中分配页表。
+/// 本函数只能被调用一次。
+///
+/// 违反上述要求时，panic并给出原因
 pub fn mmio_init() {
+    if let Err(e) = mmio_try_init(LockedFrameAllocator::is_initialized()) {
+        match e {
+            SystemError::EAGAIN_OR_EWOULDBLOCK => {
+                panic!("mmio_init: frame allocator is not initialized, allocator_init() must run before mmio_init()")
+            }
+            SystemError::EEXIST => panic!("mmio_init: mmio pool is already initialized"),
+            _ => panic!("mmio_init: failed to initialize mmio pool: {:?}", e),
+        }
+    }
+}
+
+/// 检查初始化的顺序，然后初始化mmio内存池
+///
+/// ## 参数
+///
+/// - `allocator_ready`：全局页帧分配器是否已经初始化（通常为`LockedFrameAllocator::is_initialized()`）
+///
+/// ## 返回值
+///
+/// - `EAGAIN_OR_EWOULDBLOCK`：页帧分配器还没有初始化
+/// - `EEXIST`：mmio内存池已经被初始化
+pub fn mmio_try_init(allocator_ready: bool) -> Result<(), SystemError> {
+    if !allocator_ready {
+        return Err(SystemError::EAGAIN_OR_EWOULDBLOCK);
+    }
+    if MMIO_INITIALIZED
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(SystemError::EEXIST);
+    }
+
     kdebug!("Initializing MMIO buddy memory pool...");
     // 初始化mmio内存池
     unsafe {
@@ -622,6 +806,7 @@ pub fn mmio_init() {
     }
 
     kinfo!("MMIO buddy memory pool init done");
+    return Ok(());
 }
 /// @brief 创建一块mmio区域，并将vma绑定到initial_mm
 ///
@@ -636,7 +821,7 @@ pub fn mmio_init() {
 /// @return int 错误码
 #[no_mangle]
 pub extern "C" fn mmio_create(
-    size: u32,
+    size: u64,
     vm_flags: vm_flags_t,
     res_vaddr: *mut u64,
     res_length: *mut u64,
@@ -16,19 +16,32 @@ use core::{
 };
 
 use self::{
-    allocator::page_frame::{VirtPageFrame, VirtPageFrameIter},
+    allocator::page_frame::{PageFrameCount, VirtPageFrame, VirtPageFrameIter},
+    error::MmError,
     page::round_up_to_page_size,
     ucontext::{AddressSpace, UserMapper},
 };
 
 pub mod allocator;
 pub mod c_adapter;
+pub mod cache_type;
+pub mod error;
+pub mod fixmap;
+pub mod initrd;
 pub mod kernel_mapper;
+pub mod kmem_stat;
+pub mod ksm;
 pub mod mmio_buddy;
 pub mod no_init;
 pub mod page;
 pub mod percpu;
+pub mod quarantine;
+pub mod reserved;
+pub mod scratch;
+pub mod stack_guard;
+pub mod swap;
 pub mod syscall;
+pub mod trampoline;
 pub mod ucontext;
 
 /// 内核INIT进程的用户地址空间结构体（仅在process_init中初始化）
@@ -317,6 +330,196 @@ pub struct PhysMemoryArea {
     pub size: usize,
 }
 
+impl PhysMemoryArea {
+    /// 把区域裁剪为以页为粒度：基地址向上对齐到页大小，结束地址向下对齐到页大小
+    ///
+    /// 某些固件报告的内存区域的基地址或者大小不是页大小的整数倍，
+    /// 而bump分配器和伙伴分配器都假设内存区域以页为粒度。
+    /// 结束地址超出`usize`的范围时，被截断到地址空间的末尾
+    ///
+    /// ## 返回值
+    ///
+    /// 裁剪之后的区域。如果裁剪之后区域为空，返回None
+    pub fn page_trimmed(&self) -> Option<PhysMemoryArea> {
+        let base = self.base.data().checked_add(MMArch::PAGE_SIZE - 1)? & !(MMArch::PAGE_SIZE - 1);
+        let end = self.end() & !(MMArch::PAGE_SIZE - 1);
+        if end <= base {
+            return None;
+        }
+        return Some(PhysMemoryArea {
+            base: PhysAddr::new(base),
+            size: end - base,
+        });
+    }
+
+    /// 区域的结束地址（不包含）。超出`usize`的范围时，返回`usize::MAX`
+    pub fn end(&self) -> usize {
+        return self.base.data().saturating_add(self.size);
+    }
+}
+
+/// 把一个内存区域加入容量固定的数组中。数组已满时，先合并已经收集的区域（参见`merge_memory_areas`），腾出空间
+///
+/// ## 参数
+///
+/// - `areas`：存放内存区域的数组
+/// - `count`：数组中已有的区域数量
+/// - `area`：要加入的区域
+///
+/// ## 返回值
+///
+/// - `Ok(count)`：加入之后数组中的区域数量
+/// - `Err(count)`：合并之后数组仍然是满的，区域被丢弃。返回数组中的区域数量（可能因为合并而减少）
+pub fn push_memory_area(
+    areas: &mut [PhysMemoryArea],
+    count: usize,
+    area: PhysMemoryArea,
+) -> Result<usize, usize> {
+    let mut count = count;
+    if count >= areas.len() {
+        count = merge_memory_areas(&mut areas[..count]);
+    }
+    if count >= areas.len() {
+        return Err(count);
+    }
+    areas[count] = area;
+    return Ok(count + 1);
+}
+
+/// 把内存区域按基地址排序，并合并首尾相接或者相互重叠的区域，得到数量最少、互不重叠的区域
+///
+/// 某些固件报告的内存区域是乱序的，或者把一段连续的RAM拆分成了许多相邻的区域。
+/// 重叠的部分只会被保留一次，结束地址超出`usize`范围的区域被截断到地址空间的末尾（参见`PhysMemoryArea::end`）。
+/// 这个函数不会分配内存，可以在堆初始化之前使用
+///
+/// ## 参数
+///
+/// - `areas`：内存区域（大小为0的区域会被移除）
+///
+/// ## 返回值
+///
+/// 合并之后的区域数量（合并之后的区域按基地址从小到大，位于`areas`的前面）
+pub fn merge_memory_areas(areas: &mut [PhysMemoryArea]) -> usize {
+    areas.sort_unstable_by_key(|area| area.base);
+    let mut count = 0;
+    for i in 0..areas.len() {
+        let area = areas[i];
+        if area.size == 0 {
+            continue;
+        }
+        if count > 0 {
+            let prev = &mut areas[count - 1];
+            let prev_end = prev.end();
+            if area.base.data() <= prev_end {
+                prev.size = cmp::max(prev_end, area.end()) - prev.base.data();
+                continue;
+            }
+        }
+        areas[count] = PhysMemoryArea {
+            base: area.base,
+            size: area.end() - area.base.data(),
+        };
+        count += 1;
+    }
+    return count;
+}
+
+/// 从内存区域中挖去与`holes`重叠的部分（比如固件报告的RAM与保留的区域重叠）
+///
+/// 一个区域被挖去中间的部分时会被拆分为两个，因此区域的数量可能增加，新增的区域使用`areas`中`count`之后的空间。
+/// 空间不足时，多出来的部分被丢弃（不会被当作可用的内存）。这个函数不会分配内存，可以在堆初始化之前使用
+///
+/// ## 参数
+///
+/// - `areas`：内存区域的数组，前`count`个是有效的区域，按基地址排序并且互不重叠（参见`merge_memory_areas`）
+/// - `count`：有效的区域数量
+/// - `holes`：要挖去的区域，同样按基地址排序并且互不重叠
+///
+/// ## 返回值
+///
+/// 挖去之后的区域数量（区域按基地址从小到大，位于`areas`的前面）
+pub fn subtract_memory_areas(
+    areas: &mut [PhysMemoryArea],
+    count: usize,
+    holes: &[PhysMemoryArea],
+) -> usize {
+    let cap = areas.len();
+    // 先把区域移动到数组的末尾，再从前面开始写入结果，这样写入的位置总是在还未读取的区域之前
+    areas.copy_within(0..count, cap - count);
+    let mut out = 0;
+    let mut first_hole = 0;
+    for read in cap - count..cap {
+        let area = areas[read];
+        let mut base = area.base.data();
+        let end = area.end();
+        // 在这个区域之前结束的空洞，也在之后的区域之前结束
+        while first_hole < holes.len() && holes[first_hole].end() <= base {
+            first_hole += 1;
+        }
+        for hole in holes[first_hole..].iter() {
+            if hole.base.data() >= end {
+                break;
+            }
+            if hole.base.data() > base && out <= read {
+                areas[out] = PhysMemoryArea {
+                    base: PhysAddr::new(base),
+                    size: hole.base.data() - base,
+                };
+                out += 1;
+            }
+            base = cmp::max(base, hole.end());
+        }
+        if base < end && out <= read {
+            areas[out] = PhysMemoryArea {
+                base: PhysAddr::new(base),
+                size: end - base,
+            };
+            out += 1;
+        }
+    }
+    return out;
+}
+
+/// 把内存区域的总大小限制在`limit`字节以内（用于`mem=`启动参数）
+///
+/// 优先裁剪地址最高的区域：每次从基地址最高的区域的末尾裁剪，区域变为空时将其移除（其余区域的顺序不变）。
+/// `keep_below`以下的内存（比如内核镜像所在的内存）不会被裁剪，因此裁剪之后的总大小可能仍然大于`limit`
+///
+/// ## 参数
+///
+/// - `areas`：以页为粒度的内存区域
+/// - `limit`：总大小的上限（字节，会向下对齐到页大小）
+/// - `keep_below`：这个地址以下的内存不会被裁剪
+///
+/// ## 返回值
+///
+/// 裁剪之后的区域数量（有效的区域位于`areas`的前面）
+pub fn cap_memory_areas(areas: &mut [PhysMemoryArea], limit: usize, keep_below: PhysAddr) -> usize {
+    let limit = limit & !(MMArch::PAGE_SIZE - 1);
+    let keep_below = round_up_to_page_size(keep_below.data());
+    let mut count = areas.len();
+    let mut total: usize = areas.iter().map(|area| area.size).sum();
+
+    while total > limit && count > 0 {
+        let highest = (0..count).max_by_key(|i| areas[*i].base).unwrap();
+        let area = &mut areas[highest];
+        let floor = cmp::max(area.base.data(), keep_below);
+        let trimmable = (area.base.data() + area.size).saturating_sub(floor);
+        if trimmable == 0 {
+            // 其余的区域都位于keep_below以下
+            break;
+        }
+        let trim = cmp::min(total - limit, trimmable);
+        area.size -= trim;
+        total -= trim;
+        if area.size == 0 {
+            areas.copy_within(highest + 1..count, highest);
+            count -= 1;
+        }
+    }
+    return count;
+}
+
 pub trait MemoryManagementArch: Clone + Copy + Debug {
     /// 页面大小的shift（假如页面4K，那么这个值就是12,因为2^12=4096）
     const PAGE_SHIFT: usize;
@@ -347,6 +550,22 @@ pub trait MemoryManagementArch: Clone + Copy + Debug {
     const ENTRY_FLAG_NO_EXEC: usize;
     /// 标记当前页面可执行的标志位（Execute enable）
     const ENTRY_FLAG_EXEC: usize;
+    /// 标记当前页面已经被写入过的标志位（Dirty）
+    const ENTRY_FLAG_DIRTY: usize;
+    /// 标记当前页面为全局页面的标志位（Global），切换页表时，全局页面的TLB条目不会被刷新
+    const ENTRY_FLAG_GLOBAL: usize;
+    /// 标记当前页面为写时复制页面的软件标志位（由硬件忽略的位）
+    const ENTRY_FLAG_COW: usize;
+    /// 标记不存在的页表项记录了一个被换出的页面的软件标志位（交换槽位的下标保存在地址字段中），参见`mm::swap`
+    const ENTRY_FLAG_SWAP: usize;
+    /// 标记非最后一级的页表项直接映射一个大页（而不是指向下一级页表）的标志位
+    const ENTRY_FLAG_HUGE_PAGE: usize;
+    /// 最后一级（4K）页表项中，用于选择PAT表项的标志位
+    const ENTRY_FLAG_PAT: usize;
+    /// 映射大页的页表项中，用于选择PAT表项的标志位
+    const ENTRY_FLAG_PAT_HUGE: usize;
+    /// 各级页表的名称（下标为页表的级别，0为最后一级），用于打印调试信息
+    const PAGE_TABLE_NAMES: &'static [&'static str];
 
     /// 虚拟地址与物理地址的偏移量
     const PHYS_OFFSET: usize;
@@ -408,6 +627,15 @@ pub trait MemoryManagementArch: Clone + Copy + Debug {
     /// @brief 刷新TLB中，关于指定虚拟地址的条目
     unsafe fn invalidate_page(address: VirtAddr);
 
+    /// 刷新TLB中，从start开始的count个页面的条目
+    ///
+    /// 页面较多时，逐页刷新的开销可能比刷新整个TLB更大，架构可以选择刷新整个TLB。默认逐页调用`invalidate_page`
+    unsafe fn invalidate_range(start: VirtAddr, count: PageFrameCount) {
+        for i in 0..count.data() {
+            Self::invalidate_page(start + i * Self::PAGE_SIZE);
+        }
+    }
+
     /// @brief 刷新TLB中，所有的条目
     unsafe fn invalidate_all();
 
@@ -417,6 +645,11 @@ pub trait MemoryManagementArch: Clone + Copy + Debug {
     /// @brief 设置顶级页表的物理地址到处理器中
     unsafe fn set_table(table_kind: PageTableKind, table: PhysAddr);
 
+    /// 获取最近一次缺页异常的线性地址（在x86_64上为CR2）
+    ///
+    /// 只有在缺页异常处理程序中（并且在开中断/再次发生缺页异常之前）读取，其值才有意义
+    unsafe fn fault_address() -> VirtAddr;
+
     /// @brief 将物理地址转换为虚拟地址.
     ///
     /// @param phys 物理地址
@@ -456,7 +689,21 @@ pub trait MemoryManagementArch: Clone + Copy + Debug {
     fn initial_page_table() -> PhysAddr;
 
     /// 初始化新的usermapper，为用户进程创建页表
-    fn setup_new_usermapper() -> Result<UserMapper, SystemError>;
+    fn setup_new_usermapper() -> Result<UserMapper, MmError>;
+
+    /// 判断是否可以依赖页表项的不可执行标志来保证W^X（可写的页面不可执行）
+    ///
+    /// 处理器不支持（或者没有开启）不可执行标志时返回false，此时所有的页面都是可执行的
+    fn can_enforce_wx() -> bool {
+        return true;
+    }
+
+    /// 页表项中表示内存加密的位（比如AMD SME/SEV的C位）的掩码。它们位于地址字段中，但不属于物理地址
+    ///
+    /// 没有开启内存加密时返回0
+    fn encrypt_mask() -> usize {
+        return 0;
+    }
 }
 
 /// @brief 虚拟地址范围
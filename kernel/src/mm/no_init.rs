@@ -13,6 +13,7 @@ use core::marker::PhantomData;
 
 use super::{
     allocator::page_frame::{FrameAllocator, PageFrameCount, PageFrameUsage},
+    error::MmError,
     page::PageFlags,
     PageTableKind, VirtAddr,
 };
@@ -71,7 +72,14 @@ pub unsafe fn pseudo_map_phys(vaddr: VirtAddr, paddr: PhysAddr, count: PageFrame
     for i in 0..count.data() {
         let vaddr = vaddr + i * MMArch::PAGE_SIZE;
         let paddr = paddr + i * MMArch::PAGE_SIZE;
-        let flusher = mapper.map_phys(vaddr, paddr, flags).unwrap();
+        // 引导时建立的映射可能已经覆盖了这个页面，此时用新的映射替换它
+        let flusher = match mapper.map_phys(vaddr, paddr, flags) {
+            Err(MmError::AlreadyMapped(_)) => {
+                mapper.unmap_phys(vaddr, false).unwrap().2.ignore();
+                mapper.map_phys(vaddr, paddr, flags).unwrap()
+            }
+            r => r.unwrap(),
+        };
         flusher.ignore();
     }
 
@@ -1,20 +1,34 @@
+use alloc::{string::String, vec::Vec};
 use core::{
-    fmt::{self, Debug, Error, Formatter},
+    fmt::{self, Debug, Error, Formatter, Write},
     marker::PhantomData,
     mem,
-    ops::Add,
-    sync::atomic::{compiler_fence, Ordering},
+    ops::{Add, Range},
+    sync::atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering},
 };
 
 use crate::{
-    arch::{interrupt::ipi::send_ipi, MMArch},
-    exception::ipi::{IpiKind, IpiTarget},
-    kerror, kwarn,
+    arch::{interrupt::ipi::send_ipi, CurrentIrqArch, MMArch},
+    exception::{
+        ipi::{IpiKind, IpiTarget},
+        InterruptArch,
+    },
+    kdebug, kerror, kwarn,
+    smp::core::smp_get_processor_id,
+    syscall::SystemError,
 };
 
 use super::{
-    allocator::page_frame::FrameAllocator, syscall::ProtFlags, MemoryManagementArch, PageTableKind,
-    PhysAddr, VirtAddr,
+    allocator::{
+        frame_tag::{frame_tag_set, FRAME_TAG_PAGE_TABLE},
+        page_frame::{FrameAllocator, PageFrameCount},
+    },
+    cache_type::{check_cache_type, is_ram, CacheType},
+    error::MmError,
+    kmem_stat::{kmem_stat_add, kmem_stat_sub, KernelMemPurpose},
+    percpu::PerCpu,
+    syscall::ProtFlags,
+    MemoryManagementArch, PageTableKind, PhysAddr, VirtAddr,
 };
 
 #[derive(Debug)]
@@ -109,6 +123,8 @@ impl<Arch: MemoryManagementArch> PageTable<Arch> {
     pub unsafe fn set_entry(&self, i: usize, entry: PageEntry<Arch>) -> Option<()> {
         let entry_virt = self.entry_virt(i)?;
         Arch::write::<usize>(entry_virt, entry.data());
+        // 任何页表项的修改都会使所有的翻译缓存失效
+        translate_cache_invalidate();
         return Some(());
     }
 
@@ -148,9 +164,24 @@ impl<Arch: MemoryManagementArch> PageTable<Arch> {
         }
     }
 
+    /// 判断第i个页表项是否直接映射了一个大页（设置了`ENTRY_FLAG_HUGE_PAGE`），而不是指向下一级页表
+    ///
+    /// 最后一级页表中的同一位是PAT位，顶级页表中的同一位是保留位，因此总是返回false
+    pub unsafe fn entry_is_huge(&self, i: usize) -> bool {
+        if self.level == 0 || self.level >= Arch::PAGE_LEVELS - 1 {
+            return false;
+        }
+        return match self.entry(i) {
+            Some(entry) => entry.present() && entry.data() & Arch::ENTRY_FLAG_HUGE_PAGE != 0,
+            None => false,
+        };
+    }
+
     /// 获取第i个页表项指向的下一级页表
+    ///
+    /// 如果页表项映射了一个大页（参见`entry_is_huge`），返回None
     pub unsafe fn next_level_table(&self, index: usize) -> Option<Self> {
-        if self.level == 0 {
+        if self.level == 0 || self.entry_is_huge(index) {
             return None;
         }
 
@@ -185,6 +216,16 @@ impl<Arch: MemoryManagementArch> PageEntry<Arch> {
         }
     }
 
+    /// 构造一个指向物理地址paddr的页表项（bits为其余的标志位）
+    ///
+    /// 开启了内存加密时，RAM以加密（私有）的方式映射，设置加密位；设备内存不能被加密，不设置加密位
+    #[inline(always)]
+    pub fn new_mapping(paddr: PhysAddr, bits: usize) -> Self {
+        let mask = Arch::encrypt_mask();
+        let encrypt = if mask != 0 && is_ram(paddr) { mask } else { 0 };
+        return Self::new(paddr.data() | bits | encrypt);
+    }
+
     #[inline(always)]
     pub fn data(&self) -> usize {
         self.data
@@ -198,7 +239,8 @@ impl<Arch: MemoryManagementArch> PageEntry<Arch> {
     /// - Err(PhysAddr) 如果当前页表项不存在, 返回物理地址
     #[inline(always)]
     pub fn address(&self) -> Result<PhysAddr, PhysAddr> {
-        let paddr = PhysAddr::new(self.data & Arch::PAGE_ADDRESS_MASK);
+        // 内存加密位位于地址字段中，但不是物理地址的一部分
+        let paddr = PhysAddr::new(self.data & Arch::PAGE_ADDRESS_MASK & !Arch::encrypt_mask());
 
         if self.present() {
             Ok(paddr)
@@ -209,18 +251,24 @@ impl<Arch: MemoryManagementArch> PageEntry<Arch> {
 
     #[inline(always)]
     pub fn flags(&self) -> PageFlags<Arch> {
-        unsafe { PageFlags::from_data(self.data & Arch::ENTRY_FLAGS_MASK) }
+        return PageFlags::from_entry_bits(self.data);
     }
 
     #[inline(always)]
     pub fn set_flags(&mut self, flags: PageFlags<Arch>) {
-        self.data = (self.data & !Arch::ENTRY_FLAGS_MASK) | flags.data();
+        self.data = (self.data & !Arch::ENTRY_FLAGS_MASK) | flags.to_entry_bits();
     }
 
     #[inline(always)]
     pub fn present(&self) -> bool {
         return self.data & Arch::ENTRY_FLAG_PRESENT != 0;
     }
+
+    /// 当前页表项是否映射了一个影子栈页面（W=0, D=1）
+    #[inline(always)]
+    pub fn is_shadow_stack(&self) -> bool {
+        return self.present() && self.flags().has_shadow_stack();
+    }
 }
 
 /// 页表项的标志位
@@ -395,6 +443,73 @@ impl<Arch: MemoryManagementArch> PageFlags<Arch> {
             == Arch::ENTRY_FLAG_EXEC;
     }
 
+    /// 把当前页表项设置为CET影子栈页面
+    ///
+    /// 影子栈页面的编码为：不可写（W=0）且脏（D=1）。
+    /// 如果处理器不支持CET影子栈，或者CR4.CET没有开启，那么本函数不做任何修改
+    ///
+    /// ## 参数
+    ///
+    /// - value: 如果为true，那么将当前页表项设置为影子栈页面；否则清除影子栈的编码（页面变为普通的只读页面）
+    #[must_use]
+    #[inline(always)]
+    pub fn set_shadow_stack(self, value: bool) -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if !crate::arch::mm::X86_64MMArch::shadow_stack_enabled() {
+                return self;
+            }
+        }
+        return unsafe { self.set_shadow_stack_unchecked(value) };
+    }
+
+    /// 设置影子栈的编码，而不检查处理器是否支持CET影子栈
+    ///
+    /// ## Safety
+    ///
+    /// 在不支持影子栈的处理器上，这样的页表项只是一个普通的只读页面
+    #[must_use]
+    #[inline(always)]
+    pub unsafe fn set_shadow_stack_unchecked(self, value: bool) -> Self {
+        if value {
+            return self
+                .set_write(false)
+                .update_flags(Arch::ENTRY_FLAG_DIRTY, true);
+        } else {
+            return self.update_flags(Arch::ENTRY_FLAG_DIRTY, false);
+        }
+    }
+
+    /// 当前页表项是否具有影子栈的编码（W=0, D=1）
+    #[inline(always)]
+    pub fn has_shadow_stack(&self) -> bool {
+        return !self.has_write() && self.has_flag(Arch::ENTRY_FLAG_DIRTY);
+    }
+
+    /// 把当前页表项设置为（或者取消）写时复制页面
+    ///
+    /// 写时复制页面总是只读的，对它的写入会触发缺页异常，由`UserMapper::handle_write_fault`处理。
+    /// 设置时会同时清除脏位，避免在启用了CET影子栈的处理器上，与影子栈的编码（W=0, D=1）混淆。
+    /// 取消时不会恢复写权限，调用者需要自行设置
+    #[must_use]
+    #[inline(always)]
+    pub fn set_cow(self, value: bool) -> Self {
+        if value {
+            return self
+                .set_write(false)
+                .update_flags(Arch::ENTRY_FLAG_DIRTY, false)
+                .update_flags(Arch::ENTRY_FLAG_COW, true);
+        } else {
+            return self.update_flags(Arch::ENTRY_FLAG_COW, false);
+        }
+    }
+
+    /// 当前页表项是否为写时复制页面
+    #[inline(always)]
+    pub fn has_cow(&self) -> bool {
+        return self.has_flag(Arch::ENTRY_FLAG_COW);
+    }
+
     /// 设置当前页表项的缓存策略
     ///
     /// ## 参数
@@ -435,13 +550,143 @@ impl<Arch: MemoryManagementArch> PageFlags<Arch> {
         return self.has_flag(Arch::ENTRY_FLAG_WRITE_THROUGH);
     }
 
+    /// 设置当前页表项是否为全局页面
+    ///
+    /// ## 参数
+    ///
+    /// - value: 如果为true，那么切换页表时，该页面的TLB条目不会被刷新
+    #[inline(always)]
+    pub fn set_global(self, value: bool) -> Self {
+        return self.update_flags(Arch::ENTRY_FLAG_GLOBAL, value);
+    }
+
+    /// 当前页表项是否为全局页面
+    #[inline(always)]
+    pub fn has_global(&self) -> bool {
+        return self.has_flag(Arch::ENTRY_FLAG_GLOBAL);
+    }
+
+    /// 设置当前页表项的PAT位（与写穿、缓存禁用位一起选择PAT表项）
+    ///
+    /// PageFlags总是按照4K页表项的布局保存PAT位，映射大页时由`to_entry_bits_at`转换
+    #[inline(always)]
+    pub fn set_page_attribute_table(self, value: bool) -> Self {
+        return self.update_flags(Arch::ENTRY_FLAG_PAT, value);
+    }
+
+    /// 当前页表项是否设置了PAT位
+    #[inline(always)]
+    pub fn has_page_attribute_table(&self) -> bool {
+        return self.has_flag(Arch::ENTRY_FLAG_PAT);
+    }
+
+    /// 生成4K页表项中的标志位，等价于`to_entry_bits_at(0)`
+    #[inline(always)]
+    pub fn to_entry_bits(&self) -> usize {
+        return self.to_entry_bits_at(0);
+    }
+
+    /// 从4K页表项中解析出标志位，等价于`from_entry_bits_at(0, bits)`
+    #[inline(always)]
+    pub fn from_entry_bits(bits: usize) -> Self {
+        return Self::from_entry_bits_at(0, bits);
+    }
+
+    /// 生成第`level`级的、映射一个页面的页表项中的标志位（不包括物理地址）
+    ///
+    /// 页表项中的标志位与PageFlags之间的转换应当只通过本函数以及`from_entry_bits_at`进行：
+    /// - 不可执行位（x86_64上为第63位）：如果处理器不支持XD（该位被保留），那么总是被清除
+    /// - PAT位：PageFlags按照4K页表项的布局保存，当level大于0时，被移动到`ENTRY_FLAG_PAT_HUGE`，
+    ///   并设置`ENTRY_FLAG_HUGE_PAGE`
+    /// - 全局位以及其他的标志位保持不变
+    ///
+    /// ## 参数
+    ///
+    /// - `level`: 页表项所在的页表的级别（0表示4K页面，大于0表示大页）
+    pub fn to_entry_bits_at(&self, level: usize) -> usize {
+        let mut bits = self.data & Arch::ENTRY_FLAGS_MASK;
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::arch::mm::X86_64MMArch::is_xd_reserved() {
+                bits &= !Arch::ENTRY_FLAG_NO_EXEC;
+            }
+        }
+
+        if level > 0 {
+            let pat = bits & Arch::ENTRY_FLAG_PAT != 0;
+            bits &= !Arch::ENTRY_FLAG_PAT;
+            bits |= Arch::ENTRY_FLAG_HUGE_PAGE;
+            if pat {
+                bits |= Arch::ENTRY_FLAG_PAT_HUGE;
+            }
+        }
+        return bits;
+    }
+
+    /// 从第`level`级的、映射一个页面的页表项中解析出标志位，是`to_entry_bits_at`的逆运算
+    ///
+    /// ## 参数
+    ///
+    /// - `level`: 页表项所在的页表的级别（0表示4K页面，大于0表示大页）
+    /// - `bits`: 页表项的值（可以包含物理地址，会被忽略）
+    pub fn from_entry_bits_at(level: usize, bits: usize) -> Self {
+        let mut data = bits & Arch::ENTRY_FLAGS_MASK;
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if crate::arch::mm::X86_64MMArch::is_xd_reserved() {
+                data &= !Arch::ENTRY_FLAG_NO_EXEC;
+            }
+        }
+
+        if level > 0 {
+            // 大页的PAT位位于地址字段中，不能只从ENTRY_FLAGS_MASK中取得
+            data &= !Arch::ENTRY_FLAG_HUGE_PAGE;
+            if bits & Arch::ENTRY_FLAG_PAT_HUGE != 0 {
+                data |= Arch::ENTRY_FLAG_PAT;
+            }
+        }
+        return unsafe { Self::from_data(data) };
+    }
+
+    /// 以`PRESENT|RW|USER|NX`的形式描述标志位（用于打印调试信息）
+    pub fn describe(&self) -> String {
+        let names = [
+            (Arch::ENTRY_FLAG_PRESENT, "PRESENT"),
+            (Arch::ENTRY_FLAG_READWRITE, "RW"),
+            (Arch::ENTRY_FLAG_USER, "USER"),
+            (Arch::ENTRY_FLAG_WRITE_THROUGH, "PWT"),
+            (Arch::ENTRY_FLAG_CACHE_DISABLE, "PCD"),
+            (Arch::ENTRY_FLAG_DIRTY, "DIRTY"),
+            (Arch::ENTRY_FLAG_PAT, "PAT"),
+            (Arch::ENTRY_FLAG_GLOBAL, "GLOBAL"),
+            (Arch::ENTRY_FLAG_COW, "COW"),
+            (Arch::ENTRY_FLAG_NO_EXEC, "NX"),
+        ];
+        let mut s = String::new();
+        for (flag, name) in names.iter() {
+            // 有的架构中，某些标志位为0（比如x86_64的READONLY），不能被描述
+            if *flag == 0 || !self.has_flag(*flag) {
+                continue;
+            }
+            if !s.is_empty() {
+                s.push('|');
+            }
+            s.push_str(name);
+        }
+        if s.is_empty() {
+            s.push_str("NONE");
+        }
+        return s;
+    }
+
     /// MMIO内存的页表项标志
     #[inline(always)]
     pub fn mmio_flags() -> Self {
         return Self::new()
             .set_user(false)
             .set_write(true)
-            .set_execute(true)
             .set_page_cache_disable(true)
             .set_page_write_through(true);
     }
@@ -459,7 +704,54 @@ impl<Arch: MemoryManagementArch> fmt::Debug for PageFlags<Arch> {
     }
 }
 
+/// 是否强制所有的映射都使用4K页
+///
+/// 用于排查与大页相关的问题：启用之后，`PageMapper::best_page_size`总是返回4K，
+/// 因此所有通过`map_phys_best`建立的映射都会退化为4K页；启动时建立直接映射区域之前启用，直接映射区域也只使用4K页。默认关闭。
+static FORCE_4K_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// 设置是否强制所有的映射都使用4K页（只影响之后建立的映射）
+pub fn set_force_4k_pages(force: bool) {
+    FORCE_4K_PAGES.store(force, Ordering::SeqCst);
+}
+
+/// 是否强制所有的映射都使用4K页
+pub fn force_4k_pages() -> bool {
+    return FORCE_4K_PAGES.load(Ordering::Relaxed);
+}
+
+/// 映射一个页面时使用的页的大小
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4K页，由最后一级页表项映射
+    Size4K,
+    /// 2M大页，由倒数第二级页表项（x86_64上为PD的页表项）映射
+    Size2M,
+}
+
+impl PageSize {
+    /// 映射这个大小的页的页表项所在的页表的级别
+    pub const fn level(&self) -> usize {
+        return match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+        };
+    }
+
+    /// 页的大小（字节数）
+    pub const fn bytes<Arch: MemoryManagementArch>(&self) -> usize {
+        return Arch::PAGE_SIZE << (self.level() * Arch::PAGE_ENTRY_SHIFT);
+    }
+}
+
 /// 页表映射器
+///
+/// 映射大页的页表项（参见`map_phys_huge`）在需要修改其中某一个4K页面时（`map_phys`、`unmap_phys`、`remap`等），
+/// 会先被拆分为一张下一级页表，其中的每一项映射原来的大页的一部分，权限保持不变。
+///
+/// 所有按范围操作的方法（`map_scatter`、`map_phys_best`、`map_phys_range`、`unmap_range`、`protect_range`）
+/// 在页数为0时都不做任何事情：不会分配页表，也不会检查参数，直接返回成功以及一个空的`FlushBatch`
+/// （提交它不会刷新TLB）
 #[derive(Hash)]
 pub struct PageMapper<Arch, F> {
     /// 页表类型
@@ -494,6 +786,9 @@ impl<Arch: MemoryManagementArch, F: FrameAllocator> PageMapper<Arch, F> {
     /// 创建页表，并为这个页表创建页面映射器
     pub unsafe fn create(table_kind: PageTableKind, mut allocator: F) -> Option<Self> {
         let table_paddr = allocator.allocate_one()?;
+        kmem_stat_add(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+        frame_tag_set(table_paddr, PageFrameCount::new(1), FRAME_TAG_PAGE_TABLE);
+        PageTableAllocStats::record(Arch::PAGE_LEVELS - 1);
         // 清空页表
         let table_vaddr = Arch::phys_2_virt(table_paddr)?;
         Arch::write_bytes(table_vaddr, 0, Arch::PAGE_SIZE);
@@ -550,16 +845,29 @@ impl<Arch: MemoryManagementArch, F: FrameAllocator> PageMapper<Arch, F> {
         compiler_fence(Ordering::SeqCst);
         let phys: PhysAddr = self.frame_allocator.allocate_one()?;
         compiler_fence(Ordering::SeqCst);
-        return self.map_phys(virt, phys, flags);
+        match self.map_one(virt, phys, flags) {
+            Ok(flush) => return Some(flush),
+            Err(_) => {
+                self.frame_allocator.free_one(phys);
+                return None;
+            }
+        }
     }
 
     /// 映射一个物理页到指定的虚拟地址
+    ///
+    /// ## 返回值
+    ///
+    /// 如果映射成功，返回页表项刷新器，否则返回对应的错误。
+    /// 虚拟地址或者物理地址没有按页对齐时返回`MmError::Unaligned`，
+    /// 虚拟地址不合法（`Arch::virt_is_valid`）时返回`MmError::NotCanonical`，
+    /// 虚拟地址已经被映射时返回`MmError::AlreadyMapped`（原有的映射保持不变）
     pub unsafe fn map_phys(
         &mut self,
         virt: VirtAddr,
         phys: PhysAddr,
         flags: PageFlags<Arch>,
-    ) -> Option<PageFlush<Arch>> {
+    ) -> Result<PageFlush<Arch>, MmError> {
         // 验证虚拟地址和物理地址是否对齐
         if !(virt.check_aligned(Arch::PAGE_SIZE) && phys.check_aligned(Arch::PAGE_SIZE)) {
             kerror!(
@@ -567,163 +875,1456 @@ impl<Arch: MemoryManagementArch, F: FrameAllocator> PageMapper<Arch, F> {
                 virt,
                 phys
             );
-            return None;
+            let addr = if virt.check_aligned(Arch::PAGE_SIZE) {
+                phys.data()
+            } else {
+                virt.data()
+            };
+            return Err(MmError::Unaligned(addr));
+        }
+        if !Arch::virt_is_valid(virt) {
+            return Err(MmError::NotCanonical(virt));
+        }
+        // 缓存类型的冲突只检查内核页表：设备内存只会被映射到内核的地址空间中（参见`mmio_buddy`），
+        // 用户页表中的映射不需要获取全局的记录的锁
+        if self.table_kind == PageTableKind::Kernel {
+            check_cache_type(phys, &flags)?;
         }
         let virt = VirtAddr::new(virt.data() & (!Arch::PAGE_NEGATIVE_MASK));
 
         // TODO： 验证flags是否合法
 
         // 创建页表项
-        let entry = PageEntry::new(phys.data() | flags.data());
+        let entry = PageEntry::new_mapping(phys, flags.to_entry_bits());
         let mut table = self.table();
         loop {
-            let i = table.index_of(virt)?;
+            let i = table
+                .index_of(virt)
+                .ok_or(MmError::KernelRangeViolation(virt))?;
             assert!(i < Arch::PAGE_ENTRY_NUM);
             if table.level() == 0 {
-                // todo: 检查是否已经映射
-                // 现在不检查的原因是，刚刚启动系统时，内核会映射一些页。
-                if table.entry_mapped(i)? == true {
-                    kwarn!("Page {:?} already mapped", virt);
+                // 不会覆盖已有的映射：需要修改映射的调用者应当先取消原有的映射，或者使用`remap`
+                if table
+                    .entry(i)
+                    .ok_or(MmError::KernelRangeViolation(virt))?
+                    .present()
+                {
+                    return Err(MmError::AlreadyMapped(virt));
                 }
                 // kdebug!("Mapping {:?} to {:?}, i = {i}, entry={:?}, flags={:?}", virt, phys, entry, flags);
                 compiler_fence(Ordering::SeqCst);
                 table.set_entry(i, entry);
                 compiler_fence(Ordering::SeqCst);
-                return Some(PageFlush::new(virt));
+                return Ok(PageFlush::new(virt));
             } else {
-                let next_table = table.next_level_table(i);
-                if let Some(next_table) = next_table {
-                    table = next_table;
-                    // kdebug!("Mapping {:?} to next level table...", virt);
-                } else {
-                    // kdebug!("Allocating next level table for {:?}..., i={i}", virt);
-                    // 分配下一级页表
-                    let frame = self.frame_allocator.allocate_one()?;
-                    // 清空这个页帧
-                    MMArch::write_bytes(MMArch::phys_2_virt(frame).unwrap(), 0, MMArch::PAGE_SIZE);
-
-                    // 设置页表项的flags
-                    // let flags = Arch::ENTRY_FLAG_READWRITE
-                    //     | Arch::ENTRY_FLAG_DEFAULT_TABLE
-                    //     | if virt.kind() == PageTableKind::User {
-                    //         Arch::ENTRY_FLAG_USER
-                    //     } else {
-                    //         0
-                    //     };
-                    let flags: PageFlags<MMArch> =
-                        PageFlags::new_page_table(virt.kind() == PageTableKind::User);
-
-                    // kdebug!("Flags: {:?}", flags);
-
-                    // 把新分配的页表映射到当前页表
-                    table.set_entry(i, PageEntry::new(frame.data() | flags.data()));
-
-                    // 获取新分配的页表
-                    table = table.next_level_table(i)?;
-                }
+                // 下一级页表不存在时分配，页表项映射了大页时拆分
+                table = self.next_level_table_for_write(&table, i, virt)?;
             }
         }
     }
 
-    /// 将物理地址映射到具有线性偏移量的虚拟地址
-    #[allow(dead_code)]
-    pub unsafe fn map_linearly(
+    /// 映射一个物理页到指定的虚拟地址（单个4K页的快速路径）
+    ///
+    /// 语义与`map_phys`完全相同（包括返回的刷新器以及各种错误），但是直接根据虚拟地址计算每一级页表的下标，
+    /// 不再通过`index_of`逐级检查地址范围，适用于缺页处理等“已经从分配器得到一个页帧，把它映射到vaddr”的热路径
+    ///
+    /// ## 返回值
+    ///
+    /// 如果映射成功，返回页表项刷新器，否则返回对应的错误
+    #[inline]
+    pub unsafe fn map_one(
         &mut self,
+        virt: VirtAddr,
         phys: PhysAddr,
         flags: PageFlags<Arch>,
-    ) -> Option<(VirtAddr, PageFlush<Arch>)> {
-        let virt: VirtAddr = Arch::phys_2_virt(phys)?;
-        return self.map_phys(virt, phys, flags).map(|flush| (virt, flush));
+    ) -> Result<PageFlush<Arch>, MmError> {
+        if !(virt.check_aligned(Arch::PAGE_SIZE) && phys.check_aligned(Arch::PAGE_SIZE)) {
+            kerror!(
+                "Try to map unaligned page: virt={:?}, phys={:?}",
+                virt,
+                phys
+            );
+            let addr = if virt.check_aligned(Arch::PAGE_SIZE) {
+                phys.data()
+            } else {
+                virt.data()
+            };
+            return Err(MmError::Unaligned(addr));
+        }
+        if !Arch::virt_is_valid(virt) {
+            return Err(MmError::NotCanonical(virt));
+        }
+        if self.table_kind == PageTableKind::Kernel {
+            check_cache_type(phys, &flags)?;
+        }
+        let virt = VirtAddr::new(virt.data() & (!Arch::PAGE_NEGATIVE_MASK));
+        let entry = PageEntry::new_mapping(phys, flags.to_entry_bits());
+
+        // 顶层页表覆盖了整个地址空间，下一级页表又总是覆盖上一级页表项的范围，因此下标总是有效的
+        let index = |level: usize| {
+            (virt.data() >> (level * Arch::PAGE_ENTRY_SHIFT + Arch::PAGE_SHIFT))
+                & Arch::PAGE_ENTRY_MASK
+        };
+        let mut table = self.table();
+        for level in (1..Arch::PAGE_LEVELS).rev() {
+            let i = index(level);
+            table = self.next_level_table_for_write(&table, i, virt)?;
+        }
+
+        let i = index(0);
+        if table.entry(i).map_or(false, |entry| entry.present()) {
+            return Err(MmError::AlreadyMapped(virt));
+        }
+        compiler_fence(Ordering::SeqCst);
+        table.set_entry(i, entry);
+        compiler_fence(Ordering::SeqCst);
+        return Ok(PageFlush::new(virt));
     }
 
-    /// 修改虚拟地址的页表项的flags，并返回页表项刷新器
+    /// 以2M的大页映射一段物理内存：在倒数第二级页表中写入设置了`ENTRY_FLAG_HUGE_PAGE`的页表项，不再需要最后一级页表
     ///
-    /// 请注意，需要在修改完flags后，调用刷新器的flush方法，才能使修改生效
+    /// 返回的刷新器只包含virt一个地址：INVLPG会使包含这个地址的大页的TLB条目失效
     ///
     /// ## 参数
-    /// - virt 虚拟地址
-    /// - flags 新的页表项的flags
+    ///
+    /// - virt 虚拟地址（必须按页的大小对齐）
+    /// - phys 物理地址（必须按页的大小对齐）
+    /// - flags 页表项的flags（按照4K页表项的布局，参见`PageFlags::to_entry_bits_at`）
+    /// - size 页的大小。为`PageSize::Size4K`时等价于`map_phys`
     ///
     /// ## 返回值
     ///
-    /// 如果修改成功，返回刷新器，否则返回None
-    pub unsafe fn remap(
+    /// 如果映射成功，返回页表项刷新器。如果这个2M的范围已经有一张下一级页表，返回`MmError::AlreadyMapped`
+    pub unsafe fn map_phys_huge(
         &mut self,
         virt: VirtAddr,
+        phys: PhysAddr,
         flags: PageFlags<Arch>,
-    ) -> Option<PageFlush<Arch>> {
-        return self
-            .visit(virt, |p1, i| {
-                let mut entry = p1.entry(i)?;
-                entry.set_flags(flags);
-                p1.set_entry(i, entry);
-                Some(PageFlush::new(virt))
-            })
-            .flatten();
+        size: PageSize,
+    ) -> Result<PageFlush<Arch>, MmError> {
+        if size == PageSize::Size4K {
+            return self.map_phys(virt, phys, flags);
+        }
+
+        let bytes = size.bytes::<Arch>();
+        if !(virt.check_aligned(bytes) && phys.check_aligned(bytes)) {
+            kerror!(
+                "Try to map unaligned huge page: virt={:?}, phys={:?}",
+                virt,
+                phys
+            );
+            let addr = if virt.check_aligned(bytes) {
+                phys.data()
+            } else {
+                virt.data()
+            };
+            return Err(MmError::Unaligned(addr));
+        }
+        if !Arch::virt_is_valid(virt) {
+            return Err(MmError::NotCanonical(virt));
+        }
+        if self.table_kind == PageTableKind::Kernel {
+            check_cache_type(phys, &flags)?;
+        }
+        let virt = VirtAddr::new(virt.data() & (!Arch::PAGE_NEGATIVE_MASK));
+
+        let level = size.level();
+        let entry = PageEntry::new_mapping(phys, flags.to_entry_bits_at(level));
+        let mut table = self.table();
+        loop {
+            let i = table
+                .index_of(virt)
+                .ok_or(MmError::KernelRangeViolation(virt))?;
+            if table.level() == level {
+                // 已经映射了大页，或者下一级页表中可能还有映射，都不能直接覆盖
+                if table.entry_mapped(i) == Some(true) {
+                    return Err(MmError::AlreadyMapped(virt));
+                }
+                compiler_fence(Ordering::SeqCst);
+                table.set_entry(i, entry);
+                compiler_fence(Ordering::SeqCst);
+                return Ok(PageFlush::new(virt));
+            }
+            table = self.next_level_table_for_write(&table, i, virt)?;
+        }
     }
 
-    /// 根据虚拟地址，查找页表，获取对应的物理地址和页表项的flags
+    /// 取消一个由`map_phys_huge`建立的大页的映射（不会拆分大页）
     ///
     /// ## 参数
     ///
-    /// - virt 虚拟地址
+    /// - virt 虚拟地址（必须按页的大小对齐）
+    /// - size 页的大小。为`PageSize::Size4K`时等价于`unmap_phys(virt, false)`
     ///
     /// ## 返回值
     ///
-    /// 如果查找成功，返回物理地址和页表项的flags，否则返回None
-    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, PageFlags<Arch>)> {
-        let entry: PageEntry<Arch> = self.visit(virt, |p1, i| unsafe { p1.entry(i) })??;
-        let paddr = entry.address().ok()?;
-        let flags = entry.flags();
-        return Some((paddr, flags));
+    /// 如果取消成功，返回大页的物理地址、flags以及刷新器。如果virt处没有这个大小的大页，返回`MmError::NotMapped`
+    pub unsafe fn unmap_phys_huge(
+        &mut self,
+        virt: VirtAddr,
+        size: PageSize,
+    ) -> Result<(PhysAddr, PageFlags<Arch>, PageFlush<Arch>), MmError> {
+        if size == PageSize::Size4K {
+            return self.unmap_phys(virt, false);
+        }
+        if !virt.check_aligned(size.bytes::<Arch>()) {
+            kerror!("Try to unmap unaligned huge page: virt={:?}", virt);
+            return Err(MmError::Unaligned(virt.data()));
+        }
+        if !Arch::virt_is_valid(virt) {
+            return Err(MmError::NotCanonical(virt));
+        }
+
+        let level = size.level();
+        let mut table = self.table();
+        while table.level() > level {
+            let i = table.index_of(virt).ok_or(MmError::NotMapped(virt))?;
+            table = table.next_level_table(i).ok_or(MmError::NotMapped(virt))?;
+        }
+        let i = table.index_of(virt).ok_or(MmError::NotMapped(virt))?;
+        if !table.entry_is_huge(i) {
+            return Err(MmError::NotMapped(virt));
+        }
+        let entry = table.entry(i).ok_or(MmError::NotMapped(virt))?;
+        table.set_entry(i, PageEntry::new(0));
+        let paddr = PhysAddr::new(entry.address().unwrap().data() & !(size.bytes::<Arch>() - 1));
+        let flags = PageFlags::from_entry_bits_at(level, entry.data());
+        return Ok((paddr, flags, PageFlush::new(virt)));
     }
 
-    /// 取消虚拟地址的映射，释放页面，并返回页表项刷新器
-    ///
-    /// 请注意，需要在取消映射后，调用刷新器的flush方法，才能使修改生效
+    /// 取得页表的第i项所指向的下一级页表，用于修改页表的路径：
+    /// 页表项不存在时，分配一个新的页表；页表项映射了一个大页时，把大页拆分（参见`split_huge_entry`）
     ///
     /// ## 参数
     ///
-    /// - virt 虚拟地址
-    /// - unmap_parents 是否在父页表内，取消空闲子页表的映射
-    ///
-    /// ## 返回值
-    /// 如果取消成功，返回刷新器，否则返回None
-    pub unsafe fn unmap(&mut self, virt: VirtAddr, unmap_parents: bool) -> Option<PageFlush<Arch>> {
-        let (paddr, _, flusher) = self.unmap_phys(virt, unmap_parents)?;
-        self.frame_allocator.free_one(paddr);
-        return Some(flusher);
+    /// - `table`：当前级别的页表（不能是最后一级）
+    /// - `i`：页表项的下标
+    /// - `virt`：位于这个页表项所覆盖的范围中的任意一个虚拟地址（用于确定页表的类型）
+    unsafe fn next_level_table_for_write(
+        &mut self,
+        table: &PageTable<Arch>,
+        i: usize,
+        virt: VirtAddr,
+    ) -> Result<PageTable<Arch>, MmError> {
+        if table.entry_is_huge(i) {
+            return split_huge_entry(table, i, &mut self.frame_allocator).ok_or(
+                MmError::OutOfMemory {
+                    needed: PageFrameCount::new(1),
+                },
+            );
+        }
+        if let Some(next) = table.next_level_table(i) {
+            return Ok(next);
+        }
+        return self.allocate_next_level_table(table, i, virt);
     }
 
-    /// 取消虚拟地址的映射，并返回物理地址和页表项的flags
+    /// 为页表的第i项分配一个（清零的）下一级页表，并返回这个新的页表
     ///
     /// ## 参数
     ///
-    /// - vaddr 虚拟地址
-    /// - unmap_parents 是否在父页表内，取消空闲子页表的映射
+    /// - `table`：当前级别的页表（不能是最后一级）
+    /// - `i`：页表项的下标
+    /// - `virt`：位于这个页表项所覆盖的范围中的任意一个虚拟地址（用于确定页表的类型）
+    unsafe fn allocate_next_level_table(
+        &mut self,
+        table: &PageTable<Arch>,
+        i: usize,
+        virt: VirtAddr,
+    ) -> Result<PageTable<Arch>, MmError> {
+        let frame = self
+            .frame_allocator
+            .allocate_one()
+            .ok_or(MmError::OutOfMemory {
+                needed: PageFrameCount::new(1),
+            })?;
+        kmem_stat_add(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+        frame_tag_set(frame, PageFrameCount::new(1), FRAME_TAG_PAGE_TABLE);
+        PageTableAllocStats::record(table.level() - 1);
+        // 清空这个页帧
+        Arch::write_bytes(Arch::phys_2_virt(frame).unwrap(), 0, Arch::PAGE_SIZE);
+
+        // 设置页表项的flags
+        // let flags = Arch::ENTRY_FLAG_READWRITE
+        //     | Arch::ENTRY_FLAG_DEFAULT_TABLE
+        //     | if virt.kind() == PageTableKind::User {
+        //         Arch::ENTRY_FLAG_USER
+        //     } else {
+        //         0
+        //     };
+        let flags: PageFlags<Arch> = PageFlags::new_page_table(virt.kind() == PageTableKind::User);
+
+        // kdebug!("Flags: {:?}", flags);
+
+        // 把新分配的页表映射到当前页表
+        table.set_entry(i, PageEntry::new_mapping(frame, flags.data()));
+
+        // 获取新分配的页表
+        return table.next_level_table(i).ok_or(MmError::NotMapped(virt));
+    }
+
+    /// 把多段不连续的物理内存，依次映射到一段连续的虚拟地址空间
+    ///
+    /// 如果某一页映射失败，那么之前已经映射的页面都会被取消映射。
+    ///
+    /// ## 参数
+    ///
+    /// - virt_start 虚拟地址空间的起始地址
+    /// - segments 物理内存段的列表，每一项为(起始物理地址, 页帧数量)
+    /// - flags 页表项的flags
     ///
     /// ## 返回值
     ///
-    /// 如果取消成功，返回物理地址和页表项的flags，否则返回None
-    pub unsafe fn unmap_phys(
+    /// 如果映射成功，返回映射的总页数以及刷新器
+    pub unsafe fn map_scatter(
         &mut self,
-        virt: VirtAddr,
-        unmap_parents: bool,
-    ) -> Option<(PhysAddr, PageFlags<Arch>, PageFlush<Arch>)> {
-        if !virt.check_aligned(Arch::PAGE_SIZE) {
-            kerror!("Try to unmap unaligned page: virt={:?}", virt);
-            return None;
+        virt_start: VirtAddr,
+        segments: &[(PhysAddr, PageFrameCount)],
+        flags: PageFlags<Arch>,
+    ) -> Result<(PageFrameCount, FlushBatch<Arch>), SystemError> {
+        if segments.iter().all(|(_, count)| count.data() == 0) {
+            return Ok((PageFrameCount::new(0), FlushBatch::new()));
+        }
+        if !virt_start.check_aligned(Arch::PAGE_SIZE)
+            || segments
+                .iter()
+                .any(|(paddr, _)| !paddr.check_aligned(Arch::PAGE_SIZE))
+        {
+            return Err(SystemError::EINVAL);
         }
 
-        let mut table = self.table();
-        return unmap_phys_inner(virt, &mut table, unmap_parents, self.allocator_mut())
-            .map(|(paddr, flags)| (paddr, flags, PageFlush::<Arch>::new(virt)));
+        let mut flusher = FlushBatch::new();
+        let mut mapped = PageFrameCount::new(0);
+        let mut vaddr = virt_start;
+        for (paddr, count) in segments.iter() {
+            for i in 0..count.data() {
+                match self.map_phys(vaddr, *paddr + i * Arch::PAGE_SIZE, flags) {
+                    Ok(flush) => flusher.consume(flush),
+                    Err(e) => {
+                        kdebug!("map_scatter: failed to map {:?}: {}", vaddr, e);
+                        // 回滚已经建立的映射
+                        self.rollback_mapped(virt_start, mapped, &mut flusher);
+                        flusher.commit();
+                        return Err(e.into());
+                    }
+                }
+                mapped += 1;
+                vaddr += Arch::PAGE_SIZE;
+            }
+        }
+
+        return Ok((mapped, flusher));
     }
 
-    /// 在页表中，访问虚拟地址对应的页表项，并调用传入的函数F
-    fn visit<T>(
-        &self,
-        virt: VirtAddr,
+    /// 选择从virt开始映射时，能够使用的最大的页的大小
+    ///
+    /// virt、phys都按2M对齐，并且剩余的大小不少于2M时，返回2M，否则返回`Arch::PAGE_SIZE`。
+    /// 如果设置了`FORCE_4K_PAGES`，总是返回`Arch::PAGE_SIZE`。
+    ///
+    /// TODO: 支持1G的页（x86_64上，是否可用由`X86_64MMArch::huge_page_support`给出）
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    /// - phys 物理地址
+    /// - remaining 剩余要映射的字节数
+    pub fn best_page_size(&self, virt: VirtAddr, phys: PhysAddr, remaining: usize) -> usize {
+        let huge = PageSize::Size2M.bytes::<Arch>();
+        if force_4k_pages()
+            || remaining < huge
+            || !virt.check_aligned(huge)
+            || !phys.check_aligned(huge)
+        {
+            return Arch::PAGE_SIZE;
+        }
+        return huge;
+    }
+
+    /// 把一段连续的物理内存映射到一段连续的虚拟地址空间，尽可能使用大的页（参见`best_page_size`）
+    ///
+    /// 如果某一页映射失败，那么之前已经映射的页面都会被取消映射。
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址空间的起始地址
+    /// - phys 物理内存的起始地址
+    /// - count 要映射的4K页的数量
+    /// - flags 页表项的flags
+    ///
+    /// ## 返回值
+    ///
+    /// 如果映射成功，返回刷新器
+    pub unsafe fn map_phys_best(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        count: PageFrameCount,
+        flags: PageFlags<Arch>,
+    ) -> Result<FlushBatch<Arch>, SystemError> {
+        let mut flusher = FlushBatch::new();
+        let mut offset = 0;
+        while offset < count.bytes() {
+            let size = self.best_page_size(virt + offset, phys + offset, count.bytes() - offset);
+            let page_size = if size == Arch::PAGE_SIZE {
+                PageSize::Size4K
+            } else {
+                PageSize::Size2M
+            };
+            match self.map_phys_huge(virt + offset, phys + offset, flags, page_size) {
+                Ok(flush) => flusher.consume(flush),
+                Err(e) => {
+                    kdebug!("map_phys_best: failed to map {:?}: {}", virt + offset, e);
+                    // 回滚已经建立的映射
+                    let mapped = PageFrameCount::new(offset / Arch::PAGE_SIZE);
+                    self.rollback_mapped(virt, mapped, &mut flusher);
+                    flusher.commit();
+                    return Err(e.into());
+                }
+            }
+            offset += size;
+        }
+        return Ok(flusher);
+    }
+
+    /// 把一段连续的物理内存映射到一段连续的虚拟地址空间（只使用4K页）
+    ///
+    /// 如果某一页映射失败，那么之前已经映射的页面都会被取消映射。
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址空间的起始地址
+    /// - phys 物理内存的起始地址
+    /// - count 要映射的页数（为0时不做任何事情）
+    /// - flags 页表项的flags
+    ///
+    /// ## 返回值
+    ///
+    /// 如果映射成功，返回刷新器
+    pub unsafe fn map_phys_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        count: PageFrameCount,
+        flags: PageFlags<Arch>,
+    ) -> Result<FlushBatch<Arch>, SystemError> {
+        let mut flusher = FlushBatch::new();
+        for i in 0..count.data() {
+            let offset = i * Arch::PAGE_SIZE;
+            match self.map_phys(virt + offset, phys + offset, flags) {
+                Ok(flush) => flusher.consume(flush),
+                Err(e) => {
+                    kdebug!("map_phys_range: failed to map {:?}: {}", virt + offset, e);
+                    self.rollback_mapped(virt, PageFrameCount::new(i), &mut flusher);
+                    flusher.commit();
+                    return Err(e.into());
+                }
+            }
+        }
+        return Ok(flusher);
+    }
+
+    /// 取消映射失败之前，已经建立的count个页面的映射（不释放页帧）
+    unsafe fn rollback_mapped(
+        &mut self,
+        virt: VirtAddr,
+        count: PageFrameCount,
+        flusher: &mut FlushBatch<Arch>,
+    ) {
+        for i in 0..count.data() {
+            if let Ok((_, _, flush)) = self.unmap_phys(virt + i * Arch::PAGE_SIZE, true) {
+                flusher.consume(flush);
+            }
+        }
+    }
+
+    /// 释放一段虚拟地址范围内已经没有任何映射的页表
+    ///
+    /// 顶级页表以及顶级页表直接指向的页表不会被释放：内核的顶级页表项会被复制到每个进程的页表中，
+    /// 释放它们指向的页表会使这些副本指向已经被释放的页帧。与`unmap_phys`的`unmap_parents`不同，
+    /// 范围中没有被映射的页面不会影响页表的回收
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址空间的起始地址
+    /// - count 页数
+    ///
+    /// ## 返回值
+    ///
+    /// 返回被释放的页表的数量。调用者需要刷新这个范围的TLB（页表项的缓存）
+    pub unsafe fn reclaim_empty_tables(&mut self, virt: VirtAddr, count: PageFrameCount) -> usize {
+        let end = virt.data().saturating_add(count.bytes());
+        let mut freed = 0;
+        // 从下往上回收，下一级页表被释放之后，上一级页表可能也变为空
+        for level in 1..Arch::PAGE_LEVELS - 1 {
+            // 第level级页表中，每一项所映射的范围
+            let span = Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT);
+            let mut addr = virt.data() & !(span - 1);
+            while addr < end {
+                let vaddr = VirtAddr::new(addr);
+                let mut table = Some(self.table());
+                while let Some(t) = table.as_ref().filter(|t| t.level() > level) {
+                    table = t.index_of(vaddr).and_then(|i| t.next_level_table(i));
+                }
+                if let Some(table) = table {
+                    let i = table.index_of(vaddr).unwrap();
+                    if let Some(subtable) = table.next_level_table(i) {
+                        let empty = (0..Arch::PAGE_ENTRY_NUM).all(|k| {
+                            let e = subtable.entry(k).expect("invalid page entry");
+                            !e.present() && e.data() & Arch::ENTRY_FLAG_SWAP == 0
+                        });
+                        if empty {
+                            table.set_entry(i, PageEntry::new(0));
+                            self.frame_allocator.free_one(subtable.phys());
+                            kmem_stat_sub(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+                            freed += 1;
+                        }
+                    }
+                }
+                addr = match addr.checked_add(span) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+        }
+        return freed;
+    }
+
+    /// 取消一段连续的虚拟地址空间的映射（不释放页帧）。没有被映射的页面会被跳过
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址空间的起始地址（必须按页对齐）
+    /// - count 页数（为0时不做任何事情）
+    /// - unmap_parents 是否释放变为空的页表
+    ///
+    /// ## 返回值
+    ///
+    /// 返回被取消映射的页数以及刷新器。如果virt没有按页对齐，返回EINVAL
+    pub unsafe fn unmap_range(
+        &mut self,
+        virt: VirtAddr,
+        count: PageFrameCount,
+        unmap_parents: bool,
+    ) -> Result<(PageFrameCount, FlushBatch<Arch>), SystemError> {
+        let mut flusher = FlushBatch::new();
+        if count.data() == 0 {
+            return Ok((PageFrameCount::new(0), flusher));
+        }
+        if !virt.check_aligned(Arch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+
+        let mut unmapped = PageFrameCount::new(0);
+        for i in 0..count.data() {
+            if let Ok((_, _, flush)) = self.unmap_phys(virt + i * Arch::PAGE_SIZE, unmap_parents) {
+                flusher.consume(flush);
+                unmapped += 1;
+            }
+        }
+        return Ok((unmapped, flusher));
+    }
+
+    /// 修改一段连续的虚拟地址空间中，所有已映射页面的flags。没有被映射的页面会被跳过
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址空间的起始地址（必须按页对齐）
+    /// - count 页数（为0时不做任何事情）
+    /// - flags 新的页表项的flags
+    ///
+    /// ## 返回值
+    ///
+    /// 返回被修改的页数以及刷新器。如果virt没有按页对齐，返回EINVAL
+    pub unsafe fn protect_range(
+        &mut self,
+        virt: VirtAddr,
+        count: PageFrameCount,
+        flags: PageFlags<Arch>,
+    ) -> Result<(PageFrameCount, FlushBatch<Arch>), SystemError> {
+        let mut flusher = FlushBatch::new();
+        if count.data() == 0 {
+            return Ok((PageFrameCount::new(0), flusher));
+        }
+        if !virt.check_aligned(Arch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+
+        let mut changed = PageFrameCount::new(0);
+        for i in 0..count.data() {
+            let vaddr = virt + i * Arch::PAGE_SIZE;
+            // 与`remap`不同，不能修改不存在的页表项
+            let flush = self
+                .visit(vaddr, |p1, j| {
+                    let mut entry = p1.entry(j)?;
+                    if !entry.present() {
+                        return None;
+                    }
+                    entry.set_flags(flags);
+                    p1.set_entry(j, entry)?;
+                    Some(PageFlush::new(vaddr))
+                })
+                .flatten();
+            if let Some(flush) = flush {
+                flusher.consume(flush);
+                changed += 1;
+            }
+        }
+        return Ok((changed, flusher));
+    }
+
+    /// 将物理地址映射到具有线性偏移量的虚拟地址
+    #[allow(dead_code)]
+    pub unsafe fn map_linearly(
+        &mut self,
+        phys: PhysAddr,
+        flags: PageFlags<Arch>,
+    ) -> Option<(VirtAddr, PageFlush<Arch>)> {
+        let virt: VirtAddr = Arch::phys_2_virt(phys)?;
+        return self
+            .map_phys(virt, phys, flags)
+            .ok()
+            .map(|flush| (virt, flush));
+    }
+
+    /// 修改虚拟地址的页表项的flags，并返回页表项刷新器
+    ///
+    /// 请注意，需要在修改完flags后，调用刷新器的flush方法，才能使修改生效
+    ///
+    /// ## 参数
+    /// - virt 虚拟地址
+    /// - flags 新的页表项的flags
+    ///
+    /// ## 返回值
+    ///
+    /// 如果修改成功，返回刷新器，否则返回None
+    pub unsafe fn remap(
+        &mut self,
+        virt: VirtAddr,
+        flags: PageFlags<Arch>,
+    ) -> Option<PageFlush<Arch>> {
+        return self
+            .visit_mut(virt, |p1, i| {
+                let mut entry = p1.entry(i)?;
+                entry.set_flags(flags);
+                p1.set_entry(i, entry);
+                Some(PageFlush::new(virt))
+            })
+            .flatten();
+    }
+
+    /// 收紧页面的权限（比如去掉写权限），并立即刷新所有CPU上的TLB
+    ///
+    /// 与先调用`remap`再刷新TLB不同，这个函数保证以下的顺序：
+    /// 1. 通过一次原子的交换操作写入新的页表项，同时取得旧的页表项。
+    ///    因此，在读取与写入之间由硬件设置的脏位不会丢失
+    /// 2. 刷新当前CPU的TLB，并向其他CPU发送刷新TLB的IPI（只有一个CPU时跳过）
+    /// 3. 再次读取页表项的脏位
+    ///
+    /// 在第1步之后，TLB中没有缓存该页面（或者缓存的条目中脏位为0）的CPU在写入时，会重新遍历页表并看到新的权限，从而触发缺页异常；
+    /// 而TLB中缓存了可写且脏的条目的CPU，在写入时不会修改页表项，但是这种情况下旧的页表项中的脏位已经为1。
+    /// 因此，返回值能够反映在权限被收紧之前，页面是否被写入过。
+    ///
+    /// 第2步会等待其他CPU完成刷新（参见`crate::arch::mm::tlb_shootdown_sync`），因此返回之后，任何CPU上的写入都会看到新的权限
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    /// - new_flags 新的页表项的flags
+    ///
+    /// ## 返回值
+    ///
+    /// 如果页面在此之前被写入过（脏位为1），返回true。如果虚拟地址未映射，返回`MmError::NotMapped`
+    pub unsafe fn protect_and_flush(
+        &mut self,
+        virt: VirtAddr,
+        new_flags: PageFlags<Arch>,
+    ) -> Result<bool, MmError> {
+        let entry_virt = self
+            .visit_mut(virt, |p1, i| {
+                let entry = p1.entry(i)?;
+                if !entry.present() {
+                    return None;
+                }
+                p1.entry_virt(i)
+            })
+            .flatten()
+            .ok_or(MmError::NotMapped(virt))?;
+        let entry_ref = &*(entry_virt.data() as *const AtomicUsize);
+
+        // 1. 原子地替换页表项，保留物理地址
+        let old = entry_ref.load(Ordering::Acquire);
+        let new = (old & Arch::PAGE_ADDRESS_MASK) | new_flags.to_entry_bits();
+        let old = entry_ref.swap(new, Ordering::SeqCst);
+        translate_cache_invalidate();
+
+        // 2. 刷新TLB
+        Arch::invalidate_page(virt);
+        crate::arch::mm::tlb_shootdown_sync();
+        compiler_fence(Ordering::SeqCst);
+
+        // 3. 读取刷新之后的脏位
+        let after = entry_ref.load(Ordering::Acquire);
+        let dirty = (old | after) & Arch::ENTRY_FLAG_DIRTY != 0;
+        return Ok(dirty);
+    }
+
+    /// 修改已映射页面的页表项中的指定位，页表项中的其它位保持不变
+    ///
+    /// 与`protect`不同，这个方法也可以修改页表项的地址字段中的位（比如AMD SME/SEV的C位）
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    /// - clear 要清除的位
+    /// - set 要设置的位
+    ///
+    /// ## 返回值
+    ///
+    /// 成功时返回刷新器。如果虚拟地址未映射，返回`MmError::NotMapped`
+    pub unsafe fn update_entry_bits(
+        &mut self,
+        virt: VirtAddr,
+        clear: usize,
+        set: usize,
+    ) -> Result<PageFlush<Arch>, MmError> {
+        let entry_virt = self
+            .visit_mut(virt, |p1, i| {
+                let entry = p1.entry(i)?;
+                if !entry.present() {
+                    return None;
+                }
+                p1.entry_virt(i)
+            })
+            .flatten()
+            .ok_or(MmError::NotMapped(virt))?;
+        let entry_ref = &*(entry_virt.data() as *const AtomicUsize);
+        // 硬件可能同时设置访问位、脏位，因此需要原子地修改
+        entry_ref
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |old| {
+                Some((old & !clear) | set)
+            })
+            .ok();
+        translate_cache_invalidate();
+        return Ok(PageFlush::new(virt));
+    }
+
+    /// 原子地替换已映射页面的物理页帧，并刷新TLB（用于页面迁移、去重）
+    ///
+    /// 页表项通过一次原子操作从旧的页帧切换到新的页帧，保留原有的flags（包括在此期间由硬件设置的访问位、脏位），
+    /// 因此不存在页面未被映射的时间窗口。
+    ///
+    /// 调用者需要保证新旧页帧的内容相同，并在此之后负责释放旧的页帧。
+    /// 返回之前会等待其他CPU完成刷新（参见`crate::arch::mm::tlb_shootdown_sync`），因此返回之后旧的页帧可以立即被重用。
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址（必须按页对齐）
+    /// - new_phys 新的物理页帧的地址（必须按页对齐）
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回旧的页帧的物理地址
+    /// - `EINVAL`：地址没有按页对齐
+    /// - `EFAULT`：虚拟地址未映射（或者页面不存在）
+    pub unsafe fn replace_frame(
+        &mut self,
+        virt: VirtAddr,
+        new_phys: PhysAddr,
+    ) -> Result<PhysAddr, SystemError> {
+        if !(virt.check_aligned(Arch::PAGE_SIZE) && new_phys.check_aligned(Arch::PAGE_SIZE)) {
+            return Err(SystemError::EINVAL);
+        }
+        let entry_virt = self
+            .visit_mut(virt, |p1, i| {
+                let entry = p1.entry(i)?;
+                if !entry.present() {
+                    return None;
+                }
+                p1.entry_virt(i)
+            })
+            .flatten()
+            .ok_or(MmError::NotMapped(virt))?;
+        let entry_ref = &*(entry_virt.data() as *const AtomicUsize);
+
+        // 只替换物理地址，保留flags。硬件可能同时设置访问位、脏位，因此使用CAS循环
+        let mut old = entry_ref.load(Ordering::Acquire);
+        loop {
+            let new = (old & !Arch::PAGE_ADDRESS_MASK)
+                | PageEntry::<Arch>::new_mapping(new_phys, 0).data();
+            match entry_ref.compare_exchange(old, new, Ordering::SeqCst, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => old = current,
+            }
+        }
+        translate_cache_invalidate();
+
+        Arch::invalidate_page(virt);
+        crate::arch::mm::tlb_shootdown_sync();
+        compiler_fence(Ordering::SeqCst);
+
+        return Ok(PhysAddr::new(
+            old & Arch::PAGE_ADDRESS_MASK & !Arch::encrypt_mask(),
+        ));
+    }
+
+    /// 将虚拟地址映射到的物理页替换为新的物理页（保留原有的flags），并返回原来的物理地址以及页表项刷新器
+    ///
+    /// 页表项的更新通过一次写入完成，因此不存在“页面暂时未映射”的中间状态
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    /// - phys 新的物理地址
+    ///
+    /// ## 返回值
+    ///
+    /// 如果替换成功，返回原来的物理地址和刷新器。如果虚拟地址未映射，返回None
+    pub unsafe fn replace_phys(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+    ) -> Option<(PhysAddr, PageFlush<Arch>)> {
+        if !(virt.check_aligned(Arch::PAGE_SIZE) && phys.check_aligned(Arch::PAGE_SIZE)) {
+            kerror!(
+                "Try to replace unaligned page: virt={:?}, phys={:?}",
+                virt,
+                phys
+            );
+            return None;
+        }
+
+        return self
+            .visit_mut(virt, |p1, i| {
+                let old_entry = p1.entry(i)?;
+                let old_phys = old_entry.address().ok()?;
+                let new_entry = PageEntry::new_mapping(phys, old_entry.flags().to_entry_bits());
+                compiler_fence(Ordering::SeqCst);
+                p1.set_entry(i, new_entry);
+                compiler_fence(Ordering::SeqCst);
+                Some((old_phys, PageFlush::new(virt)))
+            })
+            .flatten();
+    }
+
+    /// 根据虚拟地址，查找页表，获取对应的物理地址和页表项的flags
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    ///
+    /// ## 返回值
+    ///
+    /// 如果查找成功，返回物理地址和页表项的flags，否则返回None
+    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, PageFlags<Arch>)> {
+        let vpn = virt.data() >> Arch::PAGE_SHIFT;
+        // 在遍历页表之前读取版本号，从而保证遍历过程中发生的修改会使本次填入的缓存失效
+        let gen = TRANSLATE_CACHE_GEN.load(Ordering::Acquire);
+        if let Some((paddr, flags)) = translate_cache_lookup(self.table_paddr, vpn, gen) {
+            return Some((paddr, unsafe { PageFlags::from_data(flags) }));
+        }
+
+        let (level, entry) = self.leaf_entry(virt)?;
+        let (paddr, flags) = if level == 0 {
+            (entry.address().ok()?, entry.flags())
+        } else {
+            // 大页：返回virt所在的4K页面的物理地址（大页的PAT位位于地址字段中，需要清除）
+            let size = Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT);
+            let base = entry.address().ok()?.data() & !(size - 1);
+            let offset = virt.data() & (size - 1) & !Arch::PAGE_OFFSET_MASK;
+            (
+                PhysAddr::new(base + offset),
+                PageFlags::from_entry_bits_at(level, entry.data()),
+            )
+        };
+        translate_cache_fill(self.table_paddr, vpn, gen, paddr, flags.data());
+        return Some((paddr, flags));
+    }
+
+    /// 查找虚拟地址所在的最后一级页表项（4K页面的页表项，或者映射大页的页表项）
+    ///
+    /// ## 返回值
+    ///
+    /// (页表项所在的页表的级别, 页表项)。如果虚拟地址未映射，返回None
+    pub fn leaf_entry(&self, virt: VirtAddr) -> Option<(usize, PageEntry<Arch>)> {
+        let mut table = self.table();
+        unsafe {
+            loop {
+                let i = table.index_of(virt)?;
+                let entry = table.entry(i)?;
+                if !entry.present() {
+                    return None;
+                }
+                let level = table.level();
+                let huge = level > 0
+                    && level < Arch::PAGE_LEVELS - 1
+                    && entry.data() & Arch::ENTRY_FLAG_HUGE_PAGE != 0;
+                if level == 0 || huge {
+                    return Some((level, entry));
+                }
+                table = table.next_level_table(i)?;
+            }
+        }
+    }
+
+    /// 读取虚拟地址对应的最后一级（4K）页表项，页面不存在时同样返回页表项（比如换出的页表项）
+    ///
+    /// ## 返回值
+    ///
+    /// 如果最后一级页表不存在（包括虚拟地址位于一个大页中的情况），返回None
+    pub fn raw_entry(&self, virt: VirtAddr) -> Option<PageEntry<Arch>> {
+        return self.visit(virt, |p1, i| unsafe { p1.entry(i) }).flatten();
+    }
+
+    /// 直接写入虚拟地址对应的最后一级（4K）页表项，不会分配页表（但是会拆分虚拟地址所在的大页）
+    ///
+    /// 调用者负责原来的页表项所指向的页帧，以及刷新TLB
+    ///
+    /// ## 返回值
+    ///
+    /// 原来的页表项。如果最后一级页表不存在，返回None
+    pub unsafe fn set_raw_entry(
+        &mut self,
+        virt: VirtAddr,
+        entry: PageEntry<Arch>,
+    ) -> Option<PageEntry<Arch>> {
+        return self
+            .visit_mut(virt, |p1, i| {
+                let old = p1.entry(i)?;
+                p1.set_entry(i, entry)?;
+                Some(old)
+            })
+            .flatten();
+    }
+
+    /// 获取CPU访问虚拟地址时实际使用的缓存类型（用于调试DMA、MMIO的一致性问题）
+    ///
+    /// 根据最后一级页表项中的PAT、PCD、PWT位（大页的PAT位位于第12位）选择PAT表项，
+    /// 并按照当前IA32_PAT寄存器中的设置解析。不考虑MTRR
+    ///
+    /// ## 返回值
+    ///
+    /// 如果虚拟地址未映射，返回None
+    #[cfg(target_arch = "x86_64")]
+    pub fn effective_cache_type(&self, virt: VirtAddr) -> Option<CacheType> {
+        let pat = crate::arch::mm::X86_64MMArch::pat_entries();
+        return self.effective_cache_type_with_pat(virt, &pat);
+    }
+
+    /// 与`effective_cache_type`相同，但是按照给定的PAT设置解析（pat中的第i项为第i个PAT表项的缓存类型）
+    #[cfg(target_arch = "x86_64")]
+    pub fn effective_cache_type_with_pat(
+        &self,
+        virt: VirtAddr,
+        pat: &[CacheType; 8],
+    ) -> Option<CacheType> {
+        let (level, entry) = self.leaf_entry(virt)?;
+        let flags = PageFlags::<Arch>::from_entry_bits_at(level, entry.data());
+        let index = (flags.has_page_attribute_table() as usize) << 2
+            | (flags.has_page_cache_disable() as usize) << 1
+            | flags.has_page_write_through() as usize;
+        return Some(pat[index]);
+    }
+
+    /// 以一行可读的文本描述虚拟地址的翻译过程（用于调试）
+    ///
+    /// 例如`PML4[0]→PDPT[1]→PD[0]→PT[5]→phys 0x205000; flags PRESENT|RW|USER|NX`。
+    /// 遇到映射大页的页表项时，在该级页表之后标注页面的大小，比如`PD[3](2M huge)`。
+    /// 如果遍历在某一级终止，描述终止的位置，比如`PML4[0]→PDPT[1]→PD[1] not present`
+    pub fn explain(&self, virt: VirtAddr) -> String {
+        let mut s = String::new();
+        if !Arch::virt_is_valid(virt) {
+            write!(s, "{:?} is not canonical", virt).ok();
+            return s;
+        }
+
+        let mut table = self.table();
+        loop {
+            let level = table.level();
+            let i = match unsafe { table.index_of(virt) } {
+                Some(i) => i,
+                None => {
+                    write!(s, "{:?} is out of range", virt).ok();
+                    return s;
+                }
+            };
+            if !s.is_empty() {
+                s.push('→');
+            }
+            write!(s, "{}[{}]", Arch::PAGE_TABLE_NAMES[level], i).ok();
+
+            let entry = unsafe { table.entry(i) }.unwrap();
+            if !entry.present() {
+                s.push_str(" not present");
+                return s;
+            }
+            // 最顶级的页表项不能映射大页
+            let huge = level > 0
+                && level < Arch::PAGE_LEVELS - 1
+                && entry.data() & Arch::ENTRY_FLAG_HUGE_PAGE != 0;
+            if level == 0 || huge {
+                let page_size = Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT);
+                if huge {
+                    if page_size >= 1 << 30 {
+                        write!(s, "({}G huge)", page_size >> 30).ok();
+                    } else {
+                        write!(s, "({}M huge)", page_size >> 20).ok();
+                    }
+                }
+                let base = entry.data()
+                    & Arch::PAGE_ADDRESS_MASK
+                    & !Arch::encrypt_mask()
+                    & !(page_size - 1);
+                let flags = PageFlags::<Arch>::from_entry_bits_at(level, entry.data());
+                write!(
+                    s,
+                    "→phys {:#x}; flags {}",
+                    base + (virt.data() & (page_size - 1)),
+                    flags.describe()
+                )
+                .ok();
+                return s;
+            }
+            table = unsafe { table.next_level_table(i) }.unwrap();
+        }
+    }
+
+    /// 取消虚拟地址的映射，释放页面，并返回页表项刷新器
+    ///
+    /// 请注意，需要在取消映射后，调用刷新器的flush方法，才能使修改生效
+    ///
+    /// ## 参数
+    ///
+    /// - virt 虚拟地址
+    /// - unmap_parents 是否在父页表内，取消空闲子页表的映射
+    ///
+    /// ## 返回值
+    /// 如果取消成功，返回刷新器，否则返回None
+    pub unsafe fn unmap(&mut self, virt: VirtAddr, unmap_parents: bool) -> Option<PageFlush<Arch>> {
+        let (paddr, _, flusher) = self.unmap_phys(virt, unmap_parents).ok()?;
+        self.frame_allocator.free_one(paddr);
+        return Some(flusher);
+    }
+
+    /// 取消虚拟地址的映射，并返回物理地址和页表项的flags
+    ///
+    /// ## 参数
+    ///
+    /// - vaddr 虚拟地址
+    /// - unmap_parents 是否在父页表内，取消空闲子页表的映射
+    ///
+    /// ## 返回值
+    ///
+    /// 如果取消成功，返回物理地址、页表项的flags以及刷新器，否则返回对应的错误。
+    /// 与`map_phys`相同，地址没有对齐或者不合法时不会遍历页表
+    pub unsafe fn unmap_phys(
+        &mut self,
+        virt: VirtAddr,
+        unmap_parents: bool,
+    ) -> Result<(PhysAddr, PageFlags<Arch>, PageFlush<Arch>), MmError> {
+        if !virt.check_aligned(Arch::PAGE_SIZE) {
+            kerror!("Try to unmap unaligned page: virt={:?}", virt);
+            return Err(MmError::Unaligned(virt.data()));
+        }
+        // 非规范地址在页表中的下标会与某个规范地址重合，不能继续遍历页表
+        if !Arch::virt_is_valid(virt) {
+            return Err(MmError::NotCanonical(virt));
+        }
+
+        let mut table = self.table();
+        return unmap_phys_inner(virt, &mut table, unmap_parents, self.allocator_mut())
+            .map(|(paddr, flags)| (paddr, flags, PageFlush::<Arch>::new(virt)))
+            .ok_or(MmError::NotMapped(virt));
+    }
+
+    /// 在range范围内，查找与虚拟地址最近的已映射页面（用于诊断访问越界之类的错误）
+    ///
+    /// vaddr所在的页面本身不参与查找
+    ///
+    /// ## 参数
+    ///
+    /// - vaddr 虚拟地址
+    /// - range 查找的范围（规范地址）
+    ///
+    /// ## 返回值
+    ///
+    /// (下方最近的已映射页面的结束地址, 上方最近的已映射页面的起始地址)
+    pub fn nearest_mappings(
+        &self,
+        vaddr: VirtAddr,
+        range: Range<VirtAddr>,
+    ) -> (Option<VirtAddr>, Option<VirtAddr>) {
+        // 页表中的地址不包含符号扩展的部分
+        let linear = |v: VirtAddr| v.data() & !Arch::PAGE_NEGATIVE_MASK;
+        let canonical = |l: usize| {
+            if l & (Arch::PAGE_ADDRESS_SIZE >> 1) != 0 {
+                VirtAddr::new(l | Arch::PAGE_NEGATIVE_MASK)
+            } else {
+                VirtAddr::new(l)
+            }
+        };
+        let page = linear(vaddr) & !(Arch::PAGE_SIZE - 1);
+        let (lo, hi) = (linear(range.start), linear(range.end));
+        let table = self.table();
+
+        let below = unsafe { Self::find_mapped_page(&table, lo, hi.min(page), true) };
+        let above =
+            unsafe { Self::find_mapped_page(&table, lo.max(page + Arch::PAGE_SIZE), hi, false) };
+        return (
+            below.map(|base| canonical(base + Arch::PAGE_SIZE)),
+            above.map(canonical),
+        );
+    }
+
+    /// 在window范围内，查找一段长度为len、起始地址按align对齐、且没有任何页面被映射的虚拟地址范围
+    ///
+    /// 映射大页的页表项所覆盖的整个范围都视为已被映射
+    ///
+    /// ## 参数
+    ///
+    /// - window 查找的范围（规范地址，不能跨越高低两半地址空间）
+    /// - len 长度（字节，会向上对齐到页大小）
+    /// - align 起始地址的对齐（必须是2的幂，小于页大小时按页大小对齐）
+    /// - top_down 为true时，从高地址向低地址查找（返回最高的空洞）；否则从低地址向高地址查找
+    ///
+    /// ## 返回值
+    ///
+    /// 找到的范围的起始地址。如果没有足够大的空洞，或者参数不合法，返回None
+    pub fn find_free_range(
+        &self,
+        window: Range<VirtAddr>,
+        len: usize,
+        align: usize,
+        top_down: bool,
+    ) -> Option<VirtAddr> {
+        if len == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let len = len.checked_add(Arch::PAGE_SIZE - 1)? & !(Arch::PAGE_SIZE - 1);
+        let align = align.max(Arch::PAGE_SIZE);
+        // 页表中的地址不包含符号扩展的部分
+        let linear = |v: VirtAddr| v.data() & !Arch::PAGE_NEGATIVE_MASK;
+        let canonical = |l: usize| {
+            if l & (Arch::PAGE_ADDRESS_SIZE >> 1) != 0 {
+                VirtAddr::new(l | Arch::PAGE_NEGATIVE_MASK)
+            } else {
+                VirtAddr::new(l)
+            }
+        };
+        let lo = linear(window.start);
+        let hi = linear(window.end) & !(Arch::PAGE_SIZE - 1);
+        let table = self.table();
+
+        if top_down {
+            let mut end = hi;
+            loop {
+                let start = end.checked_sub(len)? & !(align - 1);
+                if start < lo {
+                    return None;
+                }
+                // 与候选范围重叠的最高的映射的起始地址，作为下一个候选范围的上界
+                match unsafe { Self::find_mapped_unit(&table, start, start + len, true) } {
+                    Some((unit_start, _)) => end = unit_start,
+                    None => return Some(canonical(start)),
+                }
+            }
+        } else {
+            let mut start = lo.checked_add(align - 1)? & !(align - 1);
+            loop {
+                let end = start.checked_add(len)?;
+                if end > hi {
+                    return None;
+                }
+                // 与候选范围重叠的最低的映射的结束地址，作为下一个候选范围的下界
+                match unsafe { Self::find_mapped_unit(&table, start, end, false) } {
+                    Some((_, unit_end)) => start = unit_end.checked_add(align - 1)? & !(align - 1),
+                    None => return Some(canonical(start)),
+                }
+            }
+        }
+    }
+
+    /// 在页表中，查找与[lo, hi)重叠的第一个（descending为true时为最后一个）已映射的页面或者大页
+    ///
+    /// ## 返回值
+    ///
+    /// 页面或者大页所覆盖的范围（起始地址, 结束地址）
+    unsafe fn find_mapped_unit(
+        table: &PageTable<Arch>,
+        lo: usize,
+        hi: usize,
+        descending: bool,
+    ) -> Option<(usize, usize)> {
+        if lo >= hi {
+            return None;
+        }
+        let level = table.level();
+        let size = 1 << (level * Arch::PAGE_ENTRY_SHIFT + Arch::PAGE_SHIFT);
+        for k in 0..Arch::PAGE_ENTRY_NUM {
+            let i = if descending {
+                Arch::PAGE_ENTRY_NUM - 1 - k
+            } else {
+                k
+            };
+            let base = table.entry_base(i)?.data();
+            if base + size <= lo || base >= hi {
+                continue;
+            }
+            let entry = match table.entry(i) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            let huge = level > 0
+                && level < Arch::PAGE_LEVELS - 1
+                && entry.data() & Arch::ENTRY_FLAG_HUGE_PAGE != 0;
+            if level == 0 || huge {
+                return Some((base, base + size));
+            }
+            if let Some(next) = table.next_level_table(i) {
+                if let Some(r) = Self::find_mapped_unit(&next, lo, hi, descending) {
+                    return Some(r);
+                }
+            }
+        }
+        return None;
+    }
+
+    /// 在range范围内，查找同时可写、可执行的映射（W^X检查）
+    ///
+    /// 如果体系结构不支持不可执行位（比如x86_64的XD被保留），所有的映射都是可执行的，此时不做检查
+    ///
+    /// ## 参数
+    ///
+    /// - range 查找的范围（规范地址）
+    ///
+    /// ## 返回值
+    ///
+    /// 所有同时可写、可执行的映射的（起始虚拟地址, 大小），按地址从小到大排列
+    pub fn find_wx(&self, range: Range<VirtAddr>) -> Vec<(VirtAddr, usize)> {
+        let mut result = Vec::new();
+        if !Arch::can_enforce_wx() {
+            return result;
+        }
+
+        let linear = |v: VirtAddr| v.data() & !Arch::PAGE_NEGATIVE_MASK;
+        let (lo, hi) = (linear(range.start), linear(range.end));
+        unsafe { Self::find_wx_inner(&self.table(), lo, hi, &mut result) };
+        for (vaddr, _) in result.iter_mut() {
+            if vaddr.data() & (Arch::PAGE_ADDRESS_SIZE >> 1) != 0 {
+                *vaddr = VirtAddr::new(vaddr.data() | Arch::PAGE_NEGATIVE_MASK);
+            }
+        }
+        return result;
+    }
+
+    unsafe fn find_wx_inner(
+        table: &PageTable<Arch>,
+        lo: usize,
+        hi: usize,
+        result: &mut Vec<(VirtAddr, usize)>,
+    ) {
+        let level = table.level();
+        let size = Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT);
+        for i in 0..Arch::PAGE_ENTRY_NUM {
+            let base = match table.entry_base(i) {
+                Some(base) => base.data(),
+                None => continue,
+            };
+            if base + size <= lo || base >= hi {
+                continue;
+            }
+            let entry = match table.entry(i) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            let huge = level > 0
+                && level < Arch::PAGE_LEVELS - 1
+                && entry.data() & Arch::ENTRY_FLAG_HUGE_PAGE != 0;
+            if level == 0 || huge {
+                let flags = PageFlags::<Arch>::from_entry_bits_at(level, entry.data());
+                if flags.has_write() && flags.has_execute() {
+                    result.push((VirtAddr::new(base), size));
+                }
+            } else if let Some(next) = table.next_level_table(i) {
+                Self::find_wx_inner(&next, lo, hi, result);
+            }
+        }
+    }
+
+    /// 在页表中，查找位于[lo, hi)范围内的第一个（descending为true时为最后一个）已映射页面的起始地址
+    unsafe fn find_mapped_page(
+        table: &PageTable<Arch>,
+        lo: usize,
+        hi: usize,
+        descending: bool,
+    ) -> Option<usize> {
+        if lo >= hi {
+            return None;
+        }
+        let shift = table.level() * Arch::PAGE_ENTRY_SHIFT + Arch::PAGE_SHIFT;
+        for k in 0..Arch::PAGE_ENTRY_NUM {
+            let i = if descending {
+                Arch::PAGE_ENTRY_NUM - 1 - k
+            } else {
+                k
+            };
+            let base = table.entry_base(i)?.data();
+            if base + (1 << shift) <= lo || base >= hi {
+                continue;
+            }
+            match table.entry(i) {
+                Some(entry) if entry.present() => {}
+                _ => continue,
+            }
+            if table.level() == 0 {
+                return Some(base);
+            }
+            if table.entry_is_huge(i) {
+                // 大页中的每一个4K页面都被映射了
+                let page = if descending {
+                    (hi.min(base + (1 << shift)) - 1) & !Arch::PAGE_OFFSET_MASK
+                } else {
+                    lo.max(base) & !Arch::PAGE_OFFSET_MASK
+                };
+                return Some(page);
+            }
+            if let Some(next) = table.next_level_table(i) {
+                if let Some(r) = Self::find_mapped_page(&next, lo, hi, descending) {
+                    return Some(r);
+                }
+            }
+        }
+        return None;
+    }
+
+    /// 估算把一段虚拟地址空间以指定的页大小映射时，需要新分配的页表的数量
+    ///
+    /// 沿着页表从顶级开始向下查找：已经存在的页表不计入；对于不存在的页表项，
+    /// 根据范围的对齐情况，计算它下面的每一级各需要多少个页表。
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：起始虚拟地址（如果没有按`page_size`对齐，会向下对齐）
+    /// - `count`：要映射的页的数量（以`page_size`为单位）
+    /// - `page_size`：页的大小，必须是最后一级页表项（或者某一级大页）所覆盖的大小，
+    ///     例如x86_64上的4K、2M、1G
+    ///
+    /// ## 返回值
+    ///
+    /// 需要新分配的页表的数量
+    pub fn estimate_table_cost(
+        &self,
+        vaddr: VirtAddr,
+        count: PageFrameCount,
+        page_size: usize,
+    ) -> PageFrameCount {
+        // 映射的最后一级页表的级别
+        let leaf_level = (0..Arch::PAGE_LEVELS - 1)
+            .find(|level| Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT) == page_size)
+            .unwrap_or_else(|| panic!("estimate_table_cost: invalid page size {:#x}", page_size));
+        if count.data() == 0 {
+            return PageFrameCount::new(0);
+        }
+
+        // 页表中的地址不包含符号扩展的部分
+        let start = vaddr.data() & !Arch::PAGE_NEGATIVE_MASK;
+        let lo = start & !(page_size - 1);
+        let hi = (start + count.data() * page_size + page_size - 1) & !(page_size - 1);
+        let table = self.table();
+        return PageFrameCount::new(unsafe {
+            Self::count_missing_tables(&table, lo, hi, leaf_level)
+        });
+    }
+
+    unsafe fn count_missing_tables(
+        table: &PageTable<Arch>,
+        lo: usize,
+        hi: usize,
+        leaf_level: usize,
+    ) -> usize {
+        if table.level() == leaf_level {
+            return 0;
+        }
+        let shift = table.level() * Arch::PAGE_ENTRY_SHIFT + Arch::PAGE_SHIFT;
+        let mut cost = 0;
+        for i in 0..Arch::PAGE_ENTRY_NUM {
+            let base = match table.entry_base(i) {
+                Some(base) => base.data(),
+                None => continue,
+            };
+            if base + (1 << shift) <= lo || base >= hi {
+                continue;
+            }
+            let (start, end) = (lo.max(base), hi.min(base + (1 << shift)));
+            if let Some(next) = table.next_level_table(i) {
+                cost += Self::count_missing_tables(&next, start, end, leaf_level);
+                continue;
+            }
+            // 这个页表项不存在（或者映射了一个需要被拆分的大页），它下面的每一级页表都需要新分配：
+            // 第level级的每个页表覆盖 PAGE_SIZE << ((level + 1) * PAGE_ENTRY_SHIFT) 字节
+            for level in leaf_level..table.level() {
+                let span = Arch::PAGE_SIZE << ((level + 1) * Arch::PAGE_ENTRY_SHIFT);
+                let first = start & !(span - 1);
+                let last = (end + span - 1) & !(span - 1);
+                cost += (last - first) / span;
+            }
+        }
+        return cost;
+    }
+
+    /// 为一段虚拟地址空间预先分配所有的页表（不映射任何页），使得之后对这段地址空间的
+    /// `map_phys`不会因为分配页表而失败
+    ///
+    /// 分配之前，先通过`estimate_table_cost`检查空闲的页帧是否足够，不足时不会分配任何页表。
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：起始虚拟地址
+    /// - `count`：页的数量（以`Arch::PAGE_SIZE`为单位）
+    ///
+    /// ## 返回值
+    ///
+    /// 新分配的页表的数量。如果空闲的页帧不足，返回`MmError::OutOfMemory`
+    pub unsafe fn reserve_tables(
+        &mut self,
+        vaddr: VirtAddr,
+        count: PageFrameCount,
+    ) -> Result<PageFrameCount, MmError> {
+        if !Arch::virt_is_valid(vaddr) {
+            return Err(MmError::NotCanonical(vaddr));
+        }
+        let needed = self.estimate_table_cost(vaddr, count, Arch::PAGE_SIZE);
+        if needed.data() == 0 {
+            return Ok(needed);
+        }
+        if self.frame_allocator.usage().free().data() < needed.data() {
+            return Err(MmError::OutOfMemory { needed });
+        }
+
+        // 每个最后一级的页表所覆盖的大小
+        let span = Arch::PAGE_SIZE << Arch::PAGE_ENTRY_SHIFT;
+        let start = vaddr.data() & !Arch::PAGE_NEGATIVE_MASK;
+        let end = start + count.bytes();
+        let mut addr = start & !(span - 1);
+        while addr < end {
+            let virt = VirtAddr::new(addr);
+            let mut table = self.table();
+            while table.level() > 0 {
+                let i = table
+                    .index_of(virt)
+                    .ok_or(MmError::KernelRangeViolation(virt))?;
+                table = self.next_level_table_for_write(&table, i, virt)?;
+            }
+            addr += span;
+        }
+        return Ok(needed);
+    }
+
+    /// 在页表中，访问虚拟地址对应的最后一级页表项，并调用传入的函数F
+    ///
+    /// 如果虚拟地址位于一个大页中，返回None（需要修改页表项时，使用`visit_mut`）
+    fn visit<T>(
+        &self,
+        virt: VirtAddr,
         f: impl FnOnce(&mut PageTable<Arch>, usize) -> T,
     ) -> Option<T> {
         let mut table = self.table();
@@ -738,6 +2339,77 @@ impl<Arch: MemoryManagementArch, F: FrameAllocator> PageMapper<Arch, F> {
             }
         }
     }
+
+    /// 与`visit`相同，但是会拆分路径上映射大页的页表项，因此虚拟地址位于大页中时，同样能访问到最后一级页表项
+    ///
+    /// 拆分大页时需要分配页表，分配失败时返回None
+    unsafe fn visit_mut<T>(
+        &mut self,
+        virt: VirtAddr,
+        f: impl FnOnce(&mut PageTable<Arch>, usize) -> T,
+    ) -> Option<T> {
+        let mut table = self.table();
+        loop {
+            let i = table.index_of(virt)?;
+            if table.level() == 0 {
+                return Some(f(&mut table, i));
+            } else if table.entry_is_huge(i) {
+                table = split_huge_entry(&table, i, &mut self.frame_allocator)?;
+            } else {
+                table = table.next_level_table(i)?;
+            }
+        }
+    }
+}
+
+/// 把页表的第i项所映射的大页拆分为一张下一级页表，并返回这张页表
+///
+/// 新的页表中的每一项映射原来的大页中的一部分（2M的大页被拆分为512个4K页，1G的大页被拆分为512个2M的大页），
+/// 标志位（包括PAT位、访问位、脏位）以及地址字段中的内存加密位保持不变，因此拆分前后的地址翻译完全相同，
+/// TLB中缓存的大页的条目仍然是正确的，不需要立即刷新；之后修改其中某一页时，对这一页的INVLPG会使整个大页的条目失效。
+///
+/// ## 参数
+///
+/// - table 页表
+/// - i 映射大页的页表项的下标
+/// - allocator 用于分配新的页表的页面分配器
+///
+/// ## 返回值
+///
+/// 新的页表。如果页表项没有映射大页，或者无法分配页表，返回None
+unsafe fn split_huge_entry<Arch: MemoryManagementArch>(
+    table: &PageTable<Arch>,
+    i: usize,
+    allocator: &mut impl FrameAllocator,
+) -> Option<PageTable<Arch>> {
+    if !table.entry_is_huge(i) {
+        return None;
+    }
+    let entry = table.entry(i)?;
+    let level = table.level();
+    let size = Arch::PAGE_SIZE << (level * Arch::PAGE_ENTRY_SHIFT);
+    let sub_size = size >> Arch::PAGE_ENTRY_SHIFT;
+
+    let frame = allocator.allocate_one()?;
+    kmem_stat_add(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+    frame_tag_set(frame, PageFrameCount::new(1), FRAME_TAG_PAGE_TABLE);
+    PageTableAllocStats::record(level - 1);
+
+    // 大页的PAT位位于地址字段的最低位，按大页的大小对齐之后被清除；内存加密位位于地址字段的高位，会被保留
+    let base = entry.data() & Arch::ENTRY_ADDRESS_MASK & !(size - 1);
+    let sub_bits =
+        PageFlags::<Arch>::from_entry_bits_at(level, entry.data()).to_entry_bits_at(level - 1);
+    let subtable = PageTable::new(table.entry_base(i)?, frame, level - 1);
+    for k in 0..Arch::PAGE_ENTRY_NUM {
+        Arch::write::<usize>(subtable.entry_virt(k)?, (base + k * sub_size) | sub_bits);
+    }
+
+    let user = entry.data() & Arch::ENTRY_FLAG_USER != 0;
+    let flags: PageFlags<Arch> = PageFlags::new_page_table(user);
+    compiler_fence(Ordering::SeqCst);
+    table.set_entry(i, PageEntry::new_mapping(frame, flags.data()));
+    compiler_fence(Ordering::SeqCst);
+    return Some(subtable);
 }
 
 /// 取消页面映射，返回被取消映射的页表项的：【物理地址】和【flags】
@@ -768,7 +2440,12 @@ unsafe fn unmap_phys_inner<Arch: MemoryManagementArch>(
         return Some((entry.address().ok()?, entry.flags()));
     }
 
-    let mut subtable = table.next_level_table(i)?;
+    let mut subtable = if table.entry_is_huge(i) {
+        // 大页：先拆分，再取消其中一页的映射，大页中的其他页面保持映射
+        split_huge_entry(table, i, allocator)?
+    } else {
+        table.next_level_table(i)?
+    };
     // 递归地取消映射
     let result = unmap_phys_inner(vaddr, &mut subtable, unmap_parents, allocator)?;
 
@@ -780,18 +2457,119 @@ unsafe fn unmap_phys_inner<Arch: MemoryManagementArch>(
         // 检查子页表中是否还有映射的页面
         let x = (0..Arch::PAGE_ENTRY_NUM)
             .map(|k| subtable.entry(k).expect("invalid page entry"))
-            .any(|e| e.present());
+            // 换出的页表项同样记录着页面，不能随着子页表一起被丢弃
+            .any(|e| e.present() || e.data() & Arch::ENTRY_FLAG_SWAP != 0);
         if !x {
             // 如果没有，就取消子页表的映射
             table.set_entry(i, PageEntry::new(0));
             // 释放子页表
             allocator.free_one(subtable.phys());
+            kmem_stat_sub(KernelMemPurpose::PageTable, PageFrameCount::new(1));
         }
     }
 
     return Some(result);
 }
 
+/// 每个CPU的单项页表翻译缓存
+///
+/// 在缺页处理等热点路径中，同一个地址的翻译可能会被重复进行多次。
+/// 这里为每个CPU缓存最近一次翻译的结果。为了保证正确性，任何页表项的修改
+/// （无论是哪个页表、哪个CPU）都会增加全局的版本号，从而使所有CPU上的缓存失效。
+///
+/// 全局版本号的原子写入会在所有CPU之间争用同一个缓存行，因此缓存只在启用`translate_cache`特性时编译，
+/// 没有启用时`translate_cache_invalidate`什么也不做，翻译总是遍历页表
+#[derive(Debug, Clone, Copy)]
+struct TranslateCacheEntry {
+    /// 缓存项的版本号（0表示无效）
+    gen: usize,
+    /// 顶层页表的物理地址
+    table: PhysAddr,
+    /// 虚拟页号
+    vpn: usize,
+    /// 页面的物理地址
+    paddr: PhysAddr,
+    /// 页表项的flags
+    flags: usize,
+}
+
+impl TranslateCacheEntry {
+    const INVALID: Self = Self {
+        gen: 0,
+        table: PhysAddr::new(0),
+        vpn: 0,
+        paddr: PhysAddr::new(0),
+        flags: 0,
+    };
+}
+
+/// 是否启用页表翻译缓存
+static TRANSLATE_CACHE_ENABLED: AtomicBool = AtomicBool::new(true);
+/// 页表翻译缓存的全局版本号（从1开始，0表示无效的缓存项）
+static TRANSLATE_CACHE_GEN: AtomicUsize = AtomicUsize::new(1);
+/// 每个CPU的翻译缓存。每个缓存项只会被所属的CPU在关中断的情况下访问
+static mut TRANSLATE_CACHE: [TranslateCacheEntry; PerCpu::MAX_CPU_NUM] =
+    [TranslateCacheEntry::INVALID; PerCpu::MAX_CPU_NUM];
+
+/// 启用或者关闭页表翻译缓存
+#[allow(dead_code)]
+pub fn translate_cache_set_enabled(enabled: bool) {
+    TRANSLATE_CACHE_ENABLED.store(enabled, Ordering::SeqCst);
+    translate_cache_invalidate();
+}
+
+/// 使所有CPU上的页表翻译缓存失效
+#[inline(always)]
+pub fn translate_cache_invalidate() {
+    if cfg!(feature = "translate_cache") {
+        TRANSLATE_CACHE_GEN.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// 获取页表翻译缓存的全局版本号（没有启用`translate_cache`特性时不会改变）
+pub fn translate_cache_generation() -> usize {
+    return TRANSLATE_CACHE_GEN.load(Ordering::Acquire);
+}
+
+/// 在关中断的情况下，访问当前CPU的翻译缓存项
+fn with_translate_cache<R>(f: impl FnOnce(&mut TranslateCacheEntry) -> R) -> Option<R> {
+    if !cfg!(feature = "translate_cache") || !TRANSLATE_CACHE_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let guard = unsafe { CurrentIrqArch::save_and_disable_irq() };
+    let cpu_id = smp_get_processor_id() as usize;
+    let r = if cpu_id < PerCpu::MAX_CPU_NUM {
+        Some(f(unsafe { &mut TRANSLATE_CACHE[cpu_id] }))
+    } else {
+        None
+    };
+    drop(guard);
+    return r;
+}
+
+fn translate_cache_lookup(table: PhysAddr, vpn: usize, gen: usize) -> Option<(PhysAddr, usize)> {
+    return with_translate_cache(|e| {
+        if e.gen == gen && e.table == table && e.vpn == vpn {
+            Some((e.paddr, e.flags))
+        } else {
+            None
+        }
+    })
+    .flatten();
+}
+
+fn translate_cache_fill(table: PhysAddr, vpn: usize, gen: usize, paddr: PhysAddr, flags: usize) {
+    with_translate_cache(|e| {
+        *e = TranslateCacheEntry {
+            gen,
+            table,
+            vpn,
+            paddr,
+            flags,
+        };
+    });
+}
+
 impl<Arch, F: Debug> Debug for PageMapper<Arch, F> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PageMapper")
@@ -801,6 +2579,96 @@ impl<Arch, F: Debug> Debug for PageMapper<Arch, F> {
     }
 }
 
+/// 支持的最大页表级数
+const MAX_PAGE_LEVELS: usize = 5;
+
+const PAGE_TABLE_ALLOCS_INIT: AtomicUsize = AtomicUsize::new(0);
+/// 每一级页表累计被分配的页帧数量（只增不减）
+static PAGE_TABLE_ALLOCS: [AtomicUsize; MAX_PAGE_LEVELS] =
+    [PAGE_TABLE_ALLOCS_INIT; MAX_PAGE_LEVELS];
+
+/// 各级页表累计分配的页帧数量的快照
+///
+/// 计数器只增不减，因此可以在某个操作前后各取一次快照，通过`since`计算出这个操作新分配了多少页表
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageTableAllocStats {
+    frames: [usize; MAX_PAGE_LEVELS],
+}
+
+impl PageTableAllocStats {
+    /// 获取当前的快照
+    pub fn snapshot() -> Self {
+        let mut frames = [0; MAX_PAGE_LEVELS];
+        for (i, f) in frames.iter_mut().enumerate() {
+            *f = PAGE_TABLE_ALLOCS[i].load(Ordering::Relaxed);
+        }
+        return Self { frames };
+    }
+
+    /// 记录分配了一个第level级的页表（0为最后一级页表）
+    fn record(level: usize) {
+        if let Some(counter) = PAGE_TABLE_ALLOCS.get(level) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 计算从earlier到self之间分配的页表
+    pub fn since(&self, earlier: &Self) -> Self {
+        let mut frames = [0; MAX_PAGE_LEVELS];
+        for (i, f) in frames.iter_mut().enumerate() {
+            *f = self.frames[i] - earlier.frames[i];
+        }
+        return Self { frames };
+    }
+
+    /// 第level级页表的页帧数量（0为最后一级页表）
+    pub fn frames_at(&self, level: usize) -> usize {
+        return self.frames.get(level).copied().unwrap_or(0);
+    }
+
+    /// 所有级别的页表的页帧数量之和
+    pub fn total(&self) -> PageFrameCount {
+        return PageFrameCount::new(self.frames.iter().sum());
+    }
+}
+
+/// 启动阶段是否已经结束（新的内核页表已经被激活）
+///
+/// 在这之前，TLB中的条目没有意义，忽略刷新器是安全的。在这之后产生的刷新器如果通过`ignore()`被忽略，
+/// 很可能会在TLB中留下过期的条目，因此会被记录并打印警告。确实不需要刷新的场合应当使用`ignore_safe()`
+static FLUSH_BOOT_COMPLETE: AtomicBool = AtomicBool::new(false);
+/// 启动阶段结束之后，通过`ignore()`被忽略的刷新器的数量
+static IGNORED_FLUSHES_AFTER_BOOT: AtomicUsize = AtomicUsize::new(0);
+
+/// 标记启动阶段已经结束（在内存管理初始化完成时调用）
+pub fn flush_mark_boot_complete() {
+    FLUSH_BOOT_COMPLETE.store(true, Ordering::SeqCst);
+}
+
+/// 获取启动阶段结束之后，通过`ignore()`被忽略的刷新器的数量
+pub fn ignored_flushes_after_boot() -> usize {
+    return IGNORED_FLUSHES_AFTER_BOOT.load(Ordering::Relaxed);
+}
+
+#[inline(always)]
+fn flush_boot_complete() -> bool {
+    return FLUSH_BOOT_COMPLETE.load(Ordering::Relaxed);
+}
+
+/// 记录一个在启动阶段结束之后产生、又被`ignore()`忽略的刷新器
+///
+/// 这里只打印警告，而不会panic：被忽略的刷新并不一定会导致错误（比如页表不是当前页表），
+/// 但是应当改为使用`ignore_safe()`并说明原因
+#[cold]
+fn report_ignored_flush(what: fmt::Arguments) {
+    let count = IGNORED_FLUSHES_AFTER_BOOT.fetch_add(1, Ordering::Relaxed) + 1;
+    kwarn!(
+        "TLB flush ignored after boot: {} (total: {}), use ignore_safe() if this is intended",
+        what,
+        count
+    );
+}
+
 /// 页表刷新器的trait
 pub trait Flusher<Arch> {
     /// 取消对指定的page flusher的刷新
@@ -812,6 +2680,8 @@ pub trait Flusher<Arch> {
 #[must_use = "The flusher must call the 'flush()', or the changes to page table will be unsafely ignored."]
 pub struct PageFlush<Arch> {
     virt: VirtAddr,
+    /// 是否在启动阶段结束之后产生
+    after_boot: bool,
     phantom: PhantomData<Arch>,
 }
 
@@ -819,16 +2689,32 @@ impl<Arch: MemoryManagementArch> PageFlush<Arch> {
     pub fn new(virt: VirtAddr) -> Self {
         return Self {
             virt,
+            after_boot: flush_boot_complete(),
             phantom: PhantomData,
         };
     }
 
+    /// 需要被刷新的虚拟地址
+    pub fn virt(&self) -> VirtAddr {
+        return self.virt;
+    }
+
     pub fn flush(self) {
         unsafe { Arch::invalidate_page(self.virt) };
     }
 
     /// 忽略掉这个刷新器
+    ///
+    /// 如果刷新器是在启动阶段结束之后产生的，会被记录并打印警告（参见`FLUSH_BOOT_COMPLETE`）
     pub unsafe fn ignore(self) {
+        if self.after_boot {
+            report_ignored_flush(format_args!("page {:?}", self.virt));
+        }
+        mem::forget(self);
+    }
+
+    /// 忽略掉这个刷新器，并且确定不需要刷新（比如修改的页表不是当前页表，或者刷新已经被合并到其他刷新器中）
+    pub unsafe fn ignore_safe(self) {
         mem::forget(self);
     }
 }
@@ -837,6 +2723,8 @@ impl<Arch: MemoryManagementArch> PageFlush<Arch> {
 /// 否则会造成对页表的更改被忽略，这是不安全的
 #[must_use = "The flusher must call the 'flush()', or the changes to page table will be unsafely ignored."]
 pub struct PageFlushAll<Arch: MemoryManagementArch> {
+    /// 是否在启动阶段结束之后产生
+    after_boot: bool,
     phantom: PhantomData<fn() -> Arch>,
 }
 
@@ -844,6 +2732,7 @@ pub struct PageFlushAll<Arch: MemoryManagementArch> {
 impl<Arch: MemoryManagementArch> PageFlushAll<Arch> {
     pub fn new() -> Self {
         return Self {
+            after_boot: flush_boot_complete(),
             phantom: PhantomData,
         };
     }
@@ -853,7 +2742,17 @@ impl<Arch: MemoryManagementArch> PageFlushAll<Arch> {
     }
 
     /// 忽略掉这个刷新器
+    ///
+    /// 如果刷新器是在启动阶段结束之后产生的，会被记录并打印警告（参见`FLUSH_BOOT_COMPLETE`）
     pub unsafe fn ignore(self) {
+        if self.after_boot {
+            report_ignored_flush(format_args!("all pages"));
+        }
+        mem::forget(self);
+    }
+
+    /// 忽略掉这个刷新器，并且确定不需要刷新
+    pub unsafe fn ignore_safe(self) {
         mem::forget(self);
     }
 }
@@ -861,7 +2760,7 @@ impl<Arch: MemoryManagementArch> PageFlushAll<Arch> {
 impl<Arch: MemoryManagementArch> Flusher<Arch> for PageFlushAll<Arch> {
     /// 为page flush all 实现consume，消除对单个页面的刷新。（刷新整个页表了就不需要刷新单个页面了）
     fn consume(&mut self, flush: PageFlush<Arch>) {
-        unsafe { flush.ignore() };
+        unsafe { flush.ignore_safe() };
     }
 }
 
@@ -872,6 +2771,136 @@ impl<Arch: MemoryManagementArch, T: Flusher<Arch> + ?Sized> Flusher<Arch> for &m
     }
 }
 
+/// 批量刷新器能够记录的不连续的地址范围的数量，超出之后会退化为刷新整个TLB
+const FLUSH_BATCH_CAPACITY: usize = 16;
+/// 批量刷新的页面数量超过这个值时，直接刷新整个TLB，而不是逐页刷新
+const FLUSH_BATCH_MAX_PAGES: usize = 64;
+
+/// 批量的页表刷新器
+///
+/// 连续多次调用map/unmap/remap时，可以把它们返回的`PageFlush`交给同一个`FlushBatch`（通过`Flusher::consume`），
+/// 最后调用一次`commit()`，以最小的代价刷新所有受影响的地址范围。
+///
+/// 相邻或者重叠的范围会被合并。当不连续的范围过多，或者页面总数过多时，`commit()`会刷新整个TLB
+#[must_use = "The flush batch must call the 'commit()', or the changes to page table will be unsafely ignored."]
+pub struct FlushBatch<Arch: MemoryManagementArch> {
+    /// 按起始地址排序、互不相邻的地址范围（[start, end)）
+    ranges: [(usize, usize); FLUSH_BATCH_CAPACITY],
+    len: usize,
+    /// 是否有范围因为容量不足而没有被记录
+    overflow: bool,
+    /// 是否有范围是在启动阶段结束之后被加入的
+    after_boot: bool,
+    phantom: PhantomData<fn() -> Arch>,
+}
+
+impl<Arch: MemoryManagementArch> FlushBatch<Arch> {
+    pub const fn new() -> Self {
+        return Self {
+            ranges: [(0, 0); FLUSH_BATCH_CAPACITY],
+            len: 0,
+            overflow: false,
+            after_boot: false,
+            phantom: PhantomData,
+        };
+    }
+
+    /// 把一段地址范围加入到批量刷新中
+    ///
+    /// ## 参数
+    ///
+    /// - `start`：起始虚拟地址（会向下对齐到页）
+    /// - `count`：页面数量
+    pub fn push_range(&mut self, start: VirtAddr, count: PageFrameCount) {
+        if count.data() == 0 {
+            return;
+        }
+        self.after_boot |= flush_boot_complete();
+        let mut start = start.data() & !Arch::PAGE_OFFSET_MASK;
+        let mut end = start + count.data() * Arch::PAGE_SIZE;
+
+        // 合并所有与新范围重叠或者相邻的范围
+        let mut i = 0;
+        while i < self.len {
+            let (s, e) = self.ranges[i];
+            if s <= end && start <= e {
+                start = start.min(s);
+                end = end.max(e);
+                self.ranges.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        if self.len == FLUSH_BATCH_CAPACITY {
+            self.overflow = true;
+            return;
+        }
+        let pos = self.ranges[..self.len]
+            .iter()
+            .position(|(s, _)| *s > start)
+            .unwrap_or(self.len);
+        self.ranges.copy_within(pos..self.len, pos + 1);
+        self.ranges[pos] = (start, end);
+        self.len += 1;
+    }
+
+    /// 获取当前记录的（合并后的）地址范围
+    pub fn ranges(&self) -> impl Iterator<Item = (VirtAddr, VirtAddr)> + '_ {
+        return self.ranges[..self.len]
+            .iter()
+            .map(|(s, e)| (VirtAddr::new(*s), VirtAddr::new(*e)));
+    }
+
+    /// 获取当前记录的页面总数
+    pub fn pages(&self) -> usize {
+        return self.ranges[..self.len]
+            .iter()
+            .map(|(s, e)| (e - s) >> Arch::PAGE_SHIFT)
+            .sum();
+    }
+
+    /// 判断`commit()`是否会刷新整个TLB
+    pub fn needs_full_flush(&self) -> bool {
+        return self.overflow || self.pages() > FLUSH_BATCH_MAX_PAGES;
+    }
+
+    /// 刷新所有被记录的地址范围
+    pub fn commit(self) {
+        if self.needs_full_flush() {
+            unsafe { Arch::invalidate_all() };
+        } else {
+            for (s, e) in self.ranges[..self.len].iter() {
+                let count = PageFrameCount::new((e - s) >> Arch::PAGE_SHIFT);
+                unsafe { Arch::invalidate_range(VirtAddr::new(*s), count) };
+            }
+        }
+    }
+
+    /// 忽略掉这个刷新器（比如修改的是尚未被激活的页表）
+    ///
+    /// 如果在启动阶段结束之后有范围被加入，会被记录并打印警告（参见`FLUSH_BOOT_COMPLETE`）
+    pub unsafe fn ignore(self) {
+        if self.after_boot {
+            report_ignored_flush(format_args!("batch of {} page(s)", self.pages()));
+        }
+        mem::forget(self);
+    }
+
+    /// 忽略掉这个刷新器，并且确定不需要刷新
+    pub unsafe fn ignore_safe(self) {
+        mem::forget(self);
+    }
+}
+
+impl<Arch: MemoryManagementArch> Flusher<Arch> for FlushBatch<Arch> {
+    fn consume(&mut self, flush: PageFlush<Arch>) {
+        self.push_range(flush.virt, PageFrameCount::new(1));
+        unsafe { flush.ignore_safe() };
+    }
+}
+
 impl<Arch: MemoryManagementArch> Flusher<Arch> for () {
     fn consume(&mut self, _flush: PageFlush<Arch>) {}
 }
@@ -896,18 +2925,30 @@ impl InactiveFlusher {
     pub fn new() -> Self {
         return Self {};
     }
+
+    /// 与drop相同地刷新所有CPU上的TLB，并等待其他CPU完成刷新（参见`crate::arch::mm::tlb_shootdown_sync`）
+    pub fn flush_and_wait(self) {
+        mem::forget(self);
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::mm::pcid::pcid_invalidate_all_contexts();
+        crate::arch::mm::tlb_shootdown_sync();
+    }
 }
 
 impl Flusher<MMArch> for InactiveFlusher {
     fn consume(&mut self, flush: PageFlush<MMArch>) {
+        // 在drop时通过IPI刷新
         unsafe {
-            flush.ignore();
+            flush.ignore_safe();
         }
     }
 }
 
 impl Drop for InactiveFlusher {
     fn drop(&mut self) {
+        // 开启PCID时，当前CPU上也可能缓存着被修改的页表的条目
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::mm::pcid::pcid_invalidate_all_contexts();
         // 发送刷新页表的IPI
         send_ipi(IpiKind::FlushTLB, IpiTarget::Other);
     }
@@ -3,8 +3,18 @@ use core::sync::atomic::AtomicUsize;
 use alloc::vec::Vec;
 
 use crate::{
-    include::bindings::bindings::smp_get_total_cpu, libs::lazy_init::Lazy,
+    arch::{mm::LockedFrameAllocator, MMArch},
+    include::bindings::bindings::smp_get_total_cpu,
+    libs::{align::page_align_up, lazy_init::Lazy, spinlock::SpinLock},
     smp::core::smp_get_processor_id,
+    syscall::SystemError,
+};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount, PageRange},
+    kernel_mapper::KernelMapper,
+    page::PageFlags,
+    MemoryManagementArch, VirtAddr,
 };
 
 /// 系统中的CPU数量
@@ -88,3 +98,135 @@ impl<T> PerCpuVar<T> {
 /// PerCpu变量是线程安全的，因为每个CPU都有自己的变量。
 unsafe impl<T> Sync for PerCpuVar<T> {}
 unsafe impl<T> Send for PerCpuVar<T> {}
+
+/// 每CPU区域所在的虚拟地址空间的起始地址（紧跟在MMIO虚拟地址空间之后）
+///
+/// 第i个CPU的每CPU区域位于`PERCPU_AREA_BASE + i * PERCPU_UNIT_SIZE`，
+/// 这个地址也就是该CPU的GS基址，因此`gs:[offset]`总是指向当前CPU的那一份数据
+pub const PERCPU_AREA_BASE: VirtAddr = VirtAddr::new(0xffffa20000000000);
+/// 每个CPU的每CPU区域的大小
+pub const PERCPU_UNIT_SIZE: usize = 2 * 1024 * 1024;
+
+/// 每CPU区域中，下一个可以分配的偏移量
+static PERCPU_NEXT_OFFSET: SpinLock<usize> = SpinLock::new(0);
+
+/// 每CPU区域中的偏移量，由`alloc_percpu`返回
+///
+/// 对于任意一个CPU，`gs:[offset]`（GS基址为`percpu_unit_base(cpu)`时）都指向这个CPU的那一份数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerCpuOffset(usize);
+
+impl PerCpuOffset {
+    /// 相对于GS基址的偏移量
+    pub fn data(&self) -> usize {
+        return self.0;
+    }
+
+    /// 获取指定的CPU的那一份数据的虚拟地址
+    pub fn ptr_for(&self, cpu_id: usize) -> VirtAddr {
+        return percpu_unit_base(cpu_id) + self.0;
+    }
+}
+
+/// 获取指定的CPU的每CPU区域的起始地址（也就是这个CPU应当使用的GS基址）
+pub fn percpu_unit_base(cpu_id: usize) -> VirtAddr {
+    assert!(cpu_id < PerCpu::MAX_CPU_NUM, "cpu id {cpu_id} out of range");
+    return PERCPU_AREA_BASE + cpu_id * PERCPU_UNIT_SIZE;
+}
+
+/// 初始化每CPU区域的虚拟地址空间
+///
+/// 为每CPU区域预先创建中间级的页表，使得之后创建的用户地址空间在复制内核部分的顶级页表项时，
+/// 能够看到每CPU区域的映射。需要在创建第一个用户地址空间之前调用
+pub fn percpu_area_init() {
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper
+        .as_mut()
+        .expect("percpu_area_init: kernel mapper is readonly");
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("percpu_area_init: out of memory");
+    unsafe {
+        mapper
+            .map_phys(PERCPU_AREA_BASE, paddr, PageFlags::new().set_write(true))
+            .expect("percpu_area_init: failed to map")
+            .flush();
+        // 只取消最后一级的映射，保留中间级的页表
+        let (_, _, flusher) = mapper
+            .unmap_phys(PERCPU_AREA_BASE, false)
+            .expect("percpu_area_init: failed to unmap");
+        flusher.flush();
+        LockedFrameAllocator.free(paddr, PageFrameCount::new(1));
+    }
+}
+
+/// 为所有在线的CPU分配一块每CPU数据
+///
+/// 目前假定在线的CPU的id为`[0, smp_get_total_cpu())`
+///
+/// ## 参数
+///
+/// - `size`：每个CPU的数据的大小（字节）
+/// - `align`：对齐要求（必须是2的幂）
+pub fn alloc_percpu(size: usize, align: usize) -> Result<PerCpuOffset, SystemError> {
+    let cpus = unsafe { smp_get_total_cpu() } as usize;
+    let cpus: Vec<usize> = (0..cpus.max(1)).collect();
+    return alloc_percpu_for(size, align, &cpus);
+}
+
+/// 为指定的一组CPU分配一块每CPU数据，并为每个CPU映射其背后的物理页（已清零）
+///
+/// ## 参数
+///
+/// - `size`：每个CPU的数据的大小（字节）
+/// - `align`：对齐要求（必须是2的幂）
+/// - `cpus`：需要映射这块数据的CPU的id
+///
+/// ## 返回值
+///
+/// - `EINVAL`：size为0，或者align不是2的幂
+/// - `ENOMEM`：每CPU区域已满，或者没有足够的物理内存
+pub fn alloc_percpu_for(
+    size: usize,
+    align: usize,
+    cpus: &[usize],
+) -> Result<PerCpuOffset, SystemError> {
+    if size == 0 || !align.is_power_of_two() {
+        return Err(SystemError::EINVAL);
+    }
+    let mut next_offset = PERCPU_NEXT_OFFSET.lock();
+    let offset = (*next_offset + align - 1) & !(align - 1);
+    let end = offset.checked_add(size).ok_or(SystemError::ENOMEM)?;
+    if end > PERCPU_UNIT_SIZE {
+        return Err(SystemError::ENOMEM);
+    }
+
+    // 每个CPU的这段区域所在的页面，可能已经被之前的分配映射过了
+    let first_page = offset & !MMArch::PAGE_OFFSET_MASK;
+    let count = PageFrameCount::new((page_align_up(end) - first_page) / MMArch::PAGE_SIZE);
+    let flags = PageFlags::new().set_write(true);
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper
+        .as_mut()
+        .ok_or(SystemError::EAGAIN_OR_EWOULDBLOCK)?;
+    for cpu in cpus {
+        let base = percpu_unit_base(*cpu) + first_page;
+        for vaddr in PageRange::virt(base, count) {
+            if mapper.translate(vaddr).is_some() {
+                continue;
+            }
+            let (paddr, _) =
+                unsafe { LockedFrameAllocator.allocate_zeroed(PageFrameCount::new(1)) }
+                    .ok_or(SystemError::ENOMEM)?;
+            match unsafe { mapper.map_phys(vaddr, paddr, flags) } {
+                Ok(flusher) => flusher.flush(),
+                Err(e) => {
+                    unsafe { LockedFrameAllocator.free(paddr, PageFrameCount::new(1)) };
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    *next_offset = end;
+    return Ok(PerCpuOffset(offset));
+}
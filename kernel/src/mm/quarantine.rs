@@ -0,0 +1,157 @@
+//! 运行时隔离出现内存错误的页帧
+//!
+//! 启动时，固件报告的损坏的内存（`ReservedKind::BadRam`）不会被交给伙伴分配器。
+//! 运行时，如果硬件（通过MCE）报告某个物理地址发生了可纠正或者不可纠正的内存错误，调用`quarantine_frame`停止使用这个页帧：
+//!
+//! - 页帧空闲：立即从伙伴分配器的空闲链表中取出，记录为已隔离
+//! - 页帧已被分配：记录为待隔离。它被释放时，不会被放回空闲链表，而是变为已隔离
+//!
+//! 每个CPU的页帧缓存、后台清零的链表、低阶页帧池中的页帧在伙伴分配器看来是已分配的，因此同样被记录为待隔离，
+//! 页帧分配器从这些缓存中取出它们时会拦截（参见`quarantine_intercept`）。
+//!
+//! 被隔离的页帧永远不会再被分配，在`usage`中一直被统计为已使用。
+//! 记录保存在一张固定大小的表中，这样释放页帧的路径上不需要动态内存分配。
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    kwarn,
+    libs::spinlock::SpinLock,
+    syscall::SystemError,
+};
+
+use super::{allocator::page_frame::PageFrameCount, MemoryManagementArch, PhysAddr};
+
+/// 表中最多记录的页帧数量
+const QUARANTINE_MAX_FRAMES: usize = 64;
+
+/// 被隔离的页帧的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantineState {
+    /// 已经从页帧分配器中移除，不会再被使用
+    Isolated,
+    /// 仍然在被使用，被释放时隔离
+    Pending,
+}
+
+/// 被隔离的页帧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuarantinedFrame {
+    pub paddr: PhysAddr,
+    pub state: QuarantineState,
+}
+
+struct QuarantineTable {
+    frames: [QuarantinedFrame; QUARANTINE_MAX_FRAMES],
+    count: usize,
+}
+
+impl QuarantineTable {
+    fn find(&mut self, paddr: PhysAddr) -> Option<&mut QuarantinedFrame> {
+        return self.frames[..self.count]
+            .iter_mut()
+            .find(|frame| frame.paddr == paddr);
+    }
+}
+
+static QUARANTINE: SpinLock<QuarantineTable> = SpinLock::new(QuarantineTable {
+    frames: [QuarantinedFrame {
+        paddr: PhysAddr::new(0),
+        state: QuarantineState::Isolated,
+    }; QUARANTINE_MAX_FRAMES],
+    count: 0,
+});
+
+/// 待隔离的页帧数量。为0时，释放页帧的路径不需要获取表的锁
+static PENDING_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// 隔离一个发生了内存错误的页帧（参见模块的文档）
+///
+/// 对同一个页帧重复调用是安全的（硬件可能多次报告同一个错误），此时返回它当前的状态
+///
+/// ## 参数
+///
+/// - `paddr`：发生错误的物理地址（不需要按页对齐）
+///
+/// ## 返回值
+///
+/// - 成功：返回页帧的状态
+/// - `ENOSPC`：表已满
+pub fn quarantine_frame(paddr: PhysAddr) -> Result<QuarantineState, SystemError> {
+    let paddr = PhysAddr::new(paddr.data() & !MMArch::PAGE_OFFSET_MASK);
+    // 在持有表的锁的情况下检查页帧是否空闲：如果它此时正在被另一个CPU释放，释放的路径会等待表的锁，然后看到待隔离的记录
+    let mut table = QUARANTINE.lock_irqsave();
+    if let Some(frame) = table.find(paddr) {
+        return Ok(frame.state);
+    }
+    if table.count == QUARANTINE_MAX_FRAMES {
+        drop(table);
+        kwarn!(
+            "quarantine_frame: quarantine table is full, frame {:?} is still in use",
+            paddr
+        );
+        return Err(SystemError::ENOSPC);
+    }
+
+    // 先增加计数，使得之后开始的释放路径都会检查这张表
+    PENDING_FRAMES.fetch_add(1, Ordering::SeqCst);
+    let state = if LockedFrameAllocator.take_free_frame(paddr) {
+        PENDING_FRAMES.fetch_sub(1, Ordering::SeqCst);
+        QuarantineState::Isolated
+    } else {
+        QuarantineState::Pending
+    };
+    let count = table.count;
+    table.frames[count] = QuarantinedFrame { paddr, state };
+    table.count += 1;
+    drop(table);
+    kwarn!(
+        "quarantine_frame: frame {:?} quarantined ({:?})",
+        paddr,
+        state
+    );
+    return Ok(state);
+}
+
+/// 在页帧被释放（或者被从缓存中取出）时调用：如果它在等待隔离，就把它隔离
+///
+/// ## 返回值
+///
+/// 如果页帧被隔离，返回true，此时调用者不能再使用或者释放这个页帧
+pub fn quarantine_intercept(paddr: PhysAddr) -> bool {
+    if PENDING_FRAMES.load(Ordering::SeqCst) == 0 {
+        return false;
+    }
+    let mut table = QUARANTINE.lock_irqsave();
+    match table.find(paddr) {
+        Some(frame) if frame.state == QuarantineState::Pending => {
+            frame.state = QuarantineState::Isolated;
+            PENDING_FRAMES.fetch_sub(1, Ordering::SeqCst);
+            return true;
+        }
+        _ => return false,
+    }
+}
+
+/// 判断从paddr开始的count个页帧中，是否有等待隔离的页帧
+pub fn quarantine_pending_in(paddr: PhysAddr, count: PageFrameCount) -> bool {
+    if PENDING_FRAMES.load(Ordering::SeqCst) == 0 {
+        return false;
+    }
+    let end = paddr.data() + count.bytes();
+    let table = QUARANTINE.lock_irqsave();
+    return table.frames[..table.count].iter().any(|frame| {
+        frame.state == QuarantineState::Pending && frame.paddr >= paddr && frame.paddr.data() < end
+    });
+}
+
+/// 获取所有被隔离（以及等待隔离）的页帧
+pub fn quarantined_frames() -> Vec<QuarantinedFrame> {
+    let table = QUARANTINE.lock_irqsave();
+    let (frames, count) = (table.frames, table.count);
+    drop(table);
+    return frames[..count].to_vec();
+}
@@ -0,0 +1,125 @@
+//! 被保留的物理内存区域
+//!
+//! 内核镜像、initrd、帧缓冲区、AP处理器的启动代码、固件报告的非RAM区域等内存都不能交给伙伴分配器。
+//! 这些保留的来源分散在不同的模块中，`reserved_regions`把它们汇总起来，便于审计。
+//!
+//! 启动阶段一次性确定的区域通过`reserved_region_add`记录在一张固定大小的表中（不需要动态内存分配），
+//! 可能在之后被释放的区域（比如initrd）则在枚举时从各自的模块中查询。
+
+use alloc::vec::Vec;
+
+use crate::{kwarn, libs::spinlock::SpinLock};
+
+use super::{initrd::initrd_frames, PhysAddr, PhysMemoryArea};
+
+/// 内存区域被保留的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedKind {
+    /// 内核镜像
+    KernelImage,
+    /// initrd
+    Initrd,
+    /// 帧缓冲区
+    Framebuffer,
+    /// ACPI NVS（固件在睡眠状态之间需要保存的内存）
+    AcpiNvs,
+    /// 为崩溃转储内核保留的内存
+    Crashkernel,
+    /// AP处理器的启动代码
+    ApTrampoline,
+    /// 连续内存分配器（CMA）的区域
+    Cma,
+    /// 固件保留的内存（包括ACPI的可回收内存）
+    Firmware,
+    /// 固件报告的损坏的内存
+    BadRam,
+}
+
+/// 表中最多记录的区域数量
+const RESERVED_REGIONS_MAX: usize = 64;
+
+struct ReservedRegionTable {
+    regions: [(PhysMemoryArea, ReservedKind); RESERVED_REGIONS_MAX],
+    count: usize,
+    /// 由于表已满而没有被记录的区域数量
+    dropped: usize,
+}
+
+static RESERVED_REGIONS: SpinLock<ReservedRegionTable> = SpinLock::new(ReservedRegionTable {
+    regions: [(
+        PhysMemoryArea {
+            base: PhysAddr::new(0),
+            size: 0,
+        },
+        ReservedKind::Firmware,
+    ); RESERVED_REGIONS_MAX],
+    count: 0,
+    dropped: 0,
+});
+
+/// 记录一段被保留的物理内存区域
+///
+/// 可以在动态内存分配可用之前调用
+///
+/// ## 返回值
+///
+/// 如果表已满，返回false（区域不会被记录，只会被计数）
+pub fn reserved_region_add(area: PhysMemoryArea, kind: ReservedKind) -> bool {
+    if area.size == 0 {
+        return true;
+    }
+    let mut table = RESERVED_REGIONS.lock_irqsave();
+    if table.count >= RESERVED_REGIONS_MAX {
+        table.dropped += 1;
+        return false;
+    }
+    let index = table.count;
+    table.regions[index] = (area, kind);
+    table.count += 1;
+    return true;
+}
+
+/// 把multiboot2内存映射中的区域类型转换为保留的原因
+///
+/// ## 返回值
+///
+/// 对于可用的RAM（类型1），返回None
+pub fn reserved_kind_of_mmap_type(mmap_type: u32) -> Option<ReservedKind> {
+    return match mmap_type {
+        1 => None,
+        4 => Some(ReservedKind::AcpiNvs),
+        5 => Some(ReservedKind::BadRam),
+        // 2：保留；3：ACPI可回收（内核不会回收这部分内存）；其他类型同样不可用
+        _ => Some(ReservedKind::Firmware),
+    };
+}
+
+/// 枚举所有被保留的物理内存区域，按照基地址从小到大排列
+///
+/// 目前内核还不支持crashkernel，因此不会出现`ReservedKind::Crashkernel`的区域
+pub fn reserved_regions() -> impl Iterator<Item = (PhysMemoryArea, ReservedKind)> {
+    let mut regions: Vec<(PhysMemoryArea, ReservedKind)> = {
+        let table = RESERVED_REGIONS.lock_irqsave();
+        if table.dropped != 0 {
+            kwarn!(
+                "reserved_regions: {} regions were not recorded (table capacity: {})",
+                table.dropped,
+                RESERVED_REGIONS_MAX
+            );
+        }
+        table.regions[..table.count].to_vec()
+    };
+
+    if let Some((base, count)) = initrd_frames() {
+        regions.push((
+            PhysMemoryArea {
+                base,
+                size: count.bytes(),
+            },
+            ReservedKind::Initrd,
+        ));
+    }
+
+    regions.sort_by_key(|(area, _)| area.base);
+    return regions.into_iter();
+}
@@ -0,0 +1,156 @@
+//! 用于设备探测的不可缓存（UC）的临时页面
+//!
+//! 设备探测时，有时需要一个确定不可缓存的临时页面（比如作为设备读写的目标）。
+//! `uncached_page`从页帧分配器中分配一个页帧，并在MMIO地址空间中以UC的方式映射它。
+//!
+//! 为了避免同一个页帧同时以WB和UC的方式被映射，页帧在直接映射区域中的映射也会被修改为UC，
+//! 并通过`cache_type_annotate_ram`记录下来，因此缓存类型的检查知道这个页帧是UC的。
+//! `free_uncached_page`会恢复直接映射区域中的WB映射，然后释放页帧。
+//!
+//! 所有尚未释放的临时页面记录在本模块中，可以通过`uncached_pages`审计。
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{mm::LockedFrameAllocator, MMArch},
+    libs::spinlock::SpinLock,
+    syscall::SystemError,
+};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount},
+    cache_type::{cache_type_annotate_ram, cache_type_release, CacheType},
+    kernel_mapper::KernelMapper,
+    kmem_stat::{kmem_stat_add, kmem_stat_sub, KernelMemPurpose},
+    mmio_buddy::mmio_pool,
+    page::PageFlags,
+    MemoryManagementArch, PhysAddr, VirtAddr,
+};
+
+/// 一个不可缓存的临时页面
+#[derive(Debug, Clone, Copy)]
+struct ScratchPage {
+    paddr: PhysAddr,
+    /// MMIO地址空间中的映射
+    vaddr: VirtAddr,
+    /// 从MMIO地址空间中分配的长度
+    vaddr_len: usize,
+}
+
+/// 尚未释放的临时页面
+static SCRATCH_PAGES: SpinLock<Vec<ScratchPage>> = SpinLock::new(Vec::new());
+
+/// 分配一个不可缓存的临时页面（内容被清零）
+///
+/// ## 返回值
+///
+/// - 成功：返回（页帧的物理地址，UC映射的虚拟地址）
+/// - `ENOMEM`：没有空闲的页帧，或者MMIO地址空间不足
+pub fn uncached_page() -> Result<(PhysAddr, VirtAddr), SystemError> {
+    let paddr = unsafe { LockedFrameAllocator.allocate_one() }.ok_or(SystemError::ENOMEM)?;
+    let direct = unsafe { MMArch::phys_2_virt(paddr) }.unwrap();
+    unsafe { MMArch::write_bytes(direct, 0, MMArch::PAGE_SIZE) };
+
+    if let Err(e) = cache_type_annotate_ram(paddr, MMArch::PAGE_SIZE, CacheType::Uncacheable) {
+        unsafe { LockedFrameAllocator.free_one(paddr) };
+        return Err(e.into());
+    }
+    // 先把直接映射区域中的映射改为UC，再写回缓存中残留的数据
+    set_direct_map_uncached(direct, true);
+    unsafe { MMArch::clflush_range(direct, MMArch::PAGE_SIZE) };
+
+    let r = map_uncached(paddr);
+    let (vaddr, vaddr_len) = match r {
+        Ok(r) => r,
+        Err(e) => {
+            set_direct_map_uncached(direct, false);
+            cache_type_release(paddr);
+            unsafe { LockedFrameAllocator.free_one(paddr) };
+            return Err(e);
+        }
+    };
+
+    SCRATCH_PAGES.lock_irqsave().push(ScratchPage {
+        paddr,
+        vaddr,
+        vaddr_len,
+    });
+    kmem_stat_add(KernelMemPurpose::Vmap, PageFrameCount::new(1));
+    return Ok((paddr, vaddr));
+}
+
+/// 释放由`uncached_page`分配的临时页面
+///
+/// ## 参数
+///
+/// - `vaddr`：`uncached_page`返回的虚拟地址
+///
+/// ## 返回值
+///
+/// - `EINVAL`：vaddr不是一个尚未释放的临时页面
+pub fn free_uncached_page(vaddr: VirtAddr) -> Result<(), SystemError> {
+    let page = {
+        let mut pages = SCRATCH_PAGES.lock_irqsave();
+        let index = pages
+            .iter()
+            .position(|p| p.vaddr == vaddr)
+            .ok_or(SystemError::EINVAL)?;
+        pages.remove(index)
+    };
+
+    // release_mmio只取消映射，不会释放页帧
+    mmio_pool().release_mmio(page.vaddr, page.vaddr_len)?;
+    let direct = unsafe { MMArch::phys_2_virt(page.paddr) }.unwrap();
+    set_direct_map_uncached(direct, false);
+    cache_type_release(page.paddr);
+    unsafe { LockedFrameAllocator.free_one(page.paddr) };
+    kmem_stat_sub(KernelMemPurpose::Vmap, PageFrameCount::new(1));
+    return Ok(());
+}
+
+/// 获取所有尚未释放的临时页面（物理地址，虚拟地址）
+pub fn uncached_pages() -> Vec<(PhysAddr, VirtAddr)> {
+    return SCRATCH_PAGES
+        .lock_irqsave()
+        .iter()
+        .map(|p| (p.paddr, p.vaddr))
+        .collect();
+}
+
+/// 在MMIO地址空间中，以UC的方式映射一个页帧
+fn map_uncached(paddr: PhysAddr) -> Result<(VirtAddr, usize), SystemError> {
+    let mut vaddr: u64 = 0;
+    let mut vaddr_len: u64 = 0;
+    mmio_pool().create_mmio(MMArch::PAGE_SIZE, 0, &mut vaddr, &mut vaddr_len)?;
+    let vaddr = VirtAddr::new(vaddr as usize);
+    let r = unsafe {
+        KernelMapper::lock().map_phys_with_size(
+            vaddr,
+            paddr,
+            MMArch::PAGE_SIZE,
+            PageFlags::mmio_flags(),
+            true,
+        )
+    };
+    if let Err(e) = r {
+        mmio_pool().release_mmio(vaddr, vaddr_len as usize).ok();
+        return Err(e);
+    }
+    return Ok((vaddr, vaddr_len as usize));
+}
+
+/// 修改页帧在直接映射区域中的缓存类型（UC或者默认的WB），并刷新所有CPU上的TLB
+fn set_direct_map_uncached(direct: VirtAddr, uncached: bool) {
+    let mut mapper = KernelMapper::lock();
+    let (_, flags) = mapper
+        .translate(direct)
+        .expect("scratch: frame is not in the direct map");
+    let flags = flags
+        .set_page_cache_disable(uncached)
+        .set_page_write_through(uncached);
+    let mapper = mapper
+        .as_mut()
+        .expect("scratch: kernel mapper is read only");
+    unsafe { mapper.protect_and_flush(direct, flags) }
+        .expect("scratch: frame is not in the direct map");
+}
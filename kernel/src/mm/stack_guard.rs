@@ -0,0 +1,370 @@
+//! 内核栈保护页的登记
+//!
+//! 内核栈的下方放置一段不映射的保护页时，栈溢出会在保护页上触发缺页异常。
+//! 为了把这种缺页异常与普通的缺页异常区分开，分配内核栈的代码需要通过`register_stack_guard`
+//! 登记保护页的范围，以及栈所属的进程。缺页异常处理函数通过`classify_stack_fault`判断
+//! 发生异常的地址是否位于某个内核栈的保护页内，如果是，则报告“kernel stack overflow”。
+//!
+//! 普通进程的内核栈与PCB一起由kzalloc分配，没有保护页。AP处理器的内核栈和中断栈由`alloc_kernel_stack`
+//! 在MMIO地址空间中分配，它们的下方有一个保护页，并且在映射时登记。
+//!
+//! `map_with_guard`用于建立带保护页的映射：保护页的页表项保持为不存在（而不是只读），
+//! 因此无论是读还是写，越过映射区域的访问都会触发缺页异常。用户栈使用它来映射，
+//! 并通过`set_user_stack_guard`登记（每个用户地址空间只有一个用户栈，登记按照地址空间的顶层页表区分）。
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+use crate::{arch::MMArch, libs::spinlock::SpinLock, syscall::SystemError};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount},
+    error::MmError,
+    kernel_mapper::KernelMapper,
+    kmem_stat::{kmem_stat_add, kmem_stat_sub, KernelMemPurpose},
+    mmio_buddy::mmio_pool,
+    page::{FlushBatch, Flusher, PageFlags, PageMapper},
+    MemoryManagementArch, PhysAddr, VirtAddr,
+};
+
+/// 内核栈的大小（与C语言中的`STACK_SIZE`相同）
+pub const KERNEL_STACK_SIZE: usize = 32768;
+
+/// 一个栈的保护页
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackGuard {
+    /// 栈的最低地址（保护页紧挨在它的下方）
+    pub stack: VirtAddr,
+    /// 保护页的范围
+    pub guard: Range<VirtAddr>,
+    /// 栈所属的进程
+    pub pid: i64,
+    /// 用户栈所在的地址空间的顶层页表的物理地址。内核栈为None
+    pub table: Option<PhysAddr>,
+}
+
+/// 所有已登记的保护页
+static STACK_GUARDS: SpinLock<Vec<StackGuard>> = SpinLock::new(Vec::new());
+
+/// 登记一个内核栈的保护页
+///
+/// ## 参数
+///
+/// - `stack`：栈的最低地址，同时作为登记的键
+/// - `guard`：保护页的范围（必须按页对齐，且不为空）
+/// - `pid`：栈所属的进程
+///
+/// ## 返回值
+///
+/// - `EINVAL`：保护页的范围为空，或者没有按页对齐
+/// - `EEXIST`：这个栈已经被登记，或者保护页与已登记的保护页重叠
+pub fn register_stack_guard(
+    stack: VirtAddr,
+    guard: Range<VirtAddr>,
+    pid: i64,
+) -> Result<(), SystemError> {
+    check_guard_range(&guard)?;
+
+    let mut guards = STACK_GUARDS.lock_irqsave();
+    if guards.iter().any(|g| {
+        g.table.is_none()
+            && (g.stack == stack || (g.guard.start < guard.end && guard.start < g.guard.end))
+    }) {
+        return Err(SystemError::EEXIST);
+    }
+    guards.push(StackGuard {
+        stack,
+        guard,
+        pid,
+        table: None,
+    });
+    return Ok(());
+}
+
+/// 取消登记一个内核栈的保护页（在释放内核栈时调用）
+///
+/// ## 返回值
+///
+/// 被取消登记的保护页。如果这个栈没有被登记，返回None
+pub fn unregister_stack_guard(stack: VirtAddr) -> Option<StackGuard> {
+    let mut guards = STACK_GUARDS.lock_irqsave();
+    let index = guards
+        .iter()
+        .position(|g| g.table.is_none() && g.stack == stack)?;
+    return Some(guards.swap_remove(index));
+}
+
+/// 登记一个用户地址空间的用户栈的保护页。如果这个地址空间已经登记了保护页（比如扩展栈之后），替换它
+///
+/// ## 参数
+///
+/// - `table`：地址空间的顶层页表的物理地址
+/// - `stack`：栈的最低地址
+/// - `guard`：保护页的范围（必须按页对齐，且不为空）
+/// - `pid`：栈所属的进程
+///
+/// ## 返回值
+///
+/// - `EINVAL`：保护页的范围为空，或者没有按页对齐
+pub fn set_user_stack_guard(
+    table: PhysAddr,
+    stack: VirtAddr,
+    guard: Range<VirtAddr>,
+    pid: i64,
+) -> Result<(), SystemError> {
+    check_guard_range(&guard)?;
+
+    let new = StackGuard {
+        stack,
+        guard,
+        pid,
+        table: Some(table),
+    };
+    let mut guards = STACK_GUARDS.lock_irqsave();
+    match guards.iter_mut().find(|g| g.table == Some(table)) {
+        Some(g) => *g = new,
+        None => guards.push(new),
+    }
+    return Ok(());
+}
+
+/// 获取一个用户地址空间登记的用户栈的保护页
+pub fn user_stack_guard(table: PhysAddr) -> Option<StackGuard> {
+    let guards = STACK_GUARDS.lock_irqsave();
+    return guards.iter().find(|g| g.table == Some(table)).cloned();
+}
+
+/// 取消登记一个用户地址空间的用户栈的保护页（在销毁地址空间时调用）
+///
+/// ## 返回值
+///
+/// 被取消登记的保护页。如果这个地址空间没有登记保护页，返回None
+pub fn clear_user_stack_guard(table: PhysAddr) -> Option<StackGuard> {
+    let mut guards = STACK_GUARDS.lock_irqsave();
+    let index = guards.iter().position(|g| g.table == Some(table))?;
+    return Some(guards.swap_remove(index));
+}
+
+fn check_guard_range(guard: &Range<VirtAddr>) -> Result<(), SystemError> {
+    if guard.start >= guard.end
+        || !guard.start.check_aligned(MMArch::PAGE_SIZE)
+        || !guard.end.check_aligned(MMArch::PAGE_SIZE)
+    {
+        return Err(SystemError::EINVAL);
+    }
+    return Ok(());
+}
+
+/// 判断虚拟地址是否位于某个内核栈的保护页内
+///
+/// 供缺页异常处理函数使用。如果登记表的锁已经被占用（比如在登记的过程中发生了缺页异常），
+/// 为了避免死锁，直接返回None
+///
+/// ## 返回值
+///
+/// 如果是，返回对应的保护页
+pub fn classify_stack_fault(vaddr: VirtAddr) -> Option<StackGuard> {
+    let guards = STACK_GUARDS.try_lock_irqsave().ok()?;
+    return guards
+        .iter()
+        .find(|g| g.table.is_none() && g.guard.contains(&vaddr))
+        .cloned();
+}
+
+/// 判断虚拟地址是否位于用户地址空间（以顶层页表区分）的用户栈的保护页内
+///
+/// 与`classify_stack_fault`相同，登记表的锁已经被占用时返回None
+pub fn classify_user_stack_fault(table: PhysAddr, vaddr: VirtAddr) -> Option<StackGuard> {
+    let guards = STACK_GUARDS.try_lock_irqsave().ok()?;
+    return guards
+        .iter()
+        .find(|g| g.table == Some(table) && g.guard.contains(&vaddr))
+        .cloned();
+}
+
+/// 在MMIO地址空间中分配一个内核栈，在它的下方留出一个不映射的保护页，并登记保护页
+///
+/// 栈的最低地址按照`KERNEL_STACK_SIZE`对齐：C语言的代码通过把栈指针按栈的大小对齐来找到栈底的PCB
+///
+/// ## 参数
+///
+/// - `pid`：栈所属的进程
+///
+/// ## 返回值
+///
+/// - 成功：返回栈顶（栈的最高地址，不包括）
+/// - `ENOMEM`：无法分配虚拟地址空间或者页帧
+pub fn alloc_kernel_stack(pid: i64) -> Result<VirtAddr, SystemError> {
+    let mut vaddr: u64 = 0;
+    let mut len: u64 = 0;
+    // 区域的上半部分作为栈，下半部分的最后一个页面作为保护页，其余部分不使用
+    mmio_pool().create_mmio(2 * KERNEL_STACK_SIZE, 0, &mut vaddr, &mut len)?;
+    let region = VirtAddr::new(vaddr as usize);
+    let len = len as usize;
+    let stack = region + len - KERNEL_STACK_SIZE;
+    let guard = stack - MMArch::PAGE_SIZE;
+
+    let r = if stack.check_aligned(KERNEL_STACK_SIZE) {
+        let mut kernel_mapper = KernelMapper::lock();
+        match kernel_mapper.as_mut() {
+            Some(mapper) => unsafe {
+                map_with_guard(
+                    mapper,
+                    guard,
+                    None,
+                    PageFrameCount::from_bytes(KERNEL_STACK_SIZE).unwrap(),
+                    PageFlags::new().set_write(true),
+                    true,
+                    false,
+                )
+            }
+            .map_err(SystemError::from),
+            None => Err(SystemError::EAGAIN_OR_EWOULDBLOCK),
+        }
+    } else {
+        Err(SystemError::ENOMEM)
+    };
+    let (usable, flusher) = match r {
+        Ok(r) => r,
+        Err(e) => {
+            mmio_pool().release_mmio(region, len).ok();
+            return Err(e);
+        }
+    };
+    // 新建立的映射之前不存在，不需要刷新TLB
+    unsafe { flusher.ignore_safe() };
+    unsafe { MMArch::write_bytes(usable.start, 0, KERNEL_STACK_SIZE) };
+    kmem_stat_add(
+        KernelMemPurpose::Vmap,
+        PageFrameCount::from_bytes(KERNEL_STACK_SIZE).unwrap(),
+    );
+
+    register_stack_guard(usable.start, guard..usable.start, pid)?;
+    return Ok(usable.end);
+}
+
+/// 释放由`alloc_kernel_stack`分配的内核栈，并取消登记它的保护页
+///
+/// ## 参数
+///
+/// - `top`：`alloc_kernel_stack`返回的栈顶
+///
+/// ## 返回值
+///
+/// - `EINVAL`：这不是由`alloc_kernel_stack`分配的栈
+///
+/// ## Safety
+///
+/// 调用者需要保证栈已经不再被使用
+pub unsafe fn free_kernel_stack(top: VirtAddr) -> Result<(), SystemError> {
+    let stack = top - KERNEL_STACK_SIZE;
+    unregister_stack_guard(stack).ok_or(SystemError::EINVAL)?;
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let mapper = kernel_mapper
+        .as_mut()
+        .ok_or(SystemError::EAGAIN_OR_EWOULDBLOCK)?;
+    for i in 0..KERNEL_STACK_SIZE / MMArch::PAGE_SIZE {
+        if let Some(flush) = mapper.unmap(stack + i * MMArch::PAGE_SIZE, true) {
+            flush.flush();
+        }
+    }
+    drop(kernel_mapper);
+    kmem_stat_sub(
+        KernelMemPurpose::Vmap,
+        PageFrameCount::from_bytes(KERNEL_STACK_SIZE).unwrap(),
+    );
+    mmio_pool().release_mmio(stack - KERNEL_STACK_SIZE, 2 * KERNEL_STACK_SIZE)?;
+    return Ok(());
+}
+
+/// 映射一段内存，并在它的下方和（或）上方各留出一个不映射的保护页
+///
+/// 整个区域从`vaddr`开始：如果`guard_below`为true，`vaddr`处的页面是保护页，可用的页面紧随其后；
+/// 如果`guard_above`为true，最后一个可用页面之上的页面是保护页。保护页不会被映射，
+/// 如果它已经被映射了，返回`AlreadyMapped`，以免把一个可以访问的页面当作保护页。
+///
+/// ## 参数
+///
+/// - `mapper`：页表映射器
+/// - `vaddr`：整个区域（包括保护页）的起始虚拟地址，必须按页对齐
+/// - `paddr`：可用页面映射到的连续物理内存的起始地址。为None时，从mapper的页帧分配器中为每个页面分配页帧
+/// - `count`：可用页面的数量
+/// - `flags`：可用页面的页表项标志
+/// - `guard_below`：是否在下方留出保护页
+/// - `guard_above`：是否在上方留出保护页
+///
+/// ## 返回值
+///
+/// - `Ok((usable, flusher))`：可用页面的地址范围（不包括保护页），以及需要提交的刷新器
+/// - `Unaligned`：地址没有按页对齐
+/// - `AlreadyMapped`：保护页或者可用页面已经被映射
+/// - `OutOfMemory`：无法分配页帧或者页表（已经建立的映射会被撤销，分配的页帧会被释放）
+pub unsafe fn map_with_guard<F: FrameAllocator>(
+    mapper: &mut PageMapper<MMArch, F>,
+    vaddr: VirtAddr,
+    paddr: Option<PhysAddr>,
+    count: PageFrameCount,
+    flags: PageFlags<MMArch>,
+    guard_below: bool,
+    guard_above: bool,
+) -> Result<(Range<VirtAddr>, FlushBatch<MMArch>), MmError> {
+    if !vaddr.check_aligned(MMArch::PAGE_SIZE) {
+        return Err(MmError::Unaligned(vaddr.data()));
+    }
+    if let Some(paddr) = paddr {
+        if !paddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(MmError::Unaligned(paddr.data()));
+        }
+    }
+
+    let start = if guard_below {
+        vaddr + MMArch::PAGE_SIZE
+    } else {
+        vaddr
+    };
+    let usable = start..start + count.bytes();
+
+    // 先检查整个区域，出错时不需要撤销任何映射
+    let mut pages = (0..count.data()).map(|i| start + i * MMArch::PAGE_SIZE);
+    if let Some(mapped) = pages.find(|virt| mapper.translate(*virt).is_some()) {
+        return Err(MmError::AlreadyMapped(mapped));
+    }
+    let guards = [(guard_below, vaddr), (guard_above, usable.end)];
+    for (_, guard) in guards.iter().filter(|(enabled, _)| *enabled) {
+        if mapper.translate(*guard).is_some() {
+            return Err(MmError::AlreadyMapped(*guard));
+        }
+    }
+
+    let mut flusher = FlushBatch::new();
+    for i in 0..count.data() {
+        let virt = start + i * MMArch::PAGE_SIZE;
+        let r = match paddr {
+            Some(paddr) => mapper.map_phys(virt, paddr + i * MMArch::PAGE_SIZE, flags),
+            None => mapper.map(virt, flags).ok_or(MmError::OutOfMemory {
+                needed: PageFrameCount::new(count.data() - i),
+            }),
+        };
+        match r {
+            Ok(flush) => flusher.consume(flush),
+            Err(e) => {
+                // 撤销已经建立的映射。页帧是由这里分配的，需要一起释放
+                for j in 0..i {
+                    let virt = start + j * MMArch::PAGE_SIZE;
+                    if paddr.is_some() {
+                        if let Ok((_, _, flush)) = mapper.unmap_phys(virt, true) {
+                            flusher.consume(flush);
+                        }
+                    } else if let Some(flush) = mapper.unmap(virt, true) {
+                        flusher.consume(flush);
+                    }
+                }
+                flusher.commit();
+                return Err(e);
+            }
+        }
+    }
+    return Ok((usable, flusher));
+}
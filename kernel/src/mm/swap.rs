@@ -0,0 +1,269 @@
+//! 交换空间（swap）
+//!
+//! 页面被换出时，它的内容被写入交换空间中的一个槽位，页表项被替换为一个不存在的“换出页表项”：
+//! 地址字段中保存槽位的下标，并设置`ENTRY_FLAG_SWAP`软件标志位，其他的标志位（读写、用户、不可执行等）保持不变，
+//! 以便换入时恢复原来的权限。参见`UserMapper::swap_out`/`UserMapper::swap_in`。
+//!
+//! 槽位由`SwapSpace`中的位图管理，页面的内容通过`SwapBackend`读写。实际的磁盘驱动实现`SwapBackend`，
+//! 并通过`swap_on`注册为全局的交换空间。`RamSwapBackend`把槽位保存在内存中，用于测试。
+//!
+//! 后端的读写可能会睡眠，因此不会在持有交换空间的锁时进行。
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{arch::MMArch, libs::spinlock::SpinLock, syscall::SystemError};
+
+use super::{
+    page::{PageEntry, PageFlags},
+    MemoryManagementArch, VirtAddr,
+};
+
+/// 交换空间中的一个槽位（可以保存一个页面）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SwapSlot(usize);
+
+impl SwapSlot {
+    pub const fn new(index: usize) -> Self {
+        return Self(index);
+    }
+
+    /// 槽位的下标
+    pub const fn data(&self) -> usize {
+        return self.0;
+    }
+}
+
+/// 交换空间的后端（比如磁盘上的交换分区）
+pub trait SwapBackend: Send + Sync {
+    /// 后端能够保存的槽位数量
+    fn slots(&self) -> usize;
+
+    /// 把页面的内容写入槽位
+    ///
+    /// ## 参数
+    ///
+    /// - `slot`：槽位
+    /// - `frame_virt`：页面的虚拟地址（长度为一个页面）
+    fn write_slot(&self, slot: SwapSlot, frame_virt: VirtAddr) -> Result<(), SystemError>;
+
+    /// 把槽位中的内容读入页面，参数与`write_slot`相同
+    fn read_slot(&self, slot: SwapSlot, frame_virt: VirtAddr) -> Result<(), SystemError>;
+}
+
+/// 把槽位保存在内存中的后端（用于测试）
+pub struct RamSwapBackend {
+    data: SpinLock<Vec<u8>>,
+    slots: usize,
+}
+
+impl RamSwapBackend {
+    pub fn new(slots: usize) -> Self {
+        let mut data = Vec::new();
+        data.resize(slots * MMArch::PAGE_SIZE, 0);
+        return Self {
+            data: SpinLock::new(data),
+            slots,
+        };
+    }
+}
+
+impl SwapBackend for RamSwapBackend {
+    fn slots(&self) -> usize {
+        return self.slots;
+    }
+
+    fn write_slot(&self, slot: SwapSlot, frame_virt: VirtAddr) -> Result<(), SystemError> {
+        if slot.data() >= self.slots {
+            return Err(SystemError::EINVAL);
+        }
+        let offset = slot.data() * MMArch::PAGE_SIZE;
+        let src =
+            unsafe { core::slice::from_raw_parts(frame_virt.as_ptr::<u8>(), MMArch::PAGE_SIZE) };
+        self.data.lock_irqsave()[offset..offset + MMArch::PAGE_SIZE].copy_from_slice(src);
+        return Ok(());
+    }
+
+    fn read_slot(&self, slot: SwapSlot, frame_virt: VirtAddr) -> Result<(), SystemError> {
+        if slot.data() >= self.slots {
+            return Err(SystemError::EINVAL);
+        }
+        let offset = slot.data() * MMArch::PAGE_SIZE;
+        let dst = unsafe {
+            core::slice::from_raw_parts_mut(frame_virt.as_ptr::<u8>(), MMArch::PAGE_SIZE)
+        };
+        dst.copy_from_slice(&self.data.lock_irqsave()[offset..offset + MMArch::PAGE_SIZE]);
+        return Ok(());
+    }
+}
+
+/// 交换空间：用位图管理后端的槽位
+pub struct SwapSpace {
+    backend: Arc<dyn SwapBackend>,
+    /// 每一位对应一个槽位，为1表示已被分配
+    bitmap: Vec<u64>,
+    /// 已分配的槽位数量
+    used: usize,
+}
+
+impl SwapSpace {
+    /// 为后端创建交换空间（所有槽位都是空闲的）
+    pub fn new(backend: Arc<dyn SwapBackend>) -> Self {
+        let mut bitmap = Vec::new();
+        bitmap.resize((backend.slots() + 63) / 64, 0);
+        return Self {
+            backend,
+            bitmap,
+            used: 0,
+        };
+    }
+
+    /// 交换空间的后端
+    pub fn backend(&self) -> &Arc<dyn SwapBackend> {
+        return &self.backend;
+    }
+
+    /// 槽位的总数
+    pub fn slots(&self) -> usize {
+        return self.backend.slots();
+    }
+
+    /// 已分配的槽位数量
+    pub fn used(&self) -> usize {
+        return self.used;
+    }
+
+    fn test(&self, index: usize) -> bool {
+        return self.bitmap[index / 64] & (1 << (index % 64)) != 0;
+    }
+
+    /// 分配一个槽位
+    ///
+    /// ## 返回值
+    ///
+    /// - `ENOSPC`：所有的槽位都已被分配
+    pub fn alloc_slot(&mut self) -> Result<SwapSlot, SystemError> {
+        for (i, word) in self.bitmap.iter_mut().enumerate() {
+            if *word == u64::MAX {
+                continue;
+            }
+            let index = i * 64 + word.trailing_ones() as usize;
+            if index >= self.backend.slots() {
+                break;
+            }
+            *word |= 1 << (index % 64);
+            self.used += 1;
+            return Ok(SwapSlot::new(index));
+        }
+        return Err(SystemError::ENOSPC);
+    }
+
+    /// 释放`alloc_slot`分配的槽位
+    ///
+    /// ## 返回值
+    ///
+    /// - `EINVAL`：槽位不存在，或者没有被分配
+    pub fn free_slot(&mut self, slot: SwapSlot) -> Result<(), SystemError> {
+        let index = slot.data();
+        if index >= self.backend.slots() || !self.test(index) {
+            return Err(SystemError::EINVAL);
+        }
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+        self.used -= 1;
+        return Ok(());
+    }
+}
+
+/// 全局的交换空间
+static SWAP_SPACE: SpinLock<Option<SwapSpace>> = SpinLock::new(None);
+
+/// 把后端注册为全局的交换空间
+///
+/// ## 返回值
+///
+/// - `EBUSY`：已经注册了交换空间
+pub fn swap_on(backend: Arc<dyn SwapBackend>) -> Result<(), SystemError> {
+    let mut space = SWAP_SPACE.lock_irqsave();
+    if space.is_some() {
+        return Err(SystemError::EBUSY);
+    }
+    *space = Some(SwapSpace::new(backend));
+    return Ok(());
+}
+
+/// 注销全局的交换空间
+///
+/// ## 返回值
+///
+/// - 成功：返回交换空间的后端
+/// - `EINVAL`：没有注册交换空间
+/// - `EBUSY`：还有被换出的页面
+pub fn swap_off() -> Result<Arc<dyn SwapBackend>, SystemError> {
+    let mut space = SWAP_SPACE.lock_irqsave();
+    match space.as_ref() {
+        None => return Err(SystemError::EINVAL),
+        Some(s) if s.used() != 0 => return Err(SystemError::EBUSY),
+        _ => {}
+    }
+    return Ok(space.take().unwrap().backend);
+}
+
+/// 全局的交换空间中（已分配的槽位数量，槽位的总数）。没有注册交换空间时，返回None
+pub fn swap_usage() -> Option<(usize, usize)> {
+    return SWAP_SPACE
+        .lock_irqsave()
+        .as_ref()
+        .map(|s| (s.used(), s.slots()));
+}
+
+/// 从全局的交换空间中分配一个槽位
+///
+/// ## 返回值
+///
+/// - 成功：返回槽位，以及用于读写它的后端
+/// - `ENODEV`：没有注册交换空间
+/// - `ENOSPC`：所有的槽位都已被分配
+pub fn swap_alloc_slot() -> Result<(SwapSlot, Arc<dyn SwapBackend>), SystemError> {
+    let mut space = SWAP_SPACE.lock_irqsave();
+    let space = space.as_mut().ok_or(SystemError::ENODEV)?;
+    let slot = space.alloc_slot()?;
+    return Ok((slot, space.backend().clone()));
+}
+
+/// 释放全局的交换空间中的槽位
+pub fn swap_free_slot(slot: SwapSlot) -> Result<(), SystemError> {
+    return SWAP_SPACE
+        .lock_irqsave()
+        .as_mut()
+        .ok_or(SystemError::ENODEV)?
+        .free_slot(slot);
+}
+
+/// 全局的交换空间的后端
+pub fn swap_backend() -> Result<Arc<dyn SwapBackend>, SystemError> {
+    return SWAP_SPACE
+        .lock_irqsave()
+        .as_ref()
+        .map(|s| s.backend().clone())
+        .ok_or(SystemError::ENODEV);
+}
+
+/// 生成换出页表项：页面不存在，地址字段中保存槽位的下标，并保留页面原来的标志位
+pub fn swap_entry_encode(slot: SwapSlot, flags: PageFlags<MMArch>) -> PageEntry<MMArch> {
+    let bits = flags.to_entry_bits() & !(MMArch::ENTRY_FLAG_PRESENT | MMArch::ENTRY_FLAG_DIRTY);
+    return PageEntry::new(bits | MMArch::ENTRY_FLAG_SWAP | (slot.data() << MMArch::PAGE_SHIFT));
+}
+
+/// 解析换出页表项
+///
+/// ## 返回值
+///
+/// (槽位, 页面原来的标志位)。如果不是换出页表项，返回None
+pub fn swap_entry_decode(entry: PageEntry<MMArch>) -> Option<(SwapSlot, PageFlags<MMArch>)> {
+    if entry.present() || entry.data() & MMArch::ENTRY_FLAG_SWAP == 0 {
+        return None;
+    }
+    let slot = SwapSlot::new((entry.data() & MMArch::ENTRY_ADDRESS_MASK) >> MMArch::PAGE_SHIFT);
+    let bits = (entry.data() & MMArch::ENTRY_FLAGS_MASK & !MMArch::ENTRY_FLAG_SWAP)
+        | MMArch::ENTRY_FLAG_PRESENT;
+    return Some((slot, PageFlags::from_entry_bits(bits)));
+}
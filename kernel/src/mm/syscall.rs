@@ -141,11 +141,6 @@ impl Syscall {
             return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
         }
 
-        // 暂时不支持巨页映射
-        if map_flags.contains(MapFlags::MAP_HUGETLB) {
-            kerror!("mmap: not support huge page mapping");
-            return Err(SystemError::EOPNOTSUPP_OR_ENOTSUP);
-        }
         let current_address_space = AddressSpace::current()?;
         let start_page = current_address_space.write().map_anonymous(
             start_vaddr,
@@ -0,0 +1,184 @@
+//! 内核跳板页（trampoline）
+//!
+//! 启用KPTI之后，用户态运行时使用的页表只包含极少量的内核映射：
+//! 系统调用/中断的入口代码，以及入口代码所使用的栈。这些页面称为跳板页。
+//!
+//! 本模块维护一张“精简的内核页表”，其内核部分只包含跳板页的映射。
+//! KPTI模式下，用户态页表的内核部分应当从这张页表中复制，而不是从完整的内核页表中复制。
+//! 跳板页同时也被映射到完整的内核页表中（同一个虚拟地址），因此在两张页表之间切换时，
+//! 入口代码能够继续执行。
+//!
+//! 跳板页以全局、可执行、只读的方式映射。在KPTI完成之前，本模块也可以单独使用。
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{
+        mm::{LockedFrameAllocator, PageMapper},
+        MMArch,
+    },
+    libs::spinlock::SpinLock,
+};
+
+use super::{
+    allocator::page_frame::{FrameAllocator, PageFrameCount},
+    error::MmError,
+    kernel_mapper::KernelMapper,
+    page::PageFlags,
+    MemoryManagementArch, PageTableKind, PhysAddr, VirtAddr,
+};
+
+/// 跳板页所在的虚拟地址空间的起始地址（位于每CPU区域之后）
+pub const TRAMPOLINE_AREA_BASE: VirtAddr = VirtAddr::new(0xffffa30000000000);
+/// 跳板页所在的虚拟地址空间的大小
+pub const TRAMPOLINE_AREA_SIZE: usize = 2 * 1024 * 1024;
+
+/// 已经映射的跳板页：（虚拟地址，物理地址）
+static TRAMPOLINE_PAGES: SpinLock<Vec<(VirtAddr, PhysAddr)>> = SpinLock::new(Vec::new());
+
+/// 精简的内核页表的物理地址（在第一次映射跳板页时创建）
+static TRIMMED_KERNEL_TABLE: SpinLock<Option<PhysAddr>> = SpinLock::new(None);
+
+/// 跳板页的页表项标志：全局、可执行、只读、仅内核可访问
+pub fn trampoline_flags() -> PageFlags<MMArch> {
+    return PageFlags::new().set_execute(true).set_global(true);
+}
+
+/// 判断虚拟地址是否位于跳板页的虚拟地址空间中
+fn in_trampoline_area(vaddr: VirtAddr) -> bool {
+    return vaddr >= TRAMPOLINE_AREA_BASE
+        && vaddr.data() < TRAMPOLINE_AREA_BASE.data() + TRAMPOLINE_AREA_SIZE;
+}
+
+/// 初始化跳板页的虚拟地址空间
+///
+/// 为跳板页预先在完整的内核页表中创建中间级的页表，使得之后创建的用户地址空间在复制内核部分的顶级页表项时，
+/// 能够看到跳板页的映射。需要在创建第一个用户地址空间之前调用
+pub fn trampoline_area_init() {
+    let mut mapper = KernelMapper::lock();
+    let mapper = mapper
+        .as_mut()
+        .expect("trampoline_area_init: kernel mapper is readonly");
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate(PageFrameCount::new(1)) }
+        .expect("trampoline_area_init: out of memory");
+    unsafe {
+        mapper
+            .map_phys(TRAMPOLINE_AREA_BASE, paddr, trampoline_flags())
+            .expect("trampoline_area_init: failed to map")
+            .flush();
+        // 只取消最后一级的映射，保留中间级的页表
+        let (_, _, flusher) = mapper
+            .unmap_phys(TRAMPOLINE_AREA_BASE, false)
+            .expect("trampoline_area_init: failed to unmap");
+        flusher.flush();
+        LockedFrameAllocator.free(paddr, PageFrameCount::new(1));
+    }
+}
+
+/// 获取精简的内核页表的页面映射器，如果页表还不存在，就创建它
+fn trimmed_mapper(table: &mut Option<PhysAddr>) -> Result<PageMapper, MmError> {
+    if let Some(paddr) = *table {
+        return Ok(unsafe { PageMapper::new(PageTableKind::Kernel, paddr, LockedFrameAllocator) });
+    }
+    let mapper = unsafe { PageMapper::create(PageTableKind::Kernel, LockedFrameAllocator) }.ok_or(
+        MmError::OutOfMemory {
+            needed: PageFrameCount::new(1),
+        },
+    )?;
+    *table = Some(mapper.table().phys());
+    return Ok(mapper);
+}
+
+/// 把一个跳板页同时映射到完整的内核页表和精简的内核页表中
+///
+/// ## 参数
+///
+/// - `paddr`：跳板页的物理地址（必须按页对齐）
+/// - `vaddr`：跳板页的虚拟地址（必须按页对齐，且位于`TRAMPOLINE_AREA_BASE`开始的区域中）
+///
+/// ## 返回值
+///
+/// - `MmError::KernelRangeViolation`：虚拟地址不在跳板页的虚拟地址空间中
+/// - `MmError::AlreadyMapped`：虚拟地址已经被映射为跳板页
+pub fn map_trampoline(paddr: PhysAddr, vaddr: VirtAddr) -> Result<(), MmError> {
+    if !in_trampoline_area(vaddr) {
+        return Err(MmError::KernelRangeViolation(vaddr));
+    }
+    let mut pages = TRAMPOLINE_PAGES.lock_irqsave();
+    if pages.iter().any(|(v, _)| *v == vaddr) {
+        return Err(MmError::AlreadyMapped(vaddr));
+    }
+
+    let mut table = TRIMMED_KERNEL_TABLE.lock_irqsave();
+    let mut trimmed = trimmed_mapper(&mut table)?;
+    // 精简的内核页表不是当前页表，因此不需要刷新TLB
+    let flusher = unsafe { trimmed.map_phys(vaddr, paddr, trampoline_flags()) }?;
+    unsafe { flusher.ignore_safe() };
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let kernel_mapper = kernel_mapper
+        .as_mut()
+        .expect("map_trampoline: kernel mapper is readonly");
+    match unsafe { kernel_mapper.map_phys(vaddr, paddr, trampoline_flags()) } {
+        Ok(flusher) => flusher.flush(),
+        Err(e) => {
+            let (_, _, flusher) = unsafe { trimmed.unmap_phys(vaddr, true) }?;
+            unsafe { flusher.ignore_safe() };
+            return Err(e);
+        }
+    }
+
+    pages.push((vaddr, paddr));
+    return Ok(());
+}
+
+/// 取消一个跳板页的映射
+///
+/// ## 返回值
+///
+/// 跳板页的物理地址（由调用者负责释放）。如果虚拟地址不是跳板页，返回`MmError::NotMapped`
+pub fn unmap_trampoline(vaddr: VirtAddr) -> Result<PhysAddr, MmError> {
+    let mut pages = TRAMPOLINE_PAGES.lock_irqsave();
+    let idx = pages
+        .iter()
+        .position(|(v, _)| *v == vaddr)
+        .ok_or(MmError::NotMapped(vaddr))?;
+    let (_, paddr) = pages.remove(idx);
+
+    let mut table = TRIMMED_KERNEL_TABLE.lock_irqsave();
+    let mut trimmed = trimmed_mapper(&mut table)?;
+    let (_, _, flusher) = unsafe { trimmed.unmap_phys(vaddr, true) }?;
+    unsafe { flusher.ignore_safe() };
+
+    let mut kernel_mapper = KernelMapper::lock();
+    let kernel_mapper = kernel_mapper
+        .as_mut()
+        .expect("unmap_trampoline: kernel mapper is readonly");
+    // 保留完整的内核页表中的中间级页表（参见`trampoline_area_init`）
+    // invlpg同样会刷新全局页面的TLB条目
+    let (_, _, flusher) = unsafe { kernel_mapper.unmap_phys(vaddr, false) }?;
+    flusher.flush();
+
+    return Ok(paddr);
+}
+
+/// 获取精简的内核页表的物理地址
+///
+/// 如果还没有映射过任何跳板页，返回None
+pub fn trimmed_kernel_table() -> Option<PhysAddr> {
+    return *TRIMMED_KERNEL_TABLE.lock_irqsave();
+}
+
+/// 获取所有已经映射的跳板页
+pub fn trampoline_pages() -> Vec<(VirtAddr, PhysAddr)> {
+    return TRAMPOLINE_PAGES.lock_irqsave().clone();
+}
+
+/// 判断虚拟地址是否位于某个跳板页中
+pub fn is_trampoline(vaddr: VirtAddr) -> bool {
+    let page = VirtAddr::new(vaddr.data() & !(MMArch::PAGE_SIZE - 1));
+    return TRAMPOLINE_PAGES
+        .lock_irqsave()
+        .iter()
+        .any(|(v, _)| *v == page);
+}
@@ -5,7 +5,7 @@ use core::{
     hash::Hasher,
     intrinsics::unlikely,
     ops::Add,
-    sync::atomic::{compiler_fence, Ordering},
+    sync::atomic::{compiler_fence, AtomicUsize, Ordering},
 };
 
 use alloc::{
@@ -16,10 +16,15 @@ use alloc::{
 use hashbrown::HashSet;
 
 use crate::{
-    arch::{asm::current::current_pcb, mm::PageMapper, CurrentIrqArch, MMArch},
+    arch::{
+        asm::current::current_pcb,
+        mm::{LockedFrameAllocator, PageMapper},
+        CurrentIrqArch, MMArch,
+    },
     exception::InterruptArch,
     libs::{
         align::page_align_up,
+        lazy_init::Lazy,
         rwlock::{RwLock, RwLockWriteGuard},
         spinlock::{SpinLock, SpinLockGuard},
     },
@@ -27,12 +32,28 @@ use crate::{
 };
 
 use super::{
+    allocator::frame_tag::{frame_tag_set, FRAME_TAG_USER_ANON},
+    allocator::huge_pool::{
+        alloc_zeroed_huge_page, free_huge_page, HUGE_PAGE_FRAMES, HUGE_PAGE_SIZE,
+    },
     allocator::page_frame::{
-        deallocate_page_frames, PageFrameCount, PhysPageFrame, VirtPageFrame, VirtPageFrameIter,
+        alloc_frames_flags, alloc_frames_on_node, deallocate_page_frames, frame_pinned, AllocFlags,
+        PageFrameCount, PhysPageFrame, VirtPageFrame, VirtPageFrameIter, NUMA_NODES,
+    },
+    cache_type::is_ram,
+    kmem_stat::{kmem_stat_sub, KernelMemPurpose},
+    ksm::{dec_ref, dec_ref_bulk, inc_ref_bulk, ksm_frame_refcount, ksm_put},
+    page::{
+        Flusher, InactiveFlusher, PageEntry, PageFlags, PageFlush, PageFlushAll, PageSize,
+        PageTable,
+    },
+    stack_guard::{clear_user_stack_guard, map_with_guard, set_user_stack_guard, user_stack_guard},
+    swap::{
+        swap_alloc_slot, swap_backend, swap_entry_decode, swap_entry_encode, swap_free_slot,
+        SwapSlot,
     },
-    page::{Flusher, InactiveFlusher, PageFlags, PageFlushAll},
     syscall::{MapFlags, ProtFlags},
-    MemoryManagementArch, PageTableKind, VirtAddr, VirtRegion,
+    MemoryManagementArch, PageTableKind, PhysAddr, VirtAddr, VirtRegion,
 };
 
 /// MMAP_MIN_ADDR的默认值
@@ -143,6 +164,9 @@ impl InnerAddressSpace {
 
     /// 尝试克隆当前进程的地址空间，包括这些映射都会被克隆
     ///
+    /// 页面以写时复制的方式与新的地址空间共享（参见`UserMapper::clone_user_mapping`），
+    /// 新的地址空间拥有与当前地址空间相同的VMA
+    ///
     /// # Returns
     ///
     /// 返回克隆后的，新的地址空间的Arc指针
@@ -151,67 +175,36 @@ impl InnerAddressSpace {
         let new_addr_space = AddressSpace::new(false)?;
         let mut new_guard = new_addr_space.write();
 
-        // 拷贝用户栈的结构体信息，但是不拷贝用户栈的内容（因为后面VMA的拷贝会拷贝用户栈的内容）
+        // 拷贝用户栈的结构体信息，但是不拷贝用户栈的内容（用户栈的页面与其他页面一起被共享）
         unsafe {
             new_guard.user_stack = Some(self.user_stack.as_ref().unwrap().clone_info_only());
         }
         let _current_stack_size = self.user_stack.as_ref().unwrap().stack_size();
 
-        let current_mapper = &mut self.user_mapper.utable;
+        new_guard.user_mapper = UserMapper::clone_user_mapping(&self.user_mapper)?;
+        // 新的地址空间有相同的用户栈，也有相同的保护页（由`copy_mm`更新为子进程的pid）
+        if let Some(g) = user_stack_guard(self.user_mapper.utable.table().phys()) {
+            set_user_stack_guard(
+                new_guard.user_mapper.utable.table().phys(),
+                g.stack,
+                g.guard,
+                g.pid,
+            )?;
+        }
 
         for vma in self.mappings.vmas.iter() {
             // TODO: 增加对VMA是否为文件映射的判断，如果是的话，就跳过
 
             let vma_guard: SpinLockGuard<'_, VMA> = vma.lock();
-            let old_flags = vma_guard.flags();
-            let tmp_flags: PageFlags<MMArch> = PageFlags::new().set_write(true);
-
-            // 分配内存页并创建新的VMA
-            let new_vma = VMA::zeroed(
-                VirtPageFrame::new(vma_guard.region.start()),
-                PageFrameCount::new(vma_guard.region.size() / MMArch::PAGE_SIZE),
-                tmp_flags,
-                &mut new_guard.user_mapper.utable,
-                (),
-            )?;
-            new_guard.mappings.vmas.insert(new_vma.clone());
-            // kdebug!("new vma: {:x?}", new_vma);
-            let mut new_vma_guard = new_vma.lock();
-            for page in new_vma_guard.pages().map(|p| p.virt_address()) {
-                // kdebug!("page: {:x?}", page);
-                let current_frame = unsafe {
-                    MMArch::phys_2_virt(
-                        current_mapper
-                            .translate(page)
-                            .expect("VMA page not mapped")
-                            .0,
-                    )
-                }
-                .expect("Phys2Virt: vaddr overflow.")
-                .data() as *mut u8;
-
-                let new_frame = unsafe {
-                    MMArch::phys_2_virt(
-                        new_guard
-                            .user_mapper
-                            .utable
-                            .translate(page)
-                            .expect("VMA page not mapped")
-                            .0,
-                    )
-                }
-                .expect("Phys2Virt: vaddr overflow.")
-                .data() as *mut u8;
-
-                unsafe {
-                    // 拷贝数据
-                    new_frame.copy_from_nonoverlapping(current_frame, MMArch::PAGE_SIZE);
-                }
-            }
+            let new_vma = LockedVMA::new(VMA {
+                region: vma_guard.region.clone(),
+                flags: vma_guard.flags(),
+                mapped: vma_guard.mapped,
+                user_address_space: None,
+                self_ref: Weak::default(),
+            });
             drop(vma_guard);
-
-            new_vma_guard.remap(old_flags, &mut new_guard.user_mapper.utable, ())?;
-            drop(new_vma_guard);
+            new_guard.mappings.insert_vma(new_vma);
         }
         drop(new_guard);
         drop(irq_guard);
@@ -268,7 +261,11 @@ impl InnerAddressSpace {
             prot_flags,
             map_flags,
             move |page, count, flags, mapper, flusher| {
-                Ok(VMA::zeroed(page, count, flags, mapper, flusher)?)
+                if map_flags.contains(MapFlags::MAP_HUGETLB) {
+                    Ok(VMA::zeroed_huge(page, count, flags, mapper, flusher)?)
+                } else {
+                    Ok(VMA::zeroed(page, count, flags, mapper, flusher)?)
+                }
             },
         )?;
 
@@ -480,6 +477,19 @@ impl InnerAddressSpace {
         return self.user_stack.as_mut();
     }
 
+    /// 统计地址空间的内存使用情况
+    ///
+    /// 在`UserMapper::memory_summary`的基础上，`virtual_bytes`为所有VMA的大小之和（包括尚未被映射到页表的部分）
+    pub fn memory_summary(&self) -> MemSummary {
+        let mut summary = self.user_mapper.memory_summary();
+        summary.virtual_bytes = self
+            .mappings
+            .iter_vmas()
+            .map(|vma| vma.lock().region().size())
+            .sum();
+        return summary;
+    }
+
     /// 取消用户空间内的所有映射
     pub unsafe fn unmap_all(&mut self) {
         let mut flusher: PageFlushAll<MMArch> = PageFlushAll::new();
@@ -554,14 +564,820 @@ impl Drop for InnerAddressSpace {
     }
 }
 
-#[derive(Debug, Hash)]
+/// 共享的零页帧：所有进程中，尚未被写入过的匿名页面都可以只读地映射到这个页帧上
+static ZERO_FRAME: Lazy<PhysAddr> = Lazy::new();
+
+/// 分配共享的零页帧（需要在页帧分配器初始化之后调用）
+pub fn zero_frame_init() {
+    let (paddr, _) = unsafe { LockedFrameAllocator.allocate_zeroed(PageFrameCount::new(1)) }
+        .expect("zero_frame_init: out of memory");
+    ZERO_FRAME.init(paddr);
+}
+
+/// 获取共享的零页帧。如果尚未初始化，返回None
+pub fn zero_frame() -> Option<PhysAddr> {
+    return ZERO_FRAME.try_get().copied();
+}
+
+/// 计算把已映射的页面重新设置为flags时，页表项实际使用的标志位
+///
+/// 共享的页帧（写时复制页面、引用计数大于1的页帧）不能直接变为可写：flags可写时，以只读、写时复制的方式映射，
+/// 写入时由`UserMapper::handle_write_fault`复制。flags只读时清除COW标志位，使写入仍然被视为访问违规
+fn shared_frame_flags(
+    mapper: &PageMapper,
+    vaddr: VirtAddr,
+    flags: PageFlags<MMArch>,
+) -> PageFlags<MMArch> {
+    if !flags.has_write() {
+        return flags.set_cow(false);
+    }
+    match mapper.translate(vaddr) {
+        Some((paddr, old)) if old.has_cow() || ksm_frame_refcount(paddr) > 1 => {
+            return flags.set_write(false).set_cow(true);
+        }
+        _ => return flags,
+    }
+}
+
+/// `UserMapper::handle_write_fault`的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteFaultOutcome {
+    /// 写时复制页面：已经复制到新的页帧，并恢复了写权限
+    CowResolved,
+    /// 映射到共享零页帧的页面：已经分配了私有的页帧
+    ZeroPageReplaced,
+    /// 页面已经是可写的（比如其他核心已经处理了这个缺页异常），不需要处理
+    Spurious,
+    /// 页面被程序自己设置为只读（比如通过mprotect），应当向进程发送SIGSEGV
+    AccessViolation,
+    /// 页面没有被映射
+    NotMapped,
+}
+
+/// 进程的内存使用情况（供getrusage、ps等使用）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemSummary {
+    /// 已经映射到物理页帧的内存（常驻内存）
+    pub rss_bytes: usize,
+    /// 虚拟内存的总大小（包括尚未被映射到物理页帧的部分）
+    pub virtual_bytes: usize,
+    /// 与其他页面共享物理页帧的内存（引用计数大于1的页帧，以及共享的零页帧）
+    pub shared_bytes: usize,
+    /// 被锁定在内存中的内存。内核目前不支持mlock，mmap也不会记录`MAP_LOCKED`，因此恒为0
+    pub locked_bytes: usize,
+    /// 被`pin_frame`固定的内存（比如正在进行DMA的页帧），按4K页帧统计
+    pub pinned_bytes: usize,
+}
+
+#[derive(Debug)]
 pub struct UserMapper {
     pub utable: PageMapper,
+    /// 当前用户页表被pin住的次数（最高位表示UserMapper已经被drop）
+    pins: Arc<AtomicUsize>,
+    /// 地址空间的PCID（x86_64），为0表示没有分配PCID
+    pcid: usize,
+}
+
+impl core::hash::Hash for UserMapper {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.utable.hash(state);
+    }
 }
 
 impl UserMapper {
+    /// `pins`中，表示UserMapper已经被drop的标志位
+    const PIN_DEAD: usize = 1 << (usize::BITS - 1);
+
     pub fn new(utable: PageMapper) -> Self {
-        return Self { utable };
+        return Self {
+            utable,
+            pins: Arc::new(AtomicUsize::new(0)),
+            pcid: 0,
+        };
+    }
+
+    /// 设置地址空间的PCID（由`setup_new_usermapper`分配，UserMapper被drop时归还）
+    pub fn with_pcid(mut self, pcid: usize) -> Self {
+        self.pcid = pcid;
+        return self;
+    }
+
+    /// 获取地址空间的PCID
+    pub fn pcid(&self) -> usize {
+        return self.pcid;
+    }
+
+    /// 将用户页表设置为当前页表
+    ///
+    /// 分配了PCID时，如果当前CPU上TLB中这个地址空间的条目仍然有效，切换时不会刷新TLB
+    pub unsafe fn make_current(&self) {
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::mm::X86_64MMArch::set_table_pcid(self.utable.table().phys(), self.pcid);
+        #[cfg(not(target_arch = "x86_64"))]
+        self.utable.make_current();
+    }
+
+    /// pin住当前的用户页表，防止其在遍历的过程中被其他核心销毁（比如进程退出）
+    ///
+    /// 在返回的守卫被drop之前，用户页表不会被释放：
+    /// 如果UserMapper在此期间被drop，释放各级页表、归还PCID的操作会被推迟到最后一个守卫被drop的时候。
+    ///
+    /// 请注意，守卫只能保证顶层页表仍然有效，并不能阻止其他核心修改页表的映射
+    pub fn pin_alive(&self) -> UserMapperGuard {
+        self.pins.fetch_add(1, Ordering::Acquire);
+        return UserMapperGuard {
+            table_paddr: self.utable.table().phys(),
+            pcid: self.pcid,
+            pins: self.pins.clone(),
+        };
+    }
+
+    /// 检查一段用户空间的地址范围是否可以被访问（不进行拷贝）
+    ///
+    /// 要求范围内的地址都是规范地址，整个范围位于`USER_END_VADDR`之下，
+    /// 并且范围内的每一个页面都已经被映射，且具有用户态访问（以及写入）的权限。
+    ///
+    /// 目前用户空间的页面在建立VMA时就已经被映射，没有延迟分配的页面，因此未映射的页面一律视为不可访问
+    ///
+    /// ## 参数
+    ///
+    /// - `start`：起始虚拟地址
+    /// - `len`：长度（字节）
+    /// - `write`：是否需要写权限
+    ///
+    /// ## 返回值
+    ///
+    /// 如果整个范围都可以被访问，返回true。遇到第一个不满足要求的页面时返回false
+    pub fn access_ok(&self, start: VirtAddr, len: usize, write: bool) -> bool {
+        if len == 0 {
+            return MMArch::virt_is_valid(start) && start <= MMArch::USER_END_VADDR;
+        }
+        let end = match start.data().checked_add(len) {
+            Some(end) => VirtAddr::new(end),
+            None => return false,
+        };
+        if !MMArch::virt_is_valid(start) || end > MMArch::USER_END_VADDR {
+            return false;
+        }
+
+        let mut vaddr = VirtAddr::new(start.data() & !MMArch::PAGE_OFFSET_MASK);
+        while vaddr < end {
+            let flags = match self.utable.translate(vaddr) {
+                Some((_, flags)) => flags,
+                None => return false,
+            };
+            if !flags.has_user() || (write && !flags.has_write()) {
+                return false;
+            }
+            vaddr += MMArch::PAGE_SIZE;
+        }
+        return true;
+    }
+
+    /// 查找与用户空间的虚拟地址最近的已映射区域，用于在缺页异常时报告访问越界之类的错误
+    ///
+    /// ## 返回值
+    ///
+    /// (下方最近的已映射区域的结束地址, 上方最近的已映射区域的起始地址)
+    pub fn nearest_mappings(&self, vaddr: VirtAddr) -> (Option<VirtAddr>, Option<VirtAddr>) {
+        let user_top = VirtAddr::new(page_align_up(MMArch::USER_END_VADDR.data()));
+        return self
+            .utable
+            .nearest_mappings(vaddr, VirtAddr::new(0)..user_top);
+    }
+
+    /// 遍历用户空间的页表，统计内存使用情况
+    ///
+    /// 只遍历顶层页表中属于用户空间的表项。页表中没有记录尚未映射的区域，因此`virtual_bytes`与`rss_bytes`相同，
+    /// 需要包括这些区域时，使用`InnerAddressSpace::memory_summary`
+    pub fn memory_summary(&self) -> MemSummary {
+        let mut summary = MemSummary::default();
+        let top = self.utable.table();
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            match top.entry_base(i) {
+                Some(base) if base < MMArch::USER_END_VADDR => {}
+                _ => continue,
+            }
+            if let Some(next) = unsafe { top.next_level_table(i) } {
+                unsafe { Self::summarize_table(&next, &mut summary) };
+            }
+        }
+        summary.virtual_bytes = summary.rss_bytes;
+        return summary;
+    }
+
+    /// 统计一张页表（及其下级页表）中的映射
+    unsafe fn summarize_table(table: &PageTable<MMArch>, summary: &mut MemSummary) {
+        let level = table.level();
+        let size = 1 << (level * MMArch::PAGE_ENTRY_SHIFT + MMArch::PAGE_SHIFT);
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            let entry = match table.entry(i) {
+                Some(entry) if entry.present() => entry,
+                _ => continue,
+            };
+            let huge = level > 0 && entry.data() & MMArch::ENTRY_FLAG_HUGE_PAGE != 0;
+            if level != 0 && !huge {
+                if let Some(next) = table.next_level_table(i) {
+                    Self::summarize_table(&next, summary);
+                }
+                continue;
+            }
+
+            summary.rss_bytes += size;
+            let paddr = entry.address().unwrap();
+            if ksm_frame_refcount(paddr) > 1 || zero_frame() == Some(paddr) {
+                summary.shared_bytes += size;
+            }
+            // 页帧是按4K固定的，大页中的每个页帧都需要单独检查
+            for offset in (0..size).step_by(MMArch::PAGE_SIZE) {
+                if frame_pinned(paddr + offset) {
+                    summary.pinned_bytes += MMArch::PAGE_SIZE;
+                }
+            }
+        }
+    }
+
+    /// 在用户空间的[window_start, window_end)范围内，查找一段没有被映射的虚拟地址范围（用于没有指定地址的mmap）
+    ///
+    /// 与通常的用户地址空间布局一致，从高地址向低地址查找。window_end会被限制在`USER_END_VADDR`之下
+    ///
+    /// ## 参数
+    ///
+    /// - `window_start`：查找范围的起始地址
+    /// - `window_end`：查找范围的结束地址
+    /// - `len`：长度（字节）
+    /// - `align`：起始地址的对齐（必须是2的幂）
+    ///
+    /// ## 返回值
+    ///
+    /// 找到的范围的起始地址。如果没有足够大的空洞，返回None
+    pub fn find_free_range(
+        &self,
+        window_start: VirtAddr,
+        window_end: VirtAddr,
+        len: usize,
+        align: usize,
+    ) -> Option<VirtAddr> {
+        let user_top = VirtAddr::new(page_align_up(MMArch::USER_END_VADDR.data()));
+        return self.utable.find_free_range(
+            window_start..window_end.min(user_top),
+            len,
+            align,
+            true,
+        );
+    }
+
+    /// 清空用户空间的所有映射（用于execve）
+    ///
+    /// 取消映射并释放用户地址空间（`[0, USER_END_VADDR)`）中所有的页面，以及所有的中间页表，
+    /// 使得用户页表回到“空但有效”的状态，可以直接用于装载新的程序。
+    /// 顶层页表中与内核共享的表项不会被修改。
+    ///
+    /// 与`Drop`不同，调用这个函数之后，UserMapper仍然可以使用。
+    ///
+    /// 请注意，调用者需要保证没有VMA仍然引用这些页面（VMA应当先被移除）
+    ///
+    /// ## 返回值
+    ///
+    /// 被释放的页面数量
+    pub fn clear_user_space(&mut self) -> PageFrameCount {
+        let top = self.utable.table();
+        let is_kernel_entry = |i: usize| {
+            top.entry_base(i)
+                .map_or(true, |base| base >= MMArch::USER_END_VADDR)
+        };
+
+        // 记录内核部分的表项，以便在清空之后检查它们没有被修改
+        let kernel_entries: Vec<(usize, usize)> = (0..MMArch::PAGE_ENTRY_NUM)
+            .filter(|i| is_kernel_entry(*i))
+            .map(|i| (i, unsafe { top.entry(i) }.map_or(0, |e| e.data())))
+            .collect();
+
+        let mut freed = 0;
+        for i in (0..MMArch::PAGE_ENTRY_NUM).filter(|i| !is_kernel_entry(*i)) {
+            unsafe { Self::clear_entry(&top, i, &mut freed) };
+        }
+
+        for (i, data) in kernel_entries {
+            assert_eq!(
+                unsafe { top.entry(i) }.map_or(0, |e| e.data()),
+                data,
+                "clear_user_space: kernel entry {} of the top level table was modified",
+                i
+            );
+        }
+
+        if self.utable.is_current() {
+            unsafe { MMArch::invalidate_all() };
+        }
+        return PageFrameCount::new(freed);
+    }
+
+    /// 清空页表中指向下一级页表的表项，递归地清空并释放下一级页表
+    ///
+    /// ## 参数
+    ///
+    /// - `table`：页表（不能是最后一级页表）
+    /// - `i`：表项的下标
+    /// - `freed`：累计被释放的页面数量
+    unsafe fn clear_entry(table: &PageTable<MMArch>, i: usize, freed: &mut usize) {
+        let entry = match table.entry(i) {
+            Some(entry) if entry.present() => entry,
+            _ => return,
+        };
+        let paddr = entry.address().unwrap();
+
+        // 直接映射的大页：没有下一级页表。设备内存不是由页帧分配器分配的，不能被释放
+        if table.entry_is_huge(i) {
+            let count = PageFrameCount::new(MMArch::PAGE_ENTRY_NUM.pow(table.level() as u32));
+            let base = PhysAddr::new(paddr.data() & !(count.bytes() - 1));
+            if is_ram(base) {
+                deallocate_page_frames(PhysPageFrame::new(base), count);
+            }
+            *freed += count.data();
+            table.set_entry(i, PageEntry::new(0));
+            return;
+        }
+
+        let subtable = table.next_level_table(i).unwrap();
+        if subtable.level() == 0 {
+            Self::clear_last_level_table(&subtable, freed);
+        } else {
+            for k in 0..MMArch::PAGE_ENTRY_NUM {
+                Self::clear_entry(&subtable, k, freed);
+            }
+        }
+        deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1));
+        kmem_stat_sub(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+        table.set_entry(i, PageEntry::new(0));
+    }
+
+    /// 清空最后一级页表中的所有表项，并释放引用计数降为0的页帧
+    ///
+    /// 整张页表的页帧被收集起来，通过`dec_ref_bulk`一次性减少引用计数：
+    /// KSM页帧可能还被其他页面映射，只有最后一个页面被取消映射时才释放
+    unsafe fn clear_last_level_table(table: &PageTable<MMArch>, freed: &mut usize) {
+        let zero = zero_frame();
+        let mut frames = Vec::with_capacity(MMArch::PAGE_ENTRY_NUM);
+        for k in 0..MMArch::PAGE_ENTRY_NUM {
+            let entry = match table.entry(k) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if entry.present() {
+                let paddr = entry.address().unwrap();
+                // 共享零页帧不在引用计数表中，dec_ref_bulk会认为它需要被释放；
+                // 映射到用户空间的设备内存也不是由页帧分配器分配的
+                if zero == Some(paddr) || !is_ram(paddr) {
+                    *freed += 1;
+                } else {
+                    frames.push(paddr);
+                }
+            } else if let Some((slot, _)) = swap_entry_decode(entry) {
+                // 被换出的页面：释放槽位
+                swap_free_slot(slot).ok();
+            } else {
+                continue;
+            }
+            table.set_entry(k, PageEntry::new(0));
+        }
+        *freed += frames.len();
+        for paddr in dec_ref_bulk(&frames) {
+            deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1));
+        }
+    }
+
+    /// 销毁用户页表：释放用户空间部分剩余的页面和所有的中间页表，归还PCID，最后释放顶层页表
+    ///
+    /// 在UserMapper被drop时调用。如果此时还有`pin_alive`返回的守卫，推迟到最后一个守卫被drop时调用，
+    /// 因此持有守卫的遍历者不会访问到已经被释放的页表，PCID也不会在此期间被其他地址空间重新使用
+    ///
+    /// ## 参数
+    ///
+    /// - `table_paddr`：顶层页表的物理地址
+    /// - `pcid`：地址空间的PCID（为0表示没有分配PCID）
+    fn teardown(table_paddr: PhysAddr, pcid: usize) {
+        let top = unsafe { PageTable::new(VirtAddr::new(0), table_paddr, MMArch::PAGE_LEVELS - 1) };
+        let mut freed = 0;
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            // 顶层页表中与内核共享的表项不属于这个地址空间
+            if top
+                .entry_base(i)
+                .map_or(false, |base| base < MMArch::USER_END_VADDR)
+            {
+                unsafe { Self::clear_entry(&top, i, &mut freed) };
+            }
+        }
+        clear_user_stack_guard(table_paddr);
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::mm::pcid::free_pcid(pcid);
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = pcid;
+        Self::free_top_level_table(table_paddr);
+    }
+
+    /// 释放用户空间顶层页表占用的页帧
+    fn free_top_level_table(paddr: PhysAddr) {
+        unsafe { deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1)) };
+        kmem_stat_sub(KernelMemPurpose::PageTable, PageFrameCount::new(1));
+    }
+
+    /// 把虚拟地址所在的页面迁移到指定NUMA节点的物理页上
+    ///
+    /// 该函数会在目标节点上分配新的物理页，拷贝原页面的内容，然后更新页表项，
+    /// 刷新TLB，最后释放原来的物理页。
+    ///
+    /// 与`swap_out`相同，共享的页帧（写时复制页面、被多个页面映射的KSM页帧、共享零页）不能被迁移，
+    /// 被固定的页帧（参见`pin_frame`）也不能被迁移
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：要迁移的页面的虚拟地址（必须按页对齐）
+    /// - `target_node`：目标NUMA节点
+    ///
+    /// ## 返回值
+    ///
+    /// - `EINVAL`：地址未对齐，或者目标节点不存在
+    /// - `EFAULT`：虚拟地址没有被映射
+    /// - `EBUSY`：页帧是共享的，或者被固定
+    /// - `ENOMEM`：目标节点上无法分配新的物理页
+    #[allow(dead_code)]
+    pub fn migrate_page(&mut self, vaddr: VirtAddr, target_node: usize) -> Result<(), SystemError> {
+        if !vaddr.check_aligned(MMArch::PAGE_SIZE) || target_node >= NUMA_NODES {
+            return Err(SystemError::EINVAL);
+        }
+
+        let (old_paddr, flags) = self.utable.translate(vaddr).ok_or(SystemError::EFAULT)?;
+        if flags.has_cow()
+            || ksm_frame_refcount(old_paddr) > 1
+            || zero_frame() == Some(old_paddr)
+            || frame_pinned(old_paddr)
+        {
+            return Err(SystemError::EBUSY);
+        }
+
+        // 替换页表项失败时，新的物理页由守卫释放
+        let new_frame =
+            alloc_frames_on_node(PageFrameCount::new(1), 1, target_node, FRAME_TAG_USER_ANON)?;
+        let new_paddr = new_frame.frames()[0].0;
+
+        // 拷贝原页面的内容到新的物理页
+        unsafe {
+            let src = MMArch::phys_2_virt(old_paddr).unwrap().as_ptr::<u8>();
+            let dst = MMArch::phys_2_virt(new_paddr).unwrap().as_ptr::<u8>();
+            dst.copy_from_nonoverlapping(src, MMArch::PAGE_SIZE);
+        }
+
+        let (old_paddr, flush) =
+            unsafe { self.utable.replace_phys(vaddr, new_paddr) }.ok_or(SystemError::EFAULT)?;
+        new_frame.into_inner();
+
+        // 其他核心上可能缓存了旧的映射，因此在刷新本核心的TLB之后，还需要通知其他核心刷新TLB
+        let mut flusher = InactiveFlusher::new();
+        if self.utable.is_current() {
+            flush.flush();
+        } else {
+            flusher.consume(flush);
+        }
+        drop(flusher);
+
+        // 页帧只被这一个页面映射，KSM的引用计数表中最多只有它自己的一项
+        if ksm_put(old_paddr) != Some(false) {
+            unsafe {
+                deallocate_page_frames(PhysPageFrame::new(old_paddr), PageFrameCount::new(1))
+            };
+        }
+        return Ok(());
+    }
+
+    /// 把虚拟地址所在的页面换出到全局的交换空间（参见`mm::swap`）
+    ///
+    /// 先把页表项替换为换出页表项并刷新TLB，再把页面的内容写入槽位，最后释放页帧。
+    /// 因此写入期间对这个页面的访问会触发缺页异常，而不会产生丢失的写入。写入失败时，恢复原来的映射
+    ///
+    /// 共享的页帧（写时复制页面、被多个页面映射的KSM页帧、共享零页）不能被换出
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：页面的虚拟地址（必须按页对齐）
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回页面所在的槽位
+    /// - `EINVAL`：地址未对齐
+    /// - `EFAULT`：虚拟地址没有被映射
+    /// - `EBUSY`：页帧是共享的
+    /// - `ENODEV`：没有注册交换空间
+    /// - `ENOSPC`：交换空间已满
+    /// - 其他：后端写入失败时返回的错误
+    pub fn swap_out(&mut self, vaddr: VirtAddr) -> Result<SwapSlot, SystemError> {
+        if !vaddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+        let (paddr, flags) = self.utable.translate(vaddr).ok_or(SystemError::EFAULT)?;
+        if flags.has_cow() || ksm_frame_refcount(paddr) > 1 || zero_frame() == Some(paddr) {
+            return Err(SystemError::EBUSY);
+        }
+
+        let (slot, backend) = swap_alloc_slot()?;
+        let old = unsafe {
+            self.utable
+                .set_raw_entry(vaddr, swap_entry_encode(slot, flags))
+        }
+        .unwrap();
+        // 其他核心上可能缓存了旧的映射，因此在刷新本核心的TLB之后，还需要通知其他核心刷新TLB
+        let flush = PageFlush::<MMArch>::new(vaddr);
+        let mut flusher = InactiveFlusher::new();
+        if self.utable.is_current() {
+            flush.flush();
+        } else {
+            flusher.consume(flush);
+        }
+        drop(flusher);
+
+        if let Err(e) = backend.write_slot(slot, unsafe { MMArch::phys_2_virt(paddr) }.unwrap()) {
+            // 换出页表项不存在，TLB中不会缓存它，直接恢复原来的页表项即可
+            unsafe { self.utable.set_raw_entry(vaddr, old) };
+            swap_free_slot(slot).ok();
+            return Err(e);
+        }
+
+        if ksm_put(paddr) != Some(false) {
+            unsafe { deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1)) };
+        }
+        return Ok(slot);
+    }
+
+    /// 把`swap_out`换出的页面换入：分配新的页帧，从槽位中读取内容，以原来的标志位恢复映射，最后释放槽位
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：页面的虚拟地址（必须按页对齐）
+    ///
+    /// ## 返回值
+    ///
+    /// - `EINVAL`：地址未对齐
+    /// - `EFAULT`：页面没有被换出
+    /// - `ENODEV`：没有注册交换空间
+    /// - `ENOMEM`：无法分配新的页帧
+    /// - 其他：后端读取失败时返回的错误（页面保持换出的状态）
+    pub fn swap_in(&mut self, vaddr: VirtAddr) -> Result<(), SystemError> {
+        if !vaddr.check_aligned(MMArch::PAGE_SIZE) {
+            return Err(SystemError::EINVAL);
+        }
+        let (slot, flags) = self
+            .utable
+            .raw_entry(vaddr)
+            .and_then(swap_entry_decode)
+            .ok_or(SystemError::EFAULT)?;
+        let backend = swap_backend()?;
+
+        // 读取失败时，新的页帧由守卫释放
+        let new_frame = alloc_frames_flags(
+            PageFrameCount::new(1),
+            1,
+            AllocFlags::empty(),
+            FRAME_TAG_USER_ANON,
+        )?;
+        let new_paddr = new_frame.frames()[0].0;
+        backend.read_slot(slot, unsafe { MMArch::phys_2_virt(new_paddr) }.unwrap())?;
+
+        // 换出页表项不存在，TLB中不会缓存它，不需要刷新
+        unsafe {
+            self.utable.set_raw_entry(
+                vaddr,
+                PageEntry::new_mapping(new_paddr, flags.to_entry_bits()),
+            )
+        };
+        new_frame.into_inner();
+        swap_free_slot(slot).expect("swap_in: slot was not allocated");
+        return Ok(());
+    }
+
+    /// 处理对已映射的只读页面的写入所引起的缺页异常
+    ///
+    /// - 映射到共享零页帧的页面：分配一个已经清零的私有页帧，并设置为可写
+    /// - 写时复制页面（带有COW软件标志位）：把内容复制到新的页帧，并设置为可写
+    /// - 其他只读页面：页面被程序自己设置为只读，应当发送SIGSEGV
+    ///
+    /// 如果原页帧是共享的页帧（KSM页帧，或者`clone_user_mapping`共享的页帧），复制之后会减少它的引用计数，
+    /// 最后一个页面负责释放它。
+    ///
+    /// TODO: 写时复制页面的最后一个持有者可以直接恢复写权限，而不需要复制
+    ///
+    /// ## 参数
+    ///
+    /// - `vaddr`：发生缺页异常的虚拟地址
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：返回处理结果
+    /// - `ENOMEM`：无法分配新的页帧
+    pub fn handle_write_fault(
+        &mut self,
+        vaddr: VirtAddr,
+    ) -> Result<WriteFaultOutcome, SystemError> {
+        let vaddr = VirtAddr::new(vaddr.data() & !MMArch::PAGE_OFFSET_MASK);
+        let (old_paddr, flags) = match self.utable.translate(vaddr) {
+            Some(r) => r,
+            None => return Ok(WriteFaultOutcome::NotMapped),
+        };
+        if flags.has_write() {
+            return Ok(WriteFaultOutcome::Spurious);
+        }
+
+        let is_zero_page = zero_frame() == Some(old_paddr);
+        if !is_zero_page && !flags.has_cow() {
+            return Ok(WriteFaultOutcome::AccessViolation);
+        }
+
+        // 缺页异常处理无法等待内存回收，因此在内存耗尽时使用紧急页帧池
+        let new_frame = alloc_frames_flags(
+            PageFrameCount::new(1),
+            1,
+            AllocFlags::CRITICAL,
+            FRAME_TAG_USER_ANON,
+        )?;
+        let new_paddr = new_frame.frames()[0].0;
+        unsafe {
+            let dst = MMArch::phys_2_virt(new_paddr).unwrap();
+            if is_zero_page {
+                MMArch::write_bytes(dst, 0, MMArch::PAGE_SIZE);
+            } else {
+                let src = MMArch::phys_2_virt(old_paddr).unwrap().as_ptr::<u8>();
+                dst.as_ptr::<u8>()
+                    .copy_from_nonoverlapping(src, MMArch::PAGE_SIZE);
+            }
+        }
+
+        // 先替换页帧（此时仍然是只读的），再恢复写权限
+        let new_flags = flags.set_cow(false).set_write(true);
+        let (_, flush) =
+            unsafe { self.utable.replace_phys(vaddr, new_paddr) }.ok_or(SystemError::EFAULT)?;
+        new_frame.into_inner();
+        // 恢复写权限之后再统一刷新TLB
+        unsafe { flush.ignore_safe() };
+        let flush = unsafe { self.utable.remap(vaddr, new_flags) }.unwrap();
+
+        // 其他核心上可能缓存了旧的映射，因此在刷新本核心的TLB之后，还需要通知其他核心刷新TLB
+        let mut flusher = InactiveFlusher::new();
+        if self.utable.is_current() {
+            flush.flush();
+        } else {
+            flusher.consume(flush);
+        }
+        drop(flusher);
+
+        if is_zero_page {
+            return Ok(WriteFaultOutcome::ZeroPageReplaced);
+        }
+        if ksm_put(old_paddr) == Some(true) {
+            unsafe {
+                deallocate_page_frames(PhysPageFrame::new(old_paddr), PageFrameCount::new(1))
+            };
+        }
+        return Ok(WriteFaultOutcome::CowResolved);
+    }
+
+    /// 为fork复制用户地址空间：创建新的用户页表，并以写时复制的方式共享parent中所有的用户页面
+    ///
+    /// - 可写的页面在两张页表中都被设置为只读，并设置COW软件标志位，写入时由`handle_write_fault`复制
+    /// - 只读的页面直接共享
+    /// - 共享的页帧的引用计数通过`inc_ref_bulk`增加（共享零页除外），最后一个取消映射的页面负责释放它
+    /// - 不能共享的页面（被换出的页面、影子栈页面、大页）被复制到新的页帧中
+    ///
+    /// 返回之前，parent中被设置为只读的页面的TLB条目会被刷新（包括其他CPU上的）。
+    /// 调用者需要持有parent所属的地址空间的锁
+    ///
+    /// ## 返回值
+    ///
+    /// - 成功：新的UserMapper
+    /// - `ENOMEM`：无法分配页表或者页帧。新的页表会被清空并释放，parent中已经被设置为写时复制的页面保持不变
+    /// - 其他：读取被换出的页面失败时返回的错误
+    pub fn clone_user_mapping(parent: &UserMapper) -> Result<UserMapper, SystemError> {
+        let mut child = MMArch::setup_new_usermapper()?;
+        let top = parent.utable.table();
+        let mut shared = Vec::new();
+        let mut downgraded = false;
+
+        let mut r = Ok(());
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            match top.entry_base(i) {
+                Some(base) if base < MMArch::USER_END_VADDR => {}
+                _ => continue,
+            }
+            if let Some(next) = unsafe { top.next_level_table(i) } {
+                r = unsafe { Self::clone_table(&next, &mut child, &mut shared, &mut downgraded) };
+                if r.is_err() {
+                    break;
+                }
+            }
+        }
+
+        // 无论是否成功，子页表中已经映射的共享页帧都需要计数，清空子页表时才不会释放它们
+        inc_ref_bulk(&shared);
+        if downgraded {
+            if parent.utable.is_current() {
+                unsafe { MMArch::invalidate_all() };
+            }
+            // 其他CPU上（比如同一个进程的其他线程）可能缓存着可写的条目，
+            // 必须等待它们完成刷新，否则在返回之后它们仍然可以写入与子进程共享的页帧
+            InactiveFlusher::new().flush_and_wait();
+        }
+        if let Err(e) = r {
+            child.clear_user_space();
+            return Err(e);
+        }
+        return Ok(child);
+    }
+
+    /// 把parent的一张页表（及其下级页表）中的映射复制到child中
+    ///
+    /// ## 参数
+    ///
+    /// - `table`：parent的页表
+    /// - `child`：新的UserMapper
+    /// - `shared`：收集被共享的页帧
+    /// - `downgraded`：是否有parent中的页面被设置为只读
+    unsafe fn clone_table(
+        table: &PageTable<MMArch>,
+        child: &mut UserMapper,
+        shared: &mut Vec<PhysAddr>,
+        downgraded: &mut bool,
+    ) -> Result<(), SystemError> {
+        let level = table.level();
+        for i in 0..MMArch::PAGE_ENTRY_NUM {
+            let entry = match table.entry(i) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let vaddr = table.entry_base(i).unwrap();
+            if !entry.present() {
+                if let Some((slot, flags)) = swap_entry_decode(entry) {
+                    let new_frame = alloc_frames_flags(
+                        PageFrameCount::new(1),
+                        1,
+                        AllocFlags::empty(),
+                        FRAME_TAG_USER_ANON,
+                    )?;
+                    let new_paddr = new_frame.frames()[0].0;
+                    swap_backend()?.read_slot(slot, MMArch::phys_2_virt(new_paddr).unwrap())?;
+                    child
+                        .utable
+                        .map_phys(vaddr, new_paddr, flags)?
+                        .ignore_safe();
+                    new_frame.into_inner();
+                }
+                continue;
+            }
+
+            let huge = level > 0 && entry.data() & MMArch::ENTRY_FLAG_HUGE_PAGE != 0;
+            if level != 0 && !huge {
+                if let Some(next) = table.next_level_table(i) {
+                    Self::clone_table(&next, child, shared, downgraded)?;
+                }
+                continue;
+            }
+
+            let flags = PageFlags::from_entry_bits_at(level, entry.data());
+            let size = 1 << (level * MMArch::PAGE_ENTRY_SHIFT + MMArch::PAGE_SHIFT);
+            let paddr = PhysAddr::new(entry.data() & MMArch::PAGE_ADDRESS_MASK & !(size - 1));
+            if huge || entry.is_shadow_stack() {
+                // 逐个4K页面复制
+                for offset in (0..size).step_by(MMArch::PAGE_SIZE) {
+                    let new_frame = alloc_frames_flags(
+                        PageFrameCount::new(1),
+                        1,
+                        AllocFlags::empty(),
+                        FRAME_TAG_USER_ANON,
+                    )?;
+                    let new_paddr = new_frame.frames()[0].0;
+                    let src = MMArch::phys_2_virt(paddr + offset).unwrap().as_ptr::<u8>();
+                    let dst = MMArch::phys_2_virt(new_paddr).unwrap().as_ptr::<u8>();
+                    dst.copy_from_nonoverlapping(src, MMArch::PAGE_SIZE);
+                    child
+                        .utable
+                        .map_phys(vaddr + offset, new_paddr, flags)?
+                        .ignore_safe();
+                    new_frame.into_inner();
+                }
+                continue;
+            }
+
+            let flags = if flags.has_write() {
+                let flags = flags.set_write(false).set_cow(true);
+                table.set_entry(i, PageEntry::new_mapping(paddr, flags.to_entry_bits()));
+                *downgraded = true;
+                flags
+            } else {
+                flags
+            };
+            child.utable.map_phys(vaddr, paddr, flags)?.ignore_safe();
+            if zero_frame() != Some(paddr) {
+                shared.push(paddr);
+            }
+        }
+        return Ok(());
     }
 }
 
@@ -571,14 +1387,45 @@ impl Drop for UserMapper {
             // 如果当前要被销毁的用户空间的页表是当前进程的页表，那么就切换回初始内核页表
             unsafe { MMArch::set_table(PageTableKind::User, MMArch::initial_page_table()) }
         }
-        // 释放用户空间顶层页表占用的页帧
-        // 请注意，在释放这个页帧之前，用户页表应该已经被完全释放，否则会产生内存泄露
-        unsafe {
-            deallocate_page_frames(
-                PhysPageFrame::new(self.utable.table().phys()),
-                PageFrameCount::new(1),
-            )
-        };
+        let prev = self.pins.fetch_or(Self::PIN_DEAD, Ordering::AcqRel);
+        if prev == 0 {
+            Self::teardown(self.utable.table().phys(), self.pcid);
+        }
+        // 否则，还有守卫没有被释放，由最后一个守卫来销毁页表
+    }
+}
+
+/// 用户页表的守卫，由`UserMapper::pin_alive`返回
+///
+/// 在守卫被drop之前，用户页表（包括各级页表）不会被释放，它的PCID也不会被归还
+#[derive(Debug)]
+pub struct UserMapperGuard {
+    /// 顶层页表的物理地址
+    table_paddr: PhysAddr,
+    /// 地址空间的PCID
+    pcid: usize,
+    pins: Arc<AtomicUsize>,
+}
+
+impl UserMapperGuard {
+    /// 获取被pin住的顶层页表的物理地址
+    pub fn table_paddr(&self) -> PhysAddr {
+        return self.table_paddr;
+    }
+
+    /// 判断UserMapper是否已经被drop（此时页表正在被销毁，不应当继续遍历）
+    pub fn is_dead(&self) -> bool {
+        return self.pins.load(Ordering::Acquire) & UserMapper::PIN_DEAD != 0;
+    }
+}
+
+impl Drop for UserMapperGuard {
+    fn drop(&mut self) {
+        let prev = self.pins.fetch_sub(1, Ordering::AcqRel);
+        // UserMapper已经被drop，并且这是最后一个守卫，因此由这里销毁页表
+        if prev == UserMapper::PIN_DEAD | 1 {
+            UserMapper::teardown(self.table_paddr, self.pcid);
+        }
     }
 }
 
@@ -830,9 +1677,10 @@ impl LockedVMA {
         for page in guard.region.pages() {
             // 暂时要求所有的页帧都已经映射到页表
             // TODO: 引入Lazy Mapping, 通过缺页中断来映射页帧，这里就不必要求所有的页帧都已经映射到页表了
+            let page_flags = shared_frame_flags(mapper, page.virt_address(), flags);
             let r = unsafe {
                 mapper
-                    .remap(page.virt_address(), flags)
+                    .remap(page.virt_address(), page_flags)
                     .expect("Failed to remap, beacuse of some page is not mapped")
             };
             flusher.consume(r);
@@ -846,19 +1694,40 @@ impl LockedVMA {
 
         let mut guard = self.lock();
         assert!(guard.mapped);
-        for page in guard.region.pages() {
+        let mut pages = guard.region.pages();
+        while let Some(page) = pages.next() {
+            // 大页（参见`VMA::zeroed_huge`）：整块释放。被部分修改过的大页已经被拆分，逐个4K页面释放
+            let vaddr = page.virt_address();
+            if vaddr.check_aligned(HUGE_PAGE_SIZE) && vaddr + HUGE_PAGE_SIZE <= guard.region.end() {
+                if let Ok((paddr, _, flush)) =
+                    unsafe { mapper.unmap_phys_huge(vaddr, PageSize::Size2M) }
+                {
+                    unsafe { free_huge_page(paddr) };
+                    flusher.consume(flush);
+                    pages.nth(HUGE_PAGE_FRAMES.data() - 2);
+                    continue;
+                }
+            }
+
+            // 被换出的页面：释放槽位
+            if let Some((slot, _)) = mapper
+                .raw_entry(page.virt_address())
+                .and_then(swap_entry_decode)
+            {
+                unsafe { mapper.set_raw_entry(page.virt_address(), PageEntry::new(0)) };
+                swap_free_slot(slot).ok();
+                continue;
+            }
             let (paddr, _, flush) = unsafe { mapper.unmap_phys(page.virt_address(), true) }
                 .expect("Failed to unmap, beacuse of some page is not mapped");
 
-            // todo: 获取物理页的anon_vma的守卫
-
-            // todo: 从anon_vma中删除当前VMA
-
-            // todo: 如果物理页的anon_vma链表长度为0，则释放物理页.
-
-            // 目前由于还没有实现共享页，所以直接释放物理页也没问题。
-            // 但是在实现共享页之后，就不能直接释放物理页了，需要在anon_vma链表长度为0的时候才能释放物理页
-            unsafe { deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1)) };
+            // 页帧可能与其他地址空间共享（写时复制、KSM），引用计数降为0时才释放。
+            // 共享零页帧不在引用计数表中，永远不能被释放
+            if zero_frame() != Some(paddr) && dec_ref(paddr) {
+                unsafe {
+                    deallocate_page_frames(PhysPageFrame::new(paddr), PageFrameCount::new(1))
+                };
+            }
 
             flusher.consume(flush);
         }
@@ -995,9 +1864,10 @@ impl VMA {
             // kdebug!("remap page {:?}", page.virt_address());
             // 暂时要求所有的页帧都已经映射到页表
             // TODO: 引入Lazy Mapping, 通过缺页中断来映射页帧，这里就不必要求所有的页帧都已经映射到页表了
+            let page_flags = shared_frame_flags(mapper, page.virt_address(), flags);
             let r = unsafe {
                 mapper
-                    .remap(page.virt_address(), flags)
+                    .remap(page.virt_address(), page_flags)
                     .expect("Failed to remap, beacuse of some page is not mapped")
             };
             // kdebug!("consume page {:?}", page.virt_address());
@@ -1122,6 +1992,7 @@ impl VMA {
             VirtPageFrameIter::new(destination, destination.add(page_count));
         for frame in virt_iter {
             let paddr = mapper.translate(frame.virt_address()).unwrap().0;
+            frame_tag_set(paddr, PageFrameCount::new(1), FRAME_TAG_USER_ANON);
 
             unsafe {
                 let vaddr = MMArch::phys_2_virt(paddr).unwrap();
@@ -1133,6 +2004,69 @@ impl VMA {
     }
 }
 
+impl VMA {
+    /// 与`zeroed`相同，但是尽可能使用已经清零的2M大页（来自大页池，参见`mm::allocator::huge_pool`）映射，
+    /// 用于`MAP_HUGETLB`的匿名映射
+    ///
+    /// 按2M对齐的完整2M范围使用大页，无法分配大页时，以及首尾不足2M的部分使用4K页面
+    ///
+    /// ## 参数
+    ///
+    /// - `destination`：要映射到的虚拟地址
+    /// - `page_count`：要映射的页帧数量
+    /// - `flags`：页面标志位
+    /// - `mapper`：页表映射器
+    /// - `flusher`：页表项刷新器
+    ///
+    /// ## 返回值
+    ///
+    /// 返回映射后的虚拟内存区域
+    pub fn zeroed_huge(
+        destination: VirtPageFrame,
+        page_count: PageFrameCount,
+        flags: PageFlags<MMArch>,
+        mapper: &mut PageMapper,
+        mut flusher: impl Flusher<MMArch>,
+    ) -> Result<Arc<LockedVMA>, SystemError> {
+        let start = destination.virt_address();
+        let end = start + page_count.bytes();
+        let mut vaddr = start;
+        while vaddr < end {
+            if vaddr.check_aligned(HUGE_PAGE_SIZE) && vaddr + HUGE_PAGE_SIZE <= end {
+                if let Some(paddr) = alloc_zeroed_huge_page() {
+                    match unsafe { mapper.map_phys_huge(vaddr, paddr, flags, PageSize::Size2M) } {
+                        Ok(flush) => {
+                            flusher.consume(flush);
+                            frame_tag_set(paddr, HUGE_PAGE_FRAMES, FRAME_TAG_USER_ANON);
+                            vaddr += HUGE_PAGE_SIZE;
+                            continue;
+                        }
+                        Err(_) => unsafe { free_huge_page(paddr) },
+                    }
+                }
+            }
+
+            let flush =
+                unsafe { mapper.map(vaddr, flags) }.expect("Failed to map zero, may be OOM error");
+            flusher.consume(flush);
+            let paddr = mapper.translate(vaddr).unwrap().0;
+            frame_tag_set(paddr, PageFrameCount::new(1), FRAME_TAG_USER_ANON);
+            unsafe {
+                MMArch::write_bytes(MMArch::phys_2_virt(paddr).unwrap(), 0, MMArch::PAGE_SIZE)
+            };
+            vaddr += MMArch::PAGE_SIZE;
+        }
+
+        return Ok(LockedVMA::new(VMA {
+            region: VirtRegion::new(start, page_count.bytes()),
+            flags,
+            mapped: true,
+            user_address_space: None,
+            self_ref: Weak::default(),
+        }));
+    }
+}
+
 impl Drop for VMA {
     fn drop(&mut self) {
         // 当VMA被释放时，需要确保它已经被从页表中解除映射
@@ -1187,45 +2121,10 @@ impl UserStack {
         let stack_bottom = stack_bottom.unwrap_or(Self::DEFAULT_USER_STACK_BOTTOM);
         assert!(stack_bottom.check_aligned(MMArch::PAGE_SIZE));
 
-        // 分配用户栈的保护页
+        // 栈底之上的GUARD_PAGES_NUM个页面保持不映射，作为栈底的保护区域
         let guard_size = Self::GUARD_PAGES_NUM * MMArch::PAGE_SIZE;
         let actual_stack_bottom = stack_bottom - guard_size;
 
-        let mut prot_flags = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
-        let map_flags =
-            MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_FIXED_NOREPLACE;
-        // kdebug!(
-        //     "map anonymous stack: {:?} {}",
-        //     actual_stack_bottom,
-        //     guard_size
-        // );
-        vm.map_anonymous(
-            actual_stack_bottom,
-            guard_size,
-            prot_flags,
-            map_flags,
-            false,
-        )?;
-        // test_buddy();
-        // 设置保护页只读
-        prot_flags.remove(ProtFlags::PROT_WRITE);
-        // kdebug!(
-        //     "to mprotect stack guard pages: {:?} {}",
-        //     actual_stack_bottom,
-        //     guard_size
-        // );
-        vm.mprotect(
-            VirtPageFrame::new(actual_stack_bottom),
-            PageFrameCount::new(Self::GUARD_PAGES_NUM),
-            prot_flags,
-        )?;
-
-        // kdebug!(
-        //     "mprotect stack guard pages done: {:?} {}",
-        //     actual_stack_bottom,
-        //     guard_size
-        // );
-
         let mut user_stack = UserStack {
             stack_bottom: actual_stack_bottom,
             mapped_size: guard_size,
@@ -1244,19 +2143,10 @@ impl UserStack {
         vm: &mut InnerAddressSpace,
         mut bytes: usize,
     ) -> Result<(), SystemError> {
-        let prot_flags = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC;
-        let map_flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS;
-
         bytes = page_align_up(bytes);
         self.mapped_size += bytes;
 
-        vm.map_anonymous(
-            self.stack_bottom - self.mapped_size,
-            bytes,
-            prot_flags,
-            map_flags,
-            false,
-        )?;
+        Self::map_stack_pages(vm, self.stack_bottom - self.mapped_size, bytes, true)?;
 
         return Ok(());
     }
@@ -1278,18 +2168,81 @@ impl UserStack {
         vm: &mut RwLockWriteGuard<InnerAddressSpace>,
         mut bytes: usize,
     ) -> Result<(), SystemError> {
-        let prot_flags = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC;
-        let map_flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS;
-
         bytes = page_align_up(bytes);
         self.mapped_size += bytes;
 
-        vm.map_anonymous(
-            self.stack_bottom - self.mapped_size,
-            bytes,
+        Self::map_stack_pages(vm, self.stack_bottom - self.mapped_size, bytes, false)?;
+
+        return Ok(());
+    }
+
+    /// 在[vaddr, vaddr + bytes)映射栈的页面，并在它的下方留出一个不存在的保护页（参见`map_with_guard`）
+    ///
+    /// 保护页位于VMA之外，栈溢出时会触发缺页异常，而不会写入相邻的映射。扩展栈时，
+    /// 新页面的下方会留出新的保护页，原来的保护页则被新页面占用
+    ///
+    /// ## 参数
+    ///
+    /// - `vm` 用户地址空间结构体
+    /// - `vaddr` 新页面的最低地址
+    /// - `bytes` 新页面的大小（按页对齐）
+    /// - `guard_above` 是否同时在新页面的上方留出保护页（创建栈时）
+    fn map_stack_pages(
+        vm: &mut InnerAddressSpace,
+        vaddr: VirtAddr,
+        bytes: usize,
+        guard_above: bool,
+    ) -> Result<(), SystemError> {
+        let prot_flags = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE | ProtFlags::PROT_EXEC;
+        let map_flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS;
+        let page_count = PageFrameCount::from_bytes(bytes).ok_or(SystemError::EINVAL)?;
+
+        vm.mmap(
+            Some(vaddr),
+            page_count,
             prot_flags,
             map_flags,
-            false,
+            |page, count, flags, mapper, _flusher| {
+                let (usable, flush) = unsafe {
+                    map_with_guard(
+                        mapper,
+                        page.virt_address() - MMArch::PAGE_SIZE,
+                        None,
+                        count,
+                        flags,
+                        true,
+                        guard_above,
+                    )
+                }?;
+                // 新建立的映射之前不存在，mmap的刷新器会在VMA插入之后刷新TLB
+                unsafe { flush.ignore_safe() };
+                // 登记下方的保护页（扩展栈时，替换原来的保护页）
+                let guard = usable.start - MMArch::PAGE_SIZE..usable.start;
+                set_user_stack_guard(
+                    mapper.table().phys(),
+                    usable.start,
+                    guard,
+                    current_pcb().pid,
+                )?;
+
+                // 清空这些内存
+                for frame in VirtPageFrameIter::new(page, page.add(count)) {
+                    let paddr = mapper.translate(frame.virt_address()).unwrap().0;
+                    frame_tag_set(paddr, PageFrameCount::new(1), FRAME_TAG_USER_ANON);
+                    unsafe {
+                        let vaddr = MMArch::phys_2_virt(paddr).unwrap();
+                        MMArch::write_bytes(vaddr, 0, MMArch::PAGE_SIZE);
+                    }
+                }
+
+                return Ok(LockedVMA::new(VMA {
+                    region: VirtRegion::new(usable.start, usable.end - usable.start),
+                    flags,
+                    mapped: true,
+                    user_address_space: None,
+                    self_ref: Weak::default(),
+                }));
+            },
         )?;
 
         return Ok(());
@@ -17,6 +17,7 @@ use crate::{
         refcount::{refcount_inc, RefCount},
         spinlock::{spin_lock_irqsave, spin_unlock_irqrestore},
     },
+    mm::stack_guard::{set_user_stack_guard, user_stack_guard},
     syscall::SystemError,
 };
 
@@ -164,6 +165,11 @@ pub fn copy_mm(clone_vm: bool, new_pcb: &mut process_control_block) -> Result<()
             current_pcb().pid, new_pcb.pid, e
         )
     });
+    // 子进程的用户栈的保护页由try_clone按照父进程登记，这里改为子进程的pid
+    let table = new_address_space.read().user_mapper.utable.table().phys();
+    if let Some(g) = user_stack_guard(table) {
+        set_user_stack_guard(table, g.stack, g.guard, new_pcb.pid)?;
+    }
     unsafe { new_pcb.set_address_space(new_address_space) };
     return Ok(());
 }
@@ -48,6 +48,7 @@ extern void rs_process_exit_fpstate(struct process_control_block *pcb);
 extern void rs_drop_address_space(struct process_control_block *pcb);
 extern int process_init_files();
 extern int rs_init_stdio();
+extern void rs_mm_boot_tests();
 extern uint64_t rs_do_execve(const char *filename, const char *const argv[], const char *const envp[], struct pt_regs *regs);
 extern uint64_t rs_exec_init_process(struct pt_regs *regs);
 
@@ -196,6 +197,9 @@ ul initial_kernel_thread(ul arg)
     io_mfence();
     __rust_demo_func();
     io_mfence();
+    // 内核初始化完成，把只在初始化阶段写入的数据设置为只读
+    rs_mark_ro_after_init();
+    io_mfence();
 
     // 准备切换到用户态
     struct pt_regs *regs;
@@ -316,6 +320,7 @@ void process_init()
 {
     kinfo("Initializing process...");
     // rs_test_buddy();
+    rs_mm_boot_tests();
     io_mfence();
     rs_process_init();
     io_mfence();
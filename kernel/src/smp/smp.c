@@ -28,6 +28,14 @@ extern void rs_smp_init_idle();
 // 在head.S中定义的，APU启动时，要加载的页表
 // 由于内存管理模块初始化的时候，重置了页表，因此我们要把当前的页表传给APU
 extern uint64_t __APU_START_CR3;
+extern bool rs_low_remap_enabled();
+extern uint64_t rs_initial_page_table();
+extern uint64_t rs_ap_trampoline_frame(uint64_t *size);
+extern void rs_frame_allocator_warmup_cpu(uint32_t cpu_id);
+extern void rs_init_pcid_ap();
+extern void rs_flush_tlb_ipi();
+extern void rs_mm_idle_work();
+extern uint64_t rs_alloc_kernel_stack(int64_t pid);
 
 // kick cpu 功能所使用的中断向量号
 #define KICK_CPU_IRQ_NUM 0xc8
@@ -36,8 +44,10 @@ extern uint64_t __APU_START_CR3;
 void smp_init()
 {
     spin_init(&multi_core_starting_lock); // 初始化多核启动锁
-    // 设置多核启动时，要加载的页表
-    __APU_START_CR3 = (uint64_t)get_CR3();
+    // 设置多核启动时，要加载的页表（内存管理初始化时创建的内核页表）
+    __APU_START_CR3 = rs_initial_page_table();
+    if (__APU_START_CR3 == 0)
+        __APU_START_CR3 = (uint64_t)get_CR3();
 
     ul tmp_vaddr[MAX_SUPPORTED_PROCESSOR_NUM] = {0};
 
@@ -50,9 +60,20 @@ void smp_init()
         proc_local_apic_structs[i] = (struct acpi_Processor_Local_APIC_Structure_t *)(tmp_vaddr[i]);
     }
 
-    // 将引导程序复制到物理地址0x20000处
-    memcpy((unsigned char *)phys_2_virt(0x20000), _apu_boot_start,
-           (unsigned long)&_apu_boot_end - (unsigned long)&_apu_boot_start);
+    // 将引导程序复制到内存管理模块在1MB以下保留的页帧处
+    uint64_t trampoline_size = 0;
+    uint64_t trampoline_paddr = rs_ap_trampoline_frame(&trampoline_size);
+    uint64_t apu_boot_size = (unsigned long)&_apu_boot_end - (unsigned long)&_apu_boot_start;
+    if (apu_boot_size > trampoline_size)
+    {
+        kBUG("AP boot code (%#018lx bytes) does not fit in the reserved trampoline (%#018lx bytes)", apu_boot_size,
+             trampoline_size);
+        while (1)
+            hlt();
+    }
+    memcpy((unsigned char *)phys_2_virt(trampoline_paddr), _apu_boot_start, apu_boot_size);
+    // start-up IPI的向量号是引导程序所在的物理页号
+    uint32_t sipi_vector = (uint32_t)(trampoline_paddr >> 12);
     io_mfence();
     // 设置多核IPI中断门
     for (int i = 200; i < 210; ++i)
@@ -70,6 +91,10 @@ void smp_init()
     ipi_regiserIPI(FLUSH_TLB_IRQ_NUM, NULL, &__smp__flush_tlb_ipi_handler, NULL, NULL, "IPI flush tlb");
 
     int core_to_start = 0;
+    // AP处理器的启动代码依赖于低地址的重映射。没有建立低地址映射时，只使用BSP
+    bool ap_bringup = rs_low_remap_enabled();
+    if (!ap_bringup)
+        kwarn("Low-address remap is disabled, skip starting application processors.");
     // total_processor_num = 3;
     for (int i = 0; i < total_processor_num; ++i) // i从1开始，不初始化bsp
     {
@@ -84,6 +109,8 @@ void smp_init()
             // --total_processor_num;
             continue;
         }
+        if (!ap_bringup)
+            continue;
         if (!((proc_local_apic_structs[i]->flags & 0x1) || (proc_local_apic_structs[i]->flags & 0x2)))
         {
             // --total_processor_num;
@@ -98,9 +125,13 @@ void smp_init()
                           // count
         current_starting_cpu = proc_local_apic_structs[i]->ACPI_Processor_UID;
         io_mfence();
-        // 为每个AP处理器分配栈空间
-        cpu_core_info[current_starting_cpu].stack_start = (uint64_t)kmalloc(STACK_SIZE, 0) + STACK_SIZE;
-        cpu_core_info[current_starting_cpu].ist_stack_start = (uint64_t)(kmalloc(STACK_SIZE, 0)) + STACK_SIZE;
+        // 为每个AP处理器分配栈空间。优先使用带保护页的栈（栈溢出时报告“kernel stack overflow”），失败时使用kmalloc
+        cpu_core_info[current_starting_cpu].stack_start = rs_alloc_kernel_stack(0);
+        if (cpu_core_info[current_starting_cpu].stack_start == 0)
+            cpu_core_info[current_starting_cpu].stack_start = (uint64_t)kmalloc(STACK_SIZE, 0) + STACK_SIZE;
+        cpu_core_info[current_starting_cpu].ist_stack_start = rs_alloc_kernel_stack(0);
+        if (cpu_core_info[current_starting_cpu].ist_stack_start == 0)
+            cpu_core_info[current_starting_cpu].ist_stack_start = (uint64_t)(kmalloc(STACK_SIZE, 0)) + STACK_SIZE;
         io_mfence();
         memset((void *)cpu_core_info[current_starting_cpu].stack_start - STACK_SIZE, 0, STACK_SIZE);
         memset((void *)cpu_core_info[current_starting_cpu].ist_stack_start - STACK_SIZE, 0, STACK_SIZE);
@@ -127,12 +158,15 @@ void smp_init()
             cpu_core_info[current_starting_cpu].ist_stack_start);
         io_mfence();
 
+        // 预热AP处理器的页帧缓存，避免它最初的分配都需要获取全局页帧分配器的锁
+        rs_frame_allocator_warmup_cpu(proc_local_apic_structs[i]->local_apic_id);
+
         // kdebug("core %d, to send start up", current_starting_cpu);
         // 连续发送两次start-up IPI
-        ipi_send_IPI(DEST_PHYSICAL, IDLE, ICR_LEVEL_DE_ASSERT, EDGE_TRIGGER, 0x20, ICR_Start_up, ICR_No_Shorthand,
+        ipi_send_IPI(DEST_PHYSICAL, IDLE, ICR_LEVEL_DE_ASSERT, EDGE_TRIGGER, sipi_vector, ICR_Start_up, ICR_No_Shorthand,
                      proc_local_apic_structs[i]->local_apic_id);
         io_mfence();
-        ipi_send_IPI(DEST_PHYSICAL, IDLE, ICR_LEVEL_DE_ASSERT, EDGE_TRIGGER, 0x20, ICR_Start_up, ICR_No_Shorthand,
+        ipi_send_IPI(DEST_PHYSICAL, IDLE, ICR_LEVEL_DE_ASSERT, EDGE_TRIGGER, sipi_vector, ICR_Start_up, ICR_No_Shorthand,
                      proc_local_apic_structs[i]->local_apic_id);
         // kdebug("core %d, send start up ok", current_starting_cpu);
     }
@@ -167,6 +201,7 @@ void smp_ap_start()
     ++num_cpu_started;
 
     apic_init_ap_core_local_apic();
+    rs_init_pcid_ap();
 
     // ============ 为ap处理器初始化IDLE进程 =============
     memset(current_pcb, 0, sizeof(struct process_control_block));
@@ -209,6 +244,7 @@ void smp_ap_start()
     while (1)
     {
         // kdebug("123");
+        rs_mm_idle_work();
         hlt();
     }
 
@@ -236,9 +272,8 @@ static void __smp_kick_cpu_handler(uint64_t irq_num, uint64_t param, struct pt_r
 
 static void __smp__flush_tlb_ipi_handler(uint64_t irq_num, uint64_t param, struct pt_regs *regs)
 {
-    if (user_mode(regs))
-        return;
-    flush_tlb();
+    // 发送方可能在等待这个CPU完成刷新，因此在用户态被打断时也需要刷新
+    rs_flush_tlb_ipi();
 }
 
 /**